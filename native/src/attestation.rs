@@ -0,0 +1,982 @@
+//! NSM attestation document decoding and verification.
+//!
+//! An attestation document is a COSE_Sign1 structure (optionally CBOR tag
+//! 18): `[protected, unprotected, payload, signature]`, where `payload` is
+//! a CBOR map with module_id, digest, timestamp, pcrs, certificate,
+//! cabundle and the optional public_key, user_data and nonce.
+//! parseAttestationDocument() unwraps both layers and hands JS plain values.
+//!
+//! verifyAttestation() is the host-side check: the ES384 signature by the
+//! document's certificate, that certificate's chain through the cabundle
+//! to the AWS Nitro root (or caller-supplied roots), and optionally the
+//! nonce, PCR values and the document's age. All time-dependent checks
+//! use one evaluation time, so recorded documents can be re-verified as of
+//! when they were received.
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use p384::ecdsa::signature::Verifier;
+#[cfg(any(test, feature = "nsm-mock"))]
+use p384::ecdsa::{signature::Signer, SigningKey};
+use p384::ecdsa::{Signature, VerifyingKey};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cbor;
+use crate::x509::{self, Certificate};
+
+/// CBOR tag for COSE_Sign1 (RFC 8152).
+const COSE_SIGN1_TAG: u64 = 18;
+
+/// COSE algorithm id for ES384 (ECDSA P-384 with SHA-384).
+pub(crate) const COSE_ALG_ES384: i64 = -35;
+
+/// Most a document's timestamp may lead the evaluation time when an age
+/// limit is checked, to tolerate drift between the enclave and our clock.
+pub(crate) const MAX_CLOCK_SKEW_MS: i64 = 5_000;
+
+/// AWS Nitro Enclaves Root-G1 (CN=aws.nitro-enclaves, valid 2019-10-28 to
+/// 2049-10-28), from
+/// https://aws-nitro-enclaves.amazonaws.com/AWS_NitroEnclaves_Root-G1.zip.
+/// SHA-256 fingerprint
+/// 64:1A:03:21:A3:E2:44:EF:E4:56:46:31:95:D6:06:31:7E:D7:CD:CC:3C:17:56:E0:98:93:F3:C6:8F:79:BB:5B
+/// (checked by the tests).
+const AWS_NITRO_ROOT_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIICETCCAZagAwIBAgIRAPkxdWgbkK/hHUbMtOTn+FYwCgYIKoZIzj0EAwMwSTEL
+MAkGA1UEBhMCVVMxDzANBgNVBAoMBkFtYXpvbjEMMAoGA1UECwwDQVdTMRswGQYD
+VQQDDBJhd3Mubml0cm8tZW5jbGF2ZXMwHhcNMTkxMDI4MTMyODA1WhcNNDkxMDI4
+MTQyODA1WjBJMQswCQYDVQQGEwJVUzEPMA0GA1UECgwGQW1hem9uMQwwCgYDVQQL
+DANBV1MxGzAZBgNVBAMMEmF3cy5uaXRyby1lbmNsYXZlczB2MBAGByqGSM49AgEG
+BSuBBAAiA2IABPwCVOumCMHzaHDimtqQvkY4MpJzbolL//Zy2YlES1BR5TSksfbb
+48C8WBoyt7F2Bw7eEtaaP+ohG2bnUs990d0JX28TcPQXCEPZ3BABIeTPYwEoCWZE
+h8l5YoQwTcU/9KNCMEAwDwYDVR0TAQH/BAUwAwEB/zAdBgNVHQ4EFgQUkCW1DdkF
+R+eWw5b6cp3PmanfS5YwDgYDVR0PAQH/BAQDAgGGMAoGCCqGSM49BAMDA2kAMGYC
+MQCjfy+Rocm9Xue4YnwWmNJVA44fA0P5W2OpYow9OYCVRaEevL8uO1XYru5xtMPW
+rfMCMQCi85sWBbJwKKXdS6BptQFuZbT73o/gBh1qUxl/nNr12UO8Yfwr6wPLb+6N
+IwLz3/Y=
+-----END CERTIFICATE-----
+";
+
+#[napi(object)]
+pub struct AttestationDocument {
+    pub module_id: String,
+    /// PCR digest algorithm, e.g. "SHA384".
+    pub digest: String,
+    /// Milliseconds since the Unix epoch, as Date.now().
+    pub timestamp: f64,
+    /// PCR index → value.
+    pub pcrs: HashMap<String, Buffer>,
+    /// DER-encoded signing certificate.
+    pub certificate: Buffer,
+    /// DER-encoded intermediate certificates, root first.
+    pub cabundle: Vec<Buffer>,
+    pub public_key: Option<Buffer>,
+    pub user_data: Option<Buffer>,
+    pub nonce: Option<Buffer>,
+}
+
+/// Decode an attestation document (COSE_Sign1 bytes, as returned by
+/// attestation()) without verifying it.
+#[napi]
+pub fn parse_attestation_document(cose: Buffer) -> Result<AttestationDocument> {
+    let sign1 = CoseSign1::parse(&cose)?;
+    Ok(Document::parse(&sign1.payload)?.into_js())
+}
+
+/// The parts of a COSE_Sign1 envelope that are used. The unprotected
+/// header is unused by the NSM and dropped.
+#[derive(Debug)]
+pub(crate) struct CoseSign1 {
+    pub(crate) protected: Vec<u8>,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl CoseSign1 {
+    pub(crate) fn parse(bytes: &[u8]) -> Result<Self> {
+        let value = cbor::decode(bytes)?;
+        let value = match value {
+            Value::Tag(COSE_SIGN1_TAG, inner) => *inner,
+            Value::Tag(tag, _) => {
+                return Err(Error::from_reason(format!(
+                    "Not a COSE_Sign1 document: unexpected CBOR tag {}",
+                    tag
+                )))
+            }
+            other => other,
+        };
+        let items = match value {
+            Value::Array(items) if items.len() == 4 => items,
+            _ => {
+                return Err(Error::from_reason(
+                    "Not a COSE_Sign1 document: expected a 4-element array",
+                ))
+            }
+        };
+        let bytes_at = |index: usize, name: &str| match &items[index] {
+            Value::Bytes(b) => Ok(b.clone()),
+            _ => Err(Error::from_reason(format!(
+                "COSE_Sign1 {} must be a byte string",
+                name
+            ))),
+        };
+        Ok(CoseSign1 {
+            protected: bytes_at(0, "protected header")?,
+            payload: bytes_at(2, "payload")?,
+            signature: bytes_at(3, "signature")?,
+        })
+    }
+
+    /// Check the protected header selects ES384, then verify the signature
+    /// over the Sig_structure (RFC 9052 §4.4) with `key`.
+    pub(crate) fn verify(&self, key: &VerifyingKey) -> Result<()> {
+        let header = cbor::decode(&self.protected)?;
+        let alg = match &header {
+            Value::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| cbor::as_u64(k) == Some(1))
+                .and_then(|(_, v)| match v {
+                    Value::Integer(i) => i64::try_from(*i).ok(),
+                    _ => None,
+                }),
+            _ => None,
+        };
+        if alg != Some(COSE_ALG_ES384) {
+            return Err(Error::from_reason(format!(
+                "COSE algorithm {:?} is not ES384",
+                alg
+            )));
+        }
+
+        let sig_structure = sig_structure(&self.protected, &self.payload)?;
+        let signature = Signature::from_slice(&self.signature)
+            .map_err(|_| Error::from_reason("COSE signature is not a 96-byte ES384 signature"))?;
+        key.verify(&sig_structure, &signature)
+            .map_err(|_| Error::from_reason("COSE signature does not verify"))
+    }
+
+    /// Sign `payload` with `key`, declaring `alg` in the protected header,
+    /// and encode the tagged COSE_Sign1.
+    #[cfg(any(test, feature = "nsm-mock"))]
+    pub(crate) fn sign(payload: &[u8], alg: i64, key: &SigningKey) -> Result<Vec<u8>> {
+        let protected = cbor::encode(&Value::Map(vec![(
+            Value::Integer(1.into()),
+            Value::Integer(alg.into()),
+        )]))?;
+        let signature: Signature = key.sign(&sig_structure(&protected, payload)?);
+        cbor::encode(&Value::Tag(
+            COSE_SIGN1_TAG,
+            Box::new(Value::Array(vec![
+                Value::Bytes(protected),
+                Value::Map(vec![]),
+                Value::Bytes(payload.to_vec()),
+                Value::Bytes(signature.to_bytes().to_vec()),
+            ])),
+        ))
+    }
+}
+
+/// The Sig_structure (RFC 9052 §4.4) COSE_Sign1 signatures cover.
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    cbor::encode(&Value::Array(vec![
+        cbor::text("Signature1"),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(payload.to_vec()),
+    ]))
+}
+
+/// napi-free form of AttestationDocument.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Document {
+    pub(crate) module_id: String,
+    pub(crate) digest: String,
+    pub(crate) timestamp: u64,
+    pub(crate) pcrs: BTreeMap<u32, Vec<u8>>,
+    pub(crate) certificate: Vec<u8>,
+    pub(crate) cabundle: Vec<Vec<u8>>,
+    pub(crate) public_key: Option<Vec<u8>>,
+    pub(crate) user_data: Option<Vec<u8>>,
+    pub(crate) nonce: Option<Vec<u8>>,
+}
+
+impl Document {
+    /// Decode the COSE_Sign1 payload.
+    pub(crate) fn parse(payload: &[u8]) -> Result<Self> {
+        let doc = cbor::decode(payload)?;
+        if !matches!(doc, Value::Map(_)) {
+            return Err(Error::from_reason(
+                "Attestation document payload must be a CBOR map",
+            ));
+        }
+        let field = |key: &str| {
+            cbor::map_get(&doc, key)
+                .ok_or_else(|| Error::from_reason(format!("Attestation document missing {}", key)))
+        };
+        let invalid =
+            |key: &str| Error::from_reason(format!("Attestation document has an invalid {}", key));
+        let text_field = |key: &str| -> Result<String> {
+            cbor::as_text(field(key)?)
+                .map(str::to_string)
+                .ok_or_else(|| invalid(key))
+        };
+        let bytes_field = |key: &str| -> Result<Vec<u8>> {
+            match field(key)? {
+                Value::Bytes(b) => Ok(b.clone()),
+                _ => Err(invalid(key)),
+            }
+        };
+        let optional_bytes = |key: &str| -> Result<Option<Vec<u8>>> {
+            match cbor::map_get(&doc, key) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::Bytes(b)) => Ok(Some(b.clone())),
+                Some(_) => Err(invalid(key)),
+            }
+        };
+
+        let pcrs = match field("pcrs")? {
+            Value::Map(entries) => entries
+                .iter()
+                .map(|(index, value)| {
+                    let index = cbor::as_u64(index).and_then(|n| u32::try_from(n).ok());
+                    match (index, value) {
+                        (Some(index), Value::Bytes(b)) => Ok((index, b.clone())),
+                        _ => Err(invalid("pcrs")),
+                    }
+                })
+                .collect::<Result<_>>()?,
+            _ => return Err(invalid("pcrs")),
+        };
+        let cabundle = match field("cabundle")? {
+            Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Bytes(b) => Ok(b.clone()),
+                    _ => Err(invalid("cabundle")),
+                })
+                .collect::<Result<_>>()?,
+            _ => return Err(invalid("cabundle")),
+        };
+
+        Ok(Document {
+            module_id: text_field("module_id")?,
+            digest: text_field("digest")?,
+            timestamp: cbor::as_u64(field("timestamp")?).ok_or_else(|| invalid("timestamp"))?,
+            pcrs,
+            certificate: bytes_field("certificate")?,
+            cabundle,
+            public_key: optional_bytes("public_key")?,
+            user_data: optional_bytes("user_data")?,
+            nonce: optional_bytes("nonce")?,
+        })
+    }
+
+    pub(crate) fn into_js(self) -> AttestationDocument {
+        AttestationDocument {
+            module_id: self.module_id,
+            digest: self.digest,
+            timestamp: self.timestamp as f64,
+            pcrs: self
+                .pcrs
+                .into_iter()
+                .map(|(index, value)| (index.to_string(), value.into()))
+                .collect(),
+            certificate: self.certificate.into(),
+            cabundle: self.cabundle.into_iter().map(Buffer::from).collect(),
+            public_key: self.public_key.map(Buffer::from),
+            user_data: self.user_data.map(Buffer::from),
+            nonce: self.nonce.map(Buffer::from),
+        }
+    }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct VerifyAttestationOptions {
+    /// Nonce the document must carry.
+    pub expected_nonce: Option<Buffer>,
+    /// PCR index → value the document must report; PCRs not listed are
+    /// not checked.
+    pub expected_pcrs: Option<HashMap<String, Buffer>>,
+    /// Time to evaluate certificate validity and maxAgeMs at, in
+    /// milliseconds since the Unix epoch (default now).
+    pub time: Option<f64>,
+    /// Most the document's timestamp may lag `time`. Documents dated more
+    /// than 5 seconds after `time` are rejected as well.
+    pub max_age_ms: Option<f64>,
+    /// Additional root certificates to trust, each PEM (one or more
+    /// certificates) or DER.
+    pub trusted_roots: Option<Vec<Buffer>>,
+    /// Trust the embedded AWS Nitro root (default true). Disable to accept
+    /// only `trustedRoots`, e.g. a test CA or a pinned root.
+    pub use_aws_root: Option<bool>,
+}
+
+#[napi(object)]
+pub struct AttestationVerdict {
+    /// True when every check below passed.
+    pub valid: bool,
+    /// The COSE ES384 signature verifies under the document's certificate.
+    pub signature_valid: bool,
+    /// The certificate chains through the cabundle to a trusted root, and
+    /// every certificate is valid at the verification time.
+    pub chain_valid: bool,
+    /// Absent when no expectedNonce was given.
+    pub nonce_valid: Option<bool>,
+    /// Absent when no expectedPcrs were given.
+    pub pcrs_valid: Option<bool>,
+    /// Absent when no maxAgeMs was given.
+    pub fresh: Option<bool>,
+    /// One message per failed check.
+    pub errors: Vec<String>,
+    pub document: AttestationDocument,
+}
+
+/// Verify an attestation document (COSE_Sign1 bytes). Failed checks are
+/// reported in the verdict; only a document that can't be decoded throws.
+#[napi]
+pub fn verify_attestation(
+    document: Buffer,
+    options: Option<VerifyAttestationOptions>,
+) -> Result<AttestationVerdict> {
+    let options = options.unwrap_or_default();
+    let expected_pcrs = options
+        .expected_pcrs
+        .map(|pcrs| {
+            pcrs.into_iter()
+                .map(|(index, value)| match index.parse::<u32>() {
+                    Ok(index) => Ok((index, value.to_vec())),
+                    Err(_) => Err(Error::from_reason(format!(
+                        "Invalid PCR index in expectedPcrs: {}",
+                        index
+                    ))),
+                })
+                .collect::<Result<BTreeMap<_, _>>>()
+        })
+        .transpose()?;
+    let max_age_ms = match options.max_age_ms {
+        Some(ms) if ms.is_nan() || ms < 0.0 => {
+            return Err(Error::from_reason("maxAgeMs must be a non-negative number"))
+        }
+        ms => ms.map(|ms| ms as i64),
+    };
+    let expectations = Expectations {
+        nonce: options.expected_nonce.map(|n| n.to_vec()),
+        pcrs: expected_pcrs,
+        max_age_ms,
+        time_ms: options.time.map(|t| t as i64).unwrap_or_else(now_ms),
+    };
+
+    let trusted_roots: Vec<&[u8]> = options
+        .trusted_roots
+        .iter()
+        .flatten()
+        .map(|b| &b[..])
+        .collect();
+    let roots = trust_anchors(&trusted_roots, options.use_aws_root.unwrap_or(true))?;
+
+    let verdict = verify(&document, &expectations, &roots)?;
+    Ok(verdict.into_js())
+}
+
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// The roots to verify against: `trusted_roots` (PEM or DER each), plus
+/// the AWS Nitro root if `use_aws_root`.
+pub(crate) fn trust_anchors(
+    trusted_roots: &[&[u8]],
+    use_aws_root: bool,
+) -> Result<Vec<Certificate>> {
+    let mut roots = Vec::new();
+    if use_aws_root {
+        roots.push(aws_nitro_root()?);
+    }
+    for (i, root) in trusted_roots.iter().enumerate() {
+        let certs = x509::parse_pem_or_der(root)
+            .map_err(|e| Error::from_reason(format!("trustedRoots[{}]: {}", i, e.reason)))?;
+        roots.extend(certs);
+    }
+    if roots.is_empty() {
+        return Err(Error::from_reason(
+            "No trust anchors: pass trustedRoots or leave useAwsRoot enabled",
+        ));
+    }
+    Ok(roots)
+}
+
+fn aws_nitro_root() -> Result<Certificate> {
+    x509::parse_pem_or_der(AWS_NITRO_ROOT_PEM.as_bytes())?
+        .pop()
+        .ok_or_else(|| Error::from_reason("Embedded AWS Nitro root is missing"))
+}
+
+pub(crate) struct Expectations {
+    pub(crate) nonce: Option<Vec<u8>>,
+    pub(crate) pcrs: Option<BTreeMap<u32, Vec<u8>>>,
+    pub(crate) max_age_ms: Option<i64>,
+    pub(crate) time_ms: i64,
+}
+
+/// napi-free form of AttestationVerdict.
+pub(crate) struct Verdict {
+    pub(crate) signature_valid: bool,
+    pub(crate) chain_valid: bool,
+    pub(crate) nonce_valid: Option<bool>,
+    pub(crate) pcrs_valid: Option<bool>,
+    pub(crate) fresh: Option<bool>,
+    pub(crate) errors: Vec<String>,
+    pub(crate) document: Document,
+}
+
+impl Verdict {
+    pub(crate) fn valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn into_js(self) -> AttestationVerdict {
+        AttestationVerdict {
+            valid: self.valid(),
+            signature_valid: self.signature_valid,
+            chain_valid: self.chain_valid,
+            nonce_valid: self.nonce_valid,
+            pcrs_valid: self.pcrs_valid,
+            fresh: self.fresh,
+            errors: self.errors,
+            document: self.document.into_js(),
+        }
+    }
+}
+
+/// Verify `cose` against `expectations`, trusting chains that end at one
+/// of `roots`.
+pub(crate) fn verify(
+    cose: &[u8],
+    expectations: &Expectations,
+    roots: &[Certificate],
+) -> Result<Verdict> {
+    let sign1 = CoseSign1::parse(cose)?;
+    let document = Document::parse(&sign1.payload)?;
+    let mut errors = Vec::new();
+
+    let (signature, chain) = authenticate(&sign1, &document, roots, expectations.time_ms);
+    let mut check = |result: Result<()>| match result {
+        Ok(()) => true,
+        Err(e) => {
+            errors.push(e.reason);
+            false
+        }
+    };
+    let signature_valid = check(signature);
+    let chain_valid = check(chain);
+
+    let nonce_valid = expectations.nonce.as_ref().map(|expected| {
+        let matches = document.nonce.as_ref() == Some(expected);
+        if !matches {
+            errors.push("Nonce does not match expectedNonce".to_string());
+        }
+        matches
+    });
+
+    let pcrs_valid = expectations.pcrs.as_ref().map(|expected| {
+        let mismatched: Vec<String> = expected
+            .iter()
+            .filter(|(index, value)| document.pcrs.get(index) != Some(value))
+            .map(|(index, _)| index.to_string())
+            .collect();
+        if !mismatched.is_empty() {
+            errors.push(format!("PCR mismatch: {}", mismatched.join(", ")));
+        }
+        mismatched.is_empty()
+    });
+
+    let fresh = expectations.max_age_ms.map(|max_age_ms| {
+        let age_ms = expectations.time_ms - document.timestamp as i64;
+        if age_ms < -MAX_CLOCK_SKEW_MS {
+            errors.push(format!(
+                "Document is dated {}ms in the future, more than the {}ms clock skew allowed",
+                -age_ms, MAX_CLOCK_SKEW_MS
+            ));
+            false
+        } else if age_ms > max_age_ms {
+            errors.push(format!(
+                "Document is {}ms old, more than the {}ms allowed",
+                age_ms, max_age_ms
+            ));
+            false
+        } else {
+            true
+        }
+    });
+
+    Ok(Verdict {
+        signature_valid,
+        chain_valid,
+        nonce_valid,
+        pcrs_valid,
+        fresh,
+        errors,
+        document,
+    })
+}
+
+/// The checks every verification makes: the COSE signature under the
+/// document's certificate, and that certificate's chain to `roots` at
+/// `time_ms`.
+pub(crate) fn authenticate(
+    sign1: &CoseSign1,
+    document: &Document,
+    roots: &[Certificate],
+    time_ms: i64,
+) -> (Result<()>, Result<()>) {
+    let leaf = match Certificate::from_der(&document.certificate) {
+        Ok(leaf) => leaf,
+        Err(e) => {
+            let message = format!("Signing certificate: {}", e.reason);
+            return (
+                Err(Error::from_reason(message.clone())),
+                Err(Error::from_reason(message)),
+            );
+        }
+    };
+    let signature = sign1.verify(leaf.public_key());
+    let chain = verify_chain(leaf, &document.cabundle, roots, time_ms);
+    (signature, chain)
+}
+
+/// Check `leaf` ← cabundle[n-1] ← … ← cabundle[0] ← one of `roots`, with
+/// every certificate valid at `time_ms` and every issuer a CA.
+fn verify_chain(
+    leaf: Certificate,
+    cabundle: &[Vec<u8>],
+    roots: &[Certificate],
+    time_ms: i64,
+) -> Result<()> {
+    let (anchor, intermediates) = match cabundle.split_first() {
+        Some(split) => split,
+        None => return Err(Error::from_reason("Certificate chain: cabundle is empty")),
+    };
+    let chain_error = |what: &str, e: Error| {
+        Error::from_reason(format!("Certificate chain: {}: {}", what, e.reason))
+    };
+
+    // cabundle[0] is the root itself; accept a trusted root or a
+    // certificate a trusted root issued
+    let anchor = Certificate::from_der(anchor).map_err(|e| chain_error("cabundle[0]", e))?;
+    let root = roots
+        .iter()
+        .find(|root| root.der == anchor.der || anchor.verify_issued_by(root).is_ok())
+        .ok_or_else(|| {
+            Error::from_reason("Certificate chain: cabundle[0] is not a trusted root")
+        })?;
+
+    let mut issued = Vec::new();
+    if root.der != anchor.der {
+        issued.push(anchor);
+    }
+    for (i, der) in intermediates.iter().enumerate() {
+        let what = format!("cabundle[{}]", i + 1);
+        issued.push(Certificate::from_der(der).map_err(|e| chain_error(&what, e))?);
+    }
+    issued.push(leaf);
+    let chain: Vec<&Certificate> = std::iter::once(root).chain(&issued).collect();
+
+    for (i, cert) in chain.iter().enumerate() {
+        let what = match i {
+            0 => "root".to_string(),
+            i if i == chain.len() - 1 => "signing certificate".to_string(),
+            i => format!("certificate {}", i),
+        };
+        if !cert.valid_at(time_ms) {
+            return Err(Error::from_reason(format!(
+                "Certificate chain: {} is not valid at the verification time",
+                what
+            )));
+        }
+        if i > 0 {
+            let issuer = chain[i - 1];
+            if !issuer.is_ca {
+                return Err(Error::from_reason(format!(
+                    "Certificate chain: issuer of {} is not a CA",
+                    what
+                )));
+            }
+            cert.verify_issued_by(issuer)
+                .map_err(|e| chain_error(&what, e))?;
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Test fixtures
+// =============================================================================
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    //! Documents signed by the test chain in testdata/attestation.
+
+    use super::*;
+    use p384::pkcs8::DecodePrivateKey;
+
+    /// A document payload with placeholder certificates.
+    pub(crate) fn payload() -> Value {
+        cbor::map(vec![
+            ("module_id", cbor::text("i-0123-enc0123")),
+            ("digest", cbor::text("SHA384")),
+            ("timestamp", Value::Integer(1_700_000_000_000u64.into())),
+            (
+                "pcrs",
+                Value::Map(vec![
+                    (Value::Integer(0.into()), Value::Bytes(vec![1; 48])),
+                    (Value::Integer(16.into()), Value::Bytes(vec![0; 48])),
+                ]),
+            ),
+            ("certificate", Value::Bytes(vec![0x30, 0x82])),
+            (
+                "cabundle",
+                Value::Array(vec![Value::Bytes(vec![0x30, 0x01])]),
+            ),
+            ("public_key", Value::Null),
+            ("user_data", Value::Bytes(b"hello".to_vec())),
+            ("nonce", Value::Bytes(vec![9; 8])),
+        ])
+    }
+
+    /// 2030-01-01T00:00:00Z
+    pub(crate) const JAN_2030_MS: i64 = 1_893_456_000_000;
+
+    pub(crate) fn pem_der(pem: &str) -> Vec<u8> {
+        pem_rfc7468::decode_vec(pem.as_bytes()).unwrap().1
+    }
+
+    pub(crate) fn test_root() -> Certificate {
+        Certificate::from_der(&pem_der(include_str!("../testdata/attestation/root.pem"))).unwrap()
+    }
+
+    /// A document signed by the test leaf, chained root-first.
+    pub(crate) fn signed_document(edit: impl FnOnce(&mut Vec<(Value, Value)>)) -> Vec<u8> {
+        let mut doc = payload();
+        if let Value::Map(entries) = &mut doc {
+            for (key, value) in entries.iter_mut() {
+                if key == &cbor::text("certificate") {
+                    *value =
+                        Value::Bytes(pem_der(include_str!("../testdata/attestation/leaf.pem")));
+                } else if key == &cbor::text("cabundle") {
+                    *value = Value::Array(vec![
+                        Value::Bytes(pem_der(include_str!("../testdata/attestation/root.pem"))),
+                        Value::Bytes(pem_der(include_str!(
+                            "../testdata/attestation/intermediate.pem"
+                        ))),
+                    ]);
+                }
+            }
+            edit(entries);
+        }
+        sign_payload(&cbor::encode(&doc).unwrap(), COSE_ALG_ES384)
+    }
+
+    pub(crate) fn sign_payload(payload: &[u8], alg: i64) -> Vec<u8> {
+        let key =
+            SigningKey::from_pkcs8_pem(include_str!("../testdata/attestation/leaf.key")).unwrap();
+        CoseSign1::sign(payload, alg, &key).unwrap()
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sign1(payload: &Value, tagged: bool) -> Vec<u8> {
+        let array = Value::Array(vec![
+            Value::Bytes(vec![0xa1, 0x01, 0x38, 0x22]),
+            Value::Map(vec![]),
+            Value::Bytes(cbor::encode(payload).unwrap()),
+            Value::Bytes(vec![7; 96]),
+        ]);
+        let value = if tagged {
+            Value::Tag(COSE_SIGN1_TAG, Box::new(array))
+        } else {
+            array
+        };
+        cbor::encode(&value).unwrap()
+    }
+
+    #[test]
+    fn parses_tagged_and_untagged_documents() {
+        for tagged in [true, false] {
+            let envelope = CoseSign1::parse(&sign1(&payload(), tagged)).unwrap();
+            let doc = Document::parse(&envelope.payload).unwrap();
+            assert_eq!(doc.module_id, "i-0123-enc0123");
+            assert_eq!(doc.timestamp, 1_700_000_000_000);
+            assert_eq!(doc.pcrs.keys().copied().collect::<Vec<_>>(), vec![0, 16]);
+            assert_eq!(doc.pcrs[&0], vec![1; 48]);
+            assert_eq!(doc.cabundle, vec![vec![0x30, 0x01]]);
+            assert_eq!(doc.public_key, None);
+            assert_eq!(doc.user_data.as_deref(), Some(&b"hello"[..]));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_envelopes() {
+        let not_array = cbor::encode(&Value::Bytes(vec![1])).unwrap();
+        assert!(CoseSign1::parse(&not_array).is_err());
+        let wrong_tag = cbor::encode(&Value::Tag(17, Box::new(Value::Array(vec![])))).unwrap();
+        let err = CoseSign1::parse(&wrong_tag).unwrap_err();
+        assert!(err.reason.contains("tag 17"), "{}", err.reason);
+    }
+
+    #[test]
+    fn rejects_missing_and_mistyped_fields() {
+        let mut doc = payload();
+        if let Value::Map(entries) = &mut doc {
+            entries.retain(|(k, _)| k != &cbor::text("certificate"));
+        }
+        let err = Document::parse(&cbor::encode(&doc).unwrap()).unwrap_err();
+        assert!(err.reason.contains("missing certificate"), "{}", err.reason);
+
+        let mut doc = payload();
+        if let Value::Map(entries) = &mut doc {
+            for (key, value) in entries.iter_mut() {
+                if key == &cbor::text("nonce") {
+                    *value = cbor::text("not bytes");
+                }
+            }
+        }
+        let err = Document::parse(&cbor::encode(&doc).unwrap()).unwrap_err();
+        assert!(err.reason.contains("invalid nonce"), "{}", err.reason);
+    }
+
+    // -------------------------------------------------------------------------
+    // Verification, against the test chain in testdata/attestation
+    // -------------------------------------------------------------------------
+
+    fn expect(
+        nonce: Option<Vec<u8>>,
+        pcrs: Option<Vec<(u32, Vec<u8>)>>,
+        time_ms: i64,
+    ) -> Expectations {
+        Expectations {
+            nonce,
+            pcrs: pcrs.map(|p| p.into_iter().collect()),
+            max_age_ms: None,
+            time_ms,
+        }
+    }
+
+    #[test]
+    fn embedded_aws_root_matches_published_fingerprint() {
+        let root = aws_nitro_root().unwrap();
+        let fingerprint: String = Sha256::digest(&root.der)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            fingerprint,
+            "641a0321a3e244efe456463195d606317ed7cdcc3c1756e09893f3c68f79bb5b"
+        );
+        assert!(root.is_ca);
+    }
+
+    #[test]
+    fn valid_document_passes_every_check() {
+        let cose = signed_document(|_| {});
+        let expectations = expect(
+            Some(vec![9; 8]),
+            Some(vec![(0, vec![1; 48]), (16, vec![0; 48])]),
+            JAN_2030_MS,
+        );
+        let verdict = verify(&cose, &expectations, &[test_root()]).unwrap();
+        assert!(verdict.valid(), "{:?}", verdict.errors);
+        assert!(verdict.signature_valid && verdict.chain_valid);
+        assert_eq!(verdict.nonce_valid, Some(true));
+        assert_eq!(verdict.pcrs_valid, Some(true));
+        assert_eq!(verdict.document.module_id, "i-0123-enc0123");
+
+        // Optional checks are skipped, not passed, when not requested
+        let verdict = verify(&cose, &expect(None, None, JAN_2030_MS), &[test_root()]).unwrap();
+        assert!(verdict.valid());
+        assert_eq!(verdict.nonce_valid, None);
+        assert_eq!(verdict.pcrs_valid, None);
+        assert_eq!(verdict.fresh, None);
+    }
+
+    #[test]
+    fn document_age_is_checked_at_the_evaluation_time() {
+        let cose = signed_document(|entries| {
+            for (key, value) in entries.iter_mut() {
+                if key == &cbor::text("timestamp") {
+                    *value = Value::Integer(JAN_2030_MS.into());
+                }
+            }
+        });
+        let at = |time_ms: i64| {
+            let expectations = Expectations {
+                max_age_ms: Some(60_000),
+                ..expect(None, None, time_ms)
+            };
+            verify(&cose, &expectations, &[test_root()]).unwrap()
+        };
+
+        let verdict = at(JAN_2030_MS + 60_000);
+        assert!(verdict.valid(), "{:?}", verdict.errors);
+        assert_eq!(verdict.fresh, Some(true));
+
+        let verdict = at(JAN_2030_MS + 60_001);
+        assert_eq!(verdict.fresh, Some(false));
+        assert!(verdict.chain_valid);
+        assert_eq!(
+            verdict.errors,
+            vec!["Document is 60001ms old, more than the 60000ms allowed"]
+        );
+
+        let verdict = at(JAN_2030_MS - MAX_CLOCK_SKEW_MS);
+        assert_eq!(verdict.fresh, Some(true));
+
+        let verdict = at(JAN_2030_MS - MAX_CLOCK_SKEW_MS - 1);
+        assert_eq!(verdict.fresh, Some(false));
+        assert_eq!(
+            verdict.errors,
+            vec!["Document is dated 5001ms in the future, more than the 5000ms clock skew allowed"]
+        );
+    }
+
+    #[test]
+    fn tampered_payload_fails_the_signature() {
+        let cose = signed_document(|_| {});
+        let mut sign1 = CoseSign1::parse(&cose).unwrap();
+        let last = sign1.payload.len() - 1;
+        sign1.payload[last] ^= 1; // inside the nonce
+        let tampered = cbor::encode(&Value::Array(vec![
+            Value::Bytes(sign1.protected),
+            Value::Map(vec![]),
+            Value::Bytes(sign1.payload),
+            Value::Bytes(sign1.signature),
+        ]))
+        .unwrap();
+
+        let verdict = verify(&tampered, &expect(None, None, JAN_2030_MS), &[test_root()]).unwrap();
+        assert!(!verdict.valid());
+        assert!(!verdict.signature_valid);
+        assert!(verdict.chain_valid);
+    }
+
+    #[test]
+    fn non_es384_algorithm_is_rejected() {
+        let payload = CoseSign1::parse(&signed_document(|_| {})).unwrap().payload;
+        let cose = sign_payload(&payload, -7);
+        let verdict = verify(&cose, &expect(None, None, JAN_2030_MS), &[test_root()]).unwrap();
+        assert!(!verdict.signature_valid);
+        assert!(
+            verdict.errors[0].contains("not ES384"),
+            "{:?}",
+            verdict.errors
+        );
+    }
+
+    #[test]
+    fn mismatched_nonce_and_pcrs_are_reported() {
+        let cose = signed_document(|_| {});
+        let expectations = expect(
+            Some(vec![1; 8]),
+            Some(vec![(0, vec![1; 48]), (16, vec![2; 48]), (17, vec![0; 48])]),
+            JAN_2030_MS,
+        );
+        let verdict = verify(&cose, &expectations, &[test_root()]).unwrap();
+        assert!(verdict.signature_valid && verdict.chain_valid);
+        assert_eq!(verdict.nonce_valid, Some(false));
+        assert_eq!(verdict.pcrs_valid, Some(false));
+        assert_eq!(
+            verdict.errors,
+            vec!["Nonce does not match expectedNonce", "PCR mismatch: 16, 17"]
+        );
+    }
+
+    #[test]
+    fn chain_must_reach_a_trusted_root_in_time() {
+        let cose = signed_document(|_| {});
+
+        let verdict = verify(
+            &cose,
+            &expect(None, None, JAN_2030_MS),
+            &[aws_nitro_root().unwrap()],
+        )
+        .unwrap();
+        assert!(verdict.signature_valid);
+        assert!(!verdict.chain_valid);
+        assert!(
+            verdict.errors[0].contains("not a trusted root"),
+            "{:?}",
+            verdict.errors
+        );
+
+        // The leaf expires in 2045; 2050-01-01
+        let verdict = verify(
+            &cose,
+            &expect(None, None, 2_524_608_000_000),
+            &[test_root()],
+        )
+        .unwrap();
+        assert!(!verdict.chain_valid);
+        assert!(
+            verdict.errors[0].contains("signing certificate is not valid"),
+            "{:?}",
+            verdict.errors
+        );
+    }
+
+    #[test]
+    fn custom_roots_replace_or_extend_the_aws_root() {
+        let cose = signed_document(|_| {});
+        let test_root_pem = include_str!("../testdata/attestation/root.pem").as_bytes();
+
+        let roots = trust_anchors(&[test_root_pem], true).unwrap();
+        assert_eq!(roots.len(), 2);
+        let verdict = verify(&cose, &expect(None, None, JAN_2030_MS), &roots).unwrap();
+        assert!(verdict.valid(), "{:?}", verdict.errors);
+
+        let der = test_root().der;
+        let roots = trust_anchors(&[&der], false).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert!(verify(&cose, &expect(None, None, JAN_2030_MS), &roots)
+            .unwrap()
+            .valid());
+
+        let err = trust_anchors(&[], false).err().unwrap();
+        assert!(err.reason.contains("No trust anchors"), "{}", err.reason);
+        let err = trust_anchors(&[b"not a certificate"], true).err().unwrap();
+        assert!(err.reason.starts_with("trustedRoots[0]"), "{}", err.reason);
+    }
+
+    #[test]
+    fn cabundle_must_link_every_certificate() {
+        // Intermediate missing: the leaf is not issued by the root
+        let cose = signed_document(|entries| {
+            for (key, value) in entries.iter_mut() {
+                if key == &cbor::text("cabundle") {
+                    if let Value::Array(items) = value {
+                        items.pop();
+                    }
+                }
+            }
+        });
+        let verdict = verify(&cose, &expect(None, None, JAN_2030_MS), &[test_root()]).unwrap();
+        assert!(!verdict.chain_valid);
+        assert!(
+            verdict.errors[0].contains("signing certificate"),
+            "{:?}",
+            verdict.errors
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Opt-in cache of attestation documents.
+//!
+//! Servers often attest every connection even though a document stays
+//! acceptable to verifiers for a while. With `cacheTtlMs`, attestation()
+//! reuses a document from the same NSM for the same (userData, publicKey)
+//! until it is that old, saving an NSM round trip. Requests with a nonce always go to the
+//! NSM: the nonce exists to prove the document is fresh.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::nsm_mock::MockConfig;
+
+/// Most documents kept; the oldest is evicted beyond this.
+const MAX_ENTRIES: usize = 64;
+
+static CACHE: OnceLock<Mutex<AttestationCache>> = OnceLock::new();
+
+/// Which NSM a document came from. Mocks with the same configuration
+/// produce interchangeable documents; a TYTLE_NSM_MOCK mock replaces every
+/// device path alike, so it is keyed by path.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Source {
+    Device(String),
+    Mock(MockConfig),
+}
+
+/// (source, user_data, public_key)
+type Key = (Source, Option<Vec<u8>>, Option<Vec<u8>>);
+
+#[derive(Default)]
+pub(crate) struct AttestationCache {
+    entries: HashMap<Key, (Instant, Vec<u8>)>,
+}
+
+impl AttestationCache {
+    /// The document cached for `key`, if fetched less than `ttl` before `now`.
+    fn get(&self, key: &Key, ttl: Duration, now: Instant) -> Option<Vec<u8>> {
+        self.entries
+            .get(key)
+            .filter(|(fetched, _)| now.saturating_duration_since(*fetched) < ttl)
+            .map(|(_, document)| document.clone())
+    }
+
+    fn insert(&mut self, key: Key, document: Vec<u8>, now: Instant) {
+        if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (fetched, _))| *fetched)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (now, document));
+    }
+}
+
+fn cache() -> &'static Mutex<AttestationCache> {
+    CACHE.get_or_init(Mutex::default)
+}
+
+/// The cached document for (`source`, `user_data`, `public_key`) if
+/// younger than `ttl`, else `fetch()`'s, which is cached. The lock is not
+/// held during `fetch`, so concurrent misses may each reach the NSM.
+pub(crate) fn cached(
+    source: Source,
+    user_data: Option<&[u8]>,
+    public_key: Option<&[u8]>,
+    ttl: Duration,
+    fetch: impl FnOnce() -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let key = (
+        source,
+        user_data.map(<[u8]>::to_vec),
+        public_key.map(<[u8]>::to_vec),
+    );
+    if let Some(document) = cache().lock().unwrap().get(&key, ttl, Instant::now()) {
+        return Ok(document);
+    }
+    let document = fetch()?;
+    cache()
+        .lock()
+        .unwrap()
+        .insert(key, document.clone(), Instant::now());
+    Ok(document)
+}
+
+/// Drop every cached attestation document. extendPcr() does this itself,
+/// so later documents report the new PCR value.
+#[napi]
+pub fn clear_attestation_cache() {
+    cache().lock().unwrap().entries.clear();
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(user_data: &[u8]) -> Key {
+        (
+            Source::Device("/dev/nsm".to_string()),
+            Some(user_data.to_vec()),
+            None,
+        )
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let mut cache = AttestationCache::default();
+        let start = Instant::now();
+        cache.insert(key(b"a"), vec![1], start);
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            cache.get(&key(b"a"), ttl, start + Duration::from_secs(59)),
+            Some(vec![1])
+        );
+        assert_eq!(cache.get(&key(b"a"), ttl, start + ttl), None);
+        assert_eq!(cache.get(&key(b"b"), ttl, start), None);
+    }
+
+    #[test]
+    fn the_oldest_entry_is_evicted_when_full() {
+        let mut cache = AttestationCache::default();
+        let start = Instant::now();
+        for i in 0..MAX_ENTRIES {
+            cache.insert(
+                key(&[i as u8]),
+                vec![i as u8],
+                start + Duration::from_secs(i as u64),
+            );
+        }
+        cache.insert(key(b"new"), vec![0xff], start + Duration::from_secs(1000));
+
+        let ttl = Duration::from_secs(3600);
+        let now = start + Duration::from_secs(1000);
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+        assert_eq!(cache.get(&key(&[0]), ttl, now), None);
+        assert_eq!(cache.get(&key(&[1]), ttl, now), Some(vec![1]));
+        assert_eq!(cache.get(&key(b"new"), ttl, now), Some(vec![0xff]));
+    }
+
+    #[test]
+    fn documents_are_cached_per_nsm() {
+        let mut cache = AttestationCache::default();
+        let start = Instant::now();
+        cache.insert(key(b"a"), vec![1], start);
+
+        let ttl = Duration::from_secs(60);
+        let from = |source| (source, Some(b"a".to_vec()), None);
+        let remapped = from(Source::Device("/dev/nsm0".to_string()));
+        let mock = from(Source::Mock(MockConfig::default()));
+        assert_eq!(cache.get(&remapped, ttl, start), None);
+        assert_eq!(cache.get(&mock, ttl, start), None);
+
+        cache.insert(mock.clone(), vec![2], start);
+        assert_eq!(cache.get(&mock, ttl, start), Some(vec![2]));
+        assert_eq!(cache.get(&key(b"a"), ttl, start), Some(vec![1]));
+    }
+}
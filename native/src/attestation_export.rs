@@ -0,0 +1,183 @@
+//! Attestation documents in formats people can read and paste.
+//!
+//! attestationToJson() decodes a document, without verifying it, into JSON
+//! for logs, audit trails and support tickets: PCRs and binary fields as
+//! hex, the timestamp and certificate validity as ISO 8601, and each
+//! certificate's subject, issuer and SHA-256 fingerprint (with its PEM, for
+//! `openssl x509`). The raw document rides along as base64, so the JSON is
+//! enough to re-verify it later. attestationToPem() and
+//! attestationToBase64() just wrap the bytes:
+//!
+//! ```js
+//! const doc = await nsm.attestation({ nonce });
+//! logger.info({ attestation: JSON.parse(attestationToJson(doc)) });
+//! fs.writeFileSync('attestation.pem', attestationToPem(doc));
+//! ```
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::attestation::{CoseSign1, Document};
+use crate::sigv4::{civil_from_days, hex};
+use crate::x509::{self, Certificate};
+
+/// PEM label of attestationToPem() output.
+const PEM_LABEL: &str = "NITRO ATTESTATION DOCUMENT";
+
+#[napi(object)]
+pub struct AttestationJsonOptions {
+    /// Indent the JSON (default false: one line, for log records).
+    pub pretty: Option<bool>,
+}
+
+/// Decode an attestation document (COSE_Sign1 bytes) into a JSON string
+/// for humans. Does not verify it; see verifyAttestation().
+#[napi]
+pub fn attestation_to_json(
+    document: Buffer,
+    options: Option<AttestationJsonOptions>,
+) -> Result<String> {
+    let view = json_view(&document)?;
+    let pretty = options.and_then(|o| o.pretty).unwrap_or(false);
+    let json = if pretty {
+        serde_json::to_string_pretty(&view)
+    } else {
+        serde_json::to_string(&view)
+    };
+    json.map_err(|e| Error::from_reason(format!("JSON encoding failed: {}", e)))
+}
+
+/// The document as a PEM block, as attestationToBase64() plus armor.
+#[napi]
+pub fn attestation_to_pem(document: Buffer) -> Result<String> {
+    CoseSign1::parse(&document)?;
+    pem(PEM_LABEL, &document)
+}
+
+/// The document's bytes as standard base64, e.g. for an HTTP header.
+#[napi]
+pub fn attestation_to_base64(document: Buffer) -> Result<String> {
+    CoseSign1::parse(&document)?;
+    Ok(BASE64.encode(&document[..]))
+}
+
+fn json_view(cose: &[u8]) -> Result<Value> {
+    let doc = Document::parse(&CoseSign1::parse(cose)?.payload)?;
+    let pcrs: serde_json::Map<_, _> = doc
+        .pcrs
+        .iter()
+        .map(|(index, value)| (index.to_string(), Value::from(hex(value))))
+        .collect();
+    let cabundle = doc
+        .cabundle
+        .iter()
+        .map(|der| certificate_view(der))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(json!({
+        "moduleId": doc.module_id,
+        "digest": doc.digest,
+        "timestamp": iso_time(doc.timestamp as i64),
+        "timestampMs": doc.timestamp,
+        "pcrs": pcrs,
+        "certificate": certificate_view(&doc.certificate)?,
+        "cabundle": cabundle,
+        "publicKey": doc.public_key.as_deref().map(hex),
+        "userData": doc.user_data.as_deref().map(hex),
+        "userDataText": doc.user_data.as_deref().and_then(|b| std::str::from_utf8(b).ok()),
+        "nonce": doc.nonce.as_deref().map(hex),
+        "raw": BASE64.encode(cose),
+    }))
+}
+
+fn certificate_view(der: &[u8]) -> Result<Value> {
+    let cert = Certificate::from_der(der)?;
+    Ok(json!({
+        "subject": x509::name_to_string(&cert.subject)?,
+        "issuer": x509::name_to_string(&cert.issuer)?,
+        "notBefore": iso_time(cert.not_before_ms),
+        "notAfter": iso_time(cert.not_after_ms),
+        "isCa": cert.is_ca,
+        "sha256": hex(&Sha256::digest(der)),
+        "pem": pem("CERTIFICATE", der)?,
+    }))
+}
+
+fn pem(label: &str, der: &[u8]) -> Result<String> {
+    pem_rfc7468::encode_string(label, pem_rfc7468::LineEnding::LF, der)
+        .map_err(|e| Error::from_reason(format!("PEM encoding failed: {}", e)))
+}
+
+/// `time_ms` as Date.prototype.toISOString() prints it.
+pub(crate) fn iso_time(time_ms: i64) -> String {
+    let seconds = time_ms.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        time_ms.rem_euclid(1000)
+    )
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::fixtures::*;
+
+    #[test]
+    fn json_view_decodes_every_field() {
+        let cose = signed_document(|_| {});
+        let view = json_view(&cose).unwrap();
+        assert_eq!(view["moduleId"], "i-0123-enc0123");
+        assert_eq!(view["timestamp"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(view["pcrs"]["0"], "01".repeat(48));
+        assert_eq!(view["pcrs"]["16"], "00".repeat(48));
+        assert_eq!(view["userData"], "68656c6c6f");
+        assert_eq!(view["userDataText"], "hello");
+        assert_eq!(view["publicKey"], Value::Null);
+        assert_eq!(
+            view["certificate"]["subject"],
+            "C=US, O=Test, CN=test.enclave"
+        );
+        assert_eq!(view["certificate"]["notAfter"], "2045-01-01T00:00:00.000Z");
+        assert_eq!(view["cabundle"][0]["subject"], "C=US, O=Test, CN=test.root");
+        assert_eq!(view["cabundle"][1]["isCa"], true);
+        let pem = view["certificate"]["pem"].as_str().unwrap();
+        assert_eq!(
+            pem_der(pem),
+            pem_der(include_str!("../testdata/attestation/leaf.pem"))
+        );
+        assert_eq!(BASE64.decode(view["raw"].as_str().unwrap()).unwrap(), cose);
+
+        assert!(json_view(b"not a document").is_err());
+    }
+
+    #[test]
+    fn iso_times_match_javascript() {
+        assert_eq!(iso_time(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso_time(JAN_2030_MS + 1_234), "2030-01-01T00:00:01.234Z");
+        assert_eq!(iso_time(-1), "1969-12-31T23:59:59.999Z");
+    }
+
+    #[test]
+    fn pem_armor_round_trips() {
+        let cose = signed_document(|_| {});
+        let armored = pem(PEM_LABEL, &cose).unwrap();
+        assert!(armored.starts_with("-----BEGIN NITRO ATTESTATION DOCUMENT-----\n"));
+        let (label, der) = pem_rfc7468::decode_vec(armored.as_bytes()).unwrap();
+        assert_eq!((label, der), (PEM_LABEL, cose));
+    }
+}
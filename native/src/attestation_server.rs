@@ -0,0 +1,195 @@
+//! Ready-made attestation challenge/response endpoint.
+//!
+//! Almost every deployment needs the same thing: the host connects, sends
+//! a fresh nonce, and gets back an attestation document carrying it.
+//! AttestationServer answers that natively, one request per connection:
+//!
+//! - host → enclave: a frame holding CBOR `{"nonce": bytes}`
+//!   (1 to 512 bytes).
+//! - enclave → host: `{"attestation": bytes}`, or `{"error": text}`; the
+//!   enclave then closes the connection.
+//!
+//! The document also carries the `publicKey` and `userData` given at bind
+//! time, e.g. an AttestedKeypair's public key or a TLS key the host should
+//! trust.
+//!
+//! ```js
+//! const server = AttestationServer.bind(5005, { publicKey: keypair.publicKey });
+//! ```
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::Arc;
+
+use crate::nsm::{coded, Device, DeviceConfig, NsmOptions};
+use crate::server::AcceptLoop;
+use crate::{cbor, framing, vsock};
+
+/// Same limit the NSM applies to attestation nonces.
+const MAX_NONCE_SIZE: usize = 512;
+
+#[napi(object)]
+pub struct AttestationServerOptions {
+    /// Included as public_key in every document.
+    pub public_key: Option<Buffer>,
+    /// Included as user_data in every document.
+    pub user_data: Option<Buffer>,
+    pub nsm: Option<NsmOptions>,
+}
+
+/// napi-free core of AttestationServer: what each document carries.
+struct Responder {
+    device: Device,
+    public_key: Option<Vec<u8>>,
+    user_data: Option<Vec<u8>>,
+}
+
+impl Responder {
+    fn respond(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let nonce = request_nonce(&cbor::decode(request)?)?;
+        self.device.attestation(
+            self.user_data.as_deref(),
+            Some(&nonce),
+            self.public_key.as_deref(),
+        )
+    }
+}
+
+/// Native vsock endpoint handing out attestation documents for host
+/// nonces. See the module docs for the protocol.
+#[napi]
+pub struct AttestationServer {
+    accept_loop: AcceptLoop,
+    port: u32,
+}
+
+#[napi]
+impl AttestationServer {
+    /// Open the NSM, bind `port` and start serving on native threads.
+    #[napi(factory)]
+    pub fn bind(port: u32, options: Option<AttestationServerOptions>) -> coded::Result<Self> {
+        let (public_key, user_data, nsm) = match options {
+            Some(o) => (o.public_key, o.user_data, o.nsm),
+            None => (None, None, None),
+        };
+        let responder = Arc::new(Responder {
+            device: DeviceConfig::from_js(nsm)
+                .and_then(Device::open_with)
+                .map_err(coded)?,
+            public_key: public_key.map(|key| key.to_vec()),
+            user_data: user_data.map(|data| data.to_vec()),
+        });
+        let fd = vsock::listen_raw(port).map_err(coded)?;
+        Ok(AttestationServer {
+            accept_loop: AcceptLoop::spawn(fd, move |conn, _cid, _port| {
+                serve_connection(conn, &responder)
+            }).map_err(coded)?,
+            port,
+        })
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Answer one request on `fd`; the accept loop closes it afterwards.
+fn serve_connection(fd: i32, responder: &Responder) {
+    let Ok(Some(request)) = framing::read_frame(fd) else {
+        return;
+    };
+    let response = match responder.respond(&request) {
+        Ok(document) => cbor::map(vec![("attestation", Value::Bytes(document))]),
+        Err(e) => cbor::map(vec![("error", Value::Text(e.reason))]),
+    };
+    if let Ok(bytes) = cbor::encode(&response) {
+        let _ = framing::write_frame(fd, &bytes);
+    }
+}
+
+fn request_nonce(request: &Value) -> Result<Vec<u8>> {
+    match cbor::map_get(request, "nonce") {
+        Some(Value::Bytes(nonce)) if (1..=MAX_NONCE_SIZE).contains(&nonce.len()) => {
+            Ok(nonce.clone())
+        }
+        Some(Value::Bytes(nonce)) => Err(Error::from_reason(format!(
+            "nonce must be 1 to {} bytes, got {}",
+            MAX_NONCE_SIZE,
+            nonce.len()
+        ))),
+        _ => Err(Error::from_reason(
+            "attestation request must be a CBOR map with a byte string nonce",
+        )),
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{CoseSign1, Document};
+    use crate::test_support::mock_device;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn responder() -> Responder {
+        Responder {
+            device: mock_device(),
+            public_key: Some(b"host-trusted key".to_vec()),
+            user_data: None,
+        }
+    }
+
+    /// Send `request` to a served connection and return the response.
+    fn exchange(request: &Value) -> Value {
+        let (host, enclave) = UnixStream::pair().unwrap();
+        let responder = responder();
+        let server = std::thread::spawn(move || serve_connection(enclave.as_raw_fd(), &responder));
+        framing::write_frame(host.as_raw_fd(), &cbor::encode(request).unwrap()).unwrap();
+        let response = framing::read_frame(host.as_raw_fd()).unwrap().unwrap();
+        server.join().unwrap();
+        cbor::decode(&response).unwrap()
+    }
+
+    #[test]
+    fn answers_a_nonce_with_an_attestation_document() {
+        let response = exchange(&cbor::map(vec![("nonce", Value::Bytes(vec![9; 32]))]));
+        let document = cbor::map_get(&response, "attestation")
+            .and_then(cbor::as_bytes)
+            .unwrap();
+        let sign1 = CoseSign1::parse(&document).unwrap();
+        let document = Document::parse(&sign1.payload).unwrap();
+        assert_eq!(document.nonce, Some(vec![9; 32]));
+        assert_eq!(
+            document.public_key.as_deref(),
+            Some(&b"host-trusted key"[..])
+        );
+    }
+
+    #[test]
+    fn rejects_requests_without_a_usable_nonce() {
+        for request in [
+            cbor::map(vec![]),
+            cbor::map(vec![("nonce", cbor::text("abc"))]),
+            cbor::map(vec![("nonce", Value::Bytes(vec![]))]),
+            cbor::map(vec![("nonce", Value::Bytes(vec![0; 513]))]),
+        ] {
+            let response = exchange(&request);
+            let error = cbor::map_get(&response, "error").and_then(cbor::as_text);
+            assert!(error.is_some_and(|e| e.contains("nonce")), "{:?}", response);
+        }
+    }
+}
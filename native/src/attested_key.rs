@@ -0,0 +1,259 @@
+//! Attestation-bound ephemeral keypairs.
+//!
+//! generateAttestedKeypair() creates an X25519 or P-384 keypair whose
+//! private half never leaves native memory, and requests an attestation
+//! document carrying the public key. A verifier that accepts the document
+//! can agree a key with the enclave (ECDH against `publicKey`) knowing only
+//! that enclave holds the other half. Private keys are zeroized when the
+//! keypair is destroyed or garbage collected.
+//!
+//! ```js
+//! const keypair = generateAttestedKeypair('X25519', { nonce });
+//! send({ attestation: keypair.attestation });
+//! const secret = keypair.deriveSharedSecret(peerPublicKey); // feed to HKDF
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rand_core::OsRng;
+use std::sync::Mutex;
+
+use crate::nsm::{self, coded, AttestationOptions, Device, NsmOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    X25519,
+    P384,
+}
+
+impl Algorithm {
+    fn parse(name: Option<&str>) -> Result<Self> {
+        match name.unwrap_or("X25519") {
+            "X25519" => Ok(Algorithm::X25519),
+            "P-384" => Ok(Algorithm::P384),
+            other => Err(Error::from_reason(format!(
+                "UnsupportedAlgorithm: {} (expected \"X25519\" or \"P-384\")",
+                other
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::X25519 => "X25519",
+            Algorithm::P384 => "P-384",
+        }
+    }
+}
+
+/// The private half. Both types zeroize themselves on drop.
+enum Secret {
+    X25519(x25519_dalek::StaticSecret),
+    P384(p384::ecdh::EphemeralSecret),
+}
+
+/// napi-free core of AttestedKeypair.
+struct Keypair {
+    algorithm: Algorithm,
+    /// X25519: 32 bytes. P-384: 97-byte uncompressed SEC1 point.
+    public_key: Vec<u8>,
+    /// None once destroyed.
+    secret: Mutex<Option<Secret>>,
+}
+
+impl Keypair {
+    fn generate(algorithm: Algorithm) -> Self {
+        let (secret, public_key) = match algorithm {
+            Algorithm::X25519 => {
+                let secret = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+                let public_key = x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec();
+                (Secret::X25519(secret), public_key)
+            }
+            Algorithm::P384 => {
+                let secret = p384::ecdh::EphemeralSecret::random(&mut OsRng);
+                let public_key = p384::EncodedPoint::from(secret.public_key());
+                (Secret::P384(secret), public_key.as_bytes().to_vec())
+            }
+        };
+        Keypair {
+            algorithm,
+            public_key,
+            secret: Mutex::new(Some(secret)),
+        }
+    }
+
+    /// The raw ECDH shared secret with `peer`: 32 bytes for X25519, the
+    /// 48-byte x-coordinate for P-384.
+    fn derive(&self, peer: &[u8]) -> Result<Vec<u8>> {
+        let secret = self.secret.lock().unwrap();
+        match secret.as_ref() {
+            None => Err(Error::from_reason("Keypair has been destroyed")),
+            Some(Secret::X25519(secret)) => {
+                let peer: [u8; 32] = peer
+                    .try_into()
+                    .map_err(|_| Error::from_reason("X25519 peer public key must be 32 bytes"))?;
+                let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(peer));
+                if !shared.was_contributory() {
+                    return Err(Error::from_reason("X25519 peer public key has low order"));
+                }
+                Ok(shared.as_bytes().to_vec())
+            }
+            Some(Secret::P384(secret)) => {
+                let peer = p384::PublicKey::from_sec1_bytes(peer).map_err(|_| {
+                    Error::from_reason("P-384 peer public key is not a valid SEC1 point")
+                })?;
+                Ok(secret.diffie_hellman(&peer).raw_secret_bytes().to_vec())
+            }
+        }
+    }
+
+    fn destroy(&self) {
+        self.secret.lock().unwrap().take();
+    }
+}
+
+/// An ephemeral keypair whose public key is bound into an attestation
+/// document.
+#[napi]
+pub struct AttestedKeypair {
+    inner: Keypair,
+    attestation: Vec<u8>,
+}
+
+/// Generate an `algorithm` ("X25519", the default, or "P-384") keypair and
+/// attest its public key. `options.nonce` and `options.userData` are bound
+/// into the document too; `publicKey` must not be set, and `cacheTtlMs` is
+/// ignored since every keypair is new.
+#[napi]
+pub fn generate_attested_keypair(
+    algorithm: Option<String>,
+    options: Option<AttestationOptions>,
+    nsm_options: Option<NsmOptions>,
+) -> coded::Result<AttestedKeypair> {
+    let algorithm = Algorithm::parse(algorithm.as_deref()).map_err(coded)?;
+    if options.as_ref().is_some_and(|o| o.public_key.is_some()) {
+        return Err(coded(Error::from_reason(
+            "publicKey is set by generateAttestedKeypair",
+        )));
+    }
+    nsm::with_device(nsm_options, |device| {
+        let options = options.as_ref();
+        attest(
+            device,
+            algorithm,
+            options.and_then(|o| o.user_data.as_deref()),
+            options.and_then(|o| o.nonce.as_deref()),
+        )
+    })
+    .map(|(inner, attestation)| AttestedKeypair { inner, attestation })
+}
+
+fn attest(
+    device: &Device,
+    algorithm: Algorithm,
+    user_data: Option<&[u8]>,
+    nonce: Option<&[u8]>,
+) -> Result<(Keypair, Vec<u8>)> {
+    let keypair = Keypair::generate(algorithm);
+    let attestation = device.attestation(user_data, nonce, Some(&keypair.public_key))?;
+    Ok((keypair, attestation))
+}
+
+#[napi]
+impl AttestedKeypair {
+    /// "X25519" or "P-384".
+    #[napi(getter)]
+    pub fn algorithm(&self) -> String {
+        self.inner.algorithm.name().to_string()
+    }
+
+    /// X25519: the 32-byte public key. P-384: the uncompressed SEC1 point.
+    #[napi(getter)]
+    pub fn public_key(&self) -> Buffer {
+        self.inner.public_key.clone().into()
+    }
+
+    /// The COSE_Sign1 attestation document whose public_key is `publicKey`.
+    #[napi(getter)]
+    pub fn attestation(&self) -> Buffer {
+        self.attestation.clone().into()
+    }
+
+    /// ECDH with `peerPublicKey` (same encoding as `publicKey`). Returns the
+    /// raw shared secret; derive keys from it with a KDF such as HKDF.
+    #[napi]
+    pub fn derive_shared_secret(&self, peer_public_key: Buffer) -> Result<Buffer> {
+        self.inner.derive(&peer_public_key).map(Buffer::from)
+    }
+
+    /// Zeroize the private key now rather than at garbage collection.
+    /// Later deriveSharedSecret() calls throw.
+    #[napi]
+    pub fn destroy(&self) {
+        self.inner.destroy();
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{CoseSign1, Document};
+    use crate::test_support::mock_device;
+
+    #[test]
+    fn both_sides_derive_the_same_secret() {
+        for algorithm in [Algorithm::X25519, Algorithm::P384] {
+            let a = Keypair::generate(algorithm);
+            let b = Keypair::generate(algorithm);
+            let secret = a.derive(&b.public_key).unwrap();
+            assert_eq!(secret, b.derive(&a.public_key).unwrap());
+            assert_eq!(
+                secret.len(),
+                if algorithm == Algorithm::X25519 {
+                    32
+                } else {
+                    48
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_peer_keys_and_destroyed_keypairs() {
+        let x25519 = Keypair::generate(Algorithm::X25519);
+        assert!(x25519.derive(&[0; 31]).is_err());
+        let err = x25519.derive(&[0; 32]).err().unwrap();
+        assert_eq!(err.reason, "X25519 peer public key has low order");
+
+        let p384 = Keypair::generate(Algorithm::P384);
+        assert!(p384.derive(&[4; 97]).is_err());
+
+        x25519.destroy();
+        let peer = Keypair::generate(Algorithm::X25519).public_key;
+        let err = x25519.derive(&peer).err().unwrap();
+        assert_eq!(err.reason, "Keypair has been destroyed");
+    }
+
+    #[test]
+    fn attestation_carries_the_public_key() {
+        let device = mock_device();
+        let (keypair, attestation) =
+            attest(&device, Algorithm::P384, None, Some(b"nonce")).unwrap();
+        let payload = CoseSign1::parse(&attestation).unwrap().payload;
+        let document = Document::parse(&payload).unwrap();
+        assert_eq!(document.public_key, Some(keypair.public_key));
+        assert_eq!(document.nonce, Some(b"nonce".to_vec()));
+    }
+
+    #[test]
+    fn parses_algorithm_names() {
+        assert_eq!(Algorithm::parse(None).unwrap(), Algorithm::X25519);
+        assert_eq!(Algorithm::parse(Some("P-384")).unwrap(), Algorithm::P384);
+        let err = Algorithm::parse(Some("RSA")).err().unwrap();
+        assert!(err.reason.starts_with("UnsupportedAlgorithm: RSA"));
+    }
+}
@@ -0,0 +1,374 @@
+//! Pending-connection visibility for VsockListeners.
+//!
+//! Connections the kernel has completed but nobody has accept()ed yet wait
+//! in the listen backlog, and once it's full new ones are refused. AF_VSOCK
+//! has no TCP_INFO, so listener.backlogStats() counts them through
+//! vsock_diag (NETLINK_SOCK_DIAG): a queued connection is a connected
+//! socket on the listener's port that no fd refers to yet (inode 0).
+//!
+//! Without vsock_diag (kernel module not loaded, or with the mock backend) the
+//! count falls back to poll() readiness, so `pending` is just 0 or 1 and
+//! `exact` is false. onBacklogPressure() then reports connections that
+//! have been waiting longer than `stallMs` instead:
+//!
+//! ```js
+//! listener.onBacklogPressure(({ pending, backlog }) => pool.grow(), { threshold: 0.5 });
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cancel::Canceller;
+use crate::mock;
+
+pub(crate) const DEFAULT_BACKLOG: u32 = 128;
+const DEFAULT_THRESHOLD: f64 = 0.8;
+const DEFAULT_INTERVAL_MS: u32 = 250;
+const DEFAULT_STALL_MS: u32 = 1000;
+
+#[cfg(target_os = "linux")]
+const NETLINK_SOCK_DIAG: i32 = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HDRLEN: usize = 16;
+const AF_VSOCK: u8 = 40;
+const TCP_ESTABLISHED: u8 = 1;
+/// struct vsock_diag_msg (linux/vm_sockets_diag.h)
+const VSOCK_DIAG_MSG_LEN: usize = 32;
+
+#[napi(object)]
+pub struct BacklogStats {
+    /// Connections waiting to be accepted.
+    pub pending: u32,
+    /// The listen backlog (ListenerOptions.backlog).
+    pub backlog: u32,
+    /// Whether `pending` is a count (vsock_diag) or only 0/1 readiness.
+    pub exact: bool,
+}
+
+#[napi(object)]
+pub struct BacklogPressureOptions {
+    /// Signal once pending reaches this fraction of the backlog (default
+    /// 0.8). Needs an exact count.
+    pub threshold: Option<f64>,
+    /// Without an exact count, signal once connections have been waiting
+    /// this long (default 1000 ms).
+    pub stall_ms: Option<u32>,
+    /// How often to check (default 250 ms).
+    pub interval_ms: Option<u32>,
+}
+
+pub(crate) type PressureCallback = Box<dyn Fn(BacklogStats) + Send>;
+
+/// Count the connections queued on listening socket `fd`, bound to `port`.
+pub(crate) fn backlog_stats(fd: i32, port: u32, backlog: u32) -> BacklogStats {
+    let counted = match mock::backend() {
+        Some(_) => Err(std::io::ErrorKind::Unsupported.into()),
+        None => diag_pending(port),
+    };
+    match counted {
+        Ok(pending) => BacklogStats {
+            pending,
+            backlog,
+            exact: true,
+        },
+        Err(e) => {
+            tracing::trace!(error = %e, "vsock_diag unavailable, using readiness");
+            BacklogStats {
+                pending: u32::from(readable(fd)),
+                backlog,
+                exact: false,
+            }
+        }
+    }
+}
+
+fn readable(fd: i32) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & libc::POLLIN != 0 }
+}
+
+/// Watches a listener from a native thread until `cancel` is cancelled.
+/// Owns `fd`, a dup of the listener's.
+pub(crate) fn spawn_monitor(
+    fd: i32,
+    port: u32,
+    backlog: u32,
+    options: Option<BacklogPressureOptions>,
+    cancel: Arc<Canceller>,
+    on_pressure: PressureCallback,
+) -> Result<()> {
+    let (threshold, stall_ms, interval_ms) = options
+        .map(|o| (o.threshold, o.stall_ms, o.interval_ms))
+        .unwrap_or((None, None, None));
+    let threshold = threshold.unwrap_or(DEFAULT_THRESHOLD);
+    if !(threshold > 0.0 && threshold <= 1.0) {
+        return Err(Error::from_reason("threshold must be in (0, 1]"));
+    }
+    let interval_ms = interval_ms.unwrap_or(DEFAULT_INTERVAL_MS);
+    if interval_ms == 0 {
+        return Err(Error::from_reason("intervalMs must be greater than 0"));
+    }
+    let mut pressure = Pressure {
+        limit: ((threshold * backlog as f64).ceil() as u32).max(1),
+        stall: Duration::from_millis(stall_ms.unwrap_or(DEFAULT_STALL_MS) as u64),
+        waiting_since: None,
+        signalled: false,
+    };
+    std::thread::spawn(move || {
+        // Sleeps between checks (a negative fd is ignored by poll()), but
+        // wakes at once when the listener closes
+        while cancel.wait_timeout(-1, 0, interval_ms as i32).is_ok() {
+            let stats = backlog_stats(fd, port, backlog);
+            if pressure.update(&stats, Instant::now()) {
+                tracing::warn!(
+                    stats.pending,
+                    stats.backlog,
+                    "listen backlog under pressure"
+                );
+                on_pressure(stats);
+            }
+        }
+        unsafe { libc::close(fd) };
+    });
+    Ok(())
+}
+
+/// When to signal backlog pressure: once per episode, re-armed when the
+/// backlog recovers.
+struct Pressure {
+    limit: u32,
+    stall: Duration,
+    waiting_since: Option<Instant>,
+    signalled: bool,
+}
+
+impl Pressure {
+    fn update(&mut self, stats: &BacklogStats, now: Instant) -> bool {
+        let under_pressure = if stats.exact {
+            stats.pending >= self.limit
+        } else if stats.pending > 0 {
+            let since = *self.waiting_since.get_or_insert(now);
+            now.duration_since(since) >= self.stall
+        } else {
+            false
+        };
+        if stats.pending == 0 {
+            self.waiting_since = None;
+        }
+        if !under_pressure {
+            self.signalled = false;
+            return false;
+        }
+        !std::mem::replace(&mut self.signalled, true)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn diag_pending(_port: u32) -> std::io::Result<u32> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Count connected, not yet accepted vsock sockets on `port` through a
+/// vsock_diag dump.
+#[cfg(target_os = "linux")]
+fn diag_pending(port: u32) -> std::io::Result<u32> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let result = diag_dump(fd, port);
+    unsafe { libc::close(fd) };
+    result
+}
+
+#[cfg(target_os = "linux")]
+fn diag_dump(fd: i32, port: u32) -> std::io::Result<u32> {
+    let request = diag_request();
+    if unsafe {
+        libc::send(
+            fd,
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+        )
+    } < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; 32 * 1024];
+    let mut pending = 0;
+    loop {
+        let n = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let (count, done) = count_pending(&buf[..n as usize], port)?;
+        pending += count;
+        if done {
+            return Ok(pending);
+        }
+    }
+}
+
+/// SOCK_DIAG_BY_FAMILY dump request for established vsock sockets.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn diag_request() -> Vec<u8> {
+    let flags = NLM_F_REQUEST | NLM_F_DUMP;
+    let mut request = Vec::with_capacity(NLMSG_HDRLEN + 24);
+    request.extend_from_slice(&((NLMSG_HDRLEN + 24) as u32).to_ne_bytes());
+    request.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    request.extend_from_slice(&flags.to_ne_bytes());
+    request.extend_from_slice(&1u32.to_ne_bytes()); // seq
+    request.extend_from_slice(&0u32.to_ne_bytes()); // pid
+
+    // struct vsock_diag_req
+    request.extend_from_slice(&[AF_VSOCK, 0, 0, 0]);
+    request.extend_from_slice(&(1u32 << TCP_ESTABLISHED).to_ne_bytes()); // vdiag_states
+    request.extend_from_slice(&0u32.to_ne_bytes()); // vdiag_ino
+    request.extend_from_slice(&0u32.to_ne_bytes()); // vdiag_show
+    request.extend_from_slice(&[0xff; 8]); // vdiag_cookie
+    request
+}
+
+/// Count pending connections on `port` in one recv() of dump replies.
+/// Returns whether the dump is done.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn count_pending(mut buf: &[u8], port: u32) -> std::io::Result<(u32, bool)> {
+    let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let u32_at = |b: &[u8], at: usize| u32::from_ne_bytes(b[at..at + 4].try_into().unwrap());
+    let mut pending = 0;
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0) as usize;
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Err(invalid("truncated netlink message"));
+        }
+        let body = &buf[NLMSG_HDRLEN..len];
+        match kind {
+            NLMSG_DONE => return Ok((pending, true)),
+            NLMSG_ERROR => {
+                let errno = body.get(..4).map_or(0, |b| u32_at(b, 0) as i32);
+                return Err(std::io::Error::from_raw_os_error(-errno));
+            }
+            SOCK_DIAG_BY_FAMILY if body.len() >= VSOCK_DIAG_MSG_LEN => {
+                // family, type, state, shutdown, src_cid, src_port,
+                // dst_cid, dst_port, ino, cookie
+                let state = body[2];
+                let src_port = u32_at(body, 8);
+                let ino = u32_at(body, 20);
+                if state == TCP_ESTABLISHED && src_port == port && ino == 0 {
+                    pending += 1;
+                }
+            }
+            _ => {}
+        }
+        // Messages are 4-byte aligned
+        buf = &buf[((len + 3) & !3).min(buf.len())..];
+    }
+    Ok((pending, false))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diag_msg(state: u8, src_port: u32, ino: u32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&((NLMSG_HDRLEN + VSOCK_DIAG_MSG_LEN) as u32).to_ne_bytes());
+        msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(&[AF_VSOCK, 1, state, 0]);
+        for field in [16, src_port, 3, 50000, ino, 0, 0] {
+            msg.extend_from_slice(&field.to_ne_bytes());
+        }
+        msg
+    }
+
+    fn done() -> Vec<u8> {
+        let mut msg = ((NLMSG_HDRLEN + 4) as u32).to_ne_bytes().to_vec();
+        msg.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        msg.extend_from_slice(&[0; 14]);
+        msg
+    }
+
+    #[test]
+    fn diag_replies_count_unaccepted_connections() {
+        let replies = [
+            diag_msg(TCP_ESTABLISHED, 5000, 0),
+            diag_msg(TCP_ESTABLISHED, 5000, 0),
+            // Accepted already: it has an inode
+            diag_msg(TCP_ESTABLISHED, 5000, 81234),
+            // Another listener's queue
+            diag_msg(TCP_ESTABLISHED, 6000, 0),
+        ]
+        .concat();
+        assert_eq!(count_pending(&replies, 5000).unwrap(), (2, false));
+        assert_eq!(count_pending(&done(), 5000).unwrap(), (0, true));
+        assert!(count_pending(&replies[..20], 5000).is_err());
+
+        let mut error = done();
+        error[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        error[16..20].copy_from_slice(&(-libc::ENOENT).to_ne_bytes());
+        let err = count_pending(&error, 5000).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(diag_request().len(), 40);
+    }
+
+    #[test]
+    fn pressure_is_signalled_once_per_episode() {
+        let stats = |pending, exact| BacklogStats {
+            pending,
+            backlog: 10,
+            exact,
+        };
+        let start = Instant::now();
+        let mut pressure = Pressure {
+            limit: 8,
+            stall: Duration::from_millis(500),
+            waiting_since: None,
+            signalled: false,
+        };
+        assert!(!pressure.update(&stats(7, true), start));
+        assert!(pressure.update(&stats(8, true), start));
+        assert!(!pressure.update(&stats(10, true), start));
+        assert!(!pressure.update(&stats(2, true), start));
+        assert!(pressure.update(&stats(9, true), start));
+
+        // Readiness only: connections waiting too long
+        let later = |ms| start + Duration::from_millis(ms);
+        assert!(!pressure.update(&stats(0, false), later(0)));
+        assert!(!pressure.update(&stats(1, false), later(100)));
+        assert!(!pressure.update(&stats(1, false), later(400)));
+        assert!(pressure.update(&stats(1, false), later(600)));
+        assert!(!pressure.update(&stats(1, false), later(900)));
+        assert!(!pressure.update(&stats(0, false), later(1000)));
+        assert!(!pressure.update(&stats(1, false), later(1100)));
+    }
+}
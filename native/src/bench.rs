@@ -0,0 +1,286 @@
+//! Throughput/latency measurement between enclave and host.
+//!
+//! Start a VsockEchoServer on one side and run vsockBenchmark() against it
+//! from the other. The benchmark sends fixed-size messages and waits for
+//! each echo before sending the next, so it reports both round-trip latency
+//! and the throughput achievable over a single vsock connection — a
+//! baseline for the instance type before suspecting the application layer.
+
+use napi::bindgen_prelude::*;
+use napi::Task;
+use napi_derive::napi;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::framing::read_exact;
+use crate::relay::{read_retrying, write_all_retrying};
+use crate::server::AcceptLoop;
+use crate::vsock;
+use crate::workers::WorkerTask;
+
+const DEFAULT_MESSAGE_SIZE: u32 = 4096;
+const DEFAULT_DURATION_MS: u32 = 5000;
+const MAX_MESSAGE_SIZE: u32 = 16 * 1024 * 1024;
+const CONNECT_TIMEOUT_SECS: u32 = 5;
+
+/// Read size of the echo loop.
+const ECHO_BUF_SIZE: usize = 64 * 1024;
+
+#[napi(object)]
+pub struct EchoServerStats {
+    /// Connections accepted since start.
+    pub connections: i64,
+    /// Bytes echoed back.
+    pub bytes: i64,
+}
+
+#[derive(Default)]
+struct EchoCounters {
+    connections: AtomicI64,
+    bytes: AtomicI64,
+}
+
+/// Echoes every byte it receives back to the sender, on native threads.
+#[napi]
+pub struct VsockEchoServer {
+    accept_loop: AcceptLoop,
+    counters: Arc<EchoCounters>,
+}
+
+#[napi]
+impl VsockEchoServer {
+    /// Bind `port` and start echoing.
+    #[napi(factory)]
+    pub fn start(port: u32) -> Result<Self> {
+        let counters = Arc::new(EchoCounters::default());
+        let listener_fd = vsock::listen_raw(port)?;
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn(listener_fd, move |conn, _cid, _port| {
+            loop_counters.connections.fetch_add(1, Ordering::Relaxed);
+            let _ = echo(conn, &loop_counters.bytes);
+        })?;
+
+        Ok(VsockEchoServer { accept_loop, counters })
+    }
+
+    #[napi]
+    pub fn stats(&self) -> EchoServerStats {
+        EchoServerStats {
+            connections: self.counters.connections.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stop accepting. Connections being echoed run until the peer closes.
+    /// Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Echo `fd` until EOF, counting bytes into `bytes`.
+fn echo(fd: i32, bytes: &AtomicI64) -> std::io::Result<()> {
+    let mut buf = vec![0u8; ECHO_BUF_SIZE];
+    loop {
+        let n = read_retrying(fd, &mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        write_all_retrying(fd, &buf[..n])?;
+        bytes.fetch_add(n as i64, Ordering::Relaxed);
+    }
+}
+
+#[napi(object)]
+pub struct BenchmarkOptions {
+    /// Bytes per message (default 4096, max 16 MiB).
+    pub message_size: Option<u32>,
+    /// How long to keep sending (default 5000ms).
+    pub duration_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct BenchmarkResult {
+    /// Messages sent and echoed back.
+    pub round_trips: i64,
+    /// Payload bytes sent (the same number came back).
+    pub bytes: i64,
+    pub duration_ms: f64,
+    /// Payload throughput in each direction, in megabits per second.
+    pub throughput_mbps: f64,
+    pub latency_avg_us: f64,
+    pub latency_p50_us: f64,
+    pub latency_p99_us: f64,
+    pub latency_max_us: f64,
+}
+
+/// Measure round trips against a VsockEchoServer at (cid, port).
+#[napi(ts_return_type = "Promise<BenchmarkResult>")]
+pub fn vsock_benchmark(
+    cid: u32,
+    port: u32,
+    options: Option<BenchmarkOptions>,
+) -> Result<WorkerTask<BenchmarkTask>> {
+    let (message_size, duration_ms) = options
+        .map(|o| (o.message_size, o.duration_ms))
+        .unwrap_or((None, None));
+    let message_size = message_size.unwrap_or(DEFAULT_MESSAGE_SIZE);
+    if message_size == 0 || message_size > MAX_MESSAGE_SIZE {
+        return Err(Error::from_reason(format!(
+            "messageSize must be between 1 and {}",
+            MAX_MESSAGE_SIZE
+        )));
+    }
+    Ok(WorkerTask::new(BenchmarkTask {
+        cid,
+        port,
+        message_size: message_size as usize,
+        duration: Duration::from_millis(duration_ms.unwrap_or(DEFAULT_DURATION_MS) as u64),
+    }))
+}
+
+pub struct BenchmarkTask {
+    cid: u32,
+    port: u32,
+    message_size: usize,
+    duration: Duration,
+}
+
+impl Task for BenchmarkTask {
+    type Output = BenchmarkResult;
+    type JsValue = BenchmarkResult;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let fd = vsock::connect_raw(self.cid, self.port, CONNECT_TIMEOUT_SECS)?;
+        let result = run_benchmark(fd, self.message_size, self.duration);
+        unsafe { libc::close(fd); }
+        result.map_err(|e| Error::from_reason(format!("Benchmark failed: {}", e)))
+    }
+
+    fn resolve(&mut self, _env: Env, result: Self::Output) -> Result<Self::JsValue> {
+        Ok(result)
+    }
+}
+
+/// Ping-pong `message_size` messages over `fd` for `duration`.
+///
+/// Writes go through a helper thread while this one reads the echo, so
+/// messages larger than the socket buffers can't deadlock against the
+/// echo server.
+fn run_benchmark(fd: i32, message_size: usize, duration: Duration) -> std::io::Result<BenchmarkResult> {
+    let (go, send_next) = mpsc::channel::<()>();
+    let writer = std::thread::spawn(move || -> std::io::Result<()> {
+        let message = vec![0x5au8; message_size];
+        for () in send_next {
+            write_all_retrying(fd, &message)?;
+        }
+        Ok(())
+    });
+
+    let mut echo = vec![0u8; message_size];
+    let mut latencies_us = Vec::new();
+    let start = Instant::now();
+    let mut outcome = Ok(());
+    while start.elapsed() < duration {
+        let sent = Instant::now();
+        if go.send(()).is_err() {
+            break; // writer failed; its error is reported below
+        }
+        if let Err(e) = read_exact(fd, &mut echo, false) {
+            outcome = Err(e);
+            break;
+        }
+        latencies_us.push(sent.elapsed().as_secs_f64() * 1e6);
+    }
+    let elapsed = start.elapsed();
+    drop(go);
+    if outcome.is_err() {
+        // Unblock a writer stuck on a peer that stopped reading
+        unsafe { libc::shutdown(fd, libc::SHUT_RDWR); }
+    }
+    if let Err(e) = writer.join().expect("benchmark writer panicked") {
+        outcome = outcome.and(Err(e));
+    }
+    outcome?;
+
+    Ok(summarize(latencies_us, message_size, elapsed))
+}
+
+fn summarize(mut latencies_us: Vec<f64>, message_size: usize, elapsed: Duration) -> BenchmarkResult {
+    latencies_us.sort_by(|a, b| a.total_cmp(b));
+    let round_trips = latencies_us.len();
+    let bytes = (round_trips * message_size) as i64;
+    let secs = elapsed.as_secs_f64();
+    let percentile = |p: f64| match round_trips {
+        0 => 0.0,
+        n => latencies_us[((n - 1) as f64 * p).round() as usize],
+    };
+    BenchmarkResult {
+        round_trips: round_trips as i64,
+        bytes,
+        duration_ms: secs * 1000.0,
+        throughput_mbps: if secs > 0.0 { bytes as f64 * 8.0 / secs / 1e6 } else { 0.0 },
+        latency_avg_us: if round_trips > 0 {
+            latencies_us.iter().sum::<f64>() / round_trips as f64
+        } else {
+            0.0
+        },
+        latency_p50_us: percentile(0.5),
+        latency_p99_us: percentile(0.99),
+        latency_max_us: latencies_us.last().copied().unwrap_or(0.0),
+    }
+}
+
+// =============================================================================
+// Tests — over AF_UNIX socketpairs (no vsock required)
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socketpair;
+
+    #[test]
+    fn benchmark_against_echo() {
+        let (client, server) = socketpair();
+        let echoed = Arc::new(AtomicI64::new(0));
+        let echo_thread = {
+            let echoed = echoed.clone();
+            std::thread::spawn(move || echo(server, &echoed))
+        };
+
+        // Larger than the socketpair buffers: must not deadlock
+        let result = run_benchmark(client, 1024 * 1024, Duration::from_millis(100)).unwrap();
+        assert!(result.round_trips > 0);
+        assert_eq!(result.bytes, result.round_trips * 1024 * 1024);
+        assert_eq!(echoed.load(Ordering::Relaxed), result.bytes);
+        assert!(result.latency_p50_us <= result.latency_p99_us);
+        assert!(result.latency_p99_us <= result.latency_max_us);
+        assert!(result.throughput_mbps > 0.0);
+
+        unsafe { libc::close(client); }
+        echo_thread.join().unwrap().unwrap();
+        unsafe { libc::close(server); }
+    }
+
+    #[test]
+    fn benchmark_reports_closed_peer() {
+        let (client, server) = socketpair();
+        unsafe { libc::close(server); }
+        assert!(run_benchmark(client, 16, Duration::from_millis(50)).is_err());
+        unsafe { libc::close(client); }
+    }
+
+    #[test]
+    fn summary_of_no_round_trips_is_zero() {
+        let result = summarize(Vec::new(), 16, Duration::from_millis(10));
+        assert_eq!(result.round_trips, 0);
+        assert_eq!(result.latency_p99_us, 0.0);
+        assert_eq!(result.throughput_mbps, 0.0);
+    }
+}
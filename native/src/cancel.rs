@@ -0,0 +1,187 @@
+//! Cancellation for in-flight async operations.
+//!
+//! A CancelToken owns a self-pipe. Operations that accept one poll the
+//! pipe's read end alongside the fd they're waiting on; cancel() writes a
+//! byte, which wakes every such poll and fails the operation instead of
+//! leaving a libuv worker blocked. The byte is never read back, so the
+//! token stays cancelled.
+//!
+//! To cancel from an AbortSignal:
+//! `signal.addEventListener('abort', () => token.cancel())`.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub(crate) struct Canceller {
+    cancelled: AtomicBool,
+    read_fd: i32,
+    write_fd: i32,
+}
+
+impl Canceller {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let (read_fd, write_fd) = pipe_cloexec()?;
+        Ok(Canceller { cancelled: AtomicBool::new(false), read_fd, write_fd })
+    }
+
+    pub(crate) fn cancel(&self) {
+        if !self.cancelled.swap(true, Ordering::AcqRel) {
+            let byte = 1u8;
+            unsafe { libc::write(self.write_fd, &byte as *const u8 as *const libc::c_void, 1); }
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// The fd to poll for POLLIN alongside the operation's own fd.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn fd(&self) -> i32 {
+        self.read_fd
+    }
+
+    /// Block until `fd` reports one of `events`, failing with
+    /// `cancelled_error()` if the token is cancelled first.
+    pub(crate) fn wait(&self, fd: i32, events: i16) -> std::io::Result<()> {
+        self.wait_timeout(fd, events, -1).map(drop)
+    }
+
+    /// wait() for at most `timeout_ms` (-1 for no limit). Returns `fd`'s
+    /// revents, 0 on timeout.
+    pub(crate) fn wait_timeout(&self, fd: i32, events: i16, timeout_ms: i32) -> std::io::Result<i16> {
+        let mut pfds = [
+            libc::pollfd { fd, events, revents: 0 },
+            libc::pollfd { fd: self.read_fd, events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            let ret = unsafe { libc::poll(pfds.as_mut_ptr(), 2, timeout_ms) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(err);
+            }
+            if pfds[1].revents != 0 {
+                return Err(cancelled_error());
+            }
+            return Ok(pfds[0].revents);
+        }
+    }
+}
+
+impl Drop for Canceller {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+pub(crate) fn cancelled_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Interrupted, "Operation cancelled")
+}
+
+#[cfg(target_os = "linux")]
+fn pipe_cloexec() -> std::io::Result<(i32, i32)> {
+    let mut fds = [0i32; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// pipe2() is Linux-only: pipe, then set the flags on each end.
+#[cfg(not(target_os = "linux"))]
+fn pipe_cloexec() -> std::io::Result<(i32, i32)> {
+    let mut fds = [0i32; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    for fd in fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+        }
+    }
+    Ok((fds[0], fds[1]))
+}
+
+/// Cancels the async operations it is passed to (listener.acceptAsync(),
+/// vsockConnectAsync()). One token can serve any number of operations;
+/// once cancelled it stays cancelled.
+#[napi]
+pub struct CancelToken {
+    inner: Arc<Canceller>,
+}
+
+#[napi]
+impl CancelToken {
+    #[napi(constructor)]
+    pub fn new() -> Result<Self> {
+        let inner = Canceller::new()
+            .map_err(|e| Error::from_reason(format!("pipe() failed: {}", e)))?;
+        Ok(CancelToken { inner: Arc::new(inner) })
+    }
+
+    /// Cancel every operation waiting on this token, and any started later.
+    #[napi]
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    #[napi(getter)]
+    pub fn cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+}
+
+impl CancelToken {
+    pub(crate) fn shared(&self) -> Arc<Canceller> {
+        self.inner.clone()
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_wakes_a_waiter() {
+        let canceller = Arc::new(Canceller::new().unwrap());
+        let (idle, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&idle);
+
+        let waiter = {
+            let canceller = canceller.clone();
+            std::thread::spawn(move || canceller.wait(fd, libc::POLLIN))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        canceller.cancel();
+        let err = waiter.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert!(canceller.is_cancelled());
+
+        // Stays cancelled for later waits, and cancel() is idempotent
+        canceller.cancel();
+        assert!(canceller.wait(fd, libc::POLLIN).is_err());
+    }
+
+    #[test]
+    fn ready_fd_is_not_cancelled() {
+        let canceller = Canceller::new().unwrap();
+        let (ready, mut peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut peer, b"x").unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&ready);
+        canceller.wait(fd, libc::POLLIN).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+//! CBOR encoding: the NSM wire format, also exported to JS as
+//! cborEncode()/cborDecode() for apps exchanging CBOR over vsock.
+
+use ciborium::value::{Integer, Value};
+use napi::bindgen_prelude::*;
+use napi::{
+    JsBigInt, JsFunction, JsObject, JsTypedArray, JsUnknown, KeyCollectionMode, KeyConversion,
+    KeyFilter, TypedArrayType, ValueType,
+};
+use napi_derive::napi;
+
+/// Largest integer a JS number holds exactly (Number.MAX_SAFE_INTEGER).
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Deepest nesting cborEncode() follows, which also stops cyclic values.
+const MAX_DEPTH: usize = 256;
+
+/// Encode a CBOR value to bytes.
+pub(crate) fn encode(value: &Value) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    ciborium::ser::into_writer(value, &mut out)
+        .map_err(|e| Error::from_reason(format!("CBOR encode failed: {}", e)))?;
+    Ok(out)
+}
+
+/// Decode a single CBOR value from bytes.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Value> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| Error::from_reason(format!("CBOR decode failed: {}", e)))
+}
+
+/// decode(), rejecting bytes after the value.
+fn decode_exact(bytes: &[u8]) -> Result<Value> {
+    let mut reader = bytes;
+    let value = ciborium::de::from_reader(&mut reader)
+        .map_err(|e| Error::from_reason(format!("CBOR decode failed: {}", e)))?;
+    if !reader.is_empty() {
+        return Err(Error::from_reason(format!(
+            "CBOR decode failed: {} bytes after the value",
+            reader.len()
+        )));
+    }
+    Ok(value)
+}
+
+/// Encode a JS value as CBOR.
+///
+/// null and undefined become null, safe integers and BigInts integers,
+/// other numbers floats, Buffers and Uint8Arrays byte strings, arrays
+/// arrays, Maps maps with encoded keys and other objects maps of their own
+/// enumerable string keys.
+#[napi(ts_args_type = "value: unknown")]
+pub fn cbor_encode(env: Env, value: JsUnknown) -> Result<Buffer> {
+    let map_class = env.get_global()?.get_named_property::<JsFunction>("Map")?;
+    let value = from_js(&env, &map_class, value, 0)?;
+    encode(&value).map(Buffer::from)
+}
+
+/// Decode one CBOR value, the inverse of cborEncode(): byte strings become
+/// Buffers, integers beyond Number.MAX_SAFE_INTEGER BigInts, and maps with
+/// only text keys plain objects (other maps, and any with a "__proto__"
+/// key, become Maps). Tags are dropped in favour of the tagged value.
+/// Bytes after the value throw.
+#[napi(ts_return_type = "unknown")]
+pub fn cbor_decode(env: Env, bytes: Buffer) -> Result<JsUnknown> {
+    let value = decode_exact(&bytes)?;
+    let map_class = env.get_global()?.get_named_property::<JsFunction>("Map")?;
+    to_js(&env, &map_class, value)
+}
+
+fn from_js(env: &Env, map_class: &JsFunction, value: JsUnknown, depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(Error::from_reason(format!(
+            "cborEncode: value nested more than {} deep",
+            MAX_DEPTH
+        )));
+    }
+    let unsupported =
+        |kind: &str| Error::from_reason(format!("cborEncode: cannot encode {}", kind));
+    Ok(match value.get_type()? {
+        ValueType::Undefined | ValueType::Null => Value::Null,
+        ValueType::Boolean => Value::Bool(value.coerce_to_bool()?.get_value()?),
+        ValueType::Number => from_number(value.coerce_to_number()?.get_double()?),
+        ValueType::BigInt => {
+            let (n, lossless) = unsafe { value.cast::<JsBigInt>() }.get_i128()?;
+            match Integer::try_from(n) {
+                Ok(n) if lossless => Value::Integer(n),
+                _ => return Err(unsupported("a BigInt outside the CBOR integer range")),
+            }
+        }
+        ValueType::String => Value::Text(value.coerce_to_string()?.into_utf8()?.into_owned()?),
+        ValueType::Object if value.is_typedarray()? => {
+            let array = unsafe { value.cast::<JsTypedArray>() }.into_value()?;
+            match array.typedarray_type {
+                TypedArrayType::Uint8 | TypedArrayType::Uint8Clamped => {
+                    Value::Bytes(AsRef::<[u8]>::as_ref(&array).to_vec())
+                }
+                _ => return Err(unsupported("typed arrays other than Uint8Array")),
+            }
+        }
+        ValueType::Object if value.is_array()? => {
+            let array = unsafe { value.cast::<JsObject>() };
+            (0..array.get_array_length()?)
+                .map(|index| from_js(env, map_class, array.get_element(index)?, depth + 1))
+                .collect::<Result<_>>()
+                .map(Value::Array)?
+        }
+        ValueType::Object if value.instanceof(map_class)? => {
+            let array_from = env
+                .get_global()?
+                .get_named_property::<JsObject>("Array")?
+                .get_named_property::<JsFunction>("from")?;
+            let entries: JsObject = array_from.call(None, &[value])?.try_into()?;
+            let mut map = Vec::new();
+            for index in 0..entries.get_array_length()? {
+                let entry: JsObject = entries.get_element(index)?;
+                map.push((
+                    from_js(env, map_class, entry.get_element(0)?, depth + 1)?,
+                    from_js(env, map_class, entry.get_element(1)?, depth + 1)?,
+                ));
+            }
+            Value::Map(map)
+        }
+        ValueType::Object if value.is_date()? => return Err(unsupported("a Date")),
+        ValueType::Object => {
+            let object = unsafe { value.cast::<JsObject>() };
+            let keys = object.get_all_property_names(
+                KeyCollectionMode::OwnOnly,
+                KeyFilter::Enumerable,
+                KeyConversion::NumbersToStrings,
+            )?;
+            let mut map = Vec::new();
+            for index in 0..keys.get_array_length()? {
+                let key: JsUnknown = keys.get_element(index)?;
+                if key.get_type()? != ValueType::String {
+                    continue; // symbols
+                }
+                let key = key.coerce_to_string()?;
+                let item = object.get_property::<_, JsUnknown>(&key)?;
+                map.push((
+                    Value::Text(key.into_utf8()?.into_owned()?),
+                    from_js(env, map_class, item, depth + 1)?,
+                ));
+            }
+            Value::Map(map)
+        }
+        ValueType::Function => return Err(unsupported("a function")),
+        ValueType::Symbol => return Err(unsupported("a symbol")),
+        _ => return Err(unsupported("this value")),
+    })
+}
+
+/// Safe integers as CBOR integers (except -0), other numbers as floats.
+fn from_number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER as f64 && !(n == 0.0 && n.is_sign_negative())
+    {
+        Value::Integer((n as i64).into())
+    } else {
+        Value::Float(n)
+    }
+}
+
+fn to_js(env: &Env, map_class: &JsFunction, value: Value) -> Result<JsUnknown> {
+    Ok(match value {
+        Value::Null => env.get_null()?.into_unknown(),
+        Value::Bool(b) => env.get_boolean(b)?.into_unknown(),
+        Value::Integer(n) => {
+            let n = i128::from(n);
+            if n.unsigned_abs() <= u128::from(MAX_SAFE_INTEGER) {
+                env.create_double(n as f64)?.into_unknown()
+            } else {
+                env.create_bigint_from_i128(n)?.into_unknown()?
+            }
+        }
+        Value::Float(f) => env.create_double(f)?.into_unknown(),
+        Value::Bytes(b) => env.create_buffer_with_data(b)?.into_unknown(),
+        Value::Text(t) => env.create_string_from_std(t)?.into_unknown(),
+        Value::Array(items) => {
+            let mut array = env.create_array_with_length(items.len())?;
+            for (index, item) in items.into_iter().enumerate() {
+                array.set_element(index as u32, to_js(env, map_class, item)?)?;
+            }
+            array.into_unknown()
+        }
+        Value::Map(entries) if entries.iter().all(|(k, _)| plain_key(k)) => {
+            let mut object = env.create_object()?;
+            for (key, item) in entries {
+                let Value::Text(key) = key else {
+                    unreachable!()
+                };
+                object.set_property(
+                    env.create_string_from_std(key)?,
+                    to_js(env, map_class, item)?,
+                )?;
+            }
+            object.into_unknown()
+        }
+        Value::Map(entries) => {
+            let map = map_class.new_instance::<JsUnknown>(&[])?;
+            let set = map.get_named_property::<JsFunction>("set")?;
+            for (key, item) in entries {
+                set.call(
+                    Some(&map),
+                    &[to_js(env, map_class, key)?, to_js(env, map_class, item)?],
+                )?;
+            }
+            map.into_unknown()
+        }
+        Value::Tag(_, inner) => to_js(env, map_class, *inner)?,
+        _ => return Err(Error::from_reason("cborDecode: unsupported CBOR value")),
+    })
+}
+
+/// Text keys other than "__proto__", which an object can't hold as data.
+fn plain_key(key: &Value) -> bool {
+    matches!(key, Value::Text(t) if t != "__proto__")
+}
+
+/// Shorthand for a text value (map keys, enum variant names).
+pub(crate) fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+/// Build a map with text keys, preserving insertion order.
+pub(crate) fn map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(entries.into_iter().map(|(k, v)| (text(k), v)).collect())
+}
+
+/// `Some(bytes)` as a byte string, `None` as CBOR null.
+pub(crate) fn opt_bytes(bytes: Option<&[u8]>) -> Value {
+    match bytes {
+        Some(b) => Value::Bytes(b.to_vec()),
+        None => Value::Null,
+    }
+}
+
+/// Look up a text key in a CBOR map.
+pub(crate) fn map_get<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Map(entries) => entries
+            .iter()
+            .find(|(k, _)| matches!(k, Value::Text(t) if t == key))
+            .map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+/// Byte-string contents. Also accepts an array of small integers, which is
+/// how serde-based encoders serialize a `Vec<u8>` without `serde_bytes`.
+pub(crate) fn as_bytes(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Bytes(b) => Some(b.clone()),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| as_u64(v).and_then(|n| u8::try_from(n).ok()))
+            .collect(),
+        _ => None,
+    }
+}
+
+pub(crate) fn as_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Integer(i) => u64::try_from(i128::from(*i)).ok(),
+        _ => None,
+    }
+}
+
+pub(crate) fn as_text(value: &Value) -> Option<&str> {
+    match value {
+        Value::Text(t) => Some(t.as_str()),
+        _ => None,
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let value = map(vec![
+            ("nonce", Value::Bytes(vec![1, 2, 3])),
+            ("user_data", Value::Null),
+            ("index", Value::Integer(16u16.into())),
+        ]);
+        let bytes = encode(&value).unwrap();
+        assert_eq!(decode(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn unit_variant_encodes_as_text() {
+        // NSM unit requests such as DescribeNSM are bare strings on the wire
+        assert_eq!(encode(&text("DescribeNSM")).unwrap(), b"\x6bDescribeNSM");
+    }
+
+    #[test]
+    fn map_get_finds_text_keys_only() {
+        let value = map(vec![("lock", Value::Bool(true))]);
+        assert_eq!(map_get(&value, "lock"), Some(&Value::Bool(true)));
+        assert_eq!(map_get(&value, "data"), None);
+        assert_eq!(map_get(&Value::Null, "lock"), None);
+    }
+
+    #[test]
+    fn as_bytes_accepts_byte_strings_and_int_arrays() {
+        assert_eq!(as_bytes(&Value::Bytes(vec![9, 8])), Some(vec![9, 8]));
+        let arr = Value::Array(vec![Value::Integer(1.into()), Value::Integer(255.into())]);
+        assert_eq!(as_bytes(&arr), Some(vec![1, 255]));
+        let too_big = Value::Array(vec![Value::Integer(256.into())]);
+        assert_eq!(as_bytes(&too_big), None);
+        assert_eq!(as_bytes(&Value::Null), None);
+    }
+
+    #[test]
+    fn decode_rejects_truncated_input() {
+        let bytes = encode(&Value::Bytes(vec![0; 32])).unwrap();
+        assert!(decode(&bytes[..10]).is_err());
+    }
+
+    #[test]
+    fn decode_exact_rejects_trailing_bytes() {
+        let mut bytes = encode(&text("value")).unwrap();
+        assert_eq!(decode_exact(&bytes).unwrap(), text("value"));
+        bytes.extend_from_slice(&[0xf6, 0xf6]);
+        let err = decode_exact(&bytes).unwrap_err();
+        assert_eq!(err.reason, "CBOR decode failed: 2 bytes after the value");
+    }
+
+    #[test]
+    fn js_numbers_encode_as_integers_only_when_safe() {
+        assert_eq!(from_number(42.0), Value::Integer(42.into()));
+        assert_eq!(
+            from_number(-9_007_199_254_740_991.0),
+            Value::Integer((-9_007_199_254_740_991i64).into())
+        );
+        assert_eq!(
+            from_number(9_007_199_254_740_992.0),
+            Value::Float(9_007_199_254_740_992.0)
+        );
+        assert_eq!(from_number(1.5), Value::Float(1.5));
+        assert!(matches!(from_number(-0.0), Value::Float(f) if f.is_sign_negative()));
+        assert!(matches!(from_number(f64::NAN), Value::Float(f) if f.is_nan()));
+        assert_eq!(from_number(f64::INFINITY), Value::Float(f64::INFINITY));
+        assert!(!plain_key(&text("__proto__")) && plain_key(&text("proto")));
+    }
+}
@@ -0,0 +1,324 @@
+//! CMS EnvelopedData decryption for KMS `CiphertextForRecipient`.
+//!
+//! When a KMS request names a Recipient, KMS encrypts the plaintext to the
+//! attested RSA key as a BER-encoded ContentInfo: one KeyTransRecipientInfo
+//! (RSAES-OAEP with SHA-256) wrapping an AES-256-CBC content key. KMS uses
+//! indefinite lengths and chunked OCTET STRINGs, so this reads BER rather
+//! than DER, and accepts nothing but that shape.
+
+use aes::Aes256;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use napi::bindgen_prelude::*;
+use rsa::{Oaep, RsaPrivateKey};
+use sha2::Sha256;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+/// [0] EXPLICIT, and EnvelopedData's optional originatorInfo.
+const TAG_CONTEXT_0: u8 = 0xa0;
+/// [0] IMPLICIT OCTET STRING (encryptedContent), primitive form.
+const TAG_CONTEXT_0_PRIMITIVE: u8 = 0x80;
+const CONSTRUCTED: u8 = 0x20;
+
+/// 1.2.840.113549.1.7.3
+const OID_ENVELOPED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x03];
+/// 1.2.840.113549.1.1.7
+const OID_RSAES_OAEP: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x07];
+/// 2.16.840.1.101.3.4.1.42
+const OID_AES_256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+/// Decrypt a BER ContentInfo holding EnvelopedData addressed to `key`.
+pub(crate) fn decrypt_enveloped_data(ber: &[u8], key: &RsaPrivateKey) -> Result<Vec<u8>> {
+    let mut outer = Ber::new(ber);
+    let mut content_info = Ber::new(outer.expect(TAG_SEQUENCE, "ContentInfo")?.content);
+    if content_info.expect(TAG_OID, "contentType")?.content != OID_ENVELOPED_DATA {
+        return Err(invalid("content is not EnvelopedData"));
+    }
+    let mut explicit = Ber::new(content_info.expect(TAG_CONTEXT_0, "content")?.content);
+    let mut enveloped = Ber::new(explicit.expect(TAG_SEQUENCE, "EnvelopedData")?.content);
+
+    enveloped.expect(TAG_INTEGER, "version")?;
+    if enveloped.peek_tag() == Some(TAG_CONTEXT_0) {
+        enveloped.read()?; // originatorInfo
+    }
+    let mut recipients = Ber::new(enveloped.expect(TAG_SET, "recipientInfos")?.content);
+    let content_key = content_key(
+        recipients.expect(TAG_SEQUENCE, "KeyTransRecipientInfo")?,
+        key,
+    )?;
+
+    let mut encrypted = Ber::new(
+        enveloped
+            .expect(TAG_SEQUENCE, "EncryptedContentInfo")?
+            .content,
+    );
+    encrypted.expect(TAG_OID, "contentType")?;
+    let mut algorithm = Ber::new(
+        encrypted
+            .expect(TAG_SEQUENCE, "contentEncryptionAlgorithm")?
+            .content,
+    );
+    if algorithm
+        .expect(TAG_OID, "content encryption algorithm")?
+        .content
+        != OID_AES_256_CBC
+    {
+        return Err(invalid("content encryption is not AES-256-CBC"));
+    }
+    let iv = octets(algorithm.expect(TAG_OCTET_STRING, "AES IV")?)?;
+    let ciphertext = match encrypted.read()? {
+        tlv if tlv.tag & !CONSTRUCTED == TAG_CONTEXT_0_PRIMITIVE => octets(tlv)?,
+        _ => return Err(invalid("missing encryptedContent")),
+    };
+
+    cbc::Decryptor::<Aes256>::new_from_slices(&content_key, &iv)
+        .map_err(|_| invalid("bad AES-256 key or IV length"))?
+        .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+        .map_err(|_| invalid("content does not decrypt"))
+}
+
+/// Unwrap the content key from a KeyTransRecipientInfo.
+fn content_key(recipient: Tlv, key: &RsaPrivateKey) -> Result<Vec<u8>> {
+    let mut recipient = Ber::new(recipient.content);
+    recipient.expect(TAG_INTEGER, "recipient version")?;
+    recipient.read()?; // rid
+    let mut algorithm = Ber::new(
+        recipient
+            .expect(TAG_SEQUENCE, "keyEncryptionAlgorithm")?
+            .content,
+    );
+    if algorithm
+        .expect(TAG_OID, "key encryption algorithm")?
+        .content
+        != OID_RSAES_OAEP
+    {
+        return Err(invalid("key encryption is not RSAES-OAEP"));
+    }
+    let wrapped = octets(recipient.expect(TAG_OCTET_STRING, "encryptedKey")?)?;
+    key.decrypt(Oaep::new::<Sha256>(), &wrapped)
+        .map_err(|_| invalid("content key does not decrypt with the recipient key"))
+}
+
+fn invalid(what: &str) -> Error {
+    Error::from_reason(format!("Invalid CiphertextForRecipient: {}", what))
+}
+
+/// One BER TLV. For indefinite lengths `content` excludes the
+/// end-of-contents octets.
+struct Tlv<'a> {
+    tag: u8,
+    content: &'a [u8],
+}
+
+struct Ber<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Ber<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Ber { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    fn read(&mut self) -> Result<Tlv<'a>> {
+        let truncated = || invalid("truncated BER");
+        let (&tag, rest) = self.data.split_first().ok_or_else(truncated)?;
+        if tag & 0x1f == 0x1f {
+            return Err(invalid("multi-byte tags are not supported"));
+        }
+        let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+        if first == 0x80 {
+            if tag & CONSTRUCTED == 0 {
+                return Err(invalid("indefinite length on a primitive"));
+            }
+            // Children up to the end-of-contents octets.
+            let mut children = Ber::new(rest);
+            while !children.data.starts_with(&[0, 0]) {
+                children.read()?;
+            }
+            let len = rest.len() - children.data.len();
+            self.data = &children.data[2..];
+            return Ok(Tlv {
+                tag,
+                content: &rest[..len],
+            });
+        }
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count > 4 || rest.len() < count {
+                return Err(invalid("bad BER length"));
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |n, &b| (n << 8) | b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        self.data = &rest[len..];
+        Ok(Tlv {
+            tag,
+            content: &rest[..len],
+        })
+    }
+
+    fn expect(&mut self, tag: u8, what: &str) -> Result<Tlv<'a>> {
+        let tlv = self.read()?;
+        if tlv.tag != tag {
+            return Err(invalid(&format!("unexpected {}", what)));
+        }
+        Ok(tlv)
+    }
+}
+
+/// The bytes of an OCTET STRING (or implicitly tagged one), joining the
+/// chunks of the constructed form.
+fn octets(tlv: Tlv) -> Result<Vec<u8>> {
+    if tlv.tag & CONSTRUCTED == 0 {
+        return Ok(tlv.content.to_vec());
+    }
+    let mut chunks = Ber::new(tlv.content);
+    let mut bytes = Vec::new();
+    while !chunks.is_empty() {
+        let chunk = chunks.read()?;
+        if chunk.tag & !CONSTRUCTED != TAG_OCTET_STRING {
+            return Err(invalid("unexpected OCTET STRING chunk"));
+        }
+        bytes.extend(octets(chunk)?);
+    }
+    Ok(bytes)
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    use cbc::cipher::BlockEncryptMut;
+    use rand_core::{OsRng, RngCore};
+    use rsa::RsaPublicKey;
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => out.push(len as u8),
+            len @ 0x80..=0xff => out.extend([0x81, len as u8]),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn indefinite(tag: u8, children: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![tag, 0x80];
+        out.extend(children.concat());
+        out.extend([0, 0]);
+        out
+    }
+
+    /// EnvelopedData for `plaintext` to `recipient`, shaped like KMS's:
+    /// indefinite lengths and a chunked encryptedContent.
+    pub(crate) fn envelop(plaintext: &[u8], recipient: &RsaPublicKey) -> Vec<u8> {
+        let mut content_key = [0u8; 32];
+        let mut iv = [0u8; 16];
+        OsRng.fill_bytes(&mut content_key);
+        OsRng.fill_bytes(&mut iv);
+        let wrapped = recipient
+            .encrypt(&mut OsRng, Oaep::new::<Sha256>(), &content_key)
+            .unwrap();
+        let ciphertext = cbc::Encryptor::<Aes256>::new_from_slices(&content_key, &iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+        let (first, rest) = ciphertext.split_at(ciphertext.len() / 2);
+
+        let recipient_info = tlv(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_INTEGER, &[2]),
+                tlv(TAG_CONTEXT_0_PRIMITIVE, b"subject key id"),
+                tlv(TAG_SEQUENCE, &tlv(TAG_OID, OID_RSAES_OAEP)),
+                tlv(TAG_OCTET_STRING, &wrapped),
+            ]
+            .concat(),
+        );
+        let encrypted_content_info = indefinite(
+            TAG_SEQUENCE,
+            &[
+                tlv(
+                    TAG_OID,
+                    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01],
+                ),
+                tlv(
+                    TAG_SEQUENCE,
+                    &[tlv(TAG_OID, OID_AES_256_CBC), tlv(TAG_OCTET_STRING, &iv)].concat(),
+                ),
+                indefinite(
+                    TAG_CONTEXT_0_PRIMITIVE | CONSTRUCTED,
+                    &[tlv(TAG_OCTET_STRING, first), tlv(TAG_OCTET_STRING, rest)],
+                ),
+            ],
+        );
+        let enveloped_data = indefinite(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_INTEGER, &[2]),
+                tlv(TAG_SET, &recipient_info),
+                encrypted_content_info,
+            ],
+        );
+        indefinite(
+            TAG_SEQUENCE,
+            &[
+                tlv(TAG_OID, OID_ENVELOPED_DATA),
+                indefinite(TAG_CONTEXT_0, &[enveloped_data]),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::envelop;
+    use super::*;
+    use rand_core::OsRng;
+
+    fn test_key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut OsRng, 1024).unwrap()
+    }
+
+    #[test]
+    fn decrypts_kms_shaped_enveloped_data() {
+        let key = test_key();
+        let plaintext = vec![0x5a; 100];
+        let ber = envelop(&plaintext, &key.to_public_key());
+        assert_eq!(decrypt_enveloped_data(&ber, &key).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn rejects_other_recipients_and_truncation() {
+        let key = test_key();
+        let ber = envelop(b"secret", &key.to_public_key());
+
+        let err = decrypt_enveloped_data(&ber, &test_key()).err().unwrap();
+        assert!(err.reason.contains("does not decrypt"), "{}", err.reason);
+        let err = decrypt_enveloped_data(&ber[..ber.len() - 3], &key)
+            .err()
+            .unwrap();
+        assert!(err.reason.contains("truncated"), "{}", err.reason);
+    }
+}
@@ -0,0 +1,363 @@
+//! Optional compression for framed messages.
+//!
+//! RpcClient and serveFramed() can compress large frames with zstd or lz4,
+//! cutting vsock bandwidth for bulky JSON/CBOR payloads:
+//!
+//! ```js
+//! const server = listener.serveFramed(handler, { compression: 'zstd' });
+//! const rpc = RpcClient.connect(16, 5005, { compression: 'zstd' });
+//! ```
+//!
+//! Compression is negotiated per connection. The client's first frame
+//! offers an algorithm (`\0tytle-compress/1\0zstd`) and the server answers
+//! with the one it accepts, or `none`. From then on every frame in either
+//! direction starts with a tag byte: 0 for a raw payload, 1 for zstd, 2 for
+//! lz4. Frames below the threshold (default 1 KiB), or that don't shrink,
+//! go raw. A server that gets an ordinary first frame instead serves that
+//! connection with bare frames as before, so older clients keep working.
+
+use napi::bindgen_prelude::*;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+
+use crate::framing::{self, MAX_FRAME_SIZE};
+
+const MAGIC: &[u8] = b"\0tytle-compress/1\0";
+const DEFAULT_THRESHOLD: u32 = 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+const TAG_RAW: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+const TAG_LZ4: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Algorithm {
+    Zstd,
+    Lz4,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Algorithm::Zstd),
+            "lz4" => Some(Algorithm::Lz4),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::Lz4 => "lz4",
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Algorithm::Zstd => TAG_ZSTD,
+            Algorithm::Lz4 => TAG_LZ4,
+        }
+    }
+}
+
+/// A side's compression settings, from its `compression` and
+/// `compressionThreshold` options.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Compression {
+    algorithm: Algorithm,
+    threshold: usize,
+}
+
+impl Compression {
+    /// None when `compression` isn't set.
+    pub(crate) fn from_js(
+        compression: Option<String>,
+        threshold: Option<u32>,
+    ) -> Result<Option<Self>> {
+        let Some(name) = compression else {
+            return Ok(None);
+        };
+        let algorithm = Algorithm::parse(&name).ok_or_else(|| {
+            Error::from_reason(format!(
+                "compression must be \"zstd\" or \"lz4\", got {:?}",
+                name
+            ))
+        })?;
+        Ok(Some(Compression {
+            algorithm,
+            threshold: threshold.unwrap_or(DEFAULT_THRESHOLD) as usize,
+        }))
+    }
+}
+
+/// How frames on one connection are encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Codec {
+    /// No handshake: bare payloads, as framing writes them.
+    #[default]
+    Plain,
+    /// After a handshake: tagged payloads, compressed with `algorithm`
+    /// (if one was agreed) from `threshold` bytes up.
+    Tagged {
+        algorithm: Option<Algorithm>,
+        threshold: usize,
+    },
+}
+
+impl Codec {
+    /// The agreed algorithm, if frames may be compressed.
+    pub(crate) fn algorithm(&self) -> Option<Algorithm> {
+        match self {
+            Codec::Plain => None,
+            Codec::Tagged { algorithm, .. } => *algorithm,
+        }
+    }
+
+    pub(crate) fn write_frame(&self, fd: i32, payload: &[u8]) -> std::io::Result<()> {
+        match self {
+            Codec::Plain => framing::write_frame(fd, payload),
+            Codec::Tagged { .. } => framing::write_frame(fd, &self.encode(payload)),
+        }
+    }
+
+    /// write_frame()'s bytes, for callers doing their own writes.
+    pub(crate) fn encode_frame(&self, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Plain => framing::encode_frame(payload),
+            Codec::Tagged { .. } => framing::encode_frame(&self.encode(payload)),
+        }
+    }
+
+    pub(crate) fn read_frame(&self, fd: i32) -> std::io::Result<Option<Vec<u8>>> {
+        match framing::read_frame(fd)? {
+            Some(frame) => self.decode(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let Codec::Tagged {
+            algorithm: Some(algorithm),
+            threshold,
+        } = *self
+        else {
+            return tagged(TAG_RAW, payload);
+        };
+        if payload.len() < threshold {
+            return tagged(TAG_RAW, payload);
+        }
+        let compressed = match algorithm {
+            Algorithm::Zstd => zstd::bulk::compress(payload, ZSTD_LEVEL).ok(),
+            Algorithm::Lz4 => Some(lz4_flex::block::compress_prepend_size(payload)),
+        };
+        match compressed {
+            Some(compressed) if compressed.len() < payload.len() => {
+                tagged(algorithm.tag(), &compressed)
+            }
+            _ => tagged(TAG_RAW, payload),
+        }
+    }
+
+    pub(crate) fn decode(&self, mut frame: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        if *self == Codec::Plain {
+            return Ok(frame);
+        }
+        let invalid = |msg: String| IoError::new(ErrorKind::InvalidData, msg);
+        let (tag, body) = match frame.split_first() {
+            Some((&tag, body)) => (tag, body),
+            None => return Err(invalid("Empty compressed frame".to_string())),
+        };
+        if tag == TAG_RAW {
+            frame.remove(0);
+            return Ok(frame);
+        }
+        // Only the agreed algorithm, and never past the frame size limit,
+        // so a peer can't make us inflate a bomb
+        match self.algorithm() {
+            Some(Algorithm::Zstd) if tag == TAG_ZSTD => {
+                zstd::bulk::decompress(body, MAX_FRAME_SIZE)
+                    .map_err(|e| invalid(format!("Bad zstd frame: {}", e)))
+            }
+            Some(Algorithm::Lz4) if tag == TAG_LZ4 => {
+                let size = body
+                    .get(..4)
+                    .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("Truncated lz4 frame".to_string()))?;
+                if size > MAX_FRAME_SIZE {
+                    return Err(invalid(format!(
+                        "Message too large: {} bytes (max {})",
+                        size, MAX_FRAME_SIZE
+                    )));
+                }
+                lz4_flex::block::decompress_size_prepended(body)
+                    .map_err(|e| invalid(format!("Bad lz4 frame: {}", e)))
+            }
+            _ => Err(invalid(format!("Unexpected frame tag {}", tag))),
+        }
+    }
+}
+
+fn tagged(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + body.len());
+    frame.push(tag);
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn handshake(name: &str) -> Vec<u8> {
+    [MAGIC, name.as_bytes()].concat()
+}
+
+/// Client side: offer `compression` and wait up to `timeout` for the
+/// server's answer.
+pub(crate) fn offer(
+    fd: i32,
+    compression: &Compression,
+    timeout: Duration,
+) -> std::io::Result<Codec> {
+    framing::write_frame(fd, &handshake(compression.algorithm.name()))?;
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = loop {
+        match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) } {
+            n if n >= 0 => break n > 0,
+            _ if IoError::last_os_error().kind() == ErrorKind::Interrupted => continue,
+            _ => return Err(IoError::last_os_error()),
+        }
+    };
+    if !ready {
+        return Err(IoError::new(
+            ErrorKind::TimedOut,
+            "Peer did not answer the compression handshake",
+        ));
+    }
+    let answer = framing::read_frame(fd)?.ok_or_else(|| {
+        IoError::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed during the compression handshake",
+        )
+    })?;
+    let algorithm = match answer.strip_prefix(MAGIC) {
+        Some(b"none") => None,
+        Some(name) if name == compression.algorithm.name().as_bytes() => {
+            Some(compression.algorithm)
+        }
+        _ => {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "Peer does not support compression",
+            ))
+        }
+    };
+    Ok(Codec::Tagged {
+        algorithm,
+        threshold: compression.threshold,
+    })
+}
+
+/// Server side: if a connection's first `frame` is an offer, answer it
+/// with what `supported` allows and return the codec to use. None for an
+/// ordinary frame.
+pub(crate) fn answer(
+    fd: i32,
+    frame: &[u8],
+    supported: Option<&Compression>,
+) -> std::io::Result<Option<Codec>> {
+    let Some(offered) = frame.strip_prefix(MAGIC) else {
+        return Ok(None);
+    };
+    let agreed = supported.filter(|c| c.algorithm.name().as_bytes() == offered);
+    framing::write_frame(
+        fd,
+        &handshake(agreed.map_or("none", |c| c.algorithm.name())),
+    )?;
+    Ok(Some(Codec::Tagged {
+        algorithm: agreed.map(|c| c.algorithm),
+        threshold: agreed.map_or(0, |c| c.threshold),
+    }))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn compression(name: &str) -> Compression {
+        Compression::from_js(Some(name.to_string()), None)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn frames_round_trip_and_small_ones_stay_raw() {
+        let json = serde_json::json!({ "items": vec!["repetitive payload"; 200] }).to_string();
+        for algorithm in [Algorithm::Zstd, Algorithm::Lz4] {
+            let codec = Codec::Tagged {
+                algorithm: Some(algorithm),
+                threshold: 64,
+            };
+            let big = codec.encode(json.as_bytes());
+            assert_eq!(big[0], algorithm.tag());
+            assert!(big.len() < json.len() / 4);
+            assert_eq!(codec.decode(big).unwrap(), json.as_bytes());
+
+            let small = codec.encode(b"tiny");
+            assert_eq!(small, b"\0tiny");
+            assert_eq!(codec.decode(small).unwrap(), b"tiny");
+        }
+
+        // Tags other than raw and the agreed algorithm are refused
+        let zstd = Codec::Tagged {
+            algorithm: Some(Algorithm::Zstd),
+            threshold: 0,
+        };
+        let lz4 = Codec::Tagged {
+            algorithm: Some(Algorithm::Lz4),
+            threshold: 0,
+        };
+        assert!(lz4.decode(zstd.encode(json.as_bytes())).is_err());
+        let bomb = tagged(TAG_LZ4, &(u32::MAX).to_le_bytes());
+        let err = lz4.decode(bomb).unwrap_err();
+        assert!(err.to_string().starts_with("Message too large"));
+        assert!(lz4.decode(Vec::new()).is_err());
+        assert_eq!(Codec::Plain.decode(b"bare".to_vec()).unwrap(), b"bare");
+    }
+
+    #[test]
+    fn handshake_agrees_on_a_shared_algorithm() {
+        let serve = |supported: Option<Compression>| {
+            let (client, server) = UnixStream::pair().unwrap();
+            let peer = std::thread::spawn(move || {
+                let frame = framing::read_frame(server.as_raw_fd()).unwrap().unwrap();
+                answer(server.as_raw_fd(), &frame, supported.as_ref()).unwrap()
+            });
+            let codec = offer(
+                client.as_raw_fd(),
+                &compression("zstd"),
+                Duration::from_secs(5),
+            );
+            (codec.unwrap(), peer.join().unwrap().unwrap())
+        };
+        let (client, server) = serve(Some(compression("zstd")));
+        assert_eq!(client.algorithm(), Some(Algorithm::Zstd));
+        assert_eq!(server, client);
+
+        let (client, server) = serve(Some(compression("lz4")));
+        assert_eq!((client.algorithm(), server.algorithm()), (None, None));
+        let (client, _) = serve(None);
+        assert_eq!(client.encode(&[1; 4096])[0], TAG_RAW);
+
+        // Ordinary first frames aren't offers
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert_eq!(answer(a.as_raw_fd(), b"{}", None).unwrap(), None);
+        assert!(Compression::from_js(Some("gzip".to_string()), None).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+//! HTTP CONNECT tunnelling across the enclave boundary.
+//!
+//! HttpConnectProxyServer runs inside the enclave on loopback TCP, so any
+//! HTTPS client configured with `HTTPS_PROXY=http://127.0.0.1:<port>` can
+//! reach the outside world. It validates each CONNECT request locally and
+//! passes it over vsock to HttpConnectProxyClient on the host, which checks
+//! the target against a hostname allowlist, dials it and sends back the
+//! status line the HTTPS client sees. After a `200` both halves are a plain
+//! byte relay, so TLS stays end-to-end between the enclave and the target.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::os::fd::IntoRawFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::proxy::{allowlist_permits, dial_tcp, forward_accepted, ProxyCounters, ProxyStats};
+use crate::relay::write_all_retrying;
+use crate::server::AcceptLoop;
+use crate::vsock;
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 5000;
+
+/// Upper bound on a CONNECT request line plus headers.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+#[napi(object)]
+pub struct HttpConnectProxyServerOptions {
+    /// Loopback TCP port to listen on (0 picks an ephemeral port; see `port`).
+    pub listen_port: u32,
+    /// CID of the HttpConnectProxyClient (3 = host/parent from inside the enclave).
+    pub cid: u32,
+    pub vsock_port: u32,
+    /// vsock connect timeout per connection (default 5s).
+    pub connect_timeout_secs: Option<u32>,
+}
+
+/// Enclave-side HTTP CONNECT proxy endpoint on 127.0.0.1.
+#[napi]
+pub struct HttpConnectProxyServer {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+    port: u32,
+}
+
+#[napi]
+impl HttpConnectProxyServer {
+    /// Bind 127.0.0.1:listenPort and start accepting CONNECT requests on
+    /// native threads.
+    #[napi(factory)]
+    pub fn start(options: HttpConnectProxyServerOptions) -> Result<Self> {
+        let listen_port = u16::try_from(options.listen_port)
+            .map_err(|_| Error::from_reason(format!("Invalid TCP port: {}", options.listen_port)))?;
+        let listener = TcpListener::bind(("127.0.0.1", listen_port)).map_err(|e| {
+            Error::from_reason(format!("bind(127.0.0.1:{}) failed: {}", listen_port, e))
+        })?;
+        let port = listener
+            .local_addr()
+            .map(|a| a.port() as u32)
+            .unwrap_or(options.listen_port);
+
+        let (cid, vsock_port) = (options.cid, options.vsock_port);
+        let timeout_secs = options.connect_timeout_secs.unwrap_or(5);
+        let counters = Arc::new(ProxyCounters::default());
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn_any(listener.into_raw_fd(), move |conn, _, _| {
+            loop_counters.accepted.fetch_add(1, Ordering::Relaxed);
+            let Some((_target, head, rest)) = read_connect(conn, &loop_counters) else { return };
+            let remote = match vsock::connect_raw(cid, vsock_port, timeout_secs) {
+                Ok(fd) => fd,
+                Err(_) => {
+                    loop_counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+                    let _ = write_all_retrying(conn, status_response(502).as_bytes());
+                    return;
+                }
+            };
+            // The host half answers the CONNECT; pass the request (and anything
+            // the client pipelined after it) through untouched.
+            if write_all_retrying(remote, &head)
+                .and_then(|_| write_all_retrying(remote, &rest))
+                .is_err()
+            {
+                unsafe { libc::close(remote); }
+                return;
+            }
+            forward_accepted(conn, remote, &loop_counters);
+        })?;
+
+        Ok(HttpConnectProxyServer {
+            accept_loop,
+            counters,
+            port,
+        })
+    }
+
+    /// The loopback TCP port actually bound.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new connections. Established tunnels run until either
+    /// side closes. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+#[napi(object)]
+pub struct HttpConnectProxyClientOptions {
+    /// vsock port to listen on for tunnels from the enclave.
+    pub vsock_port: u32,
+    /// "host" or "host:port" entries (host compared case-insensitively).
+    /// CONNECT targets that match no entry are refused with 403.
+    pub allowed_hosts: Vec<String>,
+    /// TCP connect timeout per tunnel (default 5000ms).
+    pub connect_timeout_ms: Option<u32>,
+}
+
+/// Host-side half: opens the TCP connections requested by the enclave.
+#[napi]
+pub struct HttpConnectProxyClient {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+}
+
+#[napi]
+impl HttpConnectProxyClient {
+    /// Bind the vsock port and start serving tunnels on native threads.
+    #[napi(factory)]
+    pub fn start(options: HttpConnectProxyClientOptions) -> Result<Self> {
+        let allowed_hosts = options.allowed_hosts;
+        let timeout = Duration::from_millis(
+            options.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS) as u64,
+        );
+        let counters = Arc::new(ProxyCounters::default());
+        let listener_fd = vsock::listen_raw(options.vsock_port)?;
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn(listener_fd, move |conn, _cid, _port| {
+            serve_tunnel(conn, &allowed_hosts, timeout, &loop_counters);
+        })?;
+
+        Ok(HttpConnectProxyClient {
+            accept_loop,
+            counters,
+        })
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new tunnels. Established tunnels run until either side
+    /// closes. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Handle one tunnel request arriving from the enclave on `conn`.
+fn serve_tunnel(conn: i32, allowed_hosts: &[String], timeout: Duration, counters: &Arc<ProxyCounters>) {
+    counters.accepted.fetch_add(1, Ordering::Relaxed);
+    let Some((target, _head, rest)) = read_connect(conn, counters) else { return };
+
+    if !allowlist_permits(allowed_hosts, &target.host, target.port) {
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+        let _ = write_all_retrying(conn, status_response(403).as_bytes());
+        return;
+    }
+    let remote = match dial_tcp(&target.host, target.port, timeout) {
+        Ok(fd) => fd,
+        Err(e) => {
+            counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+            let status = if e.kind() == ErrorKind::TimedOut { 504 } else { 502 };
+            let _ = write_all_retrying(conn, status_response(status).as_bytes());
+            return;
+        }
+    };
+    if write_all_retrying(conn, status_response(200).as_bytes())
+        .and_then(|_| write_all_retrying(remote, &rest))
+        .is_err()
+    {
+        unsafe { libc::close(remote); }
+        return;
+    }
+    forward_accepted(conn, remote, counters);
+}
+
+/// Read a request head from `conn` and make sure it is a well-formed CONNECT,
+/// answering with an error status otherwise. Returns the target, the raw
+/// head and any bytes read past it.
+fn read_connect(conn: i32, counters: &ProxyCounters) -> Option<(ConnectTarget, Vec<u8>, Vec<u8>)> {
+    let (head, rest) = read_request_head(conn).ok()?;
+    match parse_connect(&head) {
+        Ok(target) => Some((target, head, rest)),
+        Err(status) => {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            let _ = write_all_retrying(conn, status_response(status).as_bytes());
+            None
+        }
+    }
+}
+
+/// Target of a CONNECT request.
+#[derive(Debug, PartialEq)]
+struct ConnectTarget {
+    host: String,
+    port: u16,
+}
+
+/// Parse the request line of `head`. `Err` carries the HTTP status to reply with.
+fn parse_connect(head: &[u8]) -> std::result::Result<ConnectTarget, u16> {
+    let head = std::str::from_utf8(head).map_err(|_| 400u16)?;
+    let line = head.split("\r\n").next().unwrap_or("");
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(400);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(400);
+    }
+    if method != "CONNECT" {
+        return Err(405);
+    }
+
+    // authority-form: host:port, with IPv6 literals in brackets
+    let (host, port) = match target.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, port) = bracketed.split_once("]:").ok_or(400u16)?;
+            (host, port)
+        }
+        None => target.rsplit_once(':').ok_or(400u16)?,
+    };
+    let port = port.parse::<u16>().map_err(|_| 400u16)?;
+    if host.is_empty() || port == 0 {
+        return Err(400);
+    }
+    Ok(ConnectTarget {
+        host: host.to_string(),
+        port,
+    })
+}
+
+fn status_response(status: u16) -> String {
+    let reason = match status {
+        200 => return "HTTP/1.1 200 Connection Established\r\n\r\n".to_string(),
+        400 => "Bad Request",
+        403 => "Forbidden",
+        405 => "Method Not Allowed",
+        502 => "Bad Gateway",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    )
+}
+
+/// Read up to and including the blank line ending an HTTP request head.
+/// Returns the head and whatever was read past it.
+///
+/// EAGAIN (SO_RCVTIMEO expiry) is an error here, so a client that never
+/// finishes its request can't pin a thread.
+pub(crate) fn read_request_head(fd: i32) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = unsafe { libc::read(fd, chunk.as_mut_ptr() as *mut libc::c_void, chunk.len()) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before end of request head",
+            ));
+        }
+        // The terminator may straddle the previous chunk
+        let scan_from = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n as usize]);
+        if let Some(pos) = buf[scan_from..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(scan_from + pos + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_REQUEST_HEAD {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Request head too large (max {} bytes)", MAX_REQUEST_HEAD),
+            ));
+        }
+    }
+}
+
+// =============================================================================
+// Tests — request parsing and the host half over a socketpair + loopback TCP
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    fn target(host: &str, port: u16) -> ConnectTarget {
+        ConnectTarget { host: host.to_string(), port }
+    }
+
+    #[test]
+    fn parses_connect_request_lines() {
+        assert_eq!(
+            parse_connect(b"CONNECT kms.us-east-1.amazonaws.com:443 HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Ok(target("kms.us-east-1.amazonaws.com", 443))
+        );
+        assert_eq!(parse_connect(b"CONNECT [::1]:8443 HTTP/1.0\r\n\r\n"), Ok(target("::1", 8443)));
+        assert_eq!(parse_connect(b"GET http://example.com/ HTTP/1.1\r\n\r\n"), Err(405));
+        assert_eq!(parse_connect(b"CONNECT example.com HTTP/1.1\r\n\r\n"), Err(400));
+        assert_eq!(parse_connect(b"CONNECT example.com:0 HTTP/1.1\r\n\r\n"), Err(400));
+        assert_eq!(parse_connect(b"CONNECT :443 HTTP/1.1\r\n\r\n"), Err(400));
+        assert_eq!(parse_connect(b"CONNECT example.com:443 SPDY/3\r\n\r\n"), Err(400));
+        assert_eq!(parse_connect(b"CONNECT  example.com:443 HTTP/1.1\r\n\r\n"), Err(400));
+    }
+
+    #[test]
+    fn request_head_keeps_pipelined_bytes() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"CONNECT a:1 HTTP/1.1\r\n\r").unwrap();
+        client.write_all(b"\n\x16\x03\x01").unwrap();
+        let (head, rest) = read_request_head(server.into_raw_fd()).unwrap();
+        assert_eq!(head, b"CONNECT a:1 HTTP/1.1\r\n\r\n");
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[test]
+    fn oversized_request_head_is_rejected() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(&[b'a'; MAX_REQUEST_HEAD + 1024]).unwrap();
+        let err = read_request_head(server.into_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    fn tunnel_request(request: &[u8], allowed_hosts: &[String]) -> (UnixStream, Arc<ProxyCounters>) {
+        let (mut enclave, host) = UnixStream::pair().unwrap();
+        enclave.write_all(request).unwrap();
+        let counters = Arc::new(ProxyCounters::default());
+        let conn = host.into_raw_fd();
+        serve_tunnel(conn, allowed_hosts, Duration::from_secs(1), &counters);
+        unsafe { libc::close(conn); }
+        (enclave, counters)
+    }
+
+    #[test]
+    fn tunnel_relays_to_allowed_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let echo = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\nhello", port);
+        let (mut enclave, counters) = tunnel_request(request.as_bytes(), &["127.0.0.1".to_string()]);
+
+        let expected = status_response(200);
+        let mut reply = vec![0u8; expected.len() + 5];
+        enclave.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..expected.len()], expected.as_bytes());
+        assert_eq!(&reply[expected.len()..], b"hello");
+        echo.join().unwrap();
+        assert_eq!(counters.accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn tunnel_refuses_hosts_outside_allowlist() {
+        let (mut enclave, counters) =
+            tunnel_request(b"CONNECT evil.com:443 HTTP/1.1\r\n\r\n", &["example.com".to_string()]);
+        let mut reply = String::new();
+        enclave.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 403 "), "{}", reply);
+        assert_eq!(counters.rejected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn tunnel_reports_unreachable_targets() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port);
+        let (mut enclave, counters) = tunnel_request(request.as_bytes(), &["127.0.0.1".to_string()]);
+        let mut reply = String::new();
+        enclave.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 502 "), "{}", reply);
+        assert_eq!(counters.dial_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn status_responses_close_the_connection() {
+        assert_eq!(status_response(200), "HTTP/1.1 200 Connection Established\r\n\r\n");
+        assert_eq!(
+            status_response(405),
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
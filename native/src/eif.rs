@@ -0,0 +1,242 @@
+//! PCR prediction from Enclave Image Files.
+//!
+//! An EIF is a big-endian header followed by typed sections (kernel,
+//! cmdline, ramdisks, signature, metadata). The enclave's boot measures
+//! the sections into PCR0 (kernel, cmdline and every ramdisk), PCR1
+//! (kernel, cmdline and the first ramdisk) and PCR2 (the remaining
+//! ramdisks), each as a single extend of a zeroed PCR with the SHA-384 of
+//! the measured bytes. predictPcrsFromEif() recomputes them, the same
+//! values `nitro-cli describe-eif` prints:
+//!
+//! ```js
+//! const { pcr0 } = await predictPcrsFromEif('enclave.eif');
+//! const policy = new AttestationPolicy().requirePcr(0, pcr0);
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use sha2::{Digest, Sha384};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+const MAGIC: &[u8; 4] = b".eif";
+const MAX_SECTIONS: usize = 32;
+/// magic, version, flags, default_mem, default_cpus, reserved,
+/// num_sections, section_offsets, section_sizes, unused, eif_crc32.
+const HEADER_LEN: usize = 4 + 2 + 2 + 8 + 8 + 2 + 2 + 8 * MAX_SECTIONS * 2 + 4 + 4;
+/// section_type, flags, section_size.
+const SECTION_HEADER_LEN: usize = 2 + 2 + 8;
+
+const SECTION_KERNEL: u16 = 1;
+const SECTION_CMDLINE: u16 = 2;
+const SECTION_RAMDISK: u16 = 3;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[napi(object)]
+pub struct EifPcrs {
+    /// Enclave image: kernel, cmdline and every ramdisk.
+    pub pcr0: Buffer,
+    /// Linux kernel and bootstrap: kernel, cmdline and the first ramdisk.
+    pub pcr1: Buffer,
+    /// Application: the ramdisks after the first.
+    pub pcr2: Buffer,
+}
+
+/// napi-free form of EifPcrs.
+#[derive(Debug, PartialEq)]
+pub struct Pcrs {
+    pcr0: Vec<u8>,
+    pcr1: Vec<u8>,
+    pcr2: Vec<u8>,
+}
+
+/// Compute the PCR0/1/2 an enclave booted from the EIF at `path` will
+/// report. The file is read on the libuv thread pool.
+#[napi(ts_return_type = "Promise<EifPcrs>")]
+pub fn predict_pcrs_from_eif(path: String) -> AsyncTask<PredictPcrsTask> {
+    AsyncTask::new(PredictPcrsTask { path })
+}
+
+pub struct PredictPcrsTask {
+    path: String,
+}
+
+impl Task for PredictPcrsTask {
+    type Output = Pcrs;
+    type JsValue = EifPcrs;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let file = File::open(&self.path)
+            .map_err(|e| Error::from_reason(format!("Failed to open EIF {}: {}", self.path, e)))?;
+        measure(BufReader::new(file))
+    }
+
+    fn resolve(&mut self, _env: Env, pcrs: Self::Output) -> Result<Self::JsValue> {
+        Ok(EifPcrs {
+            pcr0: pcrs.pcr0.into(),
+            pcr1: pcrs.pcr1.into(),
+            pcr2: pcrs.pcr2.into(),
+        })
+    }
+}
+
+/// Hash the measured sections of the EIF read from `reader`.
+fn measure(mut reader: impl Read + Seek) -> Result<Pcrs> {
+    let mut header = [0u8; HEADER_LEN];
+    read_exact(&mut reader, &mut header, "header")?;
+    if &header[..4] != MAGIC {
+        return Err(Error::from_reason("Not an EIF file: bad magic"));
+    }
+    let num_sections = be_u16(&header[26..]) as usize;
+    if num_sections > MAX_SECTIONS {
+        return Err(Error::from_reason(format!(
+            "Invalid EIF: {} sections, at most {} allowed",
+            num_sections, MAX_SECTIONS
+        )));
+    }
+
+    let mut image = Sha384::new();
+    let mut bootstrap = Sha384::new();
+    let mut app = Sha384::new();
+    let mut ramdisks = 0;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    for index in 0..num_sections {
+        let offset = be_u64(&header[28 + 8 * index..]);
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| Error::from_reason(format!("Failed to read EIF: {}", e)))?;
+        let mut section = [0u8; SECTION_HEADER_LEN];
+        read_exact(&mut reader, &mut section, "section header")?;
+        let section_type = be_u16(&section);
+        let mut remaining = be_u64(&section[4..]);
+
+        let mut hashers: Vec<&mut Sha384> = match section_type {
+            SECTION_KERNEL | SECTION_CMDLINE => vec![&mut image, &mut bootstrap],
+            SECTION_RAMDISK if ramdisks == 0 => vec![&mut image, &mut bootstrap],
+            SECTION_RAMDISK => vec![&mut image, &mut app],
+            // Signature, metadata and unknown sections aren't measured.
+            _ => continue,
+        };
+        if section_type == SECTION_RAMDISK {
+            ramdisks += 1;
+        }
+        while remaining > 0 {
+            let len = remaining.min(CHUNK_SIZE as u64) as usize;
+            read_exact(&mut reader, &mut buf[..len], "section")?;
+            for hasher in hashers.iter_mut() {
+                hasher.update(&buf[..len]);
+            }
+            remaining -= len as u64;
+        }
+    }
+
+    Ok(Pcrs {
+        pcr0: extend(image),
+        pcr1: extend(bootstrap),
+        pcr2: extend(app),
+    })
+}
+
+/// A zeroed PCR extended once with the digest in `hasher`.
+fn extend(hasher: Sha384) -> Vec<u8> {
+    let mut pcr = Sha384::new();
+    pcr.update([0u8; 48]);
+    pcr.update(hasher.finalize());
+    pcr.finalize().to_vec()
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8], what: &str) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            Error::from_reason(format!("Invalid EIF: truncated {}", what))
+        } else {
+            Error::from_reason(format!("Failed to read EIF: {}", e))
+        }
+    })
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An EIF with `sections` (type, data) laid out back to back.
+    fn eif(sections: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..6].copy_from_slice(&4u16.to_be_bytes());
+        header[26..28].copy_from_slice(&(sections.len() as u16).to_be_bytes());
+        let mut body = Vec::new();
+        for (index, (section_type, data)) in sections.iter().enumerate() {
+            let offset = (HEADER_LEN + body.len()) as u64;
+            header[28 + 8 * index..36 + 8 * index].copy_from_slice(&offset.to_be_bytes());
+            let size_at = 28 + 8 * MAX_SECTIONS + 8 * index;
+            header[size_at..size_at + 8].copy_from_slice(&(data.len() as u64).to_be_bytes());
+            body.extend_from_slice(&section_type.to_be_bytes());
+            body.extend_from_slice(&0u16.to_be_bytes());
+            body.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        header.extend(body);
+        header
+    }
+
+    fn pcr(measured: &[u8]) -> Vec<u8> {
+        let mut extended = vec![0u8; 48];
+        extended.extend(Sha384::digest(measured));
+        Sha384::digest(&extended).to_vec()
+    }
+
+    #[test]
+    fn measures_sections_into_pcr0_1_and_2() {
+        let large = vec![0x5a; CHUNK_SIZE * 2 + 17];
+        let image = eif(&[
+            (SECTION_KERNEL, b"kernel"),
+            (SECTION_CMDLINE, b"console=ttyS0"),
+            (SECTION_RAMDISK, b"init"),
+            (SECTION_RAMDISK, &large),
+            (SECTION_RAMDISK, b"app"),
+            (4, b"signature"),
+            (5, b"{\"metadata\":true}"),
+        ]);
+        let pcrs = measure(Cursor::new(image)).unwrap();
+
+        let bootstrap = [&b"kernel"[..], b"console=ttyS0", b"init"].concat();
+        let app = [&large[..], b"app"].concat();
+        assert_eq!(pcrs.pcr0, pcr(&[&bootstrap[..], &app].concat()));
+        assert_eq!(pcrs.pcr1, pcr(&bootstrap));
+        assert_eq!(pcrs.pcr2, pcr(&app));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let err = measure(Cursor::new(b"\x7fELF".repeat(200))).unwrap_err();
+        assert_eq!(err.reason, "Not an EIF file: bad magic");
+
+        let err = measure(Cursor::new(&MAGIC[..])).unwrap_err();
+        assert_eq!(err.reason, "Invalid EIF: truncated header");
+
+        let mut image = eif(&[(SECTION_KERNEL, b"kernel")]);
+        image.truncate(image.len() - 1);
+        let err = measure(Cursor::new(image)).unwrap_err();
+        assert_eq!(err.reason, "Invalid EIF: truncated section");
+
+        let mut image = eif(&[]);
+        image[26..28].copy_from_slice(&33u16.to_be_bytes());
+        let err = measure(Cursor::new(image)).unwrap_err();
+        assert!(err.reason.contains("33 sections"), "{}", err.reason);
+    }
+}
@@ -0,0 +1,214 @@
+//! Host-side lookup of a running enclave's CID.
+//!
+//! Enclave CIDs are assigned at launch and change across restarts unless
+//! pinned, so host code shouldn't hardcode them. getEnclaveCid() asks
+//! `nitro-cli describe-enclaves` instead:
+//!
+//! ```js
+//! const cid = await getEnclaveCid({ enclaveName: 'payments' });
+//! const stream = await vsockConnectAsync(cid, 5000);
+//! ```
+//!
+//! Only enclaves in the RUNNING state count. Without a name or ID there
+//! must be exactly one.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use serde_json::Value;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::errors;
+use crate::workers::WorkerTask;
+
+const DEFAULT_NITRO_CLI: &str = "nitro-cli";
+const DEFAULT_TIMEOUT_MS: u32 = 5000;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EnclaveCidOptions {
+    /// The EnclaveName given to nitro-cli run-enclave.
+    pub enclave_name: Option<String>,
+    /// The EnclaveID, e.g. "i-0123456789abcdef0-enc0123456789abcdef".
+    pub enclave_id: Option<String>,
+    /// nitro-cli executable (default "nitro-cli" on the PATH).
+    pub nitro_cli: Option<String>,
+    /// Kill nitro-cli if it takes longer than this (default 5000).
+    pub timeout_ms: Option<u32>,
+}
+
+/// Pick the CID of the one running enclave in describe-enclaves `output`
+/// matching `name` and `id`.
+fn select_cid(output: &[u8], name: Option<&str>, id: Option<&str>) -> Result<u32> {
+    let enclaves: Vec<Value> = serde_json::from_slice(output)
+        .map_err(|e| Error::from_reason(format!("nitro-cli describe-enclaves output: {}", e)))?;
+    let field =
+        |enclave: &Value, key: &str| enclave.get(key).and_then(Value::as_str).map(str::to_string);
+    let matches: Vec<&Value> = enclaves
+        .iter()
+        .filter(|enclave| field(enclave, "State").as_deref() == Some("RUNNING"))
+        .filter(|enclave| {
+            name.is_none_or(|name| field(enclave, "EnclaveName").as_deref() == Some(name))
+        })
+        .filter(|enclave| id.is_none_or(|id| field(enclave, "EnclaveID").as_deref() == Some(id)))
+        .collect();
+    let wanted = match (name, id) {
+        (Some(name), _) => format!("named {:?}", name),
+        (None, Some(id)) => format!("with ID {:?}", id),
+        (None, None) => "at all".to_string(),
+    };
+    match matches.as_slice() {
+        [] => Err(Error::from_reason(format!(
+            "EnclaveNotFound: no running enclave {}",
+            wanted
+        ))),
+        [enclave] => enclave
+            .get("EnclaveCID")
+            .and_then(Value::as_u64)
+            .and_then(|cid| u32::try_from(cid).ok())
+            .ok_or_else(|| {
+                Error::from_reason("nitro-cli describe-enclaves output has no EnclaveCID")
+            }),
+        several => Err(Error::from_reason(format!(
+            "AmbiguousEnclave: {} running enclaves ({}); pass enclaveName or enclaveId",
+            several.len(),
+            several
+                .iter()
+                .map(|enclave| field(enclave, "EnclaveName").unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Run `program describe-enclaves`, killing it after `timeout`.
+fn describe_enclaves(program: &str, timeout: Duration) -> Result<Vec<u8>> {
+    let call = format!("spawn({} describe-enclaves)", program);
+    let mut child = Command::new(program)
+        .arg("describe-enclaves")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| errors::os_error(&call, e))?;
+    let deadline = Instant::now() + timeout;
+    // describe-enclaves prints little, so it can't block on a full pipe
+    let status = loop {
+        match child.try_wait().map_err(|e| errors::os_error(&call, e))? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::from_reason(format!(
+                    "ETIMEDOUT: {} describe-enclaves timed out after {:?}",
+                    program, timeout
+                )));
+            }
+            None => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let mut stdout = Vec::new();
+    let mut stderr = String::new();
+    if let Some(mut out) = child.stdout.take() {
+        let _ = out.read_to_end(&mut stdout);
+    }
+    if let Some(mut err) = child.stderr.take() {
+        let _ = err.read_to_string(&mut stderr);
+    }
+    if !status.success() {
+        return Err(Error::from_reason(format!(
+            "{} describe-enclaves failed ({}): {}",
+            program,
+            status,
+            stderr.trim()
+        )));
+    }
+    Ok(stdout)
+}
+
+/// The CID of a running enclave, from `nitro-cli describe-enclaves`.
+/// Rejects with code "EnclaveNotFound" if none matches, or
+/// "AmbiguousEnclave" if several do.
+#[napi(ts_return_type = "Promise<number>")]
+pub fn get_enclave_cid(options: Option<EnclaveCidOptions>) -> WorkerTask<EnclaveCidTask> {
+    WorkerTask::new(EnclaveCidTask(options.unwrap_or_default()))
+}
+
+pub struct EnclaveCidTask(EnclaveCidOptions);
+
+impl Task for EnclaveCidTask {
+    type Output = u32;
+    type JsValue = u32;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let options = &self.0;
+        let program = options.nitro_cli.as_deref().unwrap_or(DEFAULT_NITRO_CLI);
+        let timeout_ms = options.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+        let output = describe_enclaves(program, Duration::from_millis(timeout_ms as u64))?;
+        select_cid(
+            &output,
+            options.enclave_name.as_deref(),
+            options.enclave_id.as_deref(),
+        )
+    }
+
+    fn resolve(&mut self, _env: Env, cid: Self::Output) -> Result<Self::JsValue> {
+        Ok(cid)
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIBED: &str = r#"[
+        {"EnclaveName": "payments", "EnclaveID": "i-0abc-enc01", "EnclaveCID": 16, "State": "RUNNING"},
+        {"EnclaveName": "kyc", "EnclaveID": "i-0abc-enc02", "EnclaveCID": 17, "State": "RUNNING"},
+        {"EnclaveName": "old", "EnclaveID": "i-0abc-enc03", "EnclaveCID": 18, "State": "TERMINATING"}
+    ]"#;
+
+    #[test]
+    fn selects_running_enclaves_by_name_or_id() {
+        let output = DESCRIBED.as_bytes();
+        assert_eq!(select_cid(output, Some("kyc"), None).unwrap(), 17);
+        assert_eq!(select_cid(output, None, Some("i-0abc-enc01")).unwrap(), 16);
+
+        let err = select_cid(output, Some("old"), None).unwrap_err();
+        assert_eq!(
+            err.reason,
+            "EnclaveNotFound: no running enclave named \"old\""
+        );
+        let err = select_cid(output, None, None).unwrap_err();
+        assert_eq!(
+            err.reason,
+            "AmbiguousEnclave: 2 running enclaves (payments, kyc); pass enclaveName or enclaveId"
+        );
+        let single = br#"[{"EnclaveName": "solo", "EnclaveCID": 21, "State": "RUNNING"}]"#;
+        assert_eq!(select_cid(single, None, None).unwrap(), 21);
+        assert!(select_cid(b"[]", None, None)
+            .unwrap_err()
+            .reason
+            .starts_with("EnclaveNotFound"));
+    }
+
+    #[test]
+    fn reports_nitro_cli_failures() {
+        let err = describe_enclaves("/nonexistent/nitro-cli", Duration::from_secs(1)).unwrap_err();
+        assert!(err.reason.starts_with("ENOENT: spawn("), "{}", err.reason);
+        let err = describe_enclaves("false", Duration::from_secs(5)).unwrap_err();
+        assert!(
+            err.reason.starts_with("false describe-enclaves failed"),
+            "{}",
+            err.reason
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Kernel entropy seeding from the NSM.
+//!
+//! An enclave kernel boots with almost no entropy, so early getrandom()
+//! callers can block or get weakly seeded output. seedKernelEntropy() pulls
+//! bytes from the NSM's hardware RNG (GetRandom) and credits them to the
+//! kernel pool with the RNDADDENTROPY ioctl on /dev/urandom, which needs
+//! CAP_SYS_ADMIN. startSeeder() repeats that on a native thread.
+//!
+//! getRandom() is the other direction: entropy for application code that
+//! runs both inside and outside enclaves. It reads the kernel's
+//! getrandom(2) and, with `preferNsm`, tries the NSM first, falling back
+//! to the kernel wherever there is no NSM:
+//!
+//! ```js
+//! const key = getRandom(32, { preferNsm: true });
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::nsm::{self, coded, Device, DeviceConfig, NsmOptions};
+use crate::platform;
+
+/// RNDADDENTROPY: _IOW('R', 0x03, int[2]) from linux/random.h.
+const RNDADDENTROPY: u32 = 0x4008_5203;
+
+/// CAP_SYS_ADMIN's bit in /proc/self/status CapEff.
+const CAP_SYS_ADMIN: u32 = 21;
+
+const DEFAULT_SEED_BYTES: u32 = 256;
+/// The kernel input pool is 4096 bits; more per call adds nothing.
+const MAX_SEED_BYTES: u32 = 512;
+
+/// Seed the kernel entropy pool with `bytes` (default 256, at most 512)
+/// bytes from the NSM, crediting them in full. Returns the byte count.
+///
+/// Throws with `.code` "PermissionDenied" without CAP_SYS_ADMIN (checked
+/// before any randomness is drawn), as well as the usual NSM errors.
+#[napi]
+pub fn seed_kernel_entropy(bytes: Option<u32>, options: Option<NsmOptions>) -> coded::Result<u32> {
+    let bytes = seed_bytes(bytes).map_err(coded)?;
+    nsm::with_device(options, |device| seed(device, bytes))
+}
+
+fn seed_bytes(bytes: Option<u32>) -> Result<u32> {
+    match bytes.unwrap_or(DEFAULT_SEED_BYTES) {
+        bytes @ 1..=MAX_SEED_BYTES => Ok(bytes),
+        _ => Err(Error::from_reason(format!(
+            "bytes must be between 1 and {}",
+            MAX_SEED_BYTES
+        ))),
+    }
+}
+
+fn seed(device: &Device, bytes: u32) -> Result<u32> {
+    platform::require_linux("RNDADDENTROPY")?;
+    if cap_effective("/proc/self/status", CAP_SYS_ADMIN) == Some(false) {
+        return Err(permission_denied());
+    }
+    let data = device.random(Some(bytes))?;
+    add_entropy(&data)?;
+    Ok(bytes)
+}
+
+fn permission_denied() -> Error {
+    Error::from_reason(
+        "PermissionDenied: RNDADDENTROPY requires CAP_SYS_ADMIN; run the seeder as root",
+    )
+}
+
+/// Whether the process has capability `cap`, from the CapEff line of a
+/// /proc status file; None if it can't be read.
+fn cap_effective(status_path: &str, cap: u32) -> Option<bool> {
+    let status = std::fs::read_to_string(status_path).ok()?;
+    let hex = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    let caps = u64::from_str_radix(hex, 16).ok()?;
+    Some(caps & (1 << cap) != 0)
+}
+
+/// struct rand_pool_info: entropy_count (bits), buf_size (bytes), buf.
+fn rand_pool_info(data: &[u8]) -> Vec<u8> {
+    let mut info = Vec::with_capacity(8 + data.len());
+    info.extend_from_slice(&((data.len() * 8) as i32).to_ne_bytes());
+    info.extend_from_slice(&(data.len() as i32).to_ne_bytes());
+    info.extend_from_slice(data);
+    info
+}
+
+fn add_entropy(data: &[u8]) -> Result<()> {
+    let path = std::ffi::CString::new("/dev/urandom").unwrap();
+    let info = rand_pool_info(data);
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(Error::from_reason(format!(
+                "/dev/urandom open failed: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        let ret = libc::ioctl(fd, RNDADDENTROPY as _, info.as_ptr());
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+        if ret < 0 {
+            return Err(match err.raw_os_error() {
+                Some(libc::EPERM) => permission_denied(),
+                _ => Error::from_reason(format!("RNDADDENTROPY failed: {}", err)),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct GetRandomOptions {
+    /// Draw from the NSM's hardware RNG when one is available (default
+    /// false). If it can't be opened or GetRandom fails, getRandom() falls
+    /// back to the kernel.
+    pub prefer_nsm: Option<bool>,
+    /// The NSM device to try with preferNsm.
+    pub nsm: Option<NsmOptions>,
+}
+
+/// `size` random bytes (at most 1 MiB) from the kernel's getrandom(2), or
+/// from the NSM with `preferNsm` when there is one. Unlike nsmGetRandom(),
+/// works on any host.
+#[napi]
+pub fn get_random(size: u32, options: Option<GetRandomOptions>) -> Result<Buffer> {
+    let options = options.unwrap_or_default();
+    let nsm = match options.prefer_nsm {
+        Some(true) => Some(DeviceConfig::from_js(options.nsm)?),
+        _ => None,
+    };
+    random_bytes(size, nsm).map(Buffer::from)
+}
+
+/// `size` bytes from the NSM described by `nsm` if given and usable,
+/// otherwise from the kernel.
+fn random_bytes(size: u32, nsm: Option<DeviceConfig>) -> Result<Vec<u8>> {
+    if size > nsm::MAX_RANDOM_BYTES {
+        return Err(Error::from_reason(format!(
+            "size must be at most {}",
+            nsm::MAX_RANDOM_BYTES
+        )));
+    }
+    if let Some(config) = nsm {
+        match Device::open_with(config).and_then(|device| device.random(Some(size))) {
+            Ok(data) => return Ok(data),
+            Err(e) => {
+                tracing::debug!(error = %e.reason, "NSM GetRandom unavailable, using getrandom()")
+            }
+        }
+    }
+    let mut data = vec![0u8; size as usize];
+    fill_random(&mut data)?;
+    Ok(data)
+}
+
+/// Fill `buf` from getrandom(2), blocking only until the kernel pool is
+/// first initialized.
+#[cfg(target_os = "linux")]
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let n = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(crate::errors::os_error("getrandom", err));
+        }
+        filled += n as usize;
+    }
+    Ok(())
+}
+
+/// Fill `buf` from the platform's CSPRNG.
+#[cfg(not(target_os = "linux"))]
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    use ring::rand::SecureRandom;
+    ring::rand::SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| Error::from_reason("System random source failed"))
+}
+
+/// Reseeds the kernel from the NSM every `intervalMs` on a native thread.
+/// Failures after the first round are recorded in `lastError` rather than
+/// thrown; the seeder keeps trying.
+#[napi]
+pub struct EntropySeeder {
+    state: Arc<SeederState>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct SeederState {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    rounds: AtomicU32,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Seed once (throwing on failure, e.g. without CAP_SYS_ADMIN), then keep
+/// seeding `bytes` bytes every `intervalMs` until stop().
+#[napi]
+pub fn start_seeder(
+    interval_ms: u32,
+    bytes: Option<u32>,
+    options: Option<NsmOptions>,
+) -> coded::Result<EntropySeeder> {
+    if interval_ms == 0 {
+        return Err(coded(Error::from_reason("intervalMs must be positive")));
+    }
+    let bytes = seed_bytes(bytes).map_err(coded)?;
+    let device = DeviceConfig::from_js(options)
+        .and_then(Device::open_with)
+        .map_err(coded)?;
+    seed(&device, bytes).map_err(coded)?;
+
+    let state = Arc::new(SeederState {
+        stopped: Mutex::new(false),
+        wake: Condvar::new(),
+        rounds: AtomicU32::new(1),
+        last_error: Mutex::new(None),
+    });
+    let thread = {
+        let state = state.clone();
+        let interval = Duration::from_millis(interval_ms.into());
+        std::thread::spawn(move || loop {
+            let stopped = state.stopped.lock().unwrap();
+            let (stopped, _) = state
+                .wake
+                .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                .unwrap();
+            if *stopped {
+                return;
+            }
+            drop(stopped);
+            match seed(&device, bytes) {
+                Ok(_) => {
+                    state.rounds.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => *state.last_error.lock().unwrap() = Some(e.reason),
+            }
+        })
+    };
+    Ok(EntropySeeder {
+        state,
+        thread: Mutex::new(Some(thread)),
+    })
+}
+
+#[napi]
+impl EntropySeeder {
+    /// Successful seeding rounds, including the initial one.
+    #[napi(getter)]
+    pub fn rounds(&self) -> u32 {
+        self.state.rounds.load(Ordering::Relaxed)
+    }
+
+    /// The most recent background failure, if any.
+    #[napi(getter)]
+    pub fn last_error(&self) -> Option<String> {
+        self.state.last_error.lock().unwrap().clone()
+    }
+
+    /// Stop seeding and wait for the thread to exit. Safe to call
+    /// multiple times.
+    #[napi]
+    pub fn stop(&self) {
+        *self.state.stopped.lock().unwrap() = true;
+        self.state.wake.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for EntropySeeder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rand_pool_info_layout() {
+        let info = rand_pool_info(&[7; 4]);
+        assert_eq!(&info[..4], &32i32.to_ne_bytes());
+        assert_eq!(&info[4..8], &4i32.to_ne_bytes());
+        assert_eq!(&info[8..], &[7; 4]);
+    }
+
+    #[test]
+    fn reads_effective_capabilities() {
+        let dir = std::env::temp_dir().join(format!("tytle-entropy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let status = dir.join("status");
+        std::fs::write(&status, "Name:\tnode\nCapEff:\t0000000000200000\n").unwrap();
+        let path = status.to_str().unwrap();
+        assert_eq!(cap_effective(path, CAP_SYS_ADMIN), Some(true));
+        assert_eq!(cap_effective(path, 12), Some(false));
+        assert_eq!(cap_effective("/nonexistent/status", CAP_SYS_ADMIN), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seed_size_is_bounded() {
+        assert_eq!(seed_bytes(None).unwrap(), DEFAULT_SEED_BYTES);
+        assert!(seed_bytes(Some(0)).is_err());
+        assert!(seed_bytes(Some(MAX_SEED_BYTES + 1)).is_err());
+    }
+
+    #[test]
+    fn get_random_falls_back_to_the_kernel() {
+        let mut a = [0u8; 64];
+        let mut b = [0u8; 64];
+        fill_random(&mut a).unwrap();
+        fill_random(&mut b).unwrap();
+        assert_ne!(a, b);
+        fill_random(&mut []).unwrap();
+
+        let missing = DeviceConfig::new(Some("/nonexistent/nsm".into()), None).unwrap();
+        assert_eq!(random_bytes(48, Some(missing)).unwrap().len(), 48);
+        assert_eq!(random_bytes(0, None).unwrap(), Vec::<u8>::new());
+        assert!(random_bytes(nsm::MAX_RANDOM_BYTES + 1, None).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//! Structured errors for the vsock and NSM exports.
+//!
+//! Errors thrown by VsockListener, VsockStream, vsockConnectAsync() and the
+//! NSM exports carry the same properties as Node's own system errors, so JS
+//! can branch on them instead of matching messages:
+//!
+//! - `.code`: the errno name of a failed system call (e.g. "ECONNRESET",
+//!   "EAGAIN", "ETIMEDOUT"), the NSM's error code (e.g. "InvalidIndex"),
+//!   "ResponseTooLarge" or "UnsupportedPlatform", else "GenericFailure".
+//! - `.syscall`: the failed call, e.g. "connect" or "read".
+//! - `.errno`: the negated errno, as in Node (-104 for ECONNRESET).
+//!
+//! Internally errors stay napi Errors and the details live in the reason:
+//! a "CODE: " prefix (see nsm::coded()), then `call(args) failed: ...` for
+//! system calls. structured() turns that into the JS shape at the export
+//! boundary, where an Env is available.
+
+use napi::bindgen_prelude::*;
+use napi::JsUnknown;
+
+/// Errno names reported as `.code`.
+const ERRNO_NAMES: &[(i32, &str)] = &[
+    (libc::EPERM, "EPERM"),
+    (libc::ENOENT, "ENOENT"),
+    (libc::EINTR, "EINTR"),
+    (libc::EIO, "EIO"),
+    (libc::ENXIO, "ENXIO"),
+    (libc::EBADF, "EBADF"),
+    (libc::EAGAIN, "EAGAIN"),
+    (libc::ENOMEM, "ENOMEM"),
+    (libc::EACCES, "EACCES"),
+    (libc::EFAULT, "EFAULT"),
+    (libc::EBUSY, "EBUSY"),
+    (libc::EEXIST, "EEXIST"),
+    (libc::ENODEV, "ENODEV"),
+    (libc::EINVAL, "EINVAL"),
+    (libc::ENFILE, "ENFILE"),
+    (libc::EMFILE, "EMFILE"),
+    (libc::ENOTTY, "ENOTTY"),
+    (libc::ENOSPC, "ENOSPC"),
+    (libc::ESPIPE, "ESPIPE"),
+    (libc::EPIPE, "EPIPE"),
+    (libc::ENOSYS, "ENOSYS"),
+    (libc::ENOTSOCK, "ENOTSOCK"),
+    (libc::EMSGSIZE, "EMSGSIZE"),
+    (libc::EPROTONOSUPPORT, "EPROTONOSUPPORT"),
+    (libc::EOPNOTSUPP, "EOPNOTSUPP"),
+    (libc::EAFNOSUPPORT, "EAFNOSUPPORT"),
+    (libc::EADDRINUSE, "EADDRINUSE"),
+    (libc::EADDRNOTAVAIL, "EADDRNOTAVAIL"),
+    (libc::ENETDOWN, "ENETDOWN"),
+    (libc::ENETUNREACH, "ENETUNREACH"),
+    (libc::ECONNABORTED, "ECONNABORTED"),
+    (libc::ECONNRESET, "ECONNRESET"),
+    (libc::ENOBUFS, "ENOBUFS"),
+    (libc::EISCONN, "EISCONN"),
+    (libc::ENOTCONN, "ENOTCONN"),
+    (libc::ETIMEDOUT, "ETIMEDOUT"),
+    (libc::ECONNREFUSED, "ECONNREFUSED"),
+    (libc::EHOSTDOWN, "EHOSTDOWN"),
+    (libc::EHOSTUNREACH, "EHOSTUNREACH"),
+    (libc::EALREADY, "EALREADY"),
+    (libc::EINPROGRESS, "EINPROGRESS"),
+    (libc::ECANCELED, "ECANCELED"),
+];
+
+/// Fallback `.code` for errnos missing from ERRNO_NAMES.
+pub(crate) const UNKNOWN_ERRNO: &str = "EUNKNOWN";
+
+fn errno_name(errno: i32) -> Option<&'static str> {
+    ERRNO_NAMES
+        .iter()
+        .find(|&&(n, _)| n == errno)
+        .map(|&(_, name)| name)
+}
+
+fn errno_number(name: &str) -> Option<i32> {
+    ERRNO_NAMES
+        .iter()
+        .find(|&&(_, n)| n == name)
+        .map(|&(errno, _)| errno)
+}
+
+/// Error for a failed system call: "ECONNREFUSED: connect(cid=3, port=5000)
+/// failed: Connection refused (os error 111)". `call` names the syscall
+/// and its interesting arguments.
+pub(crate) fn os_error(call: impl std::fmt::Display, err: std::io::Error) -> Error {
+    let code = errno_code(&err);
+    if code == Some("EAGAIN") {
+        // Usually a read or write hitting its timeout
+        tracing::debug!(code, call = %call, error = %err, "system call failed");
+    } else {
+        tracing::warn!(code, call = %call, error = %err, "system call failed");
+    }
+    match code {
+        Some(code) => Error::from_reason(format!("{}: {} failed: {}", code, call, err)),
+        None => Error::from_reason(format!("{} failed: {}", call, err)),
+    }
+}
+
+/// The errno name for `err`, for reasons os_error() can't phrase.
+pub(crate) fn errno_code(err: &std::io::Error) -> Option<&'static str> {
+    err.raw_os_error()
+        .map(|errno| errno_name(errno).unwrap_or(UNKNOWN_ERRNO))
+}
+
+/// The "CODE" of a "CODE: message" reason: an identifier starting with an
+/// uppercase letter, e.g. "InvalidIndex" or "ECONNRESET".
+pub(crate) fn reason_code(reason: &str) -> Option<&str> {
+    let (code, _) = reason.split_once(": ")?;
+    let valid = code.starts_with(|c: char| c.is_ascii_uppercase())
+        && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some(code)
+}
+
+/// What structured() attaches to the JS error.
+#[derive(Debug, PartialEq)]
+struct Details<'a> {
+    code: &'a str,
+    syscall: Option<&'a str>,
+    /// Negated, as in Node.
+    errno: Option<i32>,
+}
+
+impl<'a> Details<'a> {
+    fn parse(status: &'a str, reason: &'a str) -> Self {
+        let Some(code) = reason_code(reason) else {
+            return Details {
+                code: status,
+                syscall: None,
+                errno: None,
+            };
+        };
+        // Only errno codes come from system calls
+        let errno = os_errno(reason).or_else(|| errno_number(code));
+        let syscall = errno.and_then(|_| {
+            let call = &reason[code.len() + 2..];
+            let name = call.split('(').next()?;
+            let is_call = name.len() < call.len()
+                && !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            is_call.then_some(name)
+        });
+        Details {
+            code,
+            syscall,
+            errno: errno.map(|n| -n),
+        }
+    }
+}
+
+/// The N of a trailing "(os error N)", as io::Error displays it.
+fn os_errno(reason: &str) -> Option<i32> {
+    let rest = reason.strip_suffix(')')?;
+    let (_, n) = rest.rsplit_once("(os error ")?;
+    n.parse().ok()
+}
+
+/// `err` as a JS Error with `.code`, and `.syscall` and `.errno` for
+/// failed system calls.
+pub(crate) fn to_js<S: AsRef<str>>(env: &Env, err: Error<S>) -> Error {
+    let details = Details::parse(err.status.as_ref(), &err.reason);
+    let build = || -> Result<JsUnknown> {
+        let mut object = env.create_error(Error::from_reason(err.reason.clone()))?;
+        object.set_named_property("code", env.create_string(details.code)?)?;
+        if let Some(syscall) = details.syscall {
+            object.set_named_property("syscall", env.create_string(syscall)?)?;
+        }
+        if let Some(errno) = details.errno {
+            object.set_named_property("errno", env.create_int32(errno)?)?;
+        }
+        Ok(object.into_unknown())
+    };
+    match build() {
+        Ok(object) => Error::from(object),
+        Err(e) => e,
+    }
+}
+
+/// `result` with its error converted by to_js().
+pub(crate) fn structured<T, S: AsRef<str>>(
+    env: &Env,
+    result: std::result::Result<T, Error<S>>,
+) -> Result<T> {
+    result.map_err(|err| to_js(env, err))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(err: &Error) -> Details<'_> {
+        Details::parse(err.status.as_ref(), &err.reason)
+    }
+
+    #[test]
+    fn system_call_failures_carry_code_syscall_and_errno() {
+        let err = os_error(
+            "connect(cid=3, port=5000)",
+            std::io::Error::from_raw_os_error(libc::ECONNREFUSED),
+        );
+        assert!(
+            err.reason
+                .starts_with("ECONNREFUSED: connect(cid=3, port=5000) failed: "),
+            "{}",
+            err.reason
+        );
+        assert_eq!(
+            details(&err),
+            Details {
+                code: "ECONNREFUSED",
+                syscall: Some("connect"),
+                errno: Some(-libc::ECONNREFUSED),
+            }
+        );
+
+        let err = os_error("read()", std::io::Error::from_raw_os_error(libc::EAGAIN));
+        assert_eq!(details(&err).code, "EAGAIN");
+        assert_eq!(details(&err).syscall, Some("read"));
+
+        // Errnos without a name keep their number
+        let err = os_error("read()", std::io::Error::from_raw_os_error(4000));
+        assert_eq!(details(&err).code, UNKNOWN_ERRNO);
+        assert_eq!(details(&err).errno, Some(-4000));
+
+        // Timeouts detected by poll() rather than reported by the kernel
+        let err = Error::from_reason("ETIMEDOUT: connect(cid=3, port=5000) timed out after 5s");
+        assert_eq!(
+            details(&err),
+            Details {
+                code: "ETIMEDOUT",
+                syscall: Some("connect"),
+                errno: Some(-libc::ETIMEDOUT),
+            }
+        );
+    }
+
+    #[test]
+    fn other_failures_only_carry_a_code() {
+        let err = Error::from_reason("InvalidIndex: NSM DescribePCR failed");
+        assert_eq!(
+            details(&err),
+            Details {
+                code: "InvalidIndex",
+                syscall: None,
+                errno: None
+            }
+        );
+        let err = Error::from_reason("Stream already closed");
+        assert_eq!(
+            details(&err),
+            Details {
+                code: "GenericFailure",
+                syscall: None,
+                errno: None
+            }
+        );
+        let err = Error::from_reason("CBOR decode failed: unexpected end");
+        assert_eq!(details(&err).code, "GenericFailure");
+        let err = os_error("accept()", crate::cancel::cancelled_error());
+        assert_eq!(err.reason, "accept() failed: Operation cancelled");
+        assert_eq!(details(&err).code, "GenericFailure");
+    }
+}
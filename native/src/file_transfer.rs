@@ -0,0 +1,532 @@
+//! Resumable file transfer over vsock.
+//!
+//! For shipping model weights or datasets into the enclave at boot. The
+//! receiver listens, the sender connects:
+//!
+//! ```js
+//! // enclave
+//! const { size, resumedFrom } = await receiveFile('/data/model.bin', { port: 5020 });
+//! // parent
+//! await sendFile('model.bin', { cid: 16, port: 5020 });
+//! ```
+//!
+//! The receiver writes to `<path>.part` and renames it over `path` once
+//! the whole file's SHA-256 matches the sender's. If the connection drops,
+//! the partial file stays, receiveFile() keeps listening, and the next
+//! sendFile() continues from where the last one stopped instead of from
+//! byte zero.
+//!
+//! Protocol, one frame per message (see framing), control messages in
+//! CBOR:
+//!
+//! - sender → receiver: `{"size": int, "sha256": bytes}`.
+//! - receiver → sender: `{"offset": int, "prefix": bytes}`, the length of
+//!   the partial file and the SHA-256 of its contents.
+//! - sender → receiver: `{"offset": int}`, where the data starts: the
+//!   receiver's offset if the prefix matches the file, else 0.
+//! - sender → receiver: the data from that offset in raw frames of at most
+//!   chunkSize bytes.
+//! - receiver → sender: `{"ok": true}`, or `{"error": text}` if the
+//!   checksum didn't match (the partial file is then discarded).
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cancel::{CancelToken, Canceller};
+use crate::workers::WorkerTask;
+use crate::{cbor, errors, framing, vsock};
+
+const DEFAULT_CHUNK_SIZE: u32 = 1024 * 1024;
+const DEFAULT_TIMEOUT_SECS: u32 = 30;
+
+#[napi(object)]
+pub struct SendFileOptions {
+    /// CID of the receiving side.
+    pub cid: u32,
+    pub port: u32,
+    /// Bytes per data frame (default 1 MiB, at most 16 MiB).
+    pub chunk_size: Option<u32>,
+    /// Connect and per-read/write timeout (default 30s).
+    pub timeout_secs: Option<u32>,
+}
+
+#[napi(object)]
+pub struct ReceiveFileOptions {
+    pub port: u32,
+    /// Give up on a sender silent for this long (default 30s); its
+    /// progress is kept for the next one.
+    pub timeout_secs: Option<u32>,
+}
+
+#[napi(object)]
+pub struct FileTransferResult {
+    /// Size of the file in bytes.
+    pub size: f64,
+    /// Bytes that were already on the receiver and not sent again.
+    pub resumed_from: f64,
+    /// Hex SHA-256 of the file.
+    pub sha256: String,
+}
+
+/// What a completed transfer moved.
+#[derive(Debug, PartialEq)]
+pub struct Transfer {
+    size: u64,
+    resumed_from: u64,
+    sha256: [u8; 32],
+}
+
+impl From<Transfer> for FileTransferResult {
+    fn from(transfer: Transfer) -> Self {
+        FileTransferResult {
+            size: transfer.size as f64,
+            resumed_from: transfer.resumed_from as f64,
+            sha256: transfer
+                .sha256
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
+}
+
+fn io_error(context: &str, path: &Path, err: std::io::Error) -> Error {
+    errors::os_error(format!("{}({})", context, path.display()), err)
+}
+
+fn read_control(fd: i32) -> Result<Value> {
+    let frame = framing::read_frame(fd)
+        .map_err(|e| errors::os_error("read()", e))?
+        .ok_or_else(|| Error::from_reason("File transfer peer closed the connection"))?;
+    let message = cbor::decode(&frame)?;
+    if let Some(error) = cbor::map_get(&message, "error").and_then(cbor::as_text) {
+        return Err(Error::from_reason(format!(
+            "File transfer peer error: {}",
+            error
+        )));
+    }
+    Ok(message)
+}
+
+fn write_control(fd: i32, entries: Vec<(&str, Value)>) -> Result<()> {
+    framing::write_frame(fd, &cbor::encode(&cbor::map(entries))?)
+        .map_err(|e| errors::os_error("write()", e))
+}
+
+fn u64_field(message: &Value, name: &str) -> Result<u64> {
+    cbor::map_get(message, name)
+        .and_then(cbor::as_u64)
+        .ok_or_else(|| Error::from_reason(format!("File transfer message has no {}", name)))
+}
+
+fn digest_field(message: &Value, name: &str) -> Result<[u8; 32]> {
+    cbor::map_get(message, name)
+        .and_then(cbor::as_bytes)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::from_reason(format!("File transfer message has no 32-byte {}", name)))
+}
+
+/// Feed the next `len` bytes of `file` to `hasher`.
+fn hash_from(file: &mut File, path: &Path, hasher: &mut Sha256, len: u64) -> Result<()> {
+    let copied =
+        std::io::copy(&mut file.take(len), hasher).map_err(|e| io_error("read", path, e))?;
+    if copied != len {
+        return Err(Error::from_reason(format!(
+            "{} changed while being read",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Send `path` to the receiver on `fd`.
+fn send(fd: i32, path: &Path, chunk_size: usize) -> Result<Transfer> {
+    let mut file = File::open(path).map_err(|e| io_error("open", path, e))?;
+    let size = file
+        .metadata()
+        .map_err(|e| io_error("stat", path, e))?
+        .len();
+    let mut hasher = Sha256::new();
+    hash_from(&mut file, path, &mut hasher, size)?;
+    let sha256: [u8; 32] = hasher.finalize().into();
+    write_control(
+        fd,
+        vec![
+            ("size", Value::Integer(size.into())),
+            ("sha256", Value::Bytes(sha256.to_vec())),
+        ],
+    )?;
+
+    // Resume only if what the receiver has is the start of this file
+    let reply = read_control(fd)?;
+    let offset = u64_field(&reply, "offset")?;
+    let prefix = digest_field(&reply, "prefix")?;
+    let mut start = 0;
+    if offset > 0 && offset <= size {
+        let mut hasher = Sha256::new();
+        file.rewind().map_err(|e| io_error("seek", path, e))?;
+        hash_from(&mut file, path, &mut hasher, offset)?;
+        if <[u8; 32]>::from(hasher.finalize()) == prefix {
+            start = offset;
+        }
+    }
+    write_control(fd, vec![("offset", Value::Integer(start.into()))])?;
+
+    file.seek(SeekFrom::Start(start))
+        .map_err(|e| io_error("seek", path, e))?;
+    let mut chunk = vec![0u8; chunk_size];
+    let mut sent = start;
+    while sent < size {
+        let want = chunk_size.min((size - sent) as usize);
+        file.read_exact(&mut chunk[..want])
+            .map_err(|e| io_error("read", path, e))?;
+        framing::write_frame(fd, &chunk[..want]).map_err(|e| errors::os_error("write()", e))?;
+        sent += want as u64;
+    }
+    read_control(fd)?;
+    Ok(Transfer {
+        size,
+        resumed_from: start,
+        sha256,
+    })
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Receive one file from the sender on `fd` into `path`, resuming from
+/// `<path>.part`.
+fn receive(fd: i32, path: &Path) -> Result<Transfer> {
+    let header = read_control(fd)?;
+    let size = u64_field(&header, "size")?;
+    let sha256 = digest_field(&header, "sha256")?;
+
+    let part = part_path(path);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part)
+        .map_err(|e| io_error("open", &part, e))?;
+    let mut have = file
+        .metadata()
+        .map_err(|e| io_error("stat", &part, e))?
+        .len();
+    if have > size {
+        have = 0;
+    }
+    let mut hasher = Sha256::new();
+    hash_from(&mut file, &part, &mut hasher, have)?;
+    write_control(
+        fd,
+        vec![
+            ("offset", Value::Integer(have.into())),
+            ("prefix", Value::Bytes(hasher.clone().finalize().to_vec())),
+        ],
+    )?;
+
+    let start = u64_field(&read_control(fd)?, "offset")?;
+    if start == 0 {
+        hasher = Sha256::new();
+    } else if start != have {
+        return Err(Error::from_reason(format!(
+            "File transfer sender resumed from {}, not {}",
+            start, have
+        )));
+    }
+    file.set_len(start)
+        .map_err(|e| io_error("ftruncate", &part, e))?;
+    file.seek(SeekFrom::Start(start))
+        .map_err(|e| io_error("seek", &part, e))?;
+
+    let mut received = start;
+    while received < size {
+        let chunk = framing::read_frame(fd)
+            .map_err(|e| errors::os_error("read()", e))?
+            .ok_or_else(|| Error::from_reason("File transfer sender closed mid-file"))?;
+        if chunk.len() as u64 > size - received {
+            return Err(Error::from_reason("File transfer sender sent past the end"));
+        }
+        file.write_all(&chunk)
+            .map_err(|e| io_error("write", &part, e))?;
+        hasher.update(&chunk);
+        received += chunk.len() as u64;
+    }
+    file.sync_all().map_err(|e| io_error("fsync", &part, e))?;
+
+    if <[u8; 32]>::from(hasher.finalize()) != sha256 {
+        // Don't resume from bytes that can't be trusted
+        let _ = std::fs::remove_file(&part);
+        let _ = write_control(fd, vec![("error", cbor::text("SHA-256 mismatch"))]);
+        return Err(Error::from_reason(format!(
+            "ChecksumMismatch: {} doesn't match the sender's SHA-256",
+            path.display()
+        )));
+    }
+    std::fs::rename(&part, path).map_err(|e| io_error("rename", &part, e))?;
+    write_control(fd, vec![("ok", Value::Bool(true))])?;
+    Ok(Transfer {
+        size,
+        resumed_from: start,
+        sha256,
+    })
+}
+
+fn set_recv_timeout(fd: i32, secs: u32) {
+    let tv = libc::timeval {
+        tv_sec: secs as libc::time_t,
+        tv_usec: 0,
+    };
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &tv as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as u32,
+        );
+    }
+}
+
+/// Send the file at `path` to a receiveFile() on (cid, port), continuing
+/// an earlier interrupted transfer where possible. Resolves once the
+/// receiver has verified the file's SHA-256.
+#[napi(ts_return_type = "Promise<FileTransferResult>")]
+pub fn send_file(path: String, options: SendFileOptions) -> Result<WorkerTask<SendTask>> {
+    let chunk_size = options.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 || chunk_size as usize > framing::MAX_FRAME_SIZE {
+        return Err(Error::from_reason(format!(
+            "chunkSize must be 1 to {}",
+            framing::MAX_FRAME_SIZE
+        )));
+    }
+    Ok(WorkerTask::new(SendTask {
+        path: PathBuf::from(path),
+        cid: options.cid,
+        port: options.port,
+        chunk_size: chunk_size as usize,
+        timeout_secs: options.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+    }))
+}
+
+pub struct SendTask {
+    path: PathBuf,
+    cid: u32,
+    port: u32,
+    chunk_size: usize,
+    timeout_secs: u32,
+}
+
+impl Task for SendTask {
+    type Output = Transfer;
+    type JsValue = FileTransferResult;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let fd = vsock::connect_raw(self.cid, self.port, self.timeout_secs)?;
+        let transfer = send(fd, &self.path, self.chunk_size);
+        unsafe {
+            libc::close(fd);
+        }
+        transfer
+    }
+
+    fn resolve(&mut self, _env: Env, transfer: Self::Output) -> Result<Self::JsValue> {
+        Ok(transfer.into())
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+/// Listen on `options.port` and receive a file from sendFile() into
+/// `path`. Senders that disconnect part way leave their progress in
+/// `<path>.part` for the next one; resolves once a transfer completes and
+/// its SHA-256 is verified. Cancelling `cancel` stops listening.
+#[napi(ts_return_type = "Promise<FileTransferResult>")]
+pub fn receive_file(
+    path: String,
+    options: ReceiveFileOptions,
+    cancel: Option<&CancelToken>,
+) -> Result<WorkerTask<ReceiveTask>> {
+    let fd = vsock::listen_raw(options.port)?;
+    Ok(WorkerTask::new(ReceiveTask {
+        path: PathBuf::from(path),
+        fd,
+        timeout_secs: options.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+        cancel: cancel.map(CancelToken::shared),
+    }))
+}
+
+pub struct ReceiveTask {
+    path: PathBuf,
+    /// The listener, closed once the task settles.
+    fd: i32,
+    timeout_secs: u32,
+    cancel: Option<Arc<Canceller>>,
+}
+
+impl Task for ReceiveTask {
+    type Output = Transfer;
+    type JsValue = FileTransferResult;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        loop {
+            if let Some(cancel) = &self.cancel {
+                cancel
+                    .wait(self.fd, libc::POLLIN)
+                    .map_err(|e| errors::os_error("accept()", e))?;
+            }
+            let (conn, cid, port) =
+                vsock::accept_raw(self.fd).map_err(|e| errors::os_error("accept()", e))?;
+            set_recv_timeout(conn, self.timeout_secs);
+            let transfer = receive(conn, &self.path);
+            unsafe {
+                libc::close(conn);
+            }
+            match transfer {
+                Ok(transfer) => return Ok(transfer),
+                Err(err) => tracing::warn!(
+                    peer_cid = cid,
+                    peer_port = port,
+                    error = %err.reason,
+                    "file transfer interrupted"
+                ),
+            }
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, transfer: Self::Output) -> Result<Self::JsValue> {
+        Ok(transfer.into())
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+
+    fn finally(&mut self, _env: Env) -> Result<()> {
+        unsafe {
+            libc::close(self.fd);
+        }
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tytle-transfer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Send `source` to `dest` over a socket pair.
+    fn transfer(source: &Path, dest: &Path) -> (Result<Transfer>, Result<Transfer>) {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let dest = dest.to_path_buf();
+        let received = std::thread::spawn(move || receive(receiver.as_raw_fd(), &dest));
+        let sent = send(sender.as_raw_fd(), source, 64 * 1024);
+        drop(sender);
+        (sent, received.join().unwrap())
+    }
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn transfers_and_verifies_whole_files() {
+        let dir = scratch("whole");
+        let data = contents(300_000);
+        std::fs::write(dir.join("source"), &data).unwrap();
+
+        let (sent, received) = transfer(&dir.join("source"), &dir.join("dest"));
+        let received = received.unwrap();
+        assert_eq!(sent.unwrap(), received);
+        assert_eq!(received.size, 300_000);
+        assert_eq!(received.resumed_from, 0);
+        assert_eq!(received.sha256, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(std::fs::read(dir.join("dest")).unwrap(), data);
+        assert!(!dir.join("dest.part").exists());
+
+        // Empty files have no data frames
+        std::fs::write(dir.join("empty"), b"").unwrap();
+        let (_, received) = transfer(&dir.join("empty"), &dir.join("empty.out"));
+        assert_eq!(received.unwrap().size, 0);
+        assert!(std::fs::read(dir.join("empty.out")).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn resumes_only_from_a_matching_partial_file() {
+        let dir = scratch("resume");
+        let data = contents(200_000);
+        std::fs::write(dir.join("source"), &data).unwrap();
+
+        // What an interrupted transfer left behind
+        std::fs::write(dir.join("dest.part"), &data[..150_000]).unwrap();
+        let (_, received) = transfer(&dir.join("source"), &dir.join("dest"));
+        assert_eq!(received.unwrap().resumed_from, 150_000);
+        assert_eq!(std::fs::read(dir.join("dest")).unwrap(), data);
+
+        // Leftovers from another file start over
+        std::fs::write(dir.join("other.part"), b"not the same file").unwrap();
+        let (_, received) = transfer(&dir.join("source"), &dir.join("other"));
+        assert_eq!(received.unwrap().resumed_from, 0);
+        assert_eq!(std::fs::read(dir.join("other")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_transfers_keep_their_progress() {
+        let dir = scratch("interrupted");
+        let data = contents(100_000);
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let dest = dir.join("dest");
+        let received = std::thread::spawn(move || receive(receiver.as_raw_fd(), &dest));
+
+        // A sender that disappears after the first 40000 bytes
+        let fd = sender.as_raw_fd();
+        write_control(
+            fd,
+            vec![
+                ("size", Value::Integer(100_000.into())),
+                ("sha256", Value::Bytes(Sha256::digest(&data).to_vec())),
+            ],
+        )
+        .unwrap();
+        read_control(fd).unwrap();
+        write_control(fd, vec![("offset", Value::Integer(0.into()))]).unwrap();
+        framing::write_frame(fd, &data[..40_000]).unwrap();
+        drop(sender);
+
+        let err = received.join().unwrap().unwrap_err();
+        assert_eq!(err.reason, "File transfer sender closed mid-file");
+        assert_eq!(
+            std::fs::read(dir.join("dest.part")).unwrap(),
+            &data[..40_000]
+        );
+        assert!(!dir.join("dest").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
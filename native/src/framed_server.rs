@@ -0,0 +1,768 @@
+//! Native request/response serving for VsockListener.
+//!
+//! listener.serveFramed(handler) runs the whole server loop natively:
+//! accepting connections, reading length-prefixed requests (the framing
+//! module's format), calling the JS handler through a ThreadsafeFunction
+//! and writing back the Buffer it returns. A complete enclave service is:
+//!
+//! ```js
+//! const listener = VsockListener.bind(5000, { maxConnections: 64 });
+//! const server = listener.serveFramed(async (request, peer) => {
+//!   const reply = await handle(JSON.parse(request.toString()), peer.cid);
+//!   return Buffer.from(JSON.stringify(reply));
+//! });
+//! ```
+//!
+//! Each connection is served on its own thread, one request at a time and
+//! in order; different connections' requests run concurrently, so async
+//! handlers overlap. If the handler throws, rejects or returns something
+//! other than a non-empty Buffer, that connection is closed (the peer
+//! reads EOF) and the failure logged; the server keeps going.
+//!
+//! With `{ compression: 'zstd' }` (or 'lz4'), clients that offer that
+//! algorithm get large frames compressed; see the compression module.
+//! Clients that open with a protocol hello (RpcClient's `negotiate`) get
+//! one back, and are disconnected if their release is incompatible; see
+//! the negotiate module.
+//!
+//! A response that can't be delivered also closes the connection, and is
+//! reported to the server's onResponseFailure() callback as one of three
+//! kinds, since they call for different retry decisions: "timeout" (the
+//! peer stopped reading for `writeTimeoutMs`), "reset" (the connection
+//! failed partway through the response) and "disconnected" (the client
+//! went away cleanly before any of it was sent). `bytesWritten` says how
+//! much of the frame got out:
+//!
+//! ```js
+//! server.onResponseFailure(({ kind, bytesWritten }) => {
+//!   if (kind !== 'disconnected') metrics.undelivered.inc({ kind });
+//! });
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::JsFunction;
+use napi_derive::napi;
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake};
+use std::thread::{JoinHandle, Thread};
+use std::time::Duration;
+
+use crate::cancel::Canceller;
+use crate::compression::{self, Codec, Compression};
+use crate::errors;
+use crate::framing;
+use crate::negotiate::{self, Hello};
+use crate::recording;
+use crate::trace::Direction;
+use crate::vsock::{NativeAcceptor, VsockStream};
+
+/// Passed to the serveFramed() handler.
+#[napi(object)]
+pub struct FramedPeer {
+    pub cid: u32,
+    pub port: u32,
+}
+
+#[napi(object)]
+pub struct FramedServerOptions {
+    /// Accept "zstd" or "lz4" compression from clients that offer it.
+    pub compression: Option<String>,
+    /// Smallest response worth compressing, in bytes (default 1024).
+    pub compression_threshold: Option<u32>,
+    /// Give up on a response, and close the connection, once the peer
+    /// hasn't read any of it for this long (default: wait indefinitely).
+    pub write_timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct FramedServerStats {
+    /// Connections being served.
+    pub connections: i64,
+    /// Requests answered.
+    pub requests: i64,
+    /// Connections closed because the handler failed or a frame was bad.
+    pub errors: i64,
+    /// Responses abandoned after writeTimeoutMs.
+    pub write_timeouts: i64,
+    /// Responses cut off by a connection failure.
+    pub resets: i64,
+    /// Responses whose client had disconnected before they were sent.
+    pub disconnects: i64,
+}
+
+/// Passed to the onResponseFailure callback.
+#[napi(object)]
+pub struct ResponseFailure {
+    /// "timeout", "reset" or "disconnected".
+    pub kind: String,
+    pub peer_cid: u32,
+    pub peer_port: u32,
+    /// Bytes of the response frame sent before it failed.
+    pub bytes_written: f64,
+    /// Size of the whole response frame, as sent on the wire.
+    pub response_bytes: f64,
+    /// The socket error, for "reset".
+    pub error: Option<String>,
+}
+
+type FailureCallback = Box<dyn Fn(ResponseFailure) + Send>;
+
+/// One request, on its way to the JS thread.
+struct Request {
+    data: Vec<u8>,
+    cid: u32,
+    port: u32,
+}
+
+type Handler = ThreadsafeFunction<Request>;
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicI64,
+    requests: AtomicI64,
+    errors: AtomicI64,
+    write_timeouts: AtomicI64,
+    resets: AtomicI64,
+    disconnects: AtomicI64,
+}
+
+/// What every connection thread shares.
+struct Shared {
+    compression: Option<Compression>,
+    write_timeout: Option<Duration>,
+    counters: Arc<Counters>,
+    on_failure: Arc<Mutex<Option<FailureCallback>>>,
+}
+
+/// Returned by listener.serveFramed(). Serving stops on close() or when
+/// the listener is closed or drained, not when this is garbage collected.
+#[napi]
+pub struct FramedServer {
+    cancel: Arc<Canceller>,
+    counters: Arc<Counters>,
+    on_failure: Arc<Mutex<Option<FailureCallback>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[napi]
+impl FramedServer {
+    /// Snapshot of the server's counters.
+    #[napi]
+    pub fn stats(&self) -> FramedServerStats {
+        FramedServerStats {
+            connections: self.counters.connections.load(Ordering::Relaxed),
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            errors: self.counters.errors.load(Ordering::Relaxed),
+            write_timeouts: self.counters.write_timeouts.load(Ordering::Relaxed),
+            resets: self.counters.resets.load(Ordering::Relaxed),
+            disconnects: self.counters.disconnects.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Call `callback` for each response that couldn't be delivered.
+    /// Replaces any previous callback.
+    #[napi]
+    pub fn on_response_failure(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(failure: ResponseFailure) => void")]
+        mut callback: ThreadsafeFunction<ResponseFailure, ErrorStrategy::Fatal>,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        *self.on_failure.lock().unwrap() = Some(Box::new(move |failure| {
+            callback.call(failure, ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Stop accepting. Connections already open are served until their
+    /// peers disconnect; listener.drain() bounds that. Safe to call
+    /// multiple times.
+    #[napi]
+    pub fn close(&self) {
+        self.cancel.cancel();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl FramedServer {
+    pub(crate) fn start(
+        env: &Env,
+        acceptor: NativeAcceptor,
+        handler: JsFunction,
+        options: Option<FramedServerOptions>,
+    ) -> Result<Self> {
+        let (compression, threshold, write_timeout_ms) = options.map_or((None, None, None), |o| {
+            (o.compression, o.compression_threshold, o.write_timeout_ms)
+        });
+        let compression = Compression::from_js(compression, threshold)?;
+        if write_timeout_ms == Some(0) {
+            return Err(Error::from_reason("writeTimeoutMs must be greater than 0"));
+        }
+        let handler = callee_handled(handler)?;
+        let handler: Handler =
+            env.create_threadsafe_function(&handler, 0, |ctx: ThreadSafeCallContext<Request>| {
+                let Request { data, cid, port } = ctx.value;
+                Ok(vec![
+                    Either::A(Buffer::from(data)),
+                    Either::B(FramedPeer { cid, port }),
+                ])
+            })?;
+        let cancel = acceptor.canceller();
+        let shared = Arc::new(Shared {
+            compression,
+            write_timeout: write_timeout_ms.map(|ms| Duration::from_millis(ms as u64)),
+            counters: Arc::new(Counters::default()),
+            on_failure: Arc::new(Mutex::new(None)),
+        });
+        let (counters, on_failure) = (shared.counters.clone(), shared.on_failure.clone());
+        // The accept thread owns the handler, and each connection a clone,
+        // so it stops keeping Node alive once serving ends
+        let thread = std::thread::spawn(move || accept_loop(acceptor, handler, shared));
+        Ok(FramedServer {
+            cancel,
+            counters,
+            on_failure,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+}
+
+/// `handler.call.bind(handler)`: a ThreadsafeFunction passes an error
+/// argument first, which this turns into `this`, so the handler sees
+/// (request, peer).
+pub(crate) fn callee_handled(handler: JsFunction) -> Result<JsFunction> {
+    let handler = handler.coerce_to_object()?;
+    let call = handler
+        .get_named_property::<JsFunction>("call")?
+        .coerce_to_object()?;
+    let bind = call.get_named_property::<JsFunction>("bind")?;
+    bind.call(Some(&call), &[handler])?.try_into()
+}
+
+fn accept_loop(acceptor: NativeAcceptor, handler: Handler, shared: Arc<Shared>) {
+    loop {
+        match acceptor.accept() {
+            Ok(stream) => {
+                let handler = handler.clone();
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let connections = &shared.counters.connections;
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    serve_stream(&stream, &handler, &shared);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(_) if acceptor.stopped() => return,
+            Err(e) => {
+                tracing::warn!(error = %e.reason, "serveFramed accept() failed");
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+fn serve_stream(stream: &VsockStream, handler: &Handler, shared: &Shared) {
+    let (cid, port) = (stream.peer_cid(), stream.peer_port());
+    let result = serve_connection(
+        stream.fd(),
+        shared.compression.as_ref(),
+        shared.write_timeout,
+        || stream.touch(),
+        |data| call_handler(handler, Request { data, cid, port }),
+    );
+    let (requests, ended) = match result {
+        Ok(requests) => (requests, None),
+        Err((requests, ended)) => (requests, Some(ended)),
+    };
+    let counters = &shared.counters;
+    counters
+        .requests
+        .fetch_add(requests as i64, Ordering::Relaxed);
+    match ended {
+        None => {}
+        Some(Ended::Failed(e)) => {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                peer_cid = cid,
+                peer_port = port,
+                error = %e.reason,
+                "serveFramed connection failed"
+            );
+        }
+        Some(Ended::Undelivered(failure)) => {
+            let counter = match failure.kind {
+                WriteFailureKind::Timeout => &counters.write_timeouts,
+                WriteFailureKind::Reset => &counters.resets,
+                WriteFailureKind::Disconnected => &counters.disconnects,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            let kind = failure.kind.as_str();
+            tracing::debug!(
+                peer_cid = cid,
+                peer_port = port,
+                kind,
+                written = failure.written,
+                "serveFramed response undelivered"
+            );
+            if let Some(on_failure) = &*shared.on_failure.lock().unwrap() {
+                on_failure(ResponseFailure {
+                    kind: kind.to_string(),
+                    peer_cid: cid,
+                    peer_port: port,
+                    bytes_written: failure.written as f64,
+                    response_bytes: failure.total as f64,
+                    error: failure.error,
+                });
+            }
+        }
+    }
+}
+
+/// Why serve_connection() closed a connection early.
+#[derive(Debug)]
+enum Ended {
+    /// The handler failed, or a frame was bad.
+    Failed(Error),
+    /// A response couldn't be delivered.
+    Undelivered(WriteFailure),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WriteFailureKind {
+    Timeout,
+    Reset,
+    Disconnected,
+}
+
+impl WriteFailureKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            WriteFailureKind::Timeout => "timeout",
+            WriteFailureKind::Reset => "reset",
+            WriteFailureKind::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// napi-free form of ResponseFailure.
+#[derive(Debug, PartialEq)]
+struct WriteFailure {
+    kind: WriteFailureKind,
+    written: usize,
+    total: usize,
+    error: Option<String>,
+}
+
+/// send() flags: never block (write_response() polls instead), and report
+/// a closed peer as EPIPE rather than SIGPIPE.
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: i32 = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: i32 = libc::MSG_DONTWAIT;
+
+/// Write all of `frame` to `fd`, waiting at most `timeout` at a time for
+/// the peer to make room.
+fn write_response(
+    fd: i32,
+    frame: &[u8],
+    timeout: Option<Duration>,
+) -> std::result::Result<(), WriteFailure> {
+    let failure = |kind, written, error: Option<std::io::Error>| WriteFailure {
+        kind,
+        written,
+        total: frame.len(),
+        error: error.map(|e| errors::os_error("send()", e).reason),
+    };
+    let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    let mut written = 0;
+    while written < frame.len() {
+        let n = unsafe {
+            libc::send(
+                fd,
+                frame[written..].as_ptr() as *const libc::c_void,
+                frame.len() - written,
+                SEND_FLAGS,
+            )
+        };
+        if n >= 0 {
+            written += n as usize;
+            continue;
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => {}
+            Some(libc::EAGAIN) => {
+                let mut pollfd = libc::pollfd {
+                    fd,
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+                if ready == 0 {
+                    return Err(failure(WriteFailureKind::Timeout, written, None));
+                }
+            }
+            // Nothing was sent to a peer that had already closed
+            Some(libc::EPIPE) if written == 0 => {
+                return Err(failure(WriteFailureKind::Disconnected, 0, None))
+            }
+            _ => return Err(failure(WriteFailureKind::Reset, written, Some(err))),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the peer has closed its end of `fd` (both directions).
+fn peer_gone(fd: i32) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: 0,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & libc::POLLHUP != 0 }
+}
+
+/// Answer frames on `fd` with `handle` until the peer disconnects. Returns
+/// the number of requests answered, alongside what ended the connection
+/// early, if anything.
+fn serve_connection(
+    fd: i32,
+    compression: Option<&Compression>,
+    write_timeout: Option<Duration>,
+    touch: impl Fn(),
+    handle: impl Fn(Vec<u8>) -> Result<Vec<u8>>,
+) -> std::result::Result<u64, (u64, Ended)> {
+    let mut answered = 0;
+    let mut codec = Codec::Plain;
+    let mut negotiated = false;
+    let failed = |answered, reason: String| (answered, Ended::Failed(Error::from_reason(reason)));
+    loop {
+        let request = match codec.read_frame(fd) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(answered),
+            Err(e) => return Err(failed(answered, format!("read: {}", e))),
+        };
+        touch();
+        if answered == 0 && codec == Codec::Plain && !negotiated {
+            match negotiate::answer(fd, &request, &Hello::local()) {
+                Ok(Some(_)) => {
+                    negotiated = true;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => return Err((0, Ended::Failed(e))),
+            }
+        }
+        if answered == 0 && codec == Codec::Plain {
+            match compression::answer(fd, &request, compression) {
+                Ok(Some(agreed)) => {
+                    codec = agreed;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => return Err(failed(0, format!("write: {}", e))),
+            }
+        }
+        let response = match handle(request) {
+            Ok(response) if response.is_empty() => {
+                Err(Error::from_reason("handler returned an empty Buffer"))
+            }
+            other => other,
+        };
+        let response = response.map_err(|e| (answered, Ended::Failed(e)))?;
+        let frame = codec
+            .encode_frame(&response)
+            .map_err(|e| failed(answered, format!("write: {}", e)))?;
+        let delivered = if peer_gone(fd) {
+            Err(WriteFailure {
+                kind: WriteFailureKind::Disconnected,
+                written: 0,
+                total: frame.len(),
+                error: None,
+            })
+        } else {
+            write_response(fd, &frame, write_timeout)
+        };
+        delivered.map_err(|failure| (answered, Ended::Undelivered(failure)))?;
+        recording::frame(fd, Direction::Write, &frame[framing::HEADER_SIZE..]);
+        touch();
+        answered += 1;
+    }
+}
+
+/// Call the JS handler and wait for its Buffer, awaiting it if it's a
+/// Promise.
+fn call_handler(handler: &Handler, request: Request) -> Result<Vec<u8>> {
+    let response = block_on(async {
+        match handler
+            .call_async::<Either<Buffer, Promise<Buffer>>>(Ok(request))
+            .await?
+        {
+            Either::A(buffer) => Ok(buffer),
+            Either::B(promise) => promise.await,
+        }
+    });
+    response
+        .map(|buffer| buffer.to_vec())
+        .map_err(|e| Error::from_reason(format!("handler failed: {}", e.reason)))
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing;
+    use crate::test_support::socketpair;
+    use std::cell::Cell;
+    use std::time::Instant;
+
+    #[test]
+    fn answers_requests_until_the_peer_disconnects() {
+        let (server, client) = socketpair();
+        let peer = std::thread::spawn(move || {
+            let mut replies = Vec::new();
+            for request in [&b"one"[..], b"two"] {
+                framing::write_frame(client, request).unwrap();
+                replies.push(framing::read_frame(client).unwrap().unwrap());
+            }
+            unsafe { libc::close(client) };
+            replies
+        });
+        let touched = Cell::new(0);
+        let answered = serve_connection(
+            server,
+            None,
+            None,
+            || touched.set(touched.get() + 1),
+            |mut request| {
+                request.reverse();
+                Ok(request)
+            },
+        );
+        assert_eq!(answered.unwrap(), 2);
+        assert_eq!(touched.get(), 4);
+        assert_eq!(peer.join().unwrap(), vec![b"eno".to_vec(), b"owt".to_vec()]);
+        unsafe { libc::close(server) };
+    }
+
+    fn reason(ended: Ended) -> String {
+        match ended {
+            Ended::Failed(e) => e.reason,
+            Ended::Undelivered(failure) => panic!("unexpected {:?}", failure),
+        }
+    }
+
+    fn undelivered(result: std::result::Result<u64, (u64, Ended)>) -> WriteFailure {
+        match result {
+            Err((_, Ended::Undelivered(failure))) => failure,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undelivered_responses_say_why() {
+        // The client leaves while the handler runs
+        let (server, client) = socketpair();
+        framing::write_frame(client, b"slow").unwrap();
+        let failure = undelivered(serve_connection(
+            server,
+            None,
+            None,
+            || {},
+            |request| {
+                unsafe { libc::close(client) };
+                Ok(request)
+            },
+        ));
+        assert_eq!(failure.kind, WriteFailureKind::Disconnected);
+        assert_eq!((failure.written, failure.total), (0, 8));
+        unsafe { libc::close(server) };
+
+        // The client stops reading: the send buffer fills and stays full
+        let (server, client) = socketpair();
+        framing::write_frame(client, b"big").unwrap();
+        let failure = undelivered(serve_connection(
+            server,
+            None,
+            Some(Duration::from_millis(50)),
+            || {},
+            |_| Ok(vec![7; 4 * 1024 * 1024]),
+        ));
+        assert_eq!(failure.kind, WriteFailureKind::Timeout);
+        assert!(failure.written > 0 && failure.written < failure.total);
+
+        unsafe {
+            libc::close(server);
+            libc::close(client);
+        }
+
+        // The client goes away partway through the response
+        let (server, client) = socketpair();
+        let reader = std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let n = unsafe { libc::read(client, buf.as_mut_ptr() as *mut libc::c_void, 1024) };
+            assert!(n > 0);
+            unsafe { libc::close(client) };
+        });
+        let failure = write_response(server, &[7; 4 * 1024 * 1024], None).unwrap_err();
+        reader.join().unwrap();
+        assert_eq!(failure.kind, WriteFailureKind::Reset);
+        assert!(failure.written > 0);
+        assert!(failure.error.unwrap().starts_with("EPIPE"));
+        unsafe { libc::close(server) };
+    }
+
+    #[test]
+    fn handler_failures_close_the_connection() {
+        let (server, client) = socketpair();
+        framing::write_frame(client, b"ok").unwrap();
+        framing::write_frame(client, b"boom").unwrap();
+        let result = serve_connection(
+            server,
+            None,
+            None,
+            || {},
+            |request| match request.as_slice() {
+                b"ok" => Ok(b"fine".to_vec()),
+                _ => Err(Error::from_reason("handler failed: boom")),
+            },
+        );
+        let (answered, ended) = result.unwrap_err();
+        assert_eq!(
+            (answered, reason(ended).as_str()),
+            (1, "handler failed: boom")
+        );
+        assert_eq!(framing::read_frame(client).unwrap().unwrap(), b"fine");
+
+        framing::write_frame(client, b"x").unwrap();
+        let (_, ended) =
+            serve_connection(server, None, None, || {}, |_| Ok(Vec::new())).unwrap_err();
+        assert_eq!(reason(ended), "handler returned an empty Buffer");
+        unsafe {
+            libc::close(server);
+            libc::close(client);
+        }
+    }
+
+    #[test]
+    fn compresses_for_clients_that_offer_it() {
+        let (server, client) = socketpair();
+        let compression = Compression::from_js(Some("lz4".to_string()), Some(16)).unwrap();
+        let peer = std::thread::spawn(move || {
+            let options = Compression::from_js(Some("lz4".to_string()), Some(16)).unwrap();
+            let codec =
+                compression::offer(client, &options.unwrap(), Duration::from_secs(5)).unwrap();
+            codec.write_frame(client, &[b'a'; 64]).unwrap();
+            let raw = framing::read_frame(client).unwrap().unwrap();
+            unsafe { libc::close(client) };
+            (codec.decode(raw.clone()).unwrap(), raw.len())
+        });
+        let answered = serve_connection(
+            server,
+            compression.as_ref(),
+            None,
+            || {},
+            |request| Ok([request.as_slice(), &[b'a'; 4000]].concat()),
+        );
+        assert_eq!(answered.unwrap(), 1);
+        let (response, wire_len) = peer.join().unwrap();
+        assert_eq!(response, vec![b'a'; 4064]);
+        assert!(wire_len < 100, "response went out uncompressed");
+        unsafe { libc::close(server) };
+    }
+
+    #[test]
+    fn negotiating_clients_get_a_hello_back() {
+        let (server, client) = socketpair();
+        let peer = std::thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let protocol = negotiate::negotiate(client, &Hello::local(), 0, deadline).unwrap();
+            framing::write_frame(client, b"ping").unwrap();
+            let reply = framing::read_frame(client).unwrap().unwrap();
+            unsafe { libc::close(client) };
+            (protocol, reply)
+        });
+        let answered = serve_connection(server, None, None, || {}, Ok);
+        assert_eq!(answered.unwrap(), 1);
+        let (protocol, reply) = peer.join().unwrap();
+        assert_eq!(protocol.peer, Hello::local());
+        assert_eq!(reply, b"ping");
+        unsafe { libc::close(server) };
+
+        // A client from an incompatible release is turned away
+        let (server, client) = socketpair();
+        let future = Hello {
+            version: 9,
+            min_version: 9,
+            ..Hello::local()
+        };
+        let peer = std::thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let negotiated = negotiate::negotiate(client, &future, 0, deadline);
+            unsafe { libc::close(client) };
+            negotiated
+        });
+        let (_, ended) = serve_connection(server, None, None, || {}, Ok).unwrap_err();
+        assert!(reason(ended).starts_with("VersionMismatch: "));
+        assert!(peer
+            .join()
+            .unwrap()
+            .unwrap_err()
+            .reason
+            .starts_with("VersionMismatch: "));
+        unsafe { libc::close(server) };
+    }
+
+    #[test]
+    fn block_on_waits_for_wakeups_from_other_threads() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            block_on(std::future::poll_fn(|cx| match rx.try_recv() {
+                Ok(value) => Poll::Ready(value),
+                Err(_) => {
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(Duration::from_millis(5));
+                        waker.wake();
+                    });
+                    Poll::Pending
+                }
+            }))
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        tx.send(42).unwrap();
+        assert_eq!(waiter.join().unwrap(), 42);
+    }
+}
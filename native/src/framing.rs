@@ -0,0 +1,166 @@
+//! Length-prefixed framing for the crate's native vsock services.
+//!
+//! Format: [4-byte big-endian length][payload] — the same framing as
+//! shared/src/protocol.ts, so host tooling can reuse one reader.
+
+use std::io::{Error, ErrorKind};
+
+use crate::recording;
+use crate::trace::Direction;
+
+pub(crate) const HEADER_SIZE: usize = 4;
+pub(crate) const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16MB, as in protocol.ts
+
+/// Read one frame. Returns `Ok(None)` on a clean EOF before any header byte.
+pub(crate) fn read_frame(fd: i32) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_SIZE];
+    if !read_exact(fd, &mut header, true)? {
+        return Ok(None);
+    }
+    let length = u32::from_be_bytes(header) as usize;
+    if length == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Empty message received"));
+    }
+    if length > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Message too large: {} bytes (max {})", length, MAX_FRAME_SIZE),
+        ));
+    }
+    let mut payload = vec![0u8; length];
+    read_exact(fd, &mut payload, false)?;
+    recording::frame(fd, Direction::Read, &payload);
+    Ok(Some(payload))
+}
+
+/// One frame (header + payload) as it goes on the wire.
+pub(crate) fn encode_frame(payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Message too large: {} bytes (max {})", payload.len(), MAX_FRAME_SIZE),
+        ));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Write one frame (header + payload), retrying short writes.
+pub(crate) fn write_frame(fd: i32, payload: &[u8]) -> std::io::Result<()> {
+    let frame = encode_frame(payload)?;
+    let mut offset = 0;
+    while offset < frame.len() {
+        let n = unsafe {
+            libc::write(
+                fd,
+                frame[offset..].as_ptr() as *const libc::c_void,
+                frame.len() - offset,
+            )
+        };
+        if n < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        offset += n as usize;
+    }
+    recording::frame(fd, Direction::Write, payload);
+    Ok(())
+}
+
+/// Fill `buf` completely. With `eof_ok`, a clean EOF before the first byte
+/// returns `Ok(false)`; EOF anywhere else is an UnexpectedEof error.
+/// EAGAIN (SO_RCVTIMEO expiry) surfaces as an error — a stalled peer must not
+/// pin a server thread forever.
+pub(crate) fn read_exact(fd: i32, buf: &mut [u8], eof_ok: bool) -> std::io::Result<bool> {
+    let mut got = 0;
+    while got < buf.len() {
+        let n = unsafe {
+            libc::read(fd, buf[got..].as_mut_ptr() as *mut libc::c_void, buf.len() - got)
+        };
+        if n < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if n == 0 {
+            if got == 0 && eof_ok {
+                return Ok(false);
+            }
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Connection closed: expected {} bytes, got {}", buf.len(), got),
+            ));
+        }
+        got += n as usize;
+    }
+    Ok(true)
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socketpair;
+
+    #[test]
+    fn frame_round_trip() {
+        let (a, b) = socketpair();
+        write_frame(a, b"hello").unwrap();
+        assert_eq!(read_frame(b).unwrap().unwrap(), b"hello");
+        unsafe { libc::close(a); libc::close(b); }
+    }
+
+    #[test]
+    fn header_is_big_endian_length() {
+        let (a, b) = socketpair();
+        write_frame(a, &[7u8; 258]).unwrap();
+        let mut header = [0u8; 4];
+        assert!(read_exact(b, &mut header, false).unwrap());
+        assert_eq!(header, [0, 0, 1, 2]);
+        unsafe { libc::close(a); libc::close(b); }
+    }
+
+    #[test]
+    fn clean_eof_before_header_is_none() {
+        let (a, b) = socketpair();
+        unsafe { libc::close(a); }
+        assert!(read_frame(b).unwrap().is_none());
+        unsafe { libc::close(b); }
+    }
+
+    #[test]
+    fn eof_mid_payload_is_an_error() {
+        let (a, b) = socketpair();
+        let partial = [0u8, 0, 0, 10, 1, 2, 3];
+        unsafe {
+            libc::write(a, partial.as_ptr() as *const libc::c_void, partial.len());
+            libc::close(a);
+        }
+        let err = read_frame(b).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        unsafe { libc::close(b); }
+    }
+
+    #[test]
+    fn rejects_empty_and_oversized_frames() {
+        let (a, b) = socketpair();
+        let empty = [0u8; 4];
+        unsafe { libc::write(a, empty.as_ptr() as *const libc::c_void, 4); }
+        assert_eq!(read_frame(b).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let huge = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        unsafe { libc::write(a, huge.as_ptr() as *const libc::c_void, 4); }
+        assert_eq!(read_frame(b).unwrap_err().kind(), ErrorKind::InvalidData);
+        unsafe { libc::close(a); libc::close(b); }
+    }
+}
@@ -0,0 +1,234 @@
+//! Health-check endpoint for host-side supervisors.
+//!
+//! HealthServer answers heartbeats natively, so a supervisor on the parent
+//! can tell a healthy enclave from a wedged one without bespoke code:
+//!
+//! - host → enclave: any frame (e.g. CBOR `{}`); several may be sent on
+//!   one connection.
+//! - enclave → host: CBOR `{"uptime_ms": int, "cid": int | null,
+//!   "nsm_available": bool, "status": text, "detail": text | null,
+//!   "status_age_ms": int}`.
+//!
+//! `status` and `detail` come from setStatus(). Because the answers come
+//! from native threads, a growing `status_age_ms` from an application that
+//! calls setStatus() periodically means its event loop is stuck.
+//!
+//! ```js
+//! const health = HealthServer.bind(5006);
+//! setInterval(() => health.setStatus(queue.length < 1000 ? 'ok' : 'degraded'), 5000);
+//! ```
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::nsm::{coded, Device, DeviceConfig, NsmOptions};
+use crate::server::AcceptLoop;
+use crate::{cbor, framing, vsock};
+
+const DEFAULT_STATUS: &str = "ok";
+
+#[napi(object)]
+pub struct HealthServerOptions {
+    /// Status reported until the first setStatus() (default "ok").
+    pub status: Option<String>,
+    pub nsm: Option<NsmOptions>,
+}
+
+struct Status {
+    status: String,
+    detail: Option<String>,
+    updated: Instant,
+}
+
+/// napi-free core of HealthServer.
+struct Health {
+    started: Instant,
+    /// None when the NSM couldn't be opened at bind time.
+    device: Option<Device>,
+    status: Mutex<Status>,
+}
+
+impl Health {
+    fn new(device: Option<Device>, status: String) -> Self {
+        let now = Instant::now();
+        Health {
+            started: now,
+            device,
+            status: Mutex::new(Status {
+                status,
+                detail: None,
+                updated: now,
+            }),
+        }
+    }
+
+    fn set_status(&self, status: String, detail: Option<String>) {
+        *self.status.lock().unwrap() = Status {
+            status,
+            detail,
+            updated: Instant::now(),
+        };
+    }
+
+    /// The NSM answers a DescribeNSM request.
+    fn nsm_available(&self) -> bool {
+        self.device
+            .as_ref()
+            .is_some_and(|device| device.describe().is_ok())
+    }
+
+    fn report(&self) -> Value {
+        let nsm_available = self.nsm_available();
+        let status = self.status.lock().unwrap();
+        let millis = |since: Instant| Value::Integer((since.elapsed().as_millis() as u64).into());
+        cbor::map(vec![
+            ("uptime_ms", millis(self.started)),
+            (
+                "cid",
+                vsock::local_cid().map_or(Value::Null, |cid| Value::Integer(cid.into())),
+            ),
+            ("nsm_available", Value::Bool(nsm_available)),
+            ("status", cbor::text(&status.status)),
+            (
+                "detail",
+                status.detail.as_deref().map_or(Value::Null, cbor::text),
+            ),
+            ("status_age_ms", millis(status.updated)),
+        ])
+    }
+}
+
+/// Native vsock endpoint answering heartbeats. See the module docs for
+/// the protocol.
+#[napi]
+pub struct HealthServer {
+    accept_loop: AcceptLoop,
+    health: Arc<Health>,
+    port: u32,
+}
+
+#[napi]
+impl HealthServer {
+    /// Bind the health endpoint on the given vsock port and start serving
+    /// on native threads. An NSM that can't be opened is reported as
+    /// unavailable rather than failing the bind.
+    #[napi(factory)]
+    pub fn bind(port: u32, options: Option<HealthServerOptions>) -> coded::Result<Self> {
+        let (status, nsm) = match options {
+            Some(o) => (o.status, o.nsm),
+            None => (None, None),
+        };
+        let config = DeviceConfig::from_js(nsm).map_err(coded)?;
+        let health = Arc::new(Health::new(
+            Device::open_with(config).ok(),
+            status.unwrap_or_else(|| DEFAULT_STATUS.to_string()),
+        ));
+        let fd = vsock::listen_raw(port).map_err(coded)?;
+        let served = health.clone();
+        Ok(HealthServer {
+            accept_loop: AcceptLoop::spawn(fd, move |conn, _cid, _port| {
+                serve_connection(conn, &served)
+            }).map_err(coded)?,
+            health,
+            port,
+        })
+    }
+
+    /// Set the status (e.g. "ok", "degraded", "draining") and optional
+    /// detail reported to supervisors; also resets status_age_ms.
+    #[napi]
+    pub fn set_status(&self, status: String, detail: Option<String>) {
+        self.health.set_status(status, detail);
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+fn serve_connection(fd: i32, health: &Health) {
+    while let Ok(Some(_)) = framing::read_frame(fd) {
+        let Ok(bytes) = cbor::encode(&health.report()) else {
+            return;
+        };
+        if framing::write_frame(fd, &bytes).is_err() {
+            return;
+        }
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_device;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn answers_each_heartbeat_with_the_current_status() {
+        let health = Arc::new(Health::new(Some(mock_device()), "starting".to_string()));
+        let (host, enclave) = UnixStream::pair().unwrap();
+        let served = {
+            let health = health.clone();
+            std::thread::spawn(move || serve_connection(enclave.as_raw_fd(), &health))
+        };
+        let heartbeat = || {
+            framing::write_frame(host.as_raw_fd(), &cbor::encode(&cbor::map(vec![])).unwrap())
+                .unwrap();
+            cbor::decode(&framing::read_frame(host.as_raw_fd()).unwrap().unwrap()).unwrap()
+        };
+
+        let report = heartbeat();
+        assert_eq!(
+            cbor::map_get(&report, "status"),
+            Some(&cbor::text("starting"))
+        );
+        assert_eq!(cbor::map_get(&report, "detail"), Some(&Value::Null));
+        assert_eq!(
+            cbor::map_get(&report, "nsm_available"),
+            Some(&Value::Bool(true))
+        );
+        assert!(cbor::map_get(&report, "uptime_ms")
+            .and_then(cbor::as_u64)
+            .is_some());
+
+        health.set_status("degraded".to_string(), Some("queue full".to_string()));
+        let report = heartbeat();
+        assert_eq!(
+            cbor::map_get(&report, "status"),
+            Some(&cbor::text("degraded"))
+        );
+        assert_eq!(
+            cbor::map_get(&report, "detail"),
+            Some(&cbor::text("queue full"))
+        );
+        drop(host);
+        served.join().unwrap();
+    }
+
+    #[test]
+    fn reports_a_missing_nsm_as_unavailable() {
+        let health = Health::new(None, DEFAULT_STATUS.to_string());
+        let report = health.report();
+        assert_eq!(
+            cbor::map_get(&report, "nsm_available"),
+            Some(&Value::Bool(false))
+        );
+    }
+}
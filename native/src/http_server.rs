@@ -0,0 +1,818 @@
+//! HTTP/1.1 server over vsock.
+//!
+//! Host-side ALBs and proxies that forward raw HTTP into the enclave can
+//! point straight at an HttpVsockServer: requests are parsed natively
+//! (request line, headers, Content-Length or chunked bodies, keep-alive,
+//! `Expect: 100-continue`) and handed to a JS handler, whose answer is
+//! written back as a well-formed response:
+//!
+//! ```js
+//! const server = HttpVsockServer.bind(8080, async (req) => {
+//!   if (req.method === 'GET' && req.path === '/health') return { body: 'ok' };
+//!   return { status: 404, headers: { 'content-type': 'text/plain' }, body: 'not found' };
+//! });
+//! ```
+//!
+//! Malformed requests get a 4xx/5xx from the server itself and never reach
+//! the handler. Content-Length and Connection response headers are set by
+//! the server. Connections idle for 60s are closed.
+
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ThreadSafeCallContext, ThreadsafeFunction};
+use napi::JsFunction;
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::errors;
+use crate::framed_server::{block_on, callee_handled};
+use crate::relay::write_all_retrying;
+use crate::server::AcceptLoop;
+use crate::vsock;
+
+/// Upper bound on a request line plus headers; larger heads get 431.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+/// Upper bound on a chunk-size line of a chunked body.
+const MAX_CHUNK_LINE: usize = 1024;
+const DEFAULT_MAX_BODY_BYTES: u32 = 1024 * 1024;
+const MAX_BODY_BYTES: u32 = 64 * 1024 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+#[napi(object)]
+pub struct HttpVsockServerOptions {
+    /// Largest request body accepted (default 1 MiB, at most 64 MiB).
+    /// Larger requests are answered with 413.
+    pub max_body_bytes: Option<u32>,
+}
+
+#[napi(object)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The request target as sent, including any query string.
+    pub path: String,
+    /// Header names are lowercased; repeated headers are joined with ", ".
+    pub headers: HashMap<String, String>,
+    /// The body, with any chunked encoding removed.
+    pub body: Buffer,
+    pub peer_cid: u32,
+    pub peer_port: u32,
+}
+
+#[napi(object)]
+pub struct HttpResponse {
+    /// Status code, 200 to 599 (default 200).
+    pub status: Option<u32>,
+    /// Content-Length, Transfer-Encoding and Connection are ignored; the
+    /// server sets them.
+    pub headers: Option<HashMap<String, String>>,
+    pub body: Option<Either<Buffer, String>>,
+}
+
+#[napi(object)]
+pub struct HttpServerStats {
+    /// Connections being served.
+    pub connections: i64,
+    /// Requests answered by the handler.
+    pub requests: i64,
+    /// Requests answered with an error instead: malformed requests and
+    /// handler failures.
+    pub errors: i64,
+}
+
+/// A request as parsed off the wire.
+#[derive(Debug, PartialEq)]
+struct Parsed {
+    method: String,
+    path: String,
+    /// Lowercased names, in arrival order.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+/// A response ready to render.
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn from_js(response: HttpResponse) -> Result<Self> {
+        let status = match response.status.unwrap_or(200) {
+            status @ 200..=599 => status as u16,
+            other => {
+                return Err(Error::from_reason(format!(
+                    "status must be 200 to 599, got {}",
+                    other
+                )))
+            }
+        };
+        let headers: Vec<(String, String)> =
+            response.headers.unwrap_or_default().into_iter().collect();
+        for (name, value) in &headers {
+            if !is_token(name) || value.contains(['\r', '\n']) {
+                return Err(Error::from_reason(format!("invalid header {:?}", name)));
+            }
+        }
+        let body = match response.body {
+            Some(Either::A(buffer)) => buffer.to_vec(),
+            Some(Either::B(text)) => text.into_bytes(),
+            None => Vec::new(),
+        };
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn error(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: format!("{} {}\n", status, reason_phrase(status)).into_bytes(),
+        }
+    }
+}
+
+/// Why no request could be read.
+#[derive(Debug, PartialEq)]
+enum ReadError {
+    /// Answer with this status, then close.
+    Status(u16),
+    /// The connection failed or closed mid-request; just close it.
+    Closed,
+}
+
+/// Buffered reads from a connection, keeping bytes past the current
+/// request for the next one.
+struct Reader {
+    fd: i32,
+    buf: Vec<u8>,
+}
+
+impl Reader {
+    fn new(fd: i32) -> Self {
+        Reader {
+            fd,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Append what the socket has; false at EOF.
+    fn fill(&mut self) -> std::result::Result<bool, ReadError> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.fd,
+                    chunk.as_mut_ptr() as *mut libc::c_void,
+                    chunk.len(),
+                )
+            };
+            if n < 0 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(ReadError::Closed);
+            }
+            self.buf.extend_from_slice(&chunk[..n as usize]);
+            return Ok(n > 0);
+        }
+    }
+
+    /// Take everything up to and including `delim`; None on EOF before
+    /// any byte. More than `max` bytes without `delim` is answered with
+    /// `too_large`.
+    fn take_until(
+        &mut self,
+        delim: &[u8],
+        max: usize,
+        too_large: u16,
+    ) -> std::result::Result<Option<Vec<u8>>, ReadError> {
+        let mut scanned = 0;
+        loop {
+            if let Some(pos) = self.buf[scanned..]
+                .windows(delim.len())
+                .position(|w| w == delim)
+            {
+                let end = scanned + pos + delim.len();
+                if end > max {
+                    return Err(ReadError::Status(too_large));
+                }
+                let rest = self.buf.split_off(end);
+                return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+            }
+            if self.buf.len() > max {
+                return Err(ReadError::Status(too_large));
+            }
+            // The delimiter may straddle what's buffered and the next read
+            scanned = self.buf.len().saturating_sub(delim.len() - 1);
+            if !self.fill()? {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(ReadError::Closed),
+                };
+            }
+        }
+    }
+
+    fn take_exact(&mut self, n: usize) -> std::result::Result<Vec<u8>, ReadError> {
+        while self.buf.len() < n {
+            if !self.fill()? {
+                return Err(ReadError::Closed);
+            }
+        }
+        let rest = self.buf.split_off(n);
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+
+    fn skip_blank_lines(&mut self) -> std::result::Result<(), ReadError> {
+        loop {
+            let blank = self
+                .buf
+                .iter()
+                .take_while(|&&b| b == b'\r' || b == b'\n')
+                .count();
+            self.buf.drain(..blank);
+            if !self.buf.is_empty() || !self.fill()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// RFC 9110 token characters, for header names and methods.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Whether a comma-separated header (e.g. Connection) lists `token`.
+fn lists(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Read the next request. Ok(None) when the peer closed between requests.
+fn read_request(
+    reader: &mut Reader,
+    max_body: usize,
+) -> std::result::Result<Option<Parsed>, ReadError> {
+    reader.skip_blank_lines()?;
+    let Some(head) = reader.take_until(b"\r\n\r\n", MAX_HEAD_BYTES, 431)? else {
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&head).map_err(|_| ReadError::Status(400))?;
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+
+    let mut parts = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ReadError::Status(400));
+    };
+    if !is_token(method) || path.is_empty() {
+        return Err(ReadError::Status(400));
+    }
+    let http10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        v if v.starts_with("HTTP/") => return Err(ReadError::Status(505)),
+        _ => return Err(ReadError::Status(400)),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        // Folded continuation lines (obs-fold) are rejected, per RFC 9112
+        let (name, value) = line.split_once(':').ok_or(ReadError::Status(400))?;
+        if !is_token(name) {
+            return Err(ReadError::Status(400));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        headers.push((name.to_ascii_lowercase(), value.to_string()));
+    }
+
+    let connection = header(&headers, "connection");
+    let keep_alive = match http10 {
+        true => lists(connection, "keep-alive"),
+        false => !lists(connection, "close"),
+    };
+
+    let transfer_encoding = header(&headers, "transfer-encoding");
+    let lengths: Vec<&str> = headers
+        .iter()
+        .filter(|(n, _)| n == "content-length")
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .collect();
+    // Both framings at once is a request smuggling vector
+    if transfer_encoding.is_some() && !lengths.is_empty() {
+        return Err(ReadError::Status(400));
+    }
+    let chunked = match transfer_encoding {
+        None => false,
+        Some(te) if te.eq_ignore_ascii_case("chunked") => true,
+        Some(_) => return Err(ReadError::Status(501)),
+    };
+    let length = match lengths.first() {
+        None => 0,
+        Some(first) => {
+            if lengths.iter().any(|l| l != first) {
+                return Err(ReadError::Status(400));
+            }
+            first.parse::<usize>().map_err(|_| ReadError::Status(400))?
+        }
+    };
+    if length > max_body {
+        return Err(ReadError::Status(413));
+    }
+
+    if (chunked || length > 0) && lists(header(&headers, "expect"), "100-continue") {
+        write_all_retrying(reader.fd, b"HTTP/1.1 100 Continue\r\n\r\n")
+            .map_err(|_| ReadError::Closed)?;
+    }
+    let body = match chunked {
+        true => read_chunked(reader, max_body)?,
+        false => reader.take_exact(length)?,
+    };
+
+    Ok(Some(Parsed {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body,
+        keep_alive,
+    }))
+}
+
+/// Decode a chunked body, discarding any trailers.
+fn read_chunked(reader: &mut Reader, max_body: usize) -> std::result::Result<Vec<u8>, ReadError> {
+    let mut body = Vec::new();
+    loop {
+        let line = reader
+            .take_until(b"\r\n", MAX_CHUNK_LINE, 400)?
+            .ok_or(ReadError::Closed)?;
+        let line = std::str::from_utf8(&line).map_err(|_| ReadError::Status(400))?;
+        let size = line.trim_end().split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::Status(400))?;
+        if size == 0 {
+            break;
+        }
+        // size is the client's; body.len() + size could overflow
+        if size > max_body - body.len() {
+            return Err(ReadError::Status(413));
+        }
+        body.extend_from_slice(&reader.take_exact(size)?);
+        if reader.take_exact(2)? != b"\r\n" {
+            return Err(ReadError::Status(400));
+        }
+    }
+    loop {
+        let trailer = reader
+            .take_until(b"\r\n", MAX_HEAD_BYTES, 431)?
+            .ok_or(ReadError::Closed)?;
+        if trailer == b"\r\n" {
+            return Ok(body);
+        }
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+fn render(response: &Response, keep_alive: bool, head_request: bool) -> Vec<u8> {
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason_phrase(response.status)
+    );
+    for (name, value) in &response.headers {
+        let managed = ["content-length", "transfer-encoding", "connection"]
+            .iter()
+            .any(|h| name.eq_ignore_ascii_case(h));
+        if !managed {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    // 204 and 304 never have a body
+    let bodiless = matches!(response.status, 204 | 304);
+    if !bodiless {
+        out.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    if !keep_alive {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    let mut out = out.into_bytes();
+    if !bodiless && !head_request {
+        out.extend_from_slice(&response.body);
+    }
+    out
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicI64,
+    requests: AtomicI64,
+    errors: AtomicI64,
+}
+
+/// Answer requests on `fd` with `handle` until either side ends the
+/// connection.
+fn serve_connection(
+    fd: i32,
+    max_body: usize,
+    counters: &Counters,
+    handle: impl Fn(Parsed) -> Result<Response>,
+) {
+    let mut reader = Reader::new(fd);
+    loop {
+        let request = match read_request(&mut reader, max_body) {
+            Ok(Some(request)) => request,
+            Ok(None) | Err(ReadError::Closed) => return,
+            Err(ReadError::Status(status)) => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                let _ = write_all_retrying(fd, &render(&Response::error(status), false, false));
+                return;
+            }
+        };
+        let head_request = request.method == "HEAD";
+        let mut keep_alive = request.keep_alive;
+        let response = match handle(request) {
+            Ok(response) => {
+                counters.requests.fetch_add(1, Ordering::Relaxed);
+                response
+            }
+            Err(e) => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(error = %e.reason, "HttpVsockServer handler failed");
+                keep_alive = false;
+                Response::error(500)
+            }
+        };
+        if write_all_retrying(fd, &render(&response, keep_alive, head_request)).is_err()
+            || !keep_alive
+        {
+            return;
+        }
+    }
+}
+
+/// Lowercased headers as a JS object, joining repeats with ", ".
+fn joined_headers(headers: Vec<(String, String)>) -> HashMap<String, String> {
+    let mut joined: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers {
+        match joined.get_mut(&name) {
+            Some(existing) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            None => {
+                joined.insert(name, value);
+            }
+        }
+    }
+    joined
+}
+
+/// A request on its way to the JS thread.
+struct Pending {
+    request: Parsed,
+    peer_cid: u32,
+    peer_port: u32,
+}
+
+type Handler = ThreadsafeFunction<Pending>;
+
+fn call_handler(handler: &Handler, pending: Pending) -> Result<Response> {
+    let response = block_on(async {
+        match handler
+            .call_async::<Either<Promise<HttpResponse>, HttpResponse>>(Ok(pending))
+            .await?
+        {
+            Either::A(promise) => promise.await,
+            Either::B(response) => Ok(response),
+        }
+    })?;
+    Response::from_js(response)
+}
+
+/// HTTP/1.1 server on a vsock port, answering with a JS handler. See the
+/// module docs.
+#[napi]
+pub struct HttpVsockServer {
+    accept_loop: AcceptLoop,
+    /// Taken by close(), so the handler stops keeping Node alive.
+    handler: Arc<Mutex<Option<Handler>>>,
+    counters: Arc<Counters>,
+    port: u32,
+}
+
+#[napi]
+impl HttpVsockServer {
+    /// Listen on the given vsock port and serve requests on native
+    /// threads, one per connection.
+    #[napi(
+        factory,
+        ts_args_type = "port: number, handler: (request: HttpRequest) => HttpResponse | Promise<HttpResponse>, options?: HttpVsockServerOptions"
+    )]
+    pub fn bind(
+        env: Env,
+        port: u32,
+        handler: JsFunction,
+        options: Option<HttpVsockServerOptions>,
+    ) -> Result<Self> {
+        let max_body = options
+            .and_then(|o| o.max_body_bytes)
+            .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        if !(1..=MAX_BODY_BYTES).contains(&max_body) {
+            return Err(Error::from_reason(format!(
+                "maxBodyBytes must be 1 to {}",
+                MAX_BODY_BYTES
+            )));
+        }
+        let handler: Handler = env.create_threadsafe_function(
+            &callee_handled(handler)?,
+            0,
+            |ctx: ThreadSafeCallContext<Pending>| {
+                let Pending {
+                    request,
+                    peer_cid,
+                    peer_port,
+                } = ctx.value;
+                Ok(vec![HttpRequest {
+                    method: request.method,
+                    path: request.path,
+                    headers: joined_headers(request.headers),
+                    body: request.body.into(),
+                    peer_cid,
+                    peer_port,
+                }])
+            },
+        )?;
+        let fd = errors::structured(&env, vsock::listen_raw(port))?;
+        let handler = Arc::new(Mutex::new(Some(handler)));
+        let counters = Arc::new(Counters::default());
+        let accept_loop = {
+            let handler = handler.clone();
+            let counters = counters.clone();
+            AcceptLoop::spawn(fd, move |conn, peer_cid, peer_port| {
+                counters.connections.fetch_add(1, Ordering::Relaxed);
+                serve_connection(conn, max_body as usize, &counters, |request| {
+                    let handler = handler.lock().unwrap().clone();
+                    match handler {
+                        Some(handler) => call_handler(
+                            &handler,
+                            Pending {
+                                request,
+                                peer_cid,
+                                peer_port,
+                            },
+                        ),
+                        None => Ok(Response::error(503)),
+                    }
+                });
+                counters.connections.fetch_sub(1, Ordering::Relaxed);
+            })?
+        };
+        Ok(HttpVsockServer {
+            accept_loop,
+            handler,
+            counters,
+            port,
+        })
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Snapshot of the server's counters.
+    #[napi]
+    pub fn stats(&self) -> HttpServerStats {
+        HttpServerStats {
+            connections: self.counters.connections.load(Ordering::Relaxed),
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            errors: self.counters.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stop accepting connections and release the handler; requests still
+    /// arriving on open connections get 503. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        self.handler.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Parse `raw` as the bytes a client sent, then EOF.
+    fn parse_all(raw: &[u8], max_body: usize) -> Vec<std::result::Result<Parsed, ReadError>> {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        client.write_all(raw).unwrap();
+        drop(client);
+        let mut reader = Reader::new(server.as_raw_fd());
+        let mut out = Vec::new();
+        loop {
+            match read_request(&mut reader, max_body) {
+                Ok(Some(request)) => out.push(Ok(request)),
+                Ok(None) => return out,
+                Err(e) => {
+                    out.push(Err(e));
+                    return out;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_pipelined_requests_and_bodies() {
+        let raw =
+            b"GET /health?verbose=1 HTTP/1.1\r\nHost: enclave\r\nX-Tag: a\r\nx-tag: b\r\n\r\n\
+                    POST /orders HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                    PUT /blob HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n";
+        let parsed = parse_all(raw, 1024);
+        assert_eq!(parsed.len(), 3);
+        let get = parsed[0].as_ref().unwrap();
+        assert_eq!(
+            (get.method.as_str(), get.path.as_str()),
+            ("GET", "/health?verbose=1")
+        );
+        assert!(get.keep_alive && get.body.is_empty());
+        let headers = joined_headers(get.headers.clone());
+        assert_eq!(headers["x-tag"], "a, b");
+        assert_eq!(headers["host"], "enclave");
+
+        assert_eq!(parsed[1].as_ref().unwrap().body, b"hello");
+        let put = parsed[2].as_ref().unwrap();
+        assert_eq!(put.body, b"abcde");
+        assert!(!put.keep_alive, "HTTP/1.0 closes by default");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let status = |raw: &[u8]| parse_all(raw, 16).pop().unwrap().unwrap_err();
+        assert_eq!(status(b"GET /\r\n\r\n"), ReadError::Status(400));
+        assert_eq!(status(b"GET / HTTP/2.0\r\n\r\n"), ReadError::Status(505));
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\n folded\r\n\r\n"),
+            ReadError::Status(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            ReadError::Status(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n"),
+            ReadError::Status(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            ReadError::Status(501)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"),
+            ReadError::Status(413)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n20\r\n"),
+            ReadError::Status(413)
+        );
+        assert_eq!(
+            status(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  1\r\na\r\nffffffffffffffff\r\n"
+            ),
+            ReadError::Status(413)
+        );
+        let huge = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_BYTES)
+        );
+        assert_eq!(
+            parse_all(huge.as_bytes(), 16).pop().unwrap().unwrap_err(),
+            ReadError::Status(431)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab"),
+            ReadError::Closed
+        );
+    }
+
+    #[test]
+    fn renders_responses() {
+        let response = Response {
+            status: 201,
+            headers: vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("Content-Length".to_string(), "999".to_string()),
+            ],
+            body: b"made".to_vec(),
+        };
+        assert_eq!(
+            render(&response, true, false),
+            b"HTTP/1.1 201 Created\r\ncontent-type: text/plain\r\nContent-Length: 4\r\n\r\nmade"
+        );
+        assert_eq!(
+            render(&response, false, true),
+            b"HTTP/1.1 201 Created\r\ncontent-type: text/plain\r\nContent-Length: 4\r\nConnection: close\r\n\r\n"
+        );
+        let no_content = Response {
+            status: 204,
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+        assert_eq!(
+            render(&no_content, true, false),
+            b"HTTP/1.1 204 No Content\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn serves_keep_alive_connections() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let counters = Counters::default();
+        client
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\nhi\
+                  GET /fail HTTP/1.1\r\n\r\nGET /never HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+        serve_connection(
+            server.as_raw_fd(),
+            1024,
+            &counters,
+            |request| match request.path.as_str() {
+                "/echo" => Ok(Response {
+                    status: 200,
+                    headers: Vec::new(),
+                    body: request.body,
+                }),
+                _ => Err(Error::from_reason("boom")),
+            },
+        );
+        drop(server);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        assert_eq!(
+            replies,
+            "HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi\
+             HTTP/1.1 500 Internal Server Error\r\ncontent-type: text/plain\r\nContent-Length: 26\r\nConnection: close\r\n\r\n\
+             500 Internal Server Error\n"
+        );
+        assert_eq!(counters.requests.load(Ordering::Relaxed), 1);
+        assert_eq!(counters.errors.load(Ordering::Relaxed), 1);
+    }
+}
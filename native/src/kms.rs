@@ -0,0 +1,632 @@
+//! AWS KMS client for enclaves, in the style of the Nitro Enclaves SDK's
+//! kmstool.
+//!
+//! An enclave has no network, so requests go over vsock to a vsock-proxy
+//! on the parent (by default CID 3, port 8000) that forwards to the
+//! regional KMS endpoint. TLS terminates inside the enclave, so the parent
+//! only relays ciphertext. Every request carries a Recipient: an NSM
+//! attestation document binding a per-client RSA key, which KMS checks
+//! against the key policy (kms:RecipientAttestation:*) and encrypts the
+//! result to. Plaintext is therefore only ever decrypted here.
+//!
+//! Credentials come from the parent (e.g. instance role credentials
+//! fetched from IMDS and passed in); rotate them with setCredentials().
+//!
+//! ```js
+//! const kms = new KmsClient({ region: 'us-east-1', credentials });
+//! const key = await kms.generateDataKey({ keyId: 'alias/app' });
+//! const plaintext = await kms.decrypt({ ciphertextBlob: key.ciphertextBlob });
+//! ```
+//!
+//! decryptToSecureBuffer() keeps the plaintext in locked native memory
+//! instead (see secure_memory).
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rand_core::OsRng;
+use rsa::pkcs8::EncodePublicKey;
+use rsa::RsaPrivateKey;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex, OnceLock};
+use zeroize::Zeroizing;
+
+use crate::nsm::{Device, DeviceConfig, NsmOptions};
+use crate::secure_memory::{LockedBytes, SecureBuffer};
+use crate::sigv4::{self, Credentials};
+use crate::workers::WorkerTask;
+use crate::{attestation, cms, vsock};
+
+/// Where kmstool expects the parent's vsock-proxy.
+const DEFAULT_PROXY_CID: u32 = 3;
+const DEFAULT_PROXY_PORT: u32 = 8000;
+const DEFAULT_TIMEOUT_SECS: u32 = 10;
+const RECIPIENT_KEY_BITS: usize = 2048;
+/// KMS responses are a few KiB; anything far larger is not KMS.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+/// GenerateRandom's NumberOfBytes range.
+const MAX_RANDOM_BYTES: u32 = 1024;
+
+#[napi(object)]
+pub struct KmsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Required for temporary (role) credentials.
+    pub session_token: Option<String>,
+}
+
+#[napi(object)]
+pub struct KmsClientOptions {
+    /// AWS region, e.g. "us-east-1".
+    pub region: String,
+    pub credentials: KmsCredentials,
+    /// KMS host, for TLS and signing (default kms.<region>.amazonaws.com).
+    /// The vsock-proxy must forward to the same host.
+    pub endpoint: Option<String>,
+    /// vsock-proxy address on the parent (default CID 3, port 8000).
+    pub proxy_cid: Option<u32>,
+    pub proxy_port: Option<u32>,
+    /// Connect and per-read timeout (default 10s).
+    pub timeout_secs: Option<u32>,
+    /// NSM used for the Recipient attestation documents.
+    pub nsm: Option<NsmOptions>,
+}
+
+#[napi(object)]
+pub struct DecryptOptions {
+    pub ciphertext_blob: Buffer,
+    /// Required for asymmetric keys; otherwise checked if given.
+    pub key_id: Option<String>,
+    pub encryption_context: Option<HashMap<String, String>>,
+    /// e.g. "SYMMETRIC_DEFAULT" (KMS's default) or "RSAES_OAEP_SHA_256".
+    pub encryption_algorithm: Option<String>,
+}
+
+#[napi(object)]
+pub struct GenerateDataKeyOptions {
+    pub key_id: String,
+    /// "AES_256" (the default) or "AES_128"; exclusive with numberOfBytes.
+    pub key_spec: Option<String>,
+    pub number_of_bytes: Option<u32>,
+    pub encryption_context: Option<HashMap<String, String>>,
+}
+
+#[napi(object)]
+pub struct DataKey {
+    pub plaintext: Buffer,
+    /// The data key encrypted under `keyId`; store it and decrypt() later.
+    pub ciphertext_blob: Buffer,
+    /// ARN of the KMS key that encrypted it.
+    pub key_id: String,
+}
+
+/// DataKey with owned buffers, for the worker thread.
+pub struct DataKeyOutput {
+    pub(crate) plaintext: Vec<u8>,
+    pub(crate) ciphertext_blob: Vec<u8>,
+    pub(crate) key_id: String,
+}
+
+/// A KMS client whose results are only decryptable inside this enclave.
+/// Calls run on the blocking-call thread pool (see configureThreadPool()),
+/// one TLS connection each. Rejections carry the KMS error type as a
+/// prefix, e.g. "AccessDeniedException: ...".
+#[napi]
+pub struct KmsClient {
+    inner: Arc<Client>,
+}
+
+#[napi]
+impl KmsClient {
+    /// Opens the NSM; throws outside an enclave unless `nsm` selects a mock.
+    #[napi(constructor)]
+    pub fn new(options: KmsClientOptions) -> Result<Self> {
+        let device = DeviceConfig::from_js(options.nsm).and_then(Device::open_with)?;
+        let endpoint = options
+            .endpoint
+            .unwrap_or_else(|| format!("kms.{}.amazonaws.com", options.region));
+        Ok(KmsClient {
+            inner: Arc::new(Client {
+                tls: tls_config(),
+                endpoint,
+                region: options.region,
+                proxy_cid: options.proxy_cid.unwrap_or(DEFAULT_PROXY_CID),
+                proxy_port: options.proxy_port.unwrap_or(DEFAULT_PROXY_PORT),
+                timeout_secs: options.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+                credentials: Mutex::new(credentials(options.credentials)),
+                device,
+                recipient_key: OnceLock::new(),
+            }),
+        })
+    }
+
+    /// Replace the credentials used for later requests, e.g. when the
+    /// parent refreshes role credentials.
+    #[napi]
+    pub fn set_credentials(&self, credentials: KmsCredentials) {
+        *self.inner.credentials.lock().unwrap() = self::credentials(credentials);
+    }
+
+    /// Decrypt a KMS ciphertext. Resolves to the plaintext.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn decrypt(&self, options: DecryptOptions) -> WorkerTask<KmsTask<Vec<u8>, Buffer>> {
+        let body = decrypt_body(options);
+        self.task(
+            move |client| client.call_for_recipient("Decrypt", body),
+            Buffer::from,
+        )
+    }
+
+    /// decrypt(), resolving to a SecureBuffer so the plaintext never
+    /// reaches the JS heap.
+    #[napi(ts_return_type = "Promise<SecureBuffer>")]
+    pub fn decrypt_to_secure_buffer(
+        &self,
+        options: DecryptOptions,
+    ) -> WorkerTask<KmsTask<LockedBytes, SecureBuffer>> {
+        let body = decrypt_body(options);
+        self.task(
+            move |client| {
+                let plaintext = Zeroizing::new(client.call_for_recipient("Decrypt", body)?);
+                LockedBytes::from_slice(&plaintext)
+            },
+            SecureBuffer::new,
+        )
+    }
+
+    /// Generate a data key under `keyId`. Resolves to its plaintext and
+    /// the ciphertext to store.
+    #[napi(ts_return_type = "Promise<DataKey>")]
+    pub fn generate_data_key(
+        &self,
+        options: GenerateDataKeyOptions,
+    ) -> WorkerTask<KmsTask<DataKeyOutput, DataKey>> {
+        let mut body = json!({ "KeyId": options.key_id });
+        let key_spec = match (&options.key_spec, options.number_of_bytes) {
+            (None, None) => Some("AES_256".to_string()),
+            _ => options.key_spec,
+        };
+        insert(&mut body, "KeySpec", key_spec);
+        insert(&mut body, "NumberOfBytes", options.number_of_bytes);
+        insert(
+            &mut body,
+            "EncryptionContext",
+            options.encryption_context.map(|context| json!(context)),
+        );
+        self.task(
+            move |client| client.generate_data_key(body),
+            |key| DataKey {
+                plaintext: key.plaintext.into(),
+                ciphertext_blob: key.ciphertext_blob.into(),
+                key_id: key.key_id,
+            },
+        )
+    }
+
+    /// `numberOfBytes` (1 to 1024) random bytes from KMS.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn generate_random(
+        &self,
+        number_of_bytes: u32,
+    ) -> Result<WorkerTask<KmsTask<Vec<u8>, Buffer>>> {
+        if !(1..=MAX_RANDOM_BYTES).contains(&number_of_bytes) {
+            return Err(Error::from_reason(format!(
+                "numberOfBytes must be between 1 and {}",
+                MAX_RANDOM_BYTES
+            )));
+        }
+        let body = json!({ "NumberOfBytes": number_of_bytes });
+        Ok(self.task(
+            move |client| client.call_for_recipient("GenerateRandom", body),
+            Buffer::from,
+        ))
+    }
+
+    fn task<O, J>(
+        &self,
+        run: impl FnOnce(&Client) -> Result<O> + Send + 'static,
+        finish: fn(O) -> J,
+    ) -> WorkerTask<KmsTask<O, J>>
+    where
+        O: Send + 'static,
+        J: ToNapiValue + TypeName,
+    {
+        let client = self.inner.clone();
+        WorkerTask::new(KmsTask {
+            run: Some(Box::new(move || run(&client))),
+            finish,
+        })
+    }
+}
+
+impl KmsClient {
+    pub(crate) fn client(&self) -> Arc<Client> {
+        self.inner.clone()
+    }
+}
+
+pub struct KmsTask<O, J> {
+    run: Option<Box<dyn FnOnce() -> Result<O> + Send>>,
+    finish: fn(O) -> J,
+}
+
+impl<O: Send + 'static, J: ToNapiValue + TypeName> Task for KmsTask<O, J> {
+    type Output = O;
+    type JsValue = J;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let run = self.run.take().expect("KMS task runs once");
+        run()
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok((self.finish)(output))
+    }
+}
+
+fn decrypt_body(options: DecryptOptions) -> Value {
+    let mut body = json!({ "CiphertextBlob": BASE64.encode(&options.ciphertext_blob) });
+    insert(&mut body, "KeyId", options.key_id);
+    insert(
+        &mut body,
+        "EncryptionContext",
+        options.encryption_context.map(|context| json!(context)),
+    );
+    insert(
+        &mut body,
+        "EncryptionAlgorithm",
+        options.encryption_algorithm,
+    );
+    body
+}
+
+fn credentials(credentials: KmsCredentials) -> Credentials {
+    Credentials {
+        access_key_id: credentials.access_key_id,
+        secret_access_key: credentials.secret_access_key,
+        session_token: credentials.session_token,
+    }
+}
+
+/// Set `body[key]` when `value` is present.
+fn insert(body: &mut Value, key: &str, value: Option<impl Into<Value>>) {
+    if let Some(value) = value {
+        body[key] = value.into();
+    }
+}
+
+fn tls_config() -> Arc<rustls::ClientConfig> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// napi-free core of KmsClient.
+pub(crate) struct Client {
+    tls: Arc<rustls::ClientConfig>,
+    endpoint: String,
+    region: String,
+    proxy_cid: u32,
+    proxy_port: u32,
+    timeout_secs: u32,
+    credentials: Mutex<Credentials>,
+    device: Device,
+    /// Generated on first use, off the JS thread.
+    recipient_key: OnceLock<RsaPrivateKey>,
+}
+
+impl Client {
+    fn recipient_key(&self) -> Result<&RsaPrivateKey> {
+        if let Some(key) = self.recipient_key.get() {
+            return Ok(key);
+        }
+        let key = RsaPrivateKey::new(&mut OsRng, RECIPIENT_KEY_BITS)
+            .map_err(|e| Error::from_reason(format!("RSA key generation failed: {}", e)))?;
+        Ok(self.recipient_key.get_or_init(|| key))
+    }
+
+    /// Call `operation` with a Recipient and return its decrypted
+    /// CiphertextForRecipient.
+    pub(crate) fn call_for_recipient(&self, operation: &str, body: Value) -> Result<Vec<u8>> {
+        let response = self.call_with_recipient(operation, body)?;
+        self.open_for_recipient(operation, &response)
+    }
+
+    pub(crate) fn generate_data_key(&self, body: Value) -> Result<DataKeyOutput> {
+        let response = self.call_with_recipient("GenerateDataKey", body)?;
+        Ok(DataKeyOutput {
+            plaintext: self.open_for_recipient("GenerateDataKey", &response)?,
+            ciphertext_blob: base64_field(&response, "CiphertextBlob", "GenerateDataKey")?,
+            key_id: response["KeyId"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    fn call_with_recipient(&self, operation: &str, mut body: Value) -> Result<Value> {
+        let key = self.recipient_key()?;
+        let public_key = key
+            .to_public_key()
+            .to_public_key_der()
+            .map_err(|e| Error::from_reason(format!("RSA public key encoding failed: {}", e)))?;
+        let document = self
+            .device
+            .attestation(None, None, Some(public_key.as_bytes()))?;
+        body["Recipient"] = json!({
+            "KeyEncryptionAlgorithm": "RSAES_OAEP_SHA_256",
+            "AttestationDocument": BASE64.encode(document),
+        });
+        self.call(operation, &body)
+    }
+
+    fn open_for_recipient(&self, operation: &str, response: &Value) -> Result<Vec<u8>> {
+        let enveloped = base64_field(response, "CiphertextForRecipient", operation)?;
+        cms::decrypt_enveloped_data(&enveloped, self.recipient_key()?)
+    }
+
+    /// One signed KMS request over a fresh TLS connection.
+    fn call(&self, operation: &str, body: &Value) -> Result<Value> {
+        let body = body.to_string().into_bytes();
+        let request = self.http_request(operation, &body, attestation::now_ms());
+
+        let fd = vsock::connect_raw(self.proxy_cid, self.proxy_port, self.timeout_secs)?;
+        // The File owns and closes the fd.
+        let socket = unsafe { std::fs::File::from_raw_fd(fd) };
+        let server_name = rustls::pki_types::ServerName::try_from(self.endpoint.clone())
+            .map_err(|_| Error::from_reason(format!("Invalid KMS endpoint {}", self.endpoint)))?;
+        let connection = rustls::ClientConnection::new(self.tls.clone(), server_name)
+            .map_err(|e| Error::from_reason(format!("TLS setup failed: {}", e)))?;
+        let mut stream = rustls::StreamOwned::new(connection, socket);
+
+        let io_err = |e: std::io::Error| {
+            Error::from_reason(format!("KMS {} via vsock-proxy failed: {}", operation, e))
+        };
+        stream.write_all(&request).map_err(io_err)?;
+        stream.flush().map_err(io_err)?;
+        let (status, body) = read_response(&mut stream).map_err(io_err)?;
+        parse_response(status, &body)
+    }
+
+    fn http_request(&self, operation: &str, body: &[u8], time_ms: i64) -> Vec<u8> {
+        let mut headers = vec![
+            ("Host".to_string(), self.endpoint.clone()),
+            (
+                "Content-Type".to_string(),
+                "application/x-amz-json-1.1".to_string(),
+            ),
+            (
+                "X-Amz-Target".to_string(),
+                format!("TrentService.{}", operation),
+            ),
+        ];
+        let credentials = self.credentials.lock().unwrap().clone();
+        sigv4::sign(
+            &credentials,
+            &self.region,
+            "kms",
+            "POST",
+            &mut headers,
+            body,
+            time_ms,
+        );
+        let mut request = String::from("POST / HTTP/1.1\r\n");
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+        request
+    }
+}
+
+fn base64_field(response: &Value, field: &str, operation: &str) -> Result<Vec<u8>> {
+    response[field]
+        .as_str()
+        .and_then(|text| BASE64.decode(text).ok())
+        .ok_or_else(|| Error::from_reason(format!("KMS {} response has no {}", operation, field)))
+}
+
+/// Read an HTTP/1.1 response: the status and body. The body is delimited by
+/// Content-Length, or else by the end of the connection.
+fn read_response(stream: &mut impl Read) -> std::io::Result<(u16, Vec<u8>)> {
+    let invalid =
+        |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if data.len() > MAX_RESPONSE_SIZE {
+            return Err(invalid("response headers too large"));
+        }
+        match stream.read(&mut chunk)? {
+            0 => return Err(invalid("connection closed before the response")),
+            n => data.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = std::str::from_utf8(&data[..head_end]).map_err(|_| invalid("malformed response"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+    let mut content_length = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(invalid("chunked responses are not supported"));
+        }
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| invalid("malformed Content-Length"))?;
+            content_length = Some(length);
+        }
+    }
+    if content_length.is_some_and(|length| length > MAX_RESPONSE_SIZE) {
+        return Err(invalid("response too large"));
+    }
+
+    let mut body = data.split_off(head_end + 4);
+    loop {
+        if content_length.is_some_and(|length| body.len() >= length) {
+            break;
+        }
+        if body.len() > MAX_RESPONSE_SIZE {
+            return Err(invalid("response too large"));
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => body.extend_from_slice(&chunk[..n]),
+            // Servers closing without close_notify, when the length is unknown.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(length) = content_length {
+        if body.len() < length {
+            return Err(invalid("response body truncated"));
+        }
+        body.truncate(length);
+    }
+    Ok((status, body))
+}
+
+/// The JSON body of a successful response, or the KMS error it reports.
+fn parse_response(status: u16, body: &[u8]) -> Result<Value> {
+    let json: Option<Value> = serde_json::from_slice(body).ok();
+    if status == 200 {
+        return json.ok_or_else(|| Error::from_reason("KMS response is not JSON"));
+    }
+    let json = json.unwrap_or(Value::Null);
+    // "__type" may be namespaced: "com.amazonaws.kms#NotFoundException".
+    let kind = json["__type"]
+        .as_str()
+        .map(|kind| kind.rsplit('#').next().unwrap_or(kind))
+        .filter(|kind| !kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("KmsError");
+    let message = json["message"]
+        .as_str()
+        .or(json["Message"].as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("HTTP {}", status));
+    Err(Error::from_reason(format!("{}: {}", kind, message)))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{CoseSign1, Document};
+    use crate::test_support::mock_device;
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::RsaPublicKey;
+    use std::io::Cursor;
+
+    fn client() -> Client {
+        Client {
+            tls: tls_config(),
+            endpoint: "kms.eu-west-1.amazonaws.com".to_string(),
+            region: "eu-west-1".to_string(),
+            proxy_cid: DEFAULT_PROXY_CID,
+            proxy_port: DEFAULT_PROXY_PORT,
+            timeout_secs: 1,
+            credentials: Mutex::new(Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: Some("token".to_string()),
+            }),
+            device: mock_device(),
+            // Smaller than RECIPIENT_KEY_BITS to keep debug-build tests fast.
+            recipient_key: OnceLock::from(RsaPrivateKey::new(&mut OsRng, 1024).unwrap()),
+        }
+    }
+
+    #[test]
+    fn requests_are_signed_kms_json() {
+        let request = client().http_request("Decrypt", b"{}", 1_440_938_160_000);
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\nHost: kms.eu-west-1.amazonaws.com\r\n"));
+        assert!(request.contains("X-Amz-Target: TrentService.Decrypt\r\n"));
+        assert!(request.contains("X-Amz-Security-Token: token\r\n"));
+        assert!(request.contains(
+            "Authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/eu-west-1/kms/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date;x-amz-security-token;x-amz-target, "
+        ));
+        assert!(request.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn recipient_documents_bind_the_rsa_key() {
+        let client = client();
+        let key = client.recipient_key().unwrap().to_public_key();
+        let spki = key.to_public_key_der().unwrap();
+        let document = client
+            .device
+            .attestation(None, None, Some(spki.as_bytes()))
+            .unwrap();
+        let payload = CoseSign1::parse(&document).unwrap().payload;
+        let bound = Document::parse(&payload).unwrap().public_key.unwrap();
+        assert_eq!(RsaPublicKey::from_public_key_der(&bound).unwrap(), key);
+
+        let enveloped = cms::fixtures::envelop(b"data key", &key);
+        let response = json!({ "CiphertextForRecipient": BASE64.encode(enveloped) });
+        assert_eq!(
+            client.open_for_recipient("Decrypt", &response).unwrap(),
+            b"data key"
+        );
+    }
+
+    #[test]
+    fn reads_responses_by_content_length_or_eof() {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}trailing";
+        let (status, body) = read_response(&mut Cursor::new(&response[..])).unwrap();
+        assert_eq!((status, body.as_slice()), (200, &b"{}"[..]));
+
+        let response = b"HTTP/1.1 400 Bad Request\r\n\r\n{\"a\":1}";
+        let (status, body) = read_response(&mut Cursor::new(&response[..])).unwrap();
+        assert_eq!((status, body.as_slice()), (400, &b"{\"a\":1}"[..]));
+
+        let truncated = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{}";
+        assert!(read_response(&mut Cursor::new(&truncated[..])).is_err());
+    }
+
+    #[test]
+    fn kms_errors_keep_their_type() {
+        let body = br#"{"__type":"com.amazonaws.kms#AccessDeniedException","message":"denied"}"#;
+        let err = parse_response(400, body).err().unwrap();
+        assert_eq!(err.reason, "AccessDeniedException: denied");
+
+        let body = br#"{"__type":"NotFoundException","Message":"no key"}"#;
+        let err = parse_response(400, body).err().unwrap();
+        assert_eq!(err.reason, "NotFoundException: no key");
+
+        let err = parse_response(503, b"<html>").err().unwrap();
+        assert_eq!(err.reason, "KmsError: HTTP 503");
+        assert_eq!(
+            parse_response(200, b"{\"KeyId\":\"k\"}").unwrap()["KeyId"],
+            "k"
+        );
+    }
+}
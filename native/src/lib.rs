@@ -1,8 +1,127 @@
 //! Native addon for Nitro Enclave operations.
 //!
-//! Provides two modules:
-//! - vsock: AF_VSOCK socket server/client for enclave ↔ host communication
-//! - nsm: /dev/nsm ioctl for NSM attestation requests
+//! Provides these modules:
+//! - vsock: AF_VSOCK socket server/client for enclave ↔ host communication, with the VMADDR_* constants
+//! - nsm: /dev/nsm ioctl for NSM attestation requests, served in arrival order (nsmQueueStats())
+//! - nsm_mock: in-process mock NSM for CI (NsmOptions.mock, TYTLE_NSM_MOCK;
+//!   needs the `nsm-mock` cargo feature outside unit tests)
+//! - errors: structured errors with .code, .syscall and .errno for the vsock and NSM exports
+//! - logging: tracing events for connections, syscall failures and NSM requests, forwarded to JS (setLogHandler())
+//! - nsm_debug: redacted per-request NSM tracing (setNsmDebugHook(), TYTLE_NSM_DEBUG)
+//! - entropy: kernel entropy seeding from NSM GetRandom, and getrandom(2) with NSM preference (seedKernelEntropy(), startSeeder(), getRandom())
+//! - attestation: attestation document decoding and verification (verifyAttestation())
+//! - attestation_export: JSON, PEM and base64 views of attestation documents for logs and audits (attestationToJson())
+//! - attestation_server: native nonce → attestation document endpoint (AttestationServer)
+//! - attestation_cache: opt-in TTL cache for attestation() (cacheTtlMs)
+//! - attested_key: attested ephemeral X25519/P-384 keypairs (generateAttestedKeypair())
+//! - eif: PCR0/1/2 prediction from Enclave Image Files (predictPcrsFromEif())
+//! - policy: host-side verification rules with per-rule failures (AttestationPolicy)
+//! - verifier: standalone verification reports listing every check, for host audit logs (verifyRemoteAttestation())
+//! - seccomp: seccomp-BPF syscall allowlists for the enclave process (applySeccompProfile())
+//! - nonce: host-side replay protection with single-use expiring nonces (NonceRegistry)
+//! - secure_channel: attestation-authenticated encrypted channels over vsock, with resumption (connectSecureChannel())
+//! - tls: rustls TLS over a VsockStream (TlsVsockServer, TlsVsockClient)
+//! - ra_tls: attestation documents in self-signed TLS certificates (generateAttestedCertificate())
+//! - relay: native bidirectional byte relay between streams/fds (pipe())
+//! - negotiate: protocol version/feature hellos so mismatched releases fail with VersionMismatch (negotiateProtocol())
+//! - recording: frame recordings of vsock sessions and their offline replay (SessionRecorder, replaySession())
+//! - rpc: JSON-RPC 2.0 client over framed vsock with per-call deadlines (RpcClient)
+//! - framed_server: native accept/read/handler/write loop for framed services (listener.serveFramed())
+//! - enclave_cid: host-side CID lookup via nitro-cli describe-enclaves (getEnclaveCid())
+//! - timesync: NTP-style clock offset to the parent over vsock (TimeSyncClient, TimeSyncServer)
+//! - kms: KMS client over the parent's vsock-proxy with attested Recipient decryption (KmsClient)
+//! - secure_memory: mlocked, zeroized key storage (SecureBuffer) and lockMemory()
+//! - acm: ACM for Nitro Enclaves certificates via the parent's agent (fetchAcmCertificate())
+//! - sealing: data sealed to a PCR-bound KMS key policy (sealData(), unsealData())
+//! - health: heartbeat endpoint with uptime, CID, NSM availability and app status (HealthServer)
+//! - log_forward: buffered log shipping over vsock with stdio capture (LogForwarder, LogReceiver)
+//! - measurements: signed PCR/module measurement reports and their vsock endpoint
+//! - metrics: addon counters, NSM latency histograms and app gauges for Prometheus (MetricsServer)
+//! - file_transfer: resumable, SHA-256-verified file transfer over vsock (sendFile(), receiveFile())
+//! - proxy: native TCP/unix ↔ vsock forwarders (VsockToTcpProxy, TcpToVsockProxy, UnixVsockBridge)
+//! - http_server: HTTP/1.1 requests over vsock answered by a JS handler (HttpVsockServer)
+//! - connect_proxy: HTTP CONNECT tunnelling (enclave-side server, host-side client)
+//! - socks: host-side SOCKS5 server over vsock and enclave-side socks5ConnectAsync()
+//! - uring: opt-in io_uring backend with batched submission (IoUringDriver)
+//! - pool: pooled, zero-copy read buffers (ReadBufferPool, stream.setReadPool())
+//! - trace: per-stream traffic tracing with hexdumps (stream.enableTrace())
+//! - throttle: per-stream token-bucket bandwidth limits (stream.setRateLimit())
+//! - write_queue: buffered stream writes with backpressure (stream.queueWrite(), onDrain())
+//! - watchdog: dead-peer detection for idle streams (stream.enableWatchdog(), onClose())
+//! - backlog: pending-connection counts and pressure alerts for listeners (listener.backlogStats(), onBacklogPressure())
+//! - probe: vsock port probing for host tooling (probePort(), scanPorts())
+//! - bench: echo server and vsockBenchmark() for throughput/latency baselines
+//! - workers: dedicated native thread pool for async APIs' blocking calls (configureThreadPool())
+//! - cancel: CancelToken for interrupting acceptAsync()/vsockConnectAsync()/connectWhenReady()
+//! - cbor: CBOR for the NSM wire format and JS (cborEncode(), cborDecode())
+//!
+//! Internal helpers: framing (length-prefixed messages), compression
+//! (negotiated zstd/lz4 for framed connections), server (native
+//! accept loop for built-in services), mock (unix-socket vsock backend
+//! selected by TYTLE_VSOCK_MOCK_DIR), platform (Linux gating: elsewhere
+//! the addon loads and vsock/NSM calls throw UnsupportedPlatform), x509
+//! (certificate parsing for attestation chains), sigv4 (AWS request
+//! signing), cms (KMS CiphertextForRecipient decryption).
 
+mod acm;
+mod attestation;
+mod attestation_cache;
+mod attestation_export;
+mod attestation_server;
+mod attested_key;
+mod backlog;
+mod bench;
+mod cancel;
+mod cbor;
+mod cms;
+mod compression;
+mod connect_proxy;
+mod eif;
+mod enclave_cid;
+mod entropy;
+mod errors;
+mod file_transfer;
+mod framed_server;
+mod framing;
+mod health;
+mod http_server;
+mod kms;
+mod log_forward;
+mod logging;
+mod measurements;
+mod metrics;
+mod mock;
+mod negotiate;
+mod nonce;
 mod nsm;
+mod nsm_debug;
+mod nsm_mock;
+mod platform;
+mod policy;
+mod pool;
+mod probe;
+mod proxy;
+mod ra_tls;
+mod relay;
+mod recording;
+mod rpc;
+mod sealing;
+mod seccomp;
+mod secure_channel;
+mod secure_memory;
+mod server;
+mod sigv4;
+mod socks;
+#[cfg(test)]
+mod test_support;
+mod throttle;
+mod timesync;
+mod tls;
+mod trace;
+mod uring;
+mod verifier;
 mod vsock;
+mod watchdog;
+mod workers;
+mod write_queue;
+mod x509;
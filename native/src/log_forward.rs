@@ -0,0 +1,535 @@
+//! Log forwarding from the enclave to the parent over vsock.
+//!
+//! An enclave has no console in production, so its logs have to leave over
+//! vsock. LogForwarder queues records in memory and a native thread ships
+//! them to the parent's LogReceiver, reconnecting as needed; when the queue
+//! is full, new records are dropped and counted, and the next record that
+//! fits carries the count. captureStdio() additionally redirects the
+//! process's stdout and stderr (console.log included) into the forwarder.
+//!
+//! Each record is one frame holding CBOR `{"timestamp_ms": int, "level":
+//! text, "message": text, "source": "app" | "stdout" | "stderr",
+//! "dropped": int}`, where `dropped` counts the records lost just before
+//! this one.
+//!
+//! ```js
+//! // parent
+//! const receiver = LogReceiver.bind(5007, (record) => console.log(record.level, record.message));
+//! // enclave
+//! const logs = new LogForwarder({ port: 5007 });
+//! logs.captureStdio();
+//! logs.write('info', 'started');
+//! ```
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::server::AcceptLoop;
+use crate::{attestation, cbor, framing, vsock};
+
+const DEFAULT_CID: u32 = 3;
+const DEFAULT_MAX_BUFFERED: u32 = 4096;
+const CONNECT_TIMEOUT_SECS: u32 = 5;
+/// Reconnect backoff bounds.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Longer messages are truncated, and captured output without a newline
+/// is forwarded in pieces of this size.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Set once stdout/stderr are redirected; there is only one of each.
+static STDIO_CAPTURED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    timestamp_ms: i64,
+    level: String,
+    message: String,
+    source: &'static str,
+    dropped: u64,
+}
+
+impl Record {
+    fn encode(&self) -> Result<Vec<u8>> {
+        cbor::encode(&cbor::map(vec![
+            ("timestamp_ms", Value::Integer(self.timestamp_ms.into())),
+            ("level", cbor::text(&self.level)),
+            ("message", cbor::text(&self.message)),
+            ("source", cbor::text(self.source)),
+            ("dropped", Value::Integer(self.dropped.into())),
+        ]))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    records: VecDeque<Record>,
+    /// Dropped since the last queued record.
+    pending_dropped: u64,
+    sent: u64,
+    dropped: u64,
+    closing: bool,
+}
+
+/// napi-free core of LogForwarder.
+struct Forwarder {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    max_buffered: usize,
+}
+
+impl Forwarder {
+    /// Queue a record, or count it as dropped. Returns whether it was
+    /// queued.
+    fn push(&self, level: &str, message: &str, source: &'static str) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closing || queue.records.len() >= self.max_buffered {
+            queue.pending_dropped += 1;
+            queue.dropped += 1;
+            return false;
+        }
+        let record = Record {
+            timestamp_ms: attestation::now_ms(),
+            level: level.to_string(),
+            message: truncate(message).to_string(),
+            source,
+            dropped: std::mem::take(&mut queue.pending_dropped),
+        };
+        queue.records.push_back(record);
+        self.changed.notify_all();
+        true
+    }
+
+    /// Ship queued records through connections from `connect` until
+    /// close(), then send what's left if still connected.
+    fn run(&self, connect: impl Fn() -> Result<i32>) {
+        let mut fd: Option<i32> = None;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let record = {
+                let mut queue = self.queue.lock().unwrap();
+                while queue.records.is_empty() && !queue.closing {
+                    queue = self.changed.wait(queue).unwrap();
+                }
+                match queue.records.front() {
+                    Some(record) => record.clone(),
+                    None => break,
+                }
+            };
+            let conn = match fd {
+                Some(conn) => conn,
+                None if self.queue.lock().unwrap().closing => break,
+                None => match connect() {
+                    Ok(conn) => {
+                        backoff = MIN_BACKOFF;
+                        *fd.insert(conn)
+                    }
+                    Err(_) => {
+                        let queue = self.queue.lock().unwrap();
+                        let _ = self.changed.wait_timeout(queue, backoff);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        continue;
+                    }
+                },
+            };
+            let sent = record
+                .encode()
+                .is_ok_and(|frame| framing::write_frame(conn, &frame).is_ok());
+            if sent {
+                let mut queue = self.queue.lock().unwrap();
+                queue.records.pop_front();
+                queue.sent += 1;
+            } else {
+                // Retry the record on a new connection
+                unsafe {
+                    libc::close(conn);
+                }
+                fd = None;
+            }
+        }
+        if let Some(conn) = fd {
+            unsafe {
+                libc::close(conn);
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closing = true;
+        self.changed.notify_all();
+    }
+}
+
+/// `message` cut to at most MAX_MESSAGE_SIZE bytes on a char boundary.
+fn truncate(message: &str) -> &str {
+    if message.len() <= MAX_MESSAGE_SIZE {
+        return message;
+    }
+    let mut end = MAX_MESSAGE_SIZE;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    &message[..end]
+}
+
+#[napi(object)]
+pub struct LogForwarderOptions {
+    /// vsock port of the parent's LogReceiver.
+    pub port: u32,
+    /// Receiver CID (default 3, the parent).
+    pub cid: Option<u32>,
+    /// Records held while the receiver is slow or unreachable (default
+    /// 4096); more are dropped.
+    pub max_buffered: Option<u32>,
+}
+
+#[napi(object)]
+pub struct LogForwarderStats {
+    /// Records waiting to be sent.
+    pub queued: i64,
+    pub sent: i64,
+    /// Records lost to a full queue, or written after close().
+    pub dropped: i64,
+}
+
+/// Enclave side: ships log records to a LogReceiver from a native thread.
+#[napi]
+pub struct LogForwarder {
+    inner: Arc<Forwarder>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[napi]
+impl LogForwarder {
+    #[napi(constructor)]
+    pub fn new(options: LogForwarderOptions) -> Result<Self> {
+        let max_buffered = options.max_buffered.unwrap_or(DEFAULT_MAX_BUFFERED);
+        if max_buffered == 0 {
+            return Err(Error::from_reason("maxBuffered must be at least 1"));
+        }
+        let inner = Arc::new(Forwarder {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            max_buffered: max_buffered as usize,
+        });
+        let (cid, port) = (options.cid.unwrap_or(DEFAULT_CID), options.port);
+        let thread = {
+            let inner = inner.clone();
+            std::thread::spawn(move || {
+                inner.run(|| vsock::connect_raw(cid, port, CONNECT_TIMEOUT_SECS))
+            })
+        };
+        Ok(LogForwarder {
+            inner,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Queue `message` at `level` (free-form, e.g. "info" or "error").
+    /// Returns false if the record was dropped because the queue is full.
+    #[napi]
+    pub fn write(&self, level: String, message: String) -> bool {
+        self.inner.push(&level, &message, "app")
+    }
+
+    /// Redirect this process's stdout and stderr into the forwarder, as
+    /// "info" and "error" records, one per line. Process-wide and
+    /// permanent: output after close() is dropped.
+    #[napi]
+    pub fn capture_stdio(&self) -> Result<()> {
+        if STDIO_CAPTURED.swap(true, Ordering::AcqRel) {
+            return Err(Error::from_reason("stdout/stderr are already captured"));
+        }
+        for (target, level, source) in [
+            (libc::STDOUT_FILENO, "info", "stdout"),
+            (libc::STDERR_FILENO, "error", "stderr"),
+        ] {
+            let read_end = redirect(target).inspect_err(|_| {
+                STDIO_CAPTURED.store(false, Ordering::Release);
+            })?;
+            let inner = self.inner.clone();
+            std::thread::spawn(move || {
+                forward_lines(read_end, |line| {
+                    inner.push(level, line, source);
+                })
+            });
+        }
+        Ok(())
+    }
+
+    #[napi]
+    pub fn stats(&self) -> LogForwarderStats {
+        let queue = self.inner.queue.lock().unwrap();
+        LogForwarderStats {
+            queued: queue.records.len() as i64,
+            sent: queue.sent as i64,
+            dropped: queue.dropped as i64,
+        }
+    }
+
+    /// Stop accepting records and wait for the native thread to send what
+    /// it can on its current connection. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) {
+        self.inner.close();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LogForwarder {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Point `target` at a new pipe and return the pipe's read end.
+fn redirect(target: i32) -> Result<i32> {
+    let mut fds = [0i32; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Error::from_reason(format!(
+            "pipe() failed: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    let [read_end, write_end] = fds;
+    let result = unsafe {
+        libc::fcntl(read_end, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::dup2(write_end, target)
+    };
+    let err = std::io::Error::last_os_error();
+    unsafe {
+        libc::close(write_end);
+    }
+    if result < 0 {
+        unsafe {
+            libc::close(read_end);
+        }
+        return Err(Error::from_reason(format!("dup2() failed: {}", err)));
+    }
+    Ok(read_end)
+}
+
+/// Read `fd` until EOF, calling `emit` for each line (without its
+/// newline) and for each MAX_MESSAGE_SIZE piece of an overlong one.
+fn forward_lines(fd: i32, mut emit: impl FnMut(&str)) {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            break;
+        }
+        pending.extend_from_slice(&buf[..n as usize]);
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            emit(String::from_utf8_lossy(&line[..newline]).trim_end_matches('\r'));
+        }
+        while pending.len() >= MAX_MESSAGE_SIZE {
+            let piece: Vec<u8> = pending.drain(..MAX_MESSAGE_SIZE).collect();
+            emit(&String::from_utf8_lossy(&piece));
+        }
+    }
+    if !pending.is_empty() {
+        emit(&String::from_utf8_lossy(&pending));
+    }
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+/// Passed to the LogReceiver callback.
+#[napi(object)]
+pub struct LogRecord {
+    pub timestamp_ms: f64,
+    pub level: String,
+    pub message: String,
+    /// "app" for LogForwarder.write(), or "stdout"/"stderr".
+    pub source: String,
+    /// Records the forwarder dropped just before this one.
+    pub dropped: f64,
+    /// CID of the forwarding enclave.
+    pub peer_cid: u32,
+}
+
+fn parse_record(frame: &[u8], peer_cid: u32) -> Option<LogRecord> {
+    let value = cbor::decode(frame).ok()?;
+    let text = |name: &str| cbor::map_get(&value, name).and_then(cbor::as_text);
+    let int = |name: &str| {
+        cbor::map_get(&value, name)
+            .and_then(|v| v.as_integer())
+            .and_then(|n| i64::try_from(n).ok())
+    };
+    Some(LogRecord {
+        timestamp_ms: int("timestamp_ms")? as f64,
+        level: text("level")?.to_string(),
+        message: text("message")?.to_string(),
+        source: text("source").unwrap_or("app").to_string(),
+        dropped: int("dropped").unwrap_or(0) as f64,
+        peer_cid,
+    })
+}
+
+/// Deliver each record on `fd` until the forwarder disconnects. Frames
+/// that aren't records are skipped.
+fn receive(fd: i32, peer_cid: u32, deliver: &(dyn Fn(LogRecord) + Send + Sync)) {
+    while let Ok(Some(frame)) = framing::read_frame(fd) {
+        if let Some(record) = parse_record(&frame, peer_cid) {
+            deliver(record);
+        }
+    }
+}
+
+/// Host side: accepts LogForwarder connections and calls back per record.
+#[napi]
+pub struct LogReceiver {
+    accept_loop: AcceptLoop,
+    port: u32,
+}
+
+#[napi]
+impl LogReceiver {
+    /// Bind `port` and call `callback` with every record forwarded to it.
+    #[napi(factory)]
+    pub fn bind(
+        env: Env,
+        port: u32,
+        #[napi(ts_arg_type = "(record: LogRecord) => void")] mut callback: ThreadsafeFunction<
+            LogRecord,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<Self> {
+        let fd = vsock::listen_raw(port)?;
+        // Like the other servers, don't keep the process alive on our own
+        callback.unref(&env)?;
+        let deliver = move |record| {
+            callback.call(record, ThreadsafeFunctionCallMode::NonBlocking);
+        };
+        Ok(LogReceiver {
+            accept_loop: AcceptLoop::spawn(fd, move |conn, cid, _port| {
+                receive(conn, cid, &deliver)
+            })?,
+            port,
+        })
+    }
+
+    /// The vsock port this receiver is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn forwarder(max_buffered: usize) -> Arc<Forwarder> {
+        Arc::new(Forwarder {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            max_buffered,
+        })
+    }
+
+    #[test]
+    fn ships_records_and_counts_drops() {
+        let forwarder = forwarder(2);
+        assert!(forwarder.push("info", "one", "app"));
+        assert!(forwarder.push("warn", "two", "app"));
+        assert!(!forwarder.push("info", "lost", "app"));
+        assert!(!forwarder.push("info", "lost", "app"));
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let sender = Mutex::new(Some(sender.into_raw_fd()));
+        let thread = {
+            let forwarder = forwarder.clone();
+            std::thread::spawn(move || {
+                forwarder.run(|| {
+                    sender
+                        .lock()
+                        .unwrap()
+                        .take()
+                        .ok_or_else(|| Error::from_reason("one connection only"))
+                })
+            })
+        };
+
+        let received = Mutex::new(Vec::new());
+        let receiver = receiver.into_raw_fd();
+        let read_one = || {
+            let frame = framing::read_frame(receiver).unwrap().unwrap();
+            received
+                .lock()
+                .unwrap()
+                .push(parse_record(&frame, 7).unwrap());
+        };
+        read_one();
+        read_one();
+        assert!(forwarder.push("error", "three", "stderr"));
+        read_one();
+        forwarder.close();
+        thread.join().unwrap();
+        unsafe {
+            libc::close(receiver);
+        }
+
+        let received = received.into_inner().unwrap();
+        let summary: Vec<(&str, &str, f64)> = received
+            .iter()
+            .map(|r| (r.level.as_str(), r.message.as_str(), r.dropped))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("info", "one", 0.0),
+                ("warn", "two", 0.0),
+                ("error", "three", 2.0)
+            ]
+        );
+        assert_eq!(received[2].source, "stderr");
+        assert_eq!(received[2].peer_cid, 7);
+        let queue = forwarder.queue.lock().unwrap();
+        assert_eq!((queue.sent, queue.dropped), (3, 2));
+        drop(queue);
+        assert!(!forwarder.push("info", "after close", "app"));
+    }
+
+    #[test]
+    fn splits_captured_output_into_lines() {
+        let (writer, reader) = UnixStream::pair().unwrap();
+        let mut lines = Vec::new();
+        let reader = reader.into_raw_fd();
+        std::io::Write::write_all(&mut &writer, b"first\r\nsecond\npart").unwrap();
+        drop(writer);
+        forward_lines(reader, |line| lines.push(line.to_string()));
+        assert_eq!(lines, vec!["first", "second", "part"]);
+
+        let long = "é".repeat(MAX_MESSAGE_SIZE);
+        assert_eq!(truncate(&long).len(), MAX_MESSAGE_SIZE);
+    }
+}
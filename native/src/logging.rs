@@ -0,0 +1,328 @@
+//! Native diagnostics through the application's logger.
+//!
+//! The addon reports what it does with `tracing` events: streams opened
+//! and closed, native server connections, failed system calls and NSM
+//! requests. setLogHandler(level, callback) installs a subscriber that
+//! forwards every event at or above `level` to JS:
+//!
+//! ```js
+//! setLogHandler('debug', ({ level, target, message, fields }) =>
+//!   logger[level]({ target, ...fields }, message));
+//! ```
+//!
+//! Until a handler is set no subscriber is installed, and the events cost
+//! a single level check.
+
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Passed to the setLogHandler() callback.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct LogEvent {
+    /// "error", "warn", "info", "debug" or "trace".
+    pub level: String,
+    /// The addon module that logged it, e.g. "vsock" or "nsm_debug".
+    pub target: String,
+    pub message: String,
+    /// Structured context, e.g. `{ peerCid: 3, peerPort: 5000 }`.
+    #[napi(ts_type = "Record<string, string | number | boolean>")]
+    pub fields: Map<String, Value>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: f64,
+}
+
+type Hook = Box<dyn Fn(LogEvent) + Send>;
+
+/// napi-free core of setLogHandler(): the level filter and the hook.
+#[derive(Default)]
+struct Sink {
+    /// A LevelFilter, see filter_to_u8().
+    level: AtomicU8,
+    hook: Mutex<Option<Hook>>,
+}
+
+impl Sink {
+    fn set(&self, level: LevelFilter, hook: Option<Hook>) {
+        let mut current = self.hook.lock().unwrap();
+        let level = if hook.is_some() {
+            level
+        } else {
+            LevelFilter::OFF
+        };
+        self.level.store(filter_to_u8(level), Ordering::Relaxed);
+        *current = hook;
+    }
+
+    fn level(&self) -> LevelFilter {
+        filter_from_u8(self.level.load(Ordering::Relaxed))
+    }
+}
+
+fn filter_to_u8(filter: LevelFilter) -> u8 {
+    match filter.into_level() {
+        None => 0,
+        Some(Level::ERROR) => 1,
+        Some(Level::WARN) => 2,
+        Some(Level::INFO) => 3,
+        Some(Level::DEBUG) => 4,
+        Some(Level::TRACE) => 5,
+    }
+}
+
+fn filter_from_u8(n: u8) -> LevelFilter {
+    match n {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    match level {
+        "error" => Ok(LevelFilter::ERROR),
+        "warn" => Ok(LevelFilter::WARN),
+        "info" => Ok(LevelFilter::INFO),
+        "debug" => Ok(LevelFilter::DEBUG),
+        "trace" => Ok(LevelFilter::TRACE),
+        _ => Err(Error::from_reason(format!(
+            "Invalid log level {:?}: expected error, warn, info, debug or trace",
+            level
+        ))),
+    }
+}
+
+/// Subscriber turning events into LogEvents for a Sink. Spans are unused.
+struct SinkSubscriber(Arc<Sink>);
+
+impl Subscriber for SinkSubscriber {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // The level changes at runtime, so ask enabled() every time
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.0.level()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.0.level())
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let hook = self.0.hook.lock().unwrap();
+        if let Some(hook) = hook.as_ref() {
+            hook(log_event(event));
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+fn log_event(event: &Event<'_>) -> LogEvent {
+    let metadata = event.metadata();
+    let mut fields = Fields::default();
+    event.record(&mut fields);
+    let target = metadata.target();
+    let target = target
+        .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+        .unwrap_or(target);
+    LogEvent {
+        level: metadata.level().as_str().to_ascii_lowercase(),
+        target: target.to_string(),
+        message: fields.message,
+        fields: fields.values,
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64() * 1000.0),
+    }
+}
+
+/// Collects an event's message and fields, with camelCase field names.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    values: Map<String, Value>,
+}
+
+impl Fields {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.values.insert(camel_case(field.name()), value);
+    }
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.insert(field, Value::String(format!("{:?}", value)));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.insert(field, Value::String(value.to_string()));
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::Bool(value));
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = !out.is_empty();
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// The process-wide sink, installed as the global subscriber on first use.
+fn global_sink() -> &'static Arc<Sink> {
+    static SINK: OnceLock<Arc<Sink>> = OnceLock::new();
+    SINK.get_or_init(|| {
+        let sink = Arc::new(Sink::default());
+        // Fails only if something else in the process got there first
+        let _ = tracing::subscriber::set_global_default(SinkSubscriber(sink.clone()));
+        sink
+    })
+}
+
+/// Call `callback` with a LogEvent for every addon event at or above
+/// `level` ("error", "warn", "info", "debug" or "trace"), replacing any
+/// previous handler, or stop with null.
+#[napi]
+pub fn set_log_handler(
+    env: Env,
+    #[napi(ts_arg_type = "'error' | 'warn' | 'info' | 'debug' | 'trace'")] level: String,
+    #[napi(ts_arg_type = "((event: LogEvent) => void) | null")] callback: Option<
+        ThreadsafeFunction<LogEvent, ErrorStrategy::Fatal>,
+    >,
+) -> Result<()> {
+    let level = parse_level(&level)?;
+    let hook: Option<Hook> = match callback {
+        Some(mut callback) => {
+            // Don't keep the process alive just for logging
+            callback.unref(&env)?;
+            Some(Box::new(move |event| {
+                callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+            }))
+        }
+        None => None,
+    };
+    global_sink().set(level, hook);
+    tracing::callsite::rebuild_interest_cache();
+    Ok(())
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `f` with a thread-local sink at `level`, returning its events.
+    fn capture(level: &str, f: impl FnOnce()) -> Vec<LogEvent> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::new(Sink::default());
+        let seen = events.clone();
+        sink.set(
+            parse_level(level).unwrap(),
+            Some(Box::new(move |event| seen.lock().unwrap().push(event))),
+        );
+        tracing::subscriber::with_default(SinkSubscriber(sink), f);
+        let captured = std::mem::take(&mut *events.lock().unwrap());
+        captured
+    }
+
+    #[test]
+    fn forwards_events_at_or_above_the_level() {
+        let events = capture("info", || {
+            tracing::debug!(peer_cid = 3u32, "too verbose");
+            tracing::info!(
+                peer_cid = 3u32,
+                peer_port = 5000u32,
+                closed = false,
+                "stream opened"
+            );
+            tracing::error!(reason = %"boom", "failed");
+        });
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].level, "info");
+        assert_eq!(events[0].target, "logging::tests");
+        assert_eq!(events[0].message, "stream opened");
+        assert_eq!(
+            Value::Object(events[0].fields.clone()),
+            serde_json::json!({ "peerCid": 3, "peerPort": 5000, "closed": false })
+        );
+        assert_eq!(events[1].level, "error");
+        assert_eq!(events[1].fields["reason"], "boom");
+
+        assert!(parse_level("verbose").is_err());
+    }
+
+    #[test]
+    fn reports_failed_system_calls() {
+        let events = capture("debug", || {
+            crate::errors::os_error(
+                "connect(cid=3, port=5000)",
+                std::io::Error::from_raw_os_error(libc::ECONNREFUSED),
+            );
+        });
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, "warn");
+        assert_eq!(events[0].target, "errors");
+        assert_eq!(events[0].fields["code"], "ECONNREFUSED");
+        assert_eq!(events[0].fields["call"], "connect(cid=3, port=5000)");
+    }
+}
@@ -0,0 +1,267 @@
+//! Signed measurement report ("software bill of measurements").
+//!
+//! Lets auditors ask a running enclave what exactly it is running — PCR
+//! snapshot, module id and runtime-extended PCRs — without a full
+//! attestation flow per query. Reports are signed by a process-lifetime
+//! P-384 key whose public half is bound into a single NSM attestation
+//! document, generated on first use and returned alongside every report.
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use p384::ecdsa::{signature::Signer, Signature, SigningKey};
+use rand_core::OsRng;
+use std::sync::{Arc, Mutex};
+
+use crate::server::AcceptLoop;
+use crate::{cbor, framing, nsm, vsock};
+
+/// Same limit the NSM applies to attestation nonces.
+const MAX_NONCE_SIZE: usize = 512;
+
+/// A measurement document plus everything needed to verify it.
+#[napi(object)]
+pub struct SignedMeasurements {
+    /// CBOR map: module_id, digest, timestamp (ms), nsm_version, pcrs,
+    /// locked_pcrs, runtime_measurements (non-zero PCRs 16+), nonce.
+    pub document: Buffer,
+    /// ES384 signature over `document` (raw r‖s, 96 bytes).
+    pub signature: Buffer,
+    /// SEC1 uncompressed P-384 public key that produced `signature`.
+    pub public_key: Buffer,
+    /// NSM attestation document (COSE_Sign1) whose public_key binds `publicKey`.
+    pub attestation: Buffer,
+}
+
+struct SignedReport {
+    document: Vec<u8>,
+    signature: Vec<u8>,
+    public_key: Vec<u8>,
+    attestation: Vec<u8>,
+}
+
+struct ReportKey {
+    signing_key: SigningKey,
+    public_key: Vec<u8>,
+    attestation: Vec<u8>,
+}
+
+static REPORT_KEY: Mutex<Option<Arc<ReportKey>>> = Mutex::new(None);
+
+/// Get (or lazily create and attest) the report signing key.
+fn report_key() -> Result<Arc<ReportKey>> {
+    let mut guard = REPORT_KEY.lock().unwrap();
+    if let Some(key) = guard.as_ref() {
+        return Ok(key.clone());
+    }
+    let signing_key = SigningKey::random(&mut OsRng);
+    let public_key = signing_key
+        .verifying_key()
+        .to_encoded_point(false)
+        .as_bytes()
+        .to_vec();
+    let attestation = nsm::Device::open()?.attestation(None, None, Some(&public_key))?;
+    let key = Arc::new(ReportKey {
+        signing_key,
+        public_key,
+        attestation,
+    });
+    *guard = Some(key.clone());
+    Ok(key)
+}
+
+fn sign(signing_key: &SigningKey, document: &[u8]) -> Vec<u8> {
+    let signature: Signature = signing_key.sign(document);
+    signature.to_bytes().to_vec()
+}
+
+/// Snapshot the NSM state into a CBOR measurement document.
+fn measurement_document(nonce: Option<&[u8]>) -> Result<Vec<u8>> {
+    let device = nsm::Device::open()?;
+    let desc = device.describe()?;
+
+    let mut pcrs = Vec::new();
+    let mut runtime = Vec::new();
+    for index in 0..desc.max_pcrs as u16 {
+        let (_locked, data) = device.read_pcr(index)?;
+        if index >= nsm::FIRST_RUNTIME_PCR && data.iter().any(|b| *b != 0) {
+            runtime.push((Value::Integer(index.into()), Value::Bytes(data.clone())));
+        }
+        pcrs.push((Value::Integer(index.into()), Value::Bytes(data)));
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    cbor::encode(&cbor::map(vec![
+        ("module_id", Value::Text(desc.module_id)),
+        ("digest", Value::Text(desc.digest)),
+        ("timestamp", Value::Integer(timestamp.into())),
+        (
+            "nsm_version",
+            Value::Text(format!(
+                "{}.{}.{}",
+                desc.version_major, desc.version_minor, desc.version_patch
+            )),
+        ),
+        ("pcrs", Value::Map(pcrs)),
+        (
+            "locked_pcrs",
+            Value::Array(desc.locked_pcrs.into_iter().map(|i| Value::Integer(i.into())).collect()),
+        ),
+        ("runtime_measurements", Value::Map(runtime)),
+        ("nonce", cbor::opt_bytes(nonce)),
+    ]))
+}
+
+fn signed_report(nonce: Option<&[u8]>) -> Result<SignedReport> {
+    if let Some(n) = nonce {
+        if n.len() > MAX_NONCE_SIZE {
+            return Err(Error::from_reason(format!(
+                "nonce too large: {} bytes (max {})",
+                n.len(),
+                MAX_NONCE_SIZE
+            )));
+        }
+    }
+    let key = report_key()?;
+    let document = measurement_document(nonce)?;
+    Ok(SignedReport {
+        signature: sign(&key.signing_key, &document),
+        document,
+        public_key: key.public_key.clone(),
+        attestation: key.attestation.clone(),
+    })
+}
+
+/// Produce a signed measurement report of the running enclave.
+///
+/// `nonce` (max 512 bytes) is embedded in the signed document so an auditor
+/// can prove freshness. The signing key is attested once per process; the
+/// same attestation document is returned with every report.
+#[napi]
+pub fn measurement_report(nonce: Option<Buffer>) -> Result<SignedMeasurements> {
+    let report = signed_report(nonce.as_deref())?;
+    Ok(SignedMeasurements {
+        document: report.document.into(),
+        signature: report.signature.into(),
+        public_key: report.public_key.into(),
+        attestation: report.attestation.into(),
+    })
+}
+
+/// Native vsock endpoint answering measurement report requests.
+///
+/// Protocol: length-prefixed frames (as in shared/src/protocol.ts) carrying
+/// CBOR. Request: `{"nonce": bytes | null}`. Response: a map with
+/// `document`, `signature`, `public_key` and `attestation` byte strings, or
+/// `{"error": text}`. Multiple requests may be sent on one connection.
+#[napi]
+pub struct MeasurementServer {
+    accept_loop: AcceptLoop,
+    port: u32,
+}
+
+#[napi]
+impl MeasurementServer {
+    /// Bind the measurement endpoint on the given vsock port and start
+    /// serving on native threads.
+    #[napi(factory)]
+    pub fn bind(port: u32) -> Result<Self> {
+        let fd = vsock::listen_raw(port)?;
+        Ok(MeasurementServer {
+            accept_loop: AcceptLoop::spawn(fd, |conn, _cid, _port| serve_connection(conn))?,
+            port,
+        })
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+fn serve_connection(fd: i32) {
+    while let Ok(Some(request)) = framing::read_frame(fd) {
+        let response = match handle_request(&request) {
+            Ok(report) => cbor::map(vec![
+                ("document", Value::Bytes(report.document)),
+                ("signature", Value::Bytes(report.signature)),
+                ("public_key", Value::Bytes(report.public_key)),
+                ("attestation", Value::Bytes(report.attestation)),
+            ]),
+            Err(e) => cbor::map(vec![("error", Value::Text(e.reason))]),
+        };
+        let Ok(bytes) = cbor::encode(&response) else { return };
+        if framing::write_frame(fd, &bytes).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_request(request: &[u8]) -> Result<SignedReport> {
+    let nonce = request_nonce(&cbor::decode(request)?)?;
+    signed_report(nonce.as_deref())
+}
+
+fn request_nonce(request: &Value) -> Result<Option<Vec<u8>>> {
+    if !matches!(request, Value::Map(_)) {
+        return Err(Error::from_reason("measurement request must be a CBOR map"));
+    }
+    match cbor::map_get(request, "nonce") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Bytes(b)) => Ok(Some(b.clone())),
+        Some(_) => Err(Error::from_reason("measurement request nonce must be a byte string")),
+    }
+}
+
+// =============================================================================
+// Tests — signing and request parsing (NSM calls require a Nitro Enclave)
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p384::ecdsa::{signature::Verifier, VerifyingKey};
+
+    #[test]
+    fn signature_verifies_with_published_public_key() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let public_key = signing_key.verifying_key().to_encoded_point(false);
+        let document = b"measurement document";
+
+        let signature = sign(&signing_key, document);
+        assert_eq!(signature.len(), 96);
+
+        let verifying_key = VerifyingKey::from_sec1_bytes(public_key.as_bytes()).unwrap();
+        let signature = Signature::from_slice(&signature).unwrap();
+        assert!(verifying_key.verify(document, &signature).is_ok());
+        assert!(verifying_key.verify(b"tampered", &signature).is_err());
+    }
+
+    #[test]
+    fn request_nonce_parsing() {
+        let with_nonce = cbor::map(vec![("nonce", Value::Bytes(vec![1, 2]))]);
+        assert_eq!(request_nonce(&with_nonce).unwrap(), Some(vec![1, 2]));
+        assert_eq!(request_nonce(&cbor::map(vec![])).unwrap(), None);
+        assert_eq!(request_nonce(&cbor::map(vec![("nonce", Value::Null)])).unwrap(), None);
+        assert!(request_nonce(&cbor::map(vec![("nonce", cbor::text("x"))])).is_err());
+        assert!(request_nonce(&Value::Null).is_err());
+    }
+
+    #[test]
+    fn oversized_nonce_is_rejected_before_nsm() {
+        let err = signed_report(Some(&[0u8; MAX_NONCE_SIZE + 1])).err().unwrap();
+        assert!(err.reason.contains("nonce too large"));
+    }
+}
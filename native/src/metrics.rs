@@ -0,0 +1,454 @@
+//! Prometheus metrics endpoint.
+//!
+//! The addon counts its own activity process-wide: VsockStreams opened and
+//! their read()/write()/sendFile() bytes, connections to native servers,
+//! the latency of every NSM request by operation and the NSM request
+//! queue. MetricsServer serves those, plus gauges the application sets, in
+//! the Prometheus text format over plain HTTP/1.1, one request per
+//! connection, so a host-side scraper can reach it through a vsock-to-TCP
+//! forwarder such as TcpToVsockProxy:
+//!
+//! ```js
+//! const metrics = MetricsServer.bind(9100);
+//! metrics.setGauge('app_queue_depth', queue.length, 'Jobs waiting');
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::connect_proxy::read_request_head;
+use crate::nsm;
+use crate::relay::write_all_retrying;
+use crate::server::AcceptLoop;
+use crate::vsock;
+
+/// Upper bounds of the NSM latency histogram buckets, in seconds.
+const NSM_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+/// Reserved for the addon's own metrics.
+const PREFIX: &str = "tytle_";
+
+static STREAMS_OPENED: AtomicU64 = AtomicU64::new(0);
+static STREAMS_OPEN: AtomicI64 = AtomicI64::new(0);
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static NATIVE_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static NSM_REQUESTS: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn stream_opened() {
+    STREAMS_OPENED.fetch_add(1, Ordering::Relaxed);
+    STREAMS_OPEN.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn stream_closed() {
+    STREAMS_OPEN.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub(crate) fn bytes_read(n: usize) {
+    BYTES_READ.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn bytes_written(n: usize) {
+    BYTES_WRITTEN.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn native_connection() {
+    NATIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record one NSM request. `failed` means it got no response at all; NSM
+/// Error responses count as answered.
+pub(crate) fn nsm_request(operation: &str, elapsed: Duration, failed: bool) {
+    let mut requests = NSM_REQUESTS.lock().unwrap();
+    let histogram = match requests.get_mut(operation) {
+        Some(histogram) => histogram,
+        None => requests.entry(operation.to_string()).or_default(),
+    };
+    histogram.observe(elapsed.as_secs_f64());
+    if failed {
+        histogram.failures += 1;
+    }
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    /// Non-cumulative counts per NSM_BUCKETS entry.
+    buckets: [u64; NSM_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+    failures: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = NSM_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+struct Gauge {
+    value: f64,
+    help: Option<String>,
+}
+
+/// napi-free core of MetricsServer: the application's gauges.
+#[derive(Default)]
+struct Registry {
+    gauges: Mutex<BTreeMap<String, Gauge>>,
+}
+
+impl Registry {
+    fn set_gauge(&self, name: &str, value: f64, help: Option<String>) -> Result<()> {
+        check_name(name)?;
+        let mut gauges = self.gauges.lock().unwrap();
+        let help = help.or_else(|| gauges.get(name).and_then(|g| g.help.clone()));
+        gauges.insert(name.to_string(), Gauge { value, help });
+        Ok(())
+    }
+
+    fn remove_gauge(&self, name: &str) -> bool {
+        self.gauges.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let nsm_queue = nsm::nsm_queue_stats();
+        let counters = [
+            (
+                "vsock_streams_opened_total",
+                "counter",
+                "VsockStreams connected or accepted.",
+                STREAMS_OPENED.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "vsock_streams_open",
+                "gauge",
+                "VsockStreams not yet closed.",
+                STREAMS_OPEN.load(Ordering::Relaxed),
+            ),
+            (
+                "vsock_bytes_read_total",
+                "counter",
+                "Bytes returned by VsockStream reads.",
+                BYTES_READ.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "vsock_bytes_written_total",
+                "counter",
+                "Bytes sent by VsockStream write() and sendFile().",
+                BYTES_WRITTEN.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "native_connections_total",
+                "counter",
+                "Connections accepted by native servers.",
+                NATIVE_CONNECTIONS.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "nsm_queue_depth",
+                "gauge",
+                "NSM requests waiting for another to finish.",
+                nsm_queue.depth as i64,
+            ),
+            (
+                "nsm_queue_waits_total",
+                "counter",
+                "NSM requests that had to wait for another to finish.",
+                nsm_queue.waited as i64,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(out, "# HELP {PREFIX}{name} {help}");
+            let _ = writeln!(out, "# TYPE {PREFIX}{name} {kind}");
+            let _ = writeln!(out, "{PREFIX}{name} {value}");
+        }
+
+        let requests = NSM_REQUESTS.lock().unwrap().clone();
+        let name = format!("{PREFIX}nsm_request_duration_seconds");
+        let _ = writeln!(out, "# HELP {name} NSM request latency by operation.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (operation, histogram) in &requests {
+            let operation = escape_label(operation);
+            let mut cumulative = 0;
+            for (bound, count) in NSM_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{operation=\"{operation}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{operation=\"{operation}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "{name}_sum{{operation=\"{operation}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "{name}_count{{operation=\"{operation}\"}} {}",
+                histogram.count
+            );
+        }
+        let name = format!("{PREFIX}nsm_request_failures_total");
+        let _ = writeln!(
+            out,
+            "# HELP {name} NSM requests that got no response, by operation."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (operation, histogram) in &requests {
+            let _ = writeln!(
+                out,
+                "{name}{{operation=\"{}\"}} {}",
+                escape_label(operation),
+                histogram.failures
+            );
+        }
+
+        for (name, gauge) in self.gauges.lock().unwrap().iter() {
+            if let Some(help) = &gauge.help {
+                let _ = writeln!(
+                    out,
+                    "# HELP {name} {}",
+                    help.replace('\\', "\\\\").replace('\n', "\\n")
+                );
+            }
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {}", format_value(gauge.value));
+        }
+        out
+    }
+}
+
+/// Prometheus metric names: `[a-zA-Z_:][a-zA-Z0-9_:]*`, minus our prefix.
+fn check_name(name: &str) -> Result<()> {
+    let valid = name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    });
+    if name.is_empty() || !valid {
+        return Err(Error::from_reason(format!(
+            "Invalid metric name {:?}: must match [a-zA-Z_:][a-zA-Z0-9_:]*",
+            name
+        )));
+    }
+    if name.starts_with(PREFIX) {
+        return Err(Error::from_reason(format!(
+            "Metric names starting with {:?} are reserved for the addon",
+            PREFIX
+        )));
+    }
+    Ok(())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Native vsock endpoint serving the addon's metrics and the application's
+/// gauges over HTTP. See the module docs.
+#[napi]
+pub struct MetricsServer {
+    accept_loop: AcceptLoop,
+    registry: Arc<Registry>,
+    port: u32,
+}
+
+#[napi]
+impl MetricsServer {
+    /// Bind `port` and start serving on native threads.
+    #[napi(factory)]
+    pub fn bind(port: u32) -> Result<Self> {
+        let registry = Arc::new(Registry::default());
+        let fd = vsock::listen_raw(port)?;
+        let served = registry.clone();
+        Ok(MetricsServer {
+            accept_loop: AcceptLoop::spawn(fd, move |conn, _cid, _port| {
+                serve_connection(conn, &served)
+            })?,
+            registry,
+            port,
+        })
+    }
+
+    /// Set gauge `name` to `value`, creating it if needed. `help` is kept
+    /// from an earlier call when omitted.
+    #[napi]
+    pub fn set_gauge(&self, name: String, value: f64, help: Option<String>) -> Result<()> {
+        self.registry.set_gauge(&name, value, help)
+    }
+
+    /// Stop exporting gauge `name`. Returns whether it existed.
+    #[napi]
+    pub fn remove_gauge(&self, name: String) -> bool {
+        self.registry.remove_gauge(&name)
+    }
+
+    /// The current exposition, as a scrape would return it.
+    #[napi]
+    pub fn render(&self) -> String {
+        self.registry.render()
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Answer one HTTP request on `fd`; the accept loop closes it afterwards.
+fn serve_connection(fd: i32, registry: &Registry) {
+    let Ok((head, _)) = read_request_head(fd) else {
+        return;
+    };
+    let response = match parse_request(&head) {
+        Ok(head_only) => {
+            let body = registry.render();
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            if !head_only {
+                response.push_str(&body);
+            }
+            response
+        }
+        Err((status, reason)) => format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status, reason
+        ),
+    };
+    let _ = write_all_retrying(fd, response.as_bytes());
+}
+
+/// Check the request line of `head`: GET or HEAD of / or /metrics.
+/// Returns whether it's a HEAD, or the status to reply with.
+fn parse_request(head: &[u8]) -> std::result::Result<bool, (u16, &'static str)> {
+    let head = std::str::from_utf8(head).map_err(|_| (400, "Bad Request"))?;
+    let line = head.split("\r\n").next().unwrap_or("");
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err((400, "Bad Request"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err((400, "Bad Request"));
+    }
+    if method != "GET" && method != "HEAD" {
+        return Err((405, "Method Not Allowed"));
+    }
+    let path = target.split('?').next().unwrap_or("");
+    if path != "/" && path != "/metrics" {
+        return Err((404, "Not Found"));
+    }
+    Ok(method == "HEAD")
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    /// Send `request` to a served connection and return the response.
+    fn scrape(registry: Arc<Registry>, request: &str) -> String {
+        let (mut host, enclave) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || serve_connection(enclave.as_raw_fd(), &registry));
+        host.write_all(request.as_bytes()).unwrap();
+        server.join().unwrap();
+        let mut response = String::new();
+        host.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_addon_metrics_and_app_gauges() {
+        nsm_request("DescribePCR", Duration::from_micros(300), false);
+        nsm_request("DescribePCR", Duration::from_secs(2), true);
+        let registry = Arc::new(Registry::default());
+        registry
+            .set_gauge("app_queue_depth", 12.0, Some("Jobs waiting".to_string()))
+            .unwrap();
+        registry.set_gauge("app_queue_depth", 3.5, None).unwrap();
+
+        let response = scrape(registry, "GET /metrics HTTP/1.1\r\nHost: enclave\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(body.contains("# TYPE tytle_vsock_streams_opened_total counter\n"));
+        assert!(body.contains("# HELP app_queue_depth Jobs waiting\n"));
+        assert!(body.contains("\napp_queue_depth 3.5\n"));
+        // Other tests record NSM requests too, so only check our shape
+        assert!(body.contains(
+            "tytle_nsm_request_duration_seconds_bucket{operation=\"DescribePCR\",le=\"0.0005\"}"
+        ));
+        assert!(body.contains("tytle_nsm_request_failures_total{operation=\"DescribePCR\"}"));
+    }
+
+    #[test]
+    fn rejects_other_requests_and_bad_gauge_names() {
+        let registry = Arc::new(Registry::default());
+        let status = |request: &str| {
+            let response = scrape(registry.clone(), request);
+            response.lines().next().unwrap().to_string()
+        };
+        assert_eq!(
+            status("POST /metrics HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+        assert_eq!(
+            status("GET /other HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(status("nonsense\r\n\r\n"), "HTTP/1.1 400 Bad Request");
+        let head = scrape(registry.clone(), "HEAD / HTTP/1.0\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK") && head.ends_with("\r\n\r\n"));
+
+        for name in ["", "1abc", "has-dash", "tytle_custom"] {
+            assert!(registry.set_gauge(name, 1.0, None).is_err(), "{:?}", name);
+        }
+        assert!(registry.set_gauge("ok:name_2", f64::INFINITY, None).is_ok());
+        assert!(registry.render().contains("\nok:name_2 +Inf\n"));
+        assert!(registry.remove_gauge("ok:name_2"));
+        assert!(!registry.remove_gauge("ok:name_2"));
+    }
+}
@@ -0,0 +1,373 @@
+//! Loopback vsock backend for local development and integration tests.
+//!
+//! When `TYTLE_VSOCK_MOCK_DIR` is set, every vsock socket this addon opens
+//! (VsockListener, VsockStream, the native proxies and servers) is a unix
+//! socket under that directory instead, keyed by (cid, port):
+//!
+//!   `<dir>/<cid>-<port>.sock`
+//!
+//! Listeners bind at this process's CID, taken from `TYTLE_VSOCK_MOCK_CID`
+//! (default 3, the parent instance as seen from an enclave). Run the
+//! "enclave" process with e.g. `TYTLE_VSOCK_MOCK_CID=16` and point both
+//! sides at the same directory to exercise the real code paths on any
+//! Linux box (or a macOS machine, where AF_VSOCK doesn't exist at all).
+//!
+//! Connectors send an 8-byte preamble, [cid BE][port BE], so the accepting
+//! side reports the same peer (cid, port) it would on AF_VSOCK. Connectors
+//! get an ephemeral port, as with a real vsock connect(). Listeners wait for
+//! preambles on all pending connections at once, so a connector that stalls
+//! before sending one never holds up the others.
+
+use napi::bindgen_prelude::*;
+use std::io::ErrorKind;
+use std::os::fd::IntoRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::errors;
+use crate::platform::accept_cloexec;
+use crate::relay::write_all_retrying;
+
+const MOCK_DIR_ENV: &str = "TYTLE_VSOCK_MOCK_DIR";
+const MOCK_CID_ENV: &str = "TYTLE_VSOCK_MOCK_CID";
+const DEFAULT_MOCK_CID: u32 = 3;
+
+/// Start of the ephemeral range handed to mock connectors.
+const FIRST_EPHEMERAL_PORT: u32 = 49152;
+
+/// How long an accepted connection may take to send its preamble.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static BACKEND: OnceLock<Option<MockBackend>> = OnceLock::new();
+static NEXT_EPHEMERAL_PORT: AtomicU32 = AtomicU32::new(FIRST_EPHEMERAL_PORT);
+
+/// Accepted connections still waiting on their preamble, by listener.
+/// Listeners are keyed by (fd, inode) since fd numbers get recycled.
+static PENDING: Mutex<Vec<((i32, u64), Pending)>> = Mutex::new(Vec::new());
+
+struct Pending {
+    conn: i32,
+    preamble: [u8; 8],
+    got: usize,
+    deadline: Instant,
+}
+
+impl Pending {
+    fn new(conn: i32) -> Self {
+        Pending { conn, preamble: [0; 8], got: 0, deadline: Instant::now() + PREAMBLE_TIMEOUT }
+    }
+}
+
+pub(crate) struct MockBackend {
+    dir: PathBuf,
+    local_cid: u32,
+}
+
+/// The mock backend, if enabled for this process. Read from the environment
+/// once, on first use.
+pub(crate) fn backend() -> Option<&'static MockBackend> {
+    BACKEND
+        .get_or_init(|| {
+            let dir = std::env::var_os(MOCK_DIR_ENV).filter(|d| !d.is_empty())?;
+            let local_cid = std::env::var(MOCK_CID_ENV)
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(DEFAULT_MOCK_CID);
+            Some(MockBackend::new(dir.into(), local_cid))
+        })
+        .as_ref()
+}
+
+impl MockBackend {
+    pub(crate) fn new(dir: PathBuf, local_cid: u32) -> Self {
+        MockBackend { dir, local_cid }
+    }
+
+    fn path(&self, cid: u32, port: u32) -> PathBuf {
+        self.dir.join(format!("{}-{}.sock", cid, port))
+    }
+
+    /// Bind a listener for `port` at this process's CID.
+    pub(crate) fn listen(&self, port: u32) -> Result<i32> {
+        let path = self.path(self.local_cid, port);
+        let bind_err =
+            |e: std::io::Error| errors::os_error(format!("bind(AF_VSOCK, port={})", port), e);
+
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(Error::from_reason(format!(
+                    "{} exists and is not a unix socket",
+                    path.display()
+                )));
+            }
+            // Someone is still listening: report it the way AF_VSOCK would.
+            // Otherwise it's left over from a previous run.
+            if UnixStream::connect(&path).is_ok() {
+                return Err(bind_err(std::io::Error::from_raw_os_error(libc::EADDRINUSE)));
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+        std::fs::create_dir_all(&self.dir).map_err(bind_err)?;
+        let listener = UnixListener::bind(&path).map_err(bind_err)?;
+        Ok(listener.into_raw_fd())
+    }
+
+    /// Connect to (cid, port). A non-zero `timeout_secs` sets
+    /// SO_RCVTIMEO/SO_SNDTIMEO, as connect_raw does.
+    pub(crate) fn connect(&self, cid: u32, port: u32, timeout_secs: u32) -> Result<i32> {
+        let stream = UnixStream::connect(self.path(cid, port)).map_err(|e| {
+            // No socket file means nobody is listening
+            let e = if e.kind() == ErrorKind::NotFound {
+                std::io::Error::from_raw_os_error(libc::ECONNREFUSED)
+            } else {
+                e
+            };
+            errors::os_error(format!("connect(cid={}, port={})", cid, port), e)
+        })?;
+        if timeout_secs > 0 {
+            let timeout = Some(std::time::Duration::from_secs(timeout_secs as u64));
+            let _ = stream.set_read_timeout(timeout);
+            let _ = stream.set_write_timeout(timeout);
+        }
+
+        let local_port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+        let mut preamble = [0u8; 8];
+        preamble[..4].copy_from_slice(&self.local_cid.to_be_bytes());
+        preamble[4..].copy_from_slice(&local_port.to_be_bytes());
+
+        let fd = stream.into_raw_fd();
+        if let Err(e) = write_all_retrying(fd, &preamble) {
+            unsafe { libc::close(fd); }
+            return Err(errors::os_error(format!("connect(cid={}, port={})", cid, port), e));
+        }
+        Ok(fd)
+    }
+}
+
+/// accept() on a mock listener, returning the peer (cid, port) from the
+/// connector's preamble. Connections are accepted as they arrive and
+/// returned in the order their preambles complete; ones that close or stall
+/// without sending one (e.g. the liveness probe in `listen`) are dropped.
+///
+/// With nothing pending this is a plain accept(), blocking or not as the
+/// listener is; after that it waits at most PREAMBLE_TIMEOUT.
+pub(crate) fn accept(fd: i32) -> std::io::Result<(i32, u32, u32)> {
+    let key = (fd, listener_inode(fd)?);
+    let mut pending = take_pending(key);
+    let result = accept_pending(fd, &mut pending);
+    PENDING
+        .lock()
+        .unwrap()
+        .extend(pending.into_iter().map(|p| (key, p)));
+    result
+}
+
+fn accept_pending(fd: i32, pending: &mut Vec<Pending>) -> std::io::Result<(i32, u32, u32)> {
+    loop {
+        let now = Instant::now();
+        pending.retain(|p| {
+            let live = p.deadline > now;
+            if !live {
+                unsafe { libc::close(p.conn); }
+            }
+            live
+        });
+        if pending.is_empty() {
+            let conn = accept_cloexec(fd)?;
+            pending.push(Pending::new(conn));
+        }
+
+        let mut pfds: Vec<libc::pollfd> = std::iter::once(fd)
+            .chain(pending.iter().map(|p| p.conn))
+            .map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+        let wait = pending.iter().map(|p| p.deadline - now).min().unwrap_or_default();
+        let timeout_ms = wait.as_millis().min(i32::MAX as u128) as i32 + 1;
+        if unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        // Back to front, so swap_remove only moves entries already checked
+        for i in (0..pending.len()).rev() {
+            if pfds[i + 1].revents == 0 {
+                continue;
+            }
+            match read_preamble(&mut pending[i]) {
+                Ok(false) => {}
+                Ok(true) => {
+                    let Pending { conn, preamble: p, .. } = pending.swap_remove(i);
+                    let cid = u32::from_be_bytes([p[0], p[1], p[2], p[3]]);
+                    let port = u32::from_be_bytes([p[4], p[5], p[6], p[7]]);
+                    return Ok((conn, cid, port));
+                }
+                Err(_) => unsafe {
+                    libc::close(pending.swap_remove(i).conn);
+                },
+            }
+        }
+
+        if pfds[0].revents != 0 {
+            match accept_cloexec(fd) {
+                Ok(conn) => pending.push(Pending::new(conn)),
+                // Another accept() took it; keep waiting on the pending ones
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EINTR)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Read what has arrived of `pending`'s preamble without blocking.
+/// Returns whether it is complete; EOF is an error.
+fn read_preamble(pending: &mut Pending) -> std::io::Result<bool> {
+    let rest = &mut pending.preamble[pending.got..];
+    let buf = rest.as_mut_ptr() as *mut libc::c_void;
+    let n = unsafe { libc::recv(pending.conn, buf, rest.len(), libc::MSG_DONTWAIT) };
+    if n == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    if n < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EAGAIN | libc::EINTR) => Ok(false),
+            _ => Err(err),
+        };
+    }
+    pending.got += n as usize;
+    Ok(pending.got == pending.preamble.len())
+}
+
+/// Take `key`'s pending connections, closing any left by closed listeners
+/// that have since timed out.
+fn take_pending(key: (i32, u64)) -> Vec<Pending> {
+    let now = Instant::now();
+    let mut all = PENDING.lock().unwrap();
+    let mut taken = Vec::new();
+    for (k, p) in std::mem::take(&mut *all) {
+        if k == key {
+            taken.push(p);
+        } else if p.deadline <= now {
+            unsafe { libc::close(p.conn); }
+        } else {
+            all.push((k, p));
+        }
+    }
+    taken
+}
+
+fn listener_inode(fd: i32) -> std::io::Result<u64> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(st.st_ino as u64)
+}
+
+// =============================================================================
+// Tests — explicit backends in a scratch directory (the env is read once)
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::read_exact;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vsock-mock-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn connect_reaches_listener_and_reports_peer() {
+        let dir = scratch_dir("connect");
+        let host = MockBackend::new(dir.clone(), 3);
+        let enclave = MockBackend::new(dir.clone(), 16);
+
+        let listener = host.listen(5000).unwrap();
+        let client = enclave.connect(3, 5000, 1).unwrap();
+        let (conn, cid, port) = accept(listener).unwrap();
+        assert_eq!(cid, 16);
+        assert!(port >= FIRST_EPHEMERAL_PORT);
+
+        write_all_retrying(client, b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        read_exact(conn, &mut buf, false).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        unsafe {
+            libc::close(client);
+            libc::close(conn);
+            libc::close(listener);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn connect_without_listener_is_refused() {
+        let dir = scratch_dir("refused");
+        let backend = MockBackend::new(dir.clone(), 3);
+        let err = backend.connect(16, 5000, 1).unwrap_err();
+        assert!(err.reason.contains("connect(cid=16, port=5000) failed"), "{}", err.reason);
+        assert!(err.reason.contains("refused"), "{}", err.reason);
+    }
+
+    #[test]
+    fn bind_detects_live_listeners_and_replaces_stale_ones() {
+        let dir = scratch_dir("bind");
+        let backend = MockBackend::new(dir.clone(), 3);
+
+        let listener = backend.listen(6000).unwrap();
+        let err = backend.listen(6000).unwrap_err();
+        assert!(err.reason.contains("bind(AF_VSOCK, port=6000) failed"), "{}", err.reason);
+
+        // The probe connection is skipped by accept()
+        let client = backend.connect(3, 6000, 1).unwrap();
+        let (conn, cid, _) = accept(listener).unwrap();
+        assert_eq!(cid, 3);
+        unsafe {
+            libc::close(client);
+            libc::close(conn);
+            libc::close(listener);
+        }
+
+        // The socket file outlives the closed listener
+        let listener = backend.listen(6000).unwrap();
+        unsafe { libc::close(listener); }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stalled_connectors_do_not_hold_up_accept() {
+        let dir = scratch_dir("stalled");
+        let backend = MockBackend::new(dir.clone(), 3);
+        let listener = backend.listen(7000).unwrap();
+
+        // Connected, but never sends its preamble
+        let stalled = UnixStream::connect(backend.path(3, 7000)).unwrap();
+        let client = backend.connect(3, 7000, 1).unwrap();
+        let started = Instant::now();
+        let (conn, cid, _) = accept(listener).unwrap();
+        assert_eq!(cid, 3);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+        // The stalled one is still pending, and is returned once it sends
+        let stalled = stalled.into_raw_fd();
+        write_all_retrying(stalled, &[0, 0, 0, 16, 0, 0, 0x13, 0x88]).unwrap();
+        let (pending_conn, cid, port) = accept(listener).unwrap();
+        assert_eq!((cid, port), (16, 5000));
+
+        for fd in [stalled, client, conn, pending_conn, listener] {
+            unsafe { libc::close(fd); }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+//! Protocol version and feature negotiation.
+//!
+//! Enclave and host are often built from different releases of this crate.
+//! Without a check, a change to a wire format shows up as garbled frames
+//! or a hung read. Negotiation makes each side send one hello frame when
+//! the connection opens:
+//!
+//! ```text
+//! \0tytle-hello/1\0 ‖ version (u16) ‖ min version (u16) ‖ features (u32) ‖ release
+//! ```
+//!
+//! all big-endian, where release is the crate version that sent it. The
+//! exchange is symmetric, so either side may speak first. Both agree on
+//! the lower of the two versions, or fail with "VersionMismatch: ..." if
+//! it is below either side's minimum; the features are the bits both set.
+//!
+//! ```js
+//! const protocol = await negotiateProtocol(stream, { requiredFeatures: ['zstd'] });
+//! // { version: 1, peerVersion: 1, peerRelease: '0.1.0', features: ['zstd', 'lz4', ...] }
+//! ```
+//!
+//! The framing and secure-channel layers run it on request: RpcClient and
+//! connectSecureChannel()/acceptSecureChannel() take `{ negotiate: true }`
+//! and expose the result as `.protocol`. serveFramed() servers answer a
+//! hello whenever a connection starts with one, so clients that don't
+//! negotiate keep working.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::errors;
+use crate::framing;
+use crate::relay::dup_fd;
+use crate::vsock::VsockStream;
+use crate::workers::WorkerTask;
+
+const MAGIC: &[u8] = b"\0tytle-hello/1\0";
+/// Wire protocol version of this release.
+pub(crate) const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this release still speaks.
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+const DEFAULT_TIMEOUT_MS: u32 = 5_000;
+
+/// Feature bits. Bits a peer sets that aren't listed here come from a
+/// newer release and are ignored.
+const FEATURES: [(&str, u32); 3] = [
+    // Framed compression (see compression)
+    ("zstd", 1 << 0),
+    ("lz4", 1 << 1),
+    // Secure channel session resumption
+    ("resumption", 1 << 2),
+];
+
+#[napi(object)]
+pub struct NegotiateOptions {
+    /// Features to advertise (default: all this release supports).
+    pub features: Option<Vec<String>>,
+    /// Fail with VersionMismatch unless both sides support these.
+    pub required_features: Option<Vec<String>>,
+    /// How long to wait for the peer's hello (default 5000).
+    pub timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct NegotiatedProtocol {
+    /// The version both sides speak.
+    pub version: u32,
+    /// The peer's own (highest) version.
+    pub peer_version: u32,
+    /// The crate version the peer was built from.
+    pub peer_release: String,
+    /// Features both sides support.
+    pub features: Vec<String>,
+    /// Features the peer advertised, as far as this release knows them.
+    pub peer_features: Vec<String>,
+}
+
+/// Exchange hellos on `stream` and agree on a protocol version and
+/// features. Rejects with "VersionMismatch: ..." if the releases can't
+/// talk, or "ETIMEDOUT: ..." if the peer doesn't answer (e.g. it predates
+/// negotiation). The stream is usable afterwards.
+#[napi(ts_return_type = "Promise<NegotiatedProtocol>")]
+pub fn negotiate_protocol(
+    stream: &VsockStream,
+    options: Option<NegotiateOptions>,
+) -> Result<WorkerTask<NegotiateTask>> {
+    let (features, required, timeout_ms) = match options {
+        Some(o) => (o.features, o.required_features, o.timeout_ms),
+        None => (None, None, None),
+    };
+    let mut local = Hello::local();
+    if let Some(names) = features {
+        local.features = feature_bits(&names)?;
+    }
+    let required = match required {
+        Some(names) => feature_bits(&names)?,
+        None => 0,
+    };
+    Ok(WorkerTask::new(NegotiateTask {
+        fd: dup_fd(stream.fd())?,
+        local,
+        required,
+        timeout: Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS) as u64),
+    }))
+}
+
+pub struct NegotiateTask {
+    /// A duplicate of the stream's fd, closed when the task is dropped.
+    fd: i32,
+    local: Hello,
+    required: u32,
+    timeout: Duration,
+}
+
+impl Task for NegotiateTask {
+    type Output = Negotiated;
+    type JsValue = NegotiatedProtocol;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let deadline = Instant::now() + self.timeout;
+        negotiate(self.fd, &self.local, self.required, deadline)
+    }
+
+    fn resolve(&mut self, _env: Env, negotiated: Self::Output) -> Result<Self::JsValue> {
+        Ok(negotiated.into_js())
+    }
+}
+
+impl Drop for NegotiateTask {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// One side's hello.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub features: u32,
+    pub release: String,
+}
+
+impl Hello {
+    /// This release's hello, advertising every feature.
+    pub(crate) fn local() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: FEATURES.iter().fold(0, |bits, (_, bit)| bits | bit),
+            release: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut frame = MAGIC.to_vec();
+        frame.extend_from_slice(&self.version.to_be_bytes());
+        frame.extend_from_slice(&self.min_version.to_be_bytes());
+        frame.extend_from_slice(&self.features.to_be_bytes());
+        frame.extend_from_slice(self.release.as_bytes());
+        frame
+    }
+
+    /// None if `frame` isn't a hello; an error if it's a malformed one.
+    fn decode(frame: &[u8]) -> Result<Option<Self>> {
+        let Some(body) = frame.strip_prefix(MAGIC) else {
+            return Ok(None);
+        };
+        if body.len() < 8 {
+            return Err(mismatch("peer sent a truncated hello"));
+        }
+        Ok(Some(Hello {
+            version: u16::from_be_bytes([body[0], body[1]]),
+            min_version: u16::from_be_bytes([body[2], body[3]]),
+            features: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+            release: String::from_utf8_lossy(&body[8..]).into_owned(),
+        }))
+    }
+}
+
+/// The outcome of a negotiation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: u32,
+    pub peer: Hello,
+}
+
+impl Negotiated {
+    pub(crate) fn into_js(self) -> NegotiatedProtocol {
+        NegotiatedProtocol {
+            version: self.version as u32,
+            peer_version: self.peer.version as u32,
+            peer_release: self.peer.release,
+            features: feature_names(self.features),
+            peer_features: feature_names(self.peer.features),
+        }
+    }
+}
+
+/// Send `local` on `fd`, wait until `deadline` for the peer's hello and
+/// agree with it, requiring the `required` feature bits.
+pub(crate) fn negotiate(
+    fd: i32,
+    local: &Hello,
+    required: u32,
+    deadline: Instant,
+) -> Result<Negotiated> {
+    framing::write_frame(fd, &local.encode()).map_err(|e| errors::os_error("protocol hello", e))?;
+    if !wait_readable(fd, deadline).map_err(|e| errors::os_error("poll()", e))? {
+        return Err(Error::from_reason(
+            "ETIMEDOUT: peer did not answer the protocol hello; it may predate negotiation",
+        ));
+    }
+    let frame = match framing::read_frame(fd) {
+        Ok(Some(frame)) => frame,
+        Ok(None) => {
+            return Err(mismatch(
+                "peer closed the connection instead of answering the hello",
+            ))
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            return Err(mismatch(format!(
+                "peer answered the hello with a bad frame: {}",
+                e
+            )))
+        }
+        Err(e) => return Err(errors::os_error("protocol hello", e)),
+    };
+    let peer = Hello::decode(&frame)?.ok_or_else(|| {
+        mismatch("peer answered the hello with a data frame; it predates negotiation")
+    })?;
+    agree(local, &peer, required)
+}
+
+/// Server side: if a connection's first `frame` is a hello, answer it
+/// with `local` and agree. None for an ordinary frame. The answer is sent
+/// even when the sides disagree, so the peer sees the mismatch too.
+pub(crate) fn answer(fd: i32, frame: &[u8], local: &Hello) -> Result<Option<Negotiated>> {
+    let Some(peer) = Hello::decode(frame)? else {
+        return Ok(None);
+    };
+    framing::write_frame(fd, &local.encode()).map_err(|e| errors::os_error("protocol hello", e))?;
+    agree(local, &peer, 0).map(Some)
+}
+
+/// What `local` and `peer` can speak: the lower of their versions, if
+/// both still support it, and the features both set.
+fn agree(local: &Hello, peer: &Hello, required: u32) -> Result<Negotiated> {
+    let version = local.version.min(peer.version);
+    if version < local.min_version || version < peer.min_version {
+        return Err(mismatch(format!(
+            "peer {} speaks protocol versions {}-{}, this release ({}) speaks {}-{}",
+            peer.release,
+            peer.min_version,
+            peer.version,
+            local.release,
+            local.min_version,
+            local.version
+        )));
+    }
+    let features = local.features & peer.features;
+    let missing = required & !features;
+    if missing != 0 {
+        return Err(mismatch(format!(
+            "peer {} does not support {}",
+            peer.release,
+            feature_names(missing).join(", ")
+        )));
+    }
+    Ok(Negotiated {
+        version,
+        features,
+        peer: peer.clone(),
+    })
+}
+
+fn mismatch(message: impl std::fmt::Display) -> Error {
+    Error::from_reason(format!("VersionMismatch: {}", message))
+}
+
+fn feature_bits(names: &[String]) -> Result<u32> {
+    names.iter().try_fold(0, |bits, name| {
+        let (_, bit) = FEATURES
+            .iter()
+            .find(|(known, _)| known == name)
+            .ok_or_else(|| Error::from_reason(format!("Unknown protocol feature: {}", name)))?;
+        Ok(bits | bit)
+    })
+}
+
+fn feature_names(bits: u32) -> Vec<String> {
+    FEATURES
+        .iter()
+        .filter(|(_, bit)| bits & bit != 0)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Wait until `fd` is readable; false if `deadline` passes first.
+fn wait_readable(fd: i32, deadline: Instant) -> std::io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as i32) } {
+            n if n >= 0 => return Ok(n > 0),
+            _ => {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn hello(version: u16, min_version: u16, features: u32) -> Hello {
+        Hello {
+            version,
+            min_version,
+            features,
+            release: format!("0.{}.0", version),
+        }
+    }
+
+    /// Negotiate `ours` against `theirs` over a socketpair, both sides at once.
+    fn exchange(
+        ours: Hello,
+        theirs: Hello,
+        required: u32,
+    ) -> (Result<Negotiated>, Result<Negotiated>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let peer = std::thread::spawn(move || negotiate(b.as_raw_fd(), &theirs, 0, deadline));
+        let negotiated = negotiate(a.as_raw_fd(), &ours, required, deadline);
+        (negotiated, peer.join().unwrap())
+    }
+
+    #[test]
+    fn peers_agree_on_the_common_version_and_features() {
+        let (ours, theirs) = exchange(hello(3, 1, 0b011), hello(2, 2, 0b1110), 0);
+        let ours = ours.unwrap();
+        assert_eq!((ours.version, ours.features), (2, 0b010));
+        assert_eq!(ours.peer, hello(2, 2, 0b1110));
+        assert_eq!(theirs.unwrap().version, 2);
+
+        let js = ours.into_js();
+        assert_eq!(js.features, vec!["lz4"]);
+        assert_eq!(js.peer_features, vec!["lz4", "resumption"]);
+        assert_eq!(js.peer_release, "0.2.0");
+
+        let local = Hello::local();
+        assert_eq!(Hello::decode(&local.encode()).unwrap(), Some(local));
+    }
+
+    #[test]
+    fn incompatible_releases_fail_with_version_mismatch() {
+        let (ours, theirs) = exchange(hello(3, 3, 0), hello(2, 1, 0), 0);
+        let expected = "VersionMismatch: peer 0.2.0 speaks protocol versions 1-2, \
+                        this release (0.3.0) speaks 3-3";
+        assert_eq!(ours.unwrap_err().reason, expected);
+        assert!(theirs.unwrap_err().reason.starts_with("VersionMismatch: "));
+
+        let (ours, _) = exchange(hello(1, 1, 0b001), hello(1, 1, 0b011), 0b100);
+        assert_eq!(
+            ours.unwrap_err().reason,
+            "VersionMismatch: peer 0.1.0 does not support resumption"
+        );
+        assert!(feature_bits(&["brotli".to_string()]).is_err());
+    }
+
+    #[test]
+    fn peers_that_do_not_negotiate_are_detected() {
+        let (a, b) = UnixStream::pair().unwrap();
+        framing::write_frame(b.as_raw_fd(), b"{\"jsonrpc\":\"2.0\"}").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let err = negotiate(a.as_raw_fd(), &Hello::local(), 0, deadline).unwrap_err();
+        assert!(err
+            .reason
+            .starts_with("VersionMismatch: peer answered the hello with a data frame"));
+
+        let silent = Instant::now() + Duration::from_millis(20);
+        let err = negotiate(a.as_raw_fd(), &Hello::local(), 0, silent).unwrap_err();
+        assert!(err.reason.starts_with("ETIMEDOUT: "));
+
+        assert!(answer(a.as_raw_fd(), b"ordinary", &Hello::local())
+            .unwrap()
+            .is_none());
+        assert!(Hello::decode(MAGIC).is_err());
+    }
+}
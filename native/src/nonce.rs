@@ -0,0 +1,219 @@
+//! Host-side nonce bookkeeping for replay protection.
+//!
+//! A verifier that wants fresh attestation documents issues a random nonce,
+//! has the enclave attest with it, and accepts the document only if the
+//! nonce is one it issued, has not expired and has not been seen before.
+//! NonceRegistry keeps that state:
+//!
+//! ```js
+//! const nonces = new NonceRegistry({ ttlMs: 60_000 });
+//! const nonce = nonces.issue();
+//! // ... enclave attests with `nonce` ...
+//! if (policy.evaluate(doc).valid && nonces.redeem(doc).valid) { ... }
+//! ```
+//!
+//! redeem() only looks at the nonce; check the document's signature and
+//! chain (AttestationPolicy, verifyAttestation()) as well.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::attestation::{self, CoseSign1, Document};
+
+const DEFAULT_TTL_MS: i64 = 5 * 60_000;
+const DEFAULT_NONCE_BYTES: u32 = 32;
+/// The NSM rejects longer nonces.
+const MAX_NONCE_BYTES: u32 = 512;
+const DEFAULT_MAX_OUTSTANDING: u32 = 10_000;
+
+#[napi(object)]
+pub struct NonceRegistryOptions {
+    /// How long an issued nonce stays redeemable (default 5 minutes).
+    pub ttl_ms: Option<f64>,
+    /// Nonce length in bytes (default 32, at most 512).
+    pub nonce_bytes: Option<u32>,
+    /// Most unexpired, unredeemed nonces (default 10000); issue() throws
+    /// beyond this rather than growing without bound.
+    pub max_outstanding: Option<u32>,
+}
+
+#[napi(object)]
+pub struct NonceRedemption {
+    /// True when the nonce was issued here, unexpired and unused.
+    pub valid: bool,
+    /// Why not, when invalid.
+    pub reason: Option<String>,
+}
+
+/// napi-free core of NonceRegistry.
+struct Registry {
+    ttl_ms: i64,
+    nonce_bytes: usize,
+    max_outstanding: usize,
+    /// Outstanding nonce → expiry (ms since the epoch).
+    outstanding: HashMap<Vec<u8>, i64>,
+}
+
+impl Registry {
+    fn issue(&mut self, now_ms: i64) -> Result<Vec<u8>> {
+        self.outstanding.retain(|_, expires| *expires > now_ms);
+        if self.outstanding.len() >= self.max_outstanding {
+            return Err(Error::from_reason(format!(
+                "Too many outstanding nonces ({}); redeem them or let them expire",
+                self.outstanding.len()
+            )));
+        }
+        let mut nonce = vec![0u8; self.nonce_bytes];
+        OsRng.fill_bytes(&mut nonce);
+        self.outstanding.insert(nonce.clone(), now_ms + self.ttl_ms);
+        Ok(nonce)
+    }
+
+    /// Check `nonce` and retire it, so it redeems at most once.
+    fn redeem(&mut self, nonce: Option<&[u8]>, now_ms: i64) -> std::result::Result<(), String> {
+        let nonce = nonce.ok_or("Document has no nonce")?;
+        match self.outstanding.remove(nonce) {
+            None => Err("Nonce was not issued here or was already redeemed".to_string()),
+            Some(expires) if expires <= now_ms => {
+                Err(format!("Nonce expired {}ms ago", now_ms - expires))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Issues random nonces and redeems each at most once before it expires.
+#[napi]
+pub struct NonceRegistry {
+    inner: Mutex<Registry>,
+}
+
+#[napi]
+impl NonceRegistry {
+    #[napi(constructor)]
+    pub fn new(options: Option<NonceRegistryOptions>) -> Result<Self> {
+        let (ttl_ms, nonce_bytes, max_outstanding) = match options {
+            Some(o) => (o.ttl_ms, o.nonce_bytes, o.max_outstanding),
+            None => (None, None, None),
+        };
+        let ttl_ms = match ttl_ms {
+            Some(ttl) if ttl.is_nan() || ttl <= 0.0 => {
+                return Err(Error::from_reason("ttlMs must be a positive number"))
+            }
+            ttl => ttl.map_or(DEFAULT_TTL_MS, |t| t as i64),
+        };
+        let nonce_bytes = nonce_bytes.unwrap_or(DEFAULT_NONCE_BYTES);
+        if !(1..=MAX_NONCE_BYTES).contains(&nonce_bytes) {
+            return Err(Error::from_reason(format!(
+                "nonceBytes must be between 1 and {}",
+                MAX_NONCE_BYTES
+            )));
+        }
+        Ok(NonceRegistry {
+            inner: Mutex::new(Registry {
+                ttl_ms,
+                nonce_bytes: nonce_bytes as usize,
+                max_outstanding: max_outstanding.unwrap_or(DEFAULT_MAX_OUTSTANDING) as usize,
+                outstanding: HashMap::new(),
+            }),
+        })
+    }
+
+    /// A new random nonce, redeemable once within the TTL.
+    #[napi]
+    pub fn issue(&self) -> Result<Buffer> {
+        let now_ms = attestation::now_ms();
+        self.inner.lock().unwrap().issue(now_ms).map(Buffer::from)
+    }
+
+    /// Redeem the nonce of `document` (COSE_Sign1 bytes) at `timeMs`
+    /// (default now). Only a document that can't be decoded throws.
+    #[napi]
+    pub fn redeem(&self, document: Buffer, time_ms: Option<f64>) -> Result<NonceRedemption> {
+        let sign1 = CoseSign1::parse(&document)?;
+        let document = Document::parse(&sign1.payload)?;
+        Ok(self.redeem_at(document.nonce.as_deref(), time_ms))
+    }
+
+    /// Redeem a raw nonce, e.g. one taken from an already-parsed document.
+    #[napi]
+    pub fn redeem_nonce(&self, nonce: Buffer, time_ms: Option<f64>) -> NonceRedemption {
+        self.redeem_at(Some(&nonce), time_ms)
+    }
+
+    /// Issued nonces not yet redeemed, including expired ones not yet
+    /// pruned by issue().
+    #[napi(getter)]
+    pub fn outstanding(&self) -> u32 {
+        self.inner.lock().unwrap().outstanding.len() as u32
+    }
+
+    fn redeem_at(&self, nonce: Option<&[u8]>, time_ms: Option<f64>) -> NonceRedemption {
+        let now_ms = time_ms
+            .map(|t| t as i64)
+            .unwrap_or_else(attestation::now_ms);
+        match self.inner.lock().unwrap().redeem(nonce, now_ms) {
+            Ok(()) => NonceRedemption {
+                valid: true,
+                reason: None,
+            },
+            Err(reason) => NonceRedemption {
+                valid: false,
+                reason: Some(reason),
+            },
+        }
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(max_outstanding: usize) -> Registry {
+        Registry {
+            ttl_ms: 1000,
+            nonce_bytes: 16,
+            max_outstanding,
+            outstanding: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn nonces_redeem_exactly_once() {
+        let mut registry = registry(10);
+        let nonce = registry.issue(0).unwrap();
+        assert_eq!(nonce.len(), 16);
+        assert_eq!(registry.redeem(Some(&nonce), 999), Ok(()));
+        assert_eq!(
+            registry.redeem(Some(&nonce), 999),
+            Err("Nonce was not issued here or was already redeemed".to_string())
+        );
+        assert_eq!(
+            registry.redeem(None, 0),
+            Err("Document has no nonce".to_string())
+        );
+    }
+
+    #[test]
+    fn expired_nonces_are_rejected_and_pruned() {
+        let mut registry = registry(1);
+        let nonce = registry.issue(0).unwrap();
+        assert_eq!(
+            registry.redeem(Some(&nonce), 1500),
+            Err("Nonce expired 500ms ago".to_string())
+        );
+
+        registry.issue(0).unwrap();
+        let err = registry.issue(500).err().unwrap();
+        assert!(err.reason.starts_with("Too many outstanding nonces"));
+        // The first has expired by 1000 and makes room.
+        assert!(registry.issue(1000).is_ok());
+    }
+}
@@ -1,14 +1,90 @@
+use ciborium::value::Value;
 use napi::bindgen_prelude::*;
 use napi_derive::napi;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
-/// NSM (Nitro Security Module) ioctl command.
-/// Computed as _IOWR(0x0A, 0, sizeof(NsmMessage)) on x86_64:
+use crate::attestation_cache::{self, Source};
+use crate::nsm_mock::{self, MockConfig, MockNsm, MockNsmOptions};
+use crate::{cbor, errors, nsm_debug, platform};
+
+/// NSM (Nitro Security Module) ioctl command: _IOWR(0x0A, 0,
+/// sizeof(NsmMessage)), as the driver and
+/// aws-nitro-enclaves-nsm-api/src/driver/mod.rs define it
+/// (NSM_IOCTL_MAGIC = 0x0A). On 64-bit targets this is 0xC020_0A00:
 ///   direction = 3 (read/write) << 30  = 0xC000_0000
 ///   size      = 32             << 16  = 0x0020_0000  (2 × iovec = 32 bytes)
 ///   type      = 0x0A           << 8   = 0x0000_0A00
 ///   nr        = 0              << 0   = 0x0000_0000
-/// Reference: aws-nitro-enclaves-nsm-api/src/driver/mod.rs (NSM_IOCTL_MAGIC = 0x0A)
-const NSM_IOCTL_CMD: i32 = 0xC020_0A00u32 as i32;
+const NSM_IOCTL_CMD: u32 = iowr(NSM_IOCTL_MAGIC, 0, std::mem::size_of::<NsmMessage>());
+const NSM_IOCTL_MAGIC: u32 = 0x0A;
+
+/// Linux's generic ioctl encoding (include/uapi/asm-generic/ioctl.h),
+/// which x86_64 and aarch64 both use.
+const IOC_NRBITS: u32 = 8;
+const IOC_TYPEBITS: u32 = 8;
+const IOC_SIZEBITS: u32 = 14;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// _IOWR(ty, nr, size): an ioctl whose argument the kernel reads and
+/// writes back.
+const fn iowr(ty: u32, nr: u32, size: usize) -> u32 {
+    assert!(size < 1 << IOC_SIZEBITS, "ioctl argument too large");
+    let size_shift = IOC_NRBITS + IOC_TYPEBITS;
+    ((IOC_READ | IOC_WRITE) << (size_shift + IOC_SIZEBITS))
+        | ((size as u32) << size_shift)
+        | (ty << IOC_NRBITS)
+        | nr
+}
+
+/// Default device node and response buffer size.
+const DEFAULT_DEVICE_PATH: &str = "/dev/nsm";
+const DEFAULT_MAX_RESPONSE_SIZE: u32 = 16 * 1024;
+/// Largest response buffer NsmOptions.maxResponseSize may request.
+const MAX_RESPONSE_SIZE_LIMIT: u32 = 16 * 1024 * 1024;
+
+/// First PCR available to applications; 0–15 are reserved for boot measurements.
+pub(crate) const FIRST_RUNTIME_PCR: u16 = 16;
+
+/// Largest Attestation request fields the NSM accepts: request key, the
+/// AttestationOptions name to report, and the limit in bytes.
+pub(crate) const ATTESTATION_FIELD_LIMITS: [(&str, &str, usize); 3] = [
+    ("user_data", "userData", 1024),
+    ("nonce", "nonce", 512),
+    ("public_key", "publicKey", 1024),
+];
+
+/// Results of exports built on the NSM. Thrown errors carry a stable
+/// `.code`: the NSM's error code (e.g. "InvalidIndex") when it rejected a
+/// request, "ResponseTooLarge" or "UnsupportedPlatform", an errno name for
+/// a failed system call, else "GenericFailure". The NSM exports themselves
+/// add `.syscall` and `.errno` (see errors.rs).
+pub(crate) mod coded {
+    pub type Result<T> = std::result::Result<T, napi::Error<String>>;
+}
+
+/// Error for an NSM Error response; coded() lifts `code` into `.code`.
+fn nsm_error(code: &str, operation: &str) -> Error {
+    Error::from_reason(format!("{}: NSM {} failed", code, operation))
+}
+
+/// Use a "Code: message" reason prefix as the error's `.code`.
+pub(crate) fn coded(err: Error) -> Error<String> {
+    let code = errors::reason_code(&err.reason).unwrap_or(err.status.as_ref()).to_string();
+    Error::new(code, err.reason)
+}
+
+/// Open a device for one call.
+pub(crate) fn with_device<T>(
+    options: Option<NsmOptions>,
+    f: impl FnOnce(&Device) -> Result<T>,
+) -> coded::Result<T> {
+    DeviceConfig::from_js(options)
+        .and_then(Device::open_with)
+        .and_then(|device| f(&device))
+        .map_err(coded)
+}
 
 /// NSM message structure for ioctl.
 /// Contains request and response iovec pointers.
@@ -18,28 +94,693 @@ struct NsmMessage {
     response: libc::iovec,
 }
 
+#[napi(object)]
+pub struct NsmOptions {
+    /// NSM device node (default "/dev/nsm"), for containers that remap it.
+    pub device_path: Option<String>,
+    /// Response buffer size in bytes (default 16 KiB, at most 16 MiB).
+    /// Raise it for responses carrying large user_data or cabundles;
+    /// responses that don't fit throw a ResponseTooLarge error.
+    pub max_response_size: Option<u32>,
+    /// Use an in-process mock NSM instead of the device (see nsm_mock);
+    /// builds without the nsm-mock feature throw MockUnavailable.
+    pub mock: Option<MockNsmOptions>,
+}
+
+/// napi-free form of NsmOptions, with defaults applied.
+#[derive(Debug, PartialEq)]
+pub(crate) struct DeviceConfig {
+    /// Explicit device node; None means /dev/nsm, or TYTLE_NSM_MOCK's mock.
+    path: Option<String>,
+    max_response_size: usize,
+    pub(crate) mock: Option<MockConfig>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            path: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE as usize,
+            mock: None,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub(crate) fn from_js(options: Option<NsmOptions>) -> Result<Self> {
+        let Some(options) = options else {
+            return Ok(DeviceConfig::default());
+        };
+        Ok(DeviceConfig {
+            mock: options.mock.map(MockConfig::from_js).transpose()?,
+            ..DeviceConfig::new(options.device_path, options.max_response_size)?
+        })
+    }
+
+    pub(crate) fn new(device_path: Option<String>, max_response_size: Option<u32>) -> Result<Self> {
+        let max_response_size = max_response_size.unwrap_or(DEFAULT_MAX_RESPONSE_SIZE);
+        if max_response_size == 0 || max_response_size > MAX_RESPONSE_SIZE_LIMIT {
+            return Err(Error::from_reason(format!(
+                "maxResponseSize must be between 1 and {}",
+                MAX_RESPONSE_SIZE_LIMIT
+            )));
+        }
+        Ok(DeviceConfig {
+            path: device_path,
+            max_response_size: max_response_size as usize,
+            mock: None,
+        })
+    }
+
+    fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or(DEFAULT_DEVICE_PATH)
+    }
+
+    /// The NSM this config selects, as the attestation cache keys it.
+    fn source(&self) -> Source {
+        match &self.mock {
+            Some(mock) => Source::Mock(mock.clone()),
+            None => Source::Device(self.path().to_string()),
+        }
+    }
+}
+
 /// Send a raw CBOR-encoded NSM request and return the raw CBOR response.
 ///
 /// The request should be CBOR-encoded (e.g., `{"Attestation": {"nonce": <bytes>, ...}}`).
 /// Returns the raw CBOR response bytes from the NSM.
 ///
+/// An NSM Error response is thrown with the NSM's code as `.code`.
+///
 /// Only works inside a Nitro Enclave where /dev/nsm exists.
-/// Outside an enclave, returns an error (use for graceful detection).
+/// Outside an enclave, returns an error (use for graceful detection);
+/// on non-Linux platforms the error is UnsupportedPlatform.
 #[napi]
-pub fn nsm_request(request: Buffer) -> Result<Buffer> {
-    unsafe {
-        // Open /dev/nsm
-        let path = std::ffi::CString::new("/dev/nsm").unwrap();
-        let fd = libc::open(path.as_ptr(), libc::O_RDWR);
+pub fn nsm_request(env: Env, request: Buffer, options: Option<NsmOptions>) -> Result<Buffer> {
+    let result = with_device(options, |device| device.checked_request(&request));
+    errors::structured(&env, result.map(Buffer::from))
+}
+
+#[napi(object)]
+pub struct EnclaveStatus {
+    /// Whether the NSM device can be opened.
+    pub is_enclave: bool,
+    /// Why, e.g. "/dev/nsm does not exist".
+    pub reason: String,
+    /// This VM's vsock CID, when vsock is available. Enclaves get a CID of
+    /// 4 or more; the parent instance is 3.
+    pub local_cid: Option<u32>,
+}
+
+/// Cheaply detect whether this process runs inside a Nitro Enclave by
+/// opening the NSM device (`devicePath` from `options`, default /dev/nsm).
+/// Never throws, so applications can branch on it at startup.
+#[napi]
+pub fn is_enclave(options: Option<NsmOptions>) -> EnclaveStatus {
+    let (is_enclave, reason) = match options.and_then(|o| o.device_path) {
+        Some(path) => detect_nsm(&path),
+        None => match nsm_mock::shared() {
+            Ok(Some(_)) => (true, "mock NSM enabled by TYTLE_NSM_MOCK".to_string()),
+            _ => detect_nsm(DEFAULT_DEVICE_PATH),
+        },
+    };
+    EnclaveStatus {
+        is_enclave,
+        reason,
+        local_cid: crate::vsock::local_cid(),
+    }
+}
+
+fn detect_nsm(path: &str) -> (bool, String) {
+    if platform::require_linux("/dev/nsm").is_err() {
+        return (false, format!("not Linux (this is {})", std::env::consts::OS));
+    }
+    match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => (true, format!("{} is available", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            (false, format!("{} does not exist", path))
+        }
+        Err(e) => (false, format!("{} could not be opened: {}", path, e)),
+    }
+}
+
+#[napi(object)]
+pub struct AttestationOptions {
+    /// Caller-chosen nonce, echoed in the document (for freshness checks).
+    pub nonce: Option<Buffer>,
+    /// Application data bound into the document.
+    pub user_data: Option<Buffer>,
+    /// Public key bound into the document, e.g. for the verifier to
+    /// encrypt a response to the enclave.
+    pub public_key: Option<Buffer>,
+    /// Reuse a document for the same userData and publicKey until it is
+    /// this many ms old. Ignored when `nonce` is set.
+    pub cache_ttl_ms: Option<f64>,
+}
+
+/// Request an attestation document from the NSM.
+///
+/// Builds the `{"Attestation": {...}}` request and unwraps the response
+/// natively, returning the COSE_Sign1 document bytes. NSM errors are
+/// thrown with the NSM's code as `.code`. Fields over the NSM's limits
+/// (nonce 512 bytes, userData and publicKey 1024) throw "InputTooLarge"
+/// naming the field before the device is opened. A cached document (see
+/// `cacheTtlMs`) is returned without opening the device.
+#[napi]
+pub fn attestation(
+    env: Env,
+    options: Option<AttestationOptions>,
+    nsm_options: Option<NsmOptions>,
+) -> Result<Buffer> {
+    let result = DeviceConfig::from_js(nsm_options).and_then(|config| {
+        attestation_with(options, config.source(), |user_data, nonce, public_key| {
+            Device::open_with(config)?.attestation(user_data, nonce, public_key)
+        })
+    });
+    errors::structured(&env, result)
+}
+
+/// Fetch the document `options` describe with `fetch(user_data, nonce,
+/// public_key)`, going through the attestation cache, under `source`, when
+/// enabled.
+fn attestation_with(
+    options: Option<AttestationOptions>,
+    source: Source,
+    fetch: impl FnOnce(Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) -> Result<Vec<u8>>,
+) -> Result<Buffer> {
+    let Some(options) = options else {
+        return fetch(None, None, None).map(Buffer::from);
+    };
+    let user_data = options.user_data.as_deref();
+    let nonce = options.nonce.as_deref();
+    let public_key = options.public_key.as_deref();
+    check_attestation_fields(user_data, nonce, public_key)?;
+    match options.cache_ttl_ms {
+        Some(ttl) if ttl.is_nan() || ttl < 0.0 => {
+            Err(Error::from_reason("cacheTtlMs must be a non-negative number"))
+        }
+        Some(ttl) if nonce.is_none() => {
+            let ttl = Duration::from_millis(ttl as u64);
+            attestation_cache::cached(source, user_data, public_key, ttl, || {
+                fetch(user_data, None, public_key)
+            })
+        }
+        _ => fetch(user_data, nonce, public_key),
+    }
+    .map(Buffer::from)
+}
+
+/// A decoded NSM response. Fields are set according to `kind`.
+#[napi(object)]
+pub struct NsmResponse {
+    /// "Attestation", "DescribePCR", "ExtendPCR", "LockPCR", "LockPCRs",
+    /// "DescribeNSM", "GetRandom" or "Error".
+    pub kind: String,
+    /// Attestation: the COSE_Sign1 attestation document.
+    pub document: Option<Buffer>,
+    /// DescribePCR: whether the PCR is locked.
+    pub lock: Option<bool>,
+    /// DescribePCR, ExtendPCR: the PCR value.
+    pub data: Option<Buffer>,
+    /// DescribeNSM
+    pub description: Option<NsmDescription>,
+    /// GetRandom: the entropy bytes.
+    pub random: Option<Buffer>,
+    /// Error: the NSM error code, e.g. "InvalidIndex".
+    pub error: Option<String>,
+}
+
+/// Send a raw CBOR-encoded NSM request, like nsmRequest(), and decode the
+/// response. An NSM Error response is returned (kind "Error"), not thrown.
+#[napi]
+pub fn nsm_request_parsed(
+    env: Env,
+    request: Buffer,
+    options: Option<NsmOptions>,
+) -> Result<NsmResponse> {
+    errors::structured(&env, with_device(options, |device| device.request_parsed(&request)))
+}
+
+/// Send several raw CBOR-encoded NSM requests over one opened device, in
+/// order, and decode each response as nsmRequestParsed() does, e.g. a
+/// DescribeNSM and the boot PCRs at startup. An NSM Error response is
+/// returned for its request; any other failure throws, naming the request.
+#[napi]
+pub fn nsm_request_batch(
+    env: Env,
+    requests: Vec<Buffer>,
+    options: Option<NsmOptions>,
+) -> Result<Vec<NsmResponse>> {
+    let result = with_device(options, |device| device.request_batch(&requests))
+        .map(|responses| responses.into_iter().map(ParsedResponse::into_js).collect());
+    errors::structured(&env, result)
+}
+
+/// napi-free form of NsmResponse.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ParsedResponse {
+    Attestation { document: Vec<u8> },
+    DescribePcr { lock: bool, data: Vec<u8> },
+    ExtendPcr { data: Vec<u8> },
+    LockPcr,
+    LockPcrs,
+    DescribeNsm(NsmDescription),
+    GetRandom { random: Vec<u8> },
+    Error { code: String },
+}
+
+impl ParsedResponse {
+    fn into_js(self) -> NsmResponse {
+        let mut js = NsmResponse {
+            kind: String::new(),
+            document: None,
+            lock: None,
+            data: None,
+            description: None,
+            random: None,
+            error: None,
+        };
+        js.kind = match self {
+            ParsedResponse::Attestation { document } => {
+                js.document = Some(document.into());
+                "Attestation"
+            }
+            ParsedResponse::DescribePcr { lock, data } => {
+                js.lock = Some(lock);
+                js.data = Some(data.into());
+                "DescribePCR"
+            }
+            ParsedResponse::ExtendPcr { data } => {
+                js.data = Some(data.into());
+                "ExtendPCR"
+            }
+            ParsedResponse::LockPcr => "LockPCR",
+            ParsedResponse::LockPcrs => "LockPCRs",
+            ParsedResponse::DescribeNsm(description) => {
+                js.description = Some(description);
+                "DescribeNSM"
+            }
+            ParsedResponse::GetRandom { random } => {
+                js.random = Some(random.into());
+                "GetRandom"
+            }
+            ParsedResponse::Error { code } => {
+                js.error = Some(code);
+                "Error"
+            }
+        }
+        .to_string();
+        js
+    }
+}
+
+/// Decode any NSM response envelope (see `Device::call` for the encoding).
+pub(crate) fn parse_response(response: &Value) -> Result<ParsedResponse> {
+    let unexpected = || Error::from_reason("NSM returned an unexpected response");
+    let bytes_field = |body: &Value, kind: &str, key: &str| -> Result<Vec<u8>> {
+        cbor::map_get(body, key)
+            .and_then(cbor::as_bytes)
+            .ok_or_else(|| Error::from_reason(format!("NSM {} response missing {}", kind, key)))
+    };
+
+    let (kind, body) = match response {
+        Value::Text(t) => {
+            return match t.as_str() {
+                "LockPCR" => Ok(ParsedResponse::LockPcr),
+                "LockPCRs" => Ok(ParsedResponse::LockPcrs),
+                _ => Err(unexpected()),
+            }
+        }
+        Value::Map(entries) if entries.len() == 1 => {
+            let (key, body) = &entries[0];
+            (cbor::as_text(key).ok_or_else(unexpected)?, body)
+        }
+        _ => return Err(unexpected()),
+    };
+
+    Ok(match kind {
+        "Attestation" => ParsedResponse::Attestation {
+            document: bytes_field(body, kind, "document")?,
+        },
+        "DescribePCR" => ParsedResponse::DescribePcr {
+            lock: matches!(cbor::map_get(body, "lock"), Some(Value::Bool(true))),
+            data: bytes_field(body, kind, "data")?,
+        },
+        "ExtendPCR" => ParsedResponse::ExtendPcr { data: bytes_field(body, kind, "data")? },
+        "DescribeNSM" => ParsedResponse::DescribeNsm(parse_description(body)?),
+        "GetRandom" => ParsedResponse::GetRandom { random: bytes_field(body, kind, "random")? },
+        "Error" => ParsedResponse::Error {
+            code: cbor::as_text(body).unwrap_or("unknown error").to_string(),
+        },
+        _ => return Err(unexpected()),
+    })
+}
+
+/// Process-wide turn-taking for NSM requests.
+///
+/// Every call opens its own Device, and the async exports run on worker
+/// threads, so requests can arrive from anywhere at once. They take a
+/// ticket here and go to the NSM one at a time, strictly in arrival order,
+/// so a burst of attestation requests can't starve a caller or interleave
+/// ioctls, whichever fd they use.
+struct Queue {
+    state: Mutex<QueueState>,
+    turn: Condvar,
+}
+
+#[derive(Clone, Copy, Default)]
+struct QueueState {
+    /// Next ticket to hand out.
+    next: u64,
+    /// Ticket whose request may run.
+    serving: u64,
+    /// Most requests ever seen waiting at once.
+    max_depth: u64,
+    /// Requests that had to wait for another.
+    waited: u64,
+}
+
+/// Held while a request runs; the next ticket goes on drop.
+struct Turn<'a>(&'a Queue);
+
+static QUEUE: Queue = Queue::new();
+
+impl Queue {
+    const fn new() -> Self {
+        Queue {
+            state: Mutex::new(QueueState { next: 0, serving: 0, max_depth: 0, waited: 0 }),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Wait for every earlier request to finish.
+    fn enter(&self) -> Turn<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next;
+        state.next += 1;
+        let ahead = ticket - state.serving;
+        if ahead > 0 {
+            state.waited += 1;
+            state.max_depth = state.max_depth.max(ahead);
+        }
+        while state.serving != ticket {
+            state = self.turn.wait(state).unwrap();
+        }
+        Turn(self)
+    }
+
+    fn stats(&self) -> NsmQueueStats {
+        let state = *self.state.lock().unwrap();
+        NsmQueueStats {
+            depth: (state.next - state.serving).saturating_sub(1) as u32,
+            max_depth: state.max_depth as u32,
+            waited: state.waited as f64,
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().serving += 1;
+        self.0.turn.notify_all();
+    }
+}
+
+#[napi(object)]
+pub struct NsmQueueStats {
+    /// Requests waiting for the NSM right now.
+    pub depth: u32,
+    /// Most requests seen waiting at once.
+    pub max_depth: u32,
+    /// Requests that had to wait for another to finish.
+    pub waited: f64,
+}
+
+/// How NSM requests are queueing. Requests from every caller, sync or
+/// async, are served one at a time in arrival order.
+#[napi]
+pub fn nsm_queue_stats() -> NsmQueueStats {
+    QUEUE.stats()
+}
+
+/// An open NSM device: the fd, or a mock.
+///
+/// Requests go through the process-wide Queue, then the backend's lock,
+/// which also keeps close() from racing an in-flight ioctl.
+pub(crate) struct Device {
+    backend: Mutex<Backend>,
+    max_response_size: usize,
+    source: Source,
+}
+
+enum Backend {
+    Fd(i32),
+    Mock(Arc<Mutex<MockNsm>>),
+    Closed,
+}
+
+impl Device {
+    /// Open /dev/nsm with the default response buffer.
+    pub(crate) fn open() -> Result<Self> {
+        Self::open_with(DeviceConfig::default())
+    }
+
+    /// Open the device `config` selects. A mock from the config replaces
+    /// the device; so does TYTLE_NSM_MOCK's, unless devicePath is set.
+    pub(crate) fn open_with(config: DeviceConfig) -> Result<Self> {
+        let source = config.source();
+        let path = config.path().to_string();
+        let mock = match config.mock {
+            Some(mock) => Some(Arc::new(Mutex::new(MockNsm::new(mock)?))),
+            None if config.path.is_none() => nsm_mock::shared()?,
+            None => None,
+        };
+        if let Some(mock) = mock {
+            return Ok(Device {
+                backend: Mutex::new(Backend::Mock(mock)),
+                max_response_size: config.max_response_size,
+                source,
+            });
+        }
+
+        platform::require_linux("/dev/nsm")?;
+        let c_path = std::ffi::CString::new(path.as_str())
+            .map_err(|_| Error::from_reason("devicePath must not contain NUL bytes"))?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
         if fd < 0 {
+            let err = std::io::Error::last_os_error();
             return Err(Error::from_reason(format!(
-                "/dev/nsm open failed (not in enclave?): {}",
-                std::io::Error::last_os_error()
+                "{}: open({}) failed (not in enclave?): {}",
+                errors::errno_code(&err).unwrap_or(errors::UNKNOWN_ERRNO),
+                path,
+                err
             )));
         }
+        Ok(Device {
+            backend: Mutex::new(Backend::Fd(fd)),
+            max_response_size: config.max_response_size,
+            source,
+        })
+    }
+
+    /// Issue one request and return the raw response.
+    pub(crate) fn request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let _turn = QUEUE.enter();
+        nsm_debug::traced(request, || match &*self.backend.lock().unwrap() {
+            Backend::Fd(fd) => nsm_ioctl(*fd, request, self.max_response_size),
+            Backend::Mock(mock) => {
+                let response = mock.lock().unwrap().handle(request)?;
+                let fits = response.len().min(self.max_response_size);
+                check_response_len(&response[..fits], response.len())?;
+                Ok(response)
+            }
+            Backend::Closed => Err(Error::from_reason("NSM device is closed")),
+        })
+    }
+
+    /// Close the device. Later requests fail; closing twice is a no-op.
+    pub(crate) fn close(&self) {
+        let mut backend = self.backend.lock().unwrap();
+        if let Backend::Fd(fd) = *backend {
+            unsafe { libc::close(fd); }
+        }
+        *backend = Backend::Closed;
+    }
+
+    /// request(), failing on an NSM Error response.
+    pub(crate) fn checked_request(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = self.request(request)?;
+        let parsed = cbor::decode(&response).and_then(|value| parse_response(&value));
+        if let Ok(ParsedResponse::Error { code }) = parsed {
+            let operation = match cbor::decode(request) {
+                Ok(Value::Text(t)) => t,
+                Ok(Value::Map(entries)) if entries.len() == 1 => {
+                    cbor::as_text(&entries[0].0).unwrap_or("request").to_string()
+                }
+                _ => "request".to_string(),
+            };
+            return Err(nsm_error(&code, &operation));
+        }
+        Ok(response)
+    }
+
+    pub(crate) fn request_parsed(&self, request: &[u8]) -> Result<NsmResponse> {
+        let response = cbor::decode(&self.request(request)?)?;
+        Ok(parse_response(&response)?.into_js())
+    }
+
+    pub(crate) fn request_batch(
+        &self,
+        requests: &[impl AsRef<[u8]>],
+    ) -> Result<Vec<ParsedResponse>> {
+        requests
+            .iter()
+            .enumerate()
+            .map(|(index, request)| {
+                self.request(request.as_ref())
+                    .and_then(|response| cbor::decode(&response))
+                    .and_then(|response| parse_response(&response))
+                    .map_err(|e| {
+                        Error::from_reason(format!("{} (batch request {})", e.reason, index))
+                    })
+            })
+            .collect()
+    }
+
+    /// Issue a structured NSM request and return the decoded response body.
+    ///
+    /// Requests and responses follow the serde encoding used by
+    /// aws-nitro-enclaves-nsm-api: unit operations are bare strings
+    /// (`"DescribeNSM"`), others are single-entry maps (`{"DescribePCR": {...}}`).
+    /// The response envelope for `operation` is unwrapped; unit responses
+    /// (e.g. `"LockPCR"`) yield `Value::Null`.
+    pub(crate) fn call(&self, request: &Value, operation: &str) -> Result<Value> {
+        let response = cbor::decode(&self.request(&cbor::encode(request)?)?)?;
+        match &response {
+            Value::Text(t) if t == operation => Ok(Value::Null),
+            Value::Map(_) => {
+                if let Some(body) = cbor::map_get(&response, operation) {
+                    return Ok(body.clone());
+                }
+                if let Some(code) = cbor::map_get(&response, "Error") {
+                    let code = cbor::as_text(code).unwrap_or("UnknownError");
+                    return Err(nsm_error(code, operation));
+                }
+                Err(Error::from_reason(format!(
+                    "NSM {} returned an unexpected response",
+                    operation
+                )))
+            }
+            _ => Err(Error::from_reason(format!(
+                "NSM {} returned an unexpected response",
+                operation
+            ))),
+        }
+    }
+
+    pub(crate) fn describe(&self) -> Result<NsmDescription> {
+        parse_description(&self.call(&cbor::text("DescribeNSM"), "DescribeNSM")?)
+    }
+
+    /// DescribePCR: returns (locked, current value).
+    pub(crate) fn read_pcr(&self, index: u16) -> Result<(bool, Vec<u8>)> {
+        let request = cbor::map(vec![(
+            "DescribePCR",
+            cbor::map(vec![("index", Value::Integer(index.into()))]),
+        )]);
+        let body = self.call(&request, "DescribePCR")?;
+        let lock = matches!(cbor::map_get(&body, "lock"), Some(Value::Bool(true)));
+        let data = cbor::map_get(&body, "data")
+            .and_then(cbor::as_bytes)
+            .ok_or_else(|| Error::from_reason("NSM DescribePCR response missing data"))?;
+        Ok((lock, data))
+    }
+
+    pub(crate) fn extend_pcr(&self, index: u32, data: &[u8]) -> Result<Vec<u8>> {
+        let index = runtime_pcr_index(index)?;
+        let request = cbor::map(vec![(
+            "ExtendPCR",
+            cbor::map(vec![
+                ("index", Value::Integer(index.into())),
+                ("data", Value::Bytes(data.to_vec())),
+            ]),
+        )]);
+        let body = self.call(&request, "ExtendPCR")?;
+        attestation_cache::clear_attestation_cache();
+        cbor::map_get(&body, "data")
+            .and_then(cbor::as_bytes)
+            .ok_or_else(|| Error::from_reason("NSM ExtendPCR response missing data"))
+    }
 
+    pub(crate) fn lock_pcr(&self, index: u32) -> Result<()> {
+        let index = runtime_pcr_index(index)?;
+        if self.read_pcr(index)?.0 {
+            return Err(Error::from_reason(format!("PCR {} is already locked", index)));
+        }
+        let request = cbor::map(vec![(
+            "LockPCR",
+            cbor::map(vec![("index", Value::Integer(index.into()))]),
+        )]);
+        self.call(&request, "LockPCR").map(|_| ())
+    }
+
+    pub(crate) fn lock_pcrs(&self, range: u32) -> Result<()> {
+        let range = pcr_range(range, self.describe()?.max_pcrs)?;
+        let request = cbor::map(vec![(
+            "LockPCRs",
+            cbor::map(vec![("range", Value::Integer(range.into()))]),
+        )]);
+        self.call(&request, "LockPCRs").map(|_| ())
+    }
+
+    /// `count` bytes of entropy, or a single GetRandom chunk without one.
+    pub(crate) fn random(&self, count: Option<u32>) -> Result<Vec<u8>> {
+        match count {
+            None => self.random_chunk(),
+            Some(count) if count > MAX_RANDOM_BYTES => Err(Error::from_reason(format!(
+                "count must be at most {}",
+                MAX_RANDOM_BYTES
+            ))),
+            Some(count) => collect_random(count as usize, || self.random_chunk()),
+        }
+    }
+
+    fn random_chunk(&self) -> Result<Vec<u8>> {
+        let body = self.call(&cbor::text("GetRandom"), "GetRandom")?;
+        cbor::map_get(&body, "random")
+            .and_then(cbor::as_bytes)
+            .ok_or_else(|| Error::from_reason("NSM GetRandom response missing random"))
+    }
+
+    /// Attestation: returns the COSE_Sign1 attestation document bytes.
+    pub(crate) fn attestation(
+        &self,
+        user_data: Option<&[u8]>,
+        nonce: Option<&[u8]>,
+        public_key: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        // The NSM's own error doesn't say which field is too large
+        check_attestation_fields(user_data, nonce, public_key)?;
+        let request = cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![
+                ("user_data", cbor::opt_bytes(user_data)),
+                ("nonce", cbor::opt_bytes(nonce)),
+                ("public_key", cbor::opt_bytes(public_key)),
+            ]),
+        )]);
+        let body = self.call(&request, "Attestation")?;
+        cbor::map_get(&body, "document")
+            .and_then(cbor::as_bytes)
+            .ok_or_else(|| Error::from_reason("NSM response missing Attestation.document"))
+    }
+}
+
+/// Issue one request/response ioctl on `fd`.
+fn nsm_ioctl(fd: i32, request: &[u8], max_response_size: usize) -> Result<Vec<u8>> {
+    unsafe {
         // Allocate response buffer (NSM responses are typically < 16KB)
-        let mut response_buf = vec![0u8; 16384];
+        let mut response_buf = vec![0u8; max_response_size];
 
         let mut msg = NsmMessage {
             request: libc::iovec {
@@ -54,17 +795,643 @@ pub fn nsm_request(request: Buffer) -> Result<Buffer> {
 
         // ioctl call to NSM
         let ret = libc::ioctl(fd, NSM_IOCTL_CMD as _, &mut msg as *mut NsmMessage);
-        libc::close(fd);
 
         if ret < 0 {
-            return Err(Error::from_reason(format!(
-                "NSM ioctl failed: {}",
-                std::io::Error::last_os_error()
-            )));
+            return Err(errors::os_error("ioctl(NSM)", std::io::Error::last_os_error()));
         }
 
+        check_response_len(&response_buf, msg.response.iov_len)?;
         // Truncate response buffer to actual response length
         response_buf.truncate(msg.response.iov_len);
-        Ok(Buffer::from(response_buf))
+        Ok(response_buf)
+    }
+}
+
+/// Detect a response that did not fit in `buffer`.
+///
+/// Drivers either report the full response length (larger than the
+/// buffer) or fill the buffer exactly and cut the CBOR short. Either way
+/// the bytes are unusable, so fail rather than hand back invalid CBOR.
+/// Requests are not retried: ExtendPCR and LockPCR must not run twice.
+fn check_response_len(buffer: &[u8], response_len: usize) -> Result<()> {
+    let truncated = response_len > buffer.len()
+        || (response_len == buffer.len() && cbor::decode(buffer).is_err());
+    if !truncated {
+        return Ok(());
+    }
+    let size = if response_len > buffer.len() {
+        format!(" ({} bytes)", response_len)
+    } else {
+        String::new()
+    };
+    Err(Error::from_reason(format!(
+        "ResponseTooLarge: NSM response{} exceeds the {}-byte buffer; raise maxResponseSize",
+        size,
+        buffer.len()
+    )))
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A persistent /dev/nsm handle.
+///
+/// The one-shot functions (attestation(), extendPcr(), ...) open and close
+/// the device on every call; an NsmDevice keeps it open, which suits
+/// services issuing many requests. Concurrent requests are serialized.
+/// The fd is released by close() or when the object is garbage collected.
+/// NsmOptions select a remapped device node or a larger response buffer.
+#[napi]
+pub struct NsmDevice {
+    inner: Device,
+}
+
+#[napi]
+impl NsmDevice {
+    /// Open the NSM device. Throws outside an enclave.
+    #[napi(constructor)]
+    pub fn new(env: Env, options: Option<NsmOptions>) -> Result<Self> {
+        let result = DeviceConfig::from_js(options).and_then(Device::open_with);
+        errors::structured(&env, result.map(|inner| NsmDevice { inner }))
+    }
+
+    /// As nsmRequest().
+    #[napi]
+    pub fn request(&self, env: Env, request: Buffer) -> Result<Buffer> {
+        errors::structured(&env, self.inner.checked_request(&request).map(Buffer::from))
+    }
+
+    /// As nsmRequestParsed().
+    #[napi]
+    pub fn request_parsed(&self, env: Env, request: Buffer) -> Result<NsmResponse> {
+        errors::structured(&env, self.inner.request_parsed(&request))
+    }
+
+    /// As nsmRequestBatch().
+    #[napi]
+    pub fn request_batch(&self, env: Env, requests: Vec<Buffer>) -> Result<Vec<NsmResponse>> {
+        let result = self
+            .inner
+            .request_batch(&requests)
+            .map(|responses| responses.into_iter().map(ParsedResponse::into_js).collect());
+        errors::structured(&env, result)
+    }
+
+    /// As attestation().
+    #[napi]
+    pub fn attestation(&self, env: Env, options: Option<AttestationOptions>) -> Result<Buffer> {
+        let source = self.inner.source.clone();
+        let result = attestation_with(options, source, |user_data, nonce, public_key| {
+            self.inner.attestation(user_data, nonce, public_key)
+        });
+        errors::structured(&env, result)
+    }
+
+    /// As nsmGetRandom().
+    #[napi]
+    pub fn get_random(&self, env: Env, count: Option<u32>) -> Result<Buffer> {
+        errors::structured(&env, self.inner.random(count).map(Buffer::from))
+    }
+
+    /// As describeNsm().
+    #[napi]
+    pub fn describe(&self, env: Env) -> Result<NsmDescription> {
+        errors::structured(&env, self.inner.describe())
+    }
+
+    /// As describePcr().
+    #[napi]
+    pub fn describe_pcr(&self, env: Env, index: u32) -> Result<PcrDescription> {
+        errors::structured(&env, describe_pcr_with(&self.inner, index))
+    }
+
+    /// As extendPcr().
+    #[napi]
+    pub fn extend_pcr(&self, env: Env, index: u32, data: Buffer) -> Result<Buffer> {
+        errors::structured(&env, self.inner.extend_pcr(index, &data).map(Buffer::from))
+    }
+
+    /// As lockPcr().
+    #[napi]
+    pub fn lock_pcr(&self, env: Env, index: u32) -> Result<()> {
+        errors::structured(&env, self.inner.lock_pcr(index))
+    }
+
+    /// As lockPcrs().
+    #[napi]
+    pub fn lock_pcrs(&self, env: Env, range: u32) -> Result<()> {
+        errors::structured(&env, self.inner.lock_pcrs(range))
+    }
+
+    /// Close the device. Later calls throw; closing twice is a no-op.
+    #[napi]
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+/// Decoded DescribeNSM response.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct NsmDescription {
+    pub version_major: u32,
+    pub version_minor: u32,
+    pub version_patch: u32,
+    pub module_id: String,
+    /// Number of PCRs the NSM provides.
+    pub max_pcrs: u32,
+    pub locked_pcrs: Vec<u32>,
+    /// Digest algorithm used for PCRs, e.g. "SHA384".
+    pub digest: String,
+}
+
+/// Describe the NSM: version, module id, PCR count, locked PCRs and
+/// digest. Throws outside an enclave, so a successful call at startup
+/// confirms a real NSM is present.
+#[napi]
+pub fn describe_nsm(env: Env) -> Result<NsmDescription> {
+    errors::structured(&env, with_device(None, Device::describe))
+}
+
+fn parse_description(body: &Value) -> Result<NsmDescription> {
+    let u32_field = |key: &str| -> Result<u32> {
+        cbor::map_get(body, key)
+            .and_then(cbor::as_u64)
+            .and_then(|n| u16::try_from(n).ok())
+            .map(u32::from)
+            .ok_or_else(|| Error::from_reason(format!("NSM DescribeNSM response missing {}", key)))
+    };
+    let text_field = |key: &str| -> Result<String> {
+        cbor::map_get(body, key)
+            .and_then(cbor::as_text)
+            .map(str::to_string)
+            .ok_or_else(|| Error::from_reason(format!("NSM DescribeNSM response missing {}", key)))
+    };
+
+    let locked_pcrs = match cbor::map_get(body, "locked_pcrs") {
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(|v| cbor::as_u64(v).and_then(|n| u16::try_from(n).ok()))
+            .map(u32::from)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(NsmDescription {
+        version_major: u32_field("version_major")?,
+        version_minor: u32_field("version_minor")?,
+        version_patch: u32_field("version_patch")?,
+        module_id: text_field("module_id")?,
+        max_pcrs: u32_field("max_pcrs")?,
+        locked_pcrs,
+        digest: text_field("digest")?,
+    })
+}
+
+#[napi(object)]
+pub struct PcrDescription {
+    /// Whether the PCR is locked against further extension.
+    pub lock: bool,
+    /// Current PCR value.
+    pub data: Buffer,
+}
+
+/// Read PCR `index` and whether it is locked.
+#[napi]
+pub fn describe_pcr(env: Env, index: u32) -> Result<PcrDescription> {
+    errors::structured(&env, with_device(None, |device| describe_pcr_with(device, index)))
+}
+
+fn describe_pcr_with(device: &Device, index: u32) -> Result<PcrDescription> {
+    let index = u16::try_from(index)
+        .map_err(|_| Error::from_reason(format!("Invalid PCR index {}", index)))?;
+    let (lock, data) = device.read_pcr(index)?;
+    Ok(PcrDescription { lock, data: data.into() })
+}
+
+/// Extend PCR `index` (16 or above) with `data` and return its new value:
+/// SHA-384(old value ‖ data). Later attestation documents report it.
+#[napi]
+pub fn extend_pcr(env: Env, index: u32, data: Buffer) -> Result<Buffer> {
+    let result = with_device(None, |device| device.extend_pcr(index, &data));
+    errors::structured(&env, result.map(Buffer::from))
+}
+
+/// Lock PCR `index` (16 or above) so it can no longer be extended.
+#[napi]
+pub fn lock_pcr(env: Env, index: u32) -> Result<()> {
+    errors::structured(&env, with_device(None, |device| device.lock_pcr(index)))
+}
+
+/// Lock PCRs 0 to `range - 1`, e.g. once initialization has extended
+/// every application PCR. Boot PCRs are already locked, so this freezes
+/// the application PCRs below `range`.
+#[napi]
+pub fn lock_pcrs(env: Env, range: u32) -> Result<()> {
+    errors::structured(&env, with_device(None, |device| device.lock_pcrs(range)))
+}
+
+fn pcr_range(range: u32, max_pcrs: u32) -> Result<u16> {
+    if range <= u32::from(FIRST_RUNTIME_PCR) || range > max_pcrs {
+        return Err(Error::from_reason(format!(
+            "PCR range {} must be between {} and the NSM's {} PCRs",
+            range,
+            FIRST_RUNTIME_PCR + 1,
+            max_pcrs
+        )));
+    }
+    Ok(range as u16)
+}
+
+/// Check `index` is a PCR applications may extend or lock.
+fn runtime_pcr_index(index: u32) -> Result<u16> {
+    match u16::try_from(index) {
+        Ok(index) if index >= FIRST_RUNTIME_PCR => Ok(index),
+        Ok(_) => Err(Error::from_reason(format!(
+            "PCR {} is reserved for boot measurements; use {} or above",
+            index, FIRST_RUNTIME_PCR
+        ))),
+        Err(_) => Err(Error::from_reason(format!("Invalid PCR index {}", index))),
+    }
+}
+
+/// Check Attestation request fields against ATTESTATION_FIELD_LIMITS.
+fn check_attestation_fields(
+    user_data: Option<&[u8]>,
+    nonce: Option<&[u8]>,
+    public_key: Option<&[u8]>,
+) -> Result<()> {
+    let fields = ATTESTATION_FIELD_LIMITS.iter().zip([user_data, nonce, public_key]);
+    for (&(_, name, limit), value) in fields {
+        match value {
+            Some(value) if value.len() > limit => {
+                return Err(Error::from_reason(format!(
+                    "InputTooLarge: {} is {} bytes; the NSM accepts at most {}",
+                    name,
+                    value.len(),
+                    limit
+                )))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Largest count nsmGetRandom() accepts.
+pub(crate) const MAX_RANDOM_BYTES: u32 = 1024 * 1024;
+
+/// Entropy from the NSM's hardware RNG.
+///
+/// Without `count`, returns the bytes of a single GetRandom request (the
+/// NSM returns up to 256). With `count`, repeats the request until exactly
+/// `count` bytes (at most 1 MiB) are collected.
+#[napi]
+pub fn nsm_get_random(env: Env, count: Option<u32>) -> Result<Buffer> {
+    errors::structured(&env, with_device(None, |device| device.random(count)).map(Buffer::from))
+}
+
+/// Call `next` until `count` bytes are collected.
+fn collect_random(count: usize, mut next: impl FnMut() -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(count);
+    while out.len() < count {
+        let chunk = next()?;
+        if chunk.is_empty() {
+            return Err(Error::from_reason("NSM GetRandom returned no bytes"));
+        }
+        let take = chunk.len().min(count - out.len());
+        out.extend_from_slice(&chunk[..take]);
+    }
+    Ok(out)
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: Value) -> ParsedResponse {
+        parse_response(&value).unwrap()
+    }
+
+    #[test]
+    fn ioctl_command_matches_the_nsm_driver() {
+        // nsm-api: nix::request_code_readwrite!(NSM_IOCTL_MAGIC, 0, size_of::<NsmMessage>())
+        assert_eq!(std::mem::size_of::<NsmMessage>(), 2 * std::mem::size_of::<libc::iovec>());
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(NSM_IOCTL_CMD, 0xC020_0A00);
+        assert_eq!(iowr(NSM_IOCTL_MAGIC, 0, 16), 0xC010_0A00);
+        assert_eq!(iowr(0xAF, 0x20, 8), 0xC008_AF20);
+    }
+
+    #[test]
+    fn parses_body_responses() {
+        let attestation = cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![("document", Value::Bytes(vec![0xd2, 0x84]))]),
+        )]);
+        assert_eq!(parse(attestation), ParsedResponse::Attestation { document: vec![0xd2, 0x84] });
+
+        let pcr = cbor::map(vec![(
+            "DescribePCR",
+            cbor::map(vec![("lock", Value::Bool(true)), ("data", Value::Bytes(vec![0; 48]))]),
+        )]);
+        assert_eq!(parse(pcr), ParsedResponse::DescribePcr { lock: true, data: vec![0; 48] });
+
+        let random = cbor::map(vec![(
+            "GetRandom",
+            cbor::map(vec![("random", Value::Bytes(vec![7; 256]))]),
+        )]);
+        assert_eq!(parse(random), ParsedResponse::GetRandom { random: vec![7; 256] });
+    }
+
+    #[test]
+    fn parses_describe_nsm() {
+        let describe = cbor::map(vec![(
+            "DescribeNSM",
+            cbor::map(vec![
+                ("version_major", Value::Integer(1.into())),
+                ("version_minor", Value::Integer(0.into())),
+                ("version_patch", Value::Integer(0.into())),
+                ("module_id", cbor::text("i-0123-enc0123")),
+                ("max_pcrs", Value::Integer(32.into())),
+                ("locked_pcrs", Value::Array(vec![Value::Integer(0.into()), Value::Integer(1.into())])),
+                ("digest", cbor::text("SHA384")),
+            ]),
+        )]);
+        let ParsedResponse::DescribeNsm(desc) = parse(describe) else {
+            panic!("expected DescribeNSM");
+        };
+        assert_eq!(desc.max_pcrs, 32);
+        assert_eq!(desc.locked_pcrs, vec![0, 1]);
+        assert_eq!(desc.module_id, "i-0123-enc0123");
+    }
+
+    #[test]
+    fn parses_unit_and_error_responses() {
+        assert_eq!(parse(cbor::text("LockPCRs")), ParsedResponse::LockPcrs);
+        let error = cbor::map(vec![("Error", cbor::text("InvalidIndex"))]);
+        assert_eq!(parse(error), ParsedResponse::Error { code: "InvalidIndex".into() });
+    }
+
+    #[test]
+    fn closed_device_rejects_requests() {
+        let path = std::ffi::CString::new("/dev/null").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR) };
+        assert!(fd >= 0);
+        let device = Device {
+            backend: Mutex::new(Backend::Fd(fd)),
+            max_response_size: 16,
+            source: Source::Device("/dev/null".to_string()),
+        };
+        device.close();
+        device.close();
+        let err = device.request(b"\x60").unwrap_err();
+        assert_eq!(err.reason, "NSM device is closed");
+    }
+
+    #[test]
+    fn device_options_apply_defaults_and_limits() {
+        assert_eq!(DeviceConfig::new(None, None).unwrap(), DeviceConfig::default());
+        let config = DeviceConfig::new(Some("/dev/nsm0".into()), None).unwrap();
+        assert_eq!(config.path(), "/dev/nsm0");
+        assert_eq!(config.max_response_size, 16 * 1024);
+
+        for size in [0, MAX_RESPONSE_SIZE_LIMIT + 1] {
+            assert!(DeviceConfig::new(None, Some(size)).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn open_failure_names_the_device_path() {
+        let config = DeviceConfig {
+            path: Some("/nonexistent/nsm".into()),
+            ..DeviceConfig::default()
+        };
+        let err = Device::open_with(config).err().unwrap();
+        assert!(
+            err.reason.starts_with("ENOENT: open(/nonexistent/nsm) failed"),
+            "{}",
+            err.reason
+        );
+    }
+
+    #[test]
+    fn truncated_responses_are_detected() {
+        let response = cbor::encode(&cbor::map(vec![(
+            "GetRandom",
+            cbor::map(vec![("random", Value::Bytes(vec![7; 64]))]),
+        )]))
+        .unwrap();
+
+        let mut buffer = response.clone();
+        buffer.resize(response.len() + 10, 0);
+        assert!(check_response_len(&buffer, response.len()).is_ok());
+        assert!(check_response_len(&response, response.len()).is_ok());
+
+        let cut = &response[..response.len() - 8];
+        let err = check_response_len(cut, cut.len()).unwrap_err();
+        assert!(err.reason.starts_with("ResponseTooLarge: NSM response exceeds"));
+        let err = check_response_len(cut, response.len()).unwrap_err();
+        assert!(err.reason.contains(&format!("({} bytes)", response.len())));
+    }
+
+    #[test]
+    fn error_codes_become_the_error_code() {
+        let err = coded(nsm_error("InvalidIndex", "DescribePCR"));
+        assert_eq!(err.status, "InvalidIndex");
+        assert_eq!(err.reason, "InvalidIndex: NSM DescribePCR failed");
+
+        let err = coded(platform::unsupported("/dev/nsm"));
+        assert_eq!(err.status, "UnsupportedPlatform");
+
+        let err = coded(Error::from_reason("CBOR decode failed: unexpected end"));
+        assert_eq!(err.status, "GenericFailure");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn detects_nsm_by_opening_the_device() {
+        assert_eq!(detect_nsm("/dev/null"), (true, "/dev/null is available".to_string()));
+        assert_eq!(
+            detect_nsm("/nonexistent/nsm"),
+            (false, "/nonexistent/nsm does not exist".to_string())
+        );
+    }
+
+    #[test]
+    fn mock_devices_serve_requests() {
+        let config = DeviceConfig {
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let device = Device::open_with(config).unwrap();
+        assert_eq!(device.describe().unwrap().max_pcrs, 32);
+        let extended = device.extend_pcr(16, b"app").unwrap();
+        assert_eq!(device.read_pcr(16).unwrap(), (false, extended));
+        device.lock_pcr(16).unwrap();
+        let err = device.extend_pcr(16, b"app").unwrap_err();
+        assert_eq!(coded(err).status, "ReadOnlyIndex");
+
+        let small = DeviceConfig {
+            max_response_size: 64,
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let err = Device::open_with(small).unwrap().attestation(None, None, None).unwrap_err();
+        assert!(err.reason.starts_with("ResponseTooLarge"));
+    }
+
+    #[test]
+    fn queue_serves_requests_one_at_a_time_in_order() {
+        let queue = Arc::new(Queue::new());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let first = queue.enter();
+        let waiters: Vec<_> = (0..4)
+            .map(|i| {
+                let (mine, order) = (queue.clone(), order.clone());
+                let waiter = std::thread::spawn(move || {
+                    let _turn = mine.enter();
+                    order.lock().unwrap().push(i);
+                });
+                // Each takes its ticket before the next starts
+                while queue.stats().depth <= i {
+                    std::thread::yield_now();
+                }
+                waiter
+            })
+            .collect();
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.max_depth, stats.waited), (4, 4, 4.0));
+        drop(first);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(queue.stats().depth, 0);
+
+        // Concurrent callers of the process-wide queue all get answers
+        let config = || DeviceConfig {
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let callers: Vec<_> = (0..8)
+            .map(|_| {
+                let device = Device::open_with(config()).unwrap();
+                std::thread::spawn(move || device.describe().unwrap().max_pcrs)
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), 32);
+        }
+    }
+
+    #[test]
+    fn batches_decode_each_response_in_order() {
+        let config = DeviceConfig {
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let device = Device::open_with(config).unwrap();
+        let describe_pcr = |index: u64| {
+            cbor::encode(&cbor::map(vec![(
+                "DescribePCR",
+                cbor::map(vec![("index", Value::Integer(index.into()))]),
+            )]))
+            .unwrap()
+        };
+        let requests = [
+            cbor::encode(&cbor::text("DescribeNSM")).unwrap(),
+            describe_pcr(0),
+            describe_pcr(99),
+        ];
+        let responses = device.request_batch(&requests).unwrap();
+        assert!(matches!(&responses[0], ParsedResponse::DescribeNsm(d) if d.max_pcrs == 32));
+        assert!(matches!(&responses[1], ParsedResponse::DescribePcr { .. }));
+        assert_eq!(responses[2], ParsedResponse::Error { code: "InvalidIndex".into() });
+
+        let small = DeviceConfig {
+            max_response_size: 128,
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let attestation = cbor::encode(&cbor::map(vec![("Attestation", cbor::map(vec![]))])).unwrap();
+        let err = Device::open_with(small)
+            .unwrap()
+            .request_batch(&[describe_pcr(0), attestation])
+            .unwrap_err();
+        assert!(err.reason.starts_with("ResponseTooLarge"), "{}", err.reason);
+        assert!(err.reason.ends_with("(batch request 1)"), "{}", err.reason);
+    }
+
+    #[test]
+    fn random_bytes_are_collected_across_requests() {
+        let mut calls = 0u8;
+        let bytes = collect_random(600, || {
+            calls += 1;
+            Ok(vec![calls; 256])
+        })
+        .unwrap();
+        assert_eq!(bytes.len(), 600);
+        assert_eq!((bytes[0], bytes[256], bytes[599]), (1, 2, 3));
+        assert_eq!(calls, 3);
+
+        assert!(collect_random(0, || unreachable!()).unwrap().is_empty());
+        let err = collect_random(1, || Ok(Vec::new())).unwrap_err();
+        assert!(err.reason.contains("no bytes"), "{}", err.reason);
+    }
+
+    #[test]
+    fn boot_pcrs_are_not_runtime_indices() {
+        assert_eq!(runtime_pcr_index(16).unwrap(), 16);
+        assert_eq!(runtime_pcr_index(31).unwrap(), 31);
+        let err = runtime_pcr_index(0).unwrap_err();
+        assert!(err.reason.contains("reserved for boot"), "{}", err.reason);
+        assert!(runtime_pcr_index(70_000).is_err());
+    }
+
+    #[test]
+    fn oversized_attestation_fields_are_named() {
+        let at_limit = check_attestation_fields(Some(&[0; 1024]), Some(&[0; 512]), Some(&[0; 1024]));
+        assert!(at_limit.is_ok());
+        let err = check_attestation_fields(None, Some(&[0; 513]), None).unwrap_err();
+        assert_eq!(err.reason, "InputTooLarge: nonce is 513 bytes; the NSM accepts at most 512");
+        assert_eq!(coded(err).status, "InputTooLarge");
+        let err = check_attestation_fields(None, None, Some(&[0; 2000])).unwrap_err();
+        assert!(err.reason.starts_with("InputTooLarge: publicKey is 2000 bytes"), "{}", err.reason);
+
+        // Checked before the request is sent
+        let config = DeviceConfig {
+            mock: Some(MockConfig::default()),
+            ..DeviceConfig::default()
+        };
+        let device = Device::open_with(config).unwrap();
+        let err = device.attestation(Some(&[0; 1025]), None, None).unwrap_err();
+        assert!(err.reason.starts_with("InputTooLarge: userData"), "{}", err.reason);
+    }
+
+    #[test]
+    fn lock_ranges_must_cover_a_runtime_pcr() {
+        assert_eq!(pcr_range(17, 32).unwrap(), 17);
+        assert_eq!(pcr_range(32, 32).unwrap(), 32);
+        assert!(pcr_range(16, 32).is_err());
+        let err = pcr_range(33, 32).unwrap_err();
+        assert!(err.reason.contains("NSM's 32 PCRs"), "{}", err.reason);
+    }
+
+    #[test]
+    fn rejects_unknown_and_incomplete_responses() {
+        assert!(parse_response(&cbor::text("Nope")).is_err());
+        assert!(parse_response(&Value::Integer(1.into())).is_err());
+        let missing = cbor::map(vec![("Attestation", cbor::map(vec![]))]);
+        let err = parse_response(&missing).unwrap_err();
+        assert!(err.reason.contains("missing document"), "{}", err.reason);
     }
 }
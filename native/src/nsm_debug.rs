@@ -0,0 +1,265 @@
+//! Opt-in NSM request tracing.
+//!
+//! setNsmDebugHook(callback) receives an NsmDebugEvent for every request
+//! the addon sends to the NSM (device or mock): operation, response kind,
+//! sizes and latency, plus both messages rendered with each byte string
+//! replaced by its length, so nonces, user data, documents and random
+//! bytes never reach the hook. `TYTLE_NSM_DEBUG=1` writes the same events
+//! to stderr, for diagnosing an enclave without changing its code.
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::{cbor, metrics};
+
+const DEBUG_ENV: &str = "TYTLE_NSM_DEBUG";
+
+/// Passed to the setNsmDebugHook() callback.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct NsmDebugEvent {
+    /// Request operation, e.g. "Attestation" or "DescribePCR".
+    pub operation: String,
+    /// Response kind, e.g. "Attestation" or "Error"; absent when the
+    /// request failed without a response.
+    pub response_kind: Option<String>,
+    /// The NSM error code of an Error response, or why the request failed.
+    pub error: Option<String>,
+    pub request_size: u32,
+    pub response_size: Option<u32>,
+    /// Time from sending the request to its result, in microseconds,
+    /// including waiting for other requests on the same device.
+    pub latency_us: f64,
+    /// The request with byte strings redacted, e.g.
+    /// `{"Attestation": {"nonce": <32 bytes>, "user_data": null}}`.
+    pub request: String,
+    /// The response, redacted likewise.
+    pub response: Option<String>,
+}
+
+type Hook = Box<dyn Fn(NsmDebugEvent) + Send>;
+
+static HOOK: Mutex<Option<Hook>> = Mutex::new(None);
+static HOOKED: AtomicBool = AtomicBool::new(false);
+
+/// Call `callback` with an NsmDebugEvent after every NSM request, or stop
+/// with null.
+#[napi]
+pub fn set_nsm_debug_hook(
+    env: Env,
+    #[napi(ts_arg_type = "((event: NsmDebugEvent) => void) | null")] callback: Option<
+        ThreadsafeFunction<NsmDebugEvent, ErrorStrategy::Fatal>,
+    >,
+) -> Result<()> {
+    let hook: Option<Hook> = match callback {
+        Some(mut callback) => {
+            // Don't keep the process alive just for tracing
+            callback.unref(&env)?;
+            Some(Box::new(move |event| {
+                callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+            }))
+        }
+        None => None,
+    };
+    let mut current = HOOK.lock().unwrap();
+    HOOKED.store(hook.is_some(), Ordering::Relaxed);
+    *current = hook;
+    Ok(())
+}
+
+fn env_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| std::env::var(DEBUG_ENV).is_ok_and(|v| !v.is_empty() && v != "0"))
+}
+
+/// Run `send` for `request`, recording its latency for MetricsServer and
+/// reporting it when tracing is on.
+pub(crate) fn traced(request: &[u8], send: impl FnOnce() -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let result = send();
+    let elapsed = start.elapsed();
+    let operation = cbor::decode(request).ok();
+    let operation = operation.as_ref().and_then(kind).unwrap_or("unknown");
+    metrics::nsm_request(operation, elapsed, result.is_err());
+    let latency_us = elapsed.as_micros() as u64;
+    match &result {
+        Ok(_) => tracing::debug!(operation, latency_us, "NSM request"),
+        Err(e) => tracing::warn!(operation, latency_us, error = %e.reason, "NSM request failed"),
+    }
+
+    let to_stderr = env_enabled();
+    if !to_stderr && !HOOKED.load(Ordering::Relaxed) {
+        return result;
+    }
+    let event = event(request, &result, elapsed);
+    if to_stderr {
+        eprintln!("{}", describe(&event));
+    }
+    if let Some(hook) = HOOK.lock().unwrap().as_ref() {
+        hook(event);
+    }
+    result
+}
+
+fn event(request: &[u8], result: &Result<Vec<u8>>, elapsed: Duration) -> NsmDebugEvent {
+    let decoded = cbor::decode(request).ok();
+    let mut event = NsmDebugEvent {
+        operation: decoded
+            .as_ref()
+            .and_then(kind)
+            .unwrap_or("unknown")
+            .to_string(),
+        response_kind: None,
+        error: None,
+        request_size: request.len() as u32,
+        response_size: None,
+        latency_us: elapsed.as_secs_f64() * 1e6,
+        request: decoded
+            .as_ref()
+            .map_or_else(|| "<malformed CBOR>".to_string(), redact),
+        response: None,
+    };
+    match result {
+        Ok(response) => {
+            let decoded = cbor::decode(response).ok();
+            event.response_size = Some(response.len() as u32);
+            event.response_kind = decoded.as_ref().and_then(kind).map(str::to_string);
+            event.error = decoded
+                .as_ref()
+                .and_then(|value| cbor::map_get(value, "Error"))
+                .and_then(cbor::as_text)
+                .map(str::to_string);
+            event.response = Some(
+                decoded
+                    .as_ref()
+                    .map_or_else(|| "<malformed CBOR>".to_string(), redact),
+            );
+        }
+        Err(e) => event.error = Some(e.reason.clone()),
+    }
+    event
+}
+
+/// The operation of a request or response: a bare string, or the key of
+/// a single-entry map.
+fn kind(value: &Value) -> Option<&str> {
+    match value {
+        Value::Text(t) => Some(t),
+        Value::Map(entries) if entries.len() == 1 => cbor::as_text(&entries[0].0),
+        _ => None,
+    }
+}
+
+/// `value` in diagnostic notation, with byte strings as `<N bytes>`.
+fn redact(value: &Value) -> String {
+    let join = |items: Vec<String>| items.join(", ");
+    match value {
+        Value::Bytes(b) => format!("<{} bytes>", b.len()),
+        Value::Text(t) => format!("{:?}", t),
+        Value::Integer(n) => i128::from(*n).to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => "null".to_string(),
+        Value::Array(items) => format!("[{}]", join(items.iter().map(redact).collect())),
+        Value::Map(entries) => format!(
+            "{{{}}}",
+            join(
+                entries
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", redact(k), redact(v)))
+                    .collect()
+            )
+        ),
+        Value::Tag(tag, inner) => format!("{}({})", tag, redact(inner)),
+        _ => "<unknown>".to_string(),
+    }
+}
+
+/// One stderr line for `event`.
+fn describe(event: &NsmDebugEvent) -> String {
+    let outcome = match (&event.response_kind, &event.error) {
+        (Some(kind), Some(error)) if kind == "Error" => format!("Error {}", error),
+        (Some(kind), _) => kind.clone(),
+        (None, Some(error)) => format!("failed: {}", error),
+        (None, None) => "no response".to_string(),
+    };
+    format!(
+        "[nsm] {} ({} bytes) -> {} ({} bytes) in {:.0}us: {} -> {}",
+        event.operation,
+        event.request_size,
+        outcome,
+        event.response_size.unwrap_or(0),
+        event.latency_us,
+        event.request,
+        event.response.as_deref().unwrap_or("-"),
+    )
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_redact_byte_strings() {
+        let request = cbor::encode(&cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![
+                ("nonce", Value::Bytes(vec![7; 32])),
+                ("user_data", Value::Null),
+            ]),
+        )]))
+        .unwrap();
+        let response = cbor::encode(&cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![("document", Value::Bytes(vec![0xd2; 4000]))]),
+        )]))
+        .unwrap();
+        let event = event(&request, &Ok(response.clone()), Duration::from_micros(1500));
+        assert_eq!(event.operation, "Attestation");
+        assert_eq!(event.response_kind.as_deref(), Some("Attestation"));
+        assert_eq!(event.error, None);
+        assert_eq!(event.request_size, request.len() as u32);
+        assert_eq!(event.response_size, Some(response.len() as u32));
+        assert_eq!(event.latency_us, 1500.0);
+        assert_eq!(
+            event.request,
+            r#"{"Attestation": {"nonce": <32 bytes>, "user_data": null}}"#
+        );
+        assert_eq!(
+            event.response.as_deref(),
+            Some(r#"{"Attestation": {"document": <4000 bytes>}}"#)
+        );
+        assert!(describe(&event).starts_with("[nsm] Attestation ("));
+    }
+
+    #[test]
+    fn events_report_nsm_errors_and_failures() {
+        let request = cbor::encode(&cbor::map(vec![(
+            "DescribePCR",
+            cbor::map(vec![("index", Value::Integer(99.into()))]),
+        )]))
+        .unwrap();
+        let response =
+            cbor::encode(&cbor::map(vec![("Error", cbor::text("InvalidIndex"))])).unwrap();
+        let event = event(&request, &Ok(response), Duration::ZERO);
+        assert_eq!(event.request, r#"{"DescribePCR": {"index": 99}}"#);
+        assert_eq!(event.error.as_deref(), Some("InvalidIndex"));
+        assert!(describe(&event).contains("-> Error InvalidIndex ("));
+
+        let failed = Err(Error::from_reason("NSM device is closed"));
+        let event = super::event(b"\xff", &failed, Duration::ZERO);
+        assert_eq!(event.operation, "unknown");
+        assert_eq!(event.request, "<malformed CBOR>");
+        assert_eq!((event.response_kind, event.response), (None, None));
+        assert_eq!(event.error.as_deref(), Some("NSM device is closed"));
+    }
+}
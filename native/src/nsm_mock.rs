@@ -0,0 +1,523 @@
+//! In-process mock NSM for tests and CI without Nitro hardware.
+//!
+//! Enabled per device with `NsmOptions.mock`, or for every NSM call in the
+//! process when `TYTLE_NSM_MOCK=1` (boot PCR N may then be set with
+//! `TYTLE_NSM_MOCK_PCR<N>=<hex>`). The mock answers the same CBOR requests
+//! as /dev/nsm: 32 SHA-384 PCRs, with 0–15 locked as after boot, and
+//! attestation documents signed by the test chain in testdata/attestation.
+//! Verify them with `trustedRoots: [mockNsmRoot()]` and `useAwsRoot: false`.
+//!
+//! Everything but GetRandom is deterministic for a fixed `timestamp`.
+//!
+//! The mock and its test key are only compiled into unit tests and builds
+//! with the `nsm-mock` cargo feature. Elsewhere the options still parse,
+//! but opening a mock, or setting `TYTLE_NSM_MOCK`, fails with
+//! MockUnavailable.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[cfg(any(test, feature = "nsm-mock"))]
+use {
+    crate::attestation::{self, CoseSign1},
+    crate::cbor,
+    crate::nsm::{ATTESTATION_FIELD_LIMITS, FIRST_RUNTIME_PCR},
+    ciborium::value::Value,
+    p384::ecdsa::SigningKey,
+    p384::pkcs8::DecodePrivateKey,
+    rand_core::{OsRng, RngCore},
+    sha2::{Digest, Sha384},
+    std::sync::OnceLock,
+};
+
+const MOCK_ENV: &str = "TYTLE_NSM_MOCK";
+#[cfg(any(test, feature = "nsm-mock"))]
+const MOCK_PCR_ENV_PREFIX: &str = "TYTLE_NSM_MOCK_PCR";
+
+#[cfg(any(test, feature = "nsm-mock"))]
+const MAX_PCRS: u16 = 32;
+#[cfg(any(test, feature = "nsm-mock"))]
+const PCR_LEN: usize = 48;
+#[cfg(any(test, feature = "nsm-mock"))]
+const DEFAULT_MODULE_ID: &str = "i-00000000000000000-enc0000000000000000";
+/// Bytes per GetRandom response.
+#[cfg(any(test, feature = "nsm-mock"))]
+const RANDOM_LEN: usize = 256;
+
+#[cfg(any(test, feature = "nsm-mock"))]
+const ROOT_PEM: &str = include_str!("../testdata/attestation/root.pem");
+#[cfg(any(test, feature = "nsm-mock"))]
+const INTERMEDIATE_PEM: &str = include_str!("../testdata/attestation/intermediate.pem");
+#[cfg(any(test, feature = "nsm-mock"))]
+const LEAF_PEM: &str = include_str!("../testdata/attestation/leaf.pem");
+#[cfg(any(test, feature = "nsm-mock"))]
+const LEAF_KEY: &str = include_str!("../testdata/attestation/leaf.key");
+
+#[cfg(any(test, feature = "nsm-mock"))]
+static SHARED: OnceLock<Option<Arc<Mutex<MockNsm>>>> = OnceLock::new();
+
+#[napi(object)]
+pub struct MockNsmOptions {
+    /// Initial PCR values by index (48 bytes each); others start zeroed.
+    pub pcrs: Option<HashMap<String, Buffer>>,
+    /// Module id reported by DescribeNSM and attestation documents.
+    pub module_id: Option<String>,
+    /// Fixed document timestamp in ms since the epoch (default now).
+    pub timestamp: Option<f64>,
+}
+
+/// The root certificate (PEM) mock attestation documents chain to.
+#[napi]
+pub fn mock_nsm_root() -> Result<Buffer> {
+    Ok(root_pem()?.as_bytes().to_vec().into())
+}
+
+#[cfg(any(test, feature = "nsm-mock"))]
+fn root_pem() -> Result<&'static str> {
+    Ok(ROOT_PEM)
+}
+
+#[cfg(not(any(test, feature = "nsm-mock")))]
+fn root_pem() -> Result<&'static str> {
+    Err(unavailable())
+}
+
+/// Error for mock use in builds without the `nsm-mock` feature.
+#[cfg(not(any(test, feature = "nsm-mock")))]
+fn unavailable() -> Error {
+    Error::from_reason(
+        "MockUnavailable: this build has no mock NSM (rebuild with --features nsm-mock)",
+    )
+}
+
+/// napi-free form of MockNsmOptions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct MockConfig {
+    pcrs: BTreeMap<u16, Vec<u8>>,
+    module_id: Option<String>,
+    timestamp_ms: Option<u64>,
+}
+
+impl MockConfig {
+    pub(crate) fn from_js(options: MockNsmOptions) -> Result<Self> {
+        let mut pcrs = BTreeMap::new();
+        for (index, value) in options.pcrs.unwrap_or_default() {
+            let index = index
+                .parse::<u16>()
+                .map_err(|_| Error::from_reason(format!("Invalid mock PCR index {}", index)))?;
+            pcrs.insert(index, value.to_vec());
+        }
+        let timestamp_ms = match options.timestamp {
+            Some(t) if t.is_nan() || t < 0.0 => {
+                return Err(Error::from_reason(
+                    "mock timestamp must be a non-negative number",
+                ))
+            }
+            t => t.map(|t| t as u64),
+        };
+        Ok(MockConfig {
+            pcrs,
+            module_id: options.module_id,
+            timestamp_ms,
+        })
+    }
+
+    #[cfg(any(test, feature = "nsm-mock"))]
+    fn from_env() -> Result<Self> {
+        let mut pcrs = BTreeMap::new();
+        for index in 0..MAX_PCRS {
+            let name = format!("{}{}", MOCK_PCR_ENV_PREFIX, index);
+            if let Ok(hex) = std::env::var(&name) {
+                let value = decode_hex(&hex)
+                    .ok_or_else(|| Error::from_reason(format!("{} is not valid hex", name)))?;
+                pcrs.insert(index, value);
+            }
+        }
+        Ok(MockConfig {
+            pcrs,
+            ..MockConfig::default()
+        })
+    }
+}
+
+fn env_enabled() -> bool {
+    std::env::var(MOCK_ENV).is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+/// The process-wide mock selected by `TYTLE_NSM_MOCK`, if enabled. Read
+/// from the environment once, on first use.
+#[cfg(any(test, feature = "nsm-mock"))]
+pub(crate) fn shared() -> Result<Option<Arc<Mutex<MockNsm>>>> {
+    if let Some(shared) = SHARED.get() {
+        return Ok(shared.clone());
+    }
+    let mock = match env_enabled() {
+        true => Some(Arc::new(Mutex::new(MockNsm::new(MockConfig::from_env()?)?))),
+        false => None,
+    };
+    Ok(SHARED.get_or_init(|| mock).clone())
+}
+
+/// Without the mock compiled in, `TYTLE_NSM_MOCK` is refused rather than
+/// silently ignored.
+#[cfg(not(any(test, feature = "nsm-mock")))]
+pub(crate) fn shared() -> Result<Option<Arc<Mutex<MockNsm>>>> {
+    match env_enabled() {
+        true => Err(unavailable()),
+        false => Ok(None),
+    }
+}
+
+/// Stand-in for the mock in builds without it; never constructed.
+#[cfg(not(any(test, feature = "nsm-mock")))]
+pub(crate) enum MockNsm {}
+
+#[cfg(not(any(test, feature = "nsm-mock")))]
+impl MockNsm {
+    pub(crate) fn new(_config: MockConfig) -> Result<Self> {
+        Err(unavailable())
+    }
+
+    pub(crate) fn handle(&mut self, _request: &[u8]) -> Result<Vec<u8>> {
+        match *self {}
+    }
+}
+
+#[cfg(any(test, feature = "nsm-mock"))]
+pub(crate) struct MockNsm {
+    module_id: String,
+    pcrs: Vec<Vec<u8>>,
+    locked: Vec<bool>,
+    timestamp_ms: Option<u64>,
+    key: SigningKey,
+}
+
+#[cfg(any(test, feature = "nsm-mock"))]
+impl MockNsm {
+    pub(crate) fn new(config: MockConfig) -> Result<Self> {
+        let mut pcrs = vec![vec![0; PCR_LEN]; MAX_PCRS as usize];
+        for (index, value) in config.pcrs {
+            if index >= MAX_PCRS || value.len() != PCR_LEN {
+                return Err(Error::from_reason(format!(
+                    "mock PCR {} must be below {} and {} bytes",
+                    index, MAX_PCRS, PCR_LEN
+                )));
+            }
+            pcrs[index as usize] = value;
+        }
+        let key = SigningKey::from_pkcs8_pem(LEAF_KEY)
+            .map_err(|e| Error::from_reason(format!("mock NSM key: {}", e)))?;
+        Ok(MockNsm {
+            module_id: config
+                .module_id
+                .unwrap_or_else(|| DEFAULT_MODULE_ID.to_string()),
+            pcrs,
+            locked: (0..MAX_PCRS).map(|i| i < FIRST_RUNTIME_PCR).collect(),
+            timestamp_ms: config.timestamp_ms,
+            key,
+        })
+    }
+
+    /// Answer a CBOR request with a CBOR response, as the NSM ioctl does.
+    pub(crate) fn handle(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = match cbor::decode(request) {
+            Ok(request) => self
+                .respond(&request)
+                .unwrap_or_else(|code| cbor::map(vec![("Error", cbor::text(code))])),
+            Err(_) => cbor::map(vec![("Error", cbor::text("InvalidArgument"))]),
+        };
+        cbor::encode(&response)
+    }
+
+    /// The response to `request`, or the NSM error code.
+    fn respond(&mut self, request: &Value) -> std::result::Result<Value, &'static str> {
+        let (operation, body) = match request {
+            Value::Text(t) => (t.as_str(), &Value::Null),
+            Value::Map(entries) if entries.len() == 1 => (
+                cbor::as_text(&entries[0].0).ok_or("InvalidOperation")?,
+                &entries[0].1,
+            ),
+            _ => return Err("InvalidOperation"),
+        };
+        let index = || -> std::result::Result<usize, &'static str> {
+            let index = cbor::map_get(body, "index")
+                .and_then(cbor::as_u64)
+                .ok_or("InvalidArgument")?;
+            match index < u64::from(MAX_PCRS) {
+                true => Ok(index as usize),
+                false => Err("InvalidIndex"),
+            }
+        };
+
+        Ok(match operation {
+            "DescribeNSM" => cbor::map(vec![("DescribeNSM", self.describe())]),
+            "DescribePCR" => {
+                let index = index()?;
+                cbor::map(vec![(
+                    "DescribePCR",
+                    cbor::map(vec![
+                        ("lock", Value::Bool(self.locked[index])),
+                        ("data", Value::Bytes(self.pcrs[index].clone())),
+                    ]),
+                )])
+            }
+            "ExtendPCR" => {
+                let index = index()?;
+                let data = cbor::map_get(body, "data")
+                    .and_then(cbor::as_bytes)
+                    .ok_or("InvalidArgument")?;
+                if self.locked[index] {
+                    return Err("ReadOnlyIndex");
+                }
+                let mut hasher = Sha384::new();
+                hasher.update(&self.pcrs[index]);
+                hasher.update(&data);
+                self.pcrs[index] = hasher.finalize().to_vec();
+                cbor::map(vec![(
+                    "ExtendPCR",
+                    cbor::map(vec![("data", Value::Bytes(self.pcrs[index].clone()))]),
+                )])
+            }
+            "LockPCR" => {
+                self.locked[index()?] = true;
+                cbor::text("LockPCR")
+            }
+            "LockPCRs" => {
+                let range = cbor::map_get(body, "range")
+                    .and_then(cbor::as_u64)
+                    .ok_or("InvalidArgument")?;
+                if range > u64::from(MAX_PCRS) {
+                    return Err("InvalidIndex");
+                }
+                self.locked[..range as usize].fill(true);
+                cbor::text("LockPCRs")
+            }
+            "GetRandom" => {
+                let mut random = vec![0; RANDOM_LEN];
+                OsRng.fill_bytes(&mut random);
+                cbor::map(vec![(
+                    "GetRandom",
+                    cbor::map(vec![("random", Value::Bytes(random))]),
+                )])
+            }
+            "Attestation" => {
+                let document = self.attestation(body)?;
+                cbor::map(vec![(
+                    "Attestation",
+                    cbor::map(vec![("document", Value::Bytes(document))]),
+                )])
+            }
+            _ => return Err("InvalidOperation"),
+        })
+    }
+
+    fn describe(&self) -> Value {
+        let locked = (0..MAX_PCRS)
+            .filter(|&i| self.locked[i as usize])
+            .map(|i| Value::Integer(i.into()))
+            .collect();
+        cbor::map(vec![
+            ("version_major", Value::Integer(1.into())),
+            ("version_minor", Value::Integer(0.into())),
+            ("version_patch", Value::Integer(0.into())),
+            ("module_id", cbor::text(&self.module_id)),
+            ("max_pcrs", Value::Integer(MAX_PCRS.into())),
+            ("locked_pcrs", Value::Array(locked)),
+            ("digest", cbor::text("SHA384")),
+        ])
+    }
+
+    /// A COSE_Sign1 attestation document for the current PCRs.
+    fn attestation(&self, body: &Value) -> std::result::Result<Vec<u8>, &'static str> {
+        let field = |key: &str| match cbor::map_get(body, key) {
+            None | Some(Value::Null) => Ok(Value::Null),
+            Some(value) => match cbor::as_bytes(value) {
+                Some(bytes) if bytes.len() > max_field_len(key) => Err("InputTooLarge"),
+                Some(bytes) => Ok(Value::Bytes(bytes)),
+                None => Err("InvalidArgument"),
+            },
+        };
+        let timestamp = self
+            .timestamp_ms
+            .unwrap_or_else(|| attestation::now_ms() as u64);
+        let pcrs = self
+            .pcrs
+            .iter()
+            .enumerate()
+            .map(|(i, pcr)| (Value::Integer(i.into()), Value::Bytes(pcr.clone())))
+            .collect();
+        let payload = cbor::map(vec![
+            ("module_id", cbor::text(&self.module_id)),
+            ("digest", cbor::text("SHA384")),
+            ("timestamp", Value::Integer(timestamp.into())),
+            ("pcrs", Value::Map(pcrs)),
+            ("certificate", Value::Bytes(pem_der(LEAF_PEM))),
+            (
+                "cabundle",
+                Value::Array(vec![
+                    Value::Bytes(pem_der(ROOT_PEM)),
+                    Value::Bytes(pem_der(INTERMEDIATE_PEM)),
+                ]),
+            ),
+            ("public_key", field("public_key")?),
+            ("user_data", field("user_data")?),
+            ("nonce", field("nonce")?),
+        ]);
+        cbor::encode(&payload)
+            .and_then(|payload| CoseSign1::sign(&payload, attestation::COSE_ALG_ES384, &self.key))
+            .map_err(|_| "InternalError")
+    }
+}
+
+/// Largest value the NSM accepts for Attestation field `key`.
+#[cfg(any(test, feature = "nsm-mock"))]
+fn max_field_len(key: &str) -> usize {
+    ATTESTATION_FIELD_LIMITS
+        .iter()
+        .find(|(field, _, _)| *field == key)
+        .map_or(0, |&(_, _, limit)| limit)
+}
+
+#[cfg(any(test, feature = "nsm-mock"))]
+fn pem_der(pem: &str) -> Vec<u8> {
+    pem_rfc7468::decode_vec(pem.as_bytes())
+        .map(|(_, der)| der)
+        .unwrap_or_default()
+}
+
+#[cfg(any(test, feature = "nsm-mock"))]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    #[allow(clippy::manual_is_multiple_of)]
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::fixtures::{test_root, JAN_2030_MS};
+    use crate::attestation::Expectations;
+    use crate::nsm::{parse_response, ParsedResponse};
+
+    fn mock() -> MockNsm {
+        let mut config = MockConfig {
+            timestamp_ms: Some(JAN_2030_MS as u64),
+            ..MockConfig::default()
+        };
+        config.pcrs.insert(0, vec![0xaa; PCR_LEN]);
+        MockNsm::new(config).unwrap()
+    }
+
+    fn call(mock: &mut MockNsm, request: Value) -> ParsedResponse {
+        let response = mock.handle(&cbor::encode(&request).unwrap()).unwrap();
+        parse_response(&cbor::decode(&response).unwrap()).unwrap()
+    }
+
+    fn pcr_request(operation: &str, index: u64, data: Option<&[u8]>) -> Value {
+        let mut body = vec![("index", Value::Integer(index.into()))];
+        if let Some(data) = data {
+            body.push(("data", Value::Bytes(data.to_vec())));
+        }
+        cbor::map(vec![(operation, cbor::map(body))])
+    }
+
+    #[test]
+    fn attestation_documents_verify_against_the_test_root() {
+        let mut mock = mock();
+        let request = cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![
+                ("user_data", Value::Null),
+                ("nonce", Value::Bytes(vec![7; 16])),
+                ("public_key", Value::Null),
+            ]),
+        )]);
+        let ParsedResponse::Attestation { document } = call(&mut mock, request.clone()) else {
+            panic!("expected Attestation");
+        };
+        let ParsedResponse::Attestation { document: again } = call(&mut mock, request) else {
+            panic!("expected Attestation");
+        };
+        assert_eq!(document, again);
+
+        let expectations = Expectations {
+            nonce: Some(vec![7; 16]),
+            pcrs: Some(BTreeMap::from([(0, vec![0xaa; PCR_LEN])])),
+            max_age_ms: None,
+            time_ms: JAN_2030_MS,
+        };
+        let verdict = attestation::verify(&document, &expectations, &[test_root()]).unwrap();
+        assert!(verdict.valid(), "{:?}", verdict.errors);
+        assert_eq!(verdict.document.module_id, DEFAULT_MODULE_ID);
+        assert_eq!(verdict.document.pcrs.len(), MAX_PCRS as usize);
+    }
+
+    #[test]
+    fn extends_and_locks_runtime_pcrs() {
+        let mut mock = mock();
+        let ParsedResponse::ExtendPcr { data } =
+            call(&mut mock, pcr_request("ExtendPCR", 16, Some(b"app")))
+        else {
+            panic!("expected ExtendPCR");
+        };
+        let mut hasher = Sha384::new();
+        hasher.update([0; PCR_LEN]);
+        hasher.update(b"app");
+        assert_eq!(data, hasher.finalize().to_vec());
+
+        assert_eq!(
+            call(&mut mock, pcr_request("LockPCR", 16, None)),
+            ParsedResponse::LockPcr
+        );
+        assert_eq!(
+            call(&mut mock, pcr_request("ExtendPCR", 16, Some(b"app"))),
+            ParsedResponse::Error {
+                code: "ReadOnlyIndex".into()
+            }
+        );
+        assert_eq!(
+            call(&mut mock, pcr_request("DescribePCR", 32, None)),
+            ParsedResponse::Error {
+                code: "InvalidIndex".into()
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected() {
+        let request = cbor::map(vec![(
+            "Attestation",
+            cbor::map(vec![(
+                "user_data",
+                Value::Bytes(vec![0; max_field_len("user_data") + 1]),
+            )]),
+        )]);
+        assert_eq!(
+            call(&mut mock(), request),
+            ParsedResponse::Error {
+                code: "InputTooLarge".into()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_pcr_config() {
+        let mut config = MockConfig::default();
+        config.pcrs.insert(3, vec![0; 32]);
+        assert!(MockNsm::new(config).is_err());
+        assert_eq!(decode_hex("0aFf"), Some(vec![0x0a, 0xff]));
+        assert_eq!(decode_hex("0a f"), None);
+    }
+}
@@ -0,0 +1,79 @@
+//! Platform gating.
+//!
+//! AF_VSOCK and /dev/nsm only exist on Linux. On other platforms (macOS dev
+//! machines) the addon still loads with the same exports; calls that need
+//! them throw an `UnsupportedPlatform` error instead. The loopback backend
+//! (TYTLE_VSOCK_MOCK_DIR) works everywhere.
+
+use napi::bindgen_prelude::*;
+
+/// The error thrown for Linux-only functionality on other platforms.
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+pub(crate) fn unsupported(feature: &str) -> Error {
+    Error::from_reason(format!(
+        "UnsupportedPlatform: {} requires Linux (this is {}); set TYTLE_VSOCK_MOCK_DIR \
+         to use the loopback vsock backend for local development",
+        feature,
+        std::env::consts::OS
+    ))
+}
+
+/// Fail with `unsupported(feature)` unless running on Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn require_linux(_feature: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn require_linux(feature: &str) -> Result<()> {
+    Err(unsupported(feature))
+}
+
+/// accept() a connection with close-on-exec set, without decoding the peer
+/// address.
+#[cfg(target_os = "linux")]
+pub(crate) fn accept_cloexec(fd: i32) -> std::io::Result<i32> {
+    let conn = unsafe {
+        libc::accept4(fd, std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC)
+    };
+    if conn < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(conn)
+}
+
+/// accept4() is Linux-only: accept, then set FD_CLOEXEC. BSD accept()
+/// also copies O_NONBLOCK from the listener, which accept4() never does.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn accept_cloexec(fd: i32) -> std::io::Result<i32> {
+    let conn = unsafe { libc::accept(fd, std::ptr::null_mut(), std::ptr::null_mut()) };
+    if conn < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    unsafe {
+        libc::fcntl(conn, libc::F_SETFD, libc::FD_CLOEXEC);
+        let flags = libc::fcntl(conn, libc::F_GETFL);
+        libc::fcntl(conn, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+    }
+    Ok(conn)
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_error_names_the_feature() {
+        let err = unsupported("/dev/nsm");
+        assert!(err.reason.starts_with("UnsupportedPlatform: /dev/nsm requires Linux"));
+    }
+
+    #[test]
+    fn require_linux_matches_target() {
+        assert_eq!(require_linux("AF_VSOCK").is_ok(), cfg!(target_os = "linux"));
+    }
+}
@@ -0,0 +1,498 @@
+//! Host-side attestation policies.
+//!
+//! An AttestationPolicy collects the rules a verifier applies to every
+//! document — required PCR values, allowed module ids, maximum document
+//! age, nonce requirements and trust anchors — and evaluate() reports each
+//! rule that failed, so a rejection says exactly why.
+//!
+//! ```js
+//! const policy = new AttestationPolicy()
+//!   .requirePcr(0, pcr0)
+//!   .allowModuleId('i-*-enc*')
+//!   .maxAgeMs(5 * 60_000)
+//!   .requireNonce(nonce);
+//! const { valid, failures } = policy.evaluate(document);
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::BTreeMap;
+
+use crate::attestation::{self, CoseSign1, Document};
+use crate::sigv4;
+
+#[napi(object)]
+pub struct PolicyFailure {
+    /// "signature", "chain", "pcr<N>", "moduleId", "maxAge" or "nonce";
+    /// verifyAttestedCertificate() adds "certificate" and "publicKey".
+    pub rule: String,
+    pub message: String,
+}
+
+#[napi(object)]
+pub struct PolicyResult {
+    /// True when no rule failed.
+    pub valid: bool,
+    pub failures: Vec<PolicyFailure>,
+    pub document: attestation::AttestationDocument,
+}
+
+#[derive(Clone, Debug)]
+enum NonceRule {
+    Any,
+    Present,
+    Equals(Vec<u8>),
+}
+
+/// napi-free form of AttestationPolicy.
+#[derive(Clone, Debug)]
+pub(crate) struct Policy {
+    /// PCR index → accepted values.
+    pcrs: BTreeMap<u32, Vec<Vec<u8>>>,
+    /// Accepted module ids; `*` matches any run of characters.
+    module_ids: Vec<String>,
+    max_age_ms: Option<i64>,
+    nonce: NonceRule,
+    trusted_roots: Vec<Vec<u8>>,
+    use_aws_root: bool,
+}
+
+/// napi-free forms of PolicyFailure and PolicyResult.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Failure {
+    pub(crate) rule: String,
+    pub(crate) message: String,
+}
+
+pub(crate) struct Evaluation {
+    pub(crate) failures: Vec<Failure>,
+    pub(crate) document: Document,
+}
+
+impl Evaluation {
+    pub(crate) fn into_js(self) -> PolicyResult {
+        PolicyResult {
+            valid: self.failures.is_empty(),
+            failures: self
+                .failures
+                .into_iter()
+                .map(|f| PolicyFailure {
+                    rule: f.rule,
+                    message: f.message,
+                })
+                .collect(),
+            document: self.document.into_js(),
+        }
+    }
+}
+
+/// One rule applied to a document, passed or not; `message` says what was
+/// found either way.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Check {
+    pub(crate) rule: String,
+    pub(crate) passed: bool,
+    pub(crate) message: String,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            pcrs: BTreeMap::new(),
+            module_ids: Vec::new(),
+            max_age_ms: None,
+            nonce: NonceRule::Any,
+            trusted_roots: Vec::new(),
+            use_aws_root: true,
+        }
+    }
+}
+
+impl Policy {
+    /// Replace the nonce rule with `nonce`, for protocols that issue their
+    /// own.
+    pub(crate) fn require_nonce(&mut self, nonce: Vec<u8>) {
+        self.nonce = NonceRule::Equals(nonce);
+    }
+
+    pub(crate) fn evaluate(&self, cose: &[u8], time_ms: i64) -> Result<Evaluation> {
+        let (checks, document) = self.check(cose, time_ms)?;
+        let failures = checks
+            .into_iter()
+            .filter(|check| !check.passed)
+            .map(|check| Failure {
+                rule: check.rule,
+                message: check.message,
+            })
+            .collect();
+        Ok(Evaluation { failures, document })
+    }
+
+    /// Apply every rule to `cose` at `time_ms`, in the order evaluate()
+    /// reports failures.
+    pub(crate) fn check(&self, cose: &[u8], time_ms: i64) -> Result<(Vec<Check>, Document)> {
+        let trusted_roots: Vec<&[u8]> = self.trusted_roots.iter().map(Vec::as_slice).collect();
+        let roots = attestation::trust_anchors(&trusted_roots, self.use_aws_root)?;
+        let sign1 = CoseSign1::parse(cose)?;
+        let document = Document::parse(&sign1.payload)?;
+
+        let mut checks = Vec::new();
+        let mut check = |rule: &str, result: std::result::Result<String, String>| {
+            let passed = result.is_ok();
+            checks.push(Check {
+                rule: rule.to_string(),
+                passed,
+                message: result.unwrap_or_else(|e| e),
+            })
+        };
+
+        let (signature, chain) = attestation::authenticate(&sign1, &document, &roots, time_ms);
+        check(
+            "signature",
+            signature
+                .map(|()| "ES384 signature verifies under the signing certificate".to_string())
+                .map_err(|e| e.reason),
+        );
+        check(
+            "chain",
+            chain
+                .map(|()| "Signing certificate chains to a trusted root".to_string())
+                .map_err(|e| e.reason),
+        );
+
+        for (index, accepted) in &self.pcrs {
+            let rule = format!("pcr{}", index);
+            match document.pcrs.get(index) {
+                Some(value) if accepted.contains(value) => check(
+                    &rule,
+                    Ok(format!("PCR{} matches ({})", index, sigv4::hex(value))),
+                ),
+                Some(_) => check(&rule, Err(format!("PCR{} does not match", index))),
+                None => check(&rule, Err(format!("PCR{} is missing", index))),
+            }
+        }
+
+        if !self.module_ids.is_empty() {
+            let allowed = self
+                .module_ids
+                .iter()
+                .any(|pattern| glob_match(pattern, &document.module_id));
+            let message = format!(
+                "Module id {} is {}allowed",
+                document.module_id,
+                if allowed { "" } else { "not " }
+            );
+            check("moduleId", if allowed { Ok(message) } else { Err(message) });
+        }
+
+        if let Some(max_age_ms) = self.max_age_ms {
+            let age_ms = time_ms - document.timestamp as i64;
+            if age_ms < -attestation::MAX_CLOCK_SKEW_MS {
+                check(
+                    "maxAge",
+                    Err(format!(
+                        "Document is dated {}ms in the future, more than the {}ms clock skew allowed",
+                        -age_ms,
+                        attestation::MAX_CLOCK_SKEW_MS
+                    )),
+                );
+            } else if age_ms > max_age_ms {
+                check(
+                    "maxAge",
+                    Err(format!(
+                        "Document is {}ms old, more than the {}ms allowed",
+                        age_ms, max_age_ms
+                    )),
+                );
+            } else {
+                check(
+                    "maxAge",
+                    Ok(format!(
+                        "Document is {}ms old, within the {}ms allowed",
+                        age_ms, max_age_ms
+                    )),
+                );
+            }
+        }
+
+        match (&self.nonce, &document.nonce) {
+            (NonceRule::Any, _) => {}
+            (NonceRule::Present, Some(_)) => {
+                check("nonce", Ok("Document carries a nonce".to_string()))
+            }
+            (NonceRule::Equals(expected), Some(nonce)) if nonce == expected => {
+                check("nonce", Ok("Nonce matches the required nonce".to_string()))
+            }
+            (_, None) => check("nonce", Err("Document has no nonce".to_string())),
+            (NonceRule::Equals(_), Some(_)) => check(
+                "nonce",
+                Err("Nonce does not match the required nonce".to_string()),
+            ),
+        }
+
+        Ok((checks, document))
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty(); // no `*` at all
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Rules for accepting attestation documents. Each setter returns the
+/// policy, so calls chain.
+#[napi]
+pub struct AttestationPolicy {
+    inner: Policy,
+}
+
+impl AttestationPolicy {
+    pub(crate) fn policy(&self) -> Policy {
+        self.inner.clone()
+    }
+}
+
+#[napi]
+impl AttestationPolicy {
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        AttestationPolicy {
+            inner: Policy::default(),
+        }
+    }
+
+    /// Require PCR `index` to equal `value`. Call again with the same
+    /// index to accept several values, e.g. during a rollout.
+    #[napi]
+    pub fn require_pcr(&mut self, this: This, index: u32, value: Buffer) -> This {
+        self.inner
+            .pcrs
+            .entry(index)
+            .or_default()
+            .push(value.to_vec());
+        this
+    }
+
+    /// Accept documents whose module_id matches `pattern`, where `*`
+    /// matches any run of characters. With no patterns, any module id is
+    /// accepted.
+    #[napi]
+    pub fn allow_module_id(&mut self, this: This, pattern: String) -> This {
+        self.inner.module_ids.push(pattern);
+        this
+    }
+
+    /// Reject documents whose timestamp is more than `ms` before the
+    /// evaluation time, or more than 5 seconds after it.
+    #[napi]
+    pub fn max_age_ms(&mut self, this: This, ms: f64) -> Result<This> {
+        if ms.is_nan() || ms < 0.0 {
+            return Err(Error::from_reason("maxAgeMs must be a non-negative number"));
+        }
+        self.inner.max_age_ms = Some(ms as i64);
+        Ok(this)
+    }
+
+    /// Require a nonce: any nonce, or exactly `nonce` when given.
+    #[napi]
+    pub fn require_nonce(&mut self, this: This, nonce: Option<Buffer>) -> This {
+        self.inner.nonce = match nonce {
+            Some(nonce) => NonceRule::Equals(nonce.to_vec()),
+            None => NonceRule::Present,
+        };
+        this
+    }
+
+    /// Trust an additional root certificate (PEM or DER), as
+    /// verifyAttestation()'s trustedRoots.
+    #[napi]
+    pub fn trust_root(&mut self, this: This, root: Buffer) -> This {
+        self.inner.trusted_roots.push(root.to_vec());
+        this
+    }
+
+    /// Whether to trust the embedded AWS Nitro root (default true).
+    #[napi]
+    pub fn use_aws_root(&mut self, this: This, enabled: bool) -> This {
+        self.inner.use_aws_root = enabled;
+        this
+    }
+
+    /// Evaluate `document` (COSE_Sign1 bytes) at `timeMs` (default now).
+    /// Only a document that can't be decoded, or a policy with no trust
+    /// anchors, throws; failed rules are listed in the result.
+    #[napi]
+    pub fn evaluate(&self, document: Buffer, time_ms: Option<f64>) -> Result<PolicyResult> {
+        let time_ms = time_ms
+            .map(|t| t as i64)
+            .unwrap_or_else(attestation::now_ms);
+        Ok(self.inner.evaluate(&document, time_ms)?.into_js())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    /// A policy trusting only the test chain in testdata/attestation.
+    pub(crate) fn test_policy() -> Policy {
+        Policy {
+            trusted_roots: vec![include_bytes!("../testdata/attestation/root.pem").to_vec()],
+            use_aws_root: false,
+            ..Policy::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::test_policy;
+    use super::*;
+    use crate::attestation::fixtures::*;
+    use ciborium::value::Value;
+
+    fn rules(evaluation: &Evaluation) -> Vec<&str> {
+        evaluation
+            .failures
+            .iter()
+            .map(|f| f.rule.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn passing_document_has_no_failures() {
+        let mut policy = test_policy();
+        policy.pcrs.insert(0, vec![vec![2; 48], vec![1; 48]]);
+        policy.module_ids.push("i-*-enc*".to_string());
+        policy.max_age_ms = Some(60_000);
+        policy.nonce = NonceRule::Equals(vec![9; 8]);
+
+        let cose = signed_document(|entries| {
+            for (key, value) in entries.iter_mut() {
+                if key == &Value::Text("timestamp".into()) {
+                    *value = Value::Integer((JAN_2030_MS - 30_000).into());
+                }
+            }
+        });
+        let evaluation = policy.evaluate(&cose, JAN_2030_MS).unwrap();
+        assert_eq!(evaluation.failures, vec![]);
+    }
+
+    #[test]
+    fn checks_say_what_passed() {
+        let mut policy = test_policy();
+        policy.pcrs.insert(16, vec![vec![0; 48]]);
+        policy.module_ids.push("i-*".to_string());
+        policy.nonce = NonceRule::Present;
+        let (checks, _) = policy.check(&signed_document(|_| {}), JAN_2030_MS).unwrap();
+        let rules: Vec<_> = checks.iter().map(|c| (c.rule.as_str(), c.passed)).collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("signature", true),
+                ("chain", true),
+                ("pcr16", true),
+                ("moduleId", true),
+                ("nonce", true)
+            ]
+        );
+        assert_eq!(
+            checks[2].message,
+            format!("PCR16 matches ({})", "00".repeat(48))
+        );
+        assert_eq!(checks[3].message, "Module id i-0123-enc0123 is allowed");
+    }
+
+    #[test]
+    fn each_failed_rule_is_reported() {
+        let mut policy = test_policy();
+        policy.pcrs.insert(0, vec![vec![2; 48]]);
+        policy.pcrs.insert(8, vec![vec![0; 48]]);
+        policy.module_ids.push("i-prod-*".to_string());
+        policy.max_age_ms = Some(1000);
+        policy.nonce = NonceRule::Equals(vec![1; 8]);
+
+        let evaluation = policy
+            .evaluate(&signed_document(|_| {}), JAN_2030_MS)
+            .unwrap();
+        assert_eq!(
+            rules(&evaluation),
+            vec!["pcr0", "pcr8", "moduleId", "maxAge", "nonce"]
+        );
+        assert_eq!(evaluation.failures[1].message, "PCR8 is missing");
+    }
+
+    #[test]
+    fn future_dated_documents_fail_the_age_rule() {
+        let mut policy = test_policy();
+        policy.max_age_ms = Some(60_000);
+        let cose = signed_document(|entries| {
+            for (key, value) in entries.iter_mut() {
+                if key == &Value::Text("timestamp".into()) {
+                    *value = Value::Integer(JAN_2030_MS.into());
+                }
+            }
+        });
+
+        let skew = attestation::MAX_CLOCK_SKEW_MS;
+        let evaluation = policy.evaluate(&cose, JAN_2030_MS - skew).unwrap();
+        assert_eq!(evaluation.failures, vec![]);
+
+        let evaluation = policy.evaluate(&cose, JAN_2030_MS - skew - 1).unwrap();
+        assert_eq!(rules(&evaluation), vec!["maxAge"]);
+        assert_eq!(
+            evaluation.failures[0].message,
+            "Document is dated 5001ms in the future, more than the 5000ms clock skew allowed"
+        );
+    }
+
+    #[test]
+    fn missing_nonce_fails_a_presence_requirement() {
+        let mut policy = test_policy();
+        policy.nonce = NonceRule::Present;
+        let cose = signed_document(|entries| {
+            entries.retain(|(k, _)| k != &Value::Text("nonce".into()));
+        });
+        let evaluation = policy.evaluate(&cose, JAN_2030_MS).unwrap();
+        assert_eq!(rules(&evaluation), vec!["nonce"]);
+    }
+
+    #[test]
+    fn untrusted_chain_fails_the_chain_rule() {
+        let policy = Policy::default(); // AWS root only
+        let evaluation = policy
+            .evaluate(&signed_document(|_| {}), JAN_2030_MS)
+            .unwrap();
+        assert_eq!(rules(&evaluation), vec!["chain"]);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("i-*-enc*", "i-0123-enc0456"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(!glob_match("a*bc", "abc-bx"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+}
@@ -0,0 +1,219 @@
+//! Read buffers handed to JS.
+//!
+//! Reads are returned as external Buffers over Rust-owned memory — no copy
+//! into the V8 heap. The allocation is reported to V8 as external memory so
+//! GC is scheduled with its real size in mind, and the Buffer's finalizer
+//! releases it.
+//!
+//! In pooled mode VsockStream.read() fills a recycled chunk instead, and the
+//! finalizer returns the chunk to the pool, so steady-state relays stop
+//! allocating a fresh Vec per read.
+
+use napi::bindgen_prelude::*;
+use napi::{Env, JsBuffer};
+use napi_derive::napi;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+const DEFAULT_MAX_POOLED: u32 = 64;
+
+#[napi(object)]
+pub struct ReadBufferPoolOptions {
+    /// Size of each pooled chunk (default 64 KiB). Pooled reads return at
+    /// most this many bytes.
+    pub chunk_size: Option<u32>,
+    /// Maximum number of idle chunks kept for reuse (default 64). Chunks
+    /// released beyond this are freed.
+    pub max_pooled: Option<u32>,
+}
+
+pub(crate) struct BufferPool {
+    chunk_size: usize,
+    max_pooled: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    /// Chunks currently lent to JS.
+    outstanding: AtomicI64,
+    /// Chunks allocated because the pool was empty.
+    allocated: AtomicI64,
+}
+
+impl BufferPool {
+    pub(crate) fn new(chunk_size: usize, max_pooled: usize) -> Self {
+        BufferPool {
+            chunk_size,
+            max_pooled,
+            free: Mutex::new(Vec::new()),
+            outstanding: AtomicI64::new(0),
+            allocated: AtomicI64::new(0),
+        }
+    }
+
+    pub(crate) fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Take a chunk (len == chunk_size), reusing an idle one if available.
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        if let Some(chunk) = self.free.lock().unwrap().pop() {
+            return chunk;
+        }
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        vec![0u8; self.chunk_size]
+    }
+
+    /// Return a chunk taken with `take`.
+    pub(crate) fn give_back(&self, chunk: Vec<u8>) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_pooled {
+            free.push(chunk);
+        }
+    }
+
+    /// Wrap the first `len` bytes of `chunk` in an external Buffer that
+    /// returns the chunk to the pool when collected.
+    pub(crate) fn lend(self: &Arc<Self>, env: &Env, mut chunk: Vec<u8>, len: usize) -> Result<JsBuffer> {
+        let (ptr, capacity) = (chunk.as_mut_ptr(), chunk.capacity());
+        let pool = self.clone();
+        lend_external(env, ptr, len, capacity, chunk, move |chunk| pool.give_back(chunk))
+    }
+}
+
+/// Hand `data` to JS as an external Buffer without copying it.
+pub(crate) fn external_buffer(env: &Env, mut data: Vec<u8>) -> Result<JsBuffer> {
+    if data.is_empty() {
+        // V8 rejects external buffers over Rust's dangling empty-Vec pointer
+        return env.create_buffer(0).map(|b| b.into_raw());
+    }
+    let (ptr, len, capacity) = (data.as_mut_ptr(), data.len(), data.capacity());
+    lend_external(env, ptr, len, capacity, data, drop)
+}
+
+/// Create an external Buffer over `ptr[..len]`, owned by `owner` until the
+/// finalizer passes it to `release`. `capacity` bytes are reported to V8 as
+/// external memory for the Buffer's lifetime.
+///
+/// Where external buffers are disallowed, napi copies the bytes and runs
+/// the finalizer immediately.
+fn lend_external<H, F>(
+    env: &Env,
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+    owner: H,
+    release: F,
+) -> Result<JsBuffer>
+where
+    H: 'static,
+    F: FnOnce(H) + 'static,
+{
+    let mut reporting_env = *env;
+    let _ = reporting_env.adjust_external_memory(capacity as i64);
+    // SAFETY: `ptr` points into `owner`'s heap allocation, which moves into
+    // the finalizer hint unchanged and is only released by the finalizer.
+    unsafe {
+        env.create_buffer_with_borrowed_data(ptr, len, owner, move |owner, mut env| {
+            let _ = env.adjust_external_memory(-(capacity as i64));
+            release(owner);
+        })
+    }
+    .map(|b| b.into_raw())
+}
+
+/// A pool of read buffers shared by any number of streams.
+/// Attach it with `stream.setReadPool(pool)`.
+#[napi]
+pub struct ReadBufferPool {
+    inner: Arc<BufferPool>,
+}
+
+#[napi]
+impl ReadBufferPool {
+    #[napi(constructor)]
+    pub fn new(options: Option<ReadBufferPoolOptions>) -> Result<Self> {
+        let (chunk_size, max_pooled) = options
+            .map(|o| (o.chunk_size, o.max_pooled))
+            .unwrap_or((None, None));
+        let chunk_size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        if chunk_size == 0 {
+            return Err(Error::from_reason("chunkSize must be greater than 0"));
+        }
+        Ok(ReadBufferPool {
+            inner: Arc::new(BufferPool::new(
+                chunk_size as usize,
+                max_pooled.unwrap_or(DEFAULT_MAX_POOLED) as usize,
+            )),
+        })
+    }
+
+    /// Size of each chunk.
+    #[napi(getter)]
+    pub fn chunk_size(&self) -> u32 {
+        self.inner.chunk_size as u32
+    }
+
+    /// Idle chunks ready for reuse.
+    #[napi(getter)]
+    pub fn pooled(&self) -> u32 {
+        self.inner.free.lock().unwrap().len() as u32
+    }
+
+    /// Chunks currently held by live Buffers.
+    #[napi(getter)]
+    pub fn outstanding(&self) -> i64 {
+        self.inner.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Chunks allocated since creation (a pool that keeps growing this
+    /// is too small for the workload).
+    #[napi(getter)]
+    pub fn allocated(&self) -> i64 {
+        self.inner.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl ReadBufferPool {
+    pub(crate) fn shared(&self) -> Arc<BufferPool> {
+        self.inner.clone()
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_reused() {
+        let pool = BufferPool::new(16, 4);
+        let mut chunk = pool.take();
+        assert_eq!(chunk.len(), 16);
+        chunk[0] = 42;
+        let ptr = chunk.as_ptr();
+        pool.give_back(chunk);
+
+        let again = pool.take();
+        assert_eq!(again.as_ptr(), ptr, "idle chunk should be handed out again");
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.outstanding.load(Ordering::Relaxed), 1);
+        pool.give_back(again);
+        assert_eq!(pool.outstanding.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn idle_chunks_are_capped() {
+        let pool = BufferPool::new(8, 2);
+        let chunks: Vec<_> = (0..5).map(|_| pool.take()).collect();
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 5);
+        for chunk in chunks {
+            pool.give_back(chunk);
+        }
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+        assert_eq!(pool.outstanding.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,242 @@
+//! vsock port probing for host tooling.
+//!
+//! probePort() makes one non-blocking connect to (cid, port) and reports
+//! whether something is listening, without speaking to it; scanPorts()
+//! does the same for a list of ports in parallel. Useful to find out
+//! which services an enclave exposes, or to fail fast with a better
+//! message than a hung connect:
+//!
+//! ```js
+//! const results = await scanPorts(16, [5000, 5005, 5010], { timeoutMs: 500 });
+//! for (const { port, status, error } of results) console.log(port, status, error ?? '');
+//! ```
+//!
+//! `status` is "open" (the connection was accepted, then closed at once),
+//! "closed" (refused: nothing listens there), "timeout" (no answer within
+//! timeoutMs, e.g. a full backlog) or "error" (e.g. no such CID; see
+//! `error`).
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::time::{Duration, Instant};
+
+use crate::workers::WorkerTask;
+use crate::{errors, vsock};
+
+const DEFAULT_TIMEOUT_MS: u32 = 1000;
+const DEFAULT_CONCURRENCY: u32 = 16;
+const MAX_CONCURRENCY: u32 = 256;
+
+#[napi(object)]
+#[derive(Debug)]
+pub struct PortProbe {
+    pub port: u32,
+    /// "open", "closed", "timeout" or "error".
+    pub status: String,
+    /// How long the connect took to succeed or fail.
+    pub elapsed_ms: f64,
+    /// The `.code` of the failure, e.g. "EHOSTUNREACH", for "error".
+    pub code: Option<String>,
+    /// Why the connect failed, for everything but "open".
+    pub error: Option<String>,
+}
+
+#[napi(object)]
+pub struct ScanPortsOptions {
+    /// Per-port connect timeout (default 1000).
+    pub timeout_ms: Option<u32>,
+    /// Ports probed at once (default 16, at most 256).
+    pub concurrency: Option<u32>,
+}
+
+/// Try one connect to (cid, port) and classify the outcome.
+fn probe(cid: u32, port: u32, timeout: Duration) -> PortProbe {
+    let started = Instant::now();
+    let result = vsock::connect_timeout(cid, port, timeout);
+    classify(port, result, started.elapsed())
+}
+
+fn classify(port: u32, result: Result<i32>, elapsed: Duration) -> PortProbe {
+    let (status, code, error) = match result {
+        Ok(fd) => {
+            unsafe {
+                libc::close(fd);
+            }
+            ("open", None, None)
+        }
+        Err(err) => {
+            let code = errors::reason_code(&err.reason).map(str::to_string);
+            let status = match code.as_deref() {
+                Some("ECONNREFUSED" | "ECONNRESET") => "closed",
+                Some("ETIMEDOUT") => "timeout",
+                _ => "error",
+            };
+            (status, code, Some(err.reason))
+        }
+    };
+    PortProbe {
+        port,
+        status: status.to_string(),
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        code,
+        error,
+    }
+}
+
+/// Run `probe` for `ports` on `threads` threads at once, keeping their
+/// order.
+fn scan<F>(ports: &[u32], threads: usize, probe: F) -> Vec<PortProbe>
+where
+    F: Fn(u32) -> PortProbe + Sync,
+{
+    let probe = &probe;
+    let mut results = Vec::with_capacity(ports.len());
+    for batch in ports.chunks(threads.max(1)) {
+        std::thread::scope(|scope| {
+            let probes: Vec<_> = batch
+                .iter()
+                .map(|&port| scope.spawn(move || probe(port)))
+                .collect();
+            results.extend(probes.into_iter().map(|probe| probe.join().unwrap()));
+        });
+    }
+    results
+}
+
+fn timeout_from_ms(timeout_ms: Option<u32>) -> Result<Duration> {
+    match timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS) {
+        0 => Err(Error::from_reason("timeoutMs must be at least 1")),
+        ms => Ok(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// Check whether anything listens on (cid, port), waiting at most
+/// `timeoutMs` (default 1000). Never rejects for the probe's outcome.
+#[napi(ts_return_type = "Promise<PortProbe>")]
+pub fn probe_port(cid: u32, port: u32, timeout_ms: Option<u32>) -> Result<WorkerTask<ProbeTask>> {
+    Ok(WorkerTask::new(ProbeTask {
+        cid,
+        ports: vec![port],
+        timeout: timeout_from_ms(timeout_ms)?,
+        concurrency: 1,
+    }))
+}
+
+/// probePort() for each of `ports`, several at once. Resolves to one
+/// PortProbe per port, in the order given.
+#[napi(ts_return_type = "Promise<PortProbe[]>")]
+pub fn scan_ports(
+    cid: u32,
+    ports: Vec<u32>,
+    options: Option<ScanPortsOptions>,
+) -> Result<WorkerTask<ScanTask>> {
+    let (timeout_ms, concurrency) = options.map_or((None, None), |o| (o.timeout_ms, o.concurrency));
+    let concurrency = concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+    if !(1..=MAX_CONCURRENCY).contains(&concurrency) {
+        return Err(Error::from_reason(format!(
+            "concurrency must be 1 to {}",
+            MAX_CONCURRENCY
+        )));
+    }
+    Ok(WorkerTask::new(ScanTask(ProbeTask {
+        cid,
+        ports,
+        timeout: timeout_from_ms(timeout_ms)?,
+        concurrency: concurrency as usize,
+    })))
+}
+
+pub struct ProbeTask {
+    cid: u32,
+    ports: Vec<u32>,
+    timeout: Duration,
+    concurrency: usize,
+}
+
+impl Task for ProbeTask {
+    type Output = Vec<PortProbe>;
+    type JsValue = PortProbe;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let (cid, timeout) = (self.cid, self.timeout);
+        Ok(scan(&self.ports, self.concurrency, |port| {
+            probe(cid, port, timeout)
+        }))
+    }
+
+    fn resolve(&mut self, _env: Env, mut probes: Self::Output) -> Result<Self::JsValue> {
+        probes
+            .pop()
+            .ok_or_else(|| Error::from_reason("No port probed"))
+    }
+}
+
+pub struct ScanTask(ProbeTask);
+
+impl Task for ScanTask {
+    type Output = Vec<PortProbe>;
+    type JsValue = Vec<PortProbe>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        self.0.compute()
+    }
+
+    fn resolve(&mut self, _env: Env, probes: Self::Output) -> Result<Self::JsValue> {
+        Ok(probes)
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn connect_error(errno: i32) -> Result<i32> {
+        Err(errors::os_error(
+            "connect(cid=16, port=5000)",
+            std::io::Error::from_raw_os_error(errno),
+        ))
+    }
+
+    #[test]
+    fn classifies_connect_outcomes() {
+        let elapsed = Duration::from_millis(3);
+        let (conn, _peer) = UnixStream::pair().unwrap();
+        let open = classify(5000, Ok(conn.into_raw_fd()), elapsed);
+        assert_eq!((open.status.as_str(), open.elapsed_ms), ("open", 3.0));
+        assert!(open.error.is_none());
+
+        let closed = classify(5000, connect_error(libc::ECONNREFUSED), elapsed);
+        assert_eq!(closed.status, "closed");
+        assert_eq!(closed.code.as_deref(), Some("ECONNREFUSED"));
+
+        let timeout = Err(Error::from_reason(
+            "ETIMEDOUT: connect(cid=16, port=5000) timed out after 500ms",
+        ));
+        assert_eq!(classify(5000, timeout, elapsed).status, "timeout");
+
+        let unreachable = classify(5000, connect_error(libc::EHOSTUNREACH), elapsed);
+        assert_eq!(unreachable.status, "error");
+        assert_eq!(unreachable.code.as_deref(), Some("EHOSTUNREACH"));
+        assert!(unreachable
+            .error
+            .unwrap()
+            .contains("connect(cid=16, port=5000)"));
+    }
+
+    #[test]
+    fn scans_keep_the_port_order() {
+        let ports: Vec<u32> = (5000..5020).collect();
+        let results = scan(&ports, 3, |port| {
+            // Later ports finish first
+            std::thread::sleep(Duration::from_millis((5020 - port) as u64));
+            classify(port, connect_error(libc::ECONNREFUSED), Duration::ZERO)
+        });
+        assert_eq!(results.iter().map(|p| p.port).collect::<Vec<_>>(), ports);
+    }
+}
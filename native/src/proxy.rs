@@ -0,0 +1,587 @@
+//! Native TCP ↔ vsock forwarders.
+//!
+//! VsockToTcpProxy is the host-side companion that replaces a separate
+//! socat/vsock-proxy deployment: it listens on a vsock port and forwards
+//! each connection to a fixed TCP host:port.
+//!
+//! TcpToVsockProxy is the enclave-side counterpart: it listens on loopback
+//! TCP and tunnels each connection to (hostCid, vsockPort), so unmodified
+//! HTTP clients inside the enclave can reach the host proxy.
+//!
+//! UnixVsockBridge maps a unix socket path to a (cid, port) in either
+//! direction, for tools that only speak unix sockets.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::IntoRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::relay::{dup_fd, spawn_relay};
+use crate::server::AcceptLoop;
+use crate::vsock;
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 5000;
+
+#[napi(object)]
+pub struct VsockToTcpProxyOptions {
+    /// vsock port to listen on.
+    pub vsock_port: u32,
+    /// TCP destination host (DNS name or IP), resolved per connection.
+    pub tcp_host: String,
+    pub tcp_port: u32,
+    /// Optional allowlist of "host" or "host:port" entries, as in
+    /// vsock-proxy's config, with IPv6 literals bare or as "[v6]:port".
+    /// When set, the destination must match an entry, and each connection
+    /// only dials addresses that a matching entry also resolves to.
+    pub allowlist: Option<Vec<String>>,
+    /// TCP connect timeout per connection (default 5000ms).
+    pub connect_timeout_ms: Option<u32>,
+}
+
+/// Counters shared by a proxy's connection threads.
+#[derive(Default)]
+pub(crate) struct ProxyCounters {
+    pub(crate) accepted: AtomicI64,
+    pub(crate) active: AtomicI64,
+    pub(crate) dial_failures: AtomicI64,
+    pub(crate) rejected: AtomicI64,
+    pub(crate) bytes_out: AtomicI64,
+    pub(crate) bytes_in: AtomicI64,
+}
+
+impl ProxyCounters {
+    pub(crate) fn snapshot(&self) -> ProxyStats {
+        ProxyStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            dial_failures: self.dial_failures.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[napi(object)]
+pub struct ProxyStats {
+    /// Connections accepted since start.
+    pub accepted: i64,
+    /// Connections currently being relayed.
+    pub active: i64,
+    /// Connections dropped because the destination could not be reached.
+    pub dial_failures: i64,
+    /// Requests refused before dialing (malformed or not allowlisted);
+    /// counted by the protocol-aware proxies, and by VsockToTcpProxy when
+    /// its host stops resolving to an allowlisted address.
+    pub rejected: i64,
+    /// Bytes forwarded from the listening side to the destination
+    /// (completed connections only).
+    pub bytes_out: i64,
+    /// Bytes forwarded from the destination back to the listening side
+    /// (completed connections only).
+    pub bytes_in: i64,
+}
+
+/// Forward `conn` (owned) to `remote` (owned) on a native relay,
+/// accounting into `counters`.
+pub(crate) fn forward(conn: i32, remote: i32, counters: Arc<ProxyCounters>) {
+    counters.active.fetch_add(1, Ordering::Relaxed);
+    let done = counters.clone();
+    let relay = spawn_relay(conn, remote, move |result| {
+        done.bytes_out.fetch_add(result.bytes_a_to_b, Ordering::Relaxed);
+        done.bytes_in.fetch_add(result.bytes_b_to_a, Ordering::Relaxed);
+        done.active.fetch_sub(1, Ordering::Relaxed);
+    });
+    if relay.is_err() {
+        counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Host-side vsock → TCP forwarder.
+#[napi]
+pub struct VsockToTcpProxy {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+}
+
+#[napi]
+impl VsockToTcpProxy {
+    /// Validate the destination against the allowlist, bind the vsock port
+    /// and start forwarding on native threads.
+    #[napi(factory)]
+    pub fn start(options: VsockToTcpProxyOptions) -> Result<Self> {
+        let port = u16::try_from(options.tcp_port)
+            .map_err(|_| Error::from_reason(format!("Invalid TCP port: {}", options.tcp_port)))?;
+        if let Some(allowlist) = &options.allowlist {
+            if !allowlist_permits(allowlist, &options.tcp_host, port) {
+                return Err(Error::from_reason(format!(
+                    "Destination {}:{} is not in the proxy allowlist",
+                    options.tcp_host, port
+                )));
+            }
+        }
+
+        let host = options.tcp_host;
+        let allowlist = options.allowlist;
+        let timeout = Duration::from_millis(
+            options.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS) as u64,
+        );
+        let counters = Arc::new(ProxyCounters::default());
+        let listener_fd = vsock::listen_raw(options.vsock_port)?;
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn(listener_fd, move |conn, _cid, _port| {
+            loop_counters.accepted.fetch_add(1, Ordering::Relaxed);
+            let dialed = match &allowlist {
+                Some(allowlist) => dial_tcp_allowlisted(&host, port, allowlist, timeout),
+                None => dial_tcp(&host, port, timeout),
+            };
+            let remote = match dialed {
+                Ok(fd) => fd,
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    loop_counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(_) => {
+                    loop_counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            };
+            forward_accepted(conn, remote, &loop_counters);
+        })?;
+
+        Ok(VsockToTcpProxy {
+            accept_loop,
+            counters,
+        })
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new connections. In-flight relays run until either
+    /// side closes. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+#[napi(object)]
+pub struct TcpToVsockProxyOptions {
+    /// Loopback TCP port to listen on (0 picks an ephemeral port; see `port`).
+    pub listen_port: u32,
+    /// Destination CID (3 = host/parent from inside the enclave).
+    pub cid: u32,
+    pub vsock_port: u32,
+    /// vsock connect timeout per connection (default 5s).
+    pub connect_timeout_secs: Option<u32>,
+}
+
+/// Enclave-side TCP (127.0.0.1) → vsock forwarder.
+#[napi]
+pub struct TcpToVsockProxy {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+    port: u32,
+}
+
+#[napi]
+impl TcpToVsockProxy {
+    /// Bind 127.0.0.1:listenPort and start tunnelling on native threads.
+    #[napi(factory)]
+    pub fn start(options: TcpToVsockProxyOptions) -> Result<Self> {
+        let listen_port = u16::try_from(options.listen_port)
+            .map_err(|_| Error::from_reason(format!("Invalid TCP port: {}", options.listen_port)))?;
+        let listener = TcpListener::bind(("127.0.0.1", listen_port)).map_err(|e| {
+            Error::from_reason(format!("bind(127.0.0.1:{}) failed: {}", listen_port, e))
+        })?;
+        let port = listener
+            .local_addr()
+            .map(|a| a.port() as u32)
+            .unwrap_or(options.listen_port);
+
+        let (cid, vsock_port) = (options.cid, options.vsock_port);
+        let timeout_secs = options.connect_timeout_secs.unwrap_or(5);
+        let counters = Arc::new(ProxyCounters::default());
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn_any(listener.into_raw_fd(), move |conn, _, _| {
+            loop_counters.accepted.fetch_add(1, Ordering::Relaxed);
+            let remote = match vsock::connect_raw(cid, vsock_port, timeout_secs) {
+                Ok(fd) => fd,
+                Err(_) => {
+                    loop_counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            };
+            forward_accepted(conn, remote, &loop_counters);
+        })?;
+
+        Ok(TcpToVsockProxy {
+            accept_loop,
+            counters,
+            port,
+        })
+    }
+
+    /// The loopback TCP port actually bound.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new connections. In-flight tunnels run until either
+    /// side closes. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+#[napi(object)]
+pub struct UnixToVsockOptions {
+    /// Unix socket path to listen on. A stale socket at this path is replaced.
+    pub path: String,
+    /// File mode for the socket (e.g. 0o660). Defaults to the process umask.
+    pub mode: Option<u32>,
+    pub cid: u32,
+    pub port: u32,
+    /// vsock connect timeout per connection (default 5s).
+    pub connect_timeout_secs: Option<u32>,
+}
+
+#[napi(object)]
+pub struct VsockToUnixOptions {
+    /// vsock port to listen on.
+    pub vsock_port: u32,
+    /// Unix socket path to connect to for each accepted connection.
+    pub path: String,
+}
+
+/// Bridge between a unix socket path and a vsock (cid, port).
+#[napi]
+pub struct UnixVsockBridge {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+    /// Socket file created by this bridge, removed on close().
+    owned_path: Option<String>,
+}
+
+#[napi]
+impl UnixVsockBridge {
+    /// Listen on a unix socket and tunnel each connection to (cid, port).
+    #[napi(factory)]
+    pub fn unix_to_vsock(options: UnixToVsockOptions) -> Result<Self> {
+        let listener = bind_unix(&options.path, options.mode)?;
+        let (cid, port) = (options.cid, options.port);
+        let timeout_secs = options.connect_timeout_secs.unwrap_or(5);
+        let counters = Arc::new(ProxyCounters::default());
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn_any(listener.into_raw_fd(), move |conn, _, _| {
+            loop_counters.accepted.fetch_add(1, Ordering::Relaxed);
+            match vsock::connect_raw(cid, port, timeout_secs) {
+                Ok(remote) => forward_accepted(conn, remote, &loop_counters),
+                Err(_) => {
+                    loop_counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })?;
+
+        Ok(UnixVsockBridge {
+            accept_loop,
+            counters,
+            owned_path: Some(options.path),
+        })
+    }
+
+    /// Listen on a vsock port and connect each connection to a unix socket.
+    #[napi(factory)]
+    pub fn vsock_to_unix(options: VsockToUnixOptions) -> Result<Self> {
+        let path = options.path;
+        let counters = Arc::new(ProxyCounters::default());
+        let listener_fd = vsock::listen_raw(options.vsock_port)?;
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn(listener_fd, move |conn, _cid, _port| {
+            loop_counters.accepted.fetch_add(1, Ordering::Relaxed);
+            match UnixStream::connect(&path) {
+                Ok(remote) => forward_accepted(conn, remote.into_raw_fd(), &loop_counters),
+                Err(_) => {
+                    loop_counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })?;
+
+        Ok(UnixVsockBridge {
+            accept_loop,
+            counters,
+            owned_path: None,
+        })
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new connections (and remove the socket file this bridge
+    /// created). In-flight connections run until either side closes.
+    /// Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        if let Some(path) = &self.owned_path {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+/// Bind a unix listener, replacing a stale socket file but never any other
+/// kind of file.
+fn bind_unix(path: &str, mode: Option<u32>) -> Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::from_reason(format!(
+                "{} exists and is not a unix socket",
+                path
+            )));
+        }
+        let _ = std::fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| Error::from_reason(format!("bind(unix:{}) failed: {}", path, e)))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+            Error::from_reason(format!("chmod({}, {:o}) failed: {}", path, mode, e))
+        })?;
+    }
+    Ok(listener)
+}
+
+/// Hand an AcceptLoop connection and an owned remote fd to a relay.
+/// AcceptLoop closes `conn` when the handler returns, so the relay gets
+/// its own duplicate.
+pub(crate) fn forward_accepted(conn: i32, remote: i32, counters: &Arc<ProxyCounters>) {
+    match dup_fd(conn) {
+        Ok(conn) => forward(conn, remote, counters.clone()),
+        Err(_) => unsafe {
+            libc::close(remote);
+        },
+    }
+}
+
+/// Resolve `host` and connect to the first reachable address.
+/// Returns an owned fd.
+pub(crate) fn dial_tcp(host: &str, port: u16, timeout: Duration) -> std::io::Result<i32> {
+    connect_first(host, (host, port).to_socket_addrs()?, timeout)
+}
+
+/// dial_tcp(), but only to addresses that an allowlist entry matching
+/// `port` resolves to, so a DNS change for `host` cannot move the
+/// destination outside the allowlist. Fails with PermissionDenied when no
+/// resolved address qualifies.
+fn dial_tcp_allowlisted(
+    host: &str,
+    port: u16,
+    allowlist: &[String],
+    timeout: Duration,
+) -> std::io::Result<i32> {
+    let allowed: Vec<IpAddr> = allowlist
+        .iter()
+        .map(|entry| allowlist_entry(entry))
+        .filter(|(_, entry_port)| entry_port.is_none_or(|p| p == port))
+        .filter_map(|(entry_host, _)| (entry_host, port).to_socket_addrs().ok())
+        .flatten()
+        .map(|addr| addr.ip())
+        .collect();
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()?
+        .filter(|addr| allowed.contains(&addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} resolved to no allowlisted address", host),
+        ));
+    }
+    connect_first(host, addrs, timeout)
+}
+
+/// Connect to the first reachable of `host`'s resolved `addrs`.
+fn connect_first(
+    host: &str,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    timeout: Duration,
+) -> std::io::Result<i32> {
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                return Ok(stream.into_raw_fd());
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} resolved to no addresses", host),
+        )
+    }))
+}
+
+/// Match `host:port` against "host" / "host:port" entries (host compared
+/// case-insensitively). `host` is bare, as parse_target() leaves IPv6
+/// literals.
+pub(crate) fn allowlist_permits(allowlist: &[String], host: &str, port: u16) -> bool {
+    allowlist.iter().any(|entry| {
+        let (entry_host, entry_port) = allowlist_entry(entry);
+        entry_host.eq_ignore_ascii_case(host) && entry_port.is_none_or(|p| p == port)
+    })
+}
+
+/// Split an allowlist entry into host and optional port. IPv6 literals
+/// are bracketed to carry a port ("[::1]:443"); a bare one ("::1") has
+/// more than one colon and so is all host.
+fn allowlist_entry(entry: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = entry.strip_prefix('[') {
+        return match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => match rest.strip_prefix(':').map(str::parse::<u16>) {
+                Some(Ok(p)) => (host, Some(p)),
+                _ => (entry, None),
+            },
+            None => (entry, None),
+        };
+    }
+    if entry.matches(':').count() > 1 {
+        return (entry, None);
+    }
+    match entry.rsplit_once(':') {
+        Some((h, p)) => match p.parse::<u16>() {
+            Ok(p) => (h, Some(p)),
+            Err(_) => (entry, None),
+        },
+        None => (entry, None),
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::fd::FromRawFd;
+
+    fn list(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn allowlist_matches_host_with_optional_port() {
+        let allowlist = list(&["kms.us-east-1.amazonaws.com:443", "Example.com"]);
+        assert!(allowlist_permits(&allowlist, "kms.us-east-1.amazonaws.com", 443));
+        assert!(!allowlist_permits(&allowlist, "kms.us-east-1.amazonaws.com", 80));
+        assert!(allowlist_permits(&allowlist, "example.com", 8080));
+        assert!(!allowlist_permits(&allowlist, "evil.com", 443));
+        assert!(!allowlist_permits(&[], "example.com", 443));
+    }
+
+    #[test]
+    fn allowlist_matches_ipv6_literals() {
+        let allowlist = list(&["[fd00::1]:443", "[fd00::2]", "FD00::3"]);
+        assert!(allowlist_permits(&allowlist, "fd00::1", 443));
+        assert!(!allowlist_permits(&allowlist, "fd00::1", 80));
+        assert!(allowlist_permits(&allowlist, "fd00::2", 8080));
+        assert!(allowlist_permits(&allowlist, "fd00::3", 443));
+        assert!(!allowlist_permits(&allowlist, "fd00::4", 443));
+        // The bare literal's last group isn't a port
+        assert!(!allowlist_permits(&allowlist, "fd00:", 3));
+    }
+
+    #[test]
+    fn bind_unix_replaces_stale_sockets_only() {
+        let dir = std::env::temp_dir();
+        let sock = dir.join(format!("bridge-{}.sock", std::process::id()));
+        let sock = sock.to_str().unwrap();
+
+        drop(bind_unix(sock, Some(0o600)).unwrap());
+        let mode = std::fs::metadata(sock).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Stale socket left behind by a previous run is replaced
+        let listener = bind_unix(sock, None).unwrap();
+        drop(listener);
+        std::fs::remove_file(sock).unwrap();
+
+        let file = dir.join(format!("bridge-{}.txt", std::process::id()));
+        std::fs::write(&file, b"not a socket").unwrap();
+        let err = bind_unix(file.to_str().unwrap(), None).unwrap_err();
+        assert!(err.reason.contains("not a unix socket"));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn dial_tcp_connects_to_local_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let fd = dial_tcp("127.0.0.1", port, Duration::from_secs(1)).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+
+        let mut client = unsafe { TcpStream::from_raw_fd(fd) };
+        client.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    #[test]
+    fn dial_tcp_reports_refused_connections() {
+        // Bind then drop to get a port that is (almost certainly) closed
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert!(dial_tcp("127.0.0.1", port, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn allowlisted_dials_only_reach_addresses_entries_resolve_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timeout = Duration::from_secs(1);
+
+        let by_name = list(&[&format!("localhost:{}", port)]);
+        let fd = dial_tcp_allowlisted("127.0.0.1", port, &by_name, timeout).unwrap();
+        unsafe { libc::close(fd); }
+        let by_address = list(&["127.0.0.1"]);
+        let fd = dial_tcp_allowlisted("localhost", port, &by_address, timeout).unwrap();
+        unsafe { libc::close(fd); }
+
+        for allowlist in [list(&["192.0.2.1"]), list(&["127.0.0.1:1"]), list(&[])] {
+            let err = dial_tcp_allowlisted("127.0.0.1", port, &allowlist, timeout).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied, "{:?}", allowlist);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+//! RA-TLS: attestation documents carried in TLS certificates.
+//!
+//! generateAttestedCertificate() makes a fresh P-384 key and a self-signed
+//! certificate for it with an extra, non-critical extension whose value is
+//! an attestation document binding the certificate's subjectPublicKeyInfo
+//! (as the document's public_key). Any TLS server can present it; a client
+//! that accepts the document with verifyAttestedCertificate() knows the
+//! TLS peer is the attested enclave, with no custom handshake. The TLS
+//! handshake itself proves possession of the key.
+//!
+//! ```js
+//! // enclave
+//! const { certificate, privateKey } = generateAttestedCertificate({ dnsNames: ['enclave.local'] });
+//! const server = new TlsVsockServer({ certificateChain: certificate, privateKey });
+//! // client, with TlsVsockClient
+//! const tls = await TlsVsockClient.attested(policy).connect(stream, 'enclave.local');
+//! // or with node:tls
+//! const socket = tls.connect({ host, port, rejectUnauthorized: false }, () => {
+//!   if (!verifyAttestedCertificate(socket.getPeerCertificate().raw, policy).valid) socket.destroy();
+//! });
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rand_core::{OsRng, RngCore};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P384_SHA384_ASN1_SIGNING};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::sync::Arc;
+
+use crate::attestation;
+use crate::nsm::{self, coded, Device, NsmOptions};
+use crate::policy::{AttestationPolicy, Evaluation, Failure, Policy, PolicyResult};
+use crate::sigv4::civil_from_days;
+use crate::x509::{
+    self, OID_BASIC_CONSTRAINTS, OID_ECDSA_WITH_SHA384, OID_EC_PUBLIC_KEY, OID_SECP384R1,
+};
+
+/// 2.25.10767752396068513132301563457366162075 (UUID
+/// 0819cb2e-700f-48be-9266-738af3bc7a9b): the attestation document
+/// extension. Its extnValue is the COSE_Sign1 document itself.
+pub(crate) const OID_ATTESTATION_DOCUMENT: &[u8] = &[
+    0x69, 0x90, 0x99, 0xe5, 0xcb, 0xce, 0x80, 0xfa, 0xa2, 0xfd, 0x92, 0xb3, 0x9c, 0xf1, 0xaf, 0x9d,
+    0xf1, 0xf5, 0x1b,
+];
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+const DEFAULT_COMMON_NAME: &str = "tytle-enclave";
+const DEFAULT_VALIDITY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+/// notBefore is backdated this much, for peers with slightly slow clocks.
+const BACKDATE_MS: i64 = 60_000;
+
+#[napi(object)]
+pub struct AttestedCertificateOptions {
+    /// subjectAltName DNS names, for clients that check the hostname.
+    pub dns_names: Option<Vec<String>>,
+    /// Subject and issuer common name (default "tytle-enclave").
+    pub common_name: Option<String>,
+    /// How long the certificate is valid (default one day).
+    pub validity_ms: Option<f64>,
+    /// Bound into the attestation document.
+    pub nonce: Option<Buffer>,
+    /// Bound into the attestation document.
+    pub user_data: Option<Buffer>,
+}
+
+#[napi(object)]
+pub struct AttestedCertificate {
+    /// PEM certificate carrying the attestation document extension.
+    pub certificate: Buffer,
+    /// PEM PKCS#8 private key for the certificate.
+    pub private_key: Buffer,
+    /// The COSE_Sign1 attestation document in the certificate.
+    pub attestation: Buffer,
+}
+
+/// napi-free form of AttestedCertificate, in DER.
+pub(crate) struct Generated {
+    pub(crate) certificate: Vec<u8>,
+    pub(crate) private_key: Vec<u8>,
+    pub(crate) attestation: Vec<u8>,
+}
+
+/// Generate a key and a self-signed certificate for it carrying a fresh
+/// attestation document over its public key.
+#[napi]
+pub fn generate_attested_certificate(
+    options: Option<AttestedCertificateOptions>,
+    nsm_options: Option<NsmOptions>,
+) -> coded::Result<AttestedCertificate> {
+    let options = options.unwrap_or(AttestedCertificateOptions {
+        dns_names: None,
+        common_name: None,
+        validity_ms: None,
+        nonce: None,
+        user_data: None,
+    });
+    let validity_ms = options.validity_ms.unwrap_or(DEFAULT_VALIDITY_MS);
+    if validity_ms.is_nan() || validity_ms <= 0.0 {
+        return Err(coded(Error::from_reason(
+            "validityMs must be a positive number",
+        )));
+    }
+    let generated = nsm::with_device(nsm_options, |device| {
+        generate(
+            device,
+            &CertificateParams {
+                common_name: options
+                    .common_name
+                    .as_deref()
+                    .unwrap_or(DEFAULT_COMMON_NAME),
+                dns_names: options.dns_names.as_deref().unwrap_or_default(),
+                not_before_ms: attestation::now_ms() - BACKDATE_MS,
+                not_after_ms: attestation::now_ms() + validity_ms as i64,
+            },
+            options.nonce.as_deref(),
+            options.user_data.as_deref(),
+        )
+    })?;
+    Ok(AttestedCertificate {
+        certificate: pem("CERTIFICATE", &generated.certificate)
+            .map_err(coded)?
+            .into(),
+        private_key: pem("PRIVATE KEY", &generated.private_key)
+            .map_err(coded)?
+            .into(),
+        attestation: generated.attestation.into(),
+    })
+}
+
+fn pem(label: &str, der: &[u8]) -> Result<Vec<u8>> {
+    pem_rfc7468::encode_string(label, pem_rfc7468::LineEnding::LF, der)
+        .map(String::into_bytes)
+        .map_err(|e| Error::from_reason(format!("PEM encoding failed: {}", e)))
+}
+
+/// Check the attestation document in `certificate` (PEM or DER) against
+/// `policy` at `timeMs` (default now), and that it binds the certificate's
+/// public key. Throws if the certificate has no attestation extension.
+#[napi]
+pub fn verify_attested_certificate(
+    certificate: Buffer,
+    policy: &AttestationPolicy,
+    time_ms: Option<f64>,
+) -> Result<PolicyResult> {
+    let time_ms = time_ms
+        .map(|t| t as i64)
+        .unwrap_or_else(attestation::now_ms);
+    let der = x509::parse_pem_or_der(&certificate)?.remove(0).der;
+    Ok(verify(&der, &policy.policy(), time_ms)?.into_js())
+}
+
+pub(crate) struct CertificateParams<'a> {
+    pub(crate) common_name: &'a str,
+    pub(crate) dns_names: &'a [String],
+    pub(crate) not_before_ms: i64,
+    pub(crate) not_after_ms: i64,
+}
+
+pub(crate) fn generate(
+    device: &Device,
+    params: &CertificateParams,
+    nonce: Option<&[u8]>,
+    user_data: Option<&[u8]>,
+) -> Result<Generated> {
+    let rng = SystemRandom::new();
+    let failed = || Error::from_reason("Key generation failed");
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, &rng)
+        .map_err(|_| failed())?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|_| failed())?;
+    let spki = sequence(&[
+        &sequence(&[
+            &tlv(TAG_OID, OID_EC_PUBLIC_KEY),
+            &tlv(TAG_OID, OID_SECP384R1),
+        ]),
+        &bit_string(key.public_key().as_ref()),
+    ]);
+    let document = device.attestation(user_data, nonce, Some(&spki))?;
+
+    let mut serial = [0u8; 16];
+    OsRng.fill_bytes(&mut serial);
+    // Positive, with no leading zero byte
+    serial[0] = serial[0] & 0x7f | 0x40;
+    let name = sequence(&[&tlv(
+        TAG_SET,
+        &sequence(&[
+            &tlv(TAG_OID, OID_COMMON_NAME),
+            &tlv(TAG_UTF8_STRING, params.common_name.as_bytes()),
+        ]),
+    )]);
+    let algorithm = sequence(&[&tlv(TAG_OID, OID_ECDSA_WITH_SHA384)]);
+
+    let mut extensions = vec![extension(OID_BASIC_CONSTRAINTS, true, &sequence(&[]))];
+    if !params.dns_names.is_empty() {
+        let names: Vec<Vec<u8>> = params
+            .dns_names
+            .iter()
+            .map(|name| tlv(TAG_DNS_NAME, name.as_bytes()))
+            .collect();
+        let names: Vec<&[u8]> = names.iter().map(Vec::as_slice).collect();
+        extensions.push(extension(OID_SUBJECT_ALT_NAME, false, &sequence(&names)));
+    }
+    extensions.push(extension(OID_ATTESTATION_DOCUMENT, false, &document));
+    let extensions: Vec<&[u8]> = extensions.iter().map(Vec::as_slice).collect();
+
+    let tbs = sequence(&[
+        &tlv(TAG_VERSION, &tlv(TAG_INTEGER, &[2])),
+        &tlv(TAG_INTEGER, &serial),
+        &algorithm,
+        &name,
+        &sequence(&[&time(params.not_before_ms), &time(params.not_after_ms)]),
+        &name,
+        &spki,
+        &tlv(TAG_EXTENSIONS, &sequence(&extensions)),
+    ]);
+    let signature = key
+        .sign(&rng, &tbs)
+        .map_err(|_| Error::from_reason("Certificate signing failed"))?;
+    Ok(Generated {
+        certificate: sequence(&[&tbs, &algorithm, &bit_string(signature.as_ref())]),
+        private_key: pkcs8.as_ref().to_vec(),
+        attestation: document,
+    })
+}
+
+/// Evaluate the attestation document in the DER `certificate` with
+/// `policy`, adding failures for a certificate that isn't validly
+/// self-signed at `time_ms` or whose key the document doesn't bind.
+pub(crate) fn verify(certificate: &[u8], policy: &Policy, time_ms: i64) -> Result<Evaluation> {
+    let certificate = x509::Certificate::from_der(certificate)?;
+    let document = certificate
+        .extension(OID_ATTESTATION_DOCUMENT)
+        .ok_or_else(|| Error::from_reason("Certificate has no attestation document extension"))?;
+    let mut evaluation = policy.evaluate(document, time_ms)?;
+    let mut fail = |rule: &str, message: String| {
+        evaluation.failures.push(Failure {
+            rule: rule.to_string(),
+            message,
+        })
+    };
+    if let Err(e) = certificate.verify_issued_by(&certificate) {
+        fail(
+            "certificate",
+            format!("Certificate is not self-signed: {}", e.reason),
+        );
+    } else if !certificate.valid_at(time_ms) {
+        fail(
+            "certificate",
+            "Certificate is expired or not yet valid".to_string(),
+        );
+    }
+    if evaluation.document.public_key.as_deref() != Some(&certificate.spki[..]) {
+        fail(
+            "publicKey",
+            "Document does not bind the certificate's public key".to_string(),
+        );
+    }
+    Ok(evaluation)
+}
+
+/// rustls verifier accepting a server certificate whose attestation
+/// document passes `policy`, in place of a CA chain and hostname check.
+#[derive(Debug)]
+pub(crate) struct AttestedServerVerifier {
+    policy: Policy,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AttestedServerVerifier {
+    pub(crate) fn new(policy: Policy, provider: &rustls::crypto::CryptoProvider) -> Arc<Self> {
+        Arc::new(AttestedServerVerifier {
+            policy,
+            algorithms: provider.signature_verification_algorithms,
+        })
+    }
+}
+
+impl ServerCertVerifier for AttestedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let reject =
+            |message: String| rustls::Error::General(format!("AttestationRejected: {}", message));
+        let time_ms = now.as_secs() as i64 * 1000;
+        let evaluation = verify(end_entity, &self.policy, time_ms).map_err(|e| reject(e.reason))?;
+        if !evaluation.failures.is_empty() {
+            let failures: Vec<String> = evaluation
+                .failures
+                .into_iter()
+                .map(|failure| failure.message)
+                .collect();
+            return Err(reject(failures.join("; ")));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTF8_STRING: u8 = 0x0c;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_DNS_NAME: u8 = 0x82;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn sequence(parts: &[&[u8]]) -> Vec<u8> {
+    tlv(TAG_SEQUENCE, &parts.concat())
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(TAG_BIT_STRING, &[&[0u8][..], bytes].concat())
+}
+
+fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let oid = tlv(TAG_OID, oid);
+    let value = tlv(TAG_OCTET_STRING, value);
+    if critical {
+        sequence(&[&oid, &[0x01, 0x01, 0xff], &value])
+    } else {
+        sequence(&[&oid, &value])
+    }
+}
+
+/// UTCTime through 2049, GeneralizedTime after, as RFC 5280 requires.
+fn time(time_ms: i64) -> Vec<u8> {
+    let seconds = time_ms.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    let rest = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    if (1950..2050).contains(&year) {
+        tlv(
+            TAG_UTC_TIME,
+            format!("{:02}{}", year % 100, rest).as_bytes(),
+        )
+    } else {
+        tlv(
+            TAG_GENERALIZED_TIME,
+            format!("{:04}{}", year, rest).as_bytes(),
+        )
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::fixtures::test_policy;
+    use crate::test_support::mock_device;
+
+    fn generate_now(dns_names: &[String]) -> Generated {
+        let now = attestation::now_ms();
+        let params = CertificateParams {
+            common_name: DEFAULT_COMMON_NAME,
+            dns_names,
+            not_before_ms: now - BACKDATE_MS,
+            not_after_ms: now + 3_600_000,
+        };
+        generate(&mock_device(), &params, Some(b"nonce"), None).unwrap()
+    }
+
+    fn rules(evaluation: &Evaluation) -> Vec<&str> {
+        evaluation
+            .failures
+            .iter()
+            .map(|f| f.rule.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn generated_certificates_carry_a_binding_document() {
+        let generated = generate_now(&["enclave.local".to_string()]);
+        let certificate = x509::Certificate::from_der(&generated.certificate).unwrap();
+        assert_eq!(
+            certificate.extension(OID_ATTESTATION_DOCUMENT),
+            Some(&generated.attestation[..])
+        );
+        assert!(!certificate.is_ca);
+
+        let evaluation = verify(
+            &generated.certificate,
+            &test_policy(),
+            attestation::now_ms(),
+        )
+        .unwrap();
+        assert_eq!(rules(&evaluation), Vec::<&str>::new());
+        assert_eq!(evaluation.document.nonce.as_deref(), Some(&b"nonce"[..]));
+
+        let later = certificate.not_after_ms + 1;
+        let evaluation = verify(&generated.certificate, &test_policy(), later).unwrap();
+        assert!(rules(&evaluation).contains(&"certificate"));
+    }
+
+    #[test]
+    fn documents_must_bind_the_certificate_key() {
+        let (first, second) = (generate_now(&[]), generate_now(&[]));
+        // The second certificate with the first one's document spliced in
+        let at = second
+            .certificate
+            .windows(second.attestation.len())
+            .position(|w| w == second.attestation)
+            .unwrap();
+        let mut spliced = second.certificate.clone();
+        assert_eq!(first.attestation.len(), second.attestation.len());
+        spliced[at..at + first.attestation.len()].copy_from_slice(&first.attestation);
+        let evaluation = verify(&spliced, &test_policy(), attestation::now_ms()).unwrap();
+        assert_eq!(rules(&evaluation), vec!["certificate", "publicKey"]);
+
+        let plain = x509::parse_pem_or_der(include_bytes!("../testdata/attestation/leaf.pem"))
+            .unwrap()
+            .remove(0);
+        let err = verify(&plain.der, &test_policy(), attestation::now_ms())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.reason,
+            "Certificate has no attestation document extension"
+        );
+    }
+
+    #[test]
+    fn encodes_times_per_rfc_5280() {
+        // 2030-01-01T00:00:00Z
+        assert_eq!(time(1_893_456_000_000), tlv(TAG_UTC_TIME, b"300101000000Z"));
+        // 2070-01-01T00:00:00Z
+        assert_eq!(
+            time(3_155_760_000_000),
+            tlv(TAG_GENERALIZED_TIME, b"20700101000000Z")
+        );
+    }
+}
@@ -0,0 +1,663 @@
+//! Recording vsock sessions and replaying them offline.
+//!
+//! A protocol regression between enclave and host builds is hard to chase
+//! when it only shows up on a Nitro instance. SessionRecorder writes every
+//! frame read or written on a stream to a file, with timestamps, and
+//! replaySession() later plays the recorded peer back against a new build
+//! on any machine:
+//!
+//! ```js
+//! // in the enclave (or on the host)
+//! const recorder = SessionRecorder.start(stream, '/tmp/session.rec');
+//! const rpc = RpcClient.fromStream(stream);
+//! // ... later
+//! recorder.stop();
+//!
+//! // in a test
+//! const { stream, replay } = replaySession('/tmp/session.rec');
+//! const rpc = RpcClient.fromStream(stream);
+//! await rpc.call('getBalance', { account });
+//! const report = await replay.finished();
+//! if (report.mismatches.length) console.log(report.mismatches[0]);
+//! ```
+//!
+//! Frames are captured in the framing module, so the recorder sees what
+//! the native layers (RpcClient, secure channels, negotiation, sendFile()
+//! and the other framed services) move on the stream or any duplicate of
+//! its descriptor, as the bytes went on the wire: after compression and
+//! encryption. Plain read()/write() calls aren't frames and aren't
+//! recorded; see enableTrace() for those.
+//!
+//! A recording is `\0tytle-recording/1\0` followed by one record per
+//! frame: direction (0 read, 1 written), microseconds since the Unix epoch
+//! (u64) and length (u32), all big-endian, then the frame's payload.
+//!
+//! The replay's peer writes each frame the recorded stream read, and
+//! expects each frame it wrote, reporting any that differ. Secure channel
+//! handshakes use fresh keys and nonces each time, so a replayed channel
+//! diverges after the hello; pass `{ compare: false }` to play such
+//! sessions without counting every frame as a mismatch.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::io::Write;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::errors;
+use crate::framing;
+use crate::trace::Direction;
+use crate::vsock::{VsockStream, VMADDR_CID_LOCAL};
+use crate::workers::WorkerTask;
+
+const MAGIC: &[u8] = b"\0tytle-recording/1\0";
+/// Direction byte, microseconds, length.
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+const DEFAULT_TIMEOUT_MS: u32 = 5_000;
+
+/// Recorders running in this process.
+static RECORDERS: Mutex<Vec<Arc<Recorder>>> = Mutex::new(Vec::new());
+/// RECORDERS.len(), checked before each frame without taking the lock.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+#[napi(object)]
+pub struct RecordingStats {
+    /// Frames recorded.
+    pub frames: f64,
+    /// Payload bytes recorded.
+    pub bytes: f64,
+}
+
+#[napi(object)]
+pub struct RecordedFrame {
+    /// "read" or "write", from the recorded stream's side.
+    pub direction: String,
+    /// Milliseconds since the Unix epoch, as Date.now().
+    pub timestamp_ms: f64,
+    pub data: Buffer,
+}
+
+#[napi(object)]
+pub struct ReplayOptions {
+    /// How long to wait for each frame the recording expects from the code
+    /// under test (default 5000).
+    pub timeout_ms: Option<u32>,
+    /// Keep the recorded gaps between incoming frames (default false:
+    /// play them as fast as they are read).
+    pub realtime: Option<bool>,
+    /// Compare the frames received with the recorded ones (default true).
+    pub compare: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ReplayMismatch {
+    /// Index of the frame in the recording.
+    pub index: u32,
+    pub expected: Buffer,
+    pub actual: Buffer,
+}
+
+#[napi(object)]
+pub struct ReplayReport {
+    /// Whether every recorded frame was played.
+    pub complete: bool,
+    /// Recorded frames written to the stream.
+    pub frames_sent: u32,
+    /// Frames received from the code under test.
+    pub frames_received: u32,
+    /// Frames received that differ from the recording.
+    pub mismatches: Vec<ReplayMismatch>,
+    /// Why the replay stopped early.
+    pub error: Option<String>,
+}
+
+/// Returned by replaySession().
+#[napi(object, object_from_js = false)]
+pub struct ReplayedSession {
+    /// Hand this to the code under test.
+    pub stream: VsockStream,
+    pub replay: SessionReplay,
+}
+
+/// Records the frames on a stream to a file. See the module docs.
+#[napi]
+pub struct SessionRecorder {
+    inner: Arc<Recorder>,
+}
+
+#[napi]
+impl SessionRecorder {
+    /// Start recording `stream`'s frames to `path`, replacing the file.
+    #[napi(factory)]
+    pub fn start(stream: &VsockStream, path: String) -> Result<Self> {
+        Ok(SessionRecorder {
+            inner: Recorder::start(stream.fd(), &path)?,
+        })
+    }
+
+    #[napi]
+    pub fn stats(&self) -> RecordingStats {
+        self.inner.stats()
+    }
+
+    /// Stop recording and close the file. Throws if writing it failed.
+    /// Safe to call multiple times.
+    #[napi]
+    pub fn stop(&self) -> Result<RecordingStats> {
+        self.inner.stop()?;
+        Ok(self.inner.stats())
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        let _ = self.inner.stop();
+    }
+}
+
+/// Decode the recording at `path`, e.g. to inspect it or build a test.
+#[napi]
+pub fn read_recording(path: String) -> Result<Vec<RecordedFrame>> {
+    Ok(load(&path)?
+        .into_iter()
+        .map(|frame| RecordedFrame {
+            direction: frame.direction.as_str().to_string(),
+            timestamp_ms: frame.timestamp_us as f64 / 1000.0,
+            data: frame.payload.into(),
+        })
+        .collect())
+}
+
+/// Play the peer of the session recorded at `path` on a new stream. The
+/// replay runs on a native thread until the recording ends, a frame
+/// doesn't arrive within timeoutMs, or stop().
+#[napi]
+pub fn replay_session(path: String, options: Option<ReplayOptions>) -> Result<ReplayedSession> {
+    let frames = load(&path)?;
+    let (timeout_ms, realtime, compare) = match options {
+        Some(o) => (o.timeout_ms, o.realtime, o.compare),
+        None => (None, None, None),
+    };
+    let options = Playback {
+        timeout: Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS) as u64),
+        realtime: realtime.unwrap_or(false),
+        compare: compare.unwrap_or(true),
+    };
+    let (ours, peer) = UnixStream::pair().map_err(|e| errors::os_error("socketpair()", e))?;
+    let (ours, peer) = (ours.into_raw_fd(), peer.into_raw_fd());
+    let state = Arc::new(ReplayState {
+        fd: peer,
+        outcome: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    let playing = state.clone();
+    std::thread::spawn(move || {
+        let outcome = play(playing.fd, &frames, &options);
+        *playing.outcome.lock().unwrap() = Some(outcome);
+        playing.done.notify_all();
+    });
+    Ok(ReplayedSession {
+        stream: VsockStream::from_raw(ours, VMADDR_CID_LOCAL, 0),
+        replay: SessionReplay { state },
+    })
+}
+
+/// A running replay, from replaySession().
+#[napi]
+pub struct SessionReplay {
+    state: Arc<ReplayState>,
+}
+
+#[napi]
+impl SessionReplay {
+    /// Resolves with the report once the replay has ended.
+    #[napi(ts_return_type = "Promise<ReplayReport>")]
+    pub fn finished(&self) -> WorkerTask<FinishedTask> {
+        WorkerTask::new(FinishedTask {
+            state: self.state.clone(),
+        })
+    }
+
+    /// End the replay early, closing the peer's end of the stream.
+    #[napi]
+    pub fn stop(&self) {
+        unsafe {
+            libc::shutdown(self.state.fd, libc::SHUT_RDWR);
+        }
+    }
+}
+
+pub struct FinishedTask {
+    state: Arc<ReplayState>,
+}
+
+impl Task for FinishedTask {
+    type Output = Outcome;
+    type JsValue = ReplayReport;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let mut outcome = self.state.outcome.lock().unwrap();
+        loop {
+            if let Some(outcome) = &*outcome {
+                return Ok(outcome.clone());
+            }
+            outcome = self.state.done.wait(outcome).unwrap();
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, outcome: Self::Output) -> Result<Self::JsValue> {
+        Ok(outcome.into_report())
+    }
+}
+
+/// Recording state for one socket.
+struct Recorder {
+    /// (st_dev, st_ino) of the socket, shared by every dup of its fd.
+    socket: (u64, u64),
+    /// None once stopped, or after a write failed.
+    file: Mutex<Option<std::fs::File>>,
+    /// Why writing the file failed.
+    error: Mutex<Option<String>>,
+    frames: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Recorder {
+    fn start(fd: i32, path: &str) -> Result<Arc<Self>> {
+        if fd < 0 {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let socket = socket_id(fd).map_err(|e| errors::os_error("fstat()", e))?;
+        let mut file = std::fs::File::create(path)
+            .map_err(|e| Error::from_reason(format!("Cannot create {}: {}", path, e)))?;
+        file.write_all(MAGIC)
+            .map_err(|e| Error::from_reason(format!("Cannot write {}: {}", path, e)))?;
+        let recorder = Arc::new(Recorder {
+            socket,
+            file: Mutex::new(Some(file)),
+            error: Mutex::new(None),
+            frames: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        });
+        let mut recorders = RECORDERS.lock().unwrap();
+        recorders.push(recorder.clone());
+        ACTIVE.store(recorders.len(), Ordering::Release);
+        Ok(recorder)
+    }
+
+    fn write(&self, direction: Direction, payload: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        let Some(out) = file.as_mut() else {
+            return;
+        };
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.push(match direction {
+            Direction::Read => 0,
+            Direction::Write => 1,
+        });
+        record.extend_from_slice(&now_us().to_be_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(payload);
+        if let Err(e) = out.write_all(&record) {
+            tracing::warn!(error = %e, "session recording failed");
+            *self.error.lock().unwrap() = Some(format!("Recording failed: {}", e));
+            file.take();
+            return;
+        }
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+    }
+
+    fn stop(self: &Arc<Self>) -> Result<()> {
+        let mut recorders = RECORDERS.lock().unwrap();
+        recorders.retain(|recorder| !Arc::ptr_eq(recorder, self));
+        ACTIVE.store(recorders.len(), Ordering::Release);
+        drop(recorders);
+        self.file.lock().unwrap().take();
+        match &*self.error.lock().unwrap() {
+            Some(reason) => Err(Error::from_reason(reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn stats(&self) -> RecordingStats {
+        RecordingStats {
+            frames: self.frames.load(Ordering::Relaxed) as f64,
+            bytes: self.bytes.load(Ordering::Relaxed) as f64,
+        }
+    }
+}
+
+/// Record a frame payload read from or written to `fd`, if its socket is
+/// being recorded. Called by the framing layer for every frame.
+pub(crate) fn frame(fd: i32, direction: Direction, payload: &[u8]) {
+    if ACTIVE.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    let Ok(socket) = socket_id(fd) else {
+        return;
+    };
+    let recorder = RECORDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|recorder| recorder.socket == socket)
+        .cloned();
+    if let Some(recorder) = recorder {
+        recorder.write(direction, payload);
+    }
+}
+
+fn socket_id(fd: i32) -> std::io::Result<(u64, u64)> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((stat.st_dev as u64, stat.st_ino as u64))
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// One frame of a recording.
+#[derive(Clone, Debug, PartialEq)]
+struct Recorded {
+    direction: Direction,
+    timestamp_us: u64,
+    payload: Vec<u8>,
+}
+
+fn load(path: &str) -> Result<Vec<Recorded>> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::from_reason(format!("Cannot read {}: {}", path, e)))?;
+    parse(&bytes).map_err(|e| Error::from_reason(format!("{}: {}", path, e)))
+}
+
+fn parse(bytes: &[u8]) -> std::result::Result<Vec<Recorded>, String> {
+    let mut rest = bytes.strip_prefix(MAGIC).ok_or("not a session recording")?;
+    let mut frames = Vec::new();
+    while !rest.is_empty() {
+        let truncated = || format!("recording truncated in frame {}", frames.len());
+        if rest.len() < RECORD_HEADER_SIZE {
+            return Err(truncated());
+        }
+        let direction = match rest[0] {
+            0 => Direction::Read,
+            1 => Direction::Write,
+            other => return Err(format!("frame {} has direction {}", frames.len(), other)),
+        };
+        let timestamp_us = u64::from_be_bytes(rest[1..9].try_into().unwrap());
+        let len = u32::from_be_bytes(rest[9..13].try_into().unwrap()) as usize;
+        let payload = rest[RECORD_HEADER_SIZE..]
+            .get(..len)
+            .ok_or_else(truncated)?;
+        frames.push(Recorded {
+            direction,
+            timestamp_us,
+            payload: payload.to_vec(),
+        });
+        rest = &rest[RECORD_HEADER_SIZE + len..];
+    }
+    Ok(frames)
+}
+
+struct Playback {
+    timeout: Duration,
+    realtime: bool,
+    compare: bool,
+}
+
+struct ReplayState {
+    /// The peer's end of the stream, owned by the replay thread.
+    fd: i32,
+    outcome: Mutex<Option<Outcome>>,
+    done: Condvar,
+}
+
+impl Drop for ReplayState {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// napi-free form of ReplayReport.
+#[derive(Clone, Debug, Default)]
+pub struct Outcome {
+    sent: u32,
+    received: u32,
+    /// (index, expected, actual)
+    mismatches: Vec<(u32, Vec<u8>, Vec<u8>)>,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn into_report(self) -> ReplayReport {
+        ReplayReport {
+            complete: self.error.is_none(),
+            frames_sent: self.sent,
+            frames_received: self.received,
+            mismatches: self
+                .mismatches
+                .into_iter()
+                .map(|(index, expected, actual)| ReplayMismatch {
+                    index,
+                    expected: expected.into(),
+                    actual: actual.into(),
+                })
+                .collect(),
+            error: self.error,
+        }
+    }
+}
+
+/// Play the recorded stream's peer on `fd`: write the frames it read and
+/// read the frames it wrote. Shuts `fd` down at the end, so the code under
+/// test sees EOF.
+fn play(fd: i32, frames: &[Recorded], options: &Playback) -> Outcome {
+    let mut outcome = Outcome::default();
+    let started = Instant::now();
+    let first_us = frames.first().map_or(0, |frame| frame.timestamp_us);
+    for (index, frame) in frames.iter().enumerate() {
+        let step = match frame.direction {
+            Direction::Read => {
+                if options.realtime {
+                    let due = Duration::from_micros(frame.timestamp_us.saturating_sub(first_us));
+                    std::thread::sleep(due.saturating_sub(started.elapsed()));
+                }
+                framing::write_frame(fd, &frame.payload)
+                    .map(|()| outcome.sent += 1)
+                    .map_err(|e| format!("writing frame {} failed: {}", index, e))
+            }
+            Direction::Write => receive(fd, index, frame, options.timeout).map(|actual| {
+                outcome.received += 1;
+                if options.compare && actual != frame.payload {
+                    let expected = frame.payload.clone();
+                    outcome.mismatches.push((index as u32, expected, actual));
+                }
+            }),
+        };
+        if let Err(reason) = step {
+            outcome.error = Some(reason);
+            break;
+        }
+    }
+    unsafe {
+        libc::shutdown(fd, libc::SHUT_RDWR);
+    }
+    outcome
+}
+
+/// Read the frame recorded as frame `index`, waiting up to `timeout`.
+fn receive(
+    fd: i32,
+    index: usize,
+    frame: &Recorded,
+    timeout: Duration,
+) -> std::result::Result<Vec<u8>, String> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let ready = loop {
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            n if n >= 0 => break n > 0,
+            _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {}
+            _ => {
+                return Err(format!(
+                    "poll() failed: {}",
+                    std::io::Error::last_os_error()
+                ))
+            }
+        }
+    };
+    if !ready {
+        return Err(format!(
+            "timed out after {} ms waiting for frame {} ({} bytes)",
+            timeout.as_millis(),
+            index,
+            frame.payload.len()
+        ));
+    }
+    match framing::read_frame(fd) {
+        Ok(Some(actual)) => Ok(actual),
+        Ok(None) => Err(format!(
+            "stream closed before frame {} ({} bytes)",
+            index,
+            frame.payload.len()
+        )),
+        Err(e) => Err(format!("reading frame {} failed: {}", index, e)),
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::dup_fd;
+    use std::os::unix::io::AsRawFd;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("tytle-{}-{}.rec", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn recorders_capture_frames_through_any_duplicate() {
+        let path = temp_path("record");
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (other, _other_peer) = UnixStream::pair().unwrap();
+        let recorder = Recorder::start(ours.as_raw_fd(), &path).unwrap();
+
+        let dup = dup_fd(ours.as_raw_fd()).unwrap();
+        framing::write_frame(dup, b"ping").unwrap();
+        assert_eq!(
+            framing::read_frame(theirs.as_raw_fd()).unwrap().unwrap(),
+            b"ping"
+        );
+        framing::write_frame(theirs.as_raw_fd(), b"pong").unwrap();
+        assert_eq!(
+            framing::read_frame(ours.as_raw_fd()).unwrap().unwrap(),
+            b"pong"
+        );
+        // Not the recorded socket
+        framing::write_frame(other.as_raw_fd(), b"elsewhere").unwrap();
+        unsafe { libc::close(dup) };
+
+        recorder.stop().unwrap();
+        assert_eq!(recorder.stats().frames, 2.0);
+        framing::write_frame(ours.as_raw_fd(), b"after stop").unwrap();
+
+        let frames = load(&path).unwrap();
+        let summary: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.direction, frame.payload.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(Direction::Write, &b"ping"[..]), (Direction::Read, b"pong")]
+        );
+        assert!(frames[0].timestamp_us <= frames[1].timestamp_us);
+        assert!(frames[0].timestamp_us > 1_600_000_000_000_000);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn malformed_recordings_are_rejected() {
+        assert_eq!(parse(MAGIC).unwrap(), vec![]);
+        assert!(parse(b"not a recording").is_err());
+        let mut truncated = MAGIC.to_vec();
+        truncated.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9, b'x']);
+        assert_eq!(
+            parse(&truncated).unwrap_err(),
+            "recording truncated in frame 0"
+        );
+    }
+
+    fn recording(frames: &[(Direction, &[u8])]) -> Vec<Recorded> {
+        frames
+            .iter()
+            .map(|&(direction, payload)| Recorded {
+                direction,
+                timestamp_us: 0,
+                payload: payload.to_vec(),
+            })
+            .collect()
+    }
+
+    /// Replay `frames` against a client that sends `request` and reads the
+    /// reply.
+    fn replay_against(frames: Vec<Recorded>, request: &'static [u8]) -> (Outcome, Vec<u8>) {
+        let (client, peer) = UnixStream::pair().unwrap();
+        let options = Playback {
+            timeout: Duration::from_millis(200),
+            realtime: false,
+            compare: true,
+        };
+        let replay = std::thread::spawn(move || play(peer.as_raw_fd(), &frames, &options));
+        framing::write_frame(client.as_raw_fd(), request).unwrap();
+        let reply = framing::read_frame(client.as_raw_fd()).unwrap().unwrap();
+        (replay.join().unwrap(), reply)
+    }
+
+    #[test]
+    fn replays_feed_the_recorded_peer_and_flag_differences() {
+        let session = || {
+            recording(&[
+                (Direction::Write, b"ping"),
+                (Direction::Read, b"pong"),
+                (Direction::Write, b"bye"),
+            ])
+        };
+        // The new build sends only the first frame
+        let (outcome, reply) = replay_against(session(), b"ping");
+        assert_eq!(reply, b"pong");
+        assert_eq!((outcome.sent, outcome.received), (1, 1));
+        assert!(outcome.mismatches.is_empty());
+        assert_eq!(
+            outcome.error.as_deref(),
+            Some("timed out after 200 ms waiting for frame 2 (3 bytes)")
+        );
+
+        let (outcome, _) = replay_against(session()[..2].to_vec(), b"PING");
+        assert!(outcome.error.is_none());
+        assert_eq!(
+            outcome.mismatches,
+            vec![(0, b"ping".to_vec(), b"PING".to_vec())]
+        );
+    }
+}
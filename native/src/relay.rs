@@ -0,0 +1,440 @@
+use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi_derive::napi;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::cancel::Canceller;
+use crate::vsock::VsockStream;
+
+/// Copy buffer size per direction. Matches the chunk size used by readExact
+/// in shared/src/protocol.ts.
+const RELAY_BUF_SIZE: usize = 65536;
+
+/// Final byte counts for a relay, passed to the onClose callback.
+#[napi(object)]
+pub struct PipeResult {
+    pub bytes_a_to_b: i64,
+    pub bytes_b_to_a: i64,
+    /// Set when a direction stopped on an I/O error rather than EOF.
+    pub error: Option<String>,
+}
+
+/// State shared between the two copy threads and the JS handle.
+///
+/// The relay owns duplicated descriptors, so JS closing its own stream
+/// objects never pulls an fd out from under a running copy thread.
+/// `fds` is taken (and the descriptors closed) exactly once, under the
+/// mutex, so close() can never shutdown() a recycled fd number.
+///
+/// The copy threads poll `cancel` alongside their fds: shutdown(2) only
+/// wakes them on sockets, and either end may be a pipe or file.
+pub(crate) struct RelayShared {
+    fds: Mutex<Option<(i32, i32)>>,
+    cancel: Canceller,
+    a_to_b: AtomicI64,
+    b_to_a: AtomicI64,
+    error: Mutex<Option<String>>,
+}
+
+impl RelayShared {
+    /// Stop both copy threads, and shut down socket ends so peers see it.
+    pub(crate) fn shutdown(&self) {
+        self.cancel.cancel();
+        if let Some((a, b)) = *self.fds.lock().unwrap() {
+            unsafe {
+                libc::shutdown(a, libc::SHUT_RDWR);
+                libc::shutdown(b, libc::SHUT_RDWR);
+            }
+        }
+    }
+
+    pub(crate) fn bytes_a_to_b(&self) -> i64 {
+        self.a_to_b.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_b_to_a(&self) -> i64 {
+        self.b_to_a.load(Ordering::Relaxed)
+    }
+
+    fn result(&self) -> PipeResult {
+        PipeResult {
+            bytes_a_to_b: self.bytes_a_to_b(),
+            bytes_b_to_a: self.bytes_b_to_a(),
+            error: self.error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Relay bytes between `a` and `b` on two dedicated threads.
+///
+/// Takes ownership of both descriptors. EOF in one direction is propagated
+/// as a half-close (shutdown(SHUT_WR)) on the other side; an I/O error in
+/// either direction tears down both. Once both directions finish, the
+/// descriptors are closed and `on_done` runs on the last copy thread.
+pub(crate) fn spawn_relay<F>(a: i32, b: i32, on_done: F) -> Result<Arc<RelayShared>>
+where
+    F: FnOnce(PipeResult) + Send + 'static,
+{
+    let cancel = match Canceller::new() {
+        Ok(cancel) => cancel,
+        Err(e) => {
+            unsafe {
+                libc::close(a);
+                libc::close(b);
+            }
+            return Err(Error::from_reason(format!("pipe() failed: {}", e)));
+        }
+    };
+    let shared = Arc::new(RelayShared {
+        fds: Mutex::new(Some((a, b))),
+        cancel,
+        a_to_b: AtomicI64::new(0),
+        b_to_a: AtomicI64::new(0),
+        error: Mutex::new(None),
+    });
+
+    let forward = {
+        let shared = shared.clone();
+        std::thread::spawn(move || copy_direction(&shared, a, b, &shared.a_to_b))
+    };
+    let thread_shared = shared.clone();
+    std::thread::spawn(move || {
+        copy_direction(&thread_shared, b, a, &thread_shared.b_to_a);
+        let _ = forward.join();
+        if let Some((a, b)) = thread_shared.fds.lock().unwrap().take() {
+            unsafe {
+                libc::close(a);
+                libc::close(b);
+            }
+        }
+        on_done(thread_shared.result());
+    });
+
+    Ok(shared)
+}
+
+/// Copy src → dst until EOF or error, counting bytes into `counter`.
+fn copy_direction(shared: &RelayShared, src: i32, dst: i32, counter: &AtomicI64) {
+    let mut buf = vec![0u8; RELAY_BUF_SIZE];
+    loop {
+        let n = match read_relay(&shared.cancel, src, &mut buf) {
+            Ok(0) => {
+                // Half-close: let the peer see EOF but keep the reverse
+                // direction flowing. Fails harmlessly (ENOTSOCK) on pipes/files.
+                unsafe { libc::shutdown(dst, libc::SHUT_WR); }
+                return;
+            }
+            Ok(n) => n,
+            Err(_) if shared.cancel.is_cancelled() => return,
+            Err(e) => {
+                record_error(shared, format!("read(fd={}) failed: {}", src, e));
+                return;
+            }
+        };
+        match write_all_relay(&shared.cancel, dst, &buf[..n]) {
+            Ok(()) => {}
+            Err(_) if shared.cancel.is_cancelled() => return,
+            Err(e) => {
+                record_error(shared, format!("write(fd={}) failed: {}", dst, e));
+                return;
+            }
+        }
+        counter.fetch_add(n as i64, Ordering::Relaxed);
+    }
+}
+
+fn record_error(shared: &RelayShared, message: String) {
+    {
+        let mut error = shared.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(message);
+        }
+    }
+    shared.shutdown();
+}
+
+/// read() once `fd` is readable, until the relay is stopped.
+fn read_relay(cancel: &Canceller, fd: i32, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        cancel.wait(fd, libc::POLLIN)?;
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+/// write() the whole slice as `fd` becomes writable, until the relay is
+/// stopped.
+fn write_all_relay(cancel: &Canceller, fd: i32, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        cancel.wait(fd, libc::POLLOUT)?;
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if n >= 0 {
+            data = &data[n as usize..];
+            continue;
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// read() that retries on EINTR and waits out EAGAIN.
+///
+/// Accepted streams carry SO_RCVTIMEO and fds handed in from Node may be
+/// non-blocking, so EAGAIN only means "idle" here — poll until readable.
+pub(crate) fn read_retrying(fd: i32, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EAGAIN) => wait_fd(fd, libc::POLLIN)?,
+            _ => return Err(err),
+        }
+    }
+}
+
+/// write() the whole slice, retrying on EINTR and waiting out EAGAIN.
+pub(crate) fn write_all_retrying(fd: i32, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if n >= 0 {
+            data = &data[n as usize..];
+            continue;
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EAGAIN) => wait_fd(fd, libc::POLLOUT)?,
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn wait_fd(fd: i32, events: i16) -> std::io::Result<()> {
+    let mut pfd = libc::pollfd { fd, events, revents: 0 };
+    loop {
+        let ret = unsafe { libc::poll(&mut pfd, 1, -1) };
+        if ret >= 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+}
+
+/// Duplicate a descriptor with CLOEXEC so the relay owns an independent handle.
+pub(crate) fn dup_fd(fd: i32) -> Result<i32> {
+    if fd < 0 {
+        return Err(Error::from_reason(format!("Invalid fd: {}", fd)));
+    }
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(Error::from_reason(format!(
+            "fcntl(F_DUPFD_CLOEXEC, fd={}) failed: {}",
+            fd,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(dup)
+}
+
+/// A running native relay created by pipe().
+#[napi]
+pub struct VsockPipe {
+    shared: Arc<RelayShared>,
+}
+
+#[napi]
+impl VsockPipe {
+    /// Bytes copied from side A to side B so far.
+    #[napi(getter)]
+    pub fn bytes_a_to_b(&self) -> i64 {
+        self.shared.bytes_a_to_b()
+    }
+
+    /// Bytes copied from side B to side A so far.
+    #[napi(getter)]
+    pub fn bytes_b_to_a(&self) -> i64 {
+        self.shared.bytes_b_to_a()
+    }
+
+    /// Stop the relay. Both copy threads are woken, whatever the fds are,
+    /// and socket ends are shut down; onClose fires once the threads exit.
+    /// Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.shared.shutdown();
+        Ok(())
+    }
+}
+
+/// Relay bytes bidirectionally between two streams (or a stream and a raw fd)
+/// entirely on native threads, without round-tripping chunks through JS.
+///
+/// Both descriptors are duplicated; the original stream objects stay usable
+/// and must still be closed by the caller. `onClose` is invoked once with the
+/// final byte counts when both directions have finished.
+#[napi]
+pub fn pipe(
+    a: &VsockStream,
+    b: Either<ClassInstance<VsockStream>, i32>,
+    #[napi(ts_arg_type = "(result: PipeResult) => void")] on_close: Option<
+        ThreadsafeFunction<PipeResult, ErrorStrategy::Fatal>,
+    >,
+) -> Result<VsockPipe> {
+    let b_fd = match &b {
+        Either::A(stream) => stream.fd(),
+        Either::B(fd) => *fd,
+    };
+    let a_dup = dup_fd(a.fd())?;
+    let b_dup = match dup_fd(b_fd) {
+        Ok(fd) => fd,
+        Err(e) => {
+            unsafe { libc::close(a_dup); }
+            return Err(e);
+        }
+    };
+
+    let shared = spawn_relay(a_dup, b_dup, move |result| {
+        if let Some(cb) = on_close {
+            cb.call(result, ThreadsafeFunctionCallMode::NonBlocking);
+        }
+    })?;
+    Ok(VsockPipe { shared })
+}
+
+// =============================================================================
+// Tests — relay over AF_UNIX socketpairs (no vsock required)
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::socketpair;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn read_exact(fd: i32, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        let mut got = 0;
+        while got < len {
+            let n = read_retrying(fd, &mut out[got..]).unwrap();
+            assert!(n > 0, "unexpected EOF after {} bytes", got);
+            got += n;
+        }
+        out
+    }
+
+    #[test]
+    fn relays_both_directions_and_reports_counts() {
+        let (client, relay_a) = socketpair();
+        let (relay_b, server) = socketpair();
+        let (tx, rx) = mpsc::channel();
+        spawn_relay(relay_a, relay_b, move |result| tx.send(result).unwrap()).unwrap();
+
+        write_all_retrying(client, b"ping from a").unwrap();
+        assert_eq!(read_exact(server, 11), b"ping from a");
+        write_all_retrying(server, b"pong").unwrap();
+        assert_eq!(read_exact(client, 4), b"pong");
+
+        unsafe {
+            libc::close(client);
+            libc::close(server);
+        }
+        let result = rx.recv_timeout(Duration::from_secs(5)).expect("relay did not finish");
+        assert_eq!(result.bytes_a_to_b, 11);
+        assert_eq!(result.bytes_b_to_a, 4);
+        assert!(result.error.is_none());
+    }
+
+    #[test]
+    fn eof_is_propagated_as_half_close() {
+        let (client, relay_a) = socketpair();
+        let (relay_b, server) = socketpair();
+        let (tx, rx) = mpsc::channel();
+        spawn_relay(relay_a, relay_b, move |result| tx.send(result).unwrap()).unwrap();
+
+        write_all_retrying(client, b"request").unwrap();
+        unsafe { libc::shutdown(client, libc::SHUT_WR); }
+
+        assert_eq!(read_exact(server, 7), b"request");
+        let mut buf = [0u8; 16];
+        assert_eq!(read_retrying(server, &mut buf).unwrap(), 0, "server should see EOF");
+
+        // Reverse direction still flows after the half-close
+        write_all_retrying(server, b"response").unwrap();
+        unsafe { libc::close(server); }
+        assert_eq!(read_exact(client, 8), b"response");
+
+        let result = rx.recv_timeout(Duration::from_secs(5)).expect("relay did not finish");
+        assert_eq!(result.bytes_a_to_b, 7);
+        assert_eq!(result.bytes_b_to_a, 8);
+        unsafe { libc::close(client); }
+    }
+
+    #[test]
+    fn shutdown_stops_an_idle_relay() {
+        let (client, relay_a) = socketpair();
+        let (relay_b, server) = socketpair();
+        let (tx, rx) = mpsc::channel();
+        let shared =
+            spawn_relay(relay_a, relay_b, move |result| tx.send(result).unwrap()).unwrap();
+
+        shared.shutdown();
+        let result = rx.recv_timeout(Duration::from_secs(5)).expect("relay did not stop");
+        assert_eq!(result.bytes_a_to_b, 0);
+        assert_eq!(result.bytes_b_to_a, 0);
+        // Descriptors were closed exactly once; a second shutdown is a no-op
+        shared.shutdown();
+        unsafe {
+            libc::close(client);
+            libc::close(server);
+        }
+    }
+
+    #[test]
+    fn shutdown_stops_a_relay_between_pipes() {
+        let mut a = [0i32; 2];
+        let mut b = [0i32; 2];
+        unsafe {
+            assert_eq!(libc::pipe(a.as_mut_ptr()), 0);
+            assert_eq!(libc::pipe(b.as_mut_ptr()), 0);
+        }
+        let (tx, rx) = mpsc::channel();
+        // Both copy threads block reading a pipe no one writes to
+        let shared = spawn_relay(a[0], b[0], move |result| tx.send(result).unwrap()).unwrap();
+
+        shared.shutdown();
+        let result = rx.recv_timeout(Duration::from_secs(5)).expect("relay did not stop");
+        assert!(result.error.is_none());
+        unsafe {
+            libc::close(a[1]);
+            libc::close(b[1]);
+        }
+    }
+
+    #[test]
+    fn dup_fd_rejects_invalid_descriptors() {
+        assert!(dup_fd(-1).is_err());
+        let err = dup_fd(999_999).unwrap_err();
+        assert!(err.reason.contains("F_DUPFD_CLOEXEC"));
+    }
+}
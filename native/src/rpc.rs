@@ -0,0 +1,586 @@
+//! JSON-RPC 2.0 client over framed vsock.
+//!
+//! RpcClient sends each call as a JSON-RPC request in one frame (see
+//! framing: the format of shared/src/protocol.ts) and tracks the request
+//! IDs in flight natively, each with its own deadline. A response settles
+//! its call's Promise; a call whose deadline passes is rejected with code
+//! ETIMEDOUT and forgotten, so a lost response can't hang the caller and a
+//! late one is dropped. Closing the connection, from either side, rejects
+//! every call still in flight.
+//!
+//! ```js
+//! const rpc = RpcClient.connect(3, 5005, { timeoutMs: 5000 });
+//! const balance = await rpc.call('getBalance', { account }, { timeoutMs: 1000 });
+//! ```
+//!
+//! With `{ compression: 'zstd' }` (or 'lz4') the client offers compression
+//! when connecting; the server must be a native serveFramed() server. See
+//! the compression module.
+//!
+//! With `{ negotiate: true }` the client first exchanges protocol hellos
+//! with the server (see negotiate), so connecting to a server from an
+//! incompatible release fails with VersionMismatch rather than garbling
+//! calls; rpc.protocol holds the outcome.
+
+use napi::bindgen_prelude::*;
+use napi::JsObject;
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::compression::{self, Codec, Compression};
+use crate::errors;
+use crate::negotiate::{self, Hello, Negotiated, NegotiatedProtocol};
+use crate::relay::dup_fd;
+use crate::vsock::{self, VsockStream};
+
+const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+const CONNECT_TIMEOUT_SECS: u32 = 5;
+
+#[napi(object)]
+pub struct RpcClientOptions {
+    /// Deadline for calls that don't set their own (default 30000).
+    pub timeout_ms: Option<u32>,
+    /// Offer "zstd" or "lz4" compression to the server.
+    pub compression: Option<String>,
+    /// Smallest request worth compressing, in bytes (default 1024).
+    pub compression_threshold: Option<u32>,
+    /// Exchange protocol hellos with the server before any call (default
+    /// false). The server must be a native serveFramed() server.
+    pub negotiate: Option<bool>,
+}
+
+#[napi(object)]
+pub struct RpcCallOptions {
+    /// Reject with ETIMEDOUT if no response arrives within this long.
+    pub timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct RpcStats {
+    /// Calls awaiting a response.
+    pub in_flight: u32,
+    /// Calls rejected because their deadline passed.
+    pub timed_out: f64,
+    /// Responses dropped because no call was waiting for their ID.
+    pub late_responses: f64,
+}
+
+type Done = Box<dyn FnOnce(Result<Value>) + Send>;
+
+struct Call {
+    method: String,
+    timeout: Duration,
+    deadline: Instant,
+    done: Done,
+}
+
+#[derive(Default)]
+struct Calls {
+    next_id: u64,
+    pending: HashMap<u64, Call>,
+    /// Why new calls fail, once the connection is gone.
+    closed: Option<String>,
+    timed_out: u64,
+    late_responses: u64,
+}
+
+/// napi-free core of RpcClient.
+struct Client {
+    fd: AtomicI32,
+    codec: Codec,
+    /// Serializes request frames.
+    writer: Mutex<()>,
+    calls: Mutex<Calls>,
+    /// Signalled when a call is added or the client closes.
+    changed: Condvar,
+    default_timeout: Duration,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Client {
+    /// Take over connected `fd` and start the reader and deadline threads.
+    fn start(fd: i32, default_timeout: Duration, codec: Codec) -> Arc<Self> {
+        let client = Arc::new(Client {
+            fd: AtomicI32::new(fd),
+            codec,
+            writer: Mutex::new(()),
+            calls: Mutex::new(Calls::default()),
+            changed: Condvar::new(),
+            default_timeout,
+            threads: Mutex::new(Vec::new()),
+        });
+        let reader = client.clone();
+        let expirer = client.clone();
+        *client.threads.lock().unwrap() = vec![
+            std::thread::spawn(move || reader.read_responses(fd)),
+            std::thread::spawn(move || expirer.expire_calls()),
+        ];
+        client
+    }
+
+    /// Send `method(params)`; `done` gets the result, the JSON-RPC error,
+    /// or the timeout.
+    fn call(&self, method: &str, params: Option<Value>, timeout: Option<Duration>, done: Done) {
+        let timeout = timeout.unwrap_or(self.default_timeout);
+        let id = {
+            let mut calls = self.calls.lock().unwrap();
+            if let Some(reason) = &calls.closed {
+                let reason = reason.clone();
+                drop(calls);
+                return done(Err(Error::from_reason(reason)));
+            }
+            calls.next_id += 1;
+            let id = calls.next_id;
+            calls.pending.insert(
+                id,
+                Call {
+                    method: method.to_string(),
+                    timeout,
+                    deadline: Instant::now() + timeout,
+                    done,
+                },
+            );
+            id
+        };
+        self.changed.notify_all();
+
+        let mut request = json!({ "jsonrpc": "2.0", "id": id, "method": method });
+        if let Some(params) = params {
+            request["params"] = params;
+        }
+        let sent = {
+            let _writer = self.writer.lock().unwrap();
+            match self.fd.load(Ordering::Acquire) {
+                -1 => Err(Error::from_reason("RpcClient is closed")),
+                fd => self
+                    .codec
+                    .write_frame(fd, request.to_string().as_bytes())
+                    .map_err(|e| errors::os_error(format!("write(RPC {})", method), e)),
+            }
+        };
+        if let Err(err) = sent {
+            let call = self.calls.lock().unwrap().pending.remove(&id);
+            if let Some(call) = call {
+                (call.done)(Err(err));
+            }
+        }
+    }
+
+    fn read_responses(&self, fd: i32) {
+        let reason = loop {
+            // Wait for a frame to start without the socket's receive timeout
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break format!("RPC connection failed: {}", err);
+            }
+            match self.codec.read_frame(fd) {
+                Ok(Some(frame)) => self.settle(&frame),
+                Ok(None) => break "RPC connection closed by peer".to_string(),
+                Err(e) => break format!("RPC connection failed: {}", e),
+            }
+        };
+        self.fail_all(reason);
+    }
+
+    /// Settle the call a response frame answers, if it's still waiting.
+    fn settle(&self, frame: &[u8]) {
+        let Ok(response) = serde_json::from_slice::<Value>(frame) else {
+            tracing::debug!(len = frame.len(), "ignoring non-JSON RPC frame");
+            return;
+        };
+        let Some(id) = response.get("id").and_then(Value::as_u64) else {
+            tracing::debug!("ignoring RPC frame without a numeric id");
+            return;
+        };
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            let call = calls.pending.remove(&id);
+            if call.is_none() {
+                calls.late_responses += 1;
+            }
+            call
+        };
+        let Some(call) = call else {
+            tracing::debug!(id, "dropping late RPC response");
+            return;
+        };
+        let result = match response.get("error") {
+            Some(error) => Err(Error::from_reason(format!(
+                "RpcError: {} failed: {} (code {})",
+                call.method,
+                error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("no message"),
+                error.get("code").map_or(Value::Null, Value::clone),
+            ))),
+            None => Ok(response.get("result").cloned().unwrap_or(Value::Null)),
+        };
+        (call.done)(result);
+    }
+
+    /// Reject calls as their deadlines pass, until the client closes.
+    fn expire_calls(&self) {
+        let mut calls = self.calls.lock().unwrap();
+        while calls.closed.is_none() {
+            let now = Instant::now();
+            let expired: Vec<u64> = calls
+                .pending
+                .iter()
+                .filter(|(_, call)| call.deadline <= now)
+                .map(|(&id, _)| id)
+                .collect();
+            if !expired.is_empty() {
+                let expired: Vec<Call> = expired
+                    .iter()
+                    .filter_map(|id| calls.pending.remove(id))
+                    .collect();
+                calls.timed_out += expired.len() as u64;
+                drop(calls);
+                for call in expired {
+                    (call.done)(Err(Error::from_reason(format!(
+                        "ETIMEDOUT: RPC call {} timed out after {}ms",
+                        call.method,
+                        call.timeout.as_millis()
+                    ))));
+                }
+                calls = self.calls.lock().unwrap();
+                continue;
+            }
+            let next = calls.pending.values().map(|call| call.deadline).min();
+            calls = match next {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(now);
+                    self.changed.wait_timeout(calls, wait).unwrap().0
+                }
+                None => self.changed.wait(calls).unwrap(),
+            };
+        }
+    }
+
+    /// Stop taking calls and reject those in flight with `reason`.
+    fn fail_all(&self, reason: String) {
+        let pending = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.closed.get_or_insert(reason.clone());
+            std::mem::take(&mut calls.pending)
+        };
+        self.changed.notify_all();
+        for (_, call) in pending {
+            (call.done)(Err(Error::from_reason(reason.clone())));
+        }
+    }
+
+    fn stats(&self) -> RpcStats {
+        let calls = self.calls.lock().unwrap();
+        RpcStats {
+            in_flight: calls.pending.len() as u32,
+            timed_out: calls.timed_out as f64,
+            late_responses: calls.late_responses as f64,
+        }
+    }
+
+    /// Reject calls in flight, stop the threads and release the fd. Safe
+    /// to call multiple times.
+    fn close(&self) {
+        self.fail_all("RpcClient is closed".to_string());
+        let fd = {
+            let _writer = self.writer.lock().unwrap();
+            self.fd.swap(-1, Ordering::AcqRel)
+        };
+        if fd == -1 {
+            return;
+        }
+        // shutdown() wakes the reader; close once it's gone so the fd
+        // number can't be reused underneath it
+        unsafe {
+            libc::shutdown(fd, libc::SHUT_RDWR);
+        }
+        for thread in self.threads.lock().unwrap().drain(..) {
+            let _ = thread.join();
+        }
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+/// JSON-RPC 2.0 client with per-call deadlines. See the module docs.
+#[napi]
+pub struct RpcClient {
+    inner: Arc<Client>,
+    protocol: Option<Negotiated>,
+}
+
+#[napi]
+impl RpcClient {
+    /// Connect to a JSON-RPC server on a vsock endpoint.
+    #[napi(factory)]
+    pub fn connect(cid: u32, port: u32, options: Option<RpcClientOptions>) -> Result<Self> {
+        let fd = vsock::connect_raw(cid, port, CONNECT_TIMEOUT_SECS)?;
+        Self::start(fd, options)
+    }
+
+    /// Speak JSON-RPC over an already-connected stream. The client uses
+    /// its own duplicate of the descriptor; don't read the stream
+    /// directly afterwards.
+    #[napi(factory)]
+    pub fn from_stream(stream: &VsockStream, options: Option<RpcClientOptions>) -> Result<Self> {
+        Self::start(dup_fd(stream.fd())?, options)
+    }
+
+    /// Take over `fd`, closing it if negotiation or the compression
+    /// handshake fails.
+    fn start(fd: i32, options: Option<RpcClientOptions>) -> Result<Self> {
+        let (timeout_ms, compression, threshold, negotiate) =
+            options.map_or((None, None, None, None), |o| {
+                (
+                    o.timeout_ms,
+                    o.compression,
+                    o.compression_threshold,
+                    o.negotiate,
+                )
+            });
+        let handshake_timeout = Duration::from_secs(CONNECT_TIMEOUT_SECS as u64);
+        let protocol = match negotiate {
+            Some(true) => {
+                let deadline = Instant::now() + handshake_timeout;
+                negotiate::negotiate(fd, &Hello::local(), 0, deadline).map(Some)
+            }
+            _ => Ok(None),
+        };
+        let handshake = protocol.and_then(|protocol| {
+            let codec = match Compression::from_js(compression, threshold)? {
+                None => Codec::Plain,
+                Some(compression) => compression::offer(fd, &compression, handshake_timeout)
+                    .map_err(|e| errors::os_error("compression handshake", e))?,
+            };
+            Ok((protocol, codec))
+        });
+        let (protocol, codec) = handshake.inspect_err(|_| unsafe {
+            libc::close(fd);
+        })?;
+        let timeout = Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS) as u64);
+        Ok(RpcClient {
+            inner: Client::start(fd, timeout, codec),
+            protocol,
+        })
+    }
+
+    /// The compression agreed with the server ("zstd" or "lz4"), or null.
+    #[napi(getter)]
+    pub fn compression(&self) -> Option<&'static str> {
+        self.inner.codec.algorithm().map(|a| a.name())
+    }
+
+    /// The protocol version and features agreed with the server, or null
+    /// without `negotiate`.
+    #[napi(getter)]
+    pub fn protocol(&self) -> Option<NegotiatedProtocol> {
+        self.protocol.clone().map(Negotiated::into_js)
+    }
+
+    /// Call `method` with `params`. Resolves to the result; rejects with
+    /// code "RpcError" for an error response, "ETIMEDOUT" once the
+    /// deadline passes, or if the connection closes first.
+    #[napi(ts_return_type = "Promise<unknown>")]
+    pub fn call(
+        &self,
+        env: Env,
+        method: String,
+        #[napi(ts_arg_type = "unknown")] params: Option<Value>,
+        options: Option<RpcCallOptions>,
+    ) -> Result<JsObject> {
+        let (deferred, promise) = env.create_deferred()?;
+        let timeout = options
+            .and_then(|o| o.timeout_ms)
+            .map(|ms| Duration::from_millis(ms as u64));
+        self.inner.call(
+            &method,
+            params,
+            timeout,
+            Box::new(move |result| {
+                deferred.resolve(move |env| errors::structured(&env, result));
+            }),
+        );
+        Ok(promise)
+    }
+
+    /// Calls in flight and how many timed out or were answered too late.
+    #[napi]
+    pub fn stats(&self) -> RpcStats {
+        self.inner.stats()
+    }
+
+    /// Close the connection, rejecting calls in flight. Safe to call
+    /// multiple times.
+    #[napi]
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+impl Drop for RpcClient {
+    fn drop(&mut self) {
+        self.inner.close();
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+
+    fn request(fd: i32) -> Value {
+        serde_json::from_slice(&framing::read_frame(fd).unwrap().unwrap()).unwrap()
+    }
+
+    fn respond(fd: i32, response: Value) {
+        framing::write_frame(fd, response.to_string().as_bytes()).unwrap();
+    }
+
+    /// `client.call()` whose result arrives on the returned channel.
+    fn call(
+        client: &Client,
+        method: &str,
+        timeout_ms: Option<u64>,
+    ) -> mpsc::Receiver<Result<Value>> {
+        let (tx, rx) = mpsc::channel();
+        client.call(
+            method,
+            Some(json!([1])),
+            timeout_ms.map(Duration::from_millis),
+            Box::new(move |result| tx.send(result).unwrap()),
+        );
+        rx
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn responses_settle_their_own_calls() {
+        let (server, conn) = UnixStream::pair().unwrap();
+        let client = Client::start(conn.into_raw_fd(), Duration::from_secs(30), Codec::Plain);
+        let first = call(&client, "first", None);
+        let second = call(&client, "second", None);
+        let (a, b) = (request(server.as_raw_fd()), request(server.as_raw_fd()));
+        assert_eq!(a["jsonrpc"], "2.0");
+        assert_eq!(a["method"], "first");
+        assert_eq!(a["params"], json!([1]));
+        assert_ne!(a["id"], b["id"]);
+
+        // Out of order, one failing
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "id": b["id"], "result": { "ok": true } }),
+        );
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "id": a["id"], "error": { "code": -32601, "message": "Method not found" } }),
+        );
+        assert_eq!(
+            second.recv_timeout(WAIT).unwrap().unwrap(),
+            json!({ "ok": true })
+        );
+        assert_eq!(
+            first.recv_timeout(WAIT).unwrap().unwrap_err().reason,
+            "RpcError: first failed: Method not found (code -32601)"
+        );
+        assert_eq!(client.stats().in_flight, 0);
+        client.close();
+    }
+
+    #[test]
+    fn calls_time_out_individually_and_late_responses_are_dropped() {
+        let (server, conn) = UnixStream::pair().unwrap();
+        let client = Client::start(conn.into_raw_fd(), Duration::from_secs(30), Codec::Plain);
+        let lost = call(&client, "lost", Some(50));
+        let patient = call(&client, "patient", None);
+        let lost_id = request(server.as_raw_fd())["id"].clone();
+        let patient_id = request(server.as_raw_fd())["id"].clone();
+
+        let err = lost.recv_timeout(WAIT).unwrap().unwrap_err();
+        assert_eq!(err.reason, "ETIMEDOUT: RPC call lost timed out after 50ms");
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "id": lost_id, "result": 1 }),
+        );
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "id": patient_id, "result": 2 }),
+        );
+        assert_eq!(patient.recv_timeout(WAIT).unwrap().unwrap(), json!(2));
+        let stats = client.stats();
+        assert_eq!((stats.timed_out, stats.late_responses), (1.0, 1.0));
+
+        // The peer going away fails what's in flight and what comes after
+        let pending = call(&client, "pending", None);
+        request(server.as_raw_fd());
+        drop(server);
+        let err = pending.recv_timeout(WAIT).unwrap().unwrap_err();
+        assert_eq!(err.reason, "RPC connection closed by peer");
+        let err = call(&client, "after", None).recv_timeout(WAIT).unwrap();
+        assert_eq!(err.unwrap_err().reason, "RPC connection closed by peer");
+        client.close();
+    }
+
+    #[test]
+    fn frames_that_are_not_responses_are_ignored() {
+        let (server, conn) = UnixStream::pair().unwrap();
+        let client = Client::start(conn.into_raw_fd(), Duration::from_secs(30), Codec::Plain);
+        let waiting = call(&client, "waiting", None);
+        let id = request(server.as_raw_fd())["id"].clone();
+
+        framing::write_frame(server.as_raw_fd(), b"not json").unwrap();
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "id": "1", "result": 0 }),
+        );
+        respond(
+            server.as_raw_fd(),
+            json!({ "jsonrpc": "2.0", "method": "notify" }),
+        );
+        respond(server.as_raw_fd(), json!({ "jsonrpc": "2.0", "id": id }));
+        assert_eq!(waiting.recv_timeout(WAIT).unwrap().unwrap(), Value::Null);
+        assert_eq!(client.stats().late_responses, 0.0);
+        client.close();
+    }
+
+    #[test]
+    fn close_rejects_calls_in_flight_and_later_ones() {
+        let (server, conn) = UnixStream::pair().unwrap();
+        let client = Client::start(conn.into_raw_fd(), Duration::from_secs(30), Codec::Plain);
+        let pending = call(&client, "pending", None);
+        request(server.as_raw_fd());
+
+        client.close();
+        let err = pending.recv_timeout(WAIT).unwrap().unwrap_err();
+        assert_eq!(err.reason, "RpcClient is closed");
+        assert_eq!(client.stats().in_flight, 0);
+        let err = call(&client, "after", None).recv_timeout(WAIT).unwrap();
+        assert_eq!(err.unwrap_err().reason, "RpcClient is closed");
+        // The server sees the connection end
+        assert_eq!(framing::read_frame(server.as_raw_fd()).unwrap(), None);
+        client.close();
+    }
+}
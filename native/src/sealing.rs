@@ -0,0 +1,369 @@
+//! Sealed data: encryption only an attested enclave can undo.
+//!
+//! sealData() asks KMS, through a KmsClient and so with a Recipient
+//! attestation document, for a fresh AES-256 data key, encrypts the data
+//! with it natively and returns a self-describing blob that carries the
+//! encrypted data key. unsealData() has KMS decrypt that key, again to an
+//! attested Recipient, and opens the blob. Binding to the enclave image is
+//! the key policy's job: with a condition such as
+//!
+//! ```json
+//! "Condition": { "StringEqualsIgnoreCase": { "kms:RecipientAttestation:PCR0": "<EIF PCR0>" } }
+//! ```
+//!
+//! only enclaves with those measurements can unseal, much like SGX sealing
+//! to MRENCLAVE. The blob can be stored anywhere, e.g. on the parent.
+//!
+//! ```js
+//! const blob = await sealData(kms, secret, { keyId: 'alias/enclave-sealing' });
+//! const secret = await unsealData(kms, blob);
+//! ```
+//!
+//! Blob format: a CBOR map `{"format": "tytle-sealed", "version": 1,
+//! "algorithm": "AES_256_GCM", "key_id": text, "encryption_context": {text:
+//! text} | null, "encrypted_key": bytes, "ciphertext": bytes}`, where
+//! `encrypted_key` is the KMS CiphertextBlob of the data key and
+//! `ciphertext` is a 12-byte nonce, the data and a 16-byte tag. The AAD is
+//! the CBOR encoding of every other field, in that order.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::HashMap;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+use crate::kms::{self, KmsClient};
+use crate::workers::WorkerTask;
+use crate::{cbor, secure_memory};
+
+const FORMAT: &str = "tytle-sealed";
+const VERSION: u64 = 1;
+const ALGORITHM: &str = "AES_256_GCM";
+
+#[napi(object)]
+pub struct SealOptions {
+    /// KMS key whose policy decides who may unseal.
+    pub key_id: String,
+    /// Stored in the blob and required by KMS to unseal it.
+    pub encryption_context: Option<HashMap<String, String>>,
+}
+
+#[napi(object)]
+pub struct UnsealOptions {
+    /// Reject blobs sealed under a different encryption context.
+    pub encryption_context: Option<HashMap<String, String>>,
+}
+
+/// Encrypt `plaintext` under a new KMS data key. Resolves to the sealed
+/// blob. Runs on the blocking-call thread pool (see configureThreadPool()).
+#[napi(ts_return_type = "Promise<Buffer>")]
+pub fn seal_data(kms: &KmsClient, plaintext: Buffer, options: SealOptions) -> WorkerTask<SealTask> {
+    WorkerTask::new(SealTask {
+        kms: kms.client(),
+        plaintext: Zeroizing::new(plaintext.to_vec()),
+        key_id: options.key_id,
+        encryption_context: options.encryption_context.map(sorted),
+    })
+}
+
+/// Decrypt a sealData() blob. Rejects with code "InvalidSealedData" for
+/// blobs that aren't one, or "DecryptionFailed" if it was altered.
+#[napi(ts_return_type = "Promise<Buffer>")]
+pub fn unseal_data(
+    kms: &KmsClient,
+    blob: Buffer,
+    options: Option<UnsealOptions>,
+) -> WorkerTask<UnsealTask> {
+    WorkerTask::new(UnsealTask {
+        kms: kms.client(),
+        blob: blob.to_vec(),
+        expected_context: options.and_then(|o| o.encryption_context).map(sorted),
+    })
+}
+
+pub struct SealTask {
+    kms: Arc<kms::Client>,
+    plaintext: Zeroizing<Vec<u8>>,
+    key_id: String,
+    encryption_context: Option<Vec<(String, String)>>,
+}
+
+impl Task for SealTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let mut body = serde_json::json!({ "KeyId": self.key_id, "KeySpec": "AES_256" });
+        if let Some(context) = &self.encryption_context {
+            body["EncryptionContext"] = context_json(context);
+        }
+        let key = self.kms.generate_data_key(body)?;
+        let header = Header {
+            key_id: key.key_id,
+            encryption_context: self.encryption_context.take(),
+            encrypted_key: key.ciphertext_blob,
+        };
+        seal(header, &Zeroizing::new(key.plaintext), &self.plaintext)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output.into())
+    }
+}
+
+pub struct UnsealTask {
+    kms: Arc<kms::Client>,
+    blob: Vec<u8>,
+    expected_context: Option<Vec<(String, String)>>,
+}
+
+impl Task for UnsealTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let (header, ciphertext) = parse(&self.blob)?;
+        check_context(self.expected_context.as_deref(), &header)?;
+        let mut body = serde_json::json!({
+            "CiphertextBlob": BASE64.encode(&header.encrypted_key),
+            "KeyId": header.key_id,
+        });
+        if let Some(context) = &header.encryption_context {
+            body["EncryptionContext"] = context_json(context);
+        }
+        let key = Zeroizing::new(self.kms.call_for_recipient("Decrypt", body)?);
+        open(&header, &key, &ciphertext)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output.into())
+    }
+}
+
+/// Fail unless `header` was sealed under `expected`, if given.
+fn check_context(expected: Option<&[(String, String)]>, header: &Header) -> Result<()> {
+    match expected {
+        Some(expected) if header.encryption_context.as_deref() != Some(expected) => Err(
+            Error::from_reason("InvalidSealedData: sealed under a different encryption context"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Encryption contexts sorted by key, so blobs encode deterministically.
+fn sorted(context: HashMap<String, String>) -> Vec<(String, String)> {
+    let mut context: Vec<_> = context.into_iter().collect();
+    context.sort();
+    context
+}
+
+fn context_json(context: &[(String, String)]) -> serde_json::Value {
+    context
+        .iter()
+        .map(|(k, v)| (k.clone(), serde_json::Value::from(v.as_str())))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Everything in a blob but the ciphertext.
+#[derive(Debug, PartialEq)]
+struct Header {
+    key_id: String,
+    encryption_context: Option<Vec<(String, String)>>,
+    encrypted_key: Vec<u8>,
+}
+
+impl Header {
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        let context = match &self.encryption_context {
+            Some(context) => Value::Map(
+                context
+                    .iter()
+                    .map(|(k, v)| (cbor::text(k), cbor::text(v)))
+                    .collect(),
+            ),
+            None => Value::Null,
+        };
+        vec![
+            ("format", cbor::text(FORMAT)),
+            ("version", Value::from(VERSION)),
+            ("algorithm", cbor::text(ALGORITHM)),
+            ("key_id", cbor::text(&self.key_id)),
+            ("encryption_context", context),
+            ("encrypted_key", Value::Bytes(self.encrypted_key.clone())),
+        ]
+    }
+
+    fn aad(&self) -> Result<Vec<u8>> {
+        cbor::encode(&cbor::map(self.fields()))
+    }
+}
+
+fn seal(header: Header, data_key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let ciphertext = secure_memory::seal(data_key, plaintext, &header.aad()?)?;
+    let mut fields = header.fields();
+    fields.push(("ciphertext", Value::Bytes(ciphertext)));
+    cbor::encode(&cbor::map(fields))
+}
+
+fn open(header: &Header, data_key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    secure_memory::open(data_key, ciphertext, &header.aad()?)
+}
+
+/// Split a blob into its header and ciphertext.
+fn parse(blob: &[u8]) -> Result<(Header, Vec<u8>)> {
+    let invalid = |what: &str| Error::from_reason(format!("InvalidSealedData: {}", what));
+    let value = cbor::decode(blob).map_err(|_| invalid("not a sealed blob"))?;
+    let text = |field: &str| cbor::map_get(&value, field).and_then(cbor::as_text);
+    let bytes = |field: &str| cbor::map_get(&value, field).and_then(cbor::as_bytes);
+    if text("format") != Some(FORMAT) {
+        return Err(invalid("not a sealed blob"));
+    }
+    let version = cbor::map_get(&value, "version").and_then(cbor::as_u64);
+    if version != Some(VERSION) || text("algorithm") != Some(ALGORITHM) {
+        return Err(invalid(&format!(
+            "unsupported version {:?} or algorithm {:?}",
+            version,
+            text("algorithm")
+        )));
+    }
+    let encryption_context = match cbor::map_get(&value, "encryption_context") {
+        None | Some(Value::Null) => None,
+        Some(Value::Map(entries)) => Some(
+            entries
+                .iter()
+                .map(|(k, v)| match (cbor::as_text(k), cbor::as_text(v)) {
+                    (Some(k), Some(v)) => Ok((k.to_string(), v.to_string())),
+                    _ => Err(invalid("malformed encryption_context")),
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        Some(_) => return Err(invalid("malformed encryption_context")),
+    };
+    let header = Header {
+        key_id: text("key_id")
+            .ok_or_else(|| invalid("missing key_id"))?
+            .to_string(),
+        encryption_context,
+        encrypted_key: bytes("encrypted_key").ok_or_else(|| invalid("missing encrypted_key"))?,
+    };
+    let ciphertext = bytes("ciphertext").ok_or_else(|| invalid("missing ciphertext"))?;
+    Ok((header, ciphertext))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            key_id: "arn:aws:kms:us-east-1:111122223333:key/sealing".to_string(),
+            encryption_context: Some(sorted(HashMap::from([
+                ("purpose".to_string(), "db".to_string()),
+                ("app".to_string(), "ledger".to_string()),
+            ]))),
+            encrypted_key: vec![9; 184],
+        }
+    }
+
+    #[test]
+    fn blobs_describe_themselves_and_open_with_the_data_key() {
+        let key = [7u8; 32];
+        let blob = seal(header(), &key, b"database password").unwrap();
+        let value = cbor::decode(&blob).unwrap();
+        assert_eq!(
+            cbor::map_get(&value, "format").and_then(cbor::as_text),
+            Some(FORMAT)
+        );
+
+        let (parsed, ciphertext) = parse(&blob).unwrap();
+        assert_eq!(parsed, header());
+        assert_eq!(
+            parsed.encryption_context.as_deref().unwrap()[0],
+            ("app".to_string(), "ledger".to_string())
+        );
+        assert_eq!(
+            open(&parsed, &key, &ciphertext).unwrap(),
+            b"database password"
+        );
+        assert!(open(&parsed, &[8u8; 32], &ciphertext).is_err());
+    }
+
+    #[test]
+    fn altered_or_foreign_blobs_are_rejected() {
+        let key = [7u8; 32];
+        let blob = seal(header(), &key, b"secret").unwrap();
+        let (mut parsed, ciphertext) = parse(&blob).unwrap();
+        parsed.key_id = "arn:aws:kms:us-east-1:111122223333:key/other".to_string();
+        let err = open(&parsed, &key, &ciphertext).unwrap_err();
+        assert!(err.reason.starts_with("DecryptionFailed"));
+
+        let err = parse(&cbor::encode(&cbor::map(vec![("format", cbor::text("x"))])).unwrap());
+        assert_eq!(
+            err.unwrap_err().reason,
+            "InvalidSealedData: not a sealed blob"
+        );
+        assert!(parse(b"not cbor at all").is_err());
+        let mut fields = header().fields();
+        fields[1].1 = Value::from(2u64);
+        let err = parse(&cbor::encode(&cbor::map(fields)).unwrap()).unwrap_err();
+        assert!(err
+            .reason
+            .starts_with("InvalidSealedData: unsupported version"));
+
+        let mut fields = header().fields();
+        fields[4].1 = Value::Map(vec![(cbor::text("app"), Value::from(1u64))]);
+        let err = parse(&cbor::encode(&cbor::map(fields)).unwrap()).unwrap_err();
+        assert_eq!(
+            err.reason,
+            "InvalidSealedData: malformed encryption_context"
+        );
+        let err = parse(&cbor::encode(&cbor::map(header().fields())).unwrap()).unwrap_err();
+        assert_eq!(err.reason, "InvalidSealedData: missing ciphertext");
+    }
+
+    #[test]
+    fn blobs_without_a_context_store_null() {
+        let header = Header {
+            encryption_context: None,
+            ..header()
+        };
+        let blob = seal(header, &[7u8; 32], b"secret").unwrap();
+        let value = cbor::decode(&blob).unwrap();
+        assert_eq!(
+            cbor::map_get(&value, "encryption_context"),
+            Some(&Value::Null)
+        );
+        let (parsed, ciphertext) = parse(&blob).unwrap();
+        assert_eq!(parsed.encryption_context, None);
+        assert_eq!(open(&parsed, &[7u8; 32], &ciphertext).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn unsealing_checks_the_expected_context() {
+        let same = sorted(HashMap::from([
+            ("app".to_string(), "ledger".to_string()),
+            ("purpose".to_string(), "db".to_string()),
+        ]));
+        let other = sorted(HashMap::from([("app".to_string(), "ledger".to_string())]));
+        assert!(check_context(None, &header()).is_ok());
+        assert!(check_context(Some(&same), &header()).is_ok());
+        let err = check_context(Some(&other), &header()).unwrap_err();
+        assert_eq!(
+            err.reason,
+            "InvalidSealedData: sealed under a different encryption context"
+        );
+        let unbound = Header {
+            encryption_context: None,
+            ..header()
+        };
+        assert!(check_context(Some(&same), &unbound).is_err());
+    }
+}
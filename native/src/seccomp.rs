@@ -0,0 +1,771 @@
+//! seccomp-BPF lockdown of the enclave process.
+//!
+//! Once an enclave app has loaded its code and opened what it needs, most
+//! of the kernel's syscall surface is only useful to an attacker.
+//! applySeccompProfile() installs a filter on every thread of the process
+//! that lets through only an allowlist of syscalls: by default the ones
+//! the addon (vsock I/O, NSM ioctls, io_uring, timesync) and Node itself
+//! (memory, threads, epoll, files) use. Anything else fails with EPERM,
+//! or kills the process with `action: 'kill'`:
+//!
+//! ```js
+//! await startServices();
+//! applySeccompProfile({ allow: ['getpgid'], action: 'kill' });
+//! ```
+//!
+//! Filters can't be removed, only stacked: calling it again can restrict
+//! the process further but never loosen it. No new process can be spawned
+//! under the default profile (execve is not allowed). Supported on x86_64
+//! and aarch64 Linux; seccompDefaultAllowlist() lists the default profile.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SeccompProfile {
+    /// Allowlist to start from: "default" (the default) or "none".
+    pub base: Option<String>,
+    /// Further syscalls to allow, by name, e.g. ["getpgid"].
+    pub allow: Option<Vec<String>>,
+    /// What other syscalls do: "errno" (fail with EPERM, the default),
+    /// "kill" (kill the process) or "log" (run, but get logged by the
+    /// kernel; for building a profile).
+    pub action: Option<String>,
+}
+
+/// What happens to a syscall missing from the allowlist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Errno,
+    Kill,
+    Log,
+}
+
+/// A parsed SeccompProfile.
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Profile {
+    /// Syscalls to start from; names this architecture lacks are skipped.
+    base: &'static [&'static str],
+    /// Syscalls the caller added; each must exist.
+    allow: Vec<String>,
+    action: Action,
+}
+
+impl Profile {
+    fn parse(profile: SeccompProfile) -> Result<Self> {
+        let base = match profile.base.as_deref().unwrap_or("default") {
+            "default" => DEFAULT_ALLOWLIST,
+            "none" => &[],
+            other => {
+                return Err(Error::from_reason(format!(
+                    "Invalid base {:?}: expected default or none",
+                    other
+                )))
+            }
+        };
+        let action = match profile.action.as_deref().unwrap_or("errno") {
+            "errno" => Action::Errno,
+            "kill" => Action::Kill,
+            "log" => Action::Log,
+            other => {
+                return Err(Error::from_reason(format!(
+                    "Invalid action {:?}: expected errno, kill or log",
+                    other
+                )))
+            }
+        };
+        Ok(Profile {
+            base,
+            allow: profile.allow.unwrap_or_default(),
+            action,
+        })
+    }
+}
+
+/// The default profile: the addon's syscalls and Node's.
+const DEFAULT_ALLOWLIST: &[&str] = &[
+    // Files and descriptors
+    "read",
+    "write",
+    "readv",
+    "writev",
+    "pread64",
+    "pwrite64",
+    "open",
+    "openat",
+    "close",
+    "close_range",
+    "lseek",
+    "fstat",
+    "stat",
+    "lstat",
+    "newfstatat",
+    "statx",
+    "statfs",
+    "fstatfs",
+    "access",
+    "faccessat",
+    "faccessat2",
+    "readlink",
+    "readlinkat",
+    "getdents64",
+    "getcwd",
+    "ftruncate",
+    "fsync",
+    "fdatasync",
+    "rename",
+    "renameat2",
+    "unlink",
+    "unlinkat",
+    "mkdir",
+    "mkdirat",
+    "fcntl",
+    "ioctl",
+    "dup",
+    "dup2",
+    "dup3",
+    "pipe",
+    "pipe2",
+    "sendfile",
+    "splice",
+    // Sockets
+    "socket",
+    "socketpair",
+    "bind",
+    "listen",
+    "accept",
+    "accept4",
+    "connect",
+    "getsockname",
+    "getpeername",
+    "setsockopt",
+    "getsockopt",
+    "sendto",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "shutdown",
+    // Event loop
+    "poll",
+    "ppoll",
+    "select",
+    "pselect6",
+    "epoll_create",
+    "epoll_create1",
+    "epoll_ctl",
+    "epoll_wait",
+    "epoll_pwait",
+    "epoll_pwait2",
+    "eventfd",
+    "eventfd2",
+    "timerfd_create",
+    "timerfd_settime",
+    "timerfd_gettime",
+    "io_uring_setup",
+    "io_uring_enter",
+    "io_uring_register",
+    // Memory
+    "mmap",
+    "munmap",
+    "mprotect",
+    "mremap",
+    "madvise",
+    "brk",
+    "mlock",
+    "mlock2",
+    "munlock",
+    "mlockall",
+    "munlockall",
+    "membarrier",
+    // Threads, signals and process state
+    "clone",
+    "clone3",
+    "futex",
+    "set_robust_list",
+    "get_robust_list",
+    "set_tid_address",
+    "rseq",
+    "exit",
+    "exit_group",
+    "wait4",
+    "kill",
+    "tgkill",
+    "rt_sigaction",
+    "rt_sigprocmask",
+    "rt_sigreturn",
+    "sigaltstack",
+    "restart_syscall",
+    "sched_yield",
+    "sched_getaffinity",
+    "gettid",
+    "getpid",
+    "getppid",
+    "getuid",
+    "geteuid",
+    "getgid",
+    "getegid",
+    "getrlimit",
+    "prlimit64",
+    "getrusage",
+    "sysinfo",
+    "uname",
+    "capget",
+    "arch_prctl",
+    "prctl",
+    "seccomp",
+    // Time and randomness
+    "clock_gettime",
+    "clock_getres",
+    "clock_nanosleep",
+    "clock_settime",
+    "nanosleep",
+    "getrandom",
+];
+
+/// The syscalls the default profile allows on this architecture.
+#[napi]
+pub fn seccomp_default_allowlist() -> Vec<String> {
+    DEFAULT_ALLOWLIST
+        .iter()
+        .filter(|name| filter::syscall_number(name).is_some())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Restrict every thread of this process to `profile`'s syscalls (the
+/// default profile if omitted). Returns how many syscalls are allowed.
+/// Irreversible; see the module docs.
+#[napi]
+pub fn apply_seccomp_profile(profile: Option<SeccompProfile>) -> Result<u32> {
+    let profile = Profile::parse(profile.unwrap_or_default())?;
+    let program = filter::compile(&profile)?;
+    filter::install(&program)?;
+    Ok(filter::allowed(&program))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod filter {
+    use super::{Action, Profile};
+    use crate::errors;
+    use napi::bindgen_prelude::*;
+
+    /// AUDIT_ARCH_* from linux/audit.h, for seccomp_data.arch.
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Set in syscall numbers of the x32 ABI, which shares x86_64's arch.
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Syscalls named the same on both architectures.
+    const SYSCALLS: &[(&str, libc::c_long)] = &[
+        ("read", libc::SYS_read),
+        ("write", libc::SYS_write),
+        ("readv", libc::SYS_readv),
+        ("writev", libc::SYS_writev),
+        ("pread64", libc::SYS_pread64),
+        ("pwrite64", libc::SYS_pwrite64),
+        ("openat", libc::SYS_openat),
+        ("close", libc::SYS_close),
+        ("close_range", libc::SYS_close_range),
+        ("lseek", libc::SYS_lseek),
+        ("fstat", libc::SYS_fstat),
+        ("newfstatat", libc::SYS_newfstatat),
+        ("statx", libc::SYS_statx),
+        ("statfs", libc::SYS_statfs),
+        ("fstatfs", libc::SYS_fstatfs),
+        ("faccessat", libc::SYS_faccessat),
+        ("faccessat2", libc::SYS_faccessat2),
+        ("readlinkat", libc::SYS_readlinkat),
+        ("getdents64", libc::SYS_getdents64),
+        ("getcwd", libc::SYS_getcwd),
+        ("ftruncate", libc::SYS_ftruncate),
+        ("fsync", libc::SYS_fsync),
+        ("fdatasync", libc::SYS_fdatasync),
+        ("renameat2", libc::SYS_renameat2),
+        ("unlinkat", libc::SYS_unlinkat),
+        ("mkdirat", libc::SYS_mkdirat),
+        ("fcntl", libc::SYS_fcntl),
+        ("ioctl", libc::SYS_ioctl),
+        ("dup", libc::SYS_dup),
+        ("dup3", libc::SYS_dup3),
+        ("pipe2", libc::SYS_pipe2),
+        ("splice", libc::SYS_splice),
+        ("socket", libc::SYS_socket),
+        ("socketpair", libc::SYS_socketpair),
+        ("bind", libc::SYS_bind),
+        ("listen", libc::SYS_listen),
+        ("accept", libc::SYS_accept),
+        ("accept4", libc::SYS_accept4),
+        ("connect", libc::SYS_connect),
+        ("getsockname", libc::SYS_getsockname),
+        ("getpeername", libc::SYS_getpeername),
+        ("setsockopt", libc::SYS_setsockopt),
+        ("getsockopt", libc::SYS_getsockopt),
+        ("sendto", libc::SYS_sendto),
+        ("recvfrom", libc::SYS_recvfrom),
+        ("sendmsg", libc::SYS_sendmsg),
+        ("recvmsg", libc::SYS_recvmsg),
+        ("shutdown", libc::SYS_shutdown),
+        ("ppoll", libc::SYS_ppoll),
+        ("pselect6", libc::SYS_pselect6),
+        ("epoll_create1", libc::SYS_epoll_create1),
+        ("epoll_ctl", libc::SYS_epoll_ctl),
+        ("epoll_pwait", libc::SYS_epoll_pwait),
+        ("epoll_pwait2", libc::SYS_epoll_pwait2),
+        ("eventfd2", libc::SYS_eventfd2),
+        ("timerfd_create", libc::SYS_timerfd_create),
+        ("timerfd_settime", libc::SYS_timerfd_settime),
+        ("timerfd_gettime", libc::SYS_timerfd_gettime),
+        ("io_uring_setup", libc::SYS_io_uring_setup),
+        ("io_uring_enter", libc::SYS_io_uring_enter),
+        ("io_uring_register", libc::SYS_io_uring_register),
+        ("mmap", libc::SYS_mmap),
+        ("munmap", libc::SYS_munmap),
+        ("mprotect", libc::SYS_mprotect),
+        ("mremap", libc::SYS_mremap),
+        ("madvise", libc::SYS_madvise),
+        ("brk", libc::SYS_brk),
+        ("mlock", libc::SYS_mlock),
+        ("mlock2", libc::SYS_mlock2),
+        ("munlock", libc::SYS_munlock),
+        ("mlockall", libc::SYS_mlockall),
+        ("munlockall", libc::SYS_munlockall),
+        ("membarrier", libc::SYS_membarrier),
+        ("clone", libc::SYS_clone),
+        ("clone3", libc::SYS_clone3),
+        ("futex", libc::SYS_futex),
+        ("set_robust_list", libc::SYS_set_robust_list),
+        ("get_robust_list", libc::SYS_get_robust_list),
+        ("set_tid_address", libc::SYS_set_tid_address),
+        ("rseq", libc::SYS_rseq),
+        ("exit", libc::SYS_exit),
+        ("exit_group", libc::SYS_exit_group),
+        ("wait4", libc::SYS_wait4),
+        ("kill", libc::SYS_kill),
+        ("tgkill", libc::SYS_tgkill),
+        ("rt_sigaction", libc::SYS_rt_sigaction),
+        ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+        ("rt_sigreturn", libc::SYS_rt_sigreturn),
+        ("sigaltstack", libc::SYS_sigaltstack),
+        ("restart_syscall", libc::SYS_restart_syscall),
+        ("sched_yield", libc::SYS_sched_yield),
+        ("sched_getaffinity", libc::SYS_sched_getaffinity),
+        ("gettid", libc::SYS_gettid),
+        ("getpid", libc::SYS_getpid),
+        ("getppid", libc::SYS_getppid),
+        ("getuid", libc::SYS_getuid),
+        ("geteuid", libc::SYS_geteuid),
+        ("getgid", libc::SYS_getgid),
+        ("getegid", libc::SYS_getegid),
+        ("getpgid", libc::SYS_getpgid),
+        ("prlimit64", libc::SYS_prlimit64),
+        ("getrusage", libc::SYS_getrusage),
+        ("sysinfo", libc::SYS_sysinfo),
+        ("uname", libc::SYS_uname),
+        ("capget", libc::SYS_capget),
+        ("prctl", libc::SYS_prctl),
+        ("seccomp", libc::SYS_seccomp),
+        ("clock_gettime", libc::SYS_clock_gettime),
+        ("clock_getres", libc::SYS_clock_getres),
+        ("clock_nanosleep", libc::SYS_clock_nanosleep),
+        ("clock_settime", libc::SYS_clock_settime),
+        ("nanosleep", libc::SYS_nanosleep),
+        ("getrandom", libc::SYS_getrandom),
+        ("execve", libc::SYS_execve),
+        ("ptrace", libc::SYS_ptrace),
+        ("chroot", libc::SYS_chroot),
+        ("mount", libc::SYS_mount),
+        ("setuid", libc::SYS_setuid),
+        ("setgid", libc::SYS_setgid),
+    ];
+
+    /// Legacy syscalls only x86_64 has; aarch64 only has their *at or
+    /// p* successors.
+    #[cfg(target_arch = "x86_64")]
+    const ARCH_SYSCALLS: &[(&str, libc::c_long)] = &[
+        ("open", libc::SYS_open),
+        ("stat", libc::SYS_stat),
+        ("lstat", libc::SYS_lstat),
+        ("access", libc::SYS_access),
+        ("readlink", libc::SYS_readlink),
+        ("rename", libc::SYS_rename),
+        ("unlink", libc::SYS_unlink),
+        ("mkdir", libc::SYS_mkdir),
+        ("dup2", libc::SYS_dup2),
+        ("pipe", libc::SYS_pipe),
+        ("sendfile", libc::SYS_sendfile),
+        ("poll", libc::SYS_poll),
+        ("select", libc::SYS_select),
+        ("epoll_create", libc::SYS_epoll_create),
+        ("epoll_wait", libc::SYS_epoll_wait),
+        ("eventfd", libc::SYS_eventfd),
+        ("getrlimit", libc::SYS_getrlimit),
+        ("arch_prctl", libc::SYS_arch_prctl),
+        ("fork", libc::SYS_fork),
+        ("vfork", libc::SYS_vfork),
+    ];
+    #[cfg(target_arch = "aarch64")]
+    const ARCH_SYSCALLS: &[(&str, libc::c_long)] = &[
+        // Missing from libc for aarch64; asm-generic/unistd.h
+        ("sendfile", 71),
+        ("getrlimit", 163),
+    ];
+
+    pub(super) fn syscall_number(name: &str) -> Option<u32> {
+        SYSCALLS
+            .iter()
+            .chain(ARCH_SYSCALLS)
+            .find(|&&(n, _)| n == name)
+            .map(|&(_, nr)| nr as u32)
+    }
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    fn ret(action: u32) -> libc::sock_filter {
+        statement(libc::BPF_RET | libc::BPF_K, action)
+    }
+
+    const ARCH_OFFSET: u32 = 4;
+    const NR_OFFSET: u32 = 0;
+
+    /// The BPF program for `profile`. Syscalls the base allowlist names
+    /// but this architecture lacks are skipped; unknown names in `allow`
+    /// are an error.
+    pub(super) fn compile(profile: &Profile) -> Result<Vec<libc::sock_filter>> {
+        let mut numbers: Vec<u32> = profile
+            .base
+            .iter()
+            .filter_map(|name| syscall_number(name))
+            .collect();
+        for name in &profile.allow {
+            let nr = syscall_number(name).ok_or_else(|| {
+                Error::from_reason(format!(
+                    "Unknown syscall {:?} for {}",
+                    name,
+                    std::env::consts::ARCH
+                ))
+            })?;
+            numbers.push(nr);
+        }
+        numbers.sort_unstable();
+        numbers.dedup();
+
+        let deny = match profile.action {
+            Action::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+            Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            Action::Log => libc::SECCOMP_RET_LOG,
+        };
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let mut program = vec![
+            // Syscall numbers mean nothing for another ABI
+            statement(load, ARCH_OFFSET),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            statement(load, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            ret(deny),
+        ]);
+        for nr in numbers {
+            program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr, 0, 1));
+            program.push(ret(libc::SECCOMP_RET_ALLOW));
+        }
+        program.push(ret(deny));
+        Ok(program)
+    }
+
+    /// How many syscalls `program` allows.
+    pub(super) fn allowed(program: &[libc::sock_filter]) -> u32 {
+        let allow = ret(libc::SECCOMP_RET_ALLOW);
+        program
+            .iter()
+            .filter(|insn| insn.code == allow.code && insn.k == allow.k)
+            .count() as u32
+    }
+
+    /// Install `program` on every thread of the process.
+    pub(super) fn install(program: &[libc::sock_filter]) -> Result<()> {
+        let prog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        // Required to install filters without CAP_SYS_ADMIN
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(errors::os_error(
+                "prctl(PR_SET_NO_NEW_PRIVS)",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret < 0 {
+            return Err(errors::os_error(
+                "seccomp(SECCOMP_SET_MODE_FILTER)",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        if ret > 0 {
+            return Err(Error::from_reason(format!(
+                "seccomp(SECCOMP_SET_MODE_FILTER) failed: thread {} can't be synchronized",
+                ret
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod filter {
+    use super::Profile;
+    use crate::platform;
+    use napi::bindgen_prelude::*;
+
+    pub(super) fn syscall_number(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub(super) fn compile(_profile: &Profile) -> Result<Vec<()>> {
+        Err(platform::unsupported("seccomp"))
+    }
+
+    pub(super) fn allowed(_program: &[()]) -> u32 {
+        0
+    }
+
+    pub(super) fn install(_program: &[()]) -> Result<()> {
+        Err(platform::unsupported("seccomp"))
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod tests {
+    use super::*;
+
+    /// Run `child` in a forked process under `profile`'s filter, returning
+    /// its exit status.
+    fn in_child(profile: SeccompProfile, child: fn() -> i32) -> i32 {
+        let program = filter::compile(&Profile::parse(profile).unwrap()).unwrap();
+        unsafe {
+            match libc::fork() {
+                0 => {
+                    // Only async-signal-safe calls from here on
+                    let code = match filter::install(&program) {
+                        Ok(()) => child(),
+                        Err(_) => 100,
+                    };
+                    libc::_exit(code);
+                }
+                pid => {
+                    let mut status = 0;
+                    libc::waitpid(pid, &mut status, 0);
+                    assert!(libc::WIFEXITED(status), "child died: {}", status);
+                    libc::WEXITSTATUS(status)
+                }
+            }
+        }
+    }
+
+    /// 0 if getpgid() works, 1 if it fails with EPERM.
+    fn try_getpgid() -> i32 {
+        if unsafe { libc::getpgid(0) } >= 0 {
+            0
+        } else if std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) {
+            1
+        } else {
+            2
+        }
+    }
+
+    #[test]
+    fn denies_syscalls_outside_the_allowlist() {
+        assert_eq!(in_child(SeccompProfile::default(), try_getpgid), 1);
+        let profile = SeccompProfile {
+            allow: Some(vec!["getpgid".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(in_child(profile, try_getpgid), 0);
+    }
+
+    #[test]
+    fn validates_profiles() {
+        let parse = |profile| Profile::parse(profile).and_then(|p| filter::compile(&p));
+        let err = parse(SeccompProfile {
+            allow: Some(vec!["no_such_call".to_string()]),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(err.reason.starts_with("Unknown syscall \"no_such_call\""));
+        assert!(parse(SeccompProfile {
+            action: Some("ignore".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+
+        let empty = parse(SeccompProfile {
+            base: Some("none".to_string()),
+            allow: Some(vec!["read".to_string(), "read".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(filter::allowed(&empty), 1);
+        let default = parse(SeccompProfile::default()).unwrap();
+        assert_eq!(
+            filter::allowed(&default) as usize,
+            seccomp_default_allowlist().len()
+        );
+    }
+
+    /// (code, jt, jf, k) of each instruction.
+    fn instructions(program: &[libc::sock_filter]) -> Vec<(u16, u8, u8, u32)> {
+        program
+            .iter()
+            .map(|insn| (insn.code, insn.jt, insn.jf, insn.k))
+            .collect()
+    }
+
+    fn compile(allow: &[&str], action: Action) -> Result<Vec<libc::sock_filter>> {
+        filter::compile(&Profile {
+            base: &[],
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            action,
+        })
+    }
+
+    #[test]
+    fn programs_check_the_architecture_first() {
+        let load = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+        let jeq = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+        let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+        let program = instructions(&compile(&["read"], Action::Errno).unwrap());
+        assert_eq!(
+            program[..4],
+            [
+                (load, 0, 0, 4), // seccomp_data.arch
+                (jeq, 1, 0, filter::AUDIT_ARCH),
+                (ret, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+                (load, 0, 0, 0), // seccomp_data.nr
+            ]
+        );
+        let read = libc::SYS_read as u32;
+        assert!(program.contains(&(jeq, 0, 1, read)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn x32_syscalls_are_denied() {
+        let jge = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+        let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+        let program = instructions(&compile(&["getpid"], Action::Kill).unwrap());
+        assert_eq!(
+            program[4..6],
+            [
+                (jge, 0, 1, filter::X32_SYSCALL_BIT),
+                (ret, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+            ]
+        );
+
+        // getpid is allowed, but not through the x32 ABI
+        fn x32_getpid() -> i32 {
+            let nr = libc::SYS_getpid | filter::X32_SYSCALL_BIT as libc::c_long;
+            if unsafe { libc::syscall(nr) } >= 0 {
+                0
+            } else if std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) {
+                1
+            } else {
+                2
+            }
+        }
+        assert_eq!(in_child(SeccompProfile::default(), x32_getpid), 1);
+    }
+
+    #[test]
+    fn other_syscalls_get_the_profile_action() {
+        for (action, deny) in [
+            (Action::Errno, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+            (Action::Kill, libc::SECCOMP_RET_KILL_PROCESS),
+            (Action::Log, libc::SECCOMP_RET_LOG),
+        ] {
+            let program = compile(&["read"], action).unwrap();
+            let last = program.last().unwrap();
+            assert_eq!(last.code, (libc::BPF_RET | libc::BPF_K) as u16);
+            assert_eq!(last.k, deny, "{:?}", action);
+        }
+        let parsed = Profile::parse(SeccompProfile {
+            action: Some("log".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(parsed.action, Action::Log);
+    }
+
+    #[test]
+    fn unknown_base_syscalls_are_skipped_but_added_ones_rejected() {
+        let profile = Profile {
+            base: &["read", "no_such_call"],
+            allow: vec!["write".to_string()],
+            action: Action::Errno,
+        };
+        assert_eq!(filter::allowed(&filter::compile(&profile).unwrap()), 2);
+
+        let profile = Profile {
+            allow: vec!["no_such_call".to_string()],
+            ..profile
+        };
+        let err = filter::compile(&profile).unwrap_err();
+        assert_eq!(
+            err.reason,
+            format!(
+                "Unknown syscall \"no_such_call\" for {}",
+                std::env::consts::ARCH
+            )
+        );
+    }
+}
@@ -0,0 +1,1048 @@
+//! Attestation-authenticated encrypted channels over vsock.
+//!
+//! The host opens a SecureChannel on a connected VsockStream; the enclave
+//! accepts it on the other end. The handshake is Noise-NK-like, with the
+//! enclave's ephemeral key authenticated by an attestation document
+//! instead of a static key:
+//!
+//! 1. host → enclave: `{version, public_key, nonce}`, an ephemeral X25519
+//!    key and a fresh 32-byte nonce.
+//! 2. enclave → host: `{attestation}`, a document whose public_key is the
+//!    enclave's ephemeral X25519 key, whose nonce is the host's and whose
+//!    user_data is SHA-256(protocol name ‖ message 1).
+//! 3. The host evaluates the document with its AttestationPolicy, and both
+//!    sides derive one ChaCha20-Poly1305 key per direction with HKDF-SHA256
+//!    from the X25519 shared secret, salted with the transcript hash.
+//!
+//! Resumption: every handshake reply also carries a `ticket`, the session's
+//! resumption secret sealed under a key that never leaves the enclave
+//! process. resumeSecureChannel() sends it back in the hello; if the enclave
+//! can open it and the session's attestation is younger than both sides'
+//! maxSessionLifetimeMs, the reply is `{public_key, finished, ticket}`
+//! instead: a fresh ephemeral key and an HMAC under the old secret, with
+//! no NSM request. Keys then derive from the X25519 secret and the old
+//! secret together. Otherwise the enclave attests as usual and the host
+//! evaluates the document with its policy. Resumed sessions keep their
+//! original attestation time, so the lifetime bounds how long a session
+//! can go without re-attestation; tickets also die with the enclave.
+//!
+//! Messages are CBOR maps and every message, handshake or data, is one
+//! length-prefixed frame (see framing). Data frames are sealed with a
+//! per-direction counter nonce, so replayed, reordered or dropped frames
+//! fail authentication.
+//!
+//! ```js
+//! // enclave
+//! const channel = await acceptSecureChannel(listener.accept());
+//! // host
+//! const channel = await connectSecureChannel(stream, policy);
+//! channel.send(Buffer.from('hello'));
+//! const reply = channel.recv(); // null once the peer closes
+//! ```
+//!
+//! With `{ negotiate: true }` on both sides, the handshake starts with a
+//! protocol hello (see negotiate), so peers from incompatible releases
+//! fail with VersionMismatch; channel.protocol holds the outcome.
+
+use ciborium::value::Value;
+use hmac::{Hmac, Mac};
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rand_core::{OsRng, RngCore};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::Zeroizing;
+
+use crate::attestation;
+use crate::negotiate::{self, Hello, Negotiated, NegotiatedProtocol};
+use crate::nsm::{Device, DeviceConfig, NsmOptions};
+use crate::policy::{AttestationPolicy, Policy};
+use crate::relay::dup_fd;
+use crate::vsock::VsockStream;
+use crate::workers::WorkerTask;
+use crate::{cbor, framing};
+
+const PROTOCOL_NAME: &[u8] = b"tytle-secure-channel/1/X25519/ChaChaPoly/SHA256";
+const VERSION: u64 = 1;
+const NONCE_SIZE: usize = 32;
+const TAG_LEN: usize = 16;
+/// Largest send() payload: a frame less the AEAD tag.
+const MAX_MESSAGE_SIZE: usize = framing::MAX_FRAME_SIZE - TAG_LEN;
+const DEFAULT_TIMEOUT_MS: u32 = 10_000;
+const DEFAULT_SESSION_LIFETIME_MS: u32 = 3_600_000;
+const SECRET_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[napi(object)]
+pub struct AcceptSecureChannelOptions {
+    /// How long to wait for each handshake message (default 10000).
+    pub timeout_ms: Option<u32>,
+    pub nsm: Option<NsmOptions>,
+    /// How long after its attestation a session can still be resumed
+    /// (default 3600000); 0 disables resumption.
+    pub max_session_lifetime_ms: Option<u32>,
+    /// Exchange protocol hellos first (default false). The host must
+    /// negotiate too.
+    pub negotiate: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ConnectSecureChannelOptions {
+    /// How long to wait for each handshake message (default 10000).
+    pub timeout_ms: Option<u32>,
+    /// resumeSecureChannel() only: how long after the attestation a ticket
+    /// may be offered before re-attesting (default 3600000).
+    pub max_session_lifetime_ms: Option<u32>,
+    /// Exchange protocol hellos first (default false). The enclave must
+    /// negotiate too.
+    pub negotiate: Option<bool>,
+}
+
+/// Enclave side: answer a host's connectSecureChannel() on `stream` with an
+/// attested ephemeral key. Rejects with "HandshakeFailed: ..." if the host
+/// misbehaves or the handshake times out.
+#[napi(ts_return_type = "Promise<SecureChannel>")]
+pub fn accept_secure_channel(
+    stream: &VsockStream,
+    options: Option<AcceptSecureChannelOptions>,
+) -> Result<WorkerTask<HandshakeTask>> {
+    let (timeout_ms, nsm, lifetime_ms, negotiate) = match options {
+        Some(o) => (o.timeout_ms, o.nsm, o.max_session_lifetime_ms, o.negotiate),
+        None => (None, None, None, None),
+    };
+    let config = DeviceConfig::from_js(nsm)?;
+    Ok(WorkerTask::new(HandshakeTask {
+        fd: dup_fd(stream.fd())?,
+        timeout: timeout(timeout_ms),
+        negotiate: negotiate.unwrap_or(false),
+        role: Some(Role::Responder {
+            config,
+            lifetime_ms: lifetime(lifetime_ms),
+        }),
+    }))
+}
+
+/// Host side: open a channel to the enclave on `stream`, accepting it only
+/// if its attestation document passes `policy` (whose nonce rule is
+/// replaced by the handshake's own nonce). Rejects with
+/// "AttestationRejected: ..." listing the failed rules, or
+/// "HandshakeFailed: ...".
+#[napi(ts_return_type = "Promise<SecureChannel>")]
+pub fn connect_secure_channel(
+    stream: &VsockStream,
+    policy: &AttestationPolicy,
+    options: Option<ConnectSecureChannelOptions>,
+) -> Result<WorkerTask<HandshakeTask>> {
+    let (timeout_ms, negotiate) = match options {
+        Some(o) => (o.timeout_ms, o.negotiate),
+        None => (None, None),
+    };
+    Ok(WorkerTask::new(HandshakeTask {
+        fd: dup_fd(stream.fd())?,
+        timeout: timeout(timeout_ms),
+        negotiate: negotiate.unwrap_or(false),
+        role: Some(Role::Initiator {
+            policy: policy.policy(),
+            resume: None,
+        }),
+    }))
+}
+
+/// Host side: reopen a channel on `stream` with `ticket` from an earlier
+/// channel, skipping the enclave's attestation if it accepts the ticket.
+/// Falls back to a full handshake checked against `policy` when the
+/// enclave declines (e.g. it restarted) or the session is older than
+/// maxSessionLifetimeMs. Rejects like connectSecureChannel().
+#[napi(ts_return_type = "Promise<SecureChannel>")]
+pub fn resume_secure_channel(
+    stream: &VsockStream,
+    policy: &AttestationPolicy,
+    ticket: &ResumptionTicket,
+    options: Option<ConnectSecureChannelOptions>,
+) -> Result<WorkerTask<HandshakeTask>> {
+    let (timeout_ms, lifetime_ms, negotiate) = match options {
+        Some(o) => (o.timeout_ms, o.max_session_lifetime_ms, o.negotiate),
+        None => (None, None, None),
+    };
+    Ok(WorkerTask::new(HandshakeTask {
+        fd: dup_fd(stream.fd())?,
+        timeout: timeout(timeout_ms),
+        negotiate: negotiate.unwrap_or(false),
+        role: Some(Role::Initiator {
+            policy: policy.policy(),
+            resume: Some((ticket.inner.clone(), lifetime(lifetime_ms))),
+        }),
+    }))
+}
+
+fn timeout(timeout_ms: Option<u32>) -> Duration {
+    Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS) as u64)
+}
+
+fn lifetime(lifetime_ms: Option<u32>) -> i64 {
+    lifetime_ms.unwrap_or(DEFAULT_SESSION_LIFETIME_MS) as i64
+}
+
+enum Role {
+    Initiator {
+        policy: Policy,
+        /// A ticket to offer and the host's session lifetime.
+        resume: Option<(Ticket, i64)>,
+    },
+    Responder {
+        config: DeviceConfig,
+        /// 0 disables resumption.
+        lifetime_ms: i64,
+    },
+}
+
+pub struct HandshakeTask {
+    /// A duplicate of the stream's fd, owned by the channel once made.
+    fd: i32,
+    timeout: Duration,
+    /// Exchange protocol hellos before the handshake.
+    negotiate: bool,
+    /// Taken by compute().
+    role: Option<Role>,
+}
+
+impl Task for HandshakeTask {
+    type Output = Session;
+    type JsValue = SecureChannel;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let deadline = Instant::now() + self.timeout;
+        let now_ms = attestation::now_ms();
+        let protocol = if self.negotiate {
+            negotiate::negotiate(self.fd, &Hello::local(), 0, deadline).map(Some)
+        } else {
+            Ok(None)
+        };
+        let result = protocol.and_then(|protocol| {
+            let mut session = match self.role.take() {
+                Some(Role::Initiator { policy, resume }) => {
+                    initiate(self.fd, policy, resume, now_ms, deadline)
+                }
+                Some(Role::Responder {
+                    config,
+                    lifetime_ms,
+                }) => Device::open_with(config)
+                    .and_then(|device| respond(self.fd, &device, lifetime_ms, now_ms, deadline)),
+                None => Err(Error::from_reason("Handshake already ran")),
+            }?;
+            session.protocol = protocol;
+            Ok(session)
+        });
+        if result.is_err() {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+        result
+    }
+
+    fn resolve(&mut self, _env: Env, session: Self::Output) -> Result<Self::JsValue> {
+        Ok(SecureChannel {
+            inner: Arc::new(session),
+        })
+    }
+}
+
+/// One direction's key and frame counter.
+struct Cipher {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Cipher {
+    fn next_nonce(&mut self) -> Result<Nonce> {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| Error::from_reason("SecureChannel frame counter exhausted"))?;
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+/// napi-free core of SecureChannel.
+pub struct Session {
+    fd: AtomicI32,
+    sender: Mutex<Cipher>,
+    receiver: Mutex<Cipher>,
+    /// Host side: the enclave's accepted attestation document.
+    peer_attestation: Option<Vec<u8>>,
+    /// Whether the handshake resumed an earlier session.
+    resumed: bool,
+    /// Host side: the ticket for resuming this session, if one was issued.
+    ticket: Option<Ticket>,
+    /// The negotiated protocol, if the handshake negotiated one.
+    protocol: Option<Negotiated>,
+}
+
+impl Session {
+    fn new(
+        fd: i32,
+        sender: UnboundKey,
+        receiver: UnboundKey,
+        peer_attestation: Option<Vec<u8>>,
+    ) -> Self {
+        Session {
+            fd: AtomicI32::new(fd),
+            sender: Mutex::new(Cipher {
+                key: LessSafeKey::new(sender),
+                counter: 0,
+            }),
+            receiver: Mutex::new(Cipher {
+                key: LessSafeKey::new(receiver),
+                counter: 0,
+            }),
+            peer_attestation,
+            resumed: false,
+            ticket: None,
+            protocol: None,
+        }
+    }
+
+    fn fd(&self) -> Result<i32> {
+        match self.fd.load(Ordering::Acquire) {
+            -1 => Err(Error::from_reason("SecureChannel is closed")),
+            fd => Ok(fd),
+        }
+    }
+
+    fn send(&self, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::from_reason(format!(
+                "Message too large: {} bytes (max {})",
+                data.len(),
+                MAX_MESSAGE_SIZE
+            )));
+        }
+        let fd = self.fd()?;
+        // Sealing and writing under one lock keeps frames in counter order
+        let mut sender = self.sender.lock().unwrap();
+        let mut frame = data.to_vec();
+        let nonce = sender.next_nonce()?;
+        sender
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut frame)
+            .map_err(|_| Error::from_reason("SecureChannel encryption failed"))?;
+        framing::write_frame(fd, &frame)
+            .map_err(|e| Error::from_reason(format!("SecureChannel send failed: {}", e)))
+    }
+
+    /// The next message, or None once the peer has closed.
+    fn recv(&self) -> Result<Option<Vec<u8>>> {
+        let fd = self.fd()?;
+        let mut receiver = self.receiver.lock().unwrap();
+        let Some(mut frame) = framing::read_frame(fd)
+            .map_err(|e| Error::from_reason(format!("SecureChannel recv failed: {}", e)))?
+        else {
+            return Ok(None);
+        };
+        let nonce = receiver.next_nonce()?;
+        let len = match receiver.key.open_in_place(nonce, Aad::empty(), &mut frame) {
+            Ok(plaintext) => plaintext.len(),
+            Err(_) => {
+                // The counters are out of step now; nothing later can verify
+                drop(receiver);
+                self.close();
+                return Err(Error::from_reason(
+                    "SecureChannel frame failed authentication; channel closed",
+                ));
+            }
+        };
+        frame.truncate(len);
+        Ok(Some(frame))
+    }
+
+    /// Shut the connection down for both ends and release the fd.
+    fn close(&self) {
+        let fd = self.fd.swap(-1, Ordering::AcqRel);
+        if fd != -1 {
+            unsafe {
+                libc::shutdown(fd, libc::SHUT_RDWR);
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let fd = self.fd.swap(-1, Ordering::AcqRel);
+        if fd != -1 {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+fn handshake_error(message: impl std::fmt::Display) -> Error {
+    Error::from_reason(format!("HandshakeFailed: {}", message))
+}
+
+/// Host side: what resuming a session takes.
+#[derive(Clone)]
+struct Ticket {
+    /// Sealed by the enclave; opaque to the host.
+    sealed: Vec<u8>,
+    secret: Zeroizing<[u8; SECRET_SIZE]>,
+    /// When the enclave's attestation was accepted.
+    established_ms: i64,
+    attestation: Vec<u8>,
+}
+
+/// Host side of the handshake, evaluating the enclave's document with
+/// `policy` at `time_ms`. `resume` offers a ticket if it's younger than
+/// the lifetime paired with it.
+fn initiate(
+    fd: i32,
+    mut policy: Policy,
+    resume: Option<(Ticket, i64)>,
+    time_ms: i64,
+    deadline: Instant,
+) -> Result<Session> {
+    let offered = resume.and_then(|(ticket, lifetime_ms)| {
+        (time_ms - ticket.established_ms < lifetime_ms).then_some(ticket)
+    });
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+    let mut nonce = vec![0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let mut hello = vec![
+        ("version", Value::Integer(VERSION.into())),
+        ("public_key", Value::Bytes(public_key.as_bytes().to_vec())),
+        ("nonce", Value::Bytes(nonce.clone())),
+    ];
+    if let Some(ticket) = &offered {
+        hello.push(("ticket", Value::Bytes(ticket.sealed.clone())));
+    }
+    let hello = cbor::encode(&cbor::map(hello))?;
+    write_message(fd, &hello)?;
+
+    let reply = read_message(fd, deadline)?;
+    let decoded = cbor::decode(&reply).ok();
+    let field = |name| {
+        decoded
+            .as_ref()
+            .and_then(|reply| cbor::map_get(reply, name))
+            .and_then(cbor::as_bytes)
+    };
+    let issued = field("ticket");
+
+    if let Some(finished) = field("finished") {
+        let Some(ticket) = offered else {
+            return Err(handshake_error(
+                "enclave resumed a session that wasn't offered",
+            ));
+        };
+        let peer: [u8; 32] = field("public_key")
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| handshake_error("resumption reply needs a 32-byte public_key"))?;
+        finished_mac(&ticket.secret[..], &hello, &peer)
+            .verify_slice(&finished)
+            .map_err(|_| handshake_error("enclave failed to prove the resumption secret"))?;
+        let shared = secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(handshake_error("enclave public key has low order"));
+        }
+        let ikm = Zeroizing::new([&shared.as_bytes()[..], &ticket.secret[..]].concat());
+        let (to_enclave, to_host) = derive_keys(&ikm, &hello, &reply)?;
+        let mut session = Session::new(fd, to_enclave, to_host, Some(ticket.attestation.clone()));
+        session.resumed = true;
+        session.ticket = issued.map(|sealed| Ticket {
+            sealed,
+            secret: resumption_secret(&ikm, &hello, &peer),
+            ..ticket
+        });
+        return Ok(session);
+    }
+
+    let attestation =
+        field("attestation").ok_or_else(|| handshake_error("enclave reply has no attestation"))?;
+
+    policy.require_nonce(nonce);
+    let evaluation = policy.evaluate(&attestation, time_ms)?;
+    let mut failures: Vec<String> = evaluation
+        .failures
+        .into_iter()
+        .map(|failure| failure.message)
+        .collect();
+    let document = evaluation.document;
+    if document.user_data.as_deref() != Some(&transcript_hash(&hello)[..]) {
+        failures.push("user_data does not bind this handshake".to_string());
+    }
+    let peer: Option<[u8; 32]> = document
+        .public_key
+        .as_deref()
+        .and_then(|key| key.try_into().ok());
+    if peer.is_none() {
+        failures.push("public_key is not an X25519 key".to_string());
+    }
+    if !failures.is_empty() {
+        return Err(Error::from_reason(format!(
+            "AttestationRejected: {}",
+            failures.join("; ")
+        )));
+    }
+
+    let peer = peer.unwrap();
+    let shared = secret.diffie_hellman(&PublicKey::from(peer));
+    if !shared.was_contributory() {
+        return Err(handshake_error("enclave public key has low order"));
+    }
+    let (to_enclave, to_host) = derive_keys(shared.as_bytes(), &hello, &reply)?;
+    let mut session = Session::new(fd, to_enclave, to_host, Some(attestation.clone()));
+    session.ticket = issued.map(|sealed| Ticket {
+        sealed,
+        secret: resumption_secret(shared.as_bytes(), &hello, &peer),
+        established_ms: time_ms,
+        attestation,
+    });
+    Ok(session)
+}
+
+/// Enclave side of the handshake, attesting with `device` unless the host
+/// offers a ticket for a session attested less than `lifetime_ms` before
+/// `now_ms`.
+fn respond(
+    fd: i32,
+    device: &Device,
+    lifetime_ms: i64,
+    now_ms: i64,
+    deadline: Instant,
+) -> Result<Session> {
+    let hello = read_message(fd, deadline)?;
+    let (version, peer, nonce, ticket) = match cbor::decode(&hello) {
+        Ok(value) => (
+            cbor::map_get(&value, "version").and_then(cbor::as_u64),
+            cbor::map_get(&value, "public_key")
+                .and_then(cbor::as_bytes)
+                .and_then(|key| <[u8; 32]>::try_from(key).ok()),
+            cbor::map_get(&value, "nonce").and_then(cbor::as_bytes),
+            cbor::map_get(&value, "ticket").and_then(cbor::as_bytes),
+        ),
+        Err(_) => (None, None, None, None),
+    };
+    if version != Some(VERSION) {
+        return Err(handshake_error(format!(
+            "unsupported protocol version {:?}",
+            version
+        )));
+    }
+    let (Some(peer), Some(nonce)) = (peer, nonce) else {
+        return Err(handshake_error(
+            "host hello needs a 32-byte public_key and a nonce",
+        ));
+    };
+    if nonce.len() != NONCE_SIZE {
+        return Err(handshake_error(format!(
+            "nonce must be {} bytes",
+            NONCE_SIZE
+        )));
+    }
+    // Tickets that don't open or are too old just mean re-attesting
+    let resumed = ticket
+        .filter(|_| lifetime_ms > 0)
+        .and_then(|ticket| open_ticket(&ticket))
+        .filter(|(_, established_ms)| now_ms - established_ms < lifetime_ms);
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(peer));
+    if !shared.was_contributory() {
+        return Err(handshake_error("host public key has low order"));
+    }
+    let ikm = match &resumed {
+        Some((old, _)) => Zeroizing::new([&shared.as_bytes()[..], &old[..]].concat()),
+        None => Zeroizing::new(shared.as_bytes().to_vec()),
+    };
+    let mut reply = match &resumed {
+        Some((old, _)) => vec![
+            ("public_key", Value::Bytes(public_key.as_bytes().to_vec())),
+            (
+                "finished",
+                Value::Bytes(
+                    finished_mac(&old[..], &hello, public_key.as_bytes())
+                        .finalize()
+                        .into_bytes()
+                        .to_vec(),
+                ),
+            ),
+        ],
+        None => {
+            let attestation = device.attestation(
+                Some(&transcript_hash(&hello)),
+                Some(&nonce),
+                Some(public_key.as_bytes()),
+            )?;
+            vec![("attestation", Value::Bytes(attestation))]
+        }
+    };
+    if lifetime_ms > 0 {
+        let established_ms = resumed.as_ref().map_or(now_ms, |&(_, at)| at);
+        let next = resumption_secret(&ikm, &hello, public_key.as_bytes());
+        reply.push((
+            "ticket",
+            Value::Bytes(seal_ticket(&next[..], established_ms)?),
+        ));
+    }
+    let reply = cbor::encode(&cbor::map(reply))?;
+    write_message(fd, &reply)?;
+
+    let (to_enclave, to_host) = derive_keys(&ikm, &hello, &reply)?;
+    let mut session = Session::new(fd, to_host, to_enclave, None);
+    session.resumed = resumed.is_some();
+    Ok(session)
+}
+
+/// SHA-256(protocol name ‖ hello), the enclave's attested user_data.
+fn transcript_hash(hello: &[u8]) -> Vec<u8> {
+    let mut hash = Sha256::new();
+    hash.update(PROTOCOL_NAME);
+    hash.update(hello);
+    hash.finalize().to_vec()
+}
+
+/// The host → enclave and enclave → host keys.
+fn derive_keys(shared: &[u8], hello: &[u8], reply: &[u8]) -> Result<(UnboundKey, UnboundKey)> {
+    let mut transcript = Sha256::new();
+    transcript.update(transcript_hash(hello));
+    transcript.update(reply);
+    let prk = Salt::new(HKDF_SHA256, &transcript.finalize()).extract(shared);
+    let key = |info: &[u8]| {
+        prk.expand(&[PROTOCOL_NAME, info], &CHACHA20_POLY1305)
+            .map(UnboundKey::from)
+            .map_err(|_| Error::from_reason("HKDF expansion failed"))
+    };
+    Ok((key(b"host to enclave")?, key(b"enclave to host")?))
+}
+
+/// The secret a ticket for this session carries: independent of the
+/// reply, so the enclave can seal it into the reply's ticket.
+fn resumption_secret(ikm: &[u8], hello: &[u8], enclave_key: &[u8]) -> Zeroizing<[u8; SECRET_SIZE]> {
+    let mut salt = Sha256::new();
+    salt.update(transcript_hash(hello));
+    salt.update(enclave_key);
+    let mut secret = Zeroizing::new([0u8; SECRET_SIZE]);
+    Salt::new(HKDF_SHA256, &salt.finalize())
+        .extract(ikm)
+        .expand(&[PROTOCOL_NAME, b"resumption"], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut secret[..]))
+        .expect("HKDF-SHA256 can expand 32 bytes");
+    secret
+}
+
+/// The enclave's proof, in a resumption reply, that it holds `secret`.
+fn finished_mac(secret: &[u8], hello: &[u8], enclave_key: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(PROTOCOL_NAME);
+    mac.update(b"enclave finished");
+    mac.update(&transcript_hash(hello));
+    mac.update(enclave_key);
+    mac
+}
+
+/// Seals tickets; random per enclave process, so restarts void them.
+fn ticket_key() -> &'static LessSafeKey {
+    static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut key[..]);
+        LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &key[..]).expect("32-byte ChaCha20 key"),
+        )
+    })
+}
+
+/// `secret` and the session's attestation time, sealed for the host to
+/// hand back: a random nonce followed by the sealed CBOR.
+fn seal_ticket(secret: &[u8], established_ms: i64) -> Result<Vec<u8>> {
+    let mut plaintext = Zeroizing::new(cbor::encode(&cbor::map(vec![
+        ("secret", Value::Bytes(secret.to_vec())),
+        ("established_ms", Value::Integer(established_ms.into())),
+    ]))?);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    ticket_key()
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(PROTOCOL_NAME),
+            &mut *plaintext,
+        )
+        .map_err(|_| Error::from_reason("Ticket encryption failed"))?;
+    Ok([&nonce[..], &plaintext[..]].concat())
+}
+
+/// The secret and attestation time in a ticket this process sealed.
+fn open_ticket(ticket: &[u8]) -> Option<(Zeroizing<[u8; SECRET_SIZE]>, i64)> {
+    if ticket.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut sealed = Zeroizing::new(sealed.to_vec());
+    let plaintext = ticket_key()
+        .open_in_place(nonce, Aad::from(PROTOCOL_NAME), &mut sealed[..])
+        .ok()?;
+    let value = cbor::decode(plaintext).ok()?;
+    let secret = cbor::map_get(&value, "secret").and_then(cbor::as_bytes)?;
+    let established_ms = match cbor::map_get(&value, "established_ms")? {
+        Value::Integer(n) => i64::try_from(*n).ok()?,
+        _ => return None,
+    };
+    Some((Zeroizing::new(secret.try_into().ok()?), established_ms))
+}
+
+fn write_message(fd: i32, message: &[u8]) -> Result<()> {
+    framing::write_frame(fd, message).map_err(handshake_error)
+}
+
+/// Read a handshake message, waiting until `deadline` for it to start.
+fn read_message(fd: i32, deadline: Instant) -> Result<Vec<u8>> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = loop {
+        let ret = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as i32) };
+        if ret >= 0 {
+            break ret;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(handshake_error(err));
+        }
+    };
+    if ready == 0 {
+        return Err(handshake_error("timed out waiting for the peer"));
+    }
+    framing::read_frame(fd)
+        .map_err(handshake_error)?
+        .ok_or_else(|| handshake_error("peer closed the connection"))
+}
+
+/// An encrypted, attestation-authenticated channel over a VsockStream,
+/// from acceptSecureChannel() or connectSecureChannel().
+///
+/// The channel uses its own duplicate of the stream's descriptor: close()
+/// ends the connection for both, but the stream must still be closed to
+/// free its descriptor. Don't read or write the stream directly once the
+/// channel is open.
+#[napi]
+pub struct SecureChannel {
+    inner: Arc<Session>,
+}
+
+#[napi]
+impl SecureChannel {
+    /// Encrypt and send `data` as one frame (at most 16 MiB less 16 bytes).
+    #[napi]
+    pub fn send(&self, data: Buffer) -> Result<()> {
+        self.inner.send(&data)
+    }
+
+    /// Receive and decrypt the next frame, or null once the peer has
+    /// closed. A frame that fails authentication throws and closes the
+    /// channel. Note: this is a blocking call — use recvAsync() on the
+    /// main thread.
+    #[napi(ts_return_type = "Buffer | null")]
+    pub fn recv(&self) -> Result<Option<Buffer>> {
+        Ok(self.inner.recv()?.map(Buffer::from))
+    }
+
+    /// recv() on the blocking-call thread pool.
+    #[napi(ts_return_type = "Promise<Buffer | null>")]
+    pub fn recv_async(&self) -> WorkerTask<RecvTask> {
+        WorkerTask::new(RecvTask {
+            session: self.inner.clone(),
+        })
+    }
+
+    /// Host side: the enclave's attestation document, accepted by the
+    /// policy. Null on the enclave side.
+    #[napi(getter)]
+    pub fn peer_attestation(&self) -> Option<Buffer> {
+        self.inner.peer_attestation.clone().map(Buffer::from)
+    }
+
+    /// Whether the handshake resumed an earlier session rather than
+    /// attesting.
+    #[napi(getter)]
+    pub fn resumed(&self) -> bool {
+        self.inner.resumed
+    }
+
+    /// The protocol version and features agreed with the peer, or null
+    /// unless both sides negotiated.
+    #[napi(getter)]
+    pub fn protocol(&self) -> Option<NegotiatedProtocol> {
+        self.inner.protocol.clone().map(Negotiated::into_js)
+    }
+
+    /// Host side: a ticket for resumeSecureChannel(), or null if the
+    /// enclave didn't issue one. Null on the enclave side.
+    #[napi(getter)]
+    pub fn resumption_ticket(&self) -> Option<ResumptionTicket> {
+        self.inner
+            .ticket
+            .clone()
+            .map(|inner| ResumptionTicket { inner })
+    }
+
+    /// Shut the connection down. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+/// A SecureChannel's resumption ticket, for resumeSecureChannel(). Its
+/// secret stays native.
+#[napi]
+pub struct ResumptionTicket {
+    inner: Ticket,
+}
+
+#[napi]
+impl ResumptionTicket {
+    /// When the enclave's attestation was accepted, in ms since the epoch.
+    /// Resuming keeps the original time.
+    #[napi(getter)]
+    pub fn established_ms(&self) -> f64 {
+        self.inner.established_ms as f64
+    }
+}
+
+pub struct RecvTask {
+    session: Arc<Session>,
+}
+
+impl Task for RecvTask {
+    type Output = Option<Vec<u8>>;
+    type JsValue = Option<Buffer>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        self.session.recv()
+    }
+
+    fn resolve(&mut self, _env: Env, message: Self::Output) -> Result<Self::JsValue> {
+        Ok(message.map(Buffer::from))
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::fixtures::JAN_2030_MS;
+    use crate::policy::fixtures::test_policy;
+    use crate::test_support::mock_device;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    const LIFETIME_MS: i64 = DEFAULT_SESSION_LIFETIME_MS as i64;
+
+    /// Run both sides of a handshake over a socketpair.
+    fn handshake(policy: Policy) -> (Result<Session>, Result<Session>) {
+        handshake_at(policy, None, LIFETIME_MS, JAN_2030_MS)
+    }
+
+    /// Run both sides of a handshake at `now_ms`, offering `resume` to an
+    /// enclave resuming sessions up to `lifetime_ms` old.
+    fn handshake_at(
+        policy: Policy,
+        resume: Option<(Ticket, i64)>,
+        lifetime_ms: i64,
+        now_ms: i64,
+    ) -> (Result<Session>, Result<Session>) {
+        let (host, enclave) = UnixStream::pair().unwrap();
+        let (host, enclave) = (host.into_raw_fd(), enclave.into_raw_fd());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let responder = std::thread::spawn(move || {
+            respond(enclave, &mock_device(), lifetime_ms, now_ms, deadline)
+        });
+        let initiated = initiate(host, policy, resume, now_ms, deadline);
+        if initiated.is_err() {
+            unsafe {
+                libc::close(host);
+            }
+        }
+        (initiated, responder.join().unwrap())
+    }
+
+    #[test]
+    fn handshake_authenticates_the_enclave_and_encrypts_both_ways() {
+        let (host, enclave) = handshake(test_policy());
+        let (host, enclave) = (host.unwrap(), enclave.unwrap());
+        let document = host.peer_attestation.as_deref().unwrap();
+        assert!(attestation::CoseSign1::parse(document).is_ok());
+        assert!(enclave.peer_attestation.is_none());
+
+        host.send(b"ping").unwrap();
+        host.send(b"").unwrap();
+        assert_eq!(enclave.recv().unwrap().unwrap(), b"ping");
+        assert_eq!(enclave.recv().unwrap().unwrap(), b"");
+        enclave.send(b"pong").unwrap();
+        assert_eq!(host.recv().unwrap().unwrap(), b"pong");
+
+        host.close();
+        assert_eq!(enclave.recv().unwrap(), None);
+        assert_eq!(
+            host.send(b"late").unwrap_err().reason,
+            "SecureChannel is closed"
+        );
+    }
+
+    #[test]
+    fn tampered_or_replayed_frames_close_the_channel() {
+        let (host, enclave) = handshake(test_policy());
+        let (host, enclave) = (host.unwrap(), enclave.unwrap());
+
+        // Replay the frame sealed under counter 0 as the second frame
+        let sender = host.sender.lock().unwrap();
+        let mut frame = b"once".to_vec();
+        let nonce = Nonce::assume_unique_for_key([0; NONCE_LEN]);
+        sender
+            .key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut frame)
+            .unwrap();
+        drop(sender);
+        let fd = host.fd().unwrap();
+        framing::write_frame(fd, &frame).unwrap();
+        framing::write_frame(fd, &frame).unwrap();
+
+        assert_eq!(enclave.recv().unwrap().unwrap(), b"once");
+        let err = enclave.recv().unwrap_err();
+        assert!(
+            err.reason.contains("failed authentication"),
+            "{}",
+            err.reason
+        );
+        assert_eq!(
+            enclave.recv().unwrap_err().reason,
+            "SecureChannel is closed"
+        );
+    }
+
+    #[test]
+    fn policy_failures_reject_the_enclave() {
+        // The policy's own nonce rule gives way to the handshake's nonce
+        let mut policy = test_policy();
+        policy.require_nonce(vec![1; NONCE_SIZE]);
+        assert!(handshake(policy).0.is_ok());
+
+        // Only the AWS root is trusted, not the mock's test chain
+        let (host, enclave) = handshake(Policy::default());
+        let err = host.err().unwrap();
+        assert!(
+            err.reason.starts_with("AttestationRejected: "),
+            "{}",
+            err.reason
+        );
+        assert!(err.reason.contains("not a trusted root"), "{}", err.reason);
+        assert!(enclave.is_ok());
+    }
+
+    #[test]
+    fn responders_reject_malformed_hellos() {
+        let (host, enclave) = UnixStream::pair().unwrap();
+        let (host, enclave) = (host.into_raw_fd(), enclave.into_raw_fd());
+        let hello = cbor::encode(&cbor::map(vec![("version", Value::Integer(2.into()))])).unwrap();
+        framing::write_frame(host, &hello).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let respond = |deadline| {
+            respond(enclave, &mock_device(), LIFETIME_MS, JAN_2030_MS, deadline)
+                .err()
+                .unwrap()
+        };
+        let err = respond(deadline);
+        assert_eq!(
+            err.reason,
+            "HandshakeFailed: unsupported protocol version Some(2)"
+        );
+
+        let err = respond(Instant::now() + Duration::from_millis(50));
+        assert_eq!(
+            err.reason,
+            "HandshakeFailed: timed out waiting for the peer"
+        );
+        unsafe {
+            libc::close(host);
+            libc::close(enclave);
+        }
+    }
+
+    #[test]
+    fn resumption_skips_attestation_within_the_session_lifetime() {
+        let (host, enclave) = handshake(test_policy());
+        let (host, enclave) = (host.unwrap(), enclave.unwrap());
+        assert!(!host.resumed && !enclave.resumed);
+        let ticket = host.ticket.clone().unwrap();
+        assert_eq!(ticket.established_ms, JAN_2030_MS);
+
+        let later = JAN_2030_MS + 60_000;
+        let resume = Some((ticket.clone(), LIFETIME_MS));
+        let (host, enclave) = handshake_at(test_policy(), resume, LIFETIME_MS, later);
+        let (host, enclave) = (host.unwrap(), enclave.unwrap());
+        assert!(host.resumed && enclave.resumed);
+        assert_eq!(host.peer_attestation, Some(ticket.attestation.clone()));
+        host.send(b"again").unwrap();
+        assert_eq!(enclave.recv().unwrap().unwrap(), b"again");
+        // The next ticket works too, and keeps the original attestation time
+        let next = host.ticket.clone().unwrap();
+        assert_eq!(next.established_ms, JAN_2030_MS);
+        assert_ne!(next.sealed, ticket.sealed);
+        let resume = Some((next, LIFETIME_MS));
+        let (host, _) = handshake_at(test_policy(), resume, LIFETIME_MS, later);
+        assert!(host.unwrap().resumed);
+
+        // Past either side's lifetime, the enclave attests again
+        let expired = JAN_2030_MS + LIFETIME_MS;
+        let resume = Some((ticket.clone(), LIFETIME_MS));
+        let (host, enclave) = handshake_at(test_policy(), resume, LIFETIME_MS, expired);
+        assert!(!host.unwrap().resumed && !enclave.unwrap().resumed);
+        let resume = Some((ticket.clone(), LIFETIME_MS));
+        let (host, _) = handshake_at(test_policy(), resume, 30_000, later);
+        let host = host.unwrap();
+        assert!(!host.resumed);
+        assert_eq!(host.ticket.as_ref().unwrap().established_ms, later);
+    }
+
+    #[test]
+    fn forged_tickets_fall_back_to_attestation() {
+        let (host, _) = handshake(test_policy());
+        let mut ticket = host.unwrap().ticket.clone().unwrap();
+        let last = ticket.sealed.len() - 1;
+        ticket.sealed[last] ^= 1;
+        let resume = Some((ticket.clone(), LIFETIME_MS));
+        let (host, enclave) = handshake_at(test_policy(), resume, LIFETIME_MS, JAN_2030_MS);
+        assert!(!host.unwrap().resumed && !enclave.unwrap().resumed);
+
+        // A host with the wrong secret can't complete a resumption
+        let (host, _) = handshake(test_policy());
+        let mut ticket = host.unwrap().ticket.clone().unwrap();
+        ticket.secret = Zeroizing::new([0; SECRET_SIZE]);
+        let resume = Some((ticket, LIFETIME_MS));
+        let (host, _) = handshake_at(test_policy(), resume, LIFETIME_MS, JAN_2030_MS);
+        assert_eq!(
+            host.err().unwrap().reason,
+            "HandshakeFailed: enclave failed to prove the resumption secret"
+        );
+    }
+}
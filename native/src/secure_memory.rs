@@ -0,0 +1,374 @@
+//! Locked, non-dumpable memory for key material.
+//!
+//! A KMS plaintext key held in a Buffer can be swapped to disk, copied
+//! around by the GC and left behind in freed memory. SecureBuffer keeps
+//! its bytes in native memory that is mlock()ed (never swapped), excluded
+//! from core dumps and zeroized when the object is destroyed or collected.
+//! The bytes are never handed to JS: the key is used through the class
+//! instead.
+//!
+//! ```js
+//! lockMemory();
+//! const key = await kms.decryptToSecureBuffer({ ciphertextBlob });
+//! const sealed = key.seal(record);
+//! const mac = key.hmacSha256(message);
+//! key.destroy();
+//! ```
+//!
+//! lockMemory() goes further and locks every page of the process, current
+//! and future, so nothing the enclave app touches is swapped.
+
+use hmac::{Hmac, Mac};
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::Sha256;
+use std::sync::Mutex;
+use zeroize::Zeroize;
+
+use crate::errors;
+
+/// Largest SecureBuffer; locked memory counts against RLIMIT_MEMLOCK.
+const MAX_SIZE: u32 = 1024 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Page-aligned anonymous memory that is mlock()ed, excluded from core
+/// dumps and zeroized before it is unmapped. Public only as the output of
+/// KmsClient::decryptToSecureBuffer()'s task; its contents stay crate-only.
+pub struct LockedBytes {
+    ptr: *mut u8,
+    len: usize,
+    mapped: usize,
+}
+
+// The mapping is owned and only reached through &self/&mut self
+unsafe impl Send for LockedBytes {}
+unsafe impl Sync for LockedBytes {}
+
+impl LockedBytes {
+    /// `len` zero bytes.
+    pub(crate) fn new(len: usize) -> Result<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mapped = len.max(1).div_ceil(page) * page;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(errors::os_error(
+                format!("mmap({})", mapped),
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let bytes = LockedBytes {
+            ptr: ptr as *mut u8,
+            len,
+            mapped,
+        };
+        if unsafe { libc::mlock(ptr, mapped) } != 0 {
+            return Err(errors::os_error(
+                format!("mlock({})", mapped),
+                std::io::Error::last_os_error(),
+            ));
+        }
+        exclude_from_dumps(ptr, mapped);
+        Ok(bytes)
+    }
+
+    pub(crate) fn from_slice(data: &[u8]) -> Result<Self> {
+        let mut bytes = Self::new(data.len())?;
+        bytes.as_mut_slice().copy_from_slice(data);
+        Ok(bytes)
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        self.as_mut_slice().zeroize();
+        unsafe {
+            let ptr = self.ptr as *mut libc::c_void;
+            libc::munlock(ptr, self.mapped);
+            libc::munmap(ptr, self.mapped);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn exclude_from_dumps(ptr: *mut libc::c_void, len: usize) {
+    // Best effort: the memory is still locked and zeroized without it
+    unsafe {
+        libc::madvise(ptr, len, libc::MADV_DONTDUMP);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exclude_from_dumps(_ptr: *mut libc::c_void, _len: usize) {}
+
+#[napi(object)]
+pub struct LockMemoryOptions {
+    /// Also lock pages mapped from now on (default true).
+    pub future: Option<bool>,
+}
+
+/// mlockall(): keep every page of the process in RAM so nothing, keys
+/// included, is ever swapped. Needs CAP_IPC_LOCK or a RLIMIT_MEMLOCK
+/// covering the whole process; throws "EPERM"/"ENOMEM" otherwise.
+#[napi]
+pub fn lock_memory(options: Option<LockMemoryOptions>) -> Result<()> {
+    let future = options.and_then(|o| o.future).unwrap_or(true);
+    lock_all(future)
+}
+
+#[cfg(target_os = "linux")]
+fn lock_all(future: bool) -> Result<()> {
+    let flags = if future {
+        libc::MCL_CURRENT | libc::MCL_FUTURE
+    } else {
+        libc::MCL_CURRENT
+    };
+    if unsafe { libc::mlockall(flags) } != 0 {
+        return Err(errors::os_error(
+            "mlockall()",
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_all(_future: bool) -> Result<()> {
+    Err(crate::platform::unsupported("mlockall()"))
+}
+
+#[napi(object)]
+pub struct CopyFromOptions {
+    /// Overwrite `data` with zeros once copied (default false).
+    pub wipe_source: Option<bool>,
+}
+
+/// Key material in locked native memory; see the module docs. Nothing
+/// returns the bytes themselves.
+#[napi]
+pub struct SecureBuffer {
+    /// None once destroyed.
+    bytes: Mutex<Option<LockedBytes>>,
+}
+
+#[napi]
+impl SecureBuffer {
+    /// Copy `data` into locked memory, optionally wiping the original.
+    #[napi(factory)]
+    pub fn copy_from(mut data: Buffer, options: Option<CopyFromOptions>) -> Result<Self> {
+        check_size(data.len())?;
+        let buffer = Self::new(LockedBytes::from_slice(&data)?);
+        if options.and_then(|o| o.wipe_source).unwrap_or(false) {
+            data.as_mut().zeroize();
+        }
+        Ok(buffer)
+    }
+
+    /// `size` random bytes, generated straight into locked memory.
+    #[napi(factory)]
+    pub fn random(size: u32) -> Result<Self> {
+        check_size(size as usize)?;
+        let mut bytes = LockedBytes::new(size as usize)?;
+        SystemRandom::new()
+            .fill(bytes.as_mut_slice())
+            .map_err(|_| Error::from_reason("Random generation failed"))?;
+        Ok(Self::new(bytes))
+    }
+
+    /// Length in bytes, or 0 once destroyed.
+    #[napi(getter)]
+    pub fn length(&self) -> u32 {
+        self.bytes
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |bytes| bytes.len as u32)
+    }
+
+    /// Whether destroy() has been called.
+    #[napi(getter)]
+    pub fn destroyed(&self) -> bool {
+        self.bytes.lock().unwrap().is_none()
+    }
+
+    /// Constant-time comparison with `other`.
+    #[napi]
+    pub fn equals(&self, other: Buffer) -> Result<bool> {
+        self.with(|bytes| Ok(constant_time_eq(bytes, &other)))
+    }
+
+    /// HMAC-SHA256 of `data` keyed with the contents.
+    #[napi]
+    pub fn hmac_sha256(&self, data: Buffer) -> Result<Buffer> {
+        self.with(|key| Ok(hmac_sha256(key, &data).into()))
+    }
+
+    /// AES-256-GCM encrypt `plaintext` with the contents (32 bytes) as the
+    /// key. Returns nonce ‖ ciphertext ‖ tag.
+    #[napi]
+    pub fn seal(&self, plaintext: Buffer, aad: Option<Buffer>) -> Result<Buffer> {
+        let aad = aad.as_deref().unwrap_or_default();
+        self.with(|key| Ok(seal(key, &plaintext, aad)?.into()))
+    }
+
+    /// Decrypt the output of seal() with the same key and `aad`.
+    #[napi]
+    pub fn open(&self, sealed: Buffer, aad: Option<Buffer>) -> Result<Buffer> {
+        let aad = aad.as_deref().unwrap_or_default();
+        self.with(|key| Ok(open(key, &sealed, aad)?.into()))
+    }
+
+    /// Zeroize and release the memory now rather than at collection.
+    /// Later calls other than `length` and `destroyed` throw.
+    #[napi]
+    pub fn destroy(&self) {
+        self.bytes.lock().unwrap().take();
+    }
+}
+
+impl SecureBuffer {
+    pub(crate) fn new(bytes: LockedBytes) -> Self {
+        SecureBuffer {
+            bytes: Mutex::new(Some(bytes)),
+        }
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&[u8]) -> Result<T>) -> Result<T> {
+        match self.bytes.lock().unwrap().as_ref() {
+            Some(bytes) => f(bytes.as_slice()),
+            None => Err(Error::from_reason("SecureBuffer destroyed")),
+        }
+    }
+}
+
+fn check_size(len: usize) -> Result<()> {
+    if len > MAX_SIZE as usize {
+        return Err(Error::from_reason(format!(
+            "SecureBuffer is limited to {} bytes",
+            MAX_SIZE
+        )));
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn aes_key(key: &[u8]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| {
+        Error::from_reason(format!(
+            "seal()/open() need a 32-byte key, not {} bytes",
+            key.len()
+        ))
+    })?;
+    Ok(LessSafeKey::new(key))
+}
+
+pub(crate) fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let key = aes_key(key)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Error::from_reason("Random generation failed"))?;
+    let mut sealed = Vec::with_capacity(NONCE_LEN + plaintext.len() + TAG_LEN);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(plaintext);
+    let tag = key
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut sealed[NONCE_LEN..],
+        )
+        .map_err(|_| Error::from_reason("Encryption failed"))?;
+    sealed.extend_from_slice(tag.as_ref());
+    Ok(sealed)
+}
+
+pub(crate) fn open(key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let key = aes_key(key)?;
+    let (nonce, ciphertext) = sealed
+        .split_at_checked(NONCE_LEN)
+        .filter(|(_, rest)| rest.len() >= TAG_LEN)
+        .ok_or_else(|| Error::from_reason("Sealed data is too short"))?;
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| Error::from_reason("Sealed data is too short"))?;
+    let mut plaintext = ciphertext.to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .map_err(|_| Error::from_reason("DecryptionFailed: wrong key, aad or corrupted data"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locked_bytes_hold_their_contents() {
+        let bytes = LockedBytes::from_slice(b"key material").unwrap();
+        assert_eq!(bytes.as_slice(), b"key material");
+        assert_eq!(bytes.mapped % 4096, 0);
+        let empty = LockedBytes::new(0).unwrap();
+        assert!(empty.as_slice().is_empty());
+
+        let key = SecureBuffer::new(LockedBytes::from_slice(&[7u8; 32]).unwrap());
+        assert_eq!(key.length(), 32);
+        assert!(key
+            .with(|bytes| Ok(constant_time_eq(bytes, &[7u8; 32])))
+            .unwrap());
+        key.destroy();
+        assert!(key.destroyed());
+        assert_eq!(key.length(), 0);
+        let err = key.with(|_| Ok(())).unwrap_err();
+        assert_eq!(err.reason, "SecureBuffer destroyed");
+    }
+
+    #[test]
+    fn seals_and_opens_with_the_key() {
+        let key = [3u8; 32];
+        let sealed = seal(&key, b"record", b"context").unwrap();
+        assert_eq!(sealed.len(), NONCE_LEN + 6 + TAG_LEN);
+        assert_eq!(open(&key, &sealed, b"context").unwrap(), b"record");
+        assert!(open(&key, &sealed, b"other context").is_err());
+        assert!(open(&[4u8; 32], &sealed, b"context").is_err());
+        assert!(open(&key, &sealed[..20], b"context").is_err());
+        assert!(seal(&key[..16], b"record", b"").is_err());
+
+        // RFC 4231 test case 2
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?")[..4],
+            [0x5b, 0xdc, 0xc1, 0x46]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Native accept loop for the crate's built-in vsock services.
+//!
+//! Runs accept() on a dedicated thread and hands each connection to a
+//! handler on its own thread, so small fixed-protocol endpoints never
+//! touch the JS event loop.
+//!
+//! The thread polls the listener alongside a Canceller's pipe rather than
+//! blocking in accept(): shutdown() doesn't wake an accept() on AF_VSOCK
+//! (it fails with ENOTCONN), so close() cancels the poll instead.
+
+use napi::bindgen_prelude::*;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::cancel::Canceller;
+use crate::errors;
+use crate::metrics;
+use crate::platform::accept_cloexec;
+use crate::vsock::accept_raw;
+
+/// Sentinel value indicating the listener fd has been closed.
+const CLOSED_FD: i32 = -1;
+
+/// Per-connection read timeout, matching the SO_RCVTIMEO set on
+/// connections accepted through VsockListener.acceptAsync().
+const CONN_RECV_TIMEOUT_SECS: i64 = 60;
+
+pub(crate) struct AcceptLoop {
+    fd: Arc<AtomicI32>,
+    cancel: Arc<Canceller>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AcceptLoop {
+    /// Start accepting on a vsock `listener_fd` (ownership is taken).
+    ///
+    /// `handler(fd, peer_cid, peer_port)` runs on a fresh thread per
+    /// connection; the connection fd is closed after it returns. The
+    /// listener is closed if the loop can't start.
+    pub(crate) fn spawn<F>(listener_fd: i32, handler: F) -> Result<Self>
+    where
+        F: Fn(i32, u32, u32) + Send + Sync + 'static,
+    {
+        Self::spawn_with(listener_fd, accept_raw, handler)
+    }
+
+    /// Start accepting on a listener of any socket family (TCP, unix).
+    /// The handler receives 0 for the peer cid/port.
+    pub(crate) fn spawn_any<F>(listener_fd: i32, handler: F) -> Result<Self>
+    where
+        F: Fn(i32, u32, u32) + Send + Sync + 'static,
+    {
+        Self::spawn_with(listener_fd, accept_any, handler)
+    }
+
+    fn spawn_with<F>(
+        listener_fd: i32,
+        accept: fn(i32) -> std::io::Result<(i32, u32, u32)>,
+        handler: F,
+    ) -> Result<Self>
+    where
+        F: Fn(i32, u32, u32) + Send + Sync + 'static,
+    {
+        let cancel = match Canceller::new() {
+            Ok(cancel) => Arc::new(cancel),
+            Err(e) => {
+                unsafe { libc::close(listener_fd); }
+                return Err(errors::os_error("pipe()", e));
+            }
+        };
+        // A connection that goes away between poll() and accept() must
+        // not leave the thread blocked where close() can't reach it
+        unsafe {
+            let flags = libc::fcntl(listener_fd, libc::F_GETFL);
+            libc::fcntl(listener_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        let fd = Arc::new(AtomicI32::new(listener_fd));
+        let handler = Arc::new(handler);
+
+        let thread = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || loop {
+                if let Err(e) = cancel.wait(listener_fd, libc::POLLIN) {
+                    if cancel.is_cancelled() {
+                        return;
+                    }
+                    tracing::warn!(error = %e, "native server poll() failed");
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    continue;
+                }
+                match accept(listener_fd) {
+                    Ok((conn, cid, port)) => {
+                        metrics::native_connection();
+                        tracing::debug!(
+                            fd = conn,
+                            peer_cid = cid,
+                            peer_port = port,
+                            "native connection accepted"
+                        );
+                        set_recv_timeout(conn);
+                        let handler = handler.clone();
+                        std::thread::spawn(move || {
+                            handler(conn, cid, port);
+                            unsafe { libc::close(conn); }
+                            tracing::debug!(fd = conn, peer_cid = cid, "native connection closed");
+                        });
+                    }
+                    Err(e) => {
+                        if cancel.is_cancelled() {
+                            return;
+                        }
+                        // EAGAIN: the connection went away after poll()
+                        if !matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN)) {
+                            tracing::warn!(error = %e, "native server accept() failed");
+                            // Transient failures (EMFILE, ECONNABORTED, ...):
+                            // back off briefly like the JS accept loop does.
+                            std::thread::sleep(std::time::Duration::from_millis(100));
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(AcceptLoop {
+            fd,
+            cancel,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Stop accepting and release the listener. Connections already being
+    /// handled run to completion. Safe to call multiple times.
+    pub(crate) fn close(&self) {
+        let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
+        if fd == CLOSED_FD {
+            return;
+        }
+        // Wake the thread polling the listener; only close the fd once it
+        // has exited so the number can't be recycled underneath it.
+        self.cancel.cancel();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+        unsafe { libc::close(fd); }
+    }
+}
+
+impl Drop for AcceptLoop {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// accept() without peer address decoding, for non-vsock listeners.
+fn accept_any(fd: i32) -> std::io::Result<(i32, u32, u32)> {
+    Ok((accept_cloexec(fd)?, 0, 0))
+}
+
+fn set_recv_timeout(fd: i32) {
+    let tv = libc::timeval { tv_sec: CONN_RECV_TIMEOUT_SECS, tv_usec: 0 };
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &tv as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as u32,
+        );
+    }
+}
+
+// =============================================================================
+// Tests — accept loops over loopback TCP and vsock (or the mock backend)
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn serves_connections_until_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept_loop = AcceptLoop::spawn_any(listener.into_raw_fd(), |conn, _, _| {
+            let mut buf = [0u8; 4];
+            let n = unsafe { libc::read(conn, buf.as_mut_ptr() as *mut libc::c_void, 4) };
+            unsafe { libc::write(conn, buf.as_ptr() as *const libc::c_void, n as usize); }
+        })
+        .unwrap();
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"echo").unwrap();
+            let mut reply = [0u8; 4];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"echo");
+            // Handler returned, so the loop closed the connection
+            assert_eq!(client.read(&mut reply).unwrap(), 0);
+        }
+
+        accept_loop.close();
+        accept_loop.close();
+        assert!(TcpStream::connect(addr).is_err(), "listener should be closed");
+    }
+
+    #[test]
+    fn closes_vsock_listeners_promptly() {
+        // Real AF_VSOCK where the kernel has it, else the unix-socket backend
+        let accept_loop = match crate::vsock::listen_raw(crate::vsock::VMADDR_PORT_ANY) {
+            Ok(fd) => AcceptLoop::spawn(fd, |_, _, _| {}),
+            Err(_) => {
+                let dir = std::env::temp_dir()
+                    .join(format!("vsock-accept-loop-{}", std::process::id()));
+                let _ = std::fs::remove_dir_all(&dir);
+                let fd = crate::mock::MockBackend::new(dir, 3).listen(5000).unwrap();
+                AcceptLoop::spawn_with(fd, crate::mock::accept, |_, _, _| {})
+            }
+        }
+        .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let (done, closed) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            accept_loop.close();
+            let _ = done.send(());
+        });
+        assert!(
+            closed.recv_timeout(std::time::Duration::from_secs(5)).is_ok(),
+            "close() hung waiting for the accept thread"
+        );
+    }
+}
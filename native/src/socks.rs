@@ -0,0 +1,448 @@
+//! SOCKS5 (RFC 1928) over vsock.
+//!
+//! Socks5Server runs on the host: it accepts vsock connections from the
+//! enclave, performs the SOCKS5 handshake (no authentication, CONNECT only)
+//! and dials the requested TCP destination. Enclave code either points an
+//! SDK's SOCKS support at a TcpToVsockProxy in front of it, or calls
+//! socks5ConnectAsync() to get a VsockStream already tunnelled to the
+//! destination.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::framing::read_exact;
+use crate::proxy::{allowlist_permits, dial_tcp, forward_accepted, ProxyCounters, ProxyStats};
+use crate::relay::write_all_retrying;
+use crate::server::AcceptLoop;
+use crate::vsock::{self, VsockStream};
+use crate::workers::WorkerTask;
+
+const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 5000;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// Reply codes (RFC 1928 §6)
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+#[napi(object)]
+pub struct Socks5ServerOptions {
+    /// vsock port to listen on.
+    pub vsock_port: u32,
+    /// Optional allowlist of "host" or "host:port" entries. When set,
+    /// requests for other destinations are refused ("not allowed by ruleset").
+    /// IP-literal requests are matched on their textual form.
+    pub allowlist: Option<Vec<String>>,
+    /// TCP connect timeout per request (default 5000ms).
+    pub connect_timeout_ms: Option<u32>,
+}
+
+/// Host-side SOCKS5 server on a vsock port.
+#[napi]
+pub struct Socks5Server {
+    accept_loop: AcceptLoop,
+    counters: Arc<ProxyCounters>,
+}
+
+#[napi]
+impl Socks5Server {
+    /// Bind the vsock port and start serving on native threads.
+    #[napi(factory)]
+    pub fn start(options: Socks5ServerOptions) -> Result<Self> {
+        let allowlist = options.allowlist;
+        let timeout = Duration::from_millis(
+            options.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS) as u64,
+        );
+        let counters = Arc::new(ProxyCounters::default());
+        let listener_fd = vsock::listen_raw(options.vsock_port)?;
+
+        let loop_counters = counters.clone();
+        let accept_loop = AcceptLoop::spawn(listener_fd, move |conn, _cid, _port| {
+            serve_socks(conn, allowlist.as_deref(), timeout, &loop_counters);
+        })?;
+
+        Ok(Socks5Server {
+            accept_loop,
+            counters,
+        })
+    }
+
+    /// Snapshot of connection and byte counters.
+    #[napi]
+    pub fn stats(&self) -> ProxyStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop accepting new connections. Established tunnels run until either
+    /// side closes. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Handle one SOCKS5 session on `conn` (closed by the caller).
+fn serve_socks(
+    conn: i32,
+    allowlist: Option<&[String]>,
+    timeout: Duration,
+    counters: &Arc<ProxyCounters>,
+) {
+    counters.accepted.fetch_add(1, Ordering::Relaxed);
+
+    // Greeting: VER, NMETHODS, METHODS...
+    let mut greeting = [0u8; 2];
+    if read_exact(conn, &mut greeting, false).is_err() || greeting[0] != SOCKS_VERSION {
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    if read_exact(conn, &mut methods, false).is_err() {
+        return;
+    }
+    if !methods.contains(&METHOD_NO_AUTH) {
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+        let _ = write_all_retrying(conn, &[SOCKS_VERSION, METHOD_UNACCEPTABLE]);
+        return;
+    }
+    if write_all_retrying(conn, &[SOCKS_VERSION, METHOD_NO_AUTH]).is_err() {
+        return;
+    }
+
+    // Request: VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+    let mut header = [0u8; 4];
+    if read_exact(conn, &mut header, false).is_err() {
+        return;
+    }
+    let (host, port) = match read_destination(conn, header[3]) {
+        Ok(Some(dest)) => dest,
+        Ok(None) => {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            let _ = write_all_retrying(conn, &reply(REP_ADDRESS_TYPE_NOT_SUPPORTED));
+            return;
+        }
+        Err(_) => return,
+    };
+    if header[0] != SOCKS_VERSION || header[1] != CMD_CONNECT {
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+        let _ = write_all_retrying(conn, &reply(REP_COMMAND_NOT_SUPPORTED));
+        return;
+    }
+    if allowlist.is_some_and(|list| !allowlist_permits(list, &host, port)) {
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+        let _ = write_all_retrying(conn, &reply(REP_NOT_ALLOWED));
+        return;
+    }
+
+    let remote = match dial_tcp(&host, port, timeout) {
+        Ok(fd) => fd,
+        Err(e) => {
+            counters.dial_failures.fetch_add(1, Ordering::Relaxed);
+            let _ = write_all_retrying(conn, &reply(dial_error_reply(&e)));
+            return;
+        }
+    };
+    if write_all_retrying(conn, &reply(REP_SUCCEEDED)).is_err() {
+        unsafe { libc::close(remote); }
+        return;
+    }
+    forward_accepted(conn, remote, counters);
+}
+
+/// Read DST.ADDR and DST.PORT for address type `atyp`.
+/// `Ok(None)` means the address type is unsupported.
+fn read_destination(fd: i32, atyp: u8) -> std::io::Result<Option<(String, u16)>> {
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            read_exact(fd, &mut addr, false)?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            read_exact(fd, &mut addr, false)?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            read_exact(fd, &mut len, false)?;
+            let mut name = vec![0u8; len[0] as usize];
+            read_exact(fd, &mut name, false)?;
+            match String::from_utf8(name) {
+                Ok(name) if !name.is_empty() => name,
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    let mut port = [0u8; 2];
+    read_exact(fd, &mut port, false)?;
+    Ok(Some((host, u16::from_be_bytes(port))))
+}
+
+/// Reply with an unspecified IPv4 bound address — clients only use BND
+/// for BIND/UDP ASSOCIATE, which this server doesn't support.
+fn reply(rep: u8) -> [u8; 10] {
+    [SOCKS_VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+fn dial_error_reply(e: &std::io::Error) -> u8 {
+    match e.raw_os_error() {
+        Some(libc::ECONNREFUSED) => REP_CONNECTION_REFUSED,
+        Some(libc::ENETUNREACH) => REP_NETWORK_UNREACHABLE,
+        Some(libc::EHOSTUNREACH) | Some(libc::ETIMEDOUT) => REP_HOST_UNREACHABLE,
+        _ if e.kind() == std::io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        // Resolution failures and "resolved to no addresses"
+        None => REP_HOST_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        REP_GENERAL_FAILURE => "general SOCKS server failure",
+        REP_NOT_ALLOWED => "connection not allowed by ruleset",
+        REP_NETWORK_UNREACHABLE => "network unreachable",
+        REP_HOST_UNREACHABLE => "host unreachable",
+        REP_CONNECTION_REFUSED => "connection refused",
+        REP_TTL_EXPIRED => "TTL expired",
+        REP_COMMAND_NOT_SUPPORTED => "command not supported",
+        REP_ADDRESS_TYPE_NOT_SUPPORTED => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[napi(object)]
+pub struct Socks5ConnectOptions {
+    /// CID of the Socks5Server (3 = host/parent from inside the enclave).
+    pub cid: u32,
+    /// vsock port of the Socks5Server.
+    pub port: u32,
+    /// Destination host (DNS name, resolved on the host, or IP literal).
+    pub host: String,
+    pub dest_port: u32,
+    /// Timeout for the vsock connect and each handshake read (default 5s).
+    pub timeout_secs: Option<u32>,
+}
+
+/// Connect to a Socks5Server over vsock and ask it to open a TCP connection
+/// to `host:destPort`. Resolves to a VsockStream carrying the tunnelled
+/// connection. Runs on the blocking-call thread pool.
+#[napi(ts_return_type = "Promise<VsockStream>")]
+pub fn socks5_connect_async(options: Socks5ConnectOptions) -> Result<WorkerTask<Socks5ConnectTask>> {
+    let dest_port = u16::try_from(options.dest_port)
+        .map_err(|_| Error::from_reason(format!("Invalid TCP port: {}", options.dest_port)))?;
+    Ok(WorkerTask::new(Socks5ConnectTask {
+        cid: options.cid,
+        port: options.port,
+        host: options.host,
+        dest_port,
+        timeout_secs: options.timeout_secs.unwrap_or(5),
+    }))
+}
+
+pub struct Socks5ConnectTask {
+    cid: u32,
+    port: u32,
+    host: String,
+    dest_port: u16,
+    timeout_secs: u32,
+}
+
+impl Task for Socks5ConnectTask {
+    type Output = i32;
+    type JsValue = VsockStream;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        // connect_raw sets SO_RCVTIMEO, so a silent server can't hang the handshake
+        let fd = vsock::connect_raw(self.cid, self.port, self.timeout_secs)?;
+        if let Err(e) = socks5_handshake(fd, &self.host, self.dest_port) {
+            unsafe { libc::close(fd); }
+            return Err(e);
+        }
+        Ok(fd)
+    }
+
+    fn resolve(&mut self, _env: Env, fd: Self::Output) -> Result<Self::JsValue> {
+        Ok(VsockStream::from_raw(fd, self.cid, self.port))
+    }
+}
+
+/// Client side of the handshake: no-auth greeting, then CONNECT host:port.
+fn socks5_handshake(fd: i32, host: &str, port: u16) -> Result<()> {
+    let io_err = |e: std::io::Error| {
+        Error::from_reason(format!("SOCKS5 handshake with {}:{} failed: {}", host, port, e))
+    };
+
+    write_all_retrying(fd, &[SOCKS_VERSION, 1, METHOD_NO_AUTH]).map_err(io_err)?;
+    let mut choice = [0u8; 2];
+    read_exact(fd, &mut choice, false).map_err(io_err)?;
+    if choice != [SOCKS_VERSION, METHOD_NO_AUTH] {
+        return Err(Error::from_reason(
+            "SOCKS5 server requires an unsupported authentication method",
+        ));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .ok()
+                .filter(|len| *len > 0)
+                .ok_or_else(|| Error::from_reason(format!("Invalid SOCKS5 host name: {:?}", host)))?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    write_all_retrying(fd, &request).map_err(io_err)?;
+
+    let mut header = [0u8; 4];
+    read_exact(fd, &mut header, false).map_err(io_err)?;
+    if header[1] != REP_SUCCEEDED {
+        return Err(Error::from_reason(format!(
+            "SOCKS5 connect to {}:{} failed: {}",
+            host,
+            port,
+            reply_message(header[1])
+        )));
+    }
+    // Skip BND.ADDR and BND.PORT
+    let bound_len = match header[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            read_exact(fd, &mut len, false).map_err(io_err)?;
+            len[0] as usize
+        }
+        other => {
+            return Err(Error::from_reason(format!(
+                "SOCKS5 reply has unknown address type {}",
+                other
+            )))
+        }
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    read_exact(fd, &mut bound, false).map_err(io_err)?;
+    Ok(())
+}
+
+// =============================================================================
+// Tests — client handshake against the server over a unix socketpair
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    /// Run serve_socks on one end of a socketpair; returns the client end.
+    fn spawn_server(allowlist: Option<Vec<String>>) -> (i32, std::thread::JoinHandle<Arc<ProxyCounters>>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = server.into_raw_fd();
+        let handle = std::thread::spawn(move || {
+            let counters = Arc::new(ProxyCounters::default());
+            serve_socks(server, allowlist.as_deref(), Duration::from_secs(1), &counters);
+            unsafe { libc::close(server); }
+            counters
+        });
+        (client.into_raw_fd(), handle)
+    }
+
+    #[test]
+    fn connect_tunnels_to_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let echo = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let (client, server) = spawn_server(Some(vec!["127.0.0.1".to_string()]));
+        socks5_handshake(client, "127.0.0.1", port).unwrap();
+        let counters = server.join().unwrap();
+        assert_eq!(counters.active.load(Ordering::Relaxed), 1);
+
+        let mut stream = unsafe { UnixStream::from_raw_fd(client) };
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn allowlist_refusal_is_reported_to_the_client() {
+        let (client, server) = spawn_server(Some(vec!["example.com:443".to_string()]));
+        let err = socks5_handshake(client, "example.com", 80).unwrap_err();
+        assert!(err.reason.contains("not allowed by ruleset"), "{}", err.reason);
+        assert_eq!(server.join().unwrap().rejected.load(Ordering::Relaxed), 1);
+        unsafe { libc::close(client); }
+    }
+
+    #[test]
+    fn refused_destination_maps_to_reply_code() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (client, server) = spawn_server(None);
+        let err = socks5_handshake(client, "127.0.0.1", port).unwrap_err();
+        assert!(err.reason.contains("connection refused"), "{}", err.reason);
+        assert_eq!(server.join().unwrap().dial_failures.load(Ordering::Relaxed), 1);
+        unsafe { libc::close(client); }
+    }
+
+    #[test]
+    fn unsupported_commands_and_methods_are_rejected() {
+        // BIND to 1.2.3.4:80
+        let (client, server) = spawn_server(None);
+        write_all_retrying(client, &[5, 1, 0]).unwrap();
+        write_all_retrying(client, &[5, 2, 0, 1, 1, 2, 3, 4, 0, 80]).unwrap();
+        let mut buf = [0u8; 12];
+        read_exact(client, &mut buf, false).unwrap();
+        assert_eq!(buf[..2], [5, 0]);
+        assert_eq!(buf[2..4], [5, REP_COMMAND_NOT_SUPPORTED]);
+        server.join().unwrap();
+        unsafe { libc::close(client); }
+
+        // Username/password only
+        let (client, server) = spawn_server(None);
+        write_all_retrying(client, &[5, 1, 2]).unwrap();
+        let mut buf = [0u8; 2];
+        read_exact(client, &mut buf, false).unwrap();
+        assert_eq!(buf, [5, METHOD_UNACCEPTABLE]);
+        server.join().unwrap();
+        unsafe { libc::close(client); }
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests.
+
+use crate::nsm::{Device, DeviceConfig};
+use crate::nsm_mock::MockConfig;
+
+/// A connected AF_UNIX stream socket pair; the caller closes both fds.
+pub(crate) fn socketpair() -> (i32, i32) {
+    let mut fds = [0i32; 2];
+    let ret = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+    assert_eq!(ret, 0, "socketpair() failed");
+    (fds[0], fds[1])
+}
+
+/// An NSM device served by a mock with the default configuration.
+pub(crate) fn mock_device() -> Device {
+    let mut config = DeviceConfig::default();
+    config.mock = Some(MockConfig::default());
+    Device::open_with(config).unwrap()
+}
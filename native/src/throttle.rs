@@ -0,0 +1,226 @@
+//! Per-stream bandwidth limits.
+//!
+//! stream.setRateLimit() caps a VsockStream's reads and/or writes with a
+//! token bucket checked natively before each syscall, so one bulk transfer
+//! can't saturate the host link that latency-sensitive control traffic
+//! shares:
+//!
+//! ```js
+//! stream.setRateLimit(8 * 1024 * 1024, { direction: 'write' }); // 8 MiB/s
+//! ```
+//!
+//! A limited call waits until the bucket holds enough bytes for it (or a
+//! full burst, for larger calls) and then moves at most what the bucket
+//! allows, returning a short count like any partial read or write.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[napi(object)]
+pub struct RateLimitOptions {
+    /// "read", "write" or "both" (the default). Each direction gets its
+    /// own bucket; the other is left as it was.
+    pub direction: Option<String>,
+    /// Bytes that may pass at once after an idle spell (default one
+    /// second's worth, bytesPerSecond). Calls larger than this are cut
+    /// short.
+    pub burst_bytes: Option<u32>,
+}
+
+/// A parsed setRateLimit() call.
+pub(crate) struct RateLimit {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    /// (bytes per second, burst), or None to remove the limit.
+    limit: Option<(u32, u32)>,
+}
+
+impl RateLimit {
+    pub(crate) fn from_js(
+        bytes_per_second: Option<u32>,
+        options: Option<RateLimitOptions>,
+    ) -> Result<Self> {
+        let (direction, burst) = options.map_or((None, None), |o| (o.direction, o.burst_bytes));
+        let (read, write) = match direction.as_deref().unwrap_or("both") {
+            "read" => (true, false),
+            "write" => (false, true),
+            "both" => (true, true),
+            other => {
+                return Err(Error::from_reason(format!(
+                    "direction must be \"read\", \"write\" or \"both\", got {:?}",
+                    other
+                )))
+            }
+        };
+        let limit = match bytes_per_second {
+            None => None,
+            Some(0) => return Err(Error::from_reason("bytesPerSecond must be positive")),
+            Some(rate) => match burst.unwrap_or(rate) {
+                0 => return Err(Error::from_reason("burstBytes must be positive")),
+                burst => Some((rate, burst)),
+            },
+        };
+        Ok(RateLimit { read, write, limit })
+    }
+
+    /// A fresh, full bucket for one direction, or None without a limit.
+    pub(crate) fn bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.limit
+            .map(|(rate, burst)| Arc::new(TokenBucket::new(rate, burst)))
+    }
+}
+
+pub(crate) struct TokenBucket {
+    /// Refill rate in bytes per second.
+    rate: f64,
+    /// Capacity in bytes.
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_second: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: bytes_per_second as f64,
+            burst: burst as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `want` bytes (at most a burst) may pass, then take them.
+    /// Returns how many were taken, which is less than `want` only for
+    /// calls larger than the burst.
+    pub(crate) fn take(&self, want: usize) -> usize {
+        if want == 0 {
+            return 0;
+        }
+        let need = (want as f64).min(self.burst);
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                self.refill(&mut state);
+                if state.tokens >= need {
+                    state.tokens -= need;
+                    return need as usize;
+                }
+                Duration::from_secs_f64((need - state.tokens) / self.rate)
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    /// Give back bytes take() granted but the syscall didn't move.
+    pub(crate) fn refund(&self, unused: usize) {
+        if unused > 0 {
+            let mut state = self.state.lock().unwrap();
+            state.tokens = (state.tokens + unused as f64).min(self.burst);
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let earned = now.duration_since(state.refilled).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + earned).min(self.burst);
+        state.refilled = now;
+    }
+}
+
+/// How many of `want` bytes may pass under `limit`, waiting if needed.
+pub(crate) fn grant(limit: &Option<Arc<TokenBucket>>, want: usize) -> usize {
+    match limit {
+        Some(bucket) => bucket.take(want),
+        None => want,
+    }
+}
+
+/// Refund the part of a grant() that wasn't used.
+pub(crate) fn settle(limit: &Option<Arc<TokenBucket>>, granted: usize, used: usize) {
+    if let Some(bucket) = limit {
+        bucket.refund(granted.saturating_sub(used));
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_paces_calls_to_the_rate() {
+        // 1000 bytes at 10 KB/s: a full burst at once, then 100ms per burst
+        let bucket = TokenBucket::new(10_000, 1000);
+        let started = Instant::now();
+        assert_eq!(bucket.take(600), 600);
+        assert_eq!(bucket.take(5000), 1000);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert_eq!(bucket.take(1000), 1000);
+        assert!(started.elapsed() >= Duration::from_millis(90));
+
+        bucket.refund(400);
+        let started = Instant::now();
+        assert_eq!(bucket.take(400), 400);
+        assert!(started.elapsed() < Duration::from_millis(30));
+        assert_eq!(bucket.take(0), 0);
+    }
+
+    #[test]
+    fn rate_limit_options_are_validated() {
+        let options = |direction: &str, burst_bytes| {
+            Some(RateLimitOptions {
+                direction: Some(direction.to_string()),
+                burst_bytes,
+            })
+        };
+        let limit = RateLimit::from_js(Some(4096), None).unwrap();
+        assert!(limit.read && limit.write);
+        assert_eq!(limit.limit, Some((4096, 4096)));
+
+        let limit = RateLimit::from_js(Some(4096), options("write", Some(512))).unwrap();
+        assert!(!limit.read && limit.write);
+        assert_eq!(limit.limit, Some((4096, 512)));
+
+        let limit = RateLimit::from_js(None, options("read", None)).unwrap();
+        assert!(limit.read && !limit.write && limit.bucket().is_none());
+
+        assert!(RateLimit::from_js(Some(0), None).is_err());
+        assert!(RateLimit::from_js(Some(1), options("both", Some(0))).is_err());
+        assert!(RateLimit::from_js(Some(1), options("sideways", None)).is_err());
+    }
+
+    #[test]
+    fn refunds_never_overfill_the_bucket() {
+        let limit = Some(Arc::new(TokenBucket::new(1000, 100)));
+        assert_eq!(grant(&limit, 100), 100);
+        // A short write: 40 of the 100 granted bytes moved
+        settle(&limit, 100, 40);
+        assert_eq!(grant(&limit, 60), 60);
+
+        settle(&limit, 5000, 0);
+        let started = Instant::now();
+        assert_eq!(grant(&limit, 100), 100);
+        assert_eq!(grant(&limit, 50), 50);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn no_limit_grants_everything() {
+        assert_eq!(grant(&None, usize::MAX), usize::MAX);
+        settle(&None, 10, 0);
+    }
+}
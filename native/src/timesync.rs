@@ -0,0 +1,385 @@
+//! Clock synchronization with the parent over vsock.
+//!
+//! Enclaves have no NTP, and their clock drifts, which breaks certificate
+//! validity checks and token expiry. The parent runs a TimeSyncServer; the
+//! enclave's TimeSyncClient exchanges NTP-style timestamps with it and
+//! keeps the measured offset:
+//!
+//! - enclave → parent: a frame holding CBOR `{"t0": int}`, the enclave's
+//!   send time.
+//! - parent → enclave: `{"t0": int, "t1": int, "t2": int}`, echoing t0
+//!   with the parent's receive and send times.
+//!
+//! With t3 the enclave's receive time, the offset is
+//! ((t1 - t0) + (t2 - t3)) / 2 and the round trip (t3 - t0) - (t2 - t1).
+//! sync() takes several samples and keeps the one with the shortest round
+//! trip. All times are nanoseconds since the Unix epoch.
+//!
+//! ```js
+//! // parent
+//! const server = TimeSyncServer.bind(5010);
+//! // enclave
+//! const clock = new TimeSyncClient({ port: 5010 });
+//! await clock.sync();
+//! const now = clock.nowAdjusted();
+//! ```
+
+use ciborium::value::Value;
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::server::AcceptLoop;
+use crate::workers::WorkerTask;
+use crate::{cbor, framing, vsock};
+
+const DEFAULT_CID: u32 = 3;
+const DEFAULT_SAMPLES: u32 = 8;
+const MAX_SAMPLES: u32 = 64;
+const DEFAULT_TIMEOUT_SECS: u32 = 5;
+/// Offset value meaning sync() hasn't succeeded yet.
+const UNSYNCED: i64 = i64::MIN;
+
+/// Nanoseconds since the Unix epoch by the system clock.
+fn system_now_ns() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(before) => -(before.duration().as_nanos() as i64),
+    }
+}
+
+/// Parent-side endpoint answering TimeSyncClient samples with its clock.
+#[napi]
+pub struct TimeSyncServer {
+    accept_loop: AcceptLoop,
+    port: u32,
+}
+
+#[napi]
+impl TimeSyncServer {
+    /// Bind the time endpoint on the given vsock port and start serving on
+    /// native threads.
+    #[napi(factory)]
+    pub fn bind(port: u32) -> Result<Self> {
+        let fd = vsock::listen_raw(port)?;
+        Ok(TimeSyncServer {
+            accept_loop: AcceptLoop::spawn(fd, |conn, _cid, _port| {
+                serve_connection(conn, system_now_ns)
+            })?,
+            port,
+        })
+    }
+
+    /// The vsock port this server is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Stop accepting connections. Safe to call multiple times.
+    #[napi]
+    pub fn close(&self) -> Result<()> {
+        self.accept_loop.close();
+        Ok(())
+    }
+}
+
+/// Answer samples on `fd` with times from `clock` until the client closes.
+fn serve_connection(fd: i32, clock: fn() -> i64) {
+    while let Ok(Some(request)) = framing::read_frame(fd) {
+        let received = clock();
+        let t0 = cbor::decode(&request)
+            .ok()
+            .and_then(|request| cbor::map_get(&request, "t0").cloned());
+        let response = match t0 {
+            Some(t0 @ Value::Integer(_)) => cbor::map(vec![
+                ("t0", t0),
+                ("t1", Value::Integer(received.into())),
+                ("t2", Value::Integer(clock().into())),
+            ]),
+            _ => cbor::map(vec![(
+                "error",
+                cbor::text("time sync request must be a CBOR map with an integer t0"),
+            )]),
+        };
+        let Ok(bytes) = cbor::encode(&response) else {
+            return;
+        };
+        if framing::write_frame(fd, &bytes).is_err() {
+            return;
+        }
+    }
+}
+
+#[napi(object)]
+pub struct TimeSyncOptions {
+    /// vsock port of the parent's TimeSyncServer.
+    pub port: u32,
+    /// Server CID (default 3, the parent).
+    pub cid: Option<u32>,
+    /// Samples per sync() (default 8, at most 64).
+    pub samples: Option<u32>,
+    /// Connect and per-read timeout (default 5s).
+    pub timeout_secs: Option<u32>,
+}
+
+#[napi(object)]
+pub struct TimeSyncResult {
+    /// Parent clock minus enclave clock, in milliseconds.
+    pub offset_ms: f64,
+    /// Round trip of the sample the offset came from, in milliseconds; the
+    /// offset is accurate to within half of it.
+    pub round_trip_ms: f64,
+    pub samples: u32,
+}
+
+/// One NTP-style exchange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    offset_ns: i64,
+    round_trip_ns: i64,
+}
+
+impl Sample {
+    fn from_timestamps(t0: i64, t1: i64, t2: i64, t3: i64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i128, t1 as i128, t2 as i128, t3 as i128);
+        Sample {
+            offset_ns: (((t1 - t0) + (t2 - t3)) / 2) as i64,
+            round_trip_ns: ((t3 - t0) - (t2 - t1)).max(0) as i64,
+        }
+    }
+}
+
+/// Take `samples` samples from the server on `fd` and return the one with
+/// the shortest round trip.
+fn measure(fd: i32, samples: u32) -> Result<Sample> {
+    let io_err = |e: std::io::Error| Error::from_reason(format!("Time sync failed: {}", e));
+    let mut best: Option<Sample> = None;
+    for _ in 0..samples {
+        let t0 = system_now_ns();
+        let request = cbor::encode(&cbor::map(vec![("t0", Value::Integer(t0.into()))]))?;
+        framing::write_frame(fd, &request).map_err(io_err)?;
+        let response = framing::read_frame(fd)
+            .map_err(io_err)?
+            .ok_or_else(|| Error::from_reason("Time sync server closed the connection"))?;
+        let t3 = system_now_ns();
+
+        let response = cbor::decode(&response)?;
+        if let Some(error) = cbor::map_get(&response, "error").and_then(cbor::as_text) {
+            return Err(Error::from_reason(format!(
+                "Time sync server error: {}",
+                error
+            )));
+        }
+        let field = |name: &str| {
+            cbor::map_get(&response, name)
+                .and_then(|value| value.as_integer())
+                .and_then(|n| i64::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::from_reason(format!("Time sync response has no integer {}", name))
+                })
+        };
+        if field("t0")? != t0 {
+            return Err(Error::from_reason(
+                "Time sync response is for another request",
+            ));
+        }
+        let sample = Sample::from_timestamps(t0, field("t1")?, field("t2")?, t3);
+        if best.is_none_or(|best| sample.round_trip_ns < best.round_trip_ns) {
+            best = Some(sample);
+        }
+    }
+    best.ok_or_else(|| Error::from_reason("samples must be at least 1"))
+}
+
+/// Enclave-side clock corrected by the parent's.
+#[napi]
+pub struct TimeSyncClient {
+    cid: u32,
+    port: u32,
+    samples: u32,
+    timeout_secs: u32,
+    /// UNSYNCED until the first successful sync().
+    offset_ns: Arc<AtomicI64>,
+}
+
+#[napi]
+impl TimeSyncClient {
+    #[napi(constructor)]
+    pub fn new(options: TimeSyncOptions) -> Result<Self> {
+        let samples = options.samples.unwrap_or(DEFAULT_SAMPLES);
+        if !(1..=MAX_SAMPLES).contains(&samples) {
+            return Err(Error::from_reason(format!(
+                "samples must be 1 to {}",
+                MAX_SAMPLES
+            )));
+        }
+        Ok(TimeSyncClient {
+            cid: options.cid.unwrap_or(DEFAULT_CID),
+            port: options.port,
+            samples,
+            timeout_secs: options.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            offset_ns: Arc::new(AtomicI64::new(UNSYNCED)),
+        })
+    }
+
+    /// Measure the offset to the parent's clock on the blocking-call thread pool,
+    /// and use it for nowAdjusted() from then on.
+    #[napi(ts_return_type = "Promise<TimeSyncResult>")]
+    pub fn sync(&self) -> WorkerTask<SyncTask> {
+        WorkerTask::new(SyncTask {
+            cid: self.cid,
+            port: self.port,
+            samples: self.samples,
+            timeout_secs: self.timeout_secs,
+            offset_ns: self.offset_ns.clone(),
+        })
+    }
+
+    /// The parent clock minus the enclave clock from the last sync(), in
+    /// milliseconds, or null before the first.
+    #[napi(getter)]
+    pub fn offset_ms(&self) -> Option<f64> {
+        match self.offset_ns.load(Ordering::Acquire) {
+            UNSYNCED => None,
+            offset => Some(offset as f64 / 1e6),
+        }
+    }
+
+    /// Milliseconds since the Unix epoch by the parent's clock: like
+    /// Date.now(), corrected by the last sync(). Throws "NotSynced: ..."
+    /// before the first.
+    #[napi]
+    pub fn now_adjusted(&self) -> Result<f64> {
+        Ok((system_now_ns() + self.offset()?) as f64 / 1e6)
+    }
+
+    /// Step the system clock (CLOCK_REALTIME) by the last sync()'s offset,
+    /// after which the offset is zero. Needs CAP_SYS_TIME; throws
+    /// "PermissionDenied: ..." without it.
+    #[napi]
+    pub fn set_system_clock(&self) -> Result<()> {
+        let target = system_now_ns() + self.offset()?;
+        let time = libc::timespec {
+            tv_sec: target.div_euclid(1_000_000_000) as libc::time_t,
+            tv_nsec: target.rem_euclid(1_000_000_000) as libc::c_long,
+        };
+        if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &time) } != 0 {
+            let err = std::io::Error::last_os_error();
+            let code = if err.raw_os_error() == Some(libc::EPERM) {
+                "PermissionDenied"
+            } else {
+                "ClockError"
+            };
+            return Err(Error::from_reason(format!(
+                "{}: clock_settime failed: {}",
+                code, err
+            )));
+        }
+        self.offset_ns.store(0, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl TimeSyncClient {
+    fn offset(&self) -> Result<i64> {
+        match self.offset_ns.load(Ordering::Acquire) {
+            UNSYNCED => Err(Error::from_reason("NotSynced: call sync() first")),
+            offset => Ok(offset),
+        }
+    }
+}
+
+pub struct SyncTask {
+    cid: u32,
+    port: u32,
+    samples: u32,
+    timeout_secs: u32,
+    offset_ns: Arc<AtomicI64>,
+}
+
+impl Task for SyncTask {
+    type Output = Sample;
+    type JsValue = TimeSyncResult;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let fd = vsock::connect_raw(self.cid, self.port, self.timeout_secs)?;
+        let sample = measure(fd, self.samples);
+        unsafe {
+            libc::close(fd);
+        }
+        let sample = sample?;
+        self.offset_ns.store(sample.offset_ns, Ordering::Release);
+        Ok(sample)
+    }
+
+    fn resolve(&mut self, _env: Env, sample: Self::Output) -> Result<Self::JsValue> {
+        Ok(TimeSyncResult {
+            offset_ms: sample.offset_ns as f64 / 1e6,
+            round_trip_ms: sample.round_trip_ns as f64 / 1e6,
+            samples: self.samples,
+        })
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    const SKEW_NS: i64 = 5_000_000_000;
+
+    fn skewed_clock() -> i64 {
+        system_now_ns() + SKEW_NS
+    }
+
+    #[test]
+    fn offsets_follow_the_ntp_formula() {
+        // Server 100 ahead, 10 each way, 4 spent in the server
+        let sample = Sample::from_timestamps(1000, 1110, 1114, 1024);
+        assert_eq!(
+            sample,
+            Sample {
+                offset_ns: 100,
+                round_trip_ns: 20
+            }
+        );
+    }
+
+    #[test]
+    fn measures_the_offset_to_the_server_clock() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let served = std::thread::spawn(move || serve_connection(server.as_raw_fd(), skewed_clock));
+        let sample = measure(client.as_raw_fd(), 4).unwrap();
+        drop(client);
+        served.join().unwrap();
+
+        assert!(
+            (sample.offset_ns - SKEW_NS).abs() <= sample.round_trip_ns / 2 + 1_000_000,
+            "{:?}",
+            sample
+        );
+        assert!(sample.round_trip_ns < 1_000_000_000, "{:?}", sample);
+    }
+
+    #[test]
+    fn servers_reject_malformed_samples() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let served =
+            std::thread::spawn(move || serve_connection(server.as_raw_fd(), system_now_ns));
+        let request = cbor::encode(&cbor::map(vec![("t0", cbor::text("now"))])).unwrap();
+        framing::write_frame(client.as_raw_fd(), &request).unwrap();
+        let response = framing::read_frame(client.as_raw_fd()).unwrap().unwrap();
+        let response = cbor::decode(&response).unwrap();
+        assert!(cbor::map_get(&response, "error").is_some());
+        drop(client);
+        served.join().unwrap();
+    }
+}
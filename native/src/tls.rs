@@ -0,0 +1,751 @@
+//! TLS over vsock with rustls.
+//!
+//! TlsVsockServer terminates TLS inside the enclave on a VsockStream the
+//! host forwards client connections to, so the host only ever sees
+//! ciphertext. TlsVsockClient originates TLS through a dumb host forwarder
+//! (e.g. TcpToVsockProxy), checking the server's certificate itself. The
+//! record layer runs directly on the stream's descriptor; no user-space
+//! TCP stack is involved.
+//!
+//! ```js
+//! const server = new TlsVsockServer({ certificateChain, privateKey });
+//! const tls = await server.accept(listener.accept());
+//! const request = tls.read(16384);
+//! tls.write(Buffer.from('HTTP/1.1 200 OK\r\n\r\n'));
+//! tls.close();
+//! ```
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::policy::{AttestationPolicy, Policy};
+use crate::ra_tls::AttestedServerVerifier;
+use crate::relay::dup_fd;
+use crate::vsock::VsockStream;
+use crate::workers::WorkerTask;
+
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u32 = 10_000;
+/// Ciphertext read from the socket at a time: one full TLS record.
+const READ_CHUNK: usize = 16 * 1024 + 256;
+
+#[napi(object)]
+pub struct TlsVsockServerOptions {
+    /// PEM certificates, leaf first, or a single DER certificate.
+    pub certificate_chain: Buffer,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1), or DER PKCS#8.
+    pub private_key: Buffer,
+    /// ALPN protocols to offer, in preference order (e.g. ["h2", "http/1.1"]).
+    pub alpn_protocols: Option<Vec<String>>,
+    /// How long accept() waits for the handshake (default 10000).
+    pub handshake_timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct TlsVsockClientOptions {
+    /// PEM certificates, or a single DER certificate, to trust instead of
+    /// the bundled public (Mozilla) roots.
+    pub trusted_roots: Option<Buffer>,
+    /// ALPN protocols to request, in preference order.
+    pub alpn_protocols: Option<Vec<String>>,
+    /// How long connect() waits for the handshake (default 10000).
+    pub handshake_timeout_ms: Option<u32>,
+}
+
+/// Enclave-side TLS termination for streams from a VsockListener.
+#[napi]
+pub struct TlsVsockServer {
+    config: Arc<ServerConfig>,
+    timeout: Duration,
+}
+
+#[napi]
+impl TlsVsockServer {
+    #[napi(constructor)]
+    pub fn new(options: TlsVsockServerOptions) -> Result<Self> {
+        Ok(TlsVsockServer {
+            config: server_config(
+                &options.certificate_chain,
+                &options.private_key,
+                options.alpn_protocols.unwrap_or_default(),
+            )?,
+            timeout: timeout(options.handshake_timeout_ms),
+        })
+    }
+
+    /// Run the server side of a handshake on `stream` on the blocking-call
+    /// thread pool. Rejects with "TLS handshake failed: ..." if the client
+    /// misbehaves, aborts or times out.
+    #[napi(ts_return_type = "Promise<TlsVsockStream>")]
+    pub fn accept(&self, stream: &VsockStream) -> Result<WorkerTask<HandshakeTask>> {
+        let connection = ServerConnection::new(self.config.clone())
+            .map_err(|e| Error::from_reason(format!("TLS setup failed: {}", e)))?;
+        Ok(WorkerTask::new(HandshakeTask {
+            fd: dup_fd(stream.fd())?,
+            timeout: self.timeout,
+            connection: Some(connection.into()),
+        }))
+    }
+}
+
+/// Enclave-side TLS client for streams to a host forwarder.
+#[napi]
+pub struct TlsVsockClient {
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+}
+
+#[napi]
+impl TlsVsockClient {
+    #[napi(constructor)]
+    pub fn new(options: Option<TlsVsockClientOptions>) -> Result<Self> {
+        let (roots, alpn, timeout_ms) = match options {
+            Some(o) => (o.trusted_roots, o.alpn_protocols, o.handshake_timeout_ms),
+            None => (None, None, None),
+        };
+        Ok(TlsVsockClient {
+            config: client_config(roots.as_deref(), alpn.unwrap_or_default())?,
+            timeout: timeout(timeout_ms),
+        })
+    }
+
+    /// A client that accepts servers by the attestation document in their
+    /// certificate (see generateAttestedCertificate()) under `policy`,
+    /// instead of by CA chain and hostname: `serverName` is then only sent
+    /// as SNI, and `options.trustedRoots` is ignored.
+    #[napi(factory)]
+    pub fn attested(
+        policy: &AttestationPolicy,
+        options: Option<TlsVsockClientOptions>,
+    ) -> Result<Self> {
+        let (alpn, timeout_ms) = match options {
+            Some(o) => (o.alpn_protocols, o.handshake_timeout_ms),
+            None => (None, None),
+        };
+        Ok(TlsVsockClient {
+            config: attested_client_config(policy.policy(), alpn.unwrap_or_default()),
+            timeout: timeout(timeout_ms),
+        })
+    }
+
+    /// Run the client side of a handshake with `serverName` on `stream`
+    /// on the blocking-call thread pool. Rejects with "TLS handshake failed: ..."
+    /// if the certificate doesn't verify for `serverName`.
+    #[napi(ts_return_type = "Promise<TlsVsockStream>")]
+    pub fn connect(
+        &self,
+        stream: &VsockStream,
+        server_name: String,
+    ) -> Result<WorkerTask<HandshakeTask>> {
+        let name = ServerName::try_from(server_name)
+            .map_err(|e| Error::from_reason(format!("Invalid server name: {}", e)))?;
+        let connection = ClientConnection::new(self.config.clone(), name)
+            .map_err(|e| Error::from_reason(format!("TLS setup failed: {}", e)))?;
+        Ok(WorkerTask::new(HandshakeTask {
+            fd: dup_fd(stream.fd())?,
+            timeout: self.timeout,
+            connection: Some(connection.into()),
+        }))
+    }
+}
+
+fn timeout(timeout_ms: Option<u32>) -> Duration {
+    Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS) as u64)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn alpn(protocols: Vec<String>) -> Vec<Vec<u8>> {
+    protocols.into_iter().map(String::into_bytes).collect()
+}
+
+fn server_config(
+    chain: &[u8],
+    key: &[u8],
+    alpn_protocols: Vec<String>,
+) -> Result<Arc<ServerConfig>> {
+    let chain = certificates(chain, "certificateChain")?;
+    let key = private_key(key)?;
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| Error::from_reason(format!("Invalid TLS certificate or key: {}", e)))?;
+    config.alpn_protocols = alpn(alpn_protocols);
+    Ok(Arc::new(config))
+}
+
+fn client_config(
+    trusted_roots: Option<&[u8]>,
+    alpn_protocols: Vec<String>,
+) -> Result<Arc<ClientConfig>> {
+    let roots = match trusted_roots {
+        Some(pem) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in certificates(pem, "trustedRoots")? {
+                roots
+                    .add(cert)
+                    .map_err(|e| Error::from_reason(format!("Invalid trustedRoots: {}", e)))?;
+            }
+            roots
+        }
+        None => rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let mut config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn(alpn_protocols);
+    Ok(Arc::new(config))
+}
+
+fn attested_client_config(policy: Policy, alpn_protocols: Vec<String>) -> Arc<ClientConfig> {
+    let provider = provider();
+    let verifier = AttestedServerVerifier::new(policy, &provider);
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn(alpn_protocols);
+    Arc::new(config)
+}
+
+/// PEM CERTIFICATE blocks, or a single DER certificate.
+fn certificates(bytes: &[u8], what: &str) -> Result<Vec<CertificateDer<'static>>> {
+    if !bytes.starts_with(b"-----") && !bytes.windows(11).any(|w| w == b"-----BEGIN ") {
+        return Ok(vec![CertificateDer::from(bytes.to_vec())]);
+    }
+    let certs = CertificateDer::pem_slice_iter(bytes)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::from_reason(format!("Invalid {}: {}", what, e)))?;
+    if certs.is_empty() {
+        return Err(Error::from_reason(format!(
+            "Invalid {}: no certificates",
+            what
+        )));
+    }
+    Ok(certs)
+}
+
+/// A PEM private key of any supported kind, or DER PKCS#8.
+fn private_key(bytes: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    if !bytes.windows(11).any(|w| w == b"-----BEGIN ") {
+        return Ok(PrivatePkcs8KeyDer::from(bytes.to_vec()).into());
+    }
+    PrivateKeyDer::from_pem_slice(bytes)
+        .map_err(|e| Error::from_reason(format!("Invalid privateKey: {}", e)))
+}
+
+pub struct HandshakeTask {
+    /// A duplicate of the stream's fd, owned by the TLS stream once made.
+    fd: i32,
+    timeout: Duration,
+    /// Taken by compute().
+    connection: Option<Connection>,
+}
+
+impl Task for HandshakeTask {
+    type Output = Session;
+    type JsValue = TlsVsockStream;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let result = match self.connection.take() {
+            Some(connection) => handshake(self.fd, connection, Instant::now() + self.timeout),
+            None => Err(Error::from_reason("Handshake already ran")),
+        };
+        if result.is_err() {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+        result
+    }
+
+    fn resolve(&mut self, _env: Env, session: Self::Output) -> Result<Self::JsValue> {
+        Ok(TlsVsockStream {
+            inner: Arc::new(session),
+        })
+    }
+}
+
+/// Blocking reads and writes on a raw fd for rustls, with reads bounded
+/// by `deadline` when set.
+struct Socket {
+    fd: i32,
+    deadline: Option<Instant>,
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            wait_readable(self.fd, deadline)?;
+        }
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        loop {
+            let n = unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn wait_readable(fd: i32, deadline: Instant) -> std::io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let ret = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as i32) };
+        if ret > 0 {
+            return Ok(());
+        }
+        if ret == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "timed out waiting for the peer",
+            ));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Drive `connection` through its handshake on `fd`.
+fn handshake(fd: i32, mut connection: Connection, deadline: Instant) -> Result<Session> {
+    let failed = |e: std::io::Error| Error::from_reason(format!("TLS handshake failed: {}", e));
+    let mut socket = Socket {
+        fd,
+        deadline: Some(deadline),
+    };
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket).map_err(failed)?;
+    }
+    while connection.wants_write() {
+        connection.write_tls(&mut socket).map_err(failed)?;
+    }
+    Ok(Session {
+        fd: AtomicI32::new(fd),
+        alpn_protocol: connection
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        protocol_version: connection.protocol_version().map(|v| match v {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            other => format!("{:?}", other),
+        }),
+        peer_certificates: connection
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|c| c.to_vec()).collect())
+            .unwrap_or_default(),
+        state: Mutex::new(State {
+            connection,
+            pending: Vec::new(),
+            eof: false,
+        }),
+    })
+}
+
+struct State {
+    connection: Connection,
+    /// Ciphertext read from the socket but not yet taken by rustls.
+    pending: Vec<u8>,
+    /// The socket has reached end of file.
+    eof: bool,
+}
+
+impl State {
+    /// Move plaintext into `buf`, feeding rustls from `pending` as needed.
+    /// None when more ciphertext is needed; Some(0) after close_notify.
+    fn take_plaintext(&mut self, fd: i32, buf: &mut [u8]) -> Result<Option<usize>> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Ok(n) => return Ok(Some(n)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(Error::from_reason(
+                        "TLS peer closed the connection without close_notify",
+                    ))
+                }
+                Err(e) => return Err(tls_error(e)),
+            }
+            if self.pending.is_empty() && !self.eof {
+                return Ok(None);
+            }
+            // With nothing pending this tells rustls about end of file
+            let mut rest = &self.pending[..];
+            self.connection.read_tls(&mut rest).map_err(tls_error)?;
+            let consumed = self.pending.len() - rest.len();
+            self.pending.drain(..consumed);
+            self.connection
+                .process_new_packets()
+                .map_err(|e| tls_error(e.to_string()))?;
+            // Answer key updates and the like
+            self.flush(fd)?;
+        }
+    }
+
+    fn flush(&mut self, fd: i32) -> Result<()> {
+        let mut socket = Socket { fd, deadline: None };
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut socket).map_err(tls_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn tls_error(e: impl std::fmt::Display) -> Error {
+    Error::from_reason(format!("TLS error: {}", e))
+}
+
+/// napi-free core of TlsVsockStream.
+pub struct Session {
+    fd: AtomicI32,
+    alpn_protocol: Option<String>,
+    protocol_version: Option<String>,
+    peer_certificates: Vec<Vec<u8>>,
+    state: Mutex<State>,
+}
+
+impl Session {
+    fn fd(&self) -> Result<i32> {
+        match self.fd.load(Ordering::Acquire) {
+            -1 => Err(Error::from_reason("TLS stream is closed")),
+            fd => Ok(fd),
+        }
+    }
+
+    /// Up to `size` bytes of plaintext; empty once the peer has closed.
+    fn read(&self, size: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; size];
+        let mut ciphertext = vec![0u8; READ_CHUNK];
+        loop {
+            let fd = self.fd()?;
+            if let Some(n) = self.state.lock().unwrap().take_plaintext(fd, &mut buf)? {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+            // Wait for the socket without the lock, so writes can proceed
+            let n = Socket { fd, deadline: None }
+                .read(&mut ciphertext)
+                .map_err(tls_error)?;
+            let mut state = self.state.lock().unwrap();
+            state.pending.extend_from_slice(&ciphertext[..n]);
+            state.eof = n == 0;
+        }
+    }
+
+    fn write(&self, mut data: &[u8]) -> Result<()> {
+        let fd = self.fd()?;
+        let mut state = self.state.lock().unwrap();
+        while !data.is_empty() {
+            let n = state.connection.writer().write(data).map_err(tls_error)?;
+            data = &data[n..];
+            state.flush(fd)?;
+        }
+        Ok(())
+    }
+
+    /// Send close_notify, then shut the connection down and release the fd.
+    fn close(&self) {
+        let fd = self.fd.swap(-1, Ordering::AcqRel);
+        if fd == -1 {
+            return;
+        }
+        if let Ok(mut state) = self.state.lock() {
+            state.connection.send_close_notify();
+            // Best effort: the peer may already be gone
+            let _ = state.flush(fd);
+        }
+        unsafe {
+            libc::shutdown(fd, libc::SHUT_RDWR);
+            libc::close(fd);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let fd = self.fd.swap(-1, Ordering::AcqRel);
+        if fd != -1 {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// A TLS session over a VsockStream, from TlsVsockServer.accept() or
+/// TlsVsockClient.connect().
+///
+/// The session uses its own duplicate of the stream's descriptor: close()
+/// ends the connection for both, but the stream must still be closed to
+/// free its descriptor. Don't read or write the stream directly once the
+/// session is open.
+#[napi]
+pub struct TlsVsockStream {
+    inner: Arc<Session>,
+}
+
+#[napi]
+impl TlsVsockStream {
+    /// Read up to `size` bytes of plaintext; an empty buffer once the peer
+    /// has sent close_notify. Throws if the connection ends without it.
+    /// Note: this is a blocking call — use readAsync() on the main thread.
+    #[napi]
+    pub fn read(&self, size: u32) -> Result<Buffer> {
+        Ok(self.inner.read(size as usize)?.into())
+    }
+
+    /// read() on the blocking-call thread pool.
+    #[napi(ts_return_type = "Promise<Buffer>")]
+    pub fn read_async(&self, size: u32) -> WorkerTask<ReadTask> {
+        WorkerTask::new(ReadTask {
+            session: self.inner.clone(),
+            size: size as usize,
+        })
+    }
+
+    /// Encrypt and send all of `data`. Returns the number of bytes written.
+    #[napi]
+    pub fn write(&self, data: Buffer) -> Result<u32> {
+        self.inner.write(&data)?;
+        Ok(data.len() as u32)
+    }
+
+    /// The negotiated ALPN protocol, or null.
+    #[napi(getter)]
+    pub fn alpn_protocol(&self) -> Option<String> {
+        self.inner.alpn_protocol.clone()
+    }
+
+    /// "TLSv1.2" or "TLSv1.3".
+    #[napi(getter)]
+    pub fn protocol_version(&self) -> Option<String> {
+        self.inner.protocol_version.clone()
+    }
+
+    /// The peer's DER certificates, leaf first; empty on the server side.
+    #[napi(getter)]
+    pub fn peer_certificates(&self) -> Vec<Buffer> {
+        self.inner
+            .peer_certificates
+            .iter()
+            .map(|cert| cert.clone().into())
+            .collect()
+    }
+
+    /// Send close_notify and shut the connection down. Safe to call
+    /// multiple times.
+    #[napi]
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+pub struct ReadTask {
+    session: Arc<Session>,
+    size: usize,
+}
+
+impl Task for ReadTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        self.session.read(self.size)
+    }
+
+    fn resolve(&mut self, _env: Env, data: Self::Output) -> Result<Self::JsValue> {
+        Ok(data.into())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation;
+    use crate::policy::fixtures::test_policy;
+    use crate::ra_tls::{generate, CertificateParams};
+    use crate::test_support::mock_device;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    const CA_PEM: &[u8] = include_bytes!("../testdata/tls/ca.pem");
+    const SERVER_PEM: &[u8] = include_bytes!("../testdata/tls/server.pem");
+    const SERVER_KEY: &[u8] = include_bytes!("../testdata/tls/server.key");
+
+    /// Run both sides of a handshake over a socketpair.
+    fn handshake_pair(
+        client: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> (Result<Session>, Result<Session>) {
+        let server = server_config(SERVER_PEM, SERVER_KEY, vec!["h2".into()]).unwrap();
+        handshake_with(server, client, server_name)
+    }
+
+    fn handshake_with(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> (Result<Session>, Result<Session>) {
+        let (client_fd, server_fd) = UnixStream::pair().unwrap();
+        let (client_fd, server_fd) = (client_fd.into_raw_fd(), server_fd.into_raw_fd());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let accepted = std::thread::spawn(move || {
+            let connection = ServerConnection::new(server).unwrap().into();
+            let result = handshake(server_fd, connection, deadline);
+            if result.is_err() {
+                unsafe {
+                    libc::close(server_fd);
+                }
+            }
+            result
+        });
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(client, name).unwrap().into();
+        let connected = handshake(client_fd, connection, deadline);
+        if connected.is_err() {
+            unsafe {
+                libc::close(client_fd);
+            }
+        }
+        (connected, accepted.join().unwrap())
+    }
+
+    #[test]
+    fn sessions_carry_data_both_ways() {
+        let client = client_config(Some(CA_PEM), vec!["http/1.1".into(), "h2".into()]).unwrap();
+        let (client, server) = handshake_pair(client, "enclave.test");
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.alpn_protocol.as_deref(), Some("h2"));
+        assert_eq!(client.protocol_version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(client.peer_certificates.len(), 1);
+        assert!(server.peer_certificates.is_empty());
+
+        client.write(b"ping").unwrap();
+        assert_eq!(server.read(1024).unwrap(), b"ping");
+        let large = vec![0x5a; 100_000];
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            while received.len() < 100_000 {
+                received.extend(client.read(65536).unwrap());
+            }
+            (client, received)
+        });
+        server.write(&large).unwrap();
+        let (client, received) = reader.join().unwrap();
+        assert_eq!(received, large);
+
+        server.close();
+        assert!(client.read(1024).unwrap().is_empty());
+        assert_eq!(
+            server.write(b"late").unwrap_err().reason,
+            "TLS stream is closed"
+        );
+    }
+
+    #[test]
+    fn handshakes_check_the_server_certificate() {
+        // Not issued by a public root
+        let (client, server) = handshake_pair(client_config(None, vec![]).unwrap(), "enclave.test");
+        let err = client.err().unwrap().reason;
+        assert!(err.starts_with("TLS handshake failed: "), "{}", err);
+        assert!(err.contains("UnknownIssuer"), "{}", err);
+        assert!(server.is_err());
+
+        let trusting = client_config(Some(CA_PEM), vec![]).unwrap();
+        let (client, _) = handshake_pair(trusting, "other.test");
+        let err = client.err().unwrap().reason;
+        assert!(err.contains("not valid for name \"other.test\""), "{}", err);
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_certificates() {
+        let err = server_config(SERVER_PEM, CA_PEM, vec![]).unwrap_err();
+        assert!(
+            err.reason.starts_with("Invalid privateKey: "),
+            "{}",
+            err.reason
+        );
+        let err = client_config(Some(b"-----BEGIN X-----\n-----END X-----\n"), vec![]).unwrap_err();
+        assert_eq!(err.reason, "Invalid trustedRoots: no certificates");
+    }
+
+    #[test]
+    fn attested_clients_check_the_certificate_document() {
+        let now = attestation::now_ms();
+        let params = CertificateParams {
+            common_name: "enclave",
+            dns_names: &[],
+            not_before_ms: now - 60_000,
+            not_after_ms: now + 3_600_000,
+        };
+        let generated = generate(&mock_device(), &params, None, None).unwrap();
+        let server = server_config(&generated.certificate, &generated.private_key, vec![]).unwrap();
+
+        let client = attested_client_config(test_policy(), vec![]);
+        let (client, server_session) = handshake_with(server.clone(), client, "any.name");
+        assert_eq!(
+            client.unwrap().peer_certificates,
+            vec![generated.certificate]
+        );
+        server_session.unwrap();
+
+        let mut policy = test_policy();
+        policy.require_nonce(b"elsewhere".to_vec());
+        let client = attested_client_config(policy, vec![]);
+        let (client, _) = handshake_with(server, client, "any.name");
+        let err = client.err().unwrap().reason;
+        assert!(
+            err.contains("AttestationRejected: Document has no nonce"),
+            "{}",
+            err
+        );
+    }
+}
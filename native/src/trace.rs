@@ -0,0 +1,130 @@
+//! Traffic tracing for VsockStream.
+//!
+//! tcpdump can't see AF_VSOCK traffic, so stream.enableTrace() reports every
+//! read() and write() to a JS callback instead: direction, wall-clock
+//! timestamp, the bytes themselves and a hexdump ready for logging.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes shown per hexdump line.
+const HEXDUMP_WIDTH: usize = 16;
+
+#[napi(object)]
+pub struct TraceEvent {
+    /// "read" or "write".
+    pub direction: String,
+    /// Milliseconds since the Unix epoch, as Date.now().
+    pub timestamp_ms: f64,
+    /// A copy of the bytes transferred.
+    pub data: Buffer,
+    /// `hexdump -C` style rendering of `data`.
+    pub hexdump: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    Read,
+    Write,
+}
+
+impl Direction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Direction::Read => "read",
+            Direction::Write => "write",
+        }
+    }
+}
+
+/// One traced transfer, converted to a TraceEvent on delivery.
+pub(crate) struct TraceRecord {
+    pub(crate) direction: Direction,
+    pub(crate) timestamp_ms: f64,
+    pub(crate) data: Vec<u8>,
+}
+
+impl TraceRecord {
+    pub(crate) fn new(direction: Direction, data: &[u8]) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0);
+        TraceRecord { direction, timestamp_ms, data: data.to_vec() }
+    }
+
+    pub(crate) fn into_event(self) -> TraceEvent {
+        TraceEvent {
+            direction: self.direction.as_str().to_string(),
+            timestamp_ms: self.timestamp_ms,
+            hexdump: hexdump(&self.data),
+            data: self.data.into(),
+        }
+    }
+}
+
+pub(crate) type TraceSink = Box<dyn Fn(TraceRecord) + Send>;
+
+/// Render `data` like `hexdump -C`: offset, 16 hex bytes split in two
+/// groups of 8, then the printable ASCII.
+pub(crate) fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        let _ = write!(out, "{:08x} ", i * HEXDUMP_WIDTH);
+        for col in 0..HEXDUMP_WIDTH {
+            if col % 8 == 0 {
+                out.push(' ');
+            }
+            match line.get(col) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_matches_hexdump_c() {
+        let dump = hexdump(b"GET / HTTP/1.1\r\nHost: x\r\n");
+        assert_eq!(
+            dump,
+            "00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n\
+             00000010  48 6f 73 74 3a 20 78 0d  0a                       |Host: x..|\n"
+        );
+    }
+
+    #[test]
+    fn hexdump_of_nothing_is_empty() {
+        assert_eq!(hexdump(&[]), "");
+    }
+
+    #[test]
+    fn records_are_timestamped() {
+        let record = TraceRecord::new(Direction::Write, b"hi");
+        assert_eq!(record.direction, Direction::Write);
+        assert_eq!(record.data, b"hi");
+        assert!(record.timestamp_ms > 1.6e12, "expected epoch milliseconds");
+    }
+}
@@ -0,0 +1,579 @@
+//! Opt-in io_uring I/O backend for VsockStream.
+//!
+//! For streaming workloads the per-call read()/write() syscall dominates.
+//! IoUringDriver lets JS queue many reads and writes across registered
+//! streams and hand them to the kernel with a single submit(); a native
+//! completion thread drains the completion queue and delivers finished
+//! operations to JS in batches through one callback invocation.
+//!
+//! Registered streams are duplicated, so closing the JS stream doesn't
+//! recycle the descriptor under an in-flight operation. Buffers are owned
+//! by the driver until their operation completes.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+
+#[napi(object)]
+pub struct IoUringOptions {
+    /// Submission queue size (default 256). The completion queue is twice
+    /// this, which is also the cap on operations in flight.
+    pub entries: Option<u32>,
+}
+
+/// One finished operation.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[napi(object)]
+pub struct IoCompletion {
+    /// The id returned by queueRead()/queueWrite().
+    pub id: i64,
+    /// The registered stream handle the operation ran on.
+    pub handle: u32,
+    /// "read" or "write".
+    pub op: String,
+    /// Bytes transferred (0 on a read means EOF), or -errno on failure.
+    /// Writes complete once every byte is written or an error occurs.
+    pub result: i32,
+    /// The bytes read (reads only).
+    pub data: Option<Buffer>,
+    /// Error message when `result` is negative.
+    pub error: Option<String>,
+}
+
+#[cfg(not(target_os = "linux"))]
+#[napi]
+pub struct IoUringDriver {}
+
+#[cfg(not(target_os = "linux"))]
+#[napi]
+impl IoUringDriver {
+    #[napi(factory)]
+    pub fn create(
+        #[napi(ts_arg_type = "(completions: IoCompletion[]) => void")] _on_completions: JsFunction,
+        _options: Option<IoUringOptions>,
+    ) -> Result<Self> {
+        Err(crate::platform::unsupported("io_uring"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{IoCompletion, IoUringOptions};
+    use io_uring::{opcode, squeue, types, IoUring};
+    use napi::bindgen_prelude::*;
+    use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+    use napi_derive::napi;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    use crate::relay::dup_fd;
+    use crate::vsock::VsockStream;
+
+    const DEFAULT_ENTRIES: u32 = 256;
+
+    /// user_data of the NOP that wakes the completion thread on close().
+    const WAKE_TAG: u64 = u64::MAX;
+    /// user_data of AsyncCancel requests issued by close().
+    const CANCEL_TAG: u64 = u64::MAX - 1;
+
+    /// io_uring_enter(2) flag: wait for completions.
+    const IORING_ENTER_GETEVENTS: u32 = 1;
+
+    enum OpKind {
+        Read,
+        Write,
+    }
+
+    /// The memory an operation reads into or writes from.
+    enum OpData {
+        Read(Vec<u8>),
+        /// Written in place, without a copy: for queueWrite() this is the
+        /// caller's Buffer, whose reference keeps the JS memory alive until
+        /// the completion is reaped.
+        Write(Box<dyn AsRef<[u8]> + Send>),
+    }
+
+    impl OpData {
+        fn kind(&self) -> OpKind {
+            match self {
+                OpData::Read(_) => OpKind::Read,
+                OpData::Write(_) => OpKind::Write,
+            }
+        }
+    }
+
+    struct PendingOp {
+        handle: u32,
+        data: OpData,
+        /// Bytes already written (writes are resubmitted until complete).
+        done: usize,
+    }
+
+    struct Registration {
+        fd: i32,
+        in_flight: usize,
+        unregistered: bool,
+    }
+
+    struct State {
+        next_id: u64,
+        next_handle: u32,
+        pending: HashMap<u64, PendingOp>,
+        handles: HashMap<u32, Registration>,
+        closing: bool,
+    }
+
+    struct Shared {
+        ring: IoUring,
+        state: Mutex<State>,
+        max_in_flight: usize,
+    }
+
+    impl Shared {
+        /// Push one SQE, flushing the queue to the kernel first if it's full.
+        /// Callers hold the state lock, which serialises submission-queue access.
+        fn push(&self, sqe: &squeue::Entry) -> std::io::Result<()> {
+            loop {
+                // SAFETY: only called with the state lock held, so there's a
+                // single submission-queue producer; buffers referenced by the
+                // SQE live in `pending` until the completion is reaped.
+                let pushed = unsafe { self.ring.submission_shared().push(sqe).is_ok() };
+                if pushed {
+                    return Ok(());
+                }
+                self.ring.submit()?;
+            }
+        }
+
+        fn sqe_for(id: u64, fd: i32, op: &mut PendingOp) -> squeue::Entry {
+            match &mut op.data {
+                OpData::Read(buf) => {
+                    opcode::Recv::new(types::Fd(fd), buf.as_mut_ptr(), buf.len() as u32)
+                        .build()
+                        .user_data(id)
+                }
+                OpData::Write(data) => {
+                    let rest = &(**data).as_ref()[op.done..];
+                    opcode::Send::new(types::Fd(fd), rest.as_ptr(), rest.len() as u32)
+                        .flags(libc::MSG_NOSIGNAL)
+                        .build()
+                        .user_data(id)
+                }
+            }
+        }
+
+        fn queue(&self, handle: u32, data: OpData) -> Result<i64> {
+            let mut state = self.state.lock().unwrap();
+            if state.closing {
+                return Err(Error::from_reason("IoUringDriver is closed"));
+            }
+            if state.pending.len() >= self.max_in_flight {
+                return Err(Error::from_reason(format!(
+                    "io_uring queue full ({} operations in flight)",
+                    state.pending.len()
+                )));
+            }
+            let fd = match state.handles.get(&handle) {
+                Some(reg) if !reg.unregistered => reg.fd,
+                _ => return Err(Error::from_reason(format!("Unknown stream handle: {}", handle))),
+            };
+
+            let id = state.next_id;
+            state.next_id += 1;
+            let mut op = PendingOp { handle, data, done: 0 };
+            let sqe = Self::sqe_for(id, fd, &mut op);
+            // Insert before pushing: the data's heap memory doesn't move.
+            state.pending.insert(id, op);
+            if let Err(e) = self.push(&sqe) {
+                state.pending.remove(&id);
+                return Err(Error::from_reason(format!("io_uring submit failed: {}", e)));
+            }
+            if let Some(reg) = state.handles.get_mut(&handle) {
+                reg.in_flight += 1;
+            }
+            Ok(id as i64)
+        }
+
+        /// Turn a CQE into a completion, or resubmit the rest of a short write.
+        fn complete(&self, state: &mut State, id: u64, result: i32) -> Option<Finished> {
+            let mut op = state.pending.remove(&id)?;
+            let fd = state.handles.get(&op.handle).map(|reg| reg.fd).unwrap_or(-1);
+
+            if let OpData::Write(data) = &op.data {
+                if result > 0 {
+                    op.done += result as usize;
+                    if op.done < (**data).as_ref().len() && !state.closing {
+                        let sqe = Self::sqe_for(id, fd, &mut op);
+                        state.pending.insert(id, op);
+                        if self.push(&sqe).and_then(|_| self.ring.submit()).is_ok() {
+                            return None;
+                        }
+                        op = state.pending.remove(&id)?;
+                    }
+                }
+            }
+
+            if let Some(reg) = state.handles.get_mut(&op.handle) {
+                reg.in_flight -= 1;
+                if reg.unregistered && reg.in_flight == 0 {
+                    unsafe { libc::close(reg.fd); }
+                    state.handles.remove(&op.handle);
+                }
+            }
+
+            let kind = op.data.kind();
+            // Report the total for writes that needed resubmitting
+            let result = match kind {
+                OpKind::Write if result >= 0 => op.done as i32,
+                _ => result,
+            };
+            let data = match op.data {
+                OpData::Read(mut buf) if result >= 0 => {
+                    buf.truncate(result as usize);
+                    Some(buf)
+                }
+                _ => None,
+            };
+            Some(Finished { id: id as i64, handle: op.handle, kind, result, data })
+        }
+    }
+
+    /// A reaped operation, converted to an IoCompletion on delivery.
+    struct Finished {
+        id: i64,
+        handle: u32,
+        kind: OpKind,
+        result: i32,
+        data: Option<Vec<u8>>,
+    }
+
+    impl Finished {
+        fn into_completion(self) -> IoCompletion {
+            IoCompletion {
+                id: self.id,
+                handle: self.handle,
+                op: match self.kind {
+                    OpKind::Read => "read",
+                    OpKind::Write => "write",
+                }
+                .to_string(),
+                result: self.result,
+                data: self.data.map(Buffer::from),
+                error: (self.result < 0)
+                    .then(|| std::io::Error::from_raw_os_error(-self.result).to_string()),
+            }
+        }
+    }
+
+    /// Batched io_uring submission/completion for registered VsockStreams.
+    #[napi]
+    pub struct IoUringDriver {
+        shared: Arc<Shared>,
+        thread: Mutex<Option<JoinHandle<()>>>,
+    }
+
+    #[napi]
+    impl IoUringDriver {
+        /// Set up a ring and its completion thread. `onCompletions` receives
+        /// every batch of finished operations. Fails if io_uring is
+        /// unavailable (old kernel, seccomp), so callers can fall back to
+        /// plain read()/write().
+        #[napi(factory)]
+        pub fn create(
+            #[napi(ts_arg_type = "(completions: IoCompletion[]) => void")]
+            on_completions: ThreadsafeFunction<Vec<IoCompletion>, ErrorStrategy::Fatal>,
+            options: Option<IoUringOptions>,
+        ) -> Result<Self> {
+            let entries = options.and_then(|o| o.entries).unwrap_or(DEFAULT_ENTRIES);
+            let ring = IoUring::builder()
+                .setup_cqsize(entries.saturating_mul(2))
+                .build(entries)
+                .map_err(|e| Error::from_reason(format!("io_uring_setup failed: {}", e)))?;
+            let max_in_flight = ring.params().cq_entries() as usize;
+
+            let shared = Arc::new(Shared {
+                ring,
+                state: Mutex::new(State {
+                    next_id: 1,
+                    next_handle: 1,
+                    pending: HashMap::new(),
+                    handles: HashMap::new(),
+                    closing: false,
+                }),
+                max_in_flight,
+            });
+
+            let thread_shared = shared.clone();
+            let thread = std::thread::spawn(move || completion_loop(&thread_shared, on_completions));
+
+            Ok(IoUringDriver {
+                shared,
+                thread: Mutex::new(Some(thread)),
+            })
+        }
+
+        /// Register a stream with the driver. Returns a handle for
+        /// queueRead()/queueWrite(). The stream's descriptor is duplicated;
+        /// the stream itself stays usable and must still be closed by the caller.
+        #[napi]
+        pub fn register(&self, stream: &VsockStream) -> Result<u32> {
+            let fd = dup_fd(stream.fd())?;
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closing {
+                unsafe { libc::close(fd); }
+                return Err(Error::from_reason("IoUringDriver is closed"));
+            }
+            let handle = state.next_handle;
+            state.next_handle += 1;
+            state.handles.insert(handle, Registration { fd, in_flight: 0, unregistered: false });
+            Ok(handle)
+        }
+
+        /// Release a handle. Operations already queued on it still complete.
+        #[napi]
+        pub fn unregister(&self, handle: u32) -> Result<()> {
+            let mut state = self.shared.state.lock().unwrap();
+            match state.handles.get_mut(&handle) {
+                Some(reg) if !reg.unregistered => {
+                    reg.unregistered = true;
+                    if reg.in_flight == 0 {
+                        unsafe { libc::close(reg.fd); }
+                        state.handles.remove(&handle);
+                    }
+                    Ok(())
+                }
+                _ => Err(Error::from_reason(format!("Unknown stream handle: {}", handle))),
+            }
+        }
+
+        /// Queue a read of up to `size` bytes. Returns the operation id.
+        /// Nothing reaches the kernel until submit().
+        #[napi]
+        pub fn queue_read(&self, handle: u32, size: u32) -> Result<i64> {
+            self.shared.queue(handle, OpData::Read(vec![0u8; size as usize]))
+        }
+
+        /// Queue a write of `data`. The Buffer is written in place, not
+        /// copied, so don't modify it before the completion arrives.
+        /// Returns the operation id.
+        /// Nothing reaches the kernel until submit().
+        #[napi]
+        pub fn queue_write(&self, handle: u32, data: Buffer) -> Result<i64> {
+            if data.is_empty() {
+                return Err(Error::from_reason("queueWrite: data must not be empty"));
+            }
+            self.shared.queue(handle, OpData::Write(Box::new(data)))
+        }
+
+        /// Submit every queued operation with one io_uring_enter(2).
+        /// Returns the number of operations submitted.
+        #[napi]
+        pub fn submit(&self) -> Result<u32> {
+            let _state = self.shared.state.lock().unwrap();
+            self.shared
+                .ring
+                .submit()
+                .map(|n| n as u32)
+                .map_err(|e| Error::from_reason(format!("io_uring submit failed: {}", e)))
+        }
+
+        /// Operations queued or in flight.
+        #[napi(getter)]
+        pub fn pending(&self) -> u32 {
+            self.shared.state.lock().unwrap().pending.len() as u32
+        }
+
+        /// Cancel in-flight operations, wait for the kernel to release their
+        /// buffers and stop the completion thread. Cancelled operations are
+        /// still reported (result -ECANCELED). Safe to call multiple times.
+        #[napi]
+        pub fn close(&self) -> Result<()> {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.closing {
+                    state.closing = true;
+                    let ids: Vec<u64> = state.pending.keys().copied().collect();
+                    for id in ids {
+                        let cancel = opcode::AsyncCancel::new(id).build().user_data(CANCEL_TAG);
+                        let _ = self.shared.push(&cancel);
+                    }
+                    let _ = self.shared.push(&opcode::Nop::new().build().user_data(WAKE_TAG));
+                    let _ = self.shared.ring.submit();
+                }
+            }
+            if let Some(thread) = self.thread.lock().unwrap().take() {
+                let _ = thread.join();
+            }
+            let mut state = self.shared.state.lock().unwrap();
+            for (_, reg) in state.handles.drain() {
+                unsafe { libc::close(reg.fd); }
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for IoUringDriver {
+        fn drop(&mut self) {
+            let _ = self.close();
+        }
+    }
+
+    fn completion_loop(
+        shared: &Shared,
+        on_completions: ThreadsafeFunction<Vec<IoCompletion>, ErrorStrategy::Fatal>,
+    ) {
+        loop {
+            // Wait without submitting: submission is driven by submit().
+            let waited = unsafe {
+                shared
+                    .ring
+                    .submitter()
+                    .enter::<libc::sigset_t>(0, 1, IORING_ENTER_GETEVENTS, None)
+            };
+            if let Err(e) = waited {
+                if e.raw_os_error() != Some(libc::EINTR) {
+                    return;
+                }
+            }
+
+            let mut state = shared.state.lock().unwrap();
+            // SAFETY: this thread is the only completion-queue consumer.
+            let cqes: Vec<(u64, i32)> = unsafe { shared.ring.completion_shared() }
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            let batch: Vec<IoCompletion> = cqes
+                .into_iter()
+                .filter(|(id, _)| *id != WAKE_TAG && *id != CANCEL_TAG)
+                .filter_map(|(id, result)| shared.complete(&mut state, id, result))
+                .map(Finished::into_completion)
+                .collect();
+            let done = state.closing && state.pending.is_empty();
+            drop(state);
+
+            if !batch.is_empty() {
+                on_completions.call(batch, ThreadsafeFunctionCallMode::NonBlocking);
+            }
+            if done {
+                return;
+            }
+        }
+    }
+
+    // =========================================================================
+    // Tests — ring round trips over a unix socketpair (skipped where io_uring
+    // is unavailable)
+    // =========================================================================
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support::socketpair;
+
+        fn shared(entries: u32) -> Option<Shared> {
+            let ring = IoUring::new(entries).ok()?;
+            let max_in_flight = ring.params().cq_entries() as usize;
+            Some(Shared {
+                ring,
+                state: Mutex::new(State {
+                    next_id: 1,
+                    next_handle: 2,
+                    pending: HashMap::new(),
+                    handles: HashMap::new(),
+                    closing: false,
+                }),
+                max_in_flight,
+            })
+        }
+
+        fn write(bytes: &'static [u8]) -> OpData {
+            OpData::Write(Box::new(bytes))
+        }
+
+        /// Wait for and reap completions until `want` user-visible ones arrive.
+        fn reap(shared: &Shared, want: usize) -> Vec<Finished> {
+            let mut out = Vec::new();
+            while out.len() < want {
+                shared.ring.submit_and_wait(1).unwrap();
+                let mut state = shared.state.lock().unwrap();
+                let cqes: Vec<(u64, i32)> = unsafe { shared.ring.completion_shared() }
+                    .map(|cqe| (cqe.user_data(), cqe.result()))
+                    .collect();
+                for (id, result) in cqes {
+                    out.extend(shared.complete(&mut state, id, result));
+                }
+            }
+            out
+        }
+
+        #[test]
+        fn batched_write_then_read_round_trip() {
+            let Some(shared) = shared(8) else {
+                eprintln!("io_uring unavailable; skipping");
+                return;
+            };
+            let (a, b) = socketpair();
+            {
+                let mut state = shared.state.lock().unwrap();
+                state.handles.insert(1, Registration { fd: a, in_flight: 0, unregistered: false });
+                state.handles.insert(2, Registration { fd: b, in_flight: 0, unregistered: false });
+            }
+
+            let w1 = shared.queue(1, write(b"hello ")).unwrap();
+            let w2 = shared.queue(1, write(b"ring")).unwrap();
+            assert_eq!(shared.ring.submit().unwrap(), 2, "both writes go in one submit");
+            let writes = reap(&shared, 2);
+            assert!(writes.iter().any(|c| c.id == w1 && c.result == 6));
+            assert!(writes.iter().any(|c| c.id == w2 && c.result == 4));
+
+            let r = shared.queue(2, OpData::Read(vec![0u8; 64])).unwrap();
+            shared.ring.submit().unwrap();
+            let reads = reap(&shared, 1);
+            assert_eq!(reads[0].id, r);
+            assert!(matches!(reads[0].kind, OpKind::Read));
+            assert_eq!(reads[0].data.as_deref(), Some(&b"hello ring"[..]));
+            assert!(shared.state.lock().unwrap().pending.is_empty());
+
+            unsafe { libc::close(a); libc::close(b); }
+        }
+
+        #[test]
+        fn errors_are_reported_as_negative_errno() {
+            let Some(shared) = shared(4) else { return };
+            let (a, b) = socketpair();
+            unsafe { libc::close(b); }
+            shared.state.lock().unwrap().handles.insert(
+                1,
+                Registration { fd: a, in_flight: 0, unregistered: false },
+            );
+            shared.queue(1, write(b"x")).unwrap();
+            shared.ring.submit().unwrap();
+            let done = reap(&shared, 1);
+            assert_eq!(done[0].result, -libc::EPIPE);
+            unsafe { libc::close(a); }
+        }
+
+        #[test]
+        fn unknown_handles_and_full_queues_are_rejected() {
+            let Some(shared) = shared(1) else { return };
+            let err = shared.queue(9, OpData::Read(vec![0u8; 1])).unwrap_err();
+            assert!(err.reason.contains("Unknown stream handle"));
+
+            let (a, b) = socketpair();
+            shared.state.lock().unwrap().handles.insert(
+                1,
+                Registration { fd: a, in_flight: 0, unregistered: false },
+            );
+            for _ in 0..shared.max_in_flight {
+                shared.queue(1, OpData::Read(vec![0u8; 1])).unwrap();
+            }
+            let err = shared.queue(1, OpData::Read(vec![0u8; 1])).unwrap_err();
+            assert!(err.reason.contains("queue full"));
+
+            // Let the queued reads finish before the buffers are dropped
+            unsafe { libc::close(b); }
+            shared.ring.submit().unwrap();
+            reap(&shared, shared.max_in_flight);
+            unsafe { libc::close(a); }
+        }
+    }
+}
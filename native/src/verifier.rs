@@ -0,0 +1,214 @@
+//! Standalone verification reports for host processes.
+//!
+//! AttestationPolicy.evaluate() answers "may this enclave in?", listing
+//! only what failed. A host that keeps an audit trail also needs what
+//! passed. verifyRemoteAttestation(document, policy) applies the same
+//! rules and reports every check it made, in order: signature, chain,
+//! certificate expiry, each required PCR, module id, age and nonce, each
+//! with pass/fail and what was found. It needs no NSM, so it runs on the
+//! parent instance or anywhere else documents are received:
+//!
+//! ```js
+//! const report = verifyRemoteAttestation(doc, policy);
+//! audit.write(report);
+//! if (!report.valid) throw new Error(report.checks.find((c) => !c.passed).message);
+//! ```
+//!
+//! A document that can't be decoded gives a report with one failed
+//! "decode" check rather than throwing, so it can be logged the same way.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use sha2::{Digest, Sha256};
+
+use crate::attestation::{self, AttestationDocument, CoseSign1, Document};
+use crate::attestation_export::iso_time;
+use crate::policy::{AttestationPolicy, Check, Policy};
+use crate::sigv4;
+use crate::x509::{self, Certificate};
+
+#[napi(object)]
+pub struct VerificationCheck {
+    /// "decode", "signature", "chain", "expiry", "pcr<N>", "moduleId",
+    /// "maxAge" or "nonce". Rules the policy doesn't set are not listed.
+    pub rule: String,
+    pub passed: bool,
+    /// What was found, e.g. why the check failed.
+    pub message: String,
+}
+
+#[napi(object)]
+pub struct VerificationReport {
+    /// True when every check passed.
+    pub valid: bool,
+    pub checks: Vec<VerificationCheck>,
+    /// The evaluation time, as an ISO 8601 string.
+    pub verified_at: String,
+    /// Hex SHA-256 of the document bytes, to identify it in logs.
+    pub document_sha256: String,
+    /// Absent when the document couldn't be decoded.
+    pub document: Option<AttestationDocument>,
+}
+
+/// Verify `document` (COSE_Sign1 bytes) against `policy` at `timeMs`
+/// (default now), reporting every check. Throws only for a policy with no
+/// trust anchors.
+#[napi]
+pub fn verify_remote_attestation(
+    document: Buffer,
+    policy: &AttestationPolicy,
+    time_ms: Option<f64>,
+) -> Result<VerificationReport> {
+    let time_ms = time_ms
+        .map(|t| t as i64)
+        .unwrap_or_else(attestation::now_ms);
+    let report = report(&document, &policy.policy(), time_ms)?;
+    Ok(VerificationReport {
+        valid: report.checks.iter().all(|check| check.passed),
+        checks: report
+            .checks
+            .into_iter()
+            .map(|check| VerificationCheck {
+                rule: check.rule,
+                passed: check.passed,
+                message: check.message,
+            })
+            .collect(),
+        verified_at: iso_time(time_ms),
+        document_sha256: sigv4::hex(&Sha256::digest(&document[..])),
+        document: report.document.map(Document::into_js),
+    })
+}
+
+/// napi-free form of VerificationReport.
+struct Report {
+    checks: Vec<Check>,
+    document: Option<Document>,
+}
+
+fn report(cose: &[u8], policy: &Policy, time_ms: i64) -> Result<Report> {
+    let decoded = CoseSign1::parse(cose).and_then(|sign1| Document::parse(&sign1.payload));
+    if let Err(e) = decoded {
+        return Ok(Report {
+            checks: vec![Check {
+                rule: "decode".to_string(),
+                passed: false,
+                message: e.reason,
+            }],
+            document: None,
+        });
+    }
+    let (mut checks, document) = policy.check(cose, time_ms)?;
+    // After "signature" and "chain"
+    let at = checks.len().min(2);
+    checks.insert(at, expiry(&document, time_ms));
+    Ok(Report {
+        checks,
+        document: Some(document),
+    })
+}
+
+/// Whether the signing certificate and the cabundle are all within their
+/// validity at `time_ms`. The chain check covers this too; this one says
+/// which certificate and when.
+fn expiry(document: &Document, time_ms: i64) -> Check {
+    let certificates = std::iter::once(&document.certificate).chain(document.cabundle.iter().rev());
+    let mut signing_expiry = None;
+    let mut count = 0;
+    for (i, der) in certificates.enumerate() {
+        let what = match i {
+            0 => "Signing certificate".to_string(),
+            i => format!("cabundle[{}]", document.cabundle.len() - i),
+        };
+        let failed = |message: String| Check {
+            rule: "expiry".to_string(),
+            passed: false,
+            message,
+        };
+        let cert = match Certificate::from_der(der) {
+            Ok(cert) => cert,
+            Err(e) => return failed(format!("{}: {}", what, e.reason)),
+        };
+        let name = x509::name_to_string(&cert.subject).unwrap_or(what);
+        if time_ms < cert.not_before_ms {
+            return failed(format!(
+                "{} is not valid until {}",
+                name,
+                iso_time(cert.not_before_ms)
+            ));
+        }
+        if time_ms > cert.not_after_ms {
+            return failed(format!(
+                "{} expired at {}",
+                name,
+                iso_time(cert.not_after_ms)
+            ));
+        }
+        signing_expiry.get_or_insert(cert.not_after_ms);
+        count += 1;
+    }
+    Check {
+        rule: "expiry".to_string(),
+        passed: true,
+        message: format!(
+            "All {} certificates are valid; the signing certificate expires at {}",
+            count,
+            iso_time(signing_expiry.unwrap_or_default())
+        ),
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::fixtures::*;
+    use crate::policy::fixtures::test_policy;
+
+    fn outcome(report: &Report) -> Vec<(&str, bool)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.rule.as_str(), check.passed))
+            .collect()
+    }
+
+    #[test]
+    fn every_check_is_reported_passed_or_not() {
+        let passed = report(&signed_document(|_| {}), &test_policy(), JAN_2030_MS).unwrap();
+        assert_eq!(
+            outcome(&passed),
+            vec![("signature", true), ("chain", true), ("expiry", true)]
+        );
+        assert_eq!(
+            passed.checks[2].message,
+            "All 3 certificates are valid; the signing certificate expires at 2045-01-01T00:00:00.000Z"
+        );
+
+        // 2046: the signing certificate has expired
+        let expired = report(
+            &signed_document(|_| {}),
+            &test_policy(),
+            JAN_2030_MS + 16 * 365 * 86_400_000,
+        )
+        .unwrap();
+        assert_eq!(
+            outcome(&expired),
+            vec![("signature", true), ("chain", false), ("expiry", false)]
+        );
+        assert_eq!(
+            expired.checks[2].message,
+            "C=US, O=Test, CN=test.enclave expired at 2045-01-01T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn undecodable_documents_fail_the_decode_check() {
+        let undecodable = report(b"not cbor", &test_policy(), JAN_2030_MS).unwrap();
+        assert_eq!(outcome(&undecodable), vec![("decode", false)]);
+        assert!(undecodable.document.is_none());
+    }
+}
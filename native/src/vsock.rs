@@ -1,11 +1,46 @@
 use napi::bindgen_prelude::*;
-use napi::Task;
+use napi::{JsBuffer, JsFunction, Task};
 use napi_derive::napi;
-use std::sync::atomic::{AtomicI32, Ordering};
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::backlog::{self, BacklogPressureOptions, BacklogStats};
+use crate::cancel::{cancelled_error, CancelToken, Canceller};
+use crate::framed_server::{FramedServer, FramedServerOptions};
+use crate::pool::{external_buffer, BufferPool, ReadBufferPool};
+use crate::throttle::{self, RateLimit, RateLimitOptions, TokenBucket};
+use crate::trace::{Direction, TraceEvent, TraceRecord, TraceSink};
+use crate::watchdog::{CloseCallback, PeerCloseEvent, Watchdog};
+use crate::workers::WorkerTask;
+use crate::write_queue::{FlushTask, Sink, WriteQueue, WriteQueueOptions, WriteQueueStats};
+use crate::{errors, metrics, mock, platform, relay};
 
 /// AF_VSOCK constants — not in libc crate, defined by Linux kernel
 const AF_VSOCK: i32 = 40;
-const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
+
+/// Well-known CIDs and ports (linux/vm_sockets.h), exported for JS.
+/// Bind to any local CID (the ListenerOptions.cid default).
+#[napi]
+pub const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
+/// The hypervisor.
+#[napi]
+pub const VMADDR_CID_HYPERVISOR: u32 = 0;
+/// This machine, over vsock loopback.
+#[napi]
+pub const VMADDR_CID_LOCAL: u32 = 1;
+/// The host of a VM.
+#[napi]
+pub const VMADDR_CID_HOST: u32 = 2;
+/// The parent instance, as seen from inside a Nitro Enclave.
+#[napi]
+pub const PARENT_CID: u32 = 3;
+/// Bind to any free port; read it back from listener.port.
+#[napi]
+pub const VMADDR_PORT_ANY: u32 = 0xFFFFFFFF;
 
 /// Sentinel value indicating the fd has been closed.
 const CLOSED_FD: i32 = -1;
@@ -20,117 +55,717 @@ struct SockaddrVm {
     svm_zero: [u8; 4],
 }
 
-/// A vsock server that listens for incoming connections.
-#[napi]
-pub struct VsockListener {
-    fd: AtomicI32,
+/// Create a listening AF_VSOCK socket bound to CID_ANY on `port`.
+/// Shared by VsockListener and the crate's native servers.
+pub(crate) fn listen_raw(port: u32) -> Result<i32> {
+    listen_at(VMADDR_CID_ANY, port, backlog::DEFAULT_BACKLOG)
 }
 
-#[napi]
-impl VsockListener {
-    /// Create a new VsockListener bound to CID_ANY on the given port.
-    /// CID_ANY means the enclave accepts connections from any CID (typically the host).
-    #[napi(factory)]
-    pub fn bind(port: u32) -> Result<Self> {
-        unsafe {
-            let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
-            if fd < 0 {
+/// listen_raw() at a specific local `cid`, with a `backlog`. The mock
+/// backend always listens at its own CID and needs an explicit port.
+fn listen_at(cid: u32, port: u32, backlog: u32) -> Result<i32> {
+    if let Some(backend) = mock::backend() {
+        if port == VMADDR_PORT_ANY {
+            return Err(Error::from_reason(
+                "VMADDR_PORT_ANY is not supported by the mock vsock backend",
+            ));
+        }
+        return backend.listen(port);
+    }
+    platform::require_linux("AF_VSOCK")?;
+    unsafe {
+        let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(errors::os_error("socket(AF_VSOCK)", std::io::Error::last_os_error()));
+        }
+
+        // Allow address reuse
+        let optval: i32 = 1;
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &optval as *const _ as *const libc::c_void,
+            std::mem::size_of::<i32>() as u32,
+        );
+
+        let addr = SockaddrVm {
+            svm_family: AF_VSOCK as u16,
+            svm_reserved1: 0,
+            svm_port: port,
+            svm_cid: cid,
+            svm_zero: [0; 4],
+        };
+
+        let ret = libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<SockaddrVm>() as u32,
+        );
+        if ret < 0 {
+            libc::close(fd);
+            return Err(errors::os_error(
+                format!("bind(AF_VSOCK, port={})", port),
+                std::io::Error::last_os_error(),
+            ));
+        }
+
+        let ret = libc::listen(fd, backlog.min(i32::MAX as u32) as i32);
+        if ret < 0 {
+            libc::close(fd);
+            return Err(errors::os_error("listen()", std::io::Error::last_os_error()));
+        }
+
+        Ok(fd)
+    }
+}
+
+/// accept() one connection on a listening vsock socket.
+/// Returns (fd, peer_cid, peer_port).
+pub(crate) fn accept_raw(fd: i32) -> std::io::Result<(i32, u32, u32)> {
+    if mock::backend().is_some() {
+        return mock::accept(fd);
+    }
+    unsafe {
+        let mut addr: SockaddrVm = std::mem::zeroed();
+        let mut addr_len = std::mem::size_of::<SockaddrVm>() as u32;
+
+        let client_fd = libc::accept(
+            fd,
+            &mut addr as *mut _ as *mut libc::sockaddr,
+            &mut addr_len,
+        );
+        if client_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok((client_fd, addr.svm_cid, addr.svm_port))
+    }
+}
+
+#[napi(object)]
+pub struct ListenerOptions {
+    /// Maximum number of accepted streams open at once. Unlimited if unset.
+    pub max_connections: Option<u32>,
+    /// What to do with connections beyond maxConnections: "queue" (default)
+    /// stops accepting until a stream closes, leaving new connections in the
+    /// kernel backlog; "close" accepts and immediately closes them.
+    pub when_full: Option<String>,
+    /// Shut down accepted streams with no read()/write()/sendFile() traffic
+    /// for this long. Streams handed to pipe() or an IoUringDriver move data
+    /// without going through the stream, so don't combine them with this.
+    pub idle_timeout_ms: Option<u32>,
+    /// Local CID to bind, e.g. VMADDR_CID_LOCAL to only take loopback
+    /// connections (default VMADDR_CID_ANY). The mock backend ignores it.
+    pub cid: Option<u32>,
+    /// Connections the kernel may queue for accept() (default 128, capped
+    /// by net.core.somaxconn). See backlogStats().
+    pub backlog: Option<u32>,
+}
+
+#[napi(object)]
+pub struct ListenerStats {
+    /// Connections handed to JS.
+    pub accepted: i64,
+    /// Connections closed on arrival because the peer CID is not allowed.
+    pub rejected: i64,
+    /// Accepted streams not yet closed.
+    pub active: i64,
+    /// Times maxConnections was hit (connections closed, or accepts queued).
+    pub limited: i64,
+    /// Streams shut down by idleTimeoutMs.
+    pub idle_closed: i64,
+    /// accept() failures, other than from cancelling or closing the
+    /// listener.
+    pub accept_errors: i64,
+}
+
+/// Passed to the onEvent callback.
+#[napi(object)]
+pub struct ListenerEvent {
+    /// "accepted", "closed" (an accepted stream was closed) or "rejected"
+    /// (the peer CID is not allowed).
+    pub kind: String,
+    pub peer_cid: u32,
+    pub peer_port: u32,
+    /// Accepted streams open after the event.
+    pub active: u32,
+}
+
+/// Passed to the onConnectionLimit callback.
+#[napi(object)]
+pub struct ConnectionLimitEvent {
+    /// "queued" (accepting paused) or "closed" (connection dropped).
+    pub action: String,
+    /// Streams open when the limit was hit.
+    pub active: u32,
+    /// CID of the dropped connection ("closed" only).
+    pub peer_cid: Option<u32>,
+}
+
+/// Passed to the onIdleClose callback.
+#[napi(object)]
+pub struct IdleCloseEvent {
+    pub peer_cid: u32,
+    pub peer_port: u32,
+    /// Time since the stream's last traffic.
+    pub idle_ms: i64,
+}
+
+/// How long a queued accept sleeps between checks for a closed listener.
+const SLOT_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+type LimitCallback = Box<dyn Fn(ConnectionLimitEvent) + Send>;
+type IdleCallback = Box<dyn Fn(IdleCloseEvent) + Send>;
+type EventCallback = Box<dyn Fn(ListenerEvent) + Send>;
+
+/// Milliseconds on a process-wide monotonic clock.
+fn now_ms() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// An accepted stream, as seen by its listener.
+struct TrackedConn {
+    fd: i32,
+    peer_cid: u32,
+    peer_port: u32,
+    /// now_ms() of the last read or write.
+    last_active: AtomicU64,
+    /// Set once the stream has been shut down for idling.
+    idle_closed: AtomicBool,
+}
+
+impl TrackedConn {
+    fn touch(&self) {
+        self.last_active.store(now_ms(), Ordering::Relaxed);
+    }
+}
+
+/// Accept policy and counters, shared with in-flight AcceptTasks and with
+/// the streams they return.
+#[derive(Default)]
+struct AcceptState {
+    /// Peer CIDs allowed to connect; None allows any.
+    allowed_cids: Mutex<Option<Vec<u32>>>,
+    max_connections: Option<u32>,
+    close_when_full: bool,
+    idle_timeout: Option<Duration>,
+    /// Accepted streams not yet closed.
+    active: Mutex<u32>,
+    slot_freed: Condvar,
+    /// Accepted streams still open, for drain() and the idle reaper.
+    tracked: Mutex<HashMap<u64, Arc<TrackedConn>>>,
+    next_tracked_id: AtomicU64,
+    on_idle_close: Mutex<Option<IdleCallback>>,
+    idle_closed: AtomicI64,
+    closed: AtomicBool,
+    on_limit: Mutex<Option<LimitCallback>>,
+    on_event: Mutex<Option<EventCallback>>,
+    accepted: AtomicI64,
+    rejected: AtomicI64,
+    limited: AtomicI64,
+    accept_errors: AtomicI64,
+    /// Native accept loops and monitors to stop when the listener closes.
+    native_acceptors: Mutex<Vec<Arc<Canceller>>>,
+}
+
+impl AcceptState {
+    fn new(options: Option<ListenerOptions>) -> Result<Self> {
+        let (max_connections, when_full, idle_timeout_ms) = options
+            .map(|o| (o.max_connections, o.when_full, o.idle_timeout_ms))
+            .unwrap_or((None, None, None));
+        if max_connections == Some(0) {
+            return Err(Error::from_reason("maxConnections must be greater than 0"));
+        }
+        if idle_timeout_ms == Some(0) {
+            return Err(Error::from_reason("idleTimeoutMs must be greater than 0"));
+        }
+        let close_when_full = match when_full.as_deref() {
+            None | Some("queue") => false,
+            Some("close") => true,
+            Some(other) => {
                 return Err(Error::from_reason(format!(
-                    "socket(AF_VSOCK) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+                    "whenFull must be \"queue\" or \"close\", got {:?}",
+                    other
+                )))
             }
+        };
+        Ok(AcceptState {
+            max_connections,
+            close_when_full,
+            idle_timeout: idle_timeout_ms.map(|ms| Duration::from_millis(ms as u64)),
+            ..Default::default()
+        })
+    }
 
-            // Allow address reuse
-            let optval: i32 = 1;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_REUSEADDR,
-                &optval as *const _ as *const libc::c_void,
-                std::mem::size_of::<i32>() as u32,
-            );
+    /// Call `accept` until it yields a connection from an allowed CID that
+    /// fits under maxConnections. The returned connection holds a slot until
+    /// `release` is called.
+    fn accept_with(
+        &self,
+        cancel: Option<&Canceller>,
+        mut accept: impl FnMut() -> std::io::Result<(i32, u32, u32)>,
+    ) -> std::io::Result<(i32, u32, u32)> {
+        loop {
+            // In queue mode, wait for a slot before taking a connection off
+            // the backlog at all.
+            let queued = match self.max_connections {
+                Some(max) if !self.close_when_full => {
+                    self.wait_for_slot(max, cancel)?;
+                    true
+                }
+                _ => false,
+            };
+            let (conn, cid, port) = match self.accept_allowed(&mut accept) {
+                Ok(c) => c,
+                Err(e) => {
+                    if queued {
+                        self.release();
+                    }
+                    return Err(e);
+                }
+            };
+            if queued || self.try_reserve() {
+                self.accepted.fetch_add(1, Ordering::Relaxed);
+                self.emit("accepted", cid, port);
+                return Ok((conn, cid, port));
+            }
+            unsafe { libc::close(conn); }
+            self.limit_hit("closed", Some(cid));
+        }
+    }
 
-            let addr = SockaddrVm {
-                svm_family: AF_VSOCK as u16,
-                svm_reserved1: 0,
-                svm_port: port,
-                svm_cid: VMADDR_CID_ANY,
-                svm_zero: [0; 4],
+    /// accept() on `fd`; with a `cancel` token, wait for the listener to
+    /// become readable first so cancel() can interrupt the wait. (Another
+    /// accept can still win the race for that connection and leave this one
+    /// blocked in accept() until the next.)
+    fn accept(&self, fd: i32, cancel: Option<&Canceller>) -> std::io::Result<(i32, u32, u32)> {
+        self.accept_with(cancel, || {
+            if let Some(cancel) = cancel {
+                cancel.wait(fd, libc::POLLIN)?;
+            }
+            accept_raw(fd)
+        })
+    }
+
+    /// Connections from CIDs outside the allowlist are closed unread.
+    fn accept_allowed(
+        &self,
+        accept: &mut impl FnMut() -> std::io::Result<(i32, u32, u32)>,
+    ) -> std::io::Result<(i32, u32, u32)> {
+        loop {
+            let (conn, cid, port) = accept().inspect_err(|e| self.accept_failed(e))?;
+            let allowed = match &*self.allowed_cids.lock().unwrap() {
+                Some(cids) => cids.contains(&cid),
+                None => true,
             };
+            if allowed {
+                return Ok((conn, cid, port));
+            }
+            unsafe { libc::close(conn); }
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            self.emit("rejected", cid, port);
+        }
+    }
 
-            let ret = libc::bind(
-                fd,
-                &addr as *const _ as *const libc::sockaddr,
-                std::mem::size_of::<SockaddrVm>() as u32,
-            );
-            if ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "bind(AF_VSOCK, port={}) failed: {}",
-                    port,
-                    std::io::Error::last_os_error()
-                )));
+    fn accept_failed(&self, err: &std::io::Error) {
+        // Cancelled, or woken by close()
+        if err.kind() == std::io::ErrorKind::Interrupted || self.closed.load(Ordering::Acquire) {
+            return;
+        }
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Report a connection to the onEvent callback, if any.
+    fn emit(&self, kind: &str, peer_cid: u32, peer_port: u32) {
+        if let Some(on_event) = &*self.on_event.lock().unwrap() {
+            on_event(ListenerEvent {
+                kind: kind.to_string(),
+                peer_cid,
+                peer_port,
+                active: *self.active.lock().unwrap(),
+            });
+        }
+    }
+
+    fn wait_for_slot(&self, max: u32, cancel: Option<&Canceller>) -> std::io::Result<()> {
+        if *self.active.lock().unwrap() >= max {
+            self.limit_hit("queued", None);
+        }
+        let mut active = self.active.lock().unwrap();
+        while *active >= max {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(std::io::Error::other("Listener already closed"));
+            }
+            if cancel.is_some_and(Canceller::is_cancelled) {
+                return Err(cancelled_error());
             }
+            active = self.slot_freed.wait_timeout(active, SLOT_WAIT_INTERVAL).unwrap().0;
+        }
+        *active += 1;
+        Ok(())
+    }
 
-            let ret = libc::listen(fd, 128);
-            if ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "listen() failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+    fn try_reserve(&self) -> bool {
+        let mut active = self.active.lock().unwrap();
+        if self.max_connections.is_some_and(|max| *active >= max) {
+            return false;
+        }
+        *active += 1;
+        true
+    }
+
+    fn release(&self) {
+        *self.active.lock().unwrap() -= 1;
+        // Wakes queued accepts and drain()
+        self.slot_freed.notify_all();
+    }
+
+    fn limit_hit(&self, action: &str, peer_cid: Option<u32>) {
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(action, peer_cid, "connection limit reached");
+        if let Some(on_limit) = &*self.on_limit.lock().unwrap() {
+            on_limit(ConnectionLimitEvent {
+                action: action.to_string(),
+                active: *self.active.lock().unwrap(),
+                peer_cid,
+            });
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.slot_freed.notify_all();
+        for cancel in self.native_acceptors.lock().unwrap().drain(..) {
+            cancel.cancel();
+        }
+    }
+
+    /// Wait up to `grace` for every accepted stream to close, then shut down
+    /// the rest (their owners still close the fds). Returns how many were
+    /// shut down.
+    fn drain(&self, grace: Duration) -> u32 {
+        self.close();
+        let deadline = Instant::now() + grace;
+        let mut active = self.active.lock().unwrap();
+        while *active > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            active = self.slot_freed.wait_timeout(active, deadline - now).unwrap().0;
+        }
+        drop(active);
+
+        let tracked = self.tracked.lock().unwrap();
+        for conn in tracked.values() {
+            unsafe { libc::shutdown(conn.fd, libc::SHUT_RDWR); }
+        }
+        tracked.len() as u32
+    }
+
+    /// Shut down streams with no traffic for `timeout`, once each. As with
+    /// drain(), their owners still close the fds.
+    fn reap_idle(&self, timeout: Duration) {
+        let now = now_ms();
+        let timeout = timeout.as_millis() as u64;
+        let mut reaped = Vec::new();
+        for conn in self.tracked.lock().unwrap().values() {
+            let idle = now.saturating_sub(conn.last_active.load(Ordering::Relaxed));
+            if idle >= timeout && !conn.idle_closed.swap(true, Ordering::Relaxed) {
+                // Under the tracked lock: the fd can't be closed and reused
+                unsafe { libc::shutdown(conn.fd, libc::SHUT_RDWR); }
+                reaped.push((conn.peer_cid, conn.peer_port, idle));
+            }
+        }
+
+        self.idle_closed.fetch_add(reaped.len() as i64, Ordering::Relaxed);
+        if let Some(on_idle_close) = &*self.on_idle_close.lock().unwrap() {
+            for (peer_cid, peer_port, idle) in reaped {
+                on_idle_close(IdleCloseEvent { peer_cid, peer_port, idle_ms: idle as i64 });
             }
+        }
+    }
+}
 
-            Ok(VsockListener { fd: AtomicI32::new(fd) })
+/// Check for idle streams until the listener is closed and every stream it
+/// accepted is gone.
+fn spawn_idle_reaper(state: Arc<AcceptState>, timeout: Duration) {
+    let interval = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        state.reap_idle(timeout);
+        if state.closed.load(Ordering::Acquire) && state.tracked.lock().unwrap().is_empty() {
+            break;
         }
+    });
+}
+
+/// Held by an accepted VsockStream: its maxConnections slot and tracking
+/// entry, both given up on close.
+struct ConnectionSlot {
+    state: Arc<AcceptState>,
+    id: u64,
+    conn: Arc<TrackedConn>,
+}
+
+impl ConnectionSlot {
+    fn new(state: &Arc<AcceptState>, fd: i32, peer_cid: u32, peer_port: u32) -> Self {
+        let conn = Arc::new(TrackedConn {
+            fd,
+            peer_cid,
+            peer_port,
+            last_active: AtomicU64::new(now_ms()),
+            idle_closed: AtomicBool::new(false),
+        });
+        let id = state.next_tracked_id.fetch_add(1, Ordering::Relaxed);
+        state.tracked.lock().unwrap().insert(id, conn.clone());
+        ConnectionSlot { state: state.clone(), id, conn }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.state.tracked.lock().unwrap().remove(&self.id);
+        self.state.release();
+        self.state.emit("closed", self.conn.peer_cid, self.conn.peer_port);
+    }
+}
+
+/// Stops accepting, then waits for accepted streams to close.
+pub struct DrainTask {
+    state: Arc<AcceptState>,
+    grace: Duration,
+}
+
+impl Task for DrainTask {
+    type Output = u32;
+    type JsValue = u32;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        Ok(self.state.drain(self.grace))
+    }
+
+    fn resolve(&mut self, _env: Env, forced: Self::Output) -> Result<Self::JsValue> {
+        Ok(forced)
+    }
+}
+
+/// A vsock server that listens for incoming connections.
+#[napi]
+pub struct VsockListener {
+    fd: AtomicI32,
+    /// Bound port, resolved if bind() was given VMADDR_PORT_ANY.
+    port: u32,
+    backlog: u32,
+    /// Stops the onBacklogPressure() monitor.
+    backlog_monitor: Mutex<Option<Arc<Canceller>>>,
+    state: Arc<AcceptState>,
+}
+
+#[napi]
+impl VsockListener {
+    /// Create a new VsockListener bound to CID_ANY (or options.cid) on the
+    /// given port, or on a free one for VMADDR_PORT_ANY.
+    /// CID_ANY means the enclave accepts connections from any CID (typically the host).
+    #[napi(factory)]
+    pub fn bind(env: Env, port: u32, options: Option<ListenerOptions>) -> Result<Self> {
+        errors::structured(&env, Self::listen(port, options))
     }
 
     /// Accept a new connection. Blocks until a connection arrives.
     /// Returns a VsockStream for the accepted connection.
     #[napi]
-    pub fn accept(&self) -> Result<VsockStream> {
+    pub fn accept(&self, env: Env) -> Result<VsockStream> {
+        errors::structured(&env, self.accept_blocking())
+    }
+
+    /// Accept a new connection asynchronously.
+    /// Runs libc::accept on the blocking-call thread pool (libuv's unless
+    /// configureThreadPool() was called) so the Node.js event loop
+    /// stays free for concurrent handler I/O. Cancelling `cancel` rejects
+    /// the Promise and frees the worker thread.
+    #[napi(ts_return_type = "Promise<VsockStream>")]
+    pub fn accept_async(&self, cancel: Option<&CancelToken>) -> WorkerTask<AcceptTask> {
+        WorkerTask::new(AcceptTask {
+            fd: self.fd.load(Ordering::Acquire),
+            state: self.state.clone(),
+            cancel: cancel.map(CancelToken::shared),
+        })
+    }
+
+    /// Only accept connections from these peer CIDs (e.g. `[3]` for the
+    /// parent instance). Connections from any other CID are closed as soon
+    /// as they are accepted and never reach JS. Pass null to allow any CID
+    /// again. Applies to accepts already in progress.
+    #[napi]
+    pub fn set_allowed_cids(&self, cids: Option<Vec<u32>>) {
+        *self.state.allowed_cids.lock().unwrap() = cids;
+    }
+
+    /// Stop accepting (as close() does), then wait for streams accepted from
+    /// this listener to close. Streams still open after `gracePeriodMs` are
+    /// shut down, so their pending reads return EOF. Resolves with the
+    /// number of streams that had to be shut down.
+    #[napi(ts_return_type = "Promise<number>")]
+    pub fn drain(&self, grace_period_ms: u32) -> Result<WorkerTask<DrainTask>> {
+        self.close()?;
+        Ok(WorkerTask::new(DrainTask {
+            state: self.state.clone(),
+            grace: Duration::from_millis(grace_period_ms as u64),
+        }))
+    }
+
+    /// Call `callback` whenever maxConnections is hit.
+    #[napi]
+    pub fn on_connection_limit(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(event: ConnectionLimitEvent) => void")] mut callback: ThreadsafeFunction<
+            ConnectionLimitEvent,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        // Don't keep the process alive just to report limits
+        callback.unref(&env)?;
+        *self.state.on_limit.lock().unwrap() = Some(Box::new(move |event| {
+            callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Call `callback` whenever idleTimeoutMs shuts down a stream. The
+    /// stream's reads return EOF; it still needs closing to free its fd.
+    #[napi]
+    pub fn on_idle_close(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(event: IdleCloseEvent) => void")] mut callback: ThreadsafeFunction<
+            IdleCloseEvent,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        *self.state.on_idle_close.lock().unwrap() = Some(Box::new(move |event| {
+            callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Call `callback` for every connection accepted or rejected by this
+    /// listener, and when an accepted stream closes, e.g. to feed a
+    /// connectivity dashboard. Replaces any previous event callback.
+    #[napi]
+    pub fn on_event(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(event: ListenerEvent) => void")] mut callback: ThreadsafeFunction<
+            ListenerEvent,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        *self.state.on_event.lock().unwrap() = Some(Box::new(move |event| {
+            callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// The port this listener is bound to.
+    #[napi(getter)]
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Connections waiting in the listen backlog for accept(). See
+    /// backlog.rs for how they're counted.
+    #[napi]
+    pub fn backlog_stats(&self) -> Result<BacklogStats> {
         let fd = self.fd.load(Ordering::Acquire);
         if fd == CLOSED_FD {
             return Err(Error::from_reason("Listener already closed"));
         }
-        unsafe {
-            let mut addr: SockaddrVm = std::mem::zeroed();
-            let mut addr_len = std::mem::size_of::<SockaddrVm>() as u32;
+        Ok(backlog::backlog_stats(fd, self.port, self.backlog))
+    }
 
-            let client_fd = libc::accept(
-                fd,
-                &mut addr as *mut _ as *mut libc::sockaddr,
-                &mut addr_len,
-            );
-            if client_fd < 0 {
-                return Err(Error::from_reason(format!(
-                    "accept() failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
+    /// Check the backlog every `options.intervalMs` from a native thread
+    /// and call `callback` when it fills up to `options.threshold`, so the
+    /// server can add accept concurrency before the kernel starts refusing
+    /// connections. Fires once per episode. Replaces any previous
+    /// pressure callback; stops when the listener closes.
+    #[napi]
+    pub fn on_backlog_pressure(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(stats: BacklogStats) => void")] mut callback: ThreadsafeFunction<
+            BacklogStats,
+            ErrorStrategy::Fatal,
+        >,
+        options: Option<BacklogPressureOptions>,
+    ) -> Result<()> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Listener already closed"));
+        }
+        callback.unref(&env)?;
+        let cancel = Arc::new(Canceller::new().map_err(|e| errors::os_error("pipe()", e))?);
+        let monitor_fd = relay::dup_fd(fd)?;
+        let on_pressure = Box::new(move |stats| {
+            callback.call(stats, ThreadsafeFunctionCallMode::NonBlocking);
+        });
+        let started = backlog::spawn_monitor(
+            monitor_fd,
+            self.port,
+            self.backlog,
+            options,
+            cancel.clone(),
+            on_pressure,
+        );
+        if let Err(e) = started {
+            unsafe { libc::close(monitor_fd); }
+            return Err(e);
+        }
+        if let Some(previous) = self.backlog_monitor.lock().unwrap().replace(cancel.clone()) {
+            previous.cancel();
+        }
+        self.state.native_acceptors.lock().unwrap().push(cancel);
+        Ok(())
+    }
 
-            Ok(VsockStream {
-                fd: AtomicI32::new(client_fd),
-                peer_cid: addr.svm_cid,
-                peer_port: addr.svm_port,
-            })
+    /// Snapshot of accept counters.
+    #[napi]
+    pub fn stats(&self) -> ListenerStats {
+        ListenerStats {
+            accepted: self.state.accepted.load(Ordering::Relaxed),
+            rejected: self.state.rejected.load(Ordering::Relaxed),
+            active: *self.state.active.lock().unwrap() as i64,
+            limited: self.state.limited.load(Ordering::Relaxed),
+            idle_closed: self.state.idle_closed.load(Ordering::Relaxed),
+            accept_errors: self.state.accept_errors.load(Ordering::Relaxed),
         }
     }
 
-    /// Accept a new connection asynchronously.
-    /// Runs libc::accept on the libuv thread pool so the Node.js event loop
-    /// stays free for concurrent handler I/O.
-    #[napi(ts_return_type = "Promise<VsockStream>")]
-    pub fn accept_async(&self) -> AsyncTask<AcceptTask> {
-        AsyncTask::new(AcceptTask {
-            fd: self.fd.load(Ordering::Acquire),
-        })
+    /// Serve length-prefixed requests natively: accept connections on a
+    /// native thread (under this listener's allowlist and limits), read
+    /// each frame, call `handler` with it and write back the Buffer it
+    /// returns or resolves to. See FramedServer.
+    #[napi(
+        ts_args_type = "handler: (request: Buffer, peer: FramedPeer) => Buffer | Promise<Buffer>, options?: FramedServerOptions"
+    )]
+    pub fn serve_framed(
+        &self,
+        env: Env,
+        handler: JsFunction,
+        options: Option<FramedServerOptions>,
+    ) -> Result<FramedServer> {
+        FramedServer::start(&env, self.native_acceptor()?, handler, options)
     }
 
     /// Close the listener. Safe to call multiple times.
+    /// Accepts queued behind maxConnections fail; accepted streams stay open.
     #[napi]
     pub fn close(&self) -> Result<()> {
+        self.state.close();
         let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
         if fd != CLOSED_FD {
             unsafe { libc::close(fd); }
@@ -139,8 +774,71 @@ impl VsockListener {
     }
 }
 
+impl VsockListener {
+    /// Hand accepting over to a native thread; see NativeAcceptor.
+    pub(crate) fn native_acceptor(&self) -> Result<NativeAcceptor> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Listener already closed"));
+        }
+        let cancel = Arc::new(Canceller::new().map_err(|e| errors::os_error("pipe()", e))?);
+        let acceptor = NativeAcceptor {
+            fd: relay::dup_fd(fd)?,
+            state: self.state.clone(),
+            cancel: cancel.clone(),
+        };
+        self.state.native_acceptors.lock().unwrap().push(cancel);
+        Ok(acceptor)
+    }
+
+    fn listen(port: u32, options: Option<ListenerOptions>) -> Result<Self> {
+        let cid = options.as_ref().and_then(|o| o.cid).unwrap_or(VMADDR_CID_ANY);
+        let backlog = options.as_ref().and_then(|o| o.backlog).unwrap_or(backlog::DEFAULT_BACKLOG);
+        if backlog == 0 {
+            return Err(Error::from_reason("backlog must be greater than 0"));
+        }
+        let state = Arc::new(AcceptState::new(options)?);
+        let fd = listen_at(cid, port, backlog)?;
+        let port = match port {
+            VMADDR_PORT_ANY => match local_port(fd) {
+                Ok(port) => port,
+                Err(e) => {
+                    unsafe { libc::close(fd); }
+                    return Err(errors::os_error("getsockname()", e));
+                }
+            },
+            port => port,
+        };
+        tracing::info!(port, "listening");
+        if let Some(timeout) = state.idle_timeout {
+            spawn_idle_reaper(state.clone(), timeout);
+        }
+        Ok(VsockListener {
+            fd: AtomicI32::new(fd),
+            port,
+            backlog,
+            backlog_monitor: Mutex::new(None),
+            state,
+        })
+    }
+
+    fn accept_blocking(&self) -> Result<VsockStream> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Listener already closed"));
+        }
+        let (client_fd, peer_cid, peer_port) = self
+            .state
+            .accept(fd, None)
+            .map_err(|e| errors::os_error("accept()", e))?;
+        Ok(VsockStream::accepted(client_fd, peer_cid, peer_port, &self.state))
+    }
+}
+
 struct AcceptTask {
     fd: i32,
+    state: Arc<AcceptState>,
+    cancel: Option<Arc<Canceller>>,
 }
 
 impl Task for AcceptTask {
@@ -151,22 +849,11 @@ impl Task for AcceptTask {
         if self.fd == CLOSED_FD {
             return Err(Error::from_reason("Listener already closed"));
         }
+        let (client_fd, cid, port) = self
+            .state
+            .accept(self.fd, self.cancel.as_deref())
+            .map_err(|e| errors::os_error("accept()", e))?;
         unsafe {
-            let mut addr: SockaddrVm = std::mem::zeroed();
-            let mut addr_len = std::mem::size_of::<SockaddrVm>() as u32;
-
-            let client_fd = libc::accept(
-                self.fd,
-                &mut addr as *mut _ as *mut libc::sockaddr,
-                &mut addr_len,
-            );
-            if client_fd < 0 {
-                return Err(Error::from_reason(format!(
-                    "accept() failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
-
             // Set SO_RCVTIMEO on accepted connections so libc::read in
             // readMessage returns EAGAIN instead of blocking indefinitely
             // if the client connects but never sends data. Without this,
@@ -181,26 +868,90 @@ impl Task for AcceptTask {
                 std::mem::size_of::<libc::timeval>() as u32,
             );
 
-            Ok((client_fd, addr.svm_cid, addr.svm_port))
+            Ok((client_fd, cid, port))
         }
     }
 
     fn resolve(&mut self, _env: Env, (fd, cid, port): Self::Output) -> Result<Self::JsValue> {
-        Ok(VsockStream {
-            fd: AtomicI32::new(fd),
-            peer_cid: cid,
-            peer_port: port,
-        })
+        Ok(VsockStream::accepted(fd, cid, port, &self.state))
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
     }
 }
 
-/// A connected vsock stream (either from accept() or connect()).
-/// Supports binary read/write for use as a Node.js Duplex transport.
-#[napi]
-pub struct VsockStream {
+/// A listener's accept side for a native serving thread (serveFramed()):
+/// a dup of its fd, accepting under the listener's CID allowlist and
+/// connection limits, until cancelled or the listener closes.
+pub(crate) struct NativeAcceptor {
+    fd: i32,
+    state: Arc<AcceptState>,
+    cancel: Arc<Canceller>,
+}
+
+impl NativeAcceptor {
+    /// Block for the next connection, as an accepted VsockStream holding
+    /// its listener slot.
+    pub(crate) fn accept(&self) -> Result<VsockStream> {
+        let (fd, peer_cid, peer_port) = self
+            .state
+            .accept(self.fd, Some(&self.cancel))
+            .map_err(|e| errors::os_error("accept()", e))?;
+        Ok(VsockStream::accepted(fd, peer_cid, peer_port, &self.state))
+    }
+
+    /// Whether accept() has been cancelled or the listener closed.
+    pub(crate) fn stopped(&self) -> bool {
+        self.cancel.is_cancelled() || self.state.closed.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn canceller(&self) -> Arc<Canceller> {
+        self.cancel.clone()
+    }
+}
+
+impl Drop for NativeAcceptor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// Returned by VsockStream.pair().
+#[napi(object, object_from_js = false)]
+pub struct VsockPair {
+    pub a: VsockStream,
+    pub b: VsockStream,
+}
+
+/// A connected vsock stream (either from accept() or connect()).
+/// Supports binary read/write for use as a Node.js Duplex transport.
+#[napi]
+pub struct VsockStream {
     fd: AtomicI32,
     peer_cid: u32,
     peer_port: u32,
+    /// Set by setReadPool(): read() then lends pooled chunks to JS.
+    read_pool: Mutex<Option<Arc<BufferPool>>>,
+    /// Listener slot of an accepted stream, freed on close.
+    slot: Mutex<Option<ConnectionSlot>>,
+    /// Traffic timestamp of an accepted stream, for idleTimeoutMs.
+    activity: Option<Arc<TrackedConn>>,
+    /// Set by enableTrace().
+    trace: Mutex<Option<TraceSink>>,
+    /// Set by setRateLimit().
+    read_limit: Mutex<Option<Arc<TokenBucket>>>,
+    /// Shared with the write queue's thread.
+    write_limit: Arc<Mutex<Option<Arc<TokenBucket>>>>,
+    /// Started by the first queueWrite().
+    write_queue: Mutex<Option<Arc<WriteQueue>>>,
+    /// Bytes readUntil() read past its delimiter, returned before anything
+    /// else by the next read.
+    read_ahead: Mutex<Vec<u8>>,
+    /// Set by enableWatchdog().
+    watchdog: Mutex<Option<Watchdog>>,
+    /// Set by onClose(), shared with the watchdog's thread.
+    on_close: Arc<Mutex<Option<CloseCallback>>>,
 }
 
 #[napi]
@@ -208,106 +959,362 @@ impl VsockStream {
     /// Connect to a vsock endpoint at the given CID and port.
     /// CID 3 = host (parent) from inside the enclave.
     #[napi(factory)]
-    pub fn connect(cid: u32, port: u32) -> Result<Self> {
-        unsafe {
-            let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
-            if fd < 0 {
-                return Err(Error::from_reason(format!(
-                    "socket(AF_VSOCK) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
-
-            let addr = SockaddrVm {
-                svm_family: AF_VSOCK as u16,
-                svm_reserved1: 0,
-                svm_port: port,
-                svm_cid: cid,
-                svm_zero: [0; 4],
-            };
-
-            let ret = libc::connect(
-                fd,
-                &addr as *const _ as *const libc::sockaddr,
-                std::mem::size_of::<SockaddrVm>() as u32,
-            );
-            if ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "connect(cid={}, port={}) failed: {}",
-                    cid,
-                    port,
-                    std::io::Error::last_os_error()
-                )));
-            }
+    pub fn connect(env: Env, cid: u32, port: u32) -> Result<Self> {
+        errors::structured(&env, Self::connect_blocking(cid, port))
+    }
 
-            Ok(VsockStream {
-                fd: AtomicI32::new(fd),
-                peer_cid: cid,
-                peer_port: port,
-            })
-        }
+    /// Two streams connected to each other, for testing framing, RPC or
+    /// secure channels in one process without binding a real port. Uses
+    /// vsock loopback (CID 1) where the kernel has it, and otherwise (or
+    /// with TYTLE_VSOCK_MOCK_DIR set) a unix socketpair, whose streams
+    /// report peer CID 1 and port 0.
+    #[napi]
+    pub fn pair(env: Env) -> Result<VsockPair> {
+        let [a, b] = errors::structured(&env, connected_pair())?;
+        Ok(VsockPair {
+            a: Self::from_raw(a.0, a.1, a.2),
+            b: Self::from_raw(b.0, b.1, b.2),
+        })
     }
 
     /// Read up to `size` bytes from the stream.
     /// Returns a Buffer with the bytes read (may be fewer than `size`).
+    /// The Buffer is external memory owned by the addon, not a copy; with a
+    /// read pool attached it is a view of a pooled chunk and reads are capped
+    /// at the pool's chunkSize.
     /// Note: this is a blocking call (libc::read).
+    #[napi(ts_return_type = "Buffer")]
+    pub fn read(&self, env: Env, size: u32) -> Result<JsBuffer> {
+        errors::structured(&env, self.read_buffer(&env, size))
+    }
+
+    /// Read the next record ending in `delimiter` (e.g. `"\n"` for
+    /// newline-delimited JSON) and return it without the delimiter. Bytes
+    /// read past it are kept for the next readUntil() or read(). At end of
+    /// stream returns what's left as a final record, then null. Throws
+    /// EMSGSIZE, keeping the bytes for read(), if no delimiter turns up
+    /// within `maxBytes` (default 1 MiB).
+    /// Note: this is a blocking call.
+    #[napi(ts_return_type = "Buffer | null")]
+    pub fn read_until(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "Buffer | string")] delimiter: Either<Buffer, String>,
+        max_bytes: Option<u32>,
+    ) -> Result<Option<JsBuffer>> {
+        let delimiter = match &delimiter {
+            Either::A(buffer) => buffer.as_ref(),
+            Either::B(text) => text.as_bytes(),
+        };
+        let max = max_bytes.unwrap_or(DEFAULT_MAX_RECORD) as usize;
+        match errors::structured(&env, self.read_record(delimiter, max))? {
+            Some(record) => external_buffer(&env, record).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Switch read() to pooled buffers from `pool`, or back to a fresh
+    /// allocation per read with `null`.
+    #[napi]
+    pub fn set_read_pool(&self, pool: Option<&ReadBufferPool>) {
+        *self.read_pool.lock().unwrap() = pool.map(ReadBufferPool::shared);
+    }
+
+    /// Configure SO_LINGER for close().
+    ///
+    /// - `enabled` with `seconds > 0`: close() blocks (up to `seconds`) until
+    ///   queued data has been sent, so a final response survives the process
+    ///   exiting right after.
+    /// - `enabled` with `seconds == 0`: close() discards unsent data and
+    ///   resets the connection immediately.
+    /// - disabled (the default): close() returns at once and the kernel
+    ///   flushes in the background.
     #[napi]
-    pub fn read(&self, size: u32) -> Result<Buffer> {
+    pub fn set_linger(&self, env: Env, enabled: bool, seconds: u32) -> Result<()> {
         let fd = self.fd.load(Ordering::Acquire);
         if fd == CLOSED_FD {
-            return Ok(Buffer::from(Vec::<u8>::new()));
-        }
-        let mut buf = vec![0u8; size as usize];
-        unsafe {
-            let n = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
-            if n < 0 {
-                return Err(Error::from_reason(format!(
-                    "read() failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
-            if n == 0 {
-                return Ok(Buffer::from(Vec::<u8>::new()));
-            }
-            buf.truncate(n as usize);
-            Ok(Buffer::from(buf))
+            return Err(Error::from_reason("Stream already closed"));
         }
+        let result = set_linger_fd(fd, enabled, seconds)
+            .map_err(|e| errors::os_error("setsockopt(SO_LINGER)", e));
+        errors::structured(&env, result)
     }
 
     /// Write bytes to the stream. Returns number of bytes written.
+    /// `data` is read in place (including external Buffers such as those
+    /// returned by read()); it is never copied.
+    #[napi]
+    pub fn write(&self, env: Env, data: Buffer) -> Result<u32> {
+        errors::structured(&env, self.write_buffer(&data))
+    }
+
+    /// Copy `data` into the stream's write queue and return at once; a
+    /// native writer thread sends it in order. Returns false once
+    /// highWaterMark bytes are queued: wait for onDrain() before queueing
+    /// more. Throws ENOBUFS past maxQueuedBytes, and the error of a failed
+    /// send once one has failed. See configureWriteQueue().
+    #[napi]
+    pub fn queue_write(&self, env: Env, data: Buffer) -> Result<bool> {
+        errors::structured(&env, self.queue().and_then(|q| q.push(data.to_vec())))
+    }
+
+    /// Set the write queue's highWaterMark and maxQueuedBytes.
+    #[napi]
+    pub fn configure_write_queue(&self, options: WriteQueueOptions) -> Result<()> {
+        self.queue()?.configure(options)
+    }
+
+    /// Call `callback` whenever the write queue empties after queueWrite()
+    /// returned false. Replaces any previous drain callback.
+    #[napi]
+    pub fn on_drain(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "() => void")] mut callback: ThreadsafeFunction<
+            (),
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        self.queue()?.on_drain(Box::new(move || {
+            callback.call((), ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Resolve once everything queued by queueWrite() has been sent.
+    /// Rejects with the error of a failed send, or if the stream closes
+    /// first.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn flush_writes(&self) -> WorkerTask<FlushTask> {
+        WorkerTask::new(FlushTask {
+            queue: self.write_queue.lock().unwrap().clone(),
+        })
+    }
+
+    /// The write queue's backlog and progress.
     #[napi]
-    pub fn write(&self, data: Buffer) -> Result<u32> {
+    pub fn write_queue_stats(&self) -> WriteQueueStats {
+        match &*self.write_queue.lock().unwrap() {
+            Some(queue) => queue.stats(),
+            None => WriteQueueStats {
+                queued_bytes: 0,
+                queued_writes: 0,
+                written_bytes: 0.0,
+                high_water_mark: 0,
+                error: None,
+            },
+        }
+    }
+
+    /// Limit reads and/or writes (options.direction, default both) to
+    /// `bytesPerSecond` each, or remove the limit with `null`. read(),
+    /// readVectored(), write(), queueWrite() and sendFile() then wait for
+    /// budget and move at most a burst per call. Traffic moved by pipe() or
+    /// IoUringDriver isn't limited.
+    #[napi]
+    pub fn set_rate_limit(
+        &self,
+        bytes_per_second: Option<u32>,
+        options: Option<RateLimitOptions>,
+    ) -> Result<()> {
+        let limit = RateLimit::from_js(bytes_per_second, options)?;
+        if limit.read {
+            *self.read_limit.lock().unwrap() = limit.bucket();
+        }
+        if limit.write {
+            *self.write_limit.lock().unwrap() = limit.bucket();
+        }
+        Ok(())
+    }
+
+    /// Read into `buffers` in place with a single readv(2), filling each
+    /// in turn before the next, e.g. a fixed-size header and a payload
+    /// Buffer preallocated from it. Returns the total bytes read, which may
+    /// stop anywhere, even mid-buffer, and is 0 at end of stream.
+    /// Note: this is a blocking call.
+    #[napi]
+    pub fn read_vectored(&self, env: Env, mut buffers: Vec<Buffer>) -> Result<u32> {
+        errors::structured(&env, self.read_into(&mut buffers))
+    }
+
+    /// Send `length` bytes from file descriptor `fd`, starting at `offset`,
+    /// without copying the contents through JS Buffers. Uses sendfile(2) for
+    /// regular files and falls back to splice(2) when `fd` is a pipe.
+    /// Returns the number of bytes sent (fewer than `length` only at EOF).
+    /// Note: this is a blocking call — use sendFileAsync for bulk transfers.
+    #[napi]
+    pub fn send_file(&self, env: Env, fd: i32, offset: i64, length: i64) -> Result<i64> {
+        let out_fd = self.fd.load(Ordering::Acquire);
+        if out_fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        self.touch();
+        let limit = self.write_limit.lock().unwrap().clone();
+        let result = send_file_fd(out_fd, fd, offset, length, &limit)
+            .inspect(|&sent| metrics::bytes_written(sent as usize));
+        errors::structured(&env, result)
+    }
+
+    /// Send a file region asynchronously.
+    /// Runs the sendfile/splice loop on the blocking-call thread pool so large
+    /// exports don't block the event loop.
+    #[napi(ts_return_type = "Promise<number>")]
+    pub fn send_file_async(&self, fd: i32, offset: i64, length: i64) -> WorkerTask<SendFileTask> {
+        self.touch();
+        WorkerTask::new(SendFileTask {
+            out_fd: self.fd.load(Ordering::Acquire),
+            in_fd: fd,
+            offset,
+            length,
+            limit: self.write_limit.lock().unwrap().clone(),
+        })
+    }
+
+    /// Bytes queued for read() right now (FIONREAD), without consuming
+    /// them.
+    #[napi]
+    pub fn bytes_available(&self, env: Env) -> Result<u32> {
         let fd = self.fd.load(Ordering::Acquire);
         if fd == CLOSED_FD {
             return Err(Error::from_reason("Stream already closed"));
         }
-        unsafe {
-            let n = libc::write(
-                fd,
-                data.as_ptr() as *const libc::c_void,
-                data.len(),
-            );
-            if n < 0 {
-                return Err(Error::from_reason(format!(
-                    "write() failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
-            Ok(n as u32)
-        }
+        let result = bytes_available_fd(fd).map_err(|e| errors::os_error("ioctl(FIONREAD)", e));
+        errors::structured(&env, result)
     }
 
-    /// Close the stream. Safe to call multiple times.
+    /// Whether read() would return without blocking: data is queued, or the
+    /// peer has closed (read() then returns an empty Buffer). Waits up to
+    /// `timeoutMs` (default 0, i.e. just checks) for that to become true.
+    #[napi]
+    pub fn is_readable(&self, env: Env, timeout_ms: Option<u32>) -> Result<bool> {
+        self.poll_ready(env, libc::POLLIN, timeout_ms)
+    }
+
+    /// Whether write() would return without blocking: the send buffer has
+    /// room, or the connection has failed (write() then throws). Waits up
+    /// to `timeoutMs` (default 0, i.e. just checks) for that to become true.
+    #[napi]
+    pub fn is_writable(&self, env: Env, timeout_ms: Option<u32>) -> Result<bool> {
+        self.poll_ready(env, libc::POLLOUT, timeout_ms)
+    }
+
+    /// Close the stream, discarding anything still in the write queue.
+    /// Safe to call multiple times.
     #[napi]
     pub fn close(&self) -> Result<()> {
+        // Untrack before closing, so drain() never shuts down a reused fd
+        self.slot.lock().unwrap().take();
+        // Likewise, the writer and watchdog threads must be done with the fd
+        if let Some(queue) = self.write_queue.lock().unwrap().take() {
+            queue.stop();
+        }
+        self.watchdog.lock().unwrap().take();
         let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
         if fd != CLOSED_FD {
             unsafe { libc::close(fd); }
+            metrics::stream_closed();
+            tracing::debug!(
+                fd,
+                peer_cid = self.peer_cid,
+                peer_port = self.peer_port,
+                "stream closed"
+            );
         }
         Ok(())
     }
 
+    /// Hand the descriptor over to the caller, e.g. for
+    /// `new net.Socket({ fd })`: it's switched to non-blocking mode and
+    /// returned, and this stream becomes closed without closing it. The
+    /// caller now owns the fd, and an accepted stream no longer counts
+    /// against its listener's maxConnections. Throws if queueWrite() or
+    /// readUntil() still hold bytes for it.
+    #[napi(js_name = "intoFd")]
+    pub fn detach_fd(&self, env: Env) -> Result<i32> {
+        errors::structured(&env, self.release_fd())
+    }
+
+    /// A second stream on a dup() of this one's descriptor, so one thread
+    /// or async task can read while another writes. Each stream has its own
+    /// close(), and the connection stays open until both are closed. The
+    /// clone starts without trace, rate limit, write queue or watchdog, and
+    /// bytes readUntil() has buffered stay with this stream.
+    #[napi]
+    pub fn try_clone(&self, env: Env) -> Result<VsockStream> {
+        errors::structured(&env, self.duplicate())
+    }
+
+    /// Report every read() and write() on this stream to `callback`, with
+    /// the bytes and a hexdump, for debugging protocol mismatches (tcpdump
+    /// can't see vsock). Replaces any previous trace callback. Traffic moved
+    /// natively (sendFile(), queueWrite(), pipe(), IoUringDriver) isn't traced.
+    #[napi]
+    pub fn enable_trace(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(event: TraceEvent) => void")] mut callback: ThreadsafeFunction<
+            TraceEvent,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        *self.trace.lock().unwrap() = Some(Box::new(move |record: TraceRecord| {
+            callback.call(record.into_event(), ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Watch for the peer going away (closing, shutting down or resetting
+    /// the connection) on a native thread, checking at least every
+    /// `timeoutMs`, and call the onClose() callback once when it does.
+    /// Replaces any previous watchdog.
+    #[napi]
+    pub fn enable_watchdog(&self, env: Env, timeout_ms: u32) -> Result<()> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let mut watchdog = self.watchdog.lock().unwrap();
+        watchdog.take();
+        let interval = Duration::from_millis(timeout_ms as u64);
+        *watchdog = Some(errors::structured(
+            &env,
+            Watchdog::start(fd, interval, self.on_close.clone()),
+        )?);
+        Ok(())
+    }
+
+    /// Stop the watchdog. Safe to call when it isn't running.
+    #[napi]
+    pub fn disable_watchdog(&self) {
+        self.watchdog.lock().unwrap().take();
+    }
+
+    /// Call `callback` when the watchdog (see enableWatchdog()) sees the
+    /// peer go away. Replaces any previous close callback.
+    #[napi]
+    pub fn on_close(
+        &self,
+        env: Env,
+        #[napi(ts_arg_type = "(event: PeerCloseEvent) => void")] mut callback: ThreadsafeFunction<
+            PeerCloseEvent,
+            ErrorStrategy::Fatal,
+        >,
+    ) -> Result<()> {
+        callback.unref(&env)?;
+        *self.on_close.lock().unwrap() = Some(Box::new(move |event| {
+            callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+        }));
+        Ok(())
+    }
+
+    /// Stop tracing. Safe to call when tracing is off.
+    #[napi]
+    pub fn disable_trace(&self) {
+        self.trace.lock().unwrap().take();
+    }
+
     /// Get the file descriptor (for polling or advanced use).
     #[napi(getter)]
     pub fn fd(&self) -> i32 {
@@ -327,14 +1334,229 @@ impl VsockStream {
     }
 }
 
+pub struct SendFileTask {
+    out_fd: i32,
+    in_fd: i32,
+    offset: i64,
+    length: i64,
+    limit: Option<Arc<TokenBucket>>,
+}
+
+impl Task for SendFileTask {
+    type Output = i64;
+    type JsValue = i64;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        if self.out_fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        send_file_fd(self.out_fd, self.in_fd, self.offset, self.length, &self.limit)
+            .inspect(|&sent| metrics::bytes_written(sent as usize))
+    }
+
+    fn resolve(&mut self, _env: Env, sent: Self::Output) -> Result<Self::JsValue> {
+        Ok(sent)
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+/// Copy `length` bytes from `in_fd` (starting at `offset`) to `out_fd` in the kernel.
+///
+/// sendfile(2) requires an mmap-able input, so pipes are routed through
+/// splice(2) instead. Pipes have no file position, so `offset` must be 0.
+/// Loops until `length` bytes are sent or the input hits EOF, pacing each
+/// chunk by `limit`.
+#[cfg(not(target_os = "linux"))]
+fn send_file_fd(
+    _out_fd: i32,
+    _in_fd: i32,
+    _offset: i64,
+    _length: i64,
+    _limit: &Option<Arc<TokenBucket>>,
+) -> Result<i64> {
+    Err(platform::unsupported("sendFile (sendfile/splice)"))
+}
+
+#[cfg(target_os = "linux")]
+fn send_file_fd(
+    out_fd: i32,
+    in_fd: i32,
+    offset: i64,
+    length: i64,
+    limit: &Option<Arc<TokenBucket>>,
+) -> Result<i64> {
+    if offset < 0 || length < 0 {
+        return Err(Error::from_reason(format!(
+            "sendFile: offset and length must be non-negative (offset={}, length={})",
+            offset, length
+        )));
+    }
+    unsafe {
+        let mut st: libc::stat = std::mem::zeroed();
+        if libc::fstat(in_fd, &mut st) < 0 {
+            return Err(errors::os_error(
+                format!("fstat(fd={})", in_fd),
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let is_pipe = (st.st_mode & libc::S_IFMT) == libc::S_IFIFO;
+        if is_pipe && offset != 0 {
+            return Err(Error::from_reason(format!(
+                "sendFile: fd={} is a pipe and cannot be read from offset {}",
+                in_fd, offset
+            )));
+        }
+
+        let mut off = offset as libc::off_t;
+        let mut sent: i64 = 0;
+        while sent < length {
+            let chunk = throttle::grant(limit, (length - sent).min(i32::MAX as i64) as usize);
+            let n = if is_pipe {
+                libc::splice(
+                    in_fd,
+                    std::ptr::null_mut(),
+                    out_fd,
+                    std::ptr::null_mut(),
+                    chunk,
+                    libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+                )
+            } else {
+                libc::sendfile(out_fd, in_fd, &mut off, chunk)
+            };
+            throttle::settle(limit, chunk, n.max(0) as usize);
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(errors::os_error(
+                    format!("{}() after {} bytes", if is_pipe { "splice" } else { "sendfile" }, sent),
+                    err,
+                ));
+            }
+            if n == 0 {
+                break;
+            }
+            sent += n as i64;
+        }
+        Ok(sent)
+    }
+}
+
+/// IOCTL_VM_SOCKETS_GET_LOCAL_CID from linux/vm_sockets.h.
+const IOCTL_VM_SOCKETS_GET_LOCAL_CID: u32 = 0x7b9;
+
+/// This VM's vsock CID, read from /dev/vsock; None where vsock is unavailable.
+pub(crate) fn local_cid() -> Option<u32> {
+    platform::require_linux("AF_VSOCK").ok()?;
+    let path = std::ffi::CString::new("/dev/vsock").unwrap();
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return None;
+        }
+        let mut cid: u32 = 0;
+        let ret = libc::ioctl(fd, IOCTL_VM_SOCKETS_GET_LOCAL_CID as _, &mut cid as *mut u32);
+        libc::close(fd);
+        (ret == 0).then_some(cid)
+    }
+}
+
+/// Two connected sockets as (fd, peer_cid, peer_port) each: over vsock
+/// loopback if available, else a unix socketpair.
+fn connected_pair() -> Result<[(i32, u32, u32); 2]> {
+    if mock::backend().is_none() {
+        match loopback_pair() {
+            Ok(pair) => return Ok(pair),
+            Err(e) => tracing::debug!(error = %e, "no vsock loopback, using socketpair()"),
+        }
+    }
+    let (a, b) = std::os::unix::net::UnixStream::pair()
+        .map_err(|e| errors::os_error("socketpair()", e))?;
+    Ok([
+        (a.into_raw_fd(), VMADDR_CID_LOCAL, 0),
+        (b.into_raw_fd(), VMADDR_CID_LOCAL, 0),
+    ])
+}
+
+/// The port a vsock socket is bound to.
+fn local_port(fd: i32) -> std::io::Result<u32> {
+    let mut addr: SockaddrVm = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<SockaddrVm>() as u32;
+    let addr_ptr = &mut addr as *mut SockaddrVm as *mut libc::sockaddr;
+    if unsafe { libc::getsockname(fd, addr_ptr, &mut len) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(addr.svm_port)
+}
+
+/// Connect to a listener on an ephemeral loopback port.
+fn loopback_pair() -> std::io::Result<[(i32, u32, u32); 2]> {
+    let last_error = std::io::Error::last_os_error;
+    unsafe {
+        let listener = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
+        if listener < 0 {
+            return Err(last_error());
+        }
+        let listener = OwnedFd::from_raw_fd(listener);
+        let addr = |port| SockaddrVm {
+            svm_family: AF_VSOCK as u16,
+            svm_reserved1: 0,
+            svm_port: port,
+            svm_cid: VMADDR_CID_LOCAL,
+            svm_zero: [0; 4],
+        };
+        let len = std::mem::size_of::<SockaddrVm>() as u32;
+        let any = addr(VMADDR_PORT_ANY);
+        if libc::bind(listener.as_raw_fd(), &any as *const _ as *const libc::sockaddr, len) < 0
+            || libc::listen(listener.as_raw_fd(), 1) < 0
+        {
+            return Err(last_error());
+        }
+        let port = local_port(listener.as_raw_fd())?;
+
+        let client = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
+        if client < 0 {
+            return Err(last_error());
+        }
+        let client = OwnedFd::from_raw_fd(client);
+        let target = addr(port);
+        if libc::connect(client.as_raw_fd(), &target as *const _ as *const libc::sockaddr, len) < 0 {
+            return Err(last_error());
+        }
+        let (server, cid, client_port) = accept_raw(listener.as_raw_fd())?;
+        Ok([
+            (client.into_raw_fd(), VMADDR_CID_LOCAL, port),
+            (server, cid, client_port),
+        ])
+    }
+}
+
+/// Which socket backend this process uses: "vsock", or "mock" when
+/// TYTLE_VSOCK_MOCK_DIR selects the unix-socket loopback backend.
+#[napi]
+pub fn vsock_backend() -> String {
+    if mock::backend().is_some() { "mock" } else { "vsock" }.to_string()
+}
+
 /// Connect to a vsock endpoint asynchronously with a kernel-level timeout.
-/// Runs socket + connect on the libuv thread pool.
+/// Runs socket + connect on the blocking-call thread pool. Cancelling `cancel`
+/// abandons the connection attempt and rejects the Promise.
 #[napi(ts_return_type = "Promise<VsockStream>")]
-pub fn vsock_connect_async(cid: u32, port: u32, timeout_secs: Option<u32>) -> AsyncTask<ConnectTask> {
-    AsyncTask::new(ConnectTask {
+pub fn vsock_connect_async(
+    cid: u32,
+    port: u32,
+    timeout_secs: Option<u32>,
+    cancel: Option<&CancelToken>,
+) -> WorkerTask<ConnectTask> {
+    WorkerTask::new(ConnectTask {
         cid,
         port,
         timeout_secs: timeout_secs.unwrap_or(5),
+        cancel: cancel.map(CancelToken::shared),
     })
 }
 
@@ -342,6 +1564,7 @@ struct ConnectTask {
     cid: u32,
     port: u32,
     timeout_secs: u32,
+    cancel: Option<Arc<Canceller>>,
 }
 
 impl Task for ConnectTask {
@@ -349,21 +1572,495 @@ impl Task for ConnectTask {
     type JsValue = VsockStream;
 
     fn compute(&mut self) -> Result<Self::Output> {
+        let timeout = Duration::from_secs(self.timeout_secs as u64);
+        let fd = connect_cancellable(self.cid, self.port, timeout, self.cancel.as_deref())?;
+        Ok((fd, self.cid, self.port))
+    }
+
+    fn resolve(&mut self, _env: Env, (fd, cid, port): Self::Output) -> Result<Self::JsValue> {
+        Ok(VsockStream::from_raw(fd, cid, port))
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+#[napi(object)]
+pub struct ConnectWhenReadyOptions {
+    /// Give up this long after the first attempt (default 30 s).
+    pub deadline_ms: Option<u32>,
+    /// Wait between attempts (default 250 ms).
+    pub poll_interval_ms: Option<u32>,
+}
+
+/// Passed to connectWhenReady()'s onRetry callback after each failed
+/// attempt.
+#[napi(object)]
+pub struct ConnectRetryEvent {
+    /// Attempts so far, starting at 1.
+    pub attempt: u32,
+    /// Time since the first attempt.
+    pub elapsed_ms: i64,
+    /// Why this attempt failed, e.g. "ECONNREFUSED: connect(...) failed: ...".
+    pub error: String,
+}
+
+const DEFAULT_READY_DEADLINE_MS: u32 = 30_000;
+const DEFAULT_READY_POLL_INTERVAL_MS: u32 = 250;
+/// Longest a single connectWhenReady() attempt may take.
+const READY_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connect to (cid, port), retrying while nothing is listening there yet,
+/// e.g. while the host proxy an enclave depends on is still starting.
+/// ECONNREFUSED, ECONNRESET (what vsock reports for a port with no
+/// listener), EHOSTUNREACH, ENETUNREACH and ETIMEDOUT are retried every
+/// `pollIntervalMs` until `deadlineMs`, when the Promise rejects with
+/// ETIMEDOUT; other errors reject at once. `onRetry` is called after each
+/// failed attempt. Cancelling `cancel` stops retrying.
+#[napi(ts_return_type = "Promise<VsockStream>")]
+pub fn connect_when_ready(
+    env: Env,
+    cid: u32,
+    port: u32,
+    options: Option<ConnectWhenReadyOptions>,
+    #[napi(ts_arg_type = "(event: ConnectRetryEvent) => void")] on_retry: Option<
+        ThreadsafeFunction<ConnectRetryEvent, ErrorStrategy::Fatal>,
+    >,
+    cancel: Option<&CancelToken>,
+) -> Result<WorkerTask<ConnectWhenReadyTask>> {
+    let (deadline_ms, interval_ms) = options
+        .map(|o| (o.deadline_ms, o.poll_interval_ms))
+        .unwrap_or((None, None));
+    let on_retry: Option<RetryCallback> = match on_retry {
+        Some(mut callback) => {
+            callback.unref(&env)?;
+            Some(Box::new(move |event| {
+                callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+            }))
+        }
+        None => None,
+    };
+    Ok(WorkerTask::new(ConnectWhenReadyTask {
+        cid,
+        port,
+        deadline: Duration::from_millis(deadline_ms.unwrap_or(DEFAULT_READY_DEADLINE_MS) as u64),
+        interval: Duration::from_millis(
+            interval_ms.unwrap_or(DEFAULT_READY_POLL_INTERVAL_MS) as u64,
+        ),
+        cancel: cancel.map(CancelToken::shared),
+        on_retry,
+    }))
+}
+
+type RetryCallback = Box<dyn Fn(ConnectRetryEvent) + Send>;
+
+pub struct ConnectWhenReadyTask {
+    cid: u32,
+    port: u32,
+    deadline: Duration,
+    interval: Duration,
+    cancel: Option<Arc<Canceller>>,
+    on_retry: Option<RetryCallback>,
+}
+
+impl Task for ConnectWhenReadyTask {
+    type Output = i32;
+    type JsValue = VsockStream;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let (cid, port) = (self.cid, self.port);
+        let cancel = self.cancel.as_deref();
+        retry_connect(self.deadline, self.interval, cancel, self.on_retry.as_ref(), |timeout| {
+            connect_cancellable(cid, port, timeout, cancel)
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, fd: Self::Output) -> Result<Self::JsValue> {
+        Ok(VsockStream::from_raw(fd, self.cid, self.port))
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+/// Whether a failed connect means the peer isn't listening (yet).
+fn not_ready(err: &Error) -> bool {
+    matches!(
+        errors::reason_code(&err.reason),
+        Some("ECONNREFUSED" | "ECONNRESET" | "EHOSTUNREACH" | "ENETUNREACH" | "ETIMEDOUT")
+    )
+}
+
+/// Call `connect` with each attempt's timeout until it succeeds, fails
+/// with an error other than not_ready(), or `deadline` passes.
+fn retry_connect(
+    deadline: Duration,
+    interval: Duration,
+    cancel: Option<&Canceller>,
+    on_retry: Option<&RetryCallback>,
+    mut connect: impl FnMut(Duration) -> Result<i32>,
+) -> Result<i32> {
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = deadline.saturating_sub(started.elapsed());
+        let err = match connect(remaining.clamp(Duration::from_millis(1), READY_ATTEMPT_TIMEOUT)) {
+            Ok(fd) => return Ok(fd),
+            Err(e) if !not_ready(&e) => return Err(e),
+            Err(e) => e,
+        };
+        tracing::debug!(attempt, error = %err.reason, "peer not ready, retrying");
+        if started.elapsed() + interval >= deadline {
+            return Err(Error::from_reason(format!(
+                "ETIMEDOUT: peer not ready after {} ms and {} attempts; last error: {}",
+                started.elapsed().as_millis(),
+                attempt,
+                err.reason
+            )));
+        }
+        if let Some(on_retry) = on_retry {
+            on_retry(ConnectRetryEvent {
+                attempt,
+                elapsed_ms: started.elapsed().as_millis() as i64,
+                error: err.reason,
+            });
+        }
+        match cancel {
+            // A negative fd is ignored by poll(), so this just sleeps
+            Some(cancel) => {
+                cancel.wait_timeout(-1, 0, interval.as_millis() as i32).map_err(|_| {
+                    Error::from_reason("connectWhenReady() cancelled")
+                })?;
+            }
+            None => std::thread::sleep(interval),
+        }
+    }
+}
+
+fn set_linger_fd(fd: i32, enabled: bool, seconds: u32) -> std::io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: enabled as i32,
+        l_linger: seconds.min(i32::MAX as u32) as i32,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// IOV_MAX on Linux: readv() fails with EINVAL beyond it.
+const MAX_IOVECS: usize = 1024;
+
+/// readUntil()'s default maxBytes.
+const DEFAULT_MAX_RECORD: u32 = 1024 * 1024;
+
+/// How much readUntil() asks read(2) for at a time.
+const RECORD_READ_SIZE: usize = 64 * 1024;
+
+/// readv(2) into `buffers`, returning the total bytes read.
+fn read_vectored_fd(fd: i32, buffers: &mut [&mut [u8]]) -> std::io::Result<usize> {
+    let iovecs: Vec<libc::iovec> = buffers
+        .iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let n = unsafe { libc::readv(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// The first `n` bytes across `buffers`, concatenated.
+fn gather(buffers: &[&mut [u8]], n: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(n);
+    for buf in buffers {
+        let take = buf.len().min(n - out.len());
+        out.extend_from_slice(&buf[..take]);
+    }
+    out
+}
+
+/// Where the first `delimiter` in `pending` starts. The first `searched`
+/// bytes are known not to contain one already.
+fn find_delimiter(pending: &[u8], delimiter: &[u8], searched: usize) -> Option<usize> {
+    let from = searched.saturating_sub(delimiter.len() - 1);
+    let at = pending
+        .get(from..)?
+        .windows(delimiter.len())
+        .position(|w| w == delimiter)?;
+    Some(from + at)
+}
+
+/// Bytes waiting in `fd`'s receive queue.
+fn bytes_available_fd(fd: i32) -> std::io::Result<u32> {
+    let mut queued: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::FIONREAD as _, &mut queued as *mut libc::c_int) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(queued.max(0) as u32)
+}
+
+/// Whether `fd` reports `events`, or an error or hangup that makes the
+/// matching call return at once, within `timeout_ms`.
+fn poll_fd(fd: i32, events: i16, timeout_ms: u32) -> std::io::Result<bool> {
+    let mut pfd = libc::pollfd { fd, events, revents: 0 };
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms as u64);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let ret = unsafe { libc::poll(&mut pfd, 1, left.as_millis().min(i32::MAX as u128) as i32) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if pfd.revents & libc::POLLNVAL != 0 {
+            return Err(std::io::Error::from_raw_os_error(libc::EBADF));
+        }
+        return Ok(pfd.revents & (events | libc::POLLHUP | libc::POLLERR) != 0);
+    }
+}
+
+/// Connect to (cid, port) with a poll()-based timeout, returning a blocking
+/// fd with SO_RCVTIMEO/SO_SNDTIMEO set to the same timeout.
+/// Shared by vsockConnectAsync and the crate's native forwarders.
+pub(crate) fn connect_raw(cid: u32, port: u32, timeout_secs: u32) -> Result<i32> {
+    connect_cancellable(cid, port, Duration::from_secs(timeout_secs as u64), None)
+}
+
+/// connect_raw() with a sub-second timeout.
+pub(crate) fn connect_timeout(cid: u32, port: u32, timeout: Duration) -> Result<i32> {
+    connect_cancellable(cid, port, timeout, None)
+}
+
+/// connect_raw(), abandoning the wait for the connection when `cancel` fires.
+fn connect_cancellable(
+    cid: u32,
+    port: u32,
+    timeout: Duration,
+    cancel: Option<&Canceller>,
+) -> Result<i32> {
+    if cancel.is_some_and(Canceller::is_cancelled) {
+        return Err(Error::from_reason(format!(
+            "connect(cid={}, port={}) cancelled",
+            cid, port
+        )));
+    }
+    if let Some(backend) = mock::backend() {
+        // Rounded up: 0 would mean no timeout
+        return backend.connect(cid, port, timeout.as_secs_f64().ceil() as u32);
+    }
+    connect_vsock(cid, port, timeout, cancel)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_vsock(
+    _cid: u32,
+    _port: u32,
+    _timeout: Duration,
+    _cancel: Option<&Canceller>,
+) -> Result<i32> {
+    Err(platform::unsupported("AF_VSOCK"))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_vsock(cid: u32, port: u32, timeout: Duration, cancel: Option<&Canceller>) -> Result<i32> {
+    unsafe {
+        // Non-blocking socket for connect-with-timeout via poll()
+        let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_NONBLOCK, 0);
+        if fd < 0 {
+            return Err(errors::os_error("socket(AF_VSOCK)", std::io::Error::last_os_error()));
+        }
+
+        let addr = SockaddrVm {
+            svm_family: AF_VSOCK as u16,
+            svm_reserved1: 0,
+            svm_port: port,
+            svm_cid: cid,
+            svm_zero: [0; 4],
+        };
+
+        let ret = libc::connect(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<SockaddrVm>() as u32,
+        );
+
+        if ret < 0 {
+            let err = *libc::__errno_location();
+            if err != libc::EINPROGRESS {
+                libc::close(fd);
+                return Err(errors::os_error(
+                    format!("connect(cid={}, port={})", cid, port),
+                    std::io::Error::from_raw_os_error(err),
+                ));
+            }
+
+            // Wait for connect to complete with poll(), retrying on EINTR
+            let deadline = std::time::Instant::now() + timeout;
+            loop {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                let remaining_ms = remaining.as_millis().min(i32::MAX as u128) as i32;
+                if remaining_ms <= 0 {
+                    libc::close(fd);
+                    return Err(Error::from_reason(format!(
+                        "ETIMEDOUT: connect(cid={}, port={}) timed out after {:?}",
+                        cid, port, timeout
+                    )));
+                }
+
+                // poll() skips the negative fd when there's no cancel token
+                let mut pfds = [
+                    libc::pollfd { fd, events: libc::POLLOUT, revents: 0 },
+                    libc::pollfd {
+                        fd: cancel.map_or(-1, Canceller::fd),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                ];
+                let poll_ret = libc::poll(pfds.as_mut_ptr(), 2, remaining_ms);
+
+                if poll_ret < 0 {
+                    let poll_err = *libc::__errno_location();
+                    if poll_err == libc::EINTR {
+                        continue;
+                    }
+                    libc::close(fd);
+                    return Err(errors::os_error(
+                        format!("poll() during connect(cid={}, port={})", cid, port),
+                        std::io::Error::from_raw_os_error(poll_err),
+                    ));
+                }
+                if poll_ret == 0 {
+                    libc::close(fd);
+                    return Err(Error::from_reason(format!(
+                        "ETIMEDOUT: connect(cid={}, port={}) timed out after {:?}",
+                        cid, port, timeout
+                    )));
+                }
+                if pfds[1].revents != 0 {
+                    libc::close(fd);
+                    return Err(Error::from_reason(format!(
+                        "connect(cid={}, port={}) cancelled",
+                        cid, port
+                    )));
+                }
+                break;
+            }
+
+            // Check for connect error via SO_ERROR
+            let mut so_err: i32 = 0;
+            let mut len = std::mem::size_of::<i32>() as u32;
+            let gs_ret = libc::getsockopt(
+                fd, libc::SOL_SOCKET, libc::SO_ERROR,
+                &mut so_err as *mut _ as *mut libc::c_void,
+                &mut len,
+            );
+            if gs_ret < 0 {
+                libc::close(fd);
+                return Err(errors::os_error(
+                    format!("getsockopt(SO_ERROR) after connect(cid={}, port={})", cid, port),
+                    std::io::Error::last_os_error(),
+                ));
+            }
+            if so_err != 0 {
+                libc::close(fd);
+                return Err(errors::os_error(
+                    format!("connect(cid={}, port={})", cid, port),
+                    std::io::Error::from_raw_os_error(so_err),
+                ));
+            }
+        }
+
+        // Clear non-blocking flag for subsequent blocking read/write
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            libc::close(fd);
+            return Err(errors::os_error("fcntl(F_GETFL)", std::io::Error::last_os_error()));
+        }
+        let fl_ret = libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+        if fl_ret < 0 {
+            libc::close(fd);
+            return Err(errors::os_error("fcntl(F_SETFL)", std::io::Error::last_os_error()));
+        }
+
+        // Set I/O timeouts for subsequent read/write operations
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as i64,
+            tv_usec: timeout.subsec_micros() as i64,
+        };
+        let tv_ret = libc::setsockopt(
+            fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+            &tv as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as u32,
+        );
+        if tv_ret < 0 {
+            libc::close(fd);
+            return Err(errors::os_error(
+                "setsockopt(SO_RCVTIMEO)",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let tv_ret = libc::setsockopt(
+            fd, libc::SOL_SOCKET, libc::SO_SNDTIMEO,
+            &tv as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as u32,
+        );
+        if tv_ret < 0 {
+            libc::close(fd);
+            return Err(errors::os_error(
+                "setsockopt(SO_SNDTIMEO)",
+                std::io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(fd)
+    }
+}
+
+impl Drop for VsockListener {
+    fn drop(&mut self) {
+        self.state.close();
+        let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
+        if fd != CLOSED_FD {
+            unsafe { libc::close(fd); }
+        }
+    }
+}
+
+impl VsockStream {
+    fn connect_blocking(cid: u32, port: u32) -> Result<Self> {
+        if let Some(backend) = mock::backend() {
+            return Ok(VsockStream::from_raw(backend.connect(cid, port, 0)?, cid, port));
+        }
+        platform::require_linux("AF_VSOCK")?;
         unsafe {
-            // Non-blocking socket for connect-with-timeout via poll()
-            let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_NONBLOCK, 0);
+            let fd = libc::socket(AF_VSOCK, libc::SOCK_STREAM, 0);
             if fd < 0 {
-                return Err(Error::from_reason(format!(
-                    "socket(AF_VSOCK) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+                return Err(errors::os_error("socket(AF_VSOCK)", std::io::Error::last_os_error()));
             }
 
             let addr = SockaddrVm {
                 svm_family: AF_VSOCK as u16,
                 svm_reserved1: 0,
-                svm_port: self.port,
-                svm_cid: self.cid,
+                svm_port: port,
+                svm_cid: cid,
                 svm_zero: [0; 4],
             };
 
@@ -372,162 +2069,354 @@ impl Task for ConnectTask {
                 &addr as *const _ as *const libc::sockaddr,
                 std::mem::size_of::<SockaddrVm>() as u32,
             );
-
             if ret < 0 {
-                let err = *libc::__errno_location();
-                if err != libc::EINPROGRESS {
-                    libc::close(fd);
-                    return Err(Error::from_reason(format!(
-                        "connect(cid={}, port={}) failed: {}",
-                        self.cid, self.port,
-                        std::io::Error::from_raw_os_error(err)
-                    )));
-                }
+                libc::close(fd);
+                return Err(errors::os_error(
+                    format!("connect(cid={}, port={})", cid, port),
+                    std::io::Error::last_os_error(),
+                ));
+            }
 
-                // Wait for connect to complete with poll(), retrying on EINTR
-                let deadline = std::time::Instant::now()
-                    + std::time::Duration::from_secs(self.timeout_secs as u64);
-                loop {
-                    let remaining = deadline.saturating_duration_since(std::time::Instant::now());
-                    let remaining_ms = remaining.as_millis().min(i32::MAX as u128) as i32;
-                    if remaining_ms <= 0 {
-                        libc::close(fd);
-                        return Err(Error::from_reason(format!(
-                            "connect(cid={}, port={}) timed out after {}s",
-                            self.cid, self.port, self.timeout_secs
-                        )));
-                    }
+            Ok(VsockStream::from_raw(fd, cid, port))
+        }
+    }
 
-                    let mut pfd = libc::pollfd {
-                        fd,
-                        events: libc::POLLOUT,
-                        revents: 0,
-                    };
-                    let poll_ret = libc::poll(&mut pfd, 1, remaining_ms);
-
-                    if poll_ret < 0 {
-                        let poll_err = *libc::__errno_location();
-                        if poll_err == libc::EINTR {
-                            continue;
-                        }
-                        libc::close(fd);
-                        return Err(Error::from_reason(format!(
-                            "poll() failed during connect(cid={}, port={}): {}",
-                            self.cid, self.port,
-                            std::io::Error::from_raw_os_error(poll_err)
-                        )));
-                    }
-                    if poll_ret == 0 {
-                        libc::close(fd);
-                        return Err(Error::from_reason(format!(
-                            "connect(cid={}, port={}) timed out after {}s",
-                            self.cid, self.port, self.timeout_secs
-                        )));
-                    }
-                    break;
-                }
+    fn read_buffer(&self, env: &Env, size: u32) -> Result<JsBuffer> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return external_buffer(env, Vec::new());
+        }
+        {
+            let mut ahead = self.read_ahead.lock().unwrap();
+            if !ahead.is_empty() {
+                let n = ahead.len().min(size as usize);
+                let rest = ahead.split_off(n);
+                return external_buffer(env, std::mem::replace(&mut *ahead, rest));
+            }
+        }
+        let limit = self.read_limit.lock().unwrap().clone();
+        let pool = self.read_pool.lock().unwrap().clone();
+        if let Some(pool) = pool {
+            return self.read_pooled(env, fd, size, &pool, &limit);
+        }
+        let mut buf = vec![0u8; throttle::grant(&limit, size as usize)];
+        unsafe {
+            let n = libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            throttle::settle(&limit, buf.len(), n.max(0) as usize);
+            if n < 0 {
+                return Err(errors::os_error("read()", std::io::Error::last_os_error()));
+            }
+            buf.truncate(n as usize);
+            self.record_traffic(Direction::Read, &buf);
+            external_buffer(env, buf)
+        }
+    }
 
-                // Check for connect error via SO_ERROR
-                let mut so_err: i32 = 0;
-                let mut len = std::mem::size_of::<i32>() as u32;
-                let gs_ret = libc::getsockopt(
-                    fd, libc::SOL_SOCKET, libc::SO_ERROR,
-                    &mut so_err as *mut _ as *mut libc::c_void,
-                    &mut len,
-                );
-                if gs_ret < 0 {
-                    libc::close(fd);
-                    return Err(Error::from_reason(format!(
-                        "getsockopt(SO_ERROR) failed after connect(cid={}, port={}): {}",
-                        self.cid, self.port,
-                        std::io::Error::last_os_error()
-                    )));
-                }
-                if so_err != 0 {
-                    libc::close(fd);
-                    return Err(Error::from_reason(format!(
-                        "connect(cid={}, port={}) failed: {}",
-                        self.cid, self.port,
-                        std::io::Error::from_raw_os_error(so_err)
-                    )));
+    fn read_into(&self, buffers: &mut [Buffer]) -> Result<u32> {
+        if buffers.len() > MAX_IOVECS {
+            return Err(Error::from_reason(format!(
+                "readVectored takes at most {} buffers, got {}",
+                MAX_IOVECS,
+                buffers.len()
+            )));
+        }
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Ok(0);
+        }
+        {
+            let mut ahead = self.read_ahead.lock().unwrap();
+            if !ahead.is_empty() {
+                let mut n = 0;
+                for buffer in buffers.iter_mut() {
+                    let buf: &mut [u8] = buffer.as_mut();
+                    let take = buf.len().min(ahead.len() - n);
+                    buf[..take].copy_from_slice(&ahead[n..n + take]);
+                    n += take;
                 }
+                ahead.drain(..n);
+                return Ok(n as u32);
             }
+        }
+        let limit = self.read_limit.lock().unwrap().clone();
+        let granted = throttle::grant(&limit, buffers.iter().map(|b| b.len()).sum());
+        // Trim the buffers to the grant, leaving later ones empty
+        let mut budget = granted;
+        let mut slices: Vec<&mut [u8]> = buffers
+            .iter_mut()
+            .map(|b| {
+                let buf: &mut [u8] = b.as_mut();
+                let take = buf.len().min(budget);
+                budget -= take;
+                &mut buf[..take]
+            })
+            .collect();
+        let result = read_vectored_fd(fd, &mut slices);
+        throttle::settle(&limit, granted, *result.as_ref().unwrap_or(&0));
+        let n = result.map_err(|e| errors::os_error("readv()", e))?;
+        // Only the trace callback needs the bytes in one piece
+        let traced = self.trace.lock().unwrap().is_some();
+        if traced {
+            self.record_traffic(Direction::Read, &gather(&slices, n));
+        } else {
+            self.touch();
+            metrics::bytes_read(n);
+        }
+        Ok(n as u32)
+    }
 
-            // Clear non-blocking flag for subsequent blocking read/write
+    fn release_fd(&self) -> Result<i32> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        if !self.read_ahead.lock().unwrap().is_empty() {
+            return Err(Error::from_reason(
+                "readUntil() has buffered bytes; read() them before intoFd()",
+            ));
+        }
+        let mut queue = self.write_queue.lock().unwrap();
+        if queue.as_ref().is_some_and(|q| q.stats().queued_bytes > 0) {
+            return Err(Error::from_reason(
+                "queueWrite() has unsent bytes; await flushWrites() before intoFd()",
+            ));
+        }
+        unsafe {
             let flags = libc::fcntl(fd, libc::F_GETFL);
-            if flags < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "fcntl(F_GETFL) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
-            }
-            let fl_ret = libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
-            if fl_ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "fcntl(F_SETFL) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(errors::os_error("fcntl(O_NONBLOCK)", std::io::Error::last_os_error()));
             }
+        }
+        // As in close(), but leaving the fd open
+        self.slot.lock().unwrap().take();
+        if let Some(queue) = queue.take() {
+            queue.stop();
+        }
+        self.watchdog.lock().unwrap().take();
+        if self.fd.compare_exchange(fd, CLOSED_FD, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        metrics::stream_closed();
+        tracing::debug!(
+            fd,
+            peer_cid = self.peer_cid,
+            peer_port = self.peer_port,
+            "stream handed over"
+        );
+        Ok(fd)
+    }
+
+    fn duplicate(&self) -> Result<VsockStream> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let dup = relay::dup_fd(fd)?;
+        let mut clone = Self::from_raw(dup, self.peer_cid, self.peer_port);
+        // Traffic on either keeps an accepted connection from going idle
+        clone.activity = self.activity.clone();
+        Ok(clone)
+    }
 
-            // Set I/O timeouts for subsequent read/write operations
-            let tv = libc::timeval {
-                tv_sec: self.timeout_secs as i64,
-                tv_usec: 0,
+    /// readUntil() without the JS Buffer: None at end of stream.
+    fn read_record(&self, delimiter: &[u8], max: usize) -> Result<Option<Vec<u8>>> {
+        if delimiter.is_empty() {
+            return Err(Error::from_reason("readUntil() delimiter must not be empty"));
+        }
+        let mut pending = self.read_ahead.lock().unwrap();
+        let too_long =
+            || Error::from_reason(format!("EMSGSIZE: no delimiter within {} bytes", max));
+        let mut searched = 0;
+        loop {
+            if let Some(at) = find_delimiter(&pending, delimiter, searched) {
+                if at > max {
+                    return Err(too_long());
+                }
+                let rest = pending.split_off(at + delimiter.len());
+                let mut record = std::mem::replace(&mut *pending, rest);
+                record.truncate(at);
+                return Ok(Some(record));
+            }
+            if pending.len() > max {
+                return Err(too_long());
+            }
+            searched = pending.len();
+            let fd = self.fd.load(Ordering::Acquire);
+            let n = if fd == CLOSED_FD {
+                0
+            } else {
+                let limit = self.read_limit.lock().unwrap().clone();
+                let want = throttle::grant(&limit, RECORD_READ_SIZE);
+                pending.resize(searched + want, 0);
+                let n = unsafe {
+                    libc::read(fd, pending[searched..].as_mut_ptr() as *mut libc::c_void, want)
+                };
+                throttle::settle(&limit, want, n.max(0) as usize);
+                pending.truncate(searched + n.max(0) as usize);
+                if n < 0 {
+                    return Err(errors::os_error("read()", std::io::Error::last_os_error()));
+                }
+                n as usize
             };
-            let tv_ret = libc::setsockopt(
-                fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO,
-                &tv as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::timeval>() as u32,
-            );
-            if tv_ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "setsockopt(SO_RCVTIMEO) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+            if n == 0 {
+                // End of stream: whatever is left is the last record
+                if pending.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(std::mem::take(&mut *pending)));
             }
-            let tv_ret = libc::setsockopt(
-                fd, libc::SOL_SOCKET, libc::SO_SNDTIMEO,
-                &tv as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::timeval>() as u32,
+            self.record_traffic(Direction::Read, &pending[searched..]);
+        }
+    }
+
+    fn write_buffer(&self, data: &[u8]) -> Result<u32> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let limit = self.write_limit.lock().unwrap().clone();
+        let granted = throttle::grant(&limit, data.len());
+        unsafe {
+            let n = libc::write(
+                fd,
+                data.as_ptr() as *const libc::c_void,
+                granted,
             );
-            if tv_ret < 0 {
-                libc::close(fd);
-                return Err(Error::from_reason(format!(
-                    "setsockopt(SO_SNDTIMEO) failed: {}",
-                    std::io::Error::last_os_error()
-                )));
+            throttle::settle(&limit, granted, n.max(0) as usize);
+            if n < 0 {
+                return Err(errors::os_error("write()", std::io::Error::last_os_error()));
             }
+            self.record_traffic(Direction::Write, &data[..n as usize]);
+            Ok(n as u32)
+        }
+    }
+
+    fn poll_ready(&self, env: Env, events: i16, timeout_ms: Option<u32>) -> Result<bool> {
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let result = poll_fd(fd, events, timeout_ms.unwrap_or(0))
+            .map_err(|e| errors::os_error("poll()", e));
+        errors::structured(&env, result)
+    }
 
-            Ok((fd, self.cid, self.port))
+    fn read_pooled(
+        &self,
+        env: &Env,
+        fd: i32,
+        size: u32,
+        pool: &Arc<BufferPool>,
+        limit: &Option<Arc<TokenBucket>>,
+    ) -> Result<JsBuffer> {
+        let mut chunk = pool.take();
+        let want = throttle::grant(limit, (size as usize).min(pool.chunk_size()));
+        let n = unsafe { libc::read(fd, chunk.as_mut_ptr() as *mut libc::c_void, want) };
+        throttle::settle(limit, want, n.max(0) as usize);
+        if n <= 0 {
+            let err = std::io::Error::last_os_error();
+            pool.give_back(chunk);
+            if n < 0 {
+                return Err(errors::os_error("read()", err));
+            }
+            return external_buffer(env, Vec::new());
         }
+        self.record_traffic(Direction::Read, &chunk[..n as usize]);
+        pool.lend(env, chunk, n as usize)
     }
 
-    fn resolve(&mut self, _env: Env, (fd, cid, port): Self::Output) -> Result<Self::JsValue> {
-        Ok(VsockStream {
+    /// Wrap an already-connected vsock fd (ownership is taken).
+    pub(crate) fn from_raw(fd: i32, peer_cid: u32, peer_port: u32) -> Self {
+        metrics::stream_opened();
+        tracing::debug!(fd, peer_cid, peer_port, "stream opened");
+        VsockStream {
             fd: AtomicI32::new(fd),
-            peer_cid: cid,
-            peer_port: port,
-        })
+            peer_cid,
+            peer_port,
+            read_pool: Mutex::new(None),
+            slot: Mutex::new(None),
+            activity: None,
+            trace: Mutex::new(None),
+            read_limit: Mutex::new(None),
+            write_limit: Arc::new(Mutex::new(None)),
+            write_queue: Mutex::new(None),
+            read_ahead: Mutex::new(Vec::new()),
+            watchdog: Mutex::new(None),
+            on_close: Arc::new(Mutex::new(None)),
+        }
     }
-}
 
-impl Drop for VsockListener {
-    fn drop(&mut self) {
-        let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
-        if fd != CLOSED_FD {
-            unsafe { libc::close(fd); }
+    /// The write queue, started on first use.
+    fn queue(&self) -> Result<Arc<WriteQueue>> {
+        let mut queue = self.write_queue.lock().unwrap();
+        if let Some(queue) = &*queue {
+            return Ok(queue.clone());
+        }
+        let fd = self.fd.load(Ordering::Acquire);
+        if fd == CLOSED_FD {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        let activity = self.activity.clone();
+        let sink = Sink {
+            fd,
+            limit: self.write_limit.clone(),
+            on_write: Box::new(move |n| {
+                if let Some(activity) = &activity {
+                    activity.touch();
+                }
+                metrics::bytes_written(n);
+            }),
+        };
+        Ok(queue.insert(WriteQueue::start(sink, None)?).clone())
+    }
+
+    /// Record traffic for the listener's idle timeout.
+    pub(crate) fn touch(&self) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+    }
+
+    /// Record a read or write: refresh the idle timer and report it to the
+    /// trace callback, if any.
+    fn record_traffic(&self, direction: Direction, data: &[u8]) {
+        self.touch();
+        match direction {
+            Direction::Read => metrics::bytes_read(data.len()),
+            Direction::Write => metrics::bytes_written(data.len()),
         }
+        if let Some(trace) = &*self.trace.lock().unwrap() {
+            trace(TraceRecord::new(direction, data));
+        }
+    }
+
+    /// Wrap a connection accepted through `state`, taking over its slot.
+    fn accepted(fd: i32, peer_cid: u32, peer_port: u32, state: &Arc<AcceptState>) -> Self {
+        let mut stream = Self::from_raw(fd, peer_cid, peer_port);
+        let slot = ConnectionSlot::new(state, fd, peer_cid, peer_port);
+        stream.activity = Some(slot.conn.clone());
+        *stream.slot.get_mut().unwrap() = Some(slot);
+        stream
     }
 }
 
 impl Drop for VsockStream {
     fn drop(&mut self) {
+        self.slot.get_mut().unwrap().take();
+        if let Some(queue) = self.write_queue.get_mut().unwrap().take() {
+            queue.stop();
+        }
+        self.watchdog.get_mut().unwrap().take();
         let fd = self.fd.swap(CLOSED_FD, Ordering::AcqRel);
         if fd != CLOSED_FD {
             unsafe { libc::close(fd); }
+            metrics::stream_closed();
+            tracing::debug!(
+                fd,
+                peer_cid = self.peer_cid,
+                peer_port = self.peer_port,
+                "stream closed"
+            );
         }
     }
 }
@@ -539,6 +2428,7 @@ impl Drop for VsockStream {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::socketpair;
 
     // -------------------------------------------------------------------------
     // Struct layout: SockaddrVm must match the Linux kernel's sockaddr_vm
@@ -587,6 +2477,15 @@ mod tests {
         assert_eq!(VMADDR_CID_ANY, 0xFFFFFFFF);
     }
 
+    #[test]
+    fn well_known_addresses_match_linux_constants() {
+        // linux/vm_sockets.h
+        assert_eq!(VMADDR_CID_HYPERVISOR, 0);
+        assert_eq!(VMADDR_CID_LOCAL, 1);
+        assert_eq!(VMADDR_CID_HOST, 2);
+        assert_eq!(VMADDR_PORT_ANY, u32::MAX);
+    }
+
     #[test]
     fn closed_fd_is_negative() {
         assert_eq!(CLOSED_FD, -1);
@@ -598,16 +2497,17 @@ mod tests {
 
     #[test]
     fn connect_task_stores_params() {
-        let task = ConnectTask { cid: 16, port: 5000, timeout_secs: 5 };
+        let task = ConnectTask { cid: 16, port: 5000, timeout_secs: 5, cancel: None };
         assert_eq!(task.cid, 16);
         assert_eq!(task.port, 5000);
         assert_eq!(task.timeout_secs, 5);
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
     #[ignore] // Requires vhost_vsock kernel module (available in Nitro Enclaves, not CI)
     fn connect_task_to_invalid_cid_fails() {
-        let mut task = ConnectTask { cid: 0, port: 5000, timeout_secs: 1 };
+        let mut task = ConnectTask { cid: 0, port: 5000, timeout_secs: 1, cancel: None };
         let result = task.compute();
         assert!(result.is_err());
         let err_msg = result.unwrap_err().reason;
@@ -615,10 +2515,11 @@ mod tests {
         assert!(err_msg.contains("port=5000"), "error should mention port: {}", err_msg);
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
     #[ignore] // Requires vhost_vsock kernel module (available in Nitro Enclaves, not CI)
     fn connect_task_timeout_on_unreachable_cid() {
-        let mut task = ConnectTask { cid: 99, port: 5000, timeout_secs: 1 };
+        let mut task = ConnectTask { cid: 99, port: 5000, timeout_secs: 1, cancel: None };
         let start = std::time::Instant::now();
         let result = task.compute();
         let elapsed = start.elapsed();
@@ -633,7 +2534,7 @@ mod tests {
 
     #[test]
     fn accept_task_with_closed_fd_fails() {
-        let mut task = AcceptTask { fd: CLOSED_FD };
+        let mut task = AcceptTask { fd: CLOSED_FD, state: Default::default(), cancel: None };
         let result = task.compute();
         assert!(result.is_err());
         assert!(result.unwrap_err().reason.contains("closed"));
@@ -642,17 +2543,528 @@ mod tests {
     #[test]
     fn accept_task_with_invalid_fd_fails() {
         // fd 999999 is almost certainly not a valid listener
-        let mut task = AcceptTask { fd: 999999 };
+        let mut task = AcceptTask { fd: 999999, state: Default::default(), cancel: None };
         let result = task.compute();
         assert!(result.is_err());
         assert!(result.unwrap_err().reason.contains("accept()"));
     }
 
+    #[test]
+    fn accept_rejects_disallowed_cids() {
+        let state = AcceptState::default();
+        *state.allowed_cids.lock().unwrap() = Some(vec![3]);
+
+        // Two connections from CID 16, then one from the parent (CID 3)
+        let mut peers = vec![(16, 1025), (16, 1026), (3, 1027)].into_iter();
+        let mut remotes = Vec::new();
+        let (conn, cid, port) = state
+            .accept_with(None, || {
+                let (cid, port) = peers.next().unwrap();
+                let (local, remote) = std::os::unix::net::UnixStream::pair()?;
+                remotes.push(remote);
+                Ok((std::os::fd::IntoRawFd::into_raw_fd(local), cid, port))
+            })
+            .unwrap();
+        assert_eq!((cid, port), (3, 1027));
+
+        // Rejected connections were closed: their peers see EOF
+        use std::io::Read;
+        for remote in &mut remotes[..2] {
+            assert_eq!(remote.read(&mut [0u8; 1]).unwrap(), 0);
+        }
+        unsafe { libc::close(conn); }
+        assert_eq!(state.accepted.load(Ordering::Relaxed), 1);
+        assert_eq!(state.rejected.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn listener_events_follow_connections() {
+        let state = Arc::new(AcceptState::default());
+        *state.allowed_cids.lock().unwrap() = Some(vec![3]);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        *state.on_event.lock().unwrap() = Some(Box::new(move |e: ListenerEvent| {
+            seen.lock().unwrap().push((e.kind, e.peer_cid, e.active));
+        }));
+
+        let mut peers = vec![(16, 1025), (3, 1026)].into_iter();
+        let (fd, cid, port) = state
+            .accept_with(None, || {
+                let (cid, port) = peers.next().unwrap();
+                Ok((unsafe { libc::dup(0) }, cid, port))
+            })
+            .unwrap();
+        drop(ConnectionSlot::new(&state, fd, cid, port));
+        unsafe { libc::close(fd); }
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("rejected".to_string(), 16, 0),
+                ("accepted".to_string(), 3, 1),
+                ("closed".to_string(), 3, 0),
+            ]
+        );
+
+        let fail = |errno| move || Err(std::io::Error::from_raw_os_error(errno));
+        assert!(state.accept_with(None, fail(libc::EMFILE)).is_err());
+        assert!(state.accept_with(None, || Err(cancelled_error())).is_err());
+        state.close();
+        assert!(state.accept_with(None, fail(libc::EBADF)).is_err());
+        assert_eq!(state.accept_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn accept_allows_any_cid_by_default() {
+        let state = AcceptState::default();
+        let (_, cid, _) = state.accept_with(None, || Ok((-1, 16, 1025))).unwrap();
+        assert_eq!(cid, 16);
+        assert_eq!(state.rejected.load(Ordering::Relaxed), 0);
+    }
+
+    fn limited(max: u32, when_full: &str) -> Arc<AcceptState> {
+        let options = ListenerOptions {
+            max_connections: Some(max),
+            when_full: Some(when_full.to_string()),
+            idle_timeout_ms: None,
+            cid: None,
+            backlog: None,
+        };
+        Arc::new(AcceptState::new(Some(options)).unwrap())
+    }
+
+    /// Accept one scripted connection from `cid`, keeping its remote end.
+    fn accept_from(
+        state: &AcceptState,
+        cid: u32,
+        remotes: &mut Vec<std::os::unix::net::UnixStream>,
+    ) -> std::io::Result<(i32, u32, u32)> {
+        state.accept_with(None, || {
+            let (local, remote) = std::os::unix::net::UnixStream::pair()?;
+            remotes.push(remote);
+            Ok((std::os::fd::IntoRawFd::into_raw_fd(local), cid, 1025))
+        })
+    }
+
+    #[test]
+    fn listener_options_are_validated() {
+        let zero = ListenerOptions {
+            max_connections: Some(0),
+            when_full: None,
+            idle_timeout_ms: None,
+            cid: None,
+            backlog: None,
+        };
+        let err = AcceptState::new(Some(zero)).err().unwrap();
+        assert!(err.reason.contains("maxConnections"));
+        let bad = ListenerOptions {
+            max_connections: Some(1),
+            when_full: Some("drop".into()),
+            idle_timeout_ms: None,
+            cid: None,
+            backlog: None,
+        };
+        let err = AcceptState::new(Some(bad)).err().unwrap();
+        assert!(err.reason.contains("whenFull"));
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_closed() {
+        let state = limited(1, "close");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        *state.on_limit.lock().unwrap() = Some(Box::new(move |e: ConnectionLimitEvent| {
+            seen.lock().unwrap().push((e.action, e.active, e.peer_cid));
+        }));
+
+        let mut remotes = Vec::new();
+        let (first, _, _) = accept_from(&state, 3, &mut remotes).unwrap();
+        let first = VsockStream::accepted(first, 3, 1025, &state);
+
+        // At the limit: the next connection is closed and accept carries on,
+        // so script a second arrival to return once a slot frees up.
+        let mut arrivals = 0;
+        let (conn, cid, _) = state
+            .accept_with(None, || {
+                arrivals += 1;
+                if arrivals == 2 {
+                    first.close().unwrap();
+                }
+                let (local, remote) = std::os::unix::net::UnixStream::pair()?;
+                remotes.push(remote);
+                Ok((std::os::fd::IntoRawFd::into_raw_fd(local), 10 + arrivals, 1025))
+            })
+            .unwrap();
+        assert_eq!(cid, 12);
+
+        use std::io::Read;
+        assert_eq!(remotes[1].read(&mut [0u8; 1]).unwrap(), 0, "over-limit peer sees EOF");
+        assert_eq!(*events.lock().unwrap(), vec![("closed".to_string(), 1, Some(11))]);
+        assert_eq!(state.limited.load(Ordering::Relaxed), 1);
+        assert_eq!(*state.active.lock().unwrap(), 1);
+        unsafe { libc::close(conn); }
+    }
+
+    #[test]
+    fn accepts_queue_until_a_stream_closes() {
+        let state = limited(1, "queue");
+        let mut remotes = Vec::new();
+        let (fd, _, _) = accept_from(&state, 3, &mut remotes).unwrap();
+        let first = VsockStream::accepted(fd, 3, 1025, &state);
+
+        let waiter = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let mut remotes = Vec::new();
+                accept_from(&state, 3, &mut remotes).map(|(fd, _, _)| unsafe { libc::close(fd) })
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished(), "second accept should wait for a slot");
+        assert_eq!(state.limited.load(Ordering::Relaxed), 1);
+
+        drop(first);
+        waiter.join().unwrap().unwrap();
+        assert_eq!(state.accepted.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn closing_the_listener_fails_queued_accepts() {
+        let state = limited(1, "queue");
+        let mut remotes = Vec::new();
+        let (fd, _, _) = accept_from(&state, 3, &mut remotes).unwrap();
+
+        let waiter = {
+            let state = state.clone();
+            std::thread::spawn(move || accept_from(&state, 3, &mut Vec::new()).map(|_| ()))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        state.close();
+        let err = waiter.join().unwrap().unwrap_err();
+        assert!(err.to_string().contains("closed"));
+        unsafe { libc::close(fd); }
+    }
+
+    #[test]
+    fn drain_waits_for_streams_to_close() {
+        let state = Arc::new(AcceptState::default());
+        let mut remotes = Vec::new();
+        let (fd, _, _) = accept_from(&state, 3, &mut remotes).unwrap();
+        let stream = VsockStream::accepted(fd, 3, 1025, &state);
+
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            stream.close().unwrap();
+        });
+        let start = Instant::now();
+        assert_eq!(state.drain(Duration::from_secs(5)), 0);
+        assert!(start.elapsed() < Duration::from_secs(5), "drain should finish once streams close");
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn drain_shuts_down_streams_after_grace_period() {
+        let state = Arc::new(AcceptState::default());
+        let mut remotes = Vec::new();
+        let (fd, _, _) = accept_from(&state, 3, &mut remotes).unwrap();
+        let stream = VsockStream::accepted(fd, 3, 1025, &state);
+
+        assert_eq!(state.drain(Duration::from_millis(20)), 1);
+        use std::io::Read;
+        assert_eq!(remotes[0].read(&mut [0u8; 1]).unwrap(), 0, "peer sees EOF");
+        assert!(state.closed.load(Ordering::Acquire), "listener stops accepting");
+
+        drop(stream);
+        assert!(state.tracked.lock().unwrap().is_empty());
+        assert_eq!(*state.active.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn idle_streams_are_shut_down_once() {
+        let options = ListenerOptions {
+            max_connections: None,
+            when_full: None,
+            idle_timeout_ms: Some(30),
+            cid: None,
+            backlog: None,
+        };
+        let state = Arc::new(AcceptState::new(Some(options)).unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        *state.on_idle_close.lock().unwrap() = Some(Box::new(move |e: IdleCloseEvent| {
+            seen.lock().unwrap().push((e.peer_cid, e.idle_ms));
+        }));
+
+        let mut remotes = Vec::new();
+        let (fd, _, _) = accept_from(&state, 16, &mut remotes).unwrap();
+        let idle = VsockStream::accepted(fd, 16, 1025, &state);
+        let (fd, _, _) = accept_from(&state, 17, &mut remotes).unwrap();
+        let busy = VsockStream::accepted(fd, 17, 1026, &state);
+
+        std::thread::sleep(Duration::from_millis(40));
+        busy.touch();
+        let timeout = state.idle_timeout.unwrap();
+        state.reap_idle(timeout);
+        state.reap_idle(timeout);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1, "only the idle stream, and only once");
+        assert_eq!(events[0].0, 16);
+        assert!(events[0].1 >= 30);
+        assert_eq!(state.idle_closed.load(Ordering::Relaxed), 1);
+
+        use std::io::Read;
+        assert_eq!(remotes[0].read(&mut [0u8; 1]).unwrap(), 0, "idle peer sees EOF");
+        drop((idle, busy));
+    }
+
+    fn get_linger(fd: i32) -> libc::linger {
+        let mut linger = libc::linger { l_onoff: 0, l_linger: 0 };
+        let mut len = std::mem::size_of::<libc::linger>() as u32;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &mut linger as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        linger
+    }
+
+    #[test]
+    fn set_linger_round_trips() {
+        let (local, _remote) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&local);
+
+        set_linger_fd(fd, true, 5).unwrap();
+        let linger = get_linger(fd);
+        assert_ne!(linger.l_onoff, 0);
+        assert_eq!(linger.l_linger, 5);
+
+        set_linger_fd(fd, false, 0).unwrap();
+        assert_eq!(get_linger(fd).l_onoff, 0);
+    }
+
+    #[test]
+    fn connect_when_ready_retries_until_the_peer_listens() {
+        let errno = |code| std::io::Error::from_raw_os_error(code);
+        let refused = || errors::os_error("connect(cid=3, port=8000)", errno(libc::ECONNREFUSED));
+        let retries = Arc::new(Mutex::new(Vec::new()));
+        let seen = retries.clone();
+        let on_retry: RetryCallback = Box::new(move |e| seen.lock().unwrap().push(e.attempt));
+        let interval = Duration::from_millis(5);
+        let mut attempts = 0;
+        let fd = retry_connect(Duration::from_secs(5), interval, None, Some(&on_retry), |_| {
+            attempts += 1;
+            if attempts < 3 { Err(refused()) } else { Ok(42) }
+        });
+        assert_eq!(fd.unwrap(), 42);
+        assert_eq!(*retries.lock().unwrap(), vec![1, 2]);
+
+        // Other errors aren't retried
+        let err = retry_connect(Duration::from_secs(5), interval, None, None, |_| {
+            Err(errors::os_error("socket(AF_VSOCK)", errno(libc::EMFILE)))
+        });
+        assert!(err.unwrap_err().reason.starts_with("EMFILE"));
+
+        let started = Instant::now();
+        let err = retry_connect(Duration::from_millis(50), interval, None, None, |timeout| {
+            assert!(timeout <= Duration::from_millis(50));
+            Err(refused())
+        });
+        let reason = err.unwrap_err().reason;
+        assert!(reason.starts_with("ETIMEDOUT: peer not ready"), "{}", reason);
+        assert!(reason.ends_with(&refused().reason));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let cancel = Canceller::new().unwrap();
+        cancel.cancel();
+        let err =
+            retry_connect(Duration::from_secs(5), interval, Some(&cancel), None, |_| Err(refused()));
+        assert!(err.unwrap_err().reason.contains("cancelled"));
+    }
+
+    #[test]
+    fn set_linger_on_invalid_fd_fails() {
+        assert!(set_linger_fd(-1, true, 0).is_err());
+    }
+
+    #[test]
+    fn read_vectored_fills_buffers_in_order() {
+        let (local, remote) = socketpair();
+        let sent = unsafe { libc::write(remote, b"HDR1payload".as_ptr() as *const libc::c_void, 11) };
+        assert_eq!(sent, 11);
+
+        let mut header = [0u8; 4];
+        let mut payload = [0u8; 16];
+        let mut buffers: [&mut [u8]; 2] = [&mut header, &mut payload];
+        let n = read_vectored_fd(local, &mut buffers).unwrap();
+        assert_eq!(n, 11);
+        assert_eq!(gather(&buffers, n), b"HDR1payload");
+        assert_eq!(&header, b"HDR1");
+        assert_eq!(&payload[..7], b"payload");
+
+        unsafe { libc::close(remote); }
+        let mut rest = [0u8; 4];
+        assert_eq!(read_vectored_fd(local, &mut [&mut rest]).unwrap(), 0);
+        unsafe { libc::close(local); }
+        assert!(read_vectored_fd(-1, &mut [&mut rest]).is_err());
+    }
+
+    #[test]
+    fn records_split_on_delimiters_across_reads() {
+        assert_eq!(find_delimiter(b"{}\r\n{", b"\r\n", 0), Some(2));
+        assert_eq!(find_delimiter(b"{\"b\"", b"\r\n", 0), None);
+        // A delimiter straddling what was already searched is still found
+        assert_eq!(find_delimiter(b"{\"b\":2}\r", b"\r\n", 5), None);
+        assert_eq!(find_delimiter(b"{\"b\":2}\r\nnext", b"\r\n", 8), Some(7));
+
+        let (local, remote) = socketpair();
+        let stream = VsockStream::from_raw(local, 3, 5000);
+        let data = b"one\ntwo\nlong line\ntail";
+        let sent = unsafe { libc::write(remote, data.as_ptr() as *const libc::c_void, data.len()) };
+        assert_eq!(sent, data.len() as isize);
+        unsafe { libc::close(remote); }
+        assert_eq!(stream.read_record(b"\n", 16).unwrap().unwrap(), b"one");
+        assert_eq!(stream.read_record(b"\n", 16).unwrap().unwrap(), b"two");
+        let err = stream.read_record(b"\n", 4).unwrap_err();
+        assert!(err.reason.starts_with("EMSGSIZE"), "{}", err.reason);
+        assert_eq!(stream.read_record(b"\n", 16).unwrap().unwrap(), b"long line");
+        assert_eq!(stream.read_record(b"\n", 16).unwrap().unwrap(), b"tail");
+        assert_eq!(stream.read_record(b"\n", 16).unwrap(), None);
+        assert!(stream.read_record(b"", 16).is_err());
+    }
+
+    #[test]
+    fn pairs_are_connected_both_ways() {
+        let [a, b] = connected_pair().unwrap();
+        assert_eq!(a.1, VMADDR_CID_LOCAL);
+        let (a, b) = (VsockStream::from_raw(a.0, a.1, a.2), VsockStream::from_raw(b.0, b.1, b.2));
+        assert_eq!(a.write_buffer(b"ping\n").unwrap(), 5);
+        assert_eq!(b.read_record(b"\n", 16).unwrap().unwrap(), b"ping");
+        assert_eq!(b.write_buffer(b"pong\n").unwrap(), 5);
+        assert_eq!(a.read_record(b"\n", 16).unwrap().unwrap(), b"pong");
+        a.close().unwrap();
+        assert_eq!(b.read_record(b"\n", 16).unwrap(), None);
+    }
+
+    #[test]
+    fn into_fd_releases_the_descriptor_open() {
+        let (local, remote) = socketpair();
+        let stream = VsockStream::from_raw(local, 3, 5000);
+        assert_eq!(stream.release_fd().unwrap(), local);
+        assert_eq!(stream.fd(), CLOSED_FD);
+        assert!(stream.release_fd().is_err());
+        drop(stream);
+        let flags = unsafe { libc::fcntl(local, libc::F_GETFL) };
+        assert!(flags >= 0, "fd was closed");
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+
+        // Bytes buffered by readUntil() would be lost, so they block it
+        let sent = unsafe { libc::write(remote, b"a\nb".as_ptr() as *const libc::c_void, 3) };
+        assert_eq!(sent, 3);
+        let stream = VsockStream::from_raw(local, 3, 5000);
+        assert_eq!(stream.read_record(b"\n", 16).unwrap().unwrap(), b"a");
+        assert!(stream.release_fd().is_err());
+        drop(stream);
+        unsafe { libc::close(remote); }
+    }
+
+    #[test]
+    fn rate_limits_cut_calls_to_the_burst() {
+        let (local, remote) = socketpair();
+        let stream = VsockStream::from_raw(local, 3, 5000);
+        let options = RateLimitOptions {
+            direction: Some("write".to_string()),
+            burst_bytes: Some(100),
+        };
+        stream.set_rate_limit(Some(10_000), Some(options)).unwrap();
+        assert_eq!(stream.write_buffer(&[1; 1000]).unwrap(), 100);
+        let started = Instant::now();
+        assert_eq!(stream.write_buffer(&[2; 1000]).unwrap(), 100);
+        assert!(started.elapsed() >= Duration::from_millis(5));
+        assert!(stream.read_limit.lock().unwrap().is_none());
+
+        stream.set_rate_limit(None, None).unwrap();
+        assert_eq!(stream.write_buffer(&[3; 1000]).unwrap(), 1000);
+        assert_eq!(read_all(remote, 1200).len(), 1200);
+        drop(stream);
+        unsafe { libc::close(remote); }
+    }
+
+    #[test]
+    fn clones_outlive_the_original() {
+        let (local, remote) = socketpair();
+        let reader = VsockStream::from_raw(local, 3, 5000);
+        let writer = reader.duplicate().unwrap();
+        assert_ne!(writer.fd(), local);
+        assert_eq!((writer.peer_cid, writer.peer_port), (3, 5000));
+        assert_eq!(writer.write_buffer(b"hi").unwrap(), 2);
+        assert_eq!(read_all(remote, 2), b"hi");
+
+        // Closing one leaves the connection open for the other
+        reader.close().unwrap();
+        assert!(reader.duplicate().is_err());
+        assert_eq!(writer.write_buffer(b"still").unwrap(), 5);
+        assert_eq!(read_all(remote, 5), b"still");
+        drop(writer);
+        let mut byte = 0u8;
+        let n = unsafe { libc::read(remote, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        assert_eq!(n, 0, "connection should be closed");
+        unsafe { libc::close(remote); }
+    }
+
+    #[test]
+    fn readiness_and_pending_bytes() {
+        let (local, remote) = socketpair();
+        assert_eq!(bytes_available_fd(local).unwrap(), 0);
+        assert!(!poll_fd(local, libc::POLLIN, 0).unwrap());
+        assert!(!poll_fd(local, libc::POLLIN, 20).unwrap());
+        assert!(poll_fd(local, libc::POLLOUT, 0).unwrap());
+
+        let sent = unsafe { libc::write(remote, b"hello".as_ptr() as *const libc::c_void, 5) };
+        assert_eq!(sent, 5);
+        assert_eq!(bytes_available_fd(local).unwrap(), 5);
+        assert!(poll_fd(local, libc::POLLIN, 0).unwrap());
+
+        // A closed peer makes read() return at once, too
+        unsafe { libc::close(remote); }
+        assert_eq!(read_all(local, 5), b"hello");
+        assert!(poll_fd(local, libc::POLLIN, 0).unwrap());
+        unsafe { libc::close(local); }
+
+        assert!(bytes_available_fd(-1).is_err());
+    }
+
+    #[test]
+    fn accept_task_can_be_cancelled() {
+        let path = std::env::temp_dir().join(format!("vsock-cancel-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let canceller = Arc::new(Canceller::new().unwrap());
+
+        let mut task = AcceptTask {
+            fd: std::os::fd::AsRawFd::as_raw_fd(&listener),
+            state: Default::default(),
+            cancel: Some(canceller.clone()),
+        };
+        let waiter = std::thread::spawn(move || task.compute().map(|_| ()));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        canceller.cancel();
+        let err = waiter.join().unwrap().unwrap_err();
+        assert!(err.reason.contains("cancelled"), "{}", err.reason);
+        std::fs::remove_file(&path).unwrap();
+    }
+
     // -------------------------------------------------------------------------
     // Non-blocking connect + poll pattern (using TCP as proxy for AF_VSOCK)
     // Validates the poll-based timeout works correctly with any socket type.
     // -------------------------------------------------------------------------
 
+    #[cfg(target_os = "linux")]
     #[test]
     fn nonblocking_connect_poll_timeout_with_tcp() {
         // Use a TCP socket to an unroutable address to test the poll timeout pattern.
@@ -696,6 +3108,78 @@ mod tests {
         }
     }
 
+    // -------------------------------------------------------------------------
+    // sendFile: sendfile/splice into a socket (using AF_UNIX socketpair)
+    // -------------------------------------------------------------------------
+
+    fn read_all(fd: i32, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        let mut got = 0;
+        while got < len {
+            let n = unsafe {
+                libc::read(fd, out[got..].as_mut_ptr() as *mut libc::c_void, len - got)
+            };
+            assert!(n > 0, "read() failed or hit EOF after {} bytes", got);
+            got += n as usize;
+        }
+        out
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send_file_from_regular_file_honours_offset_and_length() {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        let path = std::env::temp_dir().join(format!("vsock-sendfile-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"0123456789abcdef").unwrap();
+        drop(file);
+        let file = std::fs::File::open(&path).unwrap();
+
+        let (a, b) = socketpair();
+        let sent = send_file_fd(a, file.as_raw_fd(), 4, 8, &None).unwrap();
+        assert_eq!(sent, 8);
+        assert_eq!(read_all(b, 8), b"456789ab");
+
+        // Length past EOF stops at EOF
+        let sent = send_file_fd(a, file.as_raw_fd(), 12, 100, &None).unwrap();
+        assert_eq!(sent, 4);
+        assert_eq!(read_all(b, 4), b"cdef");
+
+        unsafe { libc::close(a); libc::close(b); }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send_file_from_pipe_uses_splice() {
+        let mut pipe_fds = [0i32; 2];
+        assert_eq!(unsafe { libc::pipe(pipe_fds.as_mut_ptr()) }, 0);
+        let payload = b"spliced bytes";
+        unsafe {
+            libc::write(pipe_fds[1], payload.as_ptr() as *const libc::c_void, payload.len());
+            libc::close(pipe_fds[1]);
+        }
+
+        let (a, b) = socketpair();
+        let sent = send_file_fd(a, pipe_fds[0], 0, 1024, &None).unwrap();
+        assert_eq!(sent, payload.len() as i64);
+        assert_eq!(read_all(b, payload.len()), payload);
+
+        let err = send_file_fd(a, pipe_fds[0], 5, 10, &None).unwrap_err();
+        assert!(err.reason.contains("pipe"), "unexpected error: {}", err.reason);
+
+        unsafe { libc::close(pipe_fds[0]); libc::close(a); libc::close(b); }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send_file_rejects_negative_arguments() {
+        let err = send_file_fd(0, 0, -1, 10, &None).unwrap_err();
+        assert!(err.reason.contains("non-negative"));
+    }
+
     // -------------------------------------------------------------------------
     // Send/Sync: tasks must be Send for napi-rs thread pool
     // -------------------------------------------------------------------------
@@ -711,4 +3195,9 @@ mod tests {
     fn connect_task_is_send() {
         assert_send::<ConnectTask>();
     }
+
+    #[test]
+    fn send_file_task_is_send() {
+        assert_send::<SendFileTask>();
+    }
 }
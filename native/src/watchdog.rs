@@ -0,0 +1,240 @@
+//! Dead-peer detection for VsockStreams.
+//!
+//! A stream only learns that its peer is gone when a read returns EOF or a
+//! write fails, which for a mostly idle connection can be much later.
+//! stream.enableWatchdog(timeoutMs) starts a native thread that polls the
+//! fd for hangup and errors, and checks SO_ERROR at least every timeoutMs,
+//! calling the onClose() callback as soon as the peer vanishes:
+//!
+//! ```js
+//! stream.onClose(({ reason, error }) => sessions.drop(stream));
+//! stream.enableWatchdog(5000);
+//! ```
+//!
+//! The callback fires at most once per enableWatchdog(). The watchdog
+//! never reads or writes, so the stream stays usable: reads still return
+//! whatever the peer sent before it left.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::cancel::Canceller;
+use crate::errors;
+
+/// Peer shut down its end (POLLRDHUP is Linux-only; POLLHUP is always
+/// reported).
+#[cfg(target_os = "linux")]
+const HANGUP: i16 = libc::POLLRDHUP;
+#[cfg(not(target_os = "linux"))]
+const HANGUP: i16 = 0;
+
+/// Passed to the onClose callback.
+#[napi(object)]
+pub struct PeerCloseEvent {
+    /// "hangup" (the peer closed or shut down the connection) or "error"
+    /// (it failed, e.g. was reset).
+    pub reason: String,
+    /// The socket error, for "error".
+    pub error: Option<String>,
+}
+
+pub(crate) type CloseCallback = Box<dyn Fn(PeerCloseEvent) + Send>;
+
+/// A running watchdog thread, stopped on drop.
+pub(crate) struct Watchdog {
+    cancel: Arc<Canceller>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Watch `fd`, checking at least every `interval`, and report its
+    /// peer's departure to whatever callback `on_close` then holds.
+    pub(crate) fn start(
+        fd: i32,
+        interval: Duration,
+        on_close: Arc<Mutex<Option<CloseCallback>>>,
+    ) -> Result<Self> {
+        if interval.is_zero() {
+            return Err(Error::from_reason("timeoutMs must be greater than 0"));
+        }
+        let cancel = Arc::new(Canceller::new().map_err(|e| errors::os_error("pipe()", e))?);
+        let watching = cancel.clone();
+        let thread = std::thread::spawn(move || {
+            let Some(event) = watch(fd, interval, &watching) else {
+                return;
+            };
+            tracing::debug!(fd, reason = %event.reason, "watchdog saw peer close");
+            if let Some(on_close) = &*on_close.lock().unwrap() {
+                on_close(event);
+            }
+        });
+        Ok(Watchdog {
+            cancel,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Block until `fd`'s peer is gone, or None once `cancel` is cancelled.
+fn watch(fd: i32, interval: Duration, cancel: &Canceller) -> Option<PeerCloseEvent> {
+    let timeout_ms = interval.as_millis().min(i32::MAX as u128) as i32;
+    loop {
+        let revents = match cancel.wait_timeout(fd, HANGUP, timeout_ms) {
+            Ok(revents) => revents,
+            Err(_) if cancel.is_cancelled() => return None,
+            Err(e) => return Some(failed(errors::os_error("poll()", e))),
+        };
+        // POLLERR, or a reset that hasn't raised it (yet)
+        match socket_error(fd) {
+            Ok(None) => {}
+            Ok(Some(e)) => return Some(failed(errors::os_error("connection", e))),
+            Err(e) => return Some(failed(errors::os_error("getsockopt(SO_ERROR)", e))),
+        }
+        if revents & (libc::POLLHUP | HANGUP) != 0 {
+            return Some(PeerCloseEvent {
+                reason: "hangup".to_string(),
+                error: None,
+            });
+        }
+        if revents & libc::POLLNVAL != 0 {
+            return Some(failed(Error::from_reason("EBADF: stream fd was closed")));
+        }
+    }
+}
+
+fn failed(err: Error) -> PeerCloseEvent {
+    PeerCloseEvent {
+        reason: "error".to_string(),
+        error: Some(err.reason),
+    }
+}
+
+/// The pending error on `fd` (SO_ERROR), if any.
+fn socket_error(fd: i32) -> std::io::Result<Option<std::io::Error>> {
+    let mut err: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut err as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((err != 0).then(|| std::io::Error::from_raw_os_error(err)))
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Instant;
+
+    #[test]
+    fn peer_departure_is_seen_promptly() {
+        let (ours, mut peer) = UnixStream::pair().unwrap();
+        let cancel = Canceller::new().unwrap();
+        let closer = std::thread::spawn(move || {
+            // Pending data isn't a hangup
+            peer.write_all(b"last words").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        let started = Instant::now();
+        let event = watch(ours.as_raw_fd(), Duration::from_secs(10), &cancel).unwrap();
+        closer.join().unwrap();
+        assert_eq!(event.reason, "hangup");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn watchdogs_stop_quietly() {
+        let (ours, _peer) = UnixStream::pair().unwrap();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let seen = fired.clone();
+        let on_close: CloseCallback =
+            Box::new(move |event| seen.lock().unwrap().push(event.reason));
+        let watchdog = Watchdog::start(
+            ours.as_raw_fd(),
+            Duration::from_millis(10),
+            Arc::new(Mutex::new(Some(on_close))),
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        drop(watchdog);
+        assert!(fired.lock().unwrap().is_empty());
+
+        let none = Arc::new(Mutex::new(None));
+        assert!(Watchdog::start(ours.as_raw_fd(), Duration::ZERO, none).is_err());
+    }
+
+    #[test]
+    fn resets_are_reported_as_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let ours = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        // Closing with a zero linger sends RST instead of FIN
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                peer.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+        drop(peer);
+
+        let cancel = Canceller::new().unwrap();
+        let event = watch(ours.as_raw_fd(), Duration::from_secs(10), &cancel).unwrap();
+        assert_eq!(event.reason, "error");
+        let error = event.error.unwrap();
+        assert!(error.starts_with("ECONNRESET"), "{}", error);
+    }
+
+    #[test]
+    fn the_callback_set_when_the_peer_leaves_is_called() {
+        let (ours, peer) = UnixStream::pair().unwrap();
+        let on_close = Arc::new(Mutex::new(None));
+        let _watchdog =
+            Watchdog::start(ours.as_raw_fd(), Duration::from_secs(10), on_close.clone()).unwrap();
+
+        // onClose() after enableWatchdog() still gets the event
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let callback: CloseCallback = Box::new(move |event| {
+            tx.lock().unwrap().send(event.reason).unwrap();
+        });
+        *on_close.lock().unwrap() = Some(callback);
+        drop(peer);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "hangup");
+        // Once per watchdog
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+}
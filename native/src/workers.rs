@@ -0,0 +1,223 @@
+//! Dedicated native thread pool for the blocking half of async APIs.
+//!
+//! acceptAsync(), vsockConnectAsync(), TLS and secure channel handshakes,
+//! KMS calls and the other promise-returning vsock APIs block a thread for
+//! as long as the peer takes. By default they run on libuv's thread pool,
+//! which has 4 threads shared with fs, dns, zlib and every other addon, so
+//! a few slow peers can stall the whole process. configureThreadPool()
+//! moves them onto native threads of their own:
+//!
+//! ```js
+//! configureThreadPool({ size: 64 });
+//! ```
+//!
+//! Threads are started as work arrives, up to `size`, and idle ones exit
+//! when the pool shrinks. `size: 0` goes back to libuv's pool.
+
+use napi::bindgen_prelude::*;
+use napi::{sys, NapiRaw, Task};
+use napi_derive::napi;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+
+/// Upper bound for ThreadPoolOptions.size.
+const MAX_THREADS: u32 = 1024;
+
+#[napi(object)]
+pub struct ThreadPoolOptions {
+    /// Native threads servicing blocking calls, at most 1024; 0 uses
+    /// libuv's thread pool (the default).
+    pub size: u32,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    size: usize,
+    threads: usize,
+    idle: usize,
+}
+
+#[derive(Default)]
+struct Pool {
+    state: Mutex<State>,
+    work: Condvar,
+}
+
+impl Pool {
+    fn resize(self: &Arc<Self>, size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.size = size;
+        // Surplus idle threads notice and exit
+        self.work.notify_all();
+        self.spawn_needed(&mut state);
+    }
+
+    fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    fn submit(self: &Arc<Self>, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.push_back(job);
+        self.spawn_needed(&mut state);
+        self.work.notify_one();
+    }
+
+    /// Start threads for queued jobs no idle thread will pick up.
+    fn spawn_needed(self: &Arc<Self>, state: &mut State) {
+        while state.jobs.len() > state.idle && state.threads < state.size {
+            state.threads += 1;
+            let pool = self.clone();
+            std::thread::Builder::new()
+                .name("tytle-worker".to_string())
+                .spawn(move || pool.work())
+                .expect("failed to spawn worker thread");
+        }
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // Threads beyond a shrunk size leave, but the last ones finish
+            // the queue when the pool is disabled
+            if state.threads > state.size && (state.size > 0 || state.jobs.is_empty()) {
+                state.threads -= 1;
+                return;
+            }
+            match state.jobs.pop_front() {
+                Some(job) => {
+                    drop(state);
+                    job();
+                    state = self.state.lock().unwrap();
+                }
+                None => {
+                    state.idle += 1;
+                    state = self.work.wait(state).unwrap();
+                    state.idle -= 1;
+                }
+            }
+        }
+    }
+}
+
+fn pool() -> &'static Arc<Pool> {
+    static POOL: OnceLock<Arc<Pool>> = OnceLock::new();
+    POOL.get_or_init(Arc::default)
+}
+
+/// Run blocking vsock/NSM calls from async APIs on `options.size` native
+/// threads instead of libuv's shared pool. Can be called again to resize;
+/// calls already queued or running are not affected.
+#[napi]
+pub fn configure_thread_pool(options: ThreadPoolOptions) -> Result<()> {
+    if options.size > MAX_THREADS {
+        return Err(Error::from_reason(format!(
+            "size must be at most {}",
+            MAX_THREADS
+        )));
+    }
+    pool().resize(options.size as usize);
+    Ok(())
+}
+
+/// A Task run on the worker pool when configured, else on libuv's like
+/// AsyncTask. Return it from an export instead of AsyncTask to let
+/// configureThreadPool() apply.
+pub struct WorkerTask<T: Task>(T);
+
+impl<T: Task> WorkerTask<T> {
+    pub fn new(task: T) -> Self {
+        WorkerTask(task)
+    }
+}
+
+impl<T: Task + 'static> ToNapiValue for WorkerTask<T> {
+    unsafe fn to_napi_value(raw_env: sys::napi_env, val: Self) -> Result<sys::napi_value> {
+        let pool = pool();
+        if pool.size() == 0 {
+            return ToNapiValue::to_napi_value(raw_env, AsyncTask::new(val.0));
+        }
+        let env = Env::from_raw(raw_env);
+        let (deferred, promise) = env.create_deferred()?;
+        let mut task = val.0;
+        pool.submit(Box::new(move || {
+            let output = task.compute();
+            // Settled back on the JS thread, as AsyncTask does
+            deferred.resolve(move |env| {
+                let result = match output {
+                    Ok(output) => task.resolve(env, output),
+                    Err(err) => task.reject(env, err),
+                };
+                task.finally(env)?;
+                result
+            });
+        }));
+        Ok(promise.raw())
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_up_to_size_jobs_at_once() {
+        let pool = Arc::new(Pool::default());
+        pool.resize(2);
+        let (started, starts) = mpsc::channel();
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        for i in 0..3 {
+            let started = started.clone();
+            let gate = gate.clone();
+            pool.submit(Box::new(move || {
+                started.send(i).unwrap();
+                let (open, opened) = &*gate;
+                let _unused = opened
+                    .wait_while(open.lock().unwrap(), |open| !*open)
+                    .unwrap();
+            }));
+        }
+        let wait = Duration::from_secs(5);
+        starts.recv_timeout(wait).unwrap();
+        starts.recv_timeout(wait).unwrap();
+        // The third waits for a free thread
+        assert!(starts.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(pool.state.lock().unwrap().threads, 2);
+
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        starts.recv_timeout(wait).unwrap();
+    }
+
+    #[test]
+    fn shrinking_stops_idle_threads_after_the_queue_drains() {
+        let pool = Arc::new(Pool::default());
+        pool.resize(4);
+        let (done, finished) = mpsc::channel();
+        for i in 0..4 {
+            let done = done.clone();
+            pool.submit(Box::new(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                done.send(i).unwrap();
+            }));
+        }
+        pool.resize(0);
+        for _ in 0..4 {
+            finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while pool.state.lock().unwrap().threads > 0 {
+            assert!(std::time::Instant::now() < deadline, "workers didn't exit");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! Buffered VsockStream writes with backpressure.
+//!
+//! stream.queueWrite(buffer) copies the data into a bounded native queue
+//! and returns at once; a writer thread sends it, so a full socket buffer
+//! never blocks the event loop. Like Node's writable.write(), it returns
+//! false once highWaterMark bytes or more are queued, and the onDrain()
+//! callback fires when the queue has emptied again:
+//!
+//! ```js
+//! stream.onDrain(() => source.resume());
+//! source.on('data', (chunk) => { if (!stream.queueWrite(chunk)) source.pause(); });
+//! source.on('end', async () => { await stream.flushWrites(); stream.close(); });
+//! ```
+//!
+//! A write that would take the queue past maxQueuedBytes throws ENOBUFS
+//! instead. A failed send ends the queue: its error is thrown by every later
+//! queueWrite() and flushWrites(). close() discards whatever is still
+//! queued, so call flushWrites() first. Don't mix queueWrite() with write()
+//! on one stream; their bytes could interleave.
+
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::cancel::Canceller;
+use crate::errors;
+use crate::throttle::{self, TokenBucket};
+
+const DEFAULT_HIGH_WATER_MARK: u32 = 64 * 1024;
+const DEFAULT_MAX_QUEUED_BYTES: u32 = 16 * 1024 * 1024;
+
+#[napi(object)]
+pub struct WriteQueueOptions {
+    /// queueWrite() returns false from this many queued bytes (default
+    /// 64 KiB).
+    pub high_water_mark: Option<u32>,
+    /// queueWrite() throws ENOBUFS rather than queue past this (default
+    /// 16 MiB).
+    pub max_queued_bytes: Option<u32>,
+}
+
+#[napi(object)]
+pub struct WriteQueueStats {
+    /// Bytes accepted by queueWrite() and not yet sent.
+    pub queued_bytes: u32,
+    /// Buffers not yet fully sent.
+    pub queued_writes: u32,
+    /// Bytes sent by the writer thread so far.
+    pub written_bytes: f64,
+    pub high_water_mark: u32,
+    /// Why the queue stopped, if a send failed.
+    pub error: Option<String>,
+}
+
+/// What the writer thread needs from its stream.
+pub(crate) struct Sink {
+    pub(crate) fd: i32,
+    /// The stream's setRateLimit() write bucket.
+    pub(crate) limit: Arc<Mutex<Option<Arc<TokenBucket>>>>,
+    /// Called with each successful send's byte count.
+    pub(crate) on_write: Box<dyn Fn(usize) + Send>,
+}
+
+pub(crate) struct WriteQueue {
+    state: Mutex<State>,
+    /// Signalled on new data for the writer, and on emptying, failure or
+    /// stop for flush().
+    changed: Condvar,
+    cancel: Canceller,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct State {
+    chunks: VecDeque<Vec<u8>>,
+    /// Unsent bytes, including the rest of the chunk being sent.
+    queued: usize,
+    /// The writer thread has a chunk off `chunks` that isn't fully sent.
+    sending: bool,
+    written: u64,
+    high_water_mark: usize,
+    max_queued: usize,
+    /// queueWrite() returned false since the last drain.
+    need_drain: bool,
+    on_drain: Option<Box<dyn Fn() + Send>>,
+    error: Option<String>,
+    stopped: bool,
+}
+
+impl WriteQueue {
+    /// Start a writer thread sending to `sink`.
+    pub(crate) fn start(sink: Sink, options: Option<WriteQueueOptions>) -> Result<Arc<Self>> {
+        let queue = Arc::new(WriteQueue {
+            state: Mutex::new(State {
+                chunks: VecDeque::new(),
+                queued: 0,
+                sending: false,
+                written: 0,
+                high_water_mark: DEFAULT_HIGH_WATER_MARK as usize,
+                max_queued: DEFAULT_MAX_QUEUED_BYTES as usize,
+                need_drain: false,
+                on_drain: None,
+                error: None,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+            cancel: Canceller::new().map_err(|e| errors::os_error("pipe()", e))?,
+            thread: Mutex::new(None),
+        });
+        if let Some(options) = options {
+            queue.configure(options)?;
+        }
+        let writer = queue.clone();
+        *queue.thread.lock().unwrap() = Some(std::thread::spawn(move || writer.run(sink)));
+        Ok(queue)
+    }
+
+    pub(crate) fn configure(&self, options: WriteQueueOptions) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let high_water_mark = options
+            .high_water_mark
+            .map_or(state.high_water_mark, |n| n as usize);
+        let max_queued = options
+            .max_queued_bytes
+            .map_or(state.max_queued, |n| n as usize);
+        if high_water_mark == 0 || max_queued < high_water_mark {
+            return Err(Error::from_reason(
+                "highWaterMark must be positive and at most maxQueuedBytes",
+            ));
+        }
+        state.high_water_mark = high_water_mark;
+        state.max_queued = max_queued;
+        Ok(())
+    }
+
+    /// Queue `data`. Ok(false) means the high-water mark is reached and
+    /// the caller should wait for the drain callback.
+    pub(crate) fn push(&self, data: Vec<u8>) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = &state.error {
+            return Err(Error::from_reason(error.clone()));
+        }
+        if state.stopped {
+            return Err(Error::from_reason("Stream already closed"));
+        }
+        if state.queued + data.len() > state.max_queued {
+            return Err(Error::from_reason(format!(
+                "ENOBUFS: write queue is full ({} bytes queued, maxQueuedBytes {})",
+                state.queued, state.max_queued
+            )));
+        }
+        if !data.is_empty() {
+            state.queued += data.len();
+            state.chunks.push_back(data);
+            self.changed.notify_all();
+        }
+        let below = state.queued < state.high_water_mark;
+        if !below {
+            state.need_drain = true;
+        }
+        Ok(below)
+    }
+
+    pub(crate) fn on_drain(&self, callback: Box<dyn Fn() + Send>) {
+        self.state.lock().unwrap().on_drain = Some(callback);
+    }
+
+    /// Wait until everything queued is sent, or the queue fails or stops.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.queued > 0 && state.error.is_none() && !state.stopped {
+            state = self.changed.wait(state).unwrap();
+        }
+        match &state.error {
+            Some(error) => Err(Error::from_reason(error.clone())),
+            None if state.queued > 0 => Err(Error::from_reason("Stream closed before flushing")),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> WriteQueueStats {
+        let state = self.state.lock().unwrap();
+        WriteQueueStats {
+            queued_bytes: state.queued as u32,
+            queued_writes: state.chunks.len() as u32 + u32::from(state.sending),
+            written_bytes: state.written as f64,
+            high_water_mark: state.high_water_mark as u32,
+            error: state.error.clone(),
+        }
+    }
+
+    /// Discard what's queued and stop the writer thread. Safe to call
+    /// multiple times; the stream closes its fd afterwards.
+    pub(crate) fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.cancel.cancel();
+        self.changed.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn run(&self, sink: Sink) {
+        loop {
+            let chunk = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.stopped {
+                        return;
+                    }
+                    if let Some(chunk) = state.chunks.pop_front() {
+                        state.sending = true;
+                        break chunk;
+                    }
+                    state = self.changed.wait(state).unwrap();
+                }
+            };
+            let mut offset = 0;
+            while offset < chunk.len() {
+                match self.send(&sink, &chunk[offset..]) {
+                    Ok(n) => {
+                        offset += n;
+                        (sink.on_write)(n);
+                        self.sent(n, offset == chunk.len());
+                    }
+                    Err(_) if self.cancel.is_cancelled() => return,
+                    Err(e) => return self.fail(errors::os_error("write()", e)),
+                }
+            }
+        }
+    }
+
+    /// One non-blocking send under the rate limit, waiting for room (or
+    /// stop()) when the socket buffer is full.
+    fn send(&self, sink: &Sink, data: &[u8]) -> std::io::Result<usize> {
+        loop {
+            let limit = sink.limit.lock().unwrap().clone();
+            let granted = throttle::grant(&limit, data.len());
+            let n = unsafe {
+                libc::send(
+                    sink.fd,
+                    data.as_ptr() as *const libc::c_void,
+                    granted,
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            throttle::settle(&limit, granted, n.max(0) as usize);
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::Interrupted => {}
+                std::io::ErrorKind::WouldBlock => self.cancel.wait(sink.fd, libc::POLLOUT)?,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    /// Account for `n` bytes of the current chunk, `done` once it's all
+    /// sent.
+    fn sent(&self, n: usize, done: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.sending = !done;
+        state.queued -= n;
+        state.written += n as u64;
+        if state.queued == 0 {
+            self.changed.notify_all();
+            if std::mem::take(&mut state.need_drain) {
+                if let Some(on_drain) = &state.on_drain {
+                    on_drain();
+                }
+            }
+        }
+    }
+
+    fn fail(&self, err: Error) {
+        tracing::debug!(error = %err.reason, "queued write failed");
+        let mut state = self.state.lock().unwrap();
+        state.error = Some(err.reason);
+        state.chunks.clear();
+        state.queued = 0;
+        state.sending = false;
+        self.changed.notify_all();
+    }
+}
+
+pub struct FlushTask {
+    pub(crate) queue: Option<Arc<WriteQueue>>,
+}
+
+impl Task for FlushTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        match &self.queue {
+            Some(queue) => queue.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: Error) -> Result<Self::JsValue> {
+        Err(errors::to_js(&env, err))
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn start(fd: i32, written: Arc<AtomicUsize>) -> Arc<WriteQueue> {
+        let sink = Sink {
+            fd,
+            limit: Arc::new(Mutex::new(None)),
+            on_write: Box::new(move |n| {
+                written.fetch_add(n, Ordering::Relaxed);
+            }),
+        };
+        let options = WriteQueueOptions {
+            high_water_mark: Some(1024),
+            max_queued_bytes: Some(1 << 20),
+        };
+        WriteQueue::start(sink, Some(options)).unwrap()
+    }
+
+    #[test]
+    fn queue_reports_backpressure_and_drains() {
+        let (ours, mut peer) = UnixStream::pair().unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let queue = start(ours.as_raw_fd(), written.clone());
+        let (drained_tx, drained) = std::sync::mpsc::channel();
+        let drained_tx = Mutex::new(drained_tx);
+        queue.on_drain(Box::new(move || {
+            drained_tx.lock().unwrap().send(()).unwrap()
+        }));
+
+        assert!(queue.push(vec![1; 100]).unwrap());
+        // More than a socket buffer, so the writer has to wait for us
+        assert!(!queue.push(vec![2; 1 << 19]).unwrap());
+        let pushed = 100 + (1 << 19);
+        assert!(queue.stats().queued_bytes >= 1024);
+        let err = queue.push(vec![3; 1 << 20]).unwrap_err();
+        assert!(err.reason.starts_with("ENOBUFS"));
+
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0u8; pushed];
+            peer.read_exact(&mut received).unwrap();
+            received
+        });
+        queue.flush().unwrap();
+        drained
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        let received = reader.join().unwrap();
+        assert!(received[..100].iter().all(|&b| b == 1));
+        assert!(received[100..].iter().all(|&b| b == 2));
+        assert_eq!(written.load(Ordering::Relaxed), pushed);
+        let stats = queue.stats();
+        assert_eq!((stats.queued_bytes, stats.queued_writes), (0, 0));
+        assert_eq!(stats.written_bytes, pushed as f64);
+        queue.stop();
+        assert!(queue.push(vec![1]).is_err());
+    }
+
+    #[test]
+    fn failed_sends_end_the_queue() {
+        let (a, b) = UnixStream::pair().unwrap();
+        drop(b);
+        let fd = a.as_raw_fd();
+        let queue = start(fd, Arc::new(AtomicUsize::new(0)));
+        queue.push(vec![0; 16]).unwrap();
+        let err = queue.flush().unwrap_err();
+        assert!(err.reason.starts_with("EPIPE"), "{}", err.reason);
+        assert!(queue
+            .push(vec![0; 16])
+            .unwrap_err()
+            .reason
+            .starts_with("EPIPE"));
+        assert!(queue.stats().error.is_some());
+        queue.stop();
+
+        // stop() interrupts a writer waiting for socket room
+        let (ours, _peer) = UnixStream::pair().unwrap();
+        let queue = start(ours.as_raw_fd(), Arc::new(AtomicUsize::new(0)));
+        queue
+            .configure(WriteQueueOptions {
+                high_water_mark: None,
+                max_queued_bytes: Some(8 << 20),
+            })
+            .unwrap();
+        queue.push(vec![0; 4 << 20]).unwrap();
+        queue.stop();
+        assert!(queue.flush().is_err());
+    }
+
+    #[test]
+    fn options_are_validated() {
+        let (ours, _peer) = UnixStream::pair().unwrap();
+        let queue = start(ours.as_raw_fd(), Arc::new(AtomicUsize::new(0)));
+        let options = |high_water_mark, max_queued_bytes| WriteQueueOptions {
+            high_water_mark,
+            max_queued_bytes,
+        };
+        assert!(queue.configure(options(Some(0), None)).is_err());
+        assert!(queue.configure(options(None, Some(512))).is_err());
+        assert!(queue.configure(options(Some(2048), Some(1024))).is_err());
+        assert_eq!(queue.stats().high_water_mark, 1024);
+
+        // Unset options keep their current value
+        queue.configure(options(Some(16), None)).unwrap();
+        assert_eq!(queue.stats().high_water_mark, 16);
+        queue.configure(options(None, Some(16))).unwrap();
+        let err = queue.push(vec![0; 17]).unwrap_err();
+        assert!(err.reason.starts_with("ENOBUFS"), "{}", err.reason);
+        queue.stop();
+    }
+
+    #[test]
+    fn queued_writes_honour_the_rate_limit() {
+        let (ours, mut peer) = UnixStream::pair().unwrap();
+        let bucket = Arc::new(TokenBucket::new(10_000, 100));
+        let sink = Sink {
+            fd: ours.as_raw_fd(),
+            limit: Arc::new(Mutex::new(Some(bucket))),
+            on_write: Box::new(|_| {}),
+        };
+        let queue = WriteQueue::start(sink, None).unwrap();
+        assert!(queue.push(Vec::new()).unwrap());
+        assert_eq!(queue.stats().queued_writes, 0);
+
+        // A full burst at once, then 10ms per 100 bytes
+        let started = std::time::Instant::now();
+        queue.push(vec![7; 300]).unwrap();
+        queue.flush().unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(15));
+        let mut received = [0u8; 300];
+        peer.read_exact(&mut received).unwrap();
+        assert!(received.iter().all(|&b| b == 7));
+        queue.stop();
+    }
+}
@@ -0,0 +1,508 @@
+//! Minimal X.509 parsing for attestation certificate chains.
+//!
+//! Only what verifying an NSM chain needs: every certificate in it is
+//! P-384 ECDSA signed with SHA-384, so this reads the signed TBS bytes,
+//! issuer/subject names (compared byte-for-byte), validity, public key,
+//! the basicConstraints CA flag and the raw extensions, and rejects
+//! anything else.
+
+use napi::bindgen_prelude::*;
+use p384::ecdsa::{signature::Verifier, DerSignature, VerifyingKey};
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+
+/// 1.2.840.10045.4.3.3
+pub(crate) const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+/// 1.2.840.10045.2.1
+pub(crate) const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.3.132.0.34
+pub(crate) const OID_SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+/// 2.5.29.19
+pub(crate) const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+pub(crate) struct Certificate {
+    /// The whole certificate, as encoded.
+    pub(crate) der: Vec<u8>,
+    /// Raw DER of the issuer and subject Names.
+    pub(crate) issuer: Vec<u8>,
+    pub(crate) subject: Vec<u8>,
+    /// Validity period, in milliseconds since the Unix epoch.
+    pub(crate) not_before_ms: i64,
+    pub(crate) not_after_ms: i64,
+    /// basicConstraints cA.
+    pub(crate) is_ca: bool,
+    /// Raw DER of the subjectPublicKeyInfo.
+    pub(crate) spki: Vec<u8>,
+    /// (extnID, extnValue contents) of each extension.
+    extensions: Vec<(Vec<u8>, Vec<u8>)>,
+    tbs: Vec<u8>,
+    signature: Vec<u8>,
+    public_key: VerifyingKey,
+}
+
+impl Certificate {
+    pub(crate) fn from_der(der: &[u8]) -> Result<Self> {
+        let mut outer = Der::new(der);
+        let cert = outer.expect(TAG_SEQUENCE, "certificate")?;
+        if !outer.is_empty() {
+            return Err(invalid("trailing data after certificate"));
+        }
+
+        let mut cert = Der::new(cert.content);
+        let tbs = cert.expect(TAG_SEQUENCE, "tbsCertificate")?;
+        let algorithm = signature_algorithm(&mut cert)?;
+        let signature = bit_string(cert.expect(TAG_BIT_STRING, "signatureValue")?.content)?;
+
+        let mut fields = Der::new(tbs.content);
+        if fields.peek_tag() == Some(TAG_VERSION) {
+            fields.read()?;
+        }
+        fields.expect(TAG_INTEGER, "serialNumber")?;
+        if signature_algorithm(&mut fields)? != algorithm {
+            return Err(invalid("signature algorithm mismatch"));
+        }
+        let issuer = fields.expect(TAG_SEQUENCE, "issuer")?.whole.to_vec();
+        let mut validity = Der::new(fields.expect(TAG_SEQUENCE, "validity")?.content);
+        let not_before_ms = time_ms(validity.read()?)?;
+        let not_after_ms = time_ms(validity.read()?)?;
+        let subject = fields.expect(TAG_SEQUENCE, "subject")?.whole.to_vec();
+        let spki = fields.expect(TAG_SEQUENCE, "subjectPublicKeyInfo")?;
+        let public_key = public_key(spki.content)?;
+
+        let mut extensions = Vec::new();
+        while let Some(tag) = fields.peek_tag() {
+            let field = fields.read()?;
+            if tag == TAG_EXTENSIONS {
+                extensions = parse_extensions(field.content)?;
+            }
+        }
+        let is_ca = match extensions
+            .iter()
+            .find(|(oid, _)| oid == OID_BASIC_CONSTRAINTS)
+        {
+            Some((_, value)) => basic_constraints_ca(value)?,
+            None => false,
+        };
+
+        Ok(Certificate {
+            der: der.to_vec(),
+            issuer,
+            subject,
+            not_before_ms,
+            not_after_ms,
+            is_ca,
+            spki: spki.whole.to_vec(),
+            extensions,
+            tbs: tbs.whole.to_vec(),
+            signature,
+            public_key,
+        })
+    }
+
+    /// Check that `issuer` issued this certificate: the names chain and
+    /// the signature verifies under the issuer's key.
+    pub(crate) fn verify_issued_by(&self, issuer: &Certificate) -> Result<()> {
+        if self.issuer != issuer.subject {
+            return Err(Error::from_reason(
+                "issuer name does not match the issuing certificate",
+            ));
+        }
+        let signature = DerSignature::from_bytes(&self.signature)
+            .map_err(|_| invalid("malformed ECDSA signature"))?;
+        issuer
+            .public_key
+            .verify(&self.tbs, &signature)
+            .map_err(|_| {
+                Error::from_reason("signature does not verify under the issuing certificate")
+            })
+    }
+
+    pub(crate) fn valid_at(&self, time_ms: i64) -> bool {
+        self.not_before_ms <= time_ms && time_ms <= self.not_after_ms
+    }
+
+    pub(crate) fn public_key(&self) -> &VerifyingKey {
+        &self.public_key
+    }
+
+    /// The extnValue contents of extension `oid`, if present.
+    pub(crate) fn extension(&self, oid: &[u8]) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(id, _)| id == oid)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// Parse certificates given as PEM (any number of CERTIFICATE blocks) or
+/// as a single DER certificate.
+pub(crate) fn parse_pem_or_der(bytes: &[u8]) -> Result<Vec<Certificate>> {
+    const BEGIN: &[u8] = b"-----BEGIN ";
+    const END: &[u8] = b"-----END ";
+    let find =
+        |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|w| w == needle);
+
+    if find(bytes, BEGIN).is_none() {
+        return Ok(vec![Certificate::from_der(bytes)?]);
+    }
+    let mut certs = Vec::new();
+    let mut rest = bytes;
+    while let Some(start) = find(rest, BEGIN) {
+        let block = &rest[start..];
+        let end = find(block, END).ok_or_else(|| invalid("unterminated PEM block"))?;
+        let line_end = block[end..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(block.len(), |i| end + i + 1);
+        let (label, der) = pem_rfc7468::decode_vec(&block[..line_end])
+            .map_err(|e| invalid(&format!("malformed PEM: {}", e)))?;
+        if label != "CERTIFICATE" {
+            return Err(invalid(&format!("unexpected PEM block {}", label)));
+        }
+        certs.push(Certificate::from_der(&der)?);
+        rest = &block[line_end..];
+    }
+    Ok(certs)
+}
+
+/// A Name in the `C=US, O=Amazon, CN=...` form openssl prints, for
+/// display. Attribute types without a short name are shown as dotted OIDs.
+pub(crate) fn name_to_string(name: &[u8]) -> Result<String> {
+    let mut rdns = Der::new(Der::new(name).expect(TAG_SEQUENCE, "name")?.content);
+    let mut parts = Vec::new();
+    while !rdns.is_empty() {
+        let mut attributes = Der::new(rdns.expect(TAG_SET, "name")?.content);
+        while !attributes.is_empty() {
+            let mut attribute = Der::new(attributes.expect(TAG_SEQUENCE, "name")?.content);
+            let oid = attribute.expect(TAG_OID, "name attribute")?.content;
+            let value = attribute.read()?.content;
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN".to_string(),
+                [0x55, 0x04, 0x06] => "C".to_string(),
+                [0x55, 0x04, 0x07] => "L".to_string(),
+                [0x55, 0x04, 0x08] => "ST".to_string(),
+                [0x55, 0x04, 0x0a] => "O".to_string(),
+                [0x55, 0x04, 0x0b] => "OU".to_string(),
+                _ => oid_to_string(oid),
+            };
+            parts.push(format!("{}={}", label, String::from_utf8_lossy(value)));
+        }
+    }
+    Ok(parts.join(", "))
+}
+
+/// Dotted form of an OID's content bytes.
+fn oid_to_string(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for &byte in oid {
+        arc = (arc << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn invalid(what: &str) -> Error {
+    Error::from_reason(format!("Invalid certificate: {}", what))
+}
+
+/// One DER TLV.
+struct Tlv<'a> {
+    tag: u8,
+    content: &'a [u8],
+    /// Tag, length and content together.
+    whole: &'a [u8],
+}
+
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Der { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    fn read(&mut self) -> Result<Tlv<'a>> {
+        let truncated = || invalid("truncated DER");
+        let (&tag, rest) = self.data.split_first().ok_or_else(truncated)?;
+        let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return Err(invalid("bad DER length"));
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |n, &b| (n << 8) | b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        let header = self.data.len() - rest.len();
+        let whole = &self.data[..header + len];
+        self.data = &rest[len..];
+        Ok(Tlv {
+            tag,
+            content: &whole[header..],
+            whole,
+        })
+    }
+
+    fn expect(&mut self, tag: u8, what: &str) -> Result<Tlv<'a>> {
+        let tlv = self.read()?;
+        if tlv.tag != tag {
+            return Err(invalid(&format!("unexpected {}", what)));
+        }
+        Ok(tlv)
+    }
+}
+
+/// Read an AlgorithmIdentifier, requiring ecdsa-with-SHA384.
+fn signature_algorithm<'a>(der: &mut Der<'a>) -> Result<&'a [u8]> {
+    let mut algorithm = Der::new(der.expect(TAG_SEQUENCE, "signature algorithm")?.content);
+    let oid = algorithm.expect(TAG_OID, "signature algorithm")?.content;
+    if oid != OID_ECDSA_WITH_SHA384 {
+        return Err(invalid("signature algorithm is not ecdsa-with-SHA384"));
+    }
+    Ok(oid)
+}
+
+/// BIT STRING contents, requiring whole bytes.
+fn bit_string(content: &[u8]) -> Result<Vec<u8>> {
+    match content.split_first() {
+        Some((0, bits)) => Ok(bits.to_vec()),
+        _ => Err(invalid("malformed BIT STRING")),
+    }
+}
+
+fn public_key(spki: &[u8]) -> Result<VerifyingKey> {
+    let mut spki = Der::new(spki);
+    let mut algorithm = Der::new(spki.expect(TAG_SEQUENCE, "public key algorithm")?.content);
+    let key_type = algorithm.expect(TAG_OID, "public key algorithm")?.content;
+    let curve = algorithm.expect(TAG_OID, "public key curve")?.content;
+    if key_type != OID_EC_PUBLIC_KEY || curve != OID_SECP384R1 {
+        return Err(invalid("public key is not a P-384 EC key"));
+    }
+    let point = bit_string(spki.expect(TAG_BIT_STRING, "public key")?.content)?;
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| invalid("malformed P-384 public key"))
+}
+
+/// (extnID, extnValue contents) of each extension in the [3] field.
+fn parse_extensions(field: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut outer = Der::new(field);
+    let mut extensions = Der::new(outer.expect(TAG_SEQUENCE, "extensions")?.content);
+    let mut parsed = Vec::new();
+    while !extensions.is_empty() {
+        let mut extension = Der::new(extensions.expect(TAG_SEQUENCE, "extension")?.content);
+        let oid = extension.expect(TAG_OID, "extension id")?.content;
+        if extension.peek_tag() == Some(TAG_BOOLEAN) {
+            extension.read()?; // critical
+        }
+        let value = extension
+            .expect(TAG_OCTET_STRING, "extension value")?
+            .content;
+        parsed.push((oid.to_vec(), value.to_vec()));
+    }
+    Ok(parsed)
+}
+
+/// The cA flag of a basicConstraints value.
+fn basic_constraints_ca(value: &[u8]) -> Result<bool> {
+    let mut constraints = Der::new(
+        Der::new(value)
+            .expect(TAG_SEQUENCE, "basicConstraints")?
+            .content,
+    );
+    Ok(constraints.peek_tag() == Some(TAG_BOOLEAN)
+        && constraints.read()?.content.first().is_some_and(|&b| b != 0))
+}
+
+/// Decode a UTCTime or GeneralizedTime of the form X.509 requires
+/// (seconds precision, Zulu) to milliseconds since the Unix epoch.
+fn time_ms(tlv: Tlv) -> Result<i64> {
+    let text = std::str::from_utf8(tlv.content).map_err(|_| invalid("malformed time"))?;
+    let digits = text
+        .strip_suffix('Z')
+        .ok_or_else(|| invalid("time is not UTC"))?;
+    let (year, rest) = match (tlv.tag, digits.len()) {
+        (TAG_UTC_TIME, 12) => {
+            let yy = number(&digits[..2])?;
+            // RFC 5280: YY >= 50 is 19YY
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &digits[2..])
+        }
+        (TAG_GENERALIZED_TIME, 14) => (number(&digits[..4])?, &digits[4..]),
+        _ => return Err(invalid("malformed time")),
+    };
+    let field = |i: usize| number(&rest[i * 2..i * 2 + 2]);
+    let (month, day) = (field(0)?, field(1)?);
+    let (hour, minute, second) = (field(2)?, field(3)?, field(4)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid("malformed time"));
+    }
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    Ok(seconds * 1000)
+}
+
+fn number(digits: &str) -> Result<i64> {
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid("malformed time"));
+    }
+    digits.parse().map_err(|_| invalid("malformed time"))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's
+/// days_from_civil).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Certificate {
+        let pem = match name {
+            "root" => include_str!("../testdata/attestation/root.pem"),
+            "intermediate" => include_str!("../testdata/attestation/intermediate.pem"),
+            "leaf" => include_str!("../testdata/attestation/leaf.pem"),
+            _ => unreachable!(),
+        };
+        parse_pem_or_der(pem.as_bytes()).unwrap().remove(0)
+    }
+
+    /// 2030-01-01T00:00:00Z
+    const JAN_2030_MS: i64 = 1_893_456_000_000;
+
+    #[test]
+    fn parses_the_fixture_chain() {
+        let (root, intermediate, leaf) =
+            (fixture("root"), fixture("intermediate"), fixture("leaf"));
+        assert!(root.is_ca && intermediate.is_ca && !leaf.is_ca);
+        assert_eq!(root.issuer, root.subject);
+        assert_eq!(leaf.issuer, intermediate.subject);
+
+        // 2025-01-01 (UTCTime) to 2045-01-01
+        assert_eq!(leaf.not_before_ms, 1_735_689_600_000);
+        assert_eq!(leaf.not_after_ms, 2_366_841_600_000);
+        // 2070-01-01 is encoded as GeneralizedTime
+        assert_eq!(root.not_after_ms, 3_155_760_000_000);
+        assert!(leaf.valid_at(JAN_2030_MS));
+        assert!(!leaf.valid_at(leaf.not_after_ms + 1));
+    }
+
+    #[test]
+    fn names_print_like_openssl() {
+        let leaf = fixture("leaf");
+        assert_eq!(
+            name_to_string(&leaf.subject).unwrap(),
+            "C=US, O=Test, CN=test.enclave"
+        );
+        assert_eq!(
+            name_to_string(&leaf.issuer).unwrap(),
+            "C=US, O=Test, CN=test.intermediate"
+        );
+        assert_eq!(oid_to_string(OID_ECDSA_WITH_SHA384), "1.2.840.10045.4.3.3");
+        assert!(name_to_string(&[0x30, 0x03, 0x31]).is_err());
+    }
+
+    #[test]
+    fn verifies_issuer_signatures() {
+        let (root, intermediate, leaf) =
+            (fixture("root"), fixture("intermediate"), fixture("leaf"));
+        root.verify_issued_by(&root).unwrap();
+        intermediate.verify_issued_by(&root).unwrap();
+        leaf.verify_issued_by(&intermediate).unwrap();
+
+        let err = leaf.verify_issued_by(&root).unwrap_err();
+        assert!(err.reason.contains("issuer name"), "{}", err.reason);
+
+        // Tampered TBS
+        let mut forged = fixture("leaf");
+        forged.tbs[20] ^= 1;
+        let err = forged.verify_issued_by(&intermediate).unwrap_err();
+        assert!(err.reason.contains("signature"), "{}", err.reason);
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let der = fixture("leaf").der;
+        assert!(Certificate::from_der(&der[..der.len() - 1]).is_err());
+        assert!(Certificate::from_der(&[]).is_err());
+    }
+
+    #[test]
+    fn parses_pem_bundles_and_der() {
+        let bundle = [
+            include_str!("../testdata/attestation/root.pem"),
+            "\n",
+            include_str!("../testdata/attestation/intermediate.pem"),
+        ]
+        .concat();
+        let certs = parse_pem_or_der(bundle.as_bytes()).unwrap();
+        assert_eq!(certs.len(), 2);
+        assert_eq!(certs[0].der, fixture("root").der);
+        assert_eq!(certs[1].der, fixture("intermediate").der);
+
+        let der = parse_pem_or_der(&fixture("leaf").der).unwrap();
+        assert_eq!(der.len(), 1);
+
+        let key = include_str!("../testdata/attestation/leaf.key");
+        let err = parse_pem_or_der(key.as_bytes()).err().unwrap();
+        assert!(err.reason.contains("PRIVATE KEY"), "{}", err.reason);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+}